@@ -0,0 +1,209 @@
+#![no_std]
+
+//! Client library for the `global_fs` filesystem service.
+//!
+//! # Message register layout
+//!
+//! `MR0` carries the operation code on requests and the result on replies.
+//! Negative results are `-errno`. Paths and payloads are exchanged through
+//! the buffer registers: `BR0` holds the payload length in bytes, the data
+//! follows from `BR1` onwards.
+//!
+//! ```text
+//! Open (OP_OPEN)        BRs: path               Reply: MR0 = handle
+//! Read (OP_READ)        MR1: handle, MR2: len   Reply: MR0 = bytes, BRs: data
+//! Write (OP_WRITE)      MR1: handle, BRs: data  Reply: MR0 = bytes
+//! Close (OP_CLOSE)      MR1: handle             Reply: MR0 = status
+//! Stat (OP_STAT)        BRs: path               Reply: MR0 = status, MR1 = size
+//! Pread (OP_PREAD)      MR1: handle, MR2: len, MR3: offset
+//!                                               Reply: MR0 = bytes, BRs: data
+//! Pwrite (OP_PWRITE)    MR1: handle, MR2: offset, BRs: data
+//!                                               Reply: MR0 = bytes
+//! Fsync (OP_FSYNC)      MR1: handle             Reply: MR0 = status
+//! ```
+//!
+//! The positional operations leave the file position of the handle
+//! untouched.
+
+use core::cmp::min;
+use l4::sys::{l4_ipc_call, l4_ipc_error, l4_msgtag, l4_utcb, l4_utcb_br, l4_utcb_mr};
+use l4re::sys::l4re_env_get_cap;
+
+/// Operation code: list the root directory.
+pub const OP_LIST: u64 = 0;
+/// Operation code: open a file.
+pub const OP_OPEN: u64 = 1;
+/// Operation code: read at the current position.
+pub const OP_READ: u64 = 2;
+/// Operation code: write at the current position.
+pub const OP_WRITE: u64 = 3;
+/// Operation code: close a handle.
+pub const OP_CLOSE: u64 = 4;
+/// Operation code: query the size of a file.
+pub const OP_STAT: u64 = 5;
+/// Operation code: read at an explicit offset.
+pub const OP_PREAD: u64 = 6;
+/// Operation code: write at an explicit offset.
+pub const OP_PWRITE: u64 = 7;
+/// Operation code: flush a file to the backing store.
+pub const OP_FSYNC: u64 = 8;
+
+const EIO: i32 = 5;
+const BR_WORDS: usize = l4::sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
+/// Largest payload carried by a single request.
+pub const BR_DATA_MAX: usize = BR_WORDS * 8 - 8;
+
+/// Client handle to the filesystem service.
+pub struct FsClient {
+    gate: l4re::sys::l4_cap_idx_t,
+}
+
+unsafe fn br_write(data: &[u8]) {
+    let br = &mut (*l4_utcb_br()).br;
+    let len = min(data.len(), BR_DATA_MAX);
+    br[0] = len as u64;
+    core::ptr::copy_nonoverlapping(data.as_ptr(), br.as_mut_ptr().add(1) as *mut u8, len);
+}
+
+unsafe fn br_read(buf: &mut [u8], expected: usize) -> usize {
+    let br = &(*l4_utcb_br()).br;
+    let len = min(min(br[0] as usize, BR_DATA_MAX), min(buf.len(), expected));
+    core::ptr::copy_nonoverlapping(br.as_ptr().add(1) as *const u8, buf.as_mut_ptr(), len);
+    len
+}
+
+impl FsClient {
+    /// Retrieve the `global_fs` capability from the environment.
+    pub fn new() -> Option<Self> {
+        l4re_env_get_cap("global_fs").map(|gate| FsClient { gate })
+    }
+
+    /// Issue a call with `words` message registers already filled in and
+    /// decode the result in `MR0`.
+    unsafe fn call(&self, words: u32) -> Result<u64, i32> {
+        let tag = l4_ipc_call(
+            self.gate,
+            l4_utcb(),
+            l4_msgtag(0, words, 0, 0),
+            l4::sys::l4_timeout_t { raw: 0 },
+        );
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            return Err(EIO);
+        }
+        let res = (*l4_utcb_mr()).mr[0] as i64;
+        if res < 0 {
+            Err((-res) as i32)
+        } else {
+            Ok(res as u64)
+        }
+    }
+
+    /// Open (or create) the file at `path` and return its handle.
+    pub fn open(&self, path: &str) -> Result<u64, i32> {
+        unsafe {
+            br_write(path.as_bytes());
+            (*l4_utcb_mr()).mr[0] = OP_OPEN;
+            self.call(1)
+        }
+    }
+
+    /// Return the size of the file at `path`.
+    pub fn stat(&self, path: &str) -> Result<u64, i32> {
+        unsafe {
+            br_write(path.as_bytes());
+            (*l4_utcb_mr()).mr[0] = OP_STAT;
+            self.call(1)?;
+            Ok((*l4_utcb_mr()).mr[1])
+        }
+    }
+
+    /// Read from the current file position. Reads at most [`BR_DATA_MAX`]
+    /// bytes per call.
+    pub fn read(&self, handle: u64, buf: &mut [u8]) -> Result<usize, i32> {
+        unsafe {
+            let mr = &mut (*l4_utcb_mr()).mr;
+            mr[0] = OP_READ;
+            mr[1] = handle;
+            mr[2] = min(buf.len(), BR_DATA_MAX) as u64;
+            let n = self.call(3)? as usize;
+            Ok(br_read(buf, n))
+        }
+    }
+
+    /// Write at the current file position. Writes at most [`BR_DATA_MAX`]
+    /// bytes per call.
+    pub fn write(&self, handle: u64, data: &[u8]) -> Result<usize, i32> {
+        unsafe {
+            br_write(data);
+            let mr = &mut (*l4_utcb_mr()).mr;
+            mr[0] = OP_WRITE;
+            mr[1] = handle;
+            Ok(self.call(2)? as usize)
+        }
+    }
+
+    /// Read up to `buf.len()` bytes at `offset`, splitting the transfer into
+    /// as many calls as needed. Stops early at end of file.
+    pub fn pread(&self, handle: u64, buf: &mut [u8], offset: u64) -> Result<usize, i32> {
+        let mut done = 0;
+        while done < buf.len() {
+            let chunk = min(buf.len() - done, BR_DATA_MAX);
+            let n = unsafe {
+                let mr = &mut (*l4_utcb_mr()).mr;
+                mr[0] = OP_PREAD;
+                mr[1] = handle;
+                mr[2] = chunk as u64;
+                mr[3] = offset + done as u64;
+                let n = self.call(4)? as usize;
+                br_read(&mut buf[done..done + chunk], n)
+            };
+            done += n;
+            if n < chunk {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Write `data` at `offset`, splitting the transfer into as many calls as
+    /// needed.
+    pub fn pwrite(&self, handle: u64, data: &[u8], offset: u64) -> Result<usize, i32> {
+        let mut done = 0;
+        while done < data.len() {
+            let chunk = min(data.len() - done, BR_DATA_MAX);
+            let n = unsafe {
+                br_write(&data[done..done + chunk]);
+                let mr = &mut (*l4_utcb_mr()).mr;
+                mr[0] = OP_PWRITE;
+                mr[1] = handle;
+                mr[2] = offset + done as u64;
+                self.call(3)? as usize
+            };
+            done += n;
+            if n < chunk {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Flush buffered data of `handle` to the backing store.
+    pub fn fsync(&self, handle: u64) -> Result<(), i32> {
+        unsafe {
+            let mr = &mut (*l4_utcb_mr()).mr;
+            mr[0] = OP_FSYNC;
+            mr[1] = handle;
+            self.call(2).map(|_| ())
+        }
+    }
+
+    /// Close `handle`.
+    pub fn close(&self, handle: u64) -> Result<(), i32> {
+        unsafe {
+            let mr = &mut (*l4_utcb_mr()).mr;
+            mr[0] = OP_CLOSE;
+            mr[1] = handle;
+            self.call(2).map(|_| ())
+        }
+    }
+}
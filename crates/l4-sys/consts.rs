@@ -20,3 +20,9 @@ pub const MSGTAG_ERROR: i64 = l4_msgtag_flags::L4_MSGTAG_ERROR as i64;
 
 /// 0 send timeout
 pub const L4_IPC_SEND_TIMEOUT_0: l4_timeout_t = l4_timeout_t { raw: 0x04000000 };
+
+/// 0 receive timeout, never time out sending
+pub const L4_IPC_RECV_TIMEOUT_0: l4_timeout_t = l4_timeout_t { raw: 0x00000400 };
+
+/// 0 send and receive timeout
+pub const L4_IPC_BOTH_TIMEOUT_0: l4_timeout_t = l4_timeout_t { raw: 0x04000400 };
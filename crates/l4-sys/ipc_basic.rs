@@ -181,6 +181,34 @@ pub unsafe fn l4_utcb_mr_u(u: *mut l4_utcb_t) -> *mut l4_msg_regs_t {
     (u as *mut u8).offset(UtcbConsts::L4_UTCB_MSG_REGS_OFFSET as isize) as *mut l4_msg_regs_t
 }
 
+/// Re-implementation of `l4_timeout_from_us`, returning the raw 16-bit relative timeout.
+///
+/// `0` yields a zero timeout and `u32::MAX` never times out. Other values are
+/// encoded as mantissa and exponent and may be rounded down.
+#[inline]
+pub fn l4_timeout_from_us(mut us: u32) -> u16 {
+    match us {
+        0 => 0x400,
+        u32::MAX => 0,
+        _ => {
+            let mut exp = 0u16;
+            while us >= 1 << 10 {
+                us >>= 1;
+                exp += 1;
+            }
+            (exp << 10) | us as u16
+        }
+    }
+}
+
+/// Combine raw send and receive timeouts, like `l4_timeout`.
+#[inline]
+pub fn l4_timeout(snd: u16, rcv: u16) -> l4_timeout_t {
+    l4_timeout_t {
+        raw: ((snd as u32) << 16) | rcv as u32,
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // new functions
 
@@ -0,0 +1,102 @@
+//! Interrupt objects.
+//!
+//! IRQ objects are either hardware interrupts handed out by the IO server or
+//! software IRQs created through the factory (see `l4_factory_create_irq`).
+//! A thread receives interrupts after binding the IRQ to itself with
+//! `l4_rcv_ep_bind_thread`; afterwards every interrupt shows up as an IPC
+//! carrying the label given at bind time.
+
+use crate::c_api::*;
+use crate::consts::L4_IPC_BOTH_TIMEOUT_0;
+#[cfg(not(test))]
+use crate::ipc_basic::l4_ipc_send;
+use crate::ipc_basic::{l4_utcb, l4_utcb_mr_u, timeout_never};
+use crate::ipc_ext::msgtag;
+
+// Without a kernel during testing, sending hands the tag back so the
+// messages can be inspected.
+#[cfg(test)]
+unsafe fn l4_ipc_send(
+    _dest: l4_cap_idx_t,
+    _utcb: *mut l4_utcb_t,
+    tag: l4_msgtag_t,
+    _timeout: l4_timeout_t,
+) -> l4_msgtag_t {
+    tag
+}
+
+/// IRQ operation codes, mirrored from `l4/sys/irq.h`.
+pub const L4_IRQ_OP_TRIGGER: u64 = 2;
+pub const L4_IRQ_OP_EOI: u64 = 4;
+
+/// Trigger a software IRQ.
+///
+/// The sender does not block; if no thread is bound to the IRQ, the trigger is
+/// recorded and delivered once a receiver attaches.
+#[inline]
+pub unsafe fn l4_irq_trigger(irq: l4_cap_idx_t) -> l4_msgtag_t {
+    l4_irq_trigger_u(irq, l4_utcb())
+}
+
+#[inline]
+pub unsafe fn l4_irq_trigger_u(irq: l4_cap_idx_t, u: *mut l4_utcb_t) -> l4_msgtag_t {
+    let v = l4_utcb_mr_u(u);
+    mr!(v[0] = L4_IRQ_OP_TRIGGER);
+    l4_ipc_send(
+        irq,
+        u,
+        msgtag(l4_msgtag_protocol::L4_PROTO_IRQ as i64, 1, 0, 0),
+        L4_IPC_BOTH_TIMEOUT_0,
+    )
+}
+
+/// Unmask (acknowledge) an IRQ so that the next interrupt gets delivered.
+#[inline]
+pub unsafe fn l4_irq_unmask(irq: l4_cap_idx_t) -> l4_msgtag_t {
+    l4_irq_unmask_u(irq, l4_utcb())
+}
+
+#[inline]
+pub unsafe fn l4_irq_unmask_u(irq: l4_cap_idx_t, u: *mut l4_utcb_t) -> l4_msgtag_t {
+    let v = l4_utcb_mr_u(u);
+    mr!(v[0] = L4_IRQ_OP_EOI);
+    l4_ipc_send(
+        irq,
+        u,
+        msgtag(l4_msgtag_protocol::L4_PROTO_IRQ as i64, 1, 0, 0),
+        timeout_never(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc_ext::{msgtag_label, msgtag_words};
+    use core::mem::MaybeUninit;
+
+    #[test]
+    fn trigger_protocol_written() {
+        unsafe {
+            let mut utcb = MaybeUninit::<l4_utcb_t>::zeroed();
+            let u = utcb.as_mut_ptr();
+            let tag = l4_irq_trigger_u(0, u);
+            let regs = l4_utcb_mr_u(u);
+            assert_eq!((*regs).mr[0], L4_IRQ_OP_TRIGGER);
+            assert_eq!(msgtag_words(tag), 1);
+            assert_eq!(msgtag_label(tag), l4_msgtag_protocol::L4_PROTO_IRQ as i64);
+        }
+    }
+
+    #[test]
+    fn unmask_protocol_written() {
+        unsafe {
+            let mut utcb = MaybeUninit::<l4_utcb_t>::zeroed();
+            let u = utcb.as_mut_ptr();
+            let tag = l4_irq_unmask_u(0, u);
+            let regs = l4_utcb_mr_u(u);
+            assert_eq!((*regs).mr[0], L4_IRQ_OP_EOI);
+            assert_eq!(msgtag_words(tag), 1);
+            assert_eq!(msgtag_label(tag), l4_msgtag_protocol::L4_PROTO_IRQ as i64);
+        }
+    }
+}
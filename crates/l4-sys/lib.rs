@@ -11,6 +11,7 @@ pub mod consts;
 mod factory;
 pub mod helpers;
 mod ipc_basic;
+mod irq;
 mod platform;
 mod scheduler;
 mod task;
@@ -21,6 +22,7 @@ pub use crate::cap::*;
 pub use crate::factory::*;
 pub use crate::ipc_basic::*;
 pub use crate::ipc_ext::*;
+pub use crate::irq::*;
 pub use crate::platform::*;
 pub use crate::scheduler::*;
 pub use crate::task::*;
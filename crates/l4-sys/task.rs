@@ -153,6 +153,26 @@ pub unsafe fn l4_task_cap_valid_u(task: l4_cap_idx_t, cap: l4_cap_idx_t,
                                 2, 0, 0), timeout_never())
 }
 
+/// Check whether a capability has been mapped to other tasks.
+///
+/// The returned label of the message tag is 1 if mappings derived from the
+/// capability exist, 0 otherwise.
+#[inline]
+pub unsafe fn l4_task_cap_has_child(task: l4_cap_idx_t, cap: l4_cap_idx_t)
+        -> l4_msgtag_t {
+    l4_task_cap_has_child_u(task, cap, l4_utcb())
+}
+
+#[inline]
+pub unsafe fn l4_task_cap_has_child_u(task: l4_cap_idx_t, cap: l4_cap_idx_t,
+        u: *mut l4_utcb_t) -> l4_msgtag_t {
+    let v = l4_utcb_mr_u(u);
+    mr!(v[0] = L4_TASK_CAP_INFO_OP);
+    mr!(v[1] = cap | 1u64);
+    l4_ipc_call(task, u, msgtag(l4_msgtag_protocol::L4_PROTO_TASK as i64,
+                                2, 0, 0), timeout_never())
+}
+
 /// Test whether two capabilities point to the same object with the same rights.
 ///
 ///The returned label of the message tag is 1 on equality, 0 on inequality.
@@ -8,5 +8,7 @@ fn main() {
     build.file("src/timerfd.c");
     build.file("src/inotify.c");
     build.file("src/aio.c");
+    build.file("src/netdb.c");
+    build.file("src/socket.c");
     build.compile("l4re_libc_c");
 }
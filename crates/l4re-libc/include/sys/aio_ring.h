@@ -0,0 +1,73 @@
+#ifndef _L4RE_LIBC_SYS_AIO_RING_H
+#define _L4RE_LIBC_SYS_AIO_RING_H 1
+
+#include <stdint.h>
+
+/*
+ * Shared submission/completion rings served by aio_server ("global_aio").
+ *
+ * AIO_RING_SETUP maps a dataspace laid out as
+ *   struct aio_ring_header | struct aio_ring_sqe[sq_entries]
+ *                          | struct aio_ring_cqe[cq_entries] | data area
+ * an IRQ that is triggered whenever completions are posted, and an IPC
+ * gate for the ring. The offsets of the arrays and of the data area are
+ * stored in the header. SQE payloads are addressed by their offset within
+ * the data area and the fd of an SQE is an fs_server file handle.
+ *
+ * AIO_RING_ENTER and AIO_RING_DESTROY must be sent through the ring's own
+ * gate; sent through any other gate they fail with -EBADF.
+ */
+
+/* IPC opcodes (MR0) */
+#define AIO_RING_SETUP   8  /* MR1 = sq entries, MR2 = cq entries, MR3 = data bytes */
+#define AIO_RING_ENTER   9  /* ring gate; MR1 = ring handle, MR2 = max entries to submit */
+#define AIO_RING_DESTROY 10 /* ring gate; MR1 = ring handle */
+
+/* SQE opcodes */
+#define AIO_RING_OP_NOP   0
+#define AIO_RING_OP_READ  1
+#define AIO_RING_OP_WRITE 2
+#define AIO_RING_OP_FSYNC 3
+
+/* SQE flags */
+#define AIO_RING_SQE_IO_LINK  (1u << 0)
+#define AIO_RING_SQE_IO_DRAIN (1u << 1)
+
+/* AIO_RING_OP_FSYNC op_flags; fs_server has no data-only flush, so
+ * AIO_RING_FSYNC_DATASYNC currently flushes everything. */
+#define AIO_RING_FSYNC_DATASYNC (1u << 0)
+
+struct aio_ring_header {
+    uint32_t sq_head;      /* written by the server */
+    uint32_t sq_tail;      /* written by the client */
+    uint32_t sq_entries;
+    uint32_t sq_off;
+    uint32_t cq_head;      /* written by the client */
+    uint32_t cq_tail;      /* written by the server */
+    uint32_t cq_entries;
+    uint32_t cq_off;
+    uint32_t data_off;
+    uint32_t data_len;
+    uint32_t cq_overflow;
+    uint32_t reserved;
+};
+
+struct aio_ring_sqe {
+    uint8_t  opcode;
+    uint8_t  flags;
+    uint16_t ioprio;
+    int32_t  fd;
+    uint64_t off;
+    uint64_t addr;         /* offset into the data area */
+    uint32_t len;
+    uint32_t op_flags;
+    uint64_t user_data;
+};
+
+struct aio_ring_cqe {
+    uint64_t user_data;
+    int32_t  res;          /* bytes transferred or -errno */
+    uint32_t flags;
+};
+
+#endif /* _L4RE_LIBC_SYS_AIO_RING_H */
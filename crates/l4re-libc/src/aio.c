@@ -17,6 +17,13 @@
 #define OPCODE_AIO_SUSPEND 5
 #define OPCODE_AIO_FSYNC  6
 
+/*
+ * aio_server resolves aio_fildes as an fs_server file handle and completes
+ * requests in the background; aio_error() reports EINPROGRESS meanwhile.
+ * fs_server has no data-only flush, so aio_fsync(O_DSYNC, ...) flushes
+ * everything, like O_SYNC.
+ */
+
 #define BR_WORDS L4_UTCB_GENERIC_BUFFERS_SIZE
 #define BR_DATA_BYTES ((BR_WORDS - 1) * sizeof(l4_umword_t))
 
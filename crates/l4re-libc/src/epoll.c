@@ -1,7 +1,19 @@
 #include "sys/epoll.h"
+#include "sockfd.h"
+#include <errno.h>
 #include <sys/syscall.h>
+#include <time.h>
 #include <unistd.h>
 
+/*
+ * Socket descriptors are placeholders the kernel knows nothing about, so
+ * their registrations are kept in socket.c. epoll_wait() on a set that
+ * watches sockets polls net_server and the kernel in turns.
+ */
+
+/* Longest kernel wait between two polls of the sockets, in milliseconds. */
+#define SOCKET_POLL_MS 10
+
 int epoll_create(int size)
 {
     (void)size;
@@ -10,16 +22,77 @@ int epoll_create(int size)
 
 int epoll_create1(int flags)
 {
-    return (int)syscall(SYS_epoll_create1, flags);
+    int epfd = (int)syscall(SYS_epoll_create1, flags);
+    if (epfd >= 0)
+        l4re_sock_epoll_forget(epfd);
+    return epfd;
 }
 
 int epoll_ctl(int epfd, int op, int fd, struct epoll_event *event)
 {
+    if (l4re_sock_is_socket(fd)) {
+        int rc = l4re_sock_epoll_ctl(epfd, op, fd, event);
+        if (rc < 0) {
+            errno = -rc;
+            return -1;
+        }
+        return 0;
+    }
     return (int)syscall(SYS_epoll_ctl, epfd, op, fd, event);
 }
 
+static int kernel_wait(int epfd, struct epoll_event *events, int maxevents, int timeout,
+                       const sigset_t *sigmask)
+{
+    return (int)syscall(SYS_epoll_pwait, epfd, events, maxevents, timeout, sigmask,
+                        sizeof(sigset_t));
+}
+
+static long elapsed_ms(const struct timespec *start)
+{
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    return (now.tv_sec - start->tv_sec) * 1000 + (now.tv_nsec - start->tv_nsec) / 1000000;
+}
+
+/* epoll_pwait() for a set with sockets in it. */
+static int socket_wait(int epfd, struct epoll_event *events, int maxevents, int timeout,
+                       const sigset_t *sigmask)
+{
+    struct timespec start;
+    clock_gettime(CLOCK_MONOTONIC, &start);
+
+    if (maxevents <= 0) {
+        errno = EINVAL;
+        return -1;
+    }
+    for (;;) {
+        int n = l4re_sock_epoll_ready(epfd, events, maxevents);
+        if (n > 0) {
+            int more = 0;
+            if (n < maxevents)
+                more = kernel_wait(epfd, events + n, maxevents - n, 0, sigmask);
+            return more > 0 ? n + more : n;
+        }
+
+        int slice = SOCKET_POLL_MS;
+        if (timeout >= 0) {
+            long left = timeout - elapsed_ms(&start);
+            if (left <= 0)
+                return kernel_wait(epfd, events, maxevents, 0, sigmask);
+            if (left < slice)
+                slice = (int)left;
+        }
+        n = kernel_wait(epfd, events, maxevents, slice, sigmask);
+        if (n != 0)
+            return n;
+    }
+}
+
 int epoll_wait(int epfd, struct epoll_event *events, int maxevents, int timeout)
 {
+    if (l4re_sock_epoll_watches(epfd))
+        return socket_wait(epfd, events, maxevents, timeout, NULL);
 #ifdef SYS_epoll_wait
     return (int)syscall(SYS_epoll_wait, epfd, events, maxevents, timeout);
 #else
@@ -30,6 +103,7 @@ int epoll_wait(int epfd, struct epoll_event *events, int maxevents, int timeout)
 int epoll_pwait(int epfd, struct epoll_event *events, int maxevents, int timeout,
                 const sigset_t *sigmask)
 {
-    return (int)syscall(SYS_epoll_pwait, epfd, events, maxevents, timeout, sigmask,
-                        sizeof(sigset_t));
+    if (l4re_sock_epoll_watches(epfd))
+        return socket_wait(epfd, events, maxevents, timeout, sigmask);
+    return kernel_wait(epfd, events, maxevents, timeout, sigmask);
 }
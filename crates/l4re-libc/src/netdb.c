@@ -0,0 +1,297 @@
+#include <netdb.h>
+#include <arpa/inet.h>
+#include <errno.h>
+#include <netinet/in.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/socket.h>
+#include "ipc.h"
+#include "env.h"
+#include <l4/sys/ipc.h>
+#include <l4/sys/utcb.h>
+
+#define OPCODE_GETADDRINFO 0
+#define OPCODE_GETNAMEINFO 1
+
+/*
+ * Host names are resolved by dns_server through the "global_dns" gate;
+ * numeric hosts and services are handled here. Service names are not
+ * supported as there is no /etc/services.
+ */
+
+#define BR_WORDS L4_UTCB_GENERIC_BUFFERS_SIZE
+#define BR_DATA_BYTES ((BR_WORDS - 1) * sizeof(l4_umword_t))
+/* Words of an address: family << 16 | port, then 16 address bytes. */
+#define ADDR_WORDS 3
+
+struct ai_node {
+    struct addrinfo ai;
+    union {
+        struct sockaddr_in v4;
+        struct sockaddr_in6 v6;
+    } addr;
+};
+
+static l4_cap_idx_t dns_gate = L4_INVALID_CAP;
+
+static int ensure_gate(void)
+{
+    if (!l4_is_invalid_cap(dns_gate))
+        return 0;
+
+    l4_cap_idx_t gate = l4re_env_get_cap_w("global_dns");
+    if (l4_is_invalid_cap(gate))
+        return ENOENT;
+    dns_gate = gate;
+    return 0;
+}
+
+/* Issue a call with `words` message registers. Returns MR0 of the reply
+ * or EAI_FAIL if the server cannot be reached. */
+static long dns_call(unsigned words)
+{
+    l4_utcb_t *utcb = l4_utcb_w();
+    l4_msgtag_t tag = l4_ipc_call_w(dns_gate, utcb, l4_msgtag_w(0, words, 0, 0), L4_IPC_NEVER);
+    if (l4_ipc_error_w(tag, utcb))
+        return EAI_FAIL;
+    return (long)l4_utcb_mr_w()->mr[0];
+}
+
+static int parse_service(const char *service, int flags, in_port_t *port)
+{
+    if (!service) {
+        *port = 0;
+        return 0;
+    }
+    char *end;
+    unsigned long value = strtoul(service, &end, 10);
+    if (*service == '\0' || *end != '\0' || value > 65535)
+        return (flags & AI_NUMERICSERV) ? EAI_NONAME : EAI_SERVICE;
+    *port = htons((in_port_t)value);
+    return 0;
+}
+
+/* Append entries for `addr` (16 bytes, IPv4 in the first four) to the list
+ * ending at *tail, one per requested socket type. */
+static int add_entries(struct addrinfo ***tail, int family, const unsigned char *addr,
+                       in_port_t port, const struct addrinfo *hints)
+{
+    static const int types[][2] = {
+        { SOCK_STREAM, IPPROTO_TCP },
+        { SOCK_DGRAM, IPPROTO_UDP },
+    };
+    for (unsigned i = 0; i < sizeof(types) / sizeof(types[0]); i++) {
+        if (hints->ai_socktype && hints->ai_socktype != types[i][0])
+            continue;
+        if (hints->ai_protocol && hints->ai_protocol != types[i][1])
+            continue;
+        struct ai_node *node = calloc(1, sizeof(*node));
+        if (!node)
+            return EAI_MEMORY;
+        node->ai.ai_family = family;
+        node->ai.ai_socktype = types[i][0];
+        node->ai.ai_protocol = types[i][1];
+        node->ai.ai_addr = (struct sockaddr *)&node->addr;
+        if (family == AF_INET) {
+            node->addr.v4.sin_family = AF_INET;
+            node->addr.v4.sin_port = port;
+            memcpy(&node->addr.v4.sin_addr, addr, 4);
+            node->ai.ai_addrlen = sizeof(node->addr.v4);
+        } else {
+            node->addr.v6.sin6_family = AF_INET6;
+            node->addr.v6.sin6_port = port;
+            memcpy(&node->addr.v6.sin6_addr, addr, 16);
+            node->ai.ai_addrlen = sizeof(node->addr.v6);
+        }
+        **tail = &node->ai;
+        *tail = &node->ai.ai_next;
+    }
+    return 0;
+}
+
+/* Ask dns_server for the addresses of `node`. */
+static int resolve(const char *node, const struct addrinfo *hints, in_port_t port,
+                   struct addrinfo ***tail, char **canonname)
+{
+    size_t len = strlen(node);
+    if (len > BR_DATA_BYTES)
+        return EAI_NONAME;
+    if (ensure_gate())
+        return EAI_FAIL;
+
+    l4_msg_regs_t *mr = l4_utcb_mr_w();
+    l4_buf_regs_t *br = l4_utcb_br();
+    memcpy(br->br + 1, node, len);
+    br->br[0] = len;
+    mr->mr[0] = OPCODE_GETADDRINFO;
+    mr->mr[1] = (l4_umword_t)hints->ai_family;
+    mr->mr[2] = (l4_umword_t)(hints->ai_flags & (AI_CANONNAME | AI_NUMERICHOST));
+
+    long count = dns_call(3);
+    if (count < 0)
+        return (int)count;
+    size_t canon_len = mr->mr[1];
+    size_t addr_bytes = (size_t)count * ADDR_WORDS * sizeof(l4_umword_t);
+    if (addr_bytes + canon_len > BR_DATA_BYTES)
+        return EAI_FAIL;
+
+    const l4_umword_t *words = (const l4_umword_t *)(br->br + 1);
+    for (long i = 0; i < count; i++) {
+        const l4_umword_t *a = words + i * ADDR_WORDS;
+        int rc = add_entries(tail, (int)(a[0] >> 16), (const unsigned char *)(a + 1), port, hints);
+        if (rc)
+            return rc;
+    }
+    if (hints->ai_flags & AI_CANONNAME) {
+        *canonname = malloc(canon_len + 1);
+        if (!*canonname)
+            return EAI_MEMORY;
+        memcpy(*canonname, (const char *)words + addr_bytes, canon_len);
+        (*canonname)[canon_len] = '\0';
+    }
+    return 0;
+}
+
+int getaddrinfo(const char *node, const char *service, const struct addrinfo *hints,
+                struct addrinfo **res)
+{
+    static const struct addrinfo no_hints = { .ai_family = AF_UNSPEC };
+    if (!hints)
+        hints = &no_hints;
+    if (!node && !service)
+        return EAI_NONAME;
+    if (hints->ai_family != AF_UNSPEC && hints->ai_family != AF_INET &&
+        hints->ai_family != AF_INET6)
+        return EAI_FAMILY;
+    if (hints->ai_socktype && hints->ai_socktype != SOCK_STREAM &&
+        hints->ai_socktype != SOCK_DGRAM)
+        return EAI_SOCKTYPE;
+
+    in_port_t port;
+    int rc = parse_service(service, hints->ai_flags, &port);
+    if (rc)
+        return rc;
+
+    struct addrinfo *list = NULL, **tail = &list;
+    char *canonname = NULL;
+    unsigned char addr[16] = { 0 };
+    int want4 = hints->ai_family != AF_INET6;
+    int want6 = hints->ai_family != AF_INET;
+
+    if (!node) {
+        /* Wildcard address for AI_PASSIVE, loopback otherwise. */
+        int passive = hints->ai_flags & AI_PASSIVE;
+        if (want4) {
+            in_addr_t any = htonl(passive ? INADDR_ANY : INADDR_LOOPBACK);
+            memcpy(addr, &any, 4);
+            rc = add_entries(&tail, AF_INET, addr, port, hints);
+        }
+        if (!rc && want6) {
+            memcpy(addr, passive ? &in6addr_any : &in6addr_loopback, 16);
+            rc = add_entries(&tail, AF_INET6, addr, port, hints);
+        }
+    } else if (want4 && inet_pton(AF_INET, node, addr) == 1) {
+        rc = add_entries(&tail, AF_INET, addr, port, hints);
+    } else if (want6 && inet_pton(AF_INET6, node, addr) == 1) {
+        rc = add_entries(&tail, AF_INET6, addr, port, hints);
+    } else if (hints->ai_flags & AI_NUMERICHOST) {
+        rc = EAI_NONAME;
+    } else {
+        rc = resolve(node, hints, port, &tail, &canonname);
+    }
+
+    if (!rc && !list)
+        rc = EAI_NONAME;
+    if (rc) {
+        free(canonname);
+        freeaddrinfo(list);
+        return rc;
+    }
+    if (hints->ai_flags & AI_CANONNAME)
+        list->ai_canonname = canonname ? canonname : strdup(node ? node : "");
+    *res = list;
+    return 0;
+}
+
+void freeaddrinfo(struct addrinfo *res)
+{
+    while (res) {
+        struct addrinfo *next = res->ai_next;
+        free(res->ai_canonname);
+        free(res);
+        res = next;
+    }
+}
+
+int getnameinfo(const struct sockaddr *sa, socklen_t salen, char *host, socklen_t hostlen,
+                char *serv, socklen_t servlen, int flags)
+{
+    const unsigned char *addr;
+    in_port_t port;
+    if (!sa)
+        return EAI_FAMILY;
+    if (sa->sa_family == AF_INET && salen >= sizeof(struct sockaddr_in)) {
+        const struct sockaddr_in *sin = (const struct sockaddr_in *)sa;
+        addr = (const unsigned char *)&sin->sin_addr;
+        port = sin->sin_port;
+    } else if (sa->sa_family == AF_INET6 && salen >= sizeof(struct sockaddr_in6)) {
+        const struct sockaddr_in6 *sin6 = (const struct sockaddr_in6 *)sa;
+        addr = (const unsigned char *)&sin6->sin6_addr;
+        port = sin6->sin6_port;
+    } else {
+        return EAI_FAMILY;
+    }
+
+    if (serv && servlen) {
+        /* Services are always numeric. */
+        int n = snprintf(serv, servlen, "%u", (unsigned)ntohs(port));
+        if (n < 0 || (socklen_t)n >= servlen)
+            return EAI_OVERFLOW;
+    }
+    if (!host || !hostlen)
+        return 0;
+
+    if (!(flags & NI_NUMERICHOST) && !ensure_gate()) {
+        l4_msg_regs_t *mr = l4_utcb_mr_w();
+        l4_buf_regs_t *br = l4_utcb_br();
+        unsigned char bytes[16] = { 0 };
+        memcpy(bytes, addr, sa->sa_family == AF_INET ? 4 : 16);
+        mr->mr[0] = OPCODE_GETNAMEINFO;
+        mr->mr[1] = (l4_umword_t)sa->sa_family << 16;
+        memcpy(&mr->mr[2], bytes, 16);
+        mr->mr[4] = (l4_umword_t)(flags & (NI_NOFQDN | NI_NAMEREQD));
+
+        long len = dns_call(5);
+        if (len < 0)
+            return (int)len;
+        if ((size_t)len >= hostlen || (size_t)len > BR_DATA_BYTES)
+            return EAI_OVERFLOW;
+        memcpy(host, br->br + 1, (size_t)len);
+        host[len] = '\0';
+        return 0;
+    }
+    if (flags & NI_NAMEREQD)
+        return EAI_NONAME;
+    if (!inet_ntop(sa->sa_family, addr, host, hostlen))
+        return EAI_OVERFLOW;
+    return 0;
+}
+
+const char *gai_strerror(int errcode)
+{
+    switch (errcode) {
+    case 0: return "Success";
+    case EAI_BADFLAGS: return "Invalid flags";
+    case EAI_NONAME: return "Name or service not known";
+    case EAI_AGAIN: return "Temporary failure in name resolution";
+    case EAI_FAIL: return "Non-recoverable failure in name resolution";
+    case EAI_FAMILY: return "Address family not supported";
+    case EAI_SOCKTYPE: return "Socket type not supported";
+    case EAI_SERVICE: return "Service not supported for socket type";
+    case EAI_MEMORY: return "Memory allocation failure";
+    case EAI_SYSTEM: return "System error";
+    case EAI_OVERFLOW: return "Argument buffer overflow";
+    default: return "Unknown error";
+    }
+}
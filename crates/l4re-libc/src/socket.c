@@ -0,0 +1,909 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <netinet/in.h>
+#include <pthread.h>
+#include <signal.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/socket.h>
+#include <sys/syscall.h>
+#include <sys/time.h>
+#include <sys/uio.h>
+#include <time.h>
+#include <unistd.h>
+#include "sys/eventfd.h"
+#include "sockfd.h"
+#include "ipc.h"
+#include "env.h"
+#include <l4/re/c/util/cap_alloc.h>
+#include <l4/sys/ipc.h>
+#include <l4/sys/utcb.h>
+
+/*
+ * BSD sockets served by net_server through the "global_net" gate.
+ *
+ * The first socket() opens a session: net_server hands the process a gate
+ * of its own, which carries all further requests and keeps its sockets
+ * apart from other processes'. The session lasts until the process exits;
+ * net_server then closes the sockets left behind.
+ *
+ * Every socket owns a placeholder eventfd, so socket descriptors are taken
+ * from the same table as files and never collide with them. A per-process
+ * table maps the descriptor to the net_server handle. O_NONBLOCK is read
+ * from the placeholder, so fcntl() on a socket works as usual.
+ *
+ * net_server never blocks. Blocking calls retry every millisecond until
+ * they succeed or SO_RCVTIMEO/SO_SNDTIMEO expire.
+ *
+ * read(), write(), readv(), writev() and close() are defined here as well:
+ * on a socket they become recv(), send() and a release on the server, on
+ * any other descriptor they go straight to the kernel. A socket whose
+ * descriptor was closed some other way, such as by dup2(), is released on
+ * the server once its descriptor number is handed out again.
+ *
+ * Socket descriptors can be added to epoll sets, see epoll.c.
+ */
+
+#define OP_SOCKET 0
+#define OP_SEND 1
+#define OP_RECV 2
+#define OP_CLOSE 3
+#define OP_BIND 4
+#define OP_LISTEN 5
+#define OP_ACCEPT 6
+#define OP_CONNECT 7
+#define OP_SHUTDOWN 8
+#define OP_SENDTO 9
+#define OP_RECVFROM 10
+#define OP_GETSOCKNAME 11
+#define OP_GETPEERNAME 12
+#define OP_SETSOCKOPT 13
+#define OP_GETSOCKOPT 14
+#define OP_POLL 15
+#define OP_SESSION 16
+
+#define BR_WORDS L4_UTCB_GENERIC_BUFFERS_SIZE
+#define BR_DATA_BYTES ((BR_WORDS - 1) * sizeof(l4_umword_t))
+/* Words of an address: family << 16 | port, then 16 address bytes. */
+#define ADDR_WORDS 3
+
+/* Pause between retries of a blocking call. */
+#define RETRY_NSEC 1000000L
+
+struct sock {
+    int used;
+    l4_umword_t handle;
+    int domain;
+    int type;
+    struct timeval rcvtimeo;
+    struct timeval sndtimeo;
+};
+
+struct epoll_reg {
+    int epfd;
+    int fd;
+    struct epoll_event event;
+    /* Events reported last, for EPOLLET. */
+    uint32_t last;
+    /* Set after an EPOLLONESHOT event until the next EPOLL_CTL_MOD. */
+    int disabled;
+};
+
+/* The session gate. */
+static l4_cap_idx_t net_gate = L4_INVALID_CAP;
+static pthread_mutex_t gate_lock = PTHREAD_MUTEX_INITIALIZER;
+static pthread_mutex_t table_lock = PTHREAD_MUTEX_INITIALIZER;
+static struct sock *socks;
+static int nsocks;
+static struct epoll_reg *regs;
+static int nregs;
+
+/* Receive a session gate from net_server through `gate` into `session`. */
+static int open_session(l4_cap_idx_t gate, l4_cap_idx_t session)
+{
+    l4_utcb_t *utcb = l4_utcb_w();
+    l4_buf_regs_t *br = l4_utcb_br();
+    br->bdr = 0;
+    br->br[0] = session | L4_RCV_ITEM_SINGLE_CAP;
+    l4_utcb_mr_w()->mr[0] = OP_SESSION;
+    l4_msgtag_t tag = l4_ipc_call_w(gate, utcb, l4_msgtag_w(0, 1, 0, 0), L4_IPC_NEVER);
+    /* Leave no receive item behind for later calls. */
+    br->br[0] = 0;
+    if (l4_ipc_error_w(tag, utcb))
+        return EIO;
+    long res = (long)l4_utcb_mr_w()->mr[0];
+    if (res < 0)
+        return (int)-res;
+    return l4_msgtag_items(tag) == 1 ? 0 : EIO;
+}
+
+static int ensure_gate(void)
+{
+    pthread_mutex_lock(&gate_lock);
+    int err = 0;
+    if (l4_is_invalid_cap(net_gate)) {
+        l4_cap_idx_t gate = l4re_env_get_cap_w("global_net");
+        l4_cap_idx_t session = l4re_util_cap_alloc();
+        if (l4_is_invalid_cap(gate))
+            err = ENETDOWN;
+        else if (l4_is_invalid_cap(session))
+            err = ENOMEM;
+        else
+            err = open_session(gate, session);
+        if (!err)
+            net_gate = session;
+        else if (!l4_is_invalid_cap(session))
+            l4re_util_cap_free(session);
+    }
+    pthread_mutex_unlock(&gate_lock);
+    return err;
+}
+
+/* Issue a call with `words` message registers. Returns MR0 of the reply,
+ * -errno on failure. */
+static long net_call(unsigned words)
+{
+    l4_utcb_t *utcb = l4_utcb_w();
+    l4_msgtag_t tag = l4_ipc_call_w(net_gate, utcb, l4_msgtag_w(0, words, 0, 0), L4_IPC_NEVER);
+    if (l4_ipc_error_w(tag, utcb))
+        return -EIO;
+    return (long)l4_utcb_mr_w()->mr[0];
+}
+
+/* Call `op` on `handle` with `nargs` further words in MR2 onwards. */
+static long net_simple(l4_umword_t op, l4_umword_t handle, const l4_umword_t *args, unsigned nargs)
+{
+    l4_msg_regs_t *mr = l4_utcb_mr_w();
+    mr->mr[0] = op;
+    mr->mr[1] = handle;
+    for (unsigned i = 0; i < nargs; i++)
+        mr->mr[2 + i] = args[i];
+    return net_call(2 + nargs);
+}
+
+static int fail(long err)
+{
+    errno = (int)err;
+    return -1;
+}
+
+/* Look up the socket behind `fd`. */
+static int get_sock(int fd, struct sock *out)
+{
+    int found = 0;
+    pthread_mutex_lock(&table_lock);
+    if (fd >= 0 && fd < nsocks && socks[fd].used) {
+        *out = socks[fd];
+        found = 1;
+    }
+    pthread_mutex_unlock(&table_lock);
+    return found;
+}
+
+/* Drop registrations of `fd` as socket (epfd < 0) or as epoll set. */
+static void forget_regs(int fd, int as_epfd)
+{
+    int j = 0;
+    for (int i = 0; i < nregs; i++) {
+        if ((as_epfd ? regs[i].epfd : regs[i].fd) != fd)
+            regs[j++] = regs[i];
+    }
+    nregs = j;
+}
+
+/* Enter `s` under `fd`. A stale entry left behind on the same descriptor
+ * number is released on the server. */
+static int put_sock(int fd, const struct sock *s)
+{
+    l4_umword_t stale = 0;
+    int have_stale = 0;
+
+    pthread_mutex_lock(&table_lock);
+    if (fd >= nsocks) {
+        int n = nsocks ? nsocks : 16;
+        while (n <= fd)
+            n *= 2;
+        struct sock *grown = realloc(socks, (size_t)n * sizeof(*socks));
+        if (!grown) {
+            pthread_mutex_unlock(&table_lock);
+            return ENOMEM;
+        }
+        memset(grown + nsocks, 0, (size_t)(n - nsocks) * sizeof(*socks));
+        socks = grown;
+        nsocks = n;
+    }
+    if (socks[fd].used) {
+        stale = socks[fd].handle;
+        have_stale = 1;
+    }
+    forget_regs(fd, 0);
+    socks[fd] = *s;
+    socks[fd].used = 1;
+    pthread_mutex_unlock(&table_lock);
+
+    if (have_stale)
+        net_simple(OP_CLOSE, stale, NULL, 0);
+    return 0;
+}
+
+static void update_sock(int fd, const struct sock *s)
+{
+    pthread_mutex_lock(&table_lock);
+    if (fd >= 0 && fd < nsocks && socks[fd].used)
+        socks[fd] = *s;
+    pthread_mutex_unlock(&table_lock);
+}
+
+/* Give the new server socket `handle` a descriptor. */
+static int new_fd(l4_umword_t handle, int domain, int type, int flags)
+{
+    int efd_flags = 0;
+    if (flags & SOCK_NONBLOCK)
+        efd_flags |= EFD_NONBLOCK;
+    if (flags & SOCK_CLOEXEC)
+        efd_flags |= EFD_CLOEXEC;
+
+    int fd = eventfd(0, efd_flags);
+    if (fd < 0) {
+        int err = errno;
+        net_simple(OP_CLOSE, handle, NULL, 0);
+        return fail(err);
+    }
+    struct sock s = { .handle = handle, .domain = domain, .type = type };
+    int err = put_sock(fd, &s);
+    if (err) {
+        close(fd);
+        net_simple(OP_CLOSE, handle, NULL, 0);
+        return fail(err);
+    }
+    return fd;
+}
+
+static int is_nonblocking(int fd, int flags)
+{
+    if (flags & MSG_DONTWAIT)
+        return 1;
+    int fl = fcntl(fd, F_GETFL);
+    return fl >= 0 && (fl & O_NONBLOCK);
+}
+
+static int timed_out(const struct timespec *start, const struct timeval *timeout)
+{
+    if (!timeout->tv_sec && !timeout->tv_usec)
+        return 0;
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    long long elapsed_us = (long long)(now.tv_sec - start->tv_sec) * 1000000 +
+                           (now.tv_nsec - start->tv_nsec) / 1000;
+    return elapsed_us >= (long long)timeout->tv_sec * 1000000 + timeout->tv_usec;
+}
+
+/*
+ * Run `op` until it stops failing with EAGAIN, sleeping between attempts.
+ * Gives up at once on a non-blocking call and after `timeout` (zero means
+ * forever) otherwise.
+ */
+#define RETRY(rc, fd, flags, timeout, op)                                      \
+    do {                                                                       \
+        struct timespec start_;                                                \
+        clock_gettime(CLOCK_MONOTONIC, &start_);                               \
+        int nonblock_ = is_nonblocking(fd, flags);                             \
+        for (;;) {                                                             \
+            rc = (op);                                                         \
+            if (rc != -EAGAIN || nonblock_ || timed_out(&start_, timeout))     \
+                break;                                                         \
+            struct timespec pause_ = { 0, RETRY_NSEC };                        \
+            nanosleep(&pause_, NULL);                                          \
+        }                                                                      \
+    } while (0)
+
+/* Encode `sa` for a socket of `domain`. IPv4-mapped IPv6 addresses are
+ * passed as IPv4 addresses. */
+static int encode_addr(const struct sockaddr *sa, socklen_t len, int domain, l4_umword_t *w)
+{
+    unsigned char bytes[16] = { 0 };
+    int family;
+    in_port_t port;
+
+    if (!sa)
+        return EFAULT;
+    if (sa->sa_family == AF_INET && len >= sizeof(struct sockaddr_in)) {
+        const struct sockaddr_in *sin = (const struct sockaddr_in *)sa;
+        family = AF_INET;
+        port = sin->sin_port;
+        memcpy(bytes, &sin->sin_addr, 4);
+    } else if (sa->sa_family == AF_INET6 && len >= sizeof(struct sockaddr_in6)) {
+        const struct sockaddr_in6 *sin6 = (const struct sockaddr_in6 *)sa;
+        port = sin6->sin6_port;
+        if (IN6_IS_ADDR_V4MAPPED(&sin6->sin6_addr)) {
+            family = AF_INET;
+            memcpy(bytes, sin6->sin6_addr.s6_addr + 12, 4);
+        } else {
+            family = AF_INET6;
+            memcpy(bytes, &sin6->sin6_addr, 16);
+        }
+    } else if (sa->sa_family == AF_INET || sa->sa_family == AF_INET6) {
+        return EINVAL;
+    } else {
+        return EAFNOSUPPORT;
+    }
+    if (family == AF_INET6 && domain == AF_INET)
+        return EAFNOSUPPORT;
+
+    w[0] = (l4_umword_t)family << 16 | ntohs(port);
+    memcpy(&w[1], bytes, 16);
+    return 0;
+}
+
+/* Store the address in `w` into `sa`, truncated to *len. IPv4 addresses
+ * are mapped into IPv6 for AF_INET6 sockets. */
+static void decode_addr(const l4_umword_t *w, int domain, struct sockaddr *sa, socklen_t *len)
+{
+    if (!sa || !len)
+        return;
+
+    union {
+        struct sockaddr_in v4;
+        struct sockaddr_in6 v6;
+    } addr;
+    socklen_t size;
+    int family = (int)(w[0] >> 16);
+    in_port_t port = htons((in_port_t)w[0]);
+    const unsigned char *bytes = (const unsigned char *)&w[1];
+
+    memset(&addr, 0, sizeof(addr));
+    if (domain == AF_INET6) {
+        addr.v6.sin6_family = AF_INET6;
+        addr.v6.sin6_port = port;
+        if (family == AF_INET) {
+            addr.v6.sin6_addr.s6_addr[10] = 0xff;
+            addr.v6.sin6_addr.s6_addr[11] = 0xff;
+            memcpy(addr.v6.sin6_addr.s6_addr + 12, bytes, 4);
+        } else {
+            memcpy(&addr.v6.sin6_addr, bytes, 16);
+        }
+        size = sizeof(addr.v6);
+    } else {
+        addr.v4.sin_family = AF_INET;
+        addr.v4.sin_port = port;
+        memcpy(&addr.v4.sin_addr, bytes, 4);
+        size = sizeof(addr.v4);
+    }
+    memcpy(sa, &addr, *len < size ? *len : size);
+    *len = size;
+}
+
+int socket(int domain, int type, int protocol)
+{
+    int flags = type & (SOCK_NONBLOCK | SOCK_CLOEXEC);
+    type &= ~(SOCK_NONBLOCK | SOCK_CLOEXEC);
+
+    if (domain != AF_INET && domain != AF_INET6)
+        return fail(EAFNOSUPPORT);
+    int err = ensure_gate();
+    if (err)
+        return fail(err);
+
+    l4_msg_regs_t *mr = l4_utcb_mr_w();
+    mr->mr[0] = OP_SOCKET;
+    mr->mr[1] = (l4_umword_t)domain;
+    mr->mr[2] = (l4_umword_t)type;
+    mr->mr[3] = (l4_umword_t)protocol;
+    long handle = net_call(4);
+    if (handle < 0)
+        return fail(-handle);
+    return new_fd((l4_umword_t)handle, domain, type, flags);
+}
+
+int bind(int fd, const struct sockaddr *addr, socklen_t len)
+{
+    struct sock s;
+    l4_umword_t w[ADDR_WORDS];
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    int err = encode_addr(addr, len, s.domain, w);
+    if (err)
+        return fail(err);
+    long rc = net_simple(OP_BIND, s.handle, w, ADDR_WORDS);
+    return rc < 0 ? fail(-rc) : 0;
+}
+
+int listen(int fd, int backlog)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    l4_umword_t arg = backlog > 0 ? (l4_umword_t)backlog : 1;
+    long rc = net_simple(OP_LISTEN, s.handle, &arg, 1);
+    return rc < 0 ? fail(-rc) : 0;
+}
+
+int accept4(int fd, struct sockaddr *addr, socklen_t *len, int flags)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    if (flags & ~(SOCK_NONBLOCK | SOCK_CLOEXEC))
+        return fail(EINVAL);
+
+    long rc;
+    RETRY(rc, fd, 0, &s.rcvtimeo, net_simple(OP_ACCEPT, s.handle, NULL, 0));
+    if (rc < 0)
+        return fail(-rc);
+    decode_addr(&l4_utcb_mr_w()->mr[1], s.domain, addr, len);
+    return new_fd((l4_umword_t)rc, s.domain, s.type, flags);
+}
+
+int accept(int fd, struct sockaddr *addr, socklen_t *len)
+{
+    return accept4(fd, addr, len, 0);
+}
+
+/* The outcome of a connection attempt: 0 once established, -EAGAIN while
+ * the handshake runs, -ECONNREFUSED if it failed. */
+static long connect_status(const struct sock *s)
+{
+    l4_umword_t args[2] = { 0, 0 };
+    long rc = net_simple(OP_RECV, s->handle, args, 2);
+    if (rc == -ENOTCONN)
+        return -ECONNREFUSED;
+    return rc < 0 ? rc : 0;
+}
+
+int connect(int fd, const struct sockaddr *addr, socklen_t len)
+{
+    struct sock s;
+    l4_umword_t w[ADDR_WORDS];
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    int err = encode_addr(addr, len, s.domain, w);
+    if (err)
+        return fail(err);
+
+    long rc = net_simple(OP_CONNECT, s.handle, w, ADDR_WORDS);
+    if (rc != -EINPROGRESS)
+        return rc < 0 ? fail(-rc) : 0;
+    if (is_nonblocking(fd, 0))
+        return fail(EINPROGRESS);
+    RETRY(rc, fd, 0, &s.sndtimeo, connect_status(&s));
+    if (rc == -EAGAIN)
+        return fail(EINPROGRESS);
+    return rc < 0 ? fail(-rc) : 0;
+}
+
+int shutdown(int fd, int how)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    l4_umword_t arg = (l4_umword_t)how;
+    long rc = net_simple(OP_SHUTDOWN, s.handle, &arg, 1);
+    return rc < 0 ? fail(-rc) : 0;
+}
+
+/* Send one request of at most BR_DATA_BYTES, to `w` if not NULL. */
+static long send_once(const struct sock *s, const void *buf, size_t len, const l4_umword_t *w)
+{
+    l4_buf_regs_t *br = l4_utcb_br();
+    br->br[0] = len;
+    memcpy(br->br + 1, buf, len);
+    if (!w) {
+        l4_umword_t flags = 0;
+        return net_simple(OP_SEND, s->handle, &flags, 1);
+    }
+    l4_umword_t args[1 + ADDR_WORDS] = { 0 };
+    memcpy(args + 1, w, sizeof(l4_umword_t) * ADDR_WORDS);
+    return net_simple(OP_SENDTO, s->handle, args, 1 + ADDR_WORDS);
+}
+
+/*
+ * Send `len` bytes. Stream data is split into requests; a blocking send
+ * returns once everything has been queued, a non-blocking one after the
+ * first request the server does not take completely. Datagrams must fit
+ * into one request.
+ */
+static ssize_t send_data(int fd, const struct sock *s, const void *buf, size_t len, int flags,
+                         const l4_umword_t *w)
+{
+    size_t sent = 0;
+    long rc = 0;
+
+    if (s->type != SOCK_STREAM && len > BR_DATA_BYTES)
+        return fail(EMSGSIZE);
+    int nonblock = is_nonblocking(fd, flags);
+    do {
+        size_t chunk = len - sent < BR_DATA_BYTES ? len - sent : BR_DATA_BYTES;
+        RETRY(rc, fd, flags, &s->sndtimeo,
+              send_once(s, (const char *)buf + sent, chunk, w));
+        if (rc < 0)
+            break;
+        sent += (size_t)rc;
+        if ((size_t)rc < chunk && nonblock)
+            break;
+    } while (sent < len);
+
+    if (sent)
+        return (ssize_t)sent;
+    if (rc == -EPIPE && !(flags & MSG_NOSIGNAL))
+        raise(SIGPIPE);
+    return rc < 0 ? fail(-rc) : 0;
+}
+
+ssize_t sendto(int fd, const void *buf, size_t len, int flags, const struct sockaddr *addr,
+               socklen_t addrlen)
+{
+    struct sock s;
+    l4_umword_t w[ADDR_WORDS];
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    if (!addr || s.type == SOCK_STREAM)
+        return send_data(fd, &s, buf, len, flags, NULL);
+    int err = encode_addr(addr, addrlen, s.domain, w);
+    if (err)
+        return fail(err);
+    return send_data(fd, &s, buf, len, flags, w);
+}
+
+ssize_t send(int fd, const void *buf, size_t len, int flags)
+{
+    return sendto(fd, buf, len, flags, NULL, 0);
+}
+
+/* Receive one request of at most BR_DATA_BYTES; the source address is
+ * left in MR1..MR3. */
+static long recv_once(const struct sock *s, void *buf, size_t len, int flags)
+{
+    l4_umword_t args[2] = { len, (l4_umword_t)(flags & MSG_PEEK) };
+    long rc = net_simple(OP_RECVFROM, s->handle, args, 2);
+    if (rc <= 0)
+        return rc;
+    l4_buf_regs_t *br = l4_utcb_br();
+    size_t n = br->br[0] < (size_t)rc ? br->br[0] : (size_t)rc;
+    memcpy(buf, br->br + 1, n);
+    return (long)n;
+}
+
+ssize_t recvfrom(int fd, void *buf, size_t len, int flags, struct sockaddr *addr,
+                 socklen_t *addrlen)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+
+    size_t got = 0;
+    long rc;
+    do {
+        size_t chunk = len - got < BR_DATA_BYTES ? len - got : BR_DATA_BYTES;
+        RETRY(rc, fd, flags, &s.rcvtimeo, recv_once(&s, (char *)buf + got, chunk, flags));
+        if (rc <= 0)
+            break;
+        if (!got)
+            decode_addr(&l4_utcb_mr_w()->mr[1], s.domain, addr, addrlen);
+        got += (size_t)rc;
+        /* MSG_WAITALL keeps reading a stream until the buffer is full. */
+    } while ((flags & MSG_WAITALL) && !(flags & MSG_PEEK) && s.type == SOCK_STREAM &&
+             got < len);
+
+    if (got)
+        return (ssize_t)got;
+    return rc < 0 ? fail(-rc) : 0;
+}
+
+ssize_t recv(int fd, void *buf, size_t len, int flags)
+{
+    return recvfrom(fd, buf, len, flags, NULL, NULL);
+}
+
+ssize_t sendmsg(int fd, const struct msghdr *msg, int flags)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    if (msg->msg_controllen)
+        return fail(EOPNOTSUPP);
+
+    size_t len = 0;
+    for (size_t i = 0; i < (size_t)msg->msg_iovlen; i++)
+        len += msg->msg_iov[i].iov_len;
+    char *buf = malloc(len ? len : 1);
+    if (!buf)
+        return fail(ENOMEM);
+    size_t off = 0;
+    for (size_t i = 0; i < (size_t)msg->msg_iovlen; i++) {
+        memcpy(buf + off, msg->msg_iov[i].iov_base, msg->msg_iov[i].iov_len);
+        off += msg->msg_iov[i].iov_len;
+    }
+    ssize_t rc = sendto(fd, buf, len, flags, msg->msg_name, msg->msg_namelen);
+    free(buf);
+    return rc;
+}
+
+ssize_t recvmsg(int fd, struct msghdr *msg, int flags)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+
+    size_t len = 0;
+    for (size_t i = 0; i < (size_t)msg->msg_iovlen; i++)
+        len += msg->msg_iov[i].iov_len;
+    char *buf = malloc(len ? len : 1);
+    if (!buf)
+        return fail(ENOMEM);
+    ssize_t rc = recvfrom(fd, buf, len, flags, msg->msg_name,
+                          msg->msg_name ? &msg->msg_namelen : NULL);
+    size_t off = 0;
+    for (size_t i = 0; rc > 0 && i < (size_t)msg->msg_iovlen && off < (size_t)rc; i++) {
+        size_t n = msg->msg_iov[i].iov_len;
+        if (n > (size_t)rc - off)
+            n = (size_t)rc - off;
+        memcpy(msg->msg_iov[i].iov_base, buf + off, n);
+        off += n;
+    }
+    free(buf);
+    msg->msg_controllen = 0;
+    msg->msg_flags = 0;
+    return rc;
+}
+
+static int get_name(int fd, l4_umword_t op, struct sockaddr *addr, socklen_t *len)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+    long rc = net_simple(op, s.handle, NULL, 0);
+    if (rc < 0)
+        return fail(-rc);
+    decode_addr(&l4_utcb_mr_w()->mr[1], s.domain, addr, len);
+    return 0;
+}
+
+int getsockname(int fd, struct sockaddr *addr, socklen_t *len)
+{
+    return get_name(fd, OP_GETSOCKNAME, addr, len);
+}
+
+int getpeername(int fd, struct sockaddr *addr, socklen_t *len)
+{
+    return get_name(fd, OP_GETPEERNAME, addr, len);
+}
+
+/*
+ * SO_RCVTIMEO and SO_SNDTIMEO are kept here, all other options travel to
+ * net_server as integers.
+ */
+int setsockopt(int fd, int level, int name, const void *value, socklen_t len)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+
+    if (level == SOL_SOCKET && (name == SO_RCVTIMEO || name == SO_SNDTIMEO)) {
+        if (len < sizeof(struct timeval))
+            return fail(EINVAL);
+        struct timeval tv;
+        memcpy(&tv, value, sizeof(tv));
+        if (tv.tv_sec < 0 || tv.tv_usec < 0 || tv.tv_usec >= 1000000)
+            return fail(EDOM);
+        if (name == SO_RCVTIMEO)
+            s.rcvtimeo = tv;
+        else
+            s.sndtimeo = tv;
+        update_sock(fd, &s);
+        return 0;
+    }
+
+    if (len < sizeof(int))
+        return fail(EINVAL);
+    int v;
+    memcpy(&v, value, sizeof(v));
+    l4_umword_t args[3] = { (l4_umword_t)level, (l4_umword_t)name, (l4_umword_t)v };
+    long rc = net_simple(OP_SETSOCKOPT, s.handle, args, 3);
+    return rc < 0 ? fail(-rc) : 0;
+}
+
+/*
+ * Besides the options of setsockopt() this answers SO_ERROR, which reports
+ * a refused connection once, and SO_DOMAIN.
+ */
+int getsockopt(int fd, int level, int name, void *value, socklen_t *len)
+{
+    struct sock s;
+    if (!get_sock(fd, &s))
+        return fail(ENOTSOCK);
+
+    if (level == SOL_SOCKET && (name == SO_RCVTIMEO || name == SO_SNDTIMEO)) {
+        const struct timeval *tv = name == SO_RCVTIMEO ? &s.rcvtimeo : &s.sndtimeo;
+        socklen_t n = *len < sizeof(*tv) ? *len : sizeof(*tv);
+        memcpy(value, tv, n);
+        *len = n;
+        return 0;
+    }
+
+    int v;
+    if (level == SOL_SOCKET && name == SO_ERROR) {
+        v = 0;
+        if (s.type == SOCK_STREAM && connect_status(&s) == -ECONNREFUSED)
+            v = ECONNREFUSED;
+    } else if (level == SOL_SOCKET && name == SO_DOMAIN) {
+        v = s.domain;
+    } else {
+        l4_umword_t args[2] = { (l4_umword_t)level, (l4_umword_t)name };
+        long rc = net_simple(OP_GETSOCKOPT, s.handle, args, 2);
+        if (rc < 0)
+            return fail(-rc);
+        v = (int)l4_utcb_mr_w()->mr[1];
+    }
+    socklen_t n = *len < sizeof(v) ? *len : sizeof(v);
+    memcpy(value, &v, n);
+    *len = n;
+    return 0;
+}
+
+int l4re_sock_is_socket(int fd)
+{
+    struct sock s;
+    return get_sock(fd, &s);
+}
+
+static struct epoll_reg *find_reg(int epfd, int fd)
+{
+    for (int i = 0; i < nregs; i++)
+        if (regs[i].epfd == epfd && regs[i].fd == fd)
+            return &regs[i];
+    return NULL;
+}
+
+int l4re_sock_epoll_ctl(int epfd, int op, int fd, struct epoll_event *event)
+{
+    int err = 0;
+    pthread_mutex_lock(&table_lock);
+    struct epoll_reg *reg = find_reg(epfd, fd);
+    switch (op) {
+    case EPOLL_CTL_ADD:
+        if (reg) {
+            err = EEXIST;
+        } else if (!event) {
+            err = EFAULT;
+        } else {
+            struct epoll_reg *grown = realloc(regs, (size_t)(nregs + 1) * sizeof(*regs));
+            if (!grown) {
+                err = ENOMEM;
+                break;
+            }
+            regs = grown;
+            regs[nregs++] = (struct epoll_reg){ .epfd = epfd, .fd = fd, .event = *event };
+        }
+        break;
+    case EPOLL_CTL_MOD:
+        if (!reg) {
+            err = ENOENT;
+        } else if (!event) {
+            err = EFAULT;
+        } else {
+            reg->event = *event;
+            reg->last = 0;
+            reg->disabled = 0;
+        }
+        break;
+    case EPOLL_CTL_DEL:
+        if (!reg)
+            err = ENOENT;
+        else
+            *reg = regs[--nregs];
+        break;
+    default:
+        err = EINVAL;
+    }
+    pthread_mutex_unlock(&table_lock);
+    return -err;
+}
+
+int l4re_sock_epoll_watches(int epfd)
+{
+    int found = 0;
+    pthread_mutex_lock(&table_lock);
+    for (int i = 0; i < nregs && !found; i++)
+        found = regs[i].epfd == epfd;
+    pthread_mutex_unlock(&table_lock);
+    return found;
+}
+
+int l4re_sock_epoll_ready(int epfd, struct epoll_event *events, int maxevents)
+{
+    int n = 0;
+    pthread_mutex_lock(&table_lock);
+    for (int i = 0; i < nregs && n < maxevents; i++) {
+        struct epoll_reg *reg = &regs[i];
+        if (reg->epfd != epfd || reg->disabled)
+            continue;
+        if (reg->fd >= nsocks || !socks[reg->fd].used)
+            continue;
+        long ready = net_simple(OP_POLL, socks[reg->fd].handle, NULL, 0);
+        if (ready < 0)
+            continue;
+        /* Errors and hang-ups are always reported. */
+        uint32_t mask = reg->event.events | EPOLLERR | EPOLLHUP;
+        uint32_t report = (uint32_t)ready & mask;
+        if (reg->event.events & EPOLLET) {
+            uint32_t fresh = report & ~reg->last;
+            reg->last = report;
+            report = fresh;
+        }
+        if (!report)
+            continue;
+        if (reg->event.events & EPOLLONESHOT)
+            reg->disabled = 1;
+        events[n].events = report;
+        events[n].data = reg->event.data;
+        n++;
+    }
+    pthread_mutex_unlock(&table_lock);
+    return n;
+}
+
+void l4re_sock_epoll_forget(int epfd)
+{
+    pthread_mutex_lock(&table_lock);
+    forget_regs(epfd, 1);
+    pthread_mutex_unlock(&table_lock);
+}
+
+/* Take the socket behind `fd` out of the table. */
+static int take_sock(int fd, struct sock *out)
+{
+    int found = 0;
+    pthread_mutex_lock(&table_lock);
+    if (fd >= 0 && fd < nsocks && socks[fd].used) {
+        *out = socks[fd];
+        socks[fd].used = 0;
+        forget_regs(fd, 0);
+        found = 1;
+    }
+    pthread_mutex_unlock(&table_lock);
+    return found;
+}
+
+ssize_t read(int fd, void *buf, size_t len)
+{
+    if (l4re_sock_is_socket(fd))
+        return recv(fd, buf, len, 0);
+    return syscall(SYS_read, fd, buf, len);
+}
+
+ssize_t write(int fd, const void *buf, size_t len)
+{
+    if (l4re_sock_is_socket(fd))
+        return send(fd, buf, len, 0);
+    return syscall(SYS_write, fd, buf, len);
+}
+
+ssize_t readv(int fd, const struct iovec *iov, int iovcnt)
+{
+    if (l4re_sock_is_socket(fd)) {
+        struct msghdr msg = { .msg_iov = (struct iovec *)iov, .msg_iovlen = iovcnt };
+        return recvmsg(fd, &msg, 0);
+    }
+    return syscall(SYS_readv, fd, iov, iovcnt);
+}
+
+ssize_t writev(int fd, const struct iovec *iov, int iovcnt)
+{
+    if (l4re_sock_is_socket(fd)) {
+        struct msghdr msg = { .msg_iov = (struct iovec *)iov, .msg_iovlen = iovcnt };
+        return sendmsg(fd, &msg, 0);
+    }
+    return syscall(SYS_writev, fd, iov, iovcnt);
+}
+
+/* Closing a socket releases it on the server and then the placeholder. */
+int close(int fd)
+{
+    struct sock s;
+    long rc = 0;
+    if (take_sock(fd, &s))
+        rc = net_simple(OP_CLOSE, s.handle, NULL, 0);
+    if (syscall(SYS_close, fd) < 0)
+        return -1;
+    return rc < 0 ? fail(-rc) : 0;
+}
@@ -0,0 +1,21 @@
+#pragma once
+
+/* Interface between socket.c and epoll.c. */
+
+#include "sys/epoll.h"
+
+/* Whether `fd` is a socket served by net_server. */
+int l4re_sock_is_socket(int fd);
+
+/* epoll_ctl() for a socket descriptor. Returns 0 or -errno. */
+int l4re_sock_epoll_ctl(int epfd, int op, int fd, struct epoll_event *event);
+
+/* Whether `epfd` watches any socket. */
+int l4re_sock_epoll_watches(int epfd);
+
+/* Store up to `maxevents` events of the sockets watched by `epfd`. Returns
+ * the number of events. */
+int l4re_sock_epoll_ready(int epfd, struct epoll_event *events, int maxevents);
+
+/* Forget the socket registrations of an epoll descriptor that was closed. */
+void l4re_sock_epoll_forget(int epfd);
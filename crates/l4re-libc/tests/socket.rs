@@ -0,0 +1,98 @@
+use libc::{self, c_void, iovec, sockaddr, sockaddr_in, socklen_t};
+use std::mem;
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap()
+}
+
+/// A connected pair of TCP sockets on the loopback address, or None without
+/// net_server.
+unsafe fn tcp_pair() -> Option<(i32, i32)> {
+    let listener = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+    if listener < 0 {
+        assert_eq!(errno(), libc::ENETDOWN);
+        return None;
+    }
+    let mut addr: sockaddr_in = mem::zeroed();
+    addr.sin_family = libc::AF_INET as _;
+    addr.sin_addr.s_addr = u32::from_be_bytes([127, 0, 0, 1]).to_be();
+    let mut len = mem::size_of::<sockaddr_in>() as socklen_t;
+    let sa = &mut addr as *mut sockaddr_in as *mut sockaddr;
+    assert_eq!(0, libc::bind(listener, sa, len));
+    assert_eq!(0, libc::listen(listener, 1));
+    assert_eq!(0, libc::getsockname(listener, sa, &mut len));
+
+    let client = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+    assert!(client >= 0);
+    assert_eq!(0, libc::connect(client, sa, len));
+    let server = libc::accept(listener, std::ptr::null_mut(), std::ptr::null_mut());
+    assert!(server >= 0);
+    assert_eq!(0, libc::close(listener));
+    Some((client, server))
+}
+
+#[test]
+fn socket_read_write_close() {
+    unsafe {
+        let Some((client, server)) = tcp_pair() else {
+            eprintln!("no global_net gate, skipping");
+            return;
+        };
+
+        let ptr = b"ping".as_ptr() as *const c_void;
+        assert_eq!(4, libc::write(client, ptr, 4));
+        let mut buf = [0u8; 16];
+        let n = libc::read(server, buf.as_mut_ptr() as *mut c_void, buf.len());
+        assert_eq!(&buf[..n as usize], b"ping");
+
+        let (head, tail) = (b"po", b"ng");
+        let iov = [
+            iovec { iov_base: head.as_ptr() as *mut c_void, iov_len: 2 },
+            iovec { iov_base: tail.as_ptr() as *mut c_void, iov_len: 2 },
+        ];
+        assert_eq!(4, libc::writev(server, iov.as_ptr(), 2));
+        let (mut first, mut second) = ([0u8; 1], [0u8; 8]);
+        let iov = [
+            iovec { iov_base: first.as_mut_ptr() as *mut c_void, iov_len: 1 },
+            iovec { iov_base: second.as_mut_ptr() as *mut c_void, iov_len: 8 },
+        ];
+        assert_eq!(4, libc::readv(client, iov.as_ptr(), 2));
+        assert_eq!(&first, b"p");
+        assert_eq!(&second[..3], b"ong");
+
+        // close() ends the connection, the peer reads the end of stream.
+        assert_eq!(0, libc::close(client));
+        assert_eq!(0, libc::read(server, buf.as_mut_ptr() as *mut c_void, buf.len()));
+        assert_eq!(0, libc::close(server));
+        assert_eq!(-1, libc::send(server, ptr, 4, 0));
+        assert_eq!(errno(), libc::ENOTSOCK);
+    }
+}
+
+#[test]
+fn pipe_read_write_close() {
+    unsafe {
+        let mut fds = [0; 2];
+        assert_eq!(0, libc::pipe(fds.as_mut_ptr()));
+
+        let (head, tail) = (b"ab", b"cd");
+        let iov = [
+            iovec { iov_base: head.as_ptr() as *mut c_void, iov_len: 2 },
+            iovec { iov_base: tail.as_ptr() as *mut c_void, iov_len: 2 },
+        ];
+        assert_eq!(4, libc::writev(fds[1], iov.as_ptr(), 2));
+        assert_eq!(1, libc::write(fds[1], b"e".as_ptr() as *const c_void, 1));
+        let mut first = [0u8; 3];
+        let iov = [iovec { iov_base: first.as_mut_ptr() as *mut c_void, iov_len: 3 }];
+        assert_eq!(3, libc::readv(fds[0], iov.as_ptr(), 1));
+        assert_eq!(&first, b"abc");
+        assert_eq!(0, libc::close(fds[1]));
+
+        let mut buf = [0u8; 8];
+        assert_eq!(2, libc::read(fds[0], buf.as_mut_ptr() as *mut c_void, buf.len()));
+        assert_eq!(&buf[..2], b"de");
+        assert_eq!(0, libc::read(fds[0], buf.as_mut_ptr() as *mut c_void, buf.len()));
+        assert_eq!(0, libc::close(fds[0]));
+        assert_eq!(-1, libc::close(fds[0]));
+    }
+}
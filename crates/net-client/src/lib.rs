@@ -1,140 +1,500 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-//! Simple client library for the `global_net` network service.
+//! Client library for the `global_net` network service.
 //!
 //! # Message register layout
 //!
-//! All operations use the first message register (`MR0`) to denote the
-//! operation code. Additional registers carry operation specific
-//! arguments as documented below:
+//! `MR0` carries the operation code on requests and the result on replies.
+//! Negative results are `-errno`. Payloads are exchanged through the buffer
+//! registers: `BR0` holds the payload length in bytes, the data follows
+//! from `BR1` onwards.
 //!
 //! ```text
-//! MR0: operation code
+//! Socket (OP_SOCKET)        MR1: domain, MR2: type, MR3: protocol
+//!                                                      Reply: MR0 = handle
+//! Send (OP_SEND)            MR1: handle, MR2: flags, BRs: payload
+//!                                                      Reply: MR0 = bytes
+//! Receive (OP_RECV)         MR1: handle, MR2: capacity, MR3: flags
+//!                                                      Reply: MR0 = bytes, BRs: payload
+//! Close (OP_CLOSE)          MR1: handle
+//! Bind (OP_BIND)            MR1: handle, MR2..MR4: address
+//! Listen (OP_LISTEN)        MR1: handle, MR2: backlog
+//! Accept (OP_ACCEPT)        MR1: handle                Reply: MR0 = handle, MR1..MR3: peer
+//! Connect (OP_CONNECT)      MR1: handle, MR2..MR4: address
+//! Shutdown (OP_SHUTDOWN)    MR1: handle, MR2: how
+//! Send to (OP_SENDTO)       MR1: handle, MR2: flags, MR3..MR5: address, BRs: payload
+//!                                                      Reply: MR0 = bytes
+//! Receive from (OP_RECVFROM) MR1: handle, MR2: capacity, MR3: flags
+//!                                                      Reply: MR0 = bytes, MR1..MR3: source,
+//!                                                      BRs: payload
+//! Local name (OP_GETSOCKNAME)  MR1: handle             Reply: MR1..MR3: address
+//! Peer name (OP_GETPEERNAME)   MR1: handle             Reply: MR1..MR3: address
+//! Set option (OP_SETSOCKOPT)   MR1: handle, MR2: level, MR3: option, MR4: value
+//! Get option (OP_GETSOCKOPT)   MR1: handle, MR2: level, MR3: option
+//!                                                      Reply: MR1 = value
+//! Poll (OP_POLL)            MR1: handle                Reply: MR0 = ready events
+//! Session (OP_SESSION)                                 Reply: maps the session gate
+//! Close session (OP_SESSION_CLOSE)
+//! ```
 //!
-//! Socket open (OP_OPEN)
-//!   MR1: protocol (0 = UDP)
-//!   Reply: MR0 = socket handle
+//! Socket requests go through a session gate of the client's own, which
+//! [`NetClient::new`] obtains from the `global_net` gate with `OP_SESSION`.
+//! Sockets belong to the session: dropping the [`NetClient`] closes the
+//! session and with it all sockets still open.
 //!
-//! Socket send (OP_SEND)
-//!   MR1: socket handle
-//!   MR2: length in bytes
-//!   MR3..: payload (truncated to available registers)
-//!   Reply: MR0 = status (0 = ok)
+//! An address takes three registers, see [`SockAddr`]. Sockets never block:
+//! operations that would have to wait fail with [`EAGAIN`], a stream
+//! `connect` reports [`EINPROGRESS`] while the handshake is running.
 //!
-//! Socket receive (OP_RECV)
-//!   MR1: socket handle
-//!   MR2: buffer capacity
-//!   Reply: MR0 = bytes received
-//!   MR1..: payload
+//! With the `std` feature the crate also offers `TcpStream`, `TcpListener`
+//! and `UdpSocket`, which mirror their `std::net` namesakes: they wait for
+//! the service, split large writes into requests and report errors as
+//! `std::io::Error`.
 //!
-//! Socket close (OP_CLOSE)
-//!   MR1: socket handle
-//!   Reply: MR0 = status (0 = ok)
-//! ```
+//! Ping sockets are `SOCK_DGRAM` sockets with protocol [`IPPROTO_ICMP`] or
+//! [`IPPROTO_ICMPV6`]; they carry ICMP echo messages and the service fills
+//! in their identifier and checksum. [`SOCK_RAW`] sockets exchange whole IP
+//! packets and are only available to sessions opened by
+//! [`NetClient::admin`].
 //!
-//! The functions provided here implement these operations for small
-//! messages entirely transmitted via the message registers. They are
-//! intended as a starting point for more fully fledged networking
-//! support.
+//! The vsock service speaks the same protocol for [`AF_VSOCK`] stream
+//! sockets to the host; [`NetClient::vsock`] connects to it and
+//! [`SockAddr::vsock`] builds its addresses. With `std`, `VsockStream` and
+//! `VsockListener` wrap them like their TCP counterparts.
 
+use core::cmp::min;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use l4::sys::{
+    l4_ipc_call, l4_ipc_error, l4_is_invalid_cap, l4_msg_item_consts_t, l4_msgtag, l4_msgtag_items,
+    l4_utcb, l4_utcb_br, l4_utcb_mr, l4re_util_cap_alloc, l4re_util_cap_free,
+};
 use l4re::sys::l4re_env_get_cap;
-use l4::sys::{l4_ipc_call, l4_ipc_error, l4_msgtag, l4_utcb};
 
-/// Operation code: open a socket.
-pub const OP_OPEN: u64 = 0;
-/// Operation code: send data.
+#[cfg(feature = "std")]
+mod net;
+#[cfg(feature = "std")]
+pub use net::{Incoming, TcpListener, TcpStream, UdpSocket, VsockListener, VsockStream};
+
+/// Operation code: create a socket.
+pub const OP_SOCKET: u64 = 0;
+/// Operation code: send on a connected socket.
 pub const OP_SEND: u64 = 1;
 /// Operation code: receive data.
 pub const OP_RECV: u64 = 2;
 /// Operation code: close a socket.
 pub const OP_CLOSE: u64 = 3;
+/// Operation code: assign a local address.
+pub const OP_BIND: u64 = 4;
+/// Operation code: accept connections.
+pub const OP_LISTEN: u64 = 5;
+/// Operation code: take a pending connection.
+pub const OP_ACCEPT: u64 = 6;
+/// Operation code: connect to a remote address.
+pub const OP_CONNECT: u64 = 7;
+/// Operation code: shut down one or both directions.
+pub const OP_SHUTDOWN: u64 = 8;
+/// Operation code: send to an explicit address.
+pub const OP_SENDTO: u64 = 9;
+/// Operation code: receive data and its source address.
+pub const OP_RECVFROM: u64 = 10;
+/// Operation code: query the local address.
+pub const OP_GETSOCKNAME: u64 = 11;
+/// Operation code: query the peer address.
+pub const OP_GETPEERNAME: u64 = 12;
+/// Operation code: set a socket option.
+pub const OP_SETSOCKOPT: u64 = 13;
+/// Operation code: read a socket option.
+pub const OP_GETSOCKOPT: u64 = 14;
+/// Operation code: query the events ready on a socket.
+pub const OP_POLL: u64 = 15;
+/// Operation code: open a session.
+pub const OP_SESSION: u64 = 16;
+/// Operation code: close the session and its sockets.
+pub const OP_SESSION_CLOSE: u64 = 17;
+
+pub const AF_INET: i32 = 2;
+pub const AF_INET6: i32 = 10;
+pub const AF_VSOCK: i32 = 40;
+pub const SOCK_STREAM: i32 = 1;
+pub const SOCK_DGRAM: i32 = 2;
+pub const SOCK_RAW: i32 = 3;
+
+pub const IPPROTO_ICMP: i32 = 1;
+pub const IPPROTO_UDP: i32 = 17;
+pub const IPPROTO_ICMPV6: i32 = 58;
+
+/// Any CID, when binding a vsock socket.
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+/// The CID of the host.
+pub const VMADDR_CID_HOST: u32 = 2;
+/// Any port, when binding a vsock socket.
+pub const VMADDR_PORT_ANY: u32 = u32::MAX;
+
+pub const SHUT_RD: i32 = 0;
+pub const SHUT_WR: i32 = 1;
+pub const SHUT_RDWR: i32 = 2;
+
+pub const MSG_PEEK: i32 = 2;
+
+pub const SOL_SOCKET: i32 = 1;
+pub const SO_REUSEADDR: i32 = 2;
+pub const SO_TYPE: i32 = 3;
+pub const SO_SNDBUF: i32 = 7;
+pub const SO_RCVBUF: i32 = 8;
+pub const IPPROTO_TCP: i32 = 6;
+pub const TCP_NODELAY: i32 = 1;
+
+pub const EIO: i32 = 5;
+pub const EAGAIN: i32 = 11;
+pub const ENOTCONN: i32 = 107;
+pub const EINPROGRESS: i32 = 115;
+
+const BR_WORDS: usize = l4::sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
+/// Largest payload carried by a single request.
+pub const BR_DATA_MAX: usize = BR_WORDS * 8 - 8;
+
+/// Socket address as carried in three message registers:
+/// `family << 16 | port`, followed by the 16 address bytes in network order.
+/// IPv4 addresses use the first four bytes. `AF_INET6` sockets accept
+/// both families and report IPv4 peers as `AF_INET`. Vsock addresses have
+/// no IP port; the CID and the port fill the two address words.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SockAddr {
+    pub family: i32,
+    pub port: u16,
+    pub addr: [u8; 16],
+}
+
+impl SockAddr {
+    /// IPv4 address `a.b.c.d:port`.
+    pub const fn v4(octets: [u8; 4], port: u16) -> Self {
+        let mut addr = [0u8; 16];
+        addr[0] = octets[0];
+        addr[1] = octets[1];
+        addr[2] = octets[2];
+        addr[3] = octets[3];
+        SockAddr {
+            family: AF_INET,
+            port,
+            addr,
+        }
+    }
+
+    /// IPv6 address `[addr]:port`.
+    pub const fn v6(addr: [u8; 16], port: u16) -> Self {
+        SockAddr {
+            family: AF_INET6,
+            port,
+            addr,
+        }
+    }
+
+    /// Vsock address `cid:port`.
+    pub const fn vsock(cid: u32, port: u32) -> Self {
+        let cid = (cid as u64).to_ne_bytes();
+        let port = (port as u64).to_ne_bytes();
+        let mut addr = [0u8; 16];
+        let mut i = 0;
+        while i < 8 {
+            addr[i] = cid[i];
+            addr[8 + i] = port[i];
+            i += 1;
+        }
+        SockAddr {
+            family: AF_VSOCK,
+            port: 0,
+            addr,
+        }
+    }
+
+    /// CID and port of a vsock address.
+    pub fn vsock_addr(&self) -> Option<(u32, u32)> {
+        if self.family != AF_VSOCK {
+            return None;
+        }
+        let mut cid = [0u8; 8];
+        let mut port = [0u8; 8];
+        cid.copy_from_slice(&self.addr[..8]);
+        port.copy_from_slice(&self.addr[8..]);
+        Some((
+            u64::from_ne_bytes(cid) as u32,
+            u64::from_ne_bytes(port) as u32,
+        ))
+    }
+
+    fn encode(&self, words: &mut [u64]) {
+        let mut lo = [0u8; 8];
+        let mut hi = [0u8; 8];
+        lo.copy_from_slice(&self.addr[..8]);
+        hi.copy_from_slice(&self.addr[8..]);
+        words[0] = (self.family as u64) << 16 | self.port as u64;
+        words[1] = u64::from_ne_bytes(lo);
+        words[2] = u64::from_ne_bytes(hi);
+    }
+
+    fn decode(words: &[u64]) -> Self {
+        let mut addr = [0u8; 16];
+        addr[..8].copy_from_slice(&words[1].to_ne_bytes());
+        addr[8..].copy_from_slice(&words[2].to_ne_bytes());
+        SockAddr {
+            family: (words[0] >> 16) as i32,
+            port: words[0] as u16,
+            addr,
+        }
+    }
+}
+
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> Self {
+        match addr.ip() {
+            IpAddr::V4(ip) => SockAddr::v4(ip.octets(), addr.port()),
+            IpAddr::V6(ip) => SockAddr::v6(ip.octets(), addr.port()),
+        }
+    }
+}
+
+impl From<SockAddr> for SocketAddr {
+    fn from(addr: SockAddr) -> Self {
+        let ip = if addr.family == AF_INET6 {
+            IpAddr::V6(Ipv6Addr::from(addr.addr))
+        } else {
+            let [a, b, c, d, ..] = addr.addr;
+            IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+        };
+        SocketAddr::new(ip, addr.port)
+    }
+}
 
 /// Client handle to the network service.
 pub struct NetClient {
+    /// Our session gate, closed on drop.
     gate: l4re::sys::l4_cap_idx_t,
 }
 
+unsafe fn br_write(data: &[u8]) {
+    let br = &mut (*l4_utcb_br()).br;
+    let len = min(data.len(), BR_DATA_MAX);
+    br[0] = len as u64;
+    core::ptr::copy_nonoverlapping(data.as_ptr(), br.as_mut_ptr().add(1) as *mut u8, len);
+}
+
+unsafe fn br_read(buf: &mut [u8], expected: usize) -> usize {
+    let br = &(*l4_utcb_br()).br;
+    let len = min(min(br[0] as usize, BR_DATA_MAX), min(buf.len(), expected));
+    core::ptr::copy_nonoverlapping(br.as_ptr().add(1) as *const u8, buf.as_mut_ptr(), len);
+    len
+}
+
+/// Address returned in `MR1..MR3` of a reply.
+unsafe fn reply_addr() -> SockAddr {
+    let mr = &(*l4_utcb_mr()).mr;
+    SockAddr::decode(&mr[1..4])
+}
+
 impl NetClient {
-    /// Retrieve the `global_net` capability from the environment.
+    /// Open a session through the `global_net` gate of the environment.
     pub fn new() -> Option<Self> {
-        l4re_env_get_cap("global_net").map(|gate| NetClient { gate })
+        unsafe { Self::open_session(l4re_env_get_cap("global_net")?) }
     }
 
-    /// Request a UDP socket from the server.
-    pub fn open_socket(&self) -> Result<u64, i32> {
-        unsafe {
-            (*l4::sys::l4_utcb_mr()).mr[0] = OP_OPEN;
-            (*l4::sys::l4_utcb_mr()).mr[1] = 0; // protocol: UDP
-            let tag = l4_ipc_call(
-                self.gate,
-                l4_utcb(),
-                l4_msgtag(0, 2, 0, 0),
-                l4::sys::l4_timeout_t { raw: 0 },
-            );
-            let err = l4_ipc_error(tag, l4_utcb());
-            if err != 0 {
-                return Err(err);
-            }
-            Ok((*l4::sys::l4_utcb_mr()).mr[0])
-        }
-    }
-
-    /// Send a single word of data to the server.
-    pub fn send(&self, handle: u64, word: u64) -> Result<(), i32> {
+    /// Open a session through the `net_admin` gate, which may also change
+    /// the interface configuration and create raw sockets.
+    pub fn admin() -> Option<Self> {
+        unsafe { Self::open_session(l4re_env_get_cap("net_admin")?) }
+    }
+
+    /// Open a session through the `global_vsock` gate of the vsock
+    /// service, which takes `AF_VSOCK` stream sockets through the same
+    /// calls.
+    pub fn vsock() -> Option<Self> {
+        unsafe { Self::open_session(l4re_env_get_cap("global_vsock")?) }
+    }
+
+    /// Ask the service behind `gate` for a session gate of our own.
+    unsafe fn open_session(gate: l4re::sys::l4_cap_idx_t) -> Option<Self> {
+        let session = l4re_util_cap_alloc();
+        if l4_is_invalid_cap(session) {
+            return None;
+        }
+        let br = l4_utcb_br();
+        (*br).bdr = 0;
+        (*br).br[0] = session | l4_msg_item_consts_t::L4_RCV_ITEM_SINGLE_CAP as u64;
+        (*l4_utcb_mr()).mr[0] = OP_SESSION;
+        let tag = l4_ipc_call(
+            gate,
+            l4_utcb(),
+            l4_msgtag(0, 1, 0, 0),
+            l4::sys::l4_timeout_t { raw: 0 },
+        );
+        // Leave no receive item behind for later calls.
+        (*br).br[0] = 0;
+        if l4_ipc_error(tag, l4_utcb()) != 0
+            || (*l4_utcb_mr()).mr[0] != 0
+            || l4_msgtag_items(tag) != 1
+        {
+            l4re_util_cap_free(session);
+            return None;
+        }
+        Some(NetClient { gate: session })
+    }
+
+    /// Issue a call with `words` message registers already filled in and
+    /// decode the result in `MR0`.
+    unsafe fn call(&self, words: u32) -> Result<u64, i32> {
+        let tag = l4_ipc_call(
+            self.gate,
+            l4_utcb(),
+            l4_msgtag(0, words, 0, 0),
+            l4::sys::l4_timeout_t { raw: 0 },
+        );
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            return Err(EIO);
+        }
+        let res = (*l4_utcb_mr()).mr[0] as i64;
+        if res < 0 {
+            Err((-res) as i32)
+        } else {
+            Ok(res as u64)
+        }
+    }
+
+    /// Call `op` on `handle`, passing `args` in `MR2` onwards.
+    fn simple(&self, op: u64, handle: u64, args: &[u64]) -> Result<u64, i32> {
         unsafe {
-            (*l4::sys::l4_utcb_mr()).mr[0] = OP_SEND;
-            (*l4::sys::l4_utcb_mr()).mr[1] = handle;
-            (*l4::sys::l4_utcb_mr()).mr[2] = word;
-            let tag = l4_ipc_call(
-                self.gate,
-                l4_utcb(),
-                l4_msgtag(0, 3, 0, 0),
-                l4::sys::l4_timeout_t { raw: 0 },
-            );
-            let err = l4_ipc_error(tag, l4_utcb());
-            if err != 0 {
-                return Err(err);
-            }
-            Ok(())
-        }
-    }
-
-    /// Receive a single word of data from the server.
-    pub fn recv(&self, handle: u64) -> Result<u64, i32> {
+            let mr = &mut (*l4_utcb_mr()).mr;
+            mr[0] = op;
+            mr[1] = handle;
+            mr[2..2 + args.len()].copy_from_slice(args);
+            self.call(2 + args.len() as u32)
+        }
+    }
+
+    /// Create a socket of type `ty` (`SOCK_STREAM`, `SOCK_DGRAM` or
+    /// `SOCK_RAW`). `protocol` 0 selects TCP or UDP, or the vsock stream
+    /// protocol for `AF_VSOCK`.
+    pub fn socket(&self, domain: i32, ty: i32, protocol: i32) -> Result<u64, i32> {
         unsafe {
-            (*l4::sys::l4_utcb_mr()).mr[0] = OP_RECV;
-            (*l4::sys::l4_utcb_mr()).mr[1] = handle;
-            let tag = l4_ipc_call(
-                self.gate,
-                l4_utcb(),
-                l4_msgtag(0, 2, 0, 0),
-                l4::sys::l4_timeout_t { raw: 0 },
-            );
-            let err = l4_ipc_error(tag, l4_utcb());
-            if err != 0 {
-                return Err(err);
-            }
-            Ok((*l4::sys::l4_utcb_mr()).mr[0])
+            let mr = &mut (*l4_utcb_mr()).mr;
+            mr[0] = OP_SOCKET;
+            mr[1] = domain as u64;
+            mr[2] = ty as u64;
+            mr[3] = protocol as u64;
+            self.call(4)
         }
     }
 
+    /// Create an IPv4 UDP socket.
+    pub fn open_socket(&self) -> Result<u64, i32> {
+        self.socket(AF_INET, SOCK_DGRAM, 0)
+    }
+
+    pub fn bind(&self, handle: u64, addr: &SockAddr) -> Result<(), i32> {
+        let mut words = [0u64; 3];
+        addr.encode(&mut words);
+        self.simple(OP_BIND, handle, &words).map(|_| ())
+    }
+
+    pub fn listen(&self, handle: u64, backlog: u32) -> Result<(), i32> {
+        self.simple(OP_LISTEN, handle, &[backlog as u64])
+            .map(|_| ())
+    }
+
+    /// Take a pending connection. Returns the new handle and the peer.
+    pub fn accept(&self, handle: u64) -> Result<(u64, SockAddr), i32> {
+        let new = self.simple(OP_ACCEPT, handle, &[])?;
+        let peer = unsafe { reply_addr() };
+        Ok((new, peer))
+    }
+
+    pub fn connect(&self, handle: u64, addr: &SockAddr) -> Result<(), i32> {
+        let mut words = [0u64; 3];
+        addr.encode(&mut words);
+        self.simple(OP_CONNECT, handle, &words).map(|_| ())
+    }
+
+    pub fn shutdown(&self, handle: u64, how: i32) -> Result<(), i32> {
+        self.simple(OP_SHUTDOWN, handle, &[how as u64]).map(|_| ())
+    }
+
     /// Close the socket.
     pub fn close(&self, handle: u64) -> Result<(), i32> {
+        self.simple(OP_CLOSE, handle, &[]).map(|_| ())
+    }
+
+    /// Send on a connected socket. Sends at most [`BR_DATA_MAX`] bytes.
+    pub fn send(&self, handle: u64, data: &[u8]) -> Result<usize, i32> {
         unsafe {
-            (*l4::sys::l4_utcb_mr()).mr[0] = OP_CLOSE;
-            (*l4::sys::l4_utcb_mr()).mr[1] = handle;
-            let tag = l4_ipc_call(
-                self.gate,
-                l4_utcb(),
-                l4_msgtag(0, 2, 0, 0),
-                l4::sys::l4_timeout_t { raw: 0 },
-            );
-            let err = l4_ipc_error(tag, l4_utcb());
-            if err != 0 {
-                return Err(err);
-            }
-            Ok(())
+            br_write(data);
+            self.simple(OP_SEND, handle, &[0]).map(|n| n as usize)
         }
     }
+
+    /// Send a datagram to `addr`. Sends at most [`BR_DATA_MAX`] bytes.
+    pub fn sendto(&self, handle: u64, data: &[u8], addr: &SockAddr) -> Result<usize, i32> {
+        let mut words = [0u64; 4];
+        addr.encode(&mut words[1..]);
+        unsafe {
+            br_write(data);
+            self.simple(OP_SENDTO, handle, &words).map(|n| n as usize)
+        }
+    }
+
+    /// Receive up to `buf.len()` (at most [`BR_DATA_MAX`]) bytes.
+    pub fn recv(&self, handle: u64, buf: &mut [u8], flags: i32) -> Result<usize, i32> {
+        let cap = min(buf.len(), BR_DATA_MAX) as u64;
+        let n = self.simple(OP_RECV, handle, &[cap, flags as u64])? as usize;
+        Ok(unsafe { br_read(buf, n) })
+    }
+
+    /// Receive data together with its source address.
+    pub fn recvfrom(
+        &self,
+        handle: u64,
+        buf: &mut [u8],
+        flags: i32,
+    ) -> Result<(usize, SockAddr), i32> {
+        let cap = min(buf.len(), BR_DATA_MAX) as u64;
+        let n = self.simple(OP_RECVFROM, handle, &[cap, flags as u64])? as usize;
+        unsafe {
+            let src = reply_addr();
+            Ok((br_read(buf, n), src))
+        }
+    }
+
+    pub fn getsockname(&self, handle: u64) -> Result<SockAddr, i32> {
+        self.simple(OP_GETSOCKNAME, handle, &[])?;
+        Ok(unsafe { reply_addr() })
+    }
+
+    pub fn getpeername(&self, handle: u64) -> Result<SockAddr, i32> {
+        self.simple(OP_GETPEERNAME, handle, &[])?;
+        Ok(unsafe { reply_addr() })
+    }
+
+    pub fn setsockopt(&self, handle: u64, level: i32, name: i32, value: u64) -> Result<(), i32> {
+        self.simple(OP_SETSOCKOPT, handle, &[level as u64, name as u64, value])
+            .map(|_| ())
+    }
+
+    pub fn getsockopt(&self, handle: u64, level: i32, name: i32) -> Result<u64, i32> {
+        self.simple(OP_GETSOCKOPT, handle, &[level as u64, name as u64])?;
+        Ok(unsafe { (*l4_utcb_mr()).mr[1] })
+    }
+
+    /// The events ready on the socket, a mask of the `POLLIN`, `POLLOUT`,
+    /// `POLLERR`, `POLLHUP` and `POLLRDHUP` flags of `poll(2)`.
+    pub fn poll(&self, handle: u64) -> Result<u32, i32> {
+        self.simple(OP_POLL, handle, &[])
+            .map(|events| events as u32)
+    }
 }
 
+impl Drop for NetClient {
+    fn drop(&mut self) {
+        unsafe {
+            (*l4_utcb_mr()).mr[0] = OP_SESSION_CLOSE;
+            let _ = self.call(1);
+            l4re_util_cap_free(self.gate);
+        }
+    }
+}
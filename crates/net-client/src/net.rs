@@ -0,0 +1,628 @@
+//! `std::net`-like sockets on top of [`NetClient`].
+//!
+//! The service never blocks, so blocking calls retry while it reports
+//! [`EAGAIN`], pausing for [`POLL_INTERVAL`] in between, until the timeout
+//! set on the socket expires. In non-blocking mode the `EAGAIN` is returned
+//! as [`io::ErrorKind::WouldBlock`].
+//!
+//! Stream writes are split into requests of at most [`BR_DATA_MAX`] bytes;
+//! `write` sends one request, `write_all` as many as needed. Datagrams have
+//! to fit into a single request.
+//!
+//! [`VsockStream`] and [`VsockListener`] use the vsock service instead of
+//! the network service and are addressed by CID and port.
+//!
+//! Error numbers of the service are returned as OS errors, so
+//! [`io::Error::kind`] and [`io::Error::raw_os_error`] work as usual.
+
+use crate::{
+    NetClient, SockAddr, AF_INET, AF_INET6, AF_VSOCK, BR_DATA_MAX, EAGAIN, EINPROGRESS, ENOTCONN,
+    IPPROTO_TCP, MSG_PEEK, SHUT_RD, SHUT_RDWR, SHUT_WR, SOCK_DGRAM, SOCK_STREAM, SOL_SOCKET,
+    SO_REUSEADDR, TCP_NODELAY, VMADDR_CID_ANY,
+};
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Pause between retries of a blocking operation.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Backlog requested by [`TcpListener::bind`] and [`VsockListener::bind`];
+/// the service caps it.
+const BACKLOG: u32 = 128;
+
+/// `EMSGSIZE` of the service.
+const EMSGSIZE: i32 = 90;
+
+fn os_error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn connect_client() -> io::Result<NetClient> {
+    NetClient::new()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "network service not available"))
+}
+
+fn connect_vsock() -> io::Result<NetClient> {
+    NetClient::vsock()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "vsock service not available"))
+}
+
+fn shutdown_how(how: Shutdown) -> i32 {
+    match how {
+        Shutdown::Read => SHUT_RD,
+        Shutdown::Write => SHUT_WR,
+        Shutdown::Both => SHUT_RDWR,
+    }
+}
+
+/// CID and port of a vsock address returned by the service.
+fn vsock_addr(addr: Result<SockAddr, i32>) -> io::Result<(u32, u32)> {
+    addr.map_err(os_error)?
+        .vsock_addr()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a vsock address"))
+}
+
+fn domain(addr: &SocketAddr) -> i32 {
+    if addr.is_ipv4() {
+        AF_INET
+    } else {
+        AF_INET6
+    }
+}
+
+/// Run `f` on every address `addr` resolves to until one succeeds. Returns
+/// the last error otherwise.
+fn each_addr<A: ToSocketAddrs, T>(
+    addr: A,
+    mut f: impl FnMut(&SocketAddr) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut last = None;
+    for addr in addr.to_socket_addrs()? {
+        match f(&addr) {
+            Ok(res) => return Ok(res),
+            Err(err) => last = Some(err),
+        }
+    }
+    Err(last.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+/// A socket handle with the client-side settings shared by all types.
+/// Accepted connections share the session of their listener.
+struct Socket {
+    net: Arc<NetClient>,
+    handle: u64,
+    read_timeout: Cell<Option<Duration>>,
+    write_timeout: Cell<Option<Duration>>,
+    nonblocking: Cell<bool>,
+}
+
+impl Socket {
+    fn new(domain: i32, ty: i32) -> io::Result<Self> {
+        Self::with_client(connect_client()?, domain, ty)
+    }
+
+    fn with_client(net: NetClient, domain: i32, ty: i32) -> io::Result<Self> {
+        let handle = net.socket(domain, ty, 0).map_err(os_error)?;
+        Ok(Self::from_handle(Arc::new(net), handle))
+    }
+
+    /// Wait for a connection started with `connect` to complete. An empty
+    /// receive succeeds once the handshake completed and fails with
+    /// ENOTCONN if the connection was refused.
+    fn wait_connected(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.wait(timeout, |net, h| net.recv(h, &mut [], 0)) {
+            Ok(_) => Ok(()),
+            Err(err) if err.raw_os_error() == Some(ENOTCONN) => {
+                Err(io::ErrorKind::ConnectionRefused.into())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Take a pending connection of a listening socket.
+    fn accept(&self) -> io::Result<(Socket, SockAddr)> {
+        let (handle, peer) = self.wait(None, |net, h| net.accept(h))?;
+        Ok((Socket::from_handle(self.net.clone(), handle), peer))
+    }
+
+    fn from_handle(net: Arc<NetClient>, handle: u64) -> Self {
+        Socket {
+            net,
+            handle,
+            read_timeout: Cell::new(None),
+            write_timeout: Cell::new(None),
+            nonblocking: Cell::new(false),
+        }
+    }
+
+    /// Retry `op` while it fails with `EAGAIN`, unless the socket is
+    /// non-blocking or `timeout` expired.
+    fn wait<T>(
+        &self,
+        timeout: Option<Duration>,
+        mut op: impl FnMut(&NetClient, u64) -> Result<T, i32>,
+    ) -> io::Result<T> {
+        let start = Instant::now();
+        loop {
+            match op(&self.net, self.handle) {
+                Err(EAGAIN) if !self.nonblocking.get() => {
+                    if timeout.is_some_and(|t| start.elapsed() >= t) {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+                res => return res.map_err(os_error),
+            }
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8], flags: i32) -> io::Result<usize> {
+        self.wait(self.read_timeout.get(), |net, h| net.recv(h, buf, flags))
+    }
+
+    fn recv_from(&self, buf: &mut [u8], flags: i32) -> io::Result<(usize, SocketAddr)> {
+        self.wait(self.read_timeout.get(), |net, h| {
+            net.recvfrom(h, buf, flags)
+                .map(|(n, src)| (n, SocketAddr::from(src)))
+        })
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.wait(self.write_timeout.get(), |net, h| net.send(h, buf))
+    }
+
+    fn set_timeout(slot: &Cell<Option<Duration>>, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot set a 0 duration timeout",
+            ));
+        }
+        slot.set(timeout);
+        Ok(())
+    }
+
+    fn bind(&self, addr: &SocketAddr) -> io::Result<()> {
+        self.net
+            .bind(self.handle, &SockAddr::from(*addr))
+            .map_err(os_error)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.net
+            .getsockname(self.handle)
+            .map(SocketAddr::from)
+            .map_err(os_error)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.net
+            .getpeername(self.handle)
+            .map(SocketAddr::from)
+            .map_err(os_error)
+    }
+
+    fn setsockopt(&self, level: i32, name: i32, value: u64) -> io::Result<()> {
+        self.net
+            .setsockopt(self.handle, level, name, value)
+            .map_err(os_error)
+    }
+
+    fn getsockopt(&self, level: i32, name: i32) -> io::Result<u64> {
+        self.net
+            .getsockopt(self.handle, level, name)
+            .map_err(os_error)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = self.net.close(self.handle);
+    }
+}
+
+/// A TCP connection, like [`std::net::TcpStream`].
+pub struct TcpStream(Socket);
+
+impl TcpStream {
+    /// Connect to the first address of `addr` that accepts the connection.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        each_addr(addr, |addr| TcpStream::connect_inner(addr, None))
+    }
+
+    /// Connect to `addr`, giving up after `timeout`.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        if timeout.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot set a 0 duration timeout",
+            ));
+        }
+        TcpStream::connect_inner(addr, Some(timeout))
+    }
+
+    fn connect_inner(addr: &SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        let sock = Socket::new(domain(addr), SOCK_STREAM)?;
+        match sock.net.connect(sock.handle, &SockAddr::from(*addr)) {
+            Ok(()) | Err(EINPROGRESS) => {}
+            Err(err) => return Err(os_error(err)),
+        }
+        sock.wait_connected(timeout)?;
+        Ok(TcpStream(sock))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0
+            .net
+            .shutdown(self.0.handle, shutdown_how(how))
+            .map_err(os_error)
+    }
+
+    /// Receive data without removing it from the queue.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf, MSG_PEEK)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Socket::set_timeout(&self.0.read_timeout, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Socket::set_timeout(&self.0.write_timeout, timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.0.read_timeout.get())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.0.write_timeout.get())
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.0.setsockopt(IPPROTO_TCP, TCP_NODELAY, nodelay as u64)
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.0.getsockopt(IPPROTO_TCP, TCP_NODELAY).map(|v| v != 0)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf, 0)
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(&buf[..buf.len().min(BR_DATA_MAX)])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A listening TCP socket, like [`std::net::TcpListener`].
+pub struct TcpListener(Socket);
+
+impl TcpListener {
+    /// Listen on the first address of `addr` that can be bound. The
+    /// address may be reused right away, as `SO_REUSEADDR` is set.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        each_addr(addr, |addr| {
+            let sock = Socket::new(domain(addr), SOCK_STREAM)?;
+            sock.setsockopt(SOL_SOCKET, SO_REUSEADDR, 1)?;
+            sock.bind(addr)?;
+            sock.net.listen(sock.handle, BACKLOG).map_err(os_error)?;
+            Ok(TcpListener(sock))
+        })
+    }
+
+    /// Wait for a connection. Returns the stream and the peer address.
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (sock, peer) = self.0.accept()?;
+        Ok((TcpStream(sock), SocketAddr::from(peer)))
+    }
+
+    /// Iterate over incoming connections.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+/// Iterator returned by [`TcpListener::incoming`]. It never ends.
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
+
+/// A UDP socket, like [`std::net::UdpSocket`].
+pub struct UdpSocket(Socket);
+
+impl UdpSocket {
+    /// Bind to the first address of `addr` that is available.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        each_addr(addr, |addr| {
+            let sock = Socket::new(domain(addr), SOCK_DGRAM)?;
+            sock.bind(addr)?;
+            Ok(UdpSocket(sock))
+        })
+    }
+
+    /// Set the default destination and only receive datagrams from it.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        each_addr(addr, |addr| {
+            self.0
+                .net
+                .connect(self.0.handle, &SockAddr::from(*addr))
+                .map_err(os_error)
+        })
+    }
+
+    /// Send a datagram to the first address of `addr`. Datagrams longer than
+    /// [`BR_DATA_MAX`] fail with `EMSGSIZE`.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to send data to")
+        })?;
+        if buf.len() > BR_DATA_MAX {
+            return Err(os_error(EMSGSIZE));
+        }
+        let dest = SockAddr::from(addr);
+        self.0.wait(self.0.write_timeout.get(), |net, h| {
+            net.sendto(h, buf, &dest)
+        })
+    }
+
+    /// Send a datagram to the connected peer.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > BR_DATA_MAX {
+            return Err(os_error(EMSGSIZE));
+        }
+        self.0.send(buf)
+    }
+
+    /// Receive a datagram. Excess bytes of a datagram longer than `buf` are
+    /// discarded.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf, 0)
+    }
+
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf, MSG_PEEK)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf, 0)
+    }
+
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf, MSG_PEEK)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Socket::set_timeout(&self.0.read_timeout, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Socket::set_timeout(&self.0.write_timeout, timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.0.read_timeout.get())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.0.write_timeout.get())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+/// A vsock stream to the host, like [`TcpStream`] but addressed by CID and
+/// port.
+pub struct VsockStream(Socket);
+
+impl VsockStream {
+    /// Connect to `port` of `cid`, usually [`crate::VMADDR_CID_HOST`].
+    pub fn connect(cid: u32, port: u32) -> io::Result<VsockStream> {
+        VsockStream::connect_inner(cid, port, None)
+    }
+
+    /// Connect to `port` of `cid`, giving up after `timeout`.
+    pub fn connect_timeout(cid: u32, port: u32, timeout: Duration) -> io::Result<VsockStream> {
+        if timeout.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot set a 0 duration timeout",
+            ));
+        }
+        VsockStream::connect_inner(cid, port, Some(timeout))
+    }
+
+    fn connect_inner(cid: u32, port: u32, timeout: Option<Duration>) -> io::Result<VsockStream> {
+        let sock = Socket::with_client(connect_vsock()?, AF_VSOCK, SOCK_STREAM)?;
+        match sock.net.connect(sock.handle, &SockAddr::vsock(cid, port)) {
+            Ok(()) | Err(EINPROGRESS) => {}
+            Err(err) => return Err(os_error(err)),
+        }
+        sock.wait_connected(timeout)?;
+        Ok(VsockStream(sock))
+    }
+
+    /// CID and port of the peer.
+    pub fn peer_addr(&self) -> io::Result<(u32, u32)> {
+        vsock_addr(self.0.net.getpeername(self.0.handle))
+    }
+
+    /// CID and port of this end.
+    pub fn local_addr(&self) -> io::Result<(u32, u32)> {
+        vsock_addr(self.0.net.getsockname(self.0.handle))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0
+            .net
+            .shutdown(self.0.handle, shutdown_how(how))
+            .map_err(os_error)
+    }
+
+    /// Receive data without removing it from the queue.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf, MSG_PEEK)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Socket::set_timeout(&self.0.read_timeout, timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Socket::set_timeout(&self.0.write_timeout, timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.0.read_timeout.get())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.0.write_timeout.get())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
+
+impl Read for &VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf, 0)
+    }
+}
+
+impl Write for &VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(&buf[..buf.len().min(BR_DATA_MAX)])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A listening vsock socket, for connections from the host.
+pub struct VsockListener(Socket);
+
+impl VsockListener {
+    /// Listen on `port` of this guest; [`crate::VMADDR_PORT_ANY`] picks a
+    /// free one.
+    pub fn bind(port: u32) -> io::Result<VsockListener> {
+        let sock = Socket::with_client(connect_vsock()?, AF_VSOCK, SOCK_STREAM)?;
+        sock.net
+            .bind(sock.handle, &SockAddr::vsock(VMADDR_CID_ANY, port))
+            .map_err(os_error)?;
+        sock.net.listen(sock.handle, BACKLOG).map_err(os_error)?;
+        Ok(VsockListener(sock))
+    }
+
+    /// Wait for a connection. Returns the stream and the peer's CID and
+    /// port.
+    pub fn accept(&self) -> io::Result<(VsockStream, (u32, u32))> {
+        let (sock, peer) = self.0.accept()?;
+        let peer = vsock_addr(Ok(peer))?;
+        Ok((VsockStream(sock), peer))
+    }
+
+    /// CID and port the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<(u32, u32)> {
+        vsock_addr(self.0.net.getsockname(self.0.handle))
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.nonblocking.set(nonblocking);
+        Ok(())
+    }
+}
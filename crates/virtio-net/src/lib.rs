@@ -0,0 +1,689 @@
+//! virtio-net driver and the smoltcp [`Device`] adapter on top of it, shared
+//! by net_server and vswitch.
+//!
+//! The driver reaches its device through a [`NetTransport`]. On L4Re that is
+//! the device gate of driver_server, see `GateTransport` (feature `l4re`);
+//! host tests use the simulated devices of `virtio_frontend`.
+
+#[cfg(feature = "l4re")]
+use l4re::sys::l4re_env_get_cap;
+#[cfg(feature = "l4re")]
+use l4_sys::{l4_cap_idx_t, l4_ipc_call, l4_ipc_error, l4_msgtag, l4_utcb, l4_utcb_mr};
+use smoltcp::phy::{Checksum, Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::EthernetAddress;
+use std::sync::atomic::{fence, Ordering};
+
+// Queue indices.
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+// Queue sizes. Every receive buffer takes one descriptor, every transmitted
+// frame two (header and data), so both queues hold eight frames in flight.
+const RX_QUEUE_SIZE: usize = 8;
+const TX_QUEUE_SIZE: usize = 16;
+
+/// Size of a receive buffer including the virtio-net header. Large enough
+/// for a full Ethernet frame with VLAN tag, so frames only span several
+/// buffers if the device merges them on its own.
+const RX_BUF_SIZE: usize = 2048;
+/// Largest frame `receive_frame` returns.
+pub const FRAME_SIZE: usize = 1536;
+
+// Descriptor flags.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+// The driver does not want an interrupt when the device used a buffer.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+// Feature bits the driver knows about.
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const DRIVER_FEATURES: u64 = VIRTIO_NET_F_CSUM | VIRTIO_NET_F_GUEST_CSUM | VIRTIO_NET_F_MAC
+    | VIRTIO_NET_F_MRG_RXBUF | VIRTIO_NET_F_STATUS | VIRTIO_F_VERSION_1;
+
+// Operations of the device gate, the transport protocol of driver_server.
+#[cfg(feature = "l4re")]
+const OP_DEVICE_FEATURES: u64 = 0;
+#[cfg(feature = "l4re")]
+const OP_NEGOTIATE: u64 = 1;
+#[cfg(feature = "l4re")]
+const OP_CONFIG_READ: u64 = 3;
+
+// Device configuration space: the MAC address followed by the link status.
+const CONFIG_MAC: u64 = 0;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// Header flags.
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct VirtqAvail<const N: usize> {
+    flags: u16,
+    idx: u16,
+    ring: [u16; N],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct VirtqUsed<const N: usize> {
+    flags: u16,
+    idx: u16,
+    ring: [VirtqUsedElem; N],
+    avail_event: u16,
+}
+
+/// Split virtqueue with `N` descriptors. Free descriptors form a list
+/// chained through `next`. The device accesses the rings by address, so
+/// queues live on the heap.
+struct VirtQueue<const N: usize> {
+    desc: [VirtqDesc; N],
+    avail: VirtqAvail<N>,
+    used: VirtqUsed<N>,
+    free_head: u16,
+    num_free: usize,
+    /// Used ring entries already processed.
+    last_used: u16,
+}
+
+impl<const N: usize> VirtQueue<N> {
+    fn new() -> Box<Self> {
+        let mut queue = Box::new(Self {
+            desc: [VirtqDesc { addr: 0, len: 0, flags: 0, next: 0 }; N],
+            avail: VirtqAvail { flags: 0, idx: 0, ring: [0; N], used_event: 0 },
+            used: VirtqUsed {
+                flags: 0,
+                idx: 0,
+                ring: [VirtqUsedElem { id: 0, len: 0 }; N],
+                avail_event: 0,
+            },
+            free_head: 0,
+            num_free: N,
+            last_used: 0,
+        });
+        for (i, desc) in queue.desc.iter_mut().enumerate() {
+            desc.next = (i + 1) as u16;
+        }
+        queue
+    }
+
+    /// Chain descriptors for `bufs`, given as address, length and whether
+    /// the device writes the buffer, and make the chain available. Returns
+    /// the head descriptor, or `None` if the queue is full.
+    fn add(&mut self, bufs: &[(u64, u32, bool)]) -> Option<u16> {
+        if bufs.is_empty() || bufs.len() > self.num_free {
+            return None;
+        }
+        let head = self.free_head;
+        let mut last = head;
+        for (i, &(addr, len, write)) in bufs.iter().enumerate() {
+            let desc = &mut self.desc[last as usize];
+            desc.addr = addr;
+            desc.len = len;
+            desc.flags = if write { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < bufs.len() {
+                desc.flags |= VIRTQ_DESC_F_NEXT;
+                last = desc.next;
+            }
+        }
+        self.free_head = self.desc[last as usize].next;
+        self.num_free -= bufs.len();
+
+        let idx = self.avail.idx;
+        self.avail.ring[idx as usize % N] = head;
+        // The device must see the descriptors before the new index.
+        fence(Ordering::Release);
+        unsafe { core::ptr::write_volatile(&mut self.avail.idx, idx.wrapping_add(1)) };
+        Some(head)
+    }
+
+    /// Addresses of the descriptor table and the available and used rings.
+    fn addrs(&self) -> (u64, u64, u64) {
+        (
+            self.desc.as_ptr() as u64,
+            &self.avail as *const _ as u64,
+            &self.used as *const _ as u64,
+        )
+    }
+
+    /// Take the next chain the device is done with and return its head and
+    /// the number of bytes the device wrote.
+    fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { core::ptr::read_volatile(&self.used.idx) };
+        if self.last_used == used_idx {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let elem = self.used.ring[self.last_used as usize % N];
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Return the chain to the free list.
+        let head = elem.id as u16;
+        let mut last = head;
+        let mut count = 1;
+        while self.desc[last as usize].flags & VIRTQ_DESC_F_NEXT != 0 {
+            last = self.desc[last as usize].next;
+            count += 1;
+        }
+        self.desc[last as usize].next = self.free_head;
+        self.free_head = head;
+        self.num_free += count;
+        Some((head, elem.len))
+    }
+}
+
+/// Network header as defined by the virtio-net specification. `num_buffers`
+/// is only part of the header with `VIRTIO_F_VERSION_1` or
+/// `VIRTIO_NET_F_MRG_RXBUF`.
+#[derive(Copy, Clone, Default)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+impl VirtioNetHdr {
+    fn parse(buf: &[u8]) -> Self {
+        let word = |at: usize| {
+            buf.get(at..at + 2).map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+        };
+        Self {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: word(2),
+            gso_size: word(4),
+            csum_start: word(6),
+            csum_offset: word(8),
+            num_buffers: word(10),
+        }
+    }
+
+    fn emit(&self, buf: &mut [u8]) {
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        let words =
+            [self.hdr_len, self.gso_size, self.csum_start, self.csum_offset, self.num_buffers];
+        for (chunk, word) in buf[2..].chunks_exact_mut(2).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+}
+
+/// Add `data` to the ones' complement sum `sum`.
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Prepare `frame` for checksum offloading: if it carries TCP or UDP over
+/// IPv4 or IPv6, store the pseudo-header sum in its checksum field and
+/// return where the device starts summing and where it puts the result.
+fn offload_checksum(frame: &mut [u8]) -> Option<(u16, u16)> {
+    let ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+    let ip = frame.get(14..)?;
+    let (start, protocol, addrs, len) = match ethertype {
+        0x0800 => {
+            let ihl = (*ip.first()? as usize & 0xf) * 4;
+            let total = u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]) as usize;
+            (14 + ihl, *ip.get(9)?, ip.get(12..20)?, total.checked_sub(ihl)?)
+        }
+        0x86dd => {
+            let payload = u16::from_be_bytes([*ip.get(4)?, *ip.get(5)?]) as usize;
+            (14 + 40, *ip.get(6)?, ip.get(8..40)?, payload)
+        }
+        _ => return None,
+    };
+    let offset = match protocol {
+        6 => 16,
+        17 => 6,
+        _ => return None,
+    };
+    let sum = checksum_add(protocol as u32 + len as u32, addrs);
+    let field = frame.get_mut(start + offset..start + offset + 2)?;
+    field.copy_from_slice(&checksum_fold(sum).to_be_bytes());
+    Some((start as u16, offset as u16))
+}
+
+/// Finish the checksum the device left to the driver.
+fn complete_checksum(frame: &mut [u8], hdr: &VirtioNetHdr) {
+    let start = hdr.csum_start as usize;
+    let at = start + hdr.csum_offset as usize;
+    if at + 2 > frame.len() {
+        return;
+    }
+    let sum = !checksum_fold(checksum_add(0, &frame[start..]));
+    frame[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// How [`VirtioNet`] reaches its device.
+pub trait NetTransport {
+    /// Features the device offers.
+    fn device_features(&mut self) -> u64;
+
+    /// Accept `features` and return the features in effect.
+    fn negotiate(&mut self, features: u64) -> u64;
+
+    /// Read 32 bits of the device configuration space at `offset`.
+    fn read_config(&mut self, offset: u64) -> Option<u32>;
+
+    /// Hand queue `index` of `size` entries, given by the addresses of its
+    /// descriptor table and rings, to the device.
+    fn setup_queue(&mut self, index: u16, size: u16, desc: u64, avail: u64, used: u64);
+
+    /// Tell the device the driver is ready.
+    fn driver_ok(&mut self);
+
+    /// Tell the device queue `index` has new buffers.
+    fn notify(&mut self, index: u16);
+
+    /// Acknowledge the device interrupt.
+    fn ack_interrupt(&mut self);
+}
+
+/// The device gate of driver_server. Its protocol covers features and the
+/// configuration space only; queue setup, notifications and interrupt
+/// acknowledgement are left to the device.
+#[cfg(feature = "l4re")]
+pub struct GateTransport {
+    device: l4_cap_idx_t,
+}
+
+#[cfg(feature = "l4re")]
+impl GateTransport {
+    /// Call the device gate with `words` in the message registers and
+    /// return MR0 of the reply.
+    unsafe fn call(&self, words: &[u64]) -> Option<u64> {
+        let mr = l4_utcb_mr();
+        for (i, &word) in words.iter().enumerate() {
+            (*mr).mr[i] = word;
+        }
+        let tag = l4_ipc_call(
+            self.device,
+            l4_utcb(),
+            l4_msgtag(0, words.len() as u32, 0, 0),
+            l4_sys::l4_timeout_t { raw: 0 },
+        );
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            return None;
+        }
+        Some((*mr).mr[0])
+    }
+}
+
+#[cfg(feature = "l4re")]
+impl NetTransport for GateTransport {
+    fn device_features(&mut self) -> u64 {
+        unsafe { self.call(&[OP_DEVICE_FEATURES]) }.unwrap_or(0)
+    }
+
+    fn negotiate(&mut self, features: u64) -> u64 {
+        // The gate answers u64::MAX if the device rejected the features.
+        unsafe { self.call(&[OP_NEGOTIATE, features]) }.filter(|&f| f != u64::MAX).unwrap_or(0)
+    }
+
+    fn read_config(&mut self, offset: u64) -> Option<u32> {
+        let value = unsafe { self.call(&[OP_CONFIG_READ, offset]) }?;
+        u32::try_from(value).ok()
+    }
+
+    fn setup_queue(&mut self, _index: u16, _size: u16, _desc: u64, _avail: u64, _used: u64) {}
+
+    fn driver_ok(&mut self) {}
+
+    fn notify(&mut self, _index: u16) {}
+
+    fn ack_interrupt(&mut self) {}
+}
+
+/// Minimal virtio-net driver with one receive and one transmit queue.
+///
+/// Features are negotiated through the [`NetTransport`]. The driver takes
+/// the MAC address from the config space (`VIRTIO_NET_F_MAC`), tracks the
+/// link status (`VIRTIO_NET_F_STATUS`), accepts mergeable receive buffers
+/// and offloads TCP and UDP checksums in both directions.
+///
+/// The receive queue is kept filled with buffers, a received frame's
+/// buffers are posted again as soon as it has been copied out. Transmit
+/// completions raise no interrupt; they are collected in batches when the
+/// transmit queue runs full and on interrupts.
+///
+/// The driver never blocks: `receive_frame` only returns frames the device
+/// already delivered and `send_frame` fails while the transmit queue is
+/// full. The owner is expected to wait for the device interrupt, call
+/// [`VirtioNet::interrupt`] and poll afterwards.
+pub struct VirtioNet<T: NetTransport> {
+    transport: T,
+    #[cfg(feature = "l4re")]
+    irq: Option<l4_cap_idx_t>,
+    features: u64,
+    /// Size of the virtio-net header preceding every frame.
+    hdr_len: usize,
+    mac: Option<EthernetAddress>,
+    link_up: bool,
+    rx: Box<VirtQueue<RX_QUEUE_SIZE>>,
+    /// Receive buffers, indexed by their descriptor.
+    rx_bufs: Vec<[u8; RX_BUF_SIZE]>,
+    tx: Box<VirtQueue<TX_QUEUE_SIZE>>,
+    /// Header and frame of every transmission in flight, indexed by its
+    /// head descriptor.
+    tx_bufs: Vec<Option<Vec<u8>>>,
+}
+
+#[cfg(feature = "l4re")]
+impl VirtioNet<GateTransport> {
+    /// Create a new driver instance by obtaining the virtio-net device and
+    /// its IRQ capability from the environment. The capabilities are expected
+    /// under the names `virtio_net` and `virtio_net_irq` respectively.
+    ///
+    /// # Safety
+    ///
+    /// Talks to the device gate through the UTCB of the calling thread.
+    pub unsafe fn new() -> Option<Self> {
+        let device = l4re_env_get_cap("virtio_net")?;
+        let irq = l4re_env_get_cap("virtio_net_irq")?;
+        let mut net = Self::with_transport(GateTransport { device });
+        net.irq = Some(irq);
+        Some(net)
+    }
+
+    /// IRQ signalled by the device when it filled buffers or its
+    /// configuration changed.
+    pub fn irq(&self) -> l4_cap_idx_t {
+        self.irq.unwrap()
+    }
+}
+
+impl<T: NetTransport> VirtioNet<T> {
+    /// Negotiate features with the device behind `transport`, set up the
+    /// queues and hand all receive buffers to the device.
+    pub fn with_transport(transport: T) -> Self {
+        let mut net = Self {
+            transport,
+            #[cfg(feature = "l4re")]
+            irq: None,
+            features: 0,
+            hdr_len: 10,
+            mac: None,
+            link_up: true,
+            rx: VirtQueue::new(),
+            rx_bufs: vec![[0; RX_BUF_SIZE]; RX_QUEUE_SIZE],
+            tx: VirtQueue::new(),
+            tx_bufs: vec![None; TX_QUEUE_SIZE],
+        };
+
+        // Transmit completions are collected without interrupts.
+        net.tx.avail.flags = VIRTQ_AVAIL_F_NO_INTERRUPT;
+
+        let offered = net.transport.device_features();
+        net.features = net.transport.negotiate(offered & DRIVER_FEATURES);
+        if net.has(VIRTIO_F_VERSION_1) || net.has(VIRTIO_NET_F_MRG_RXBUF) {
+            net.hdr_len = 12;
+        }
+        if net.has(VIRTIO_NET_F_MAC) {
+            let lo = net.transport.read_config(CONFIG_MAC);
+            let hi = net.transport.read_config(CONFIG_MAC + 4);
+            if let (Some(lo), Some(hi)) = (lo, hi) {
+                let [a, b, c, d] = lo.to_le_bytes();
+                let [e, f, ..] = hi.to_le_bytes();
+                net.mac = Some(EthernetAddress([a, b, c, d, e, f]));
+            }
+        }
+        net.update_link();
+
+        let (desc, avail, used) = net.rx.addrs();
+        net.transport.setup_queue(RX_QUEUE, RX_QUEUE_SIZE as u16, desc, avail, used);
+        let (desc, avail, used) = net.tx.addrs();
+        net.transport.setup_queue(TX_QUEUE, TX_QUEUE_SIZE as u16, desc, avail, used);
+        net.transport.driver_ok();
+
+        // Hand all receive buffers to the device.
+        for _ in 0..RX_QUEUE_SIZE {
+            net.post_rx();
+        }
+        net.transport.notify(RX_QUEUE);
+        net
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// MAC address of the device, if it has one.
+    pub fn mac(&self) -> Option<EthernetAddress> {
+        self.mac
+    }
+
+    /// Whether the link is up. Devices without `VIRTIO_NET_F_STATUS` are
+    /// always up.
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    /// Whether the device computes TCP and UDP checksums of sent frames.
+    pub fn checksum_offload(&self) -> bool {
+        self.has(VIRTIO_NET_F_CSUM)
+    }
+
+    /// Handle an interrupt: pick up a changed link status and collect
+    /// transmit completions.
+    pub fn interrupt(&mut self) {
+        self.transport.ack_interrupt();
+        self.update_link();
+        self.reap_tx();
+    }
+
+    fn has(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    fn update_link(&mut self) {
+        if !self.has(VIRTIO_NET_F_STATUS) {
+            return;
+        }
+        // The status follows the six bytes of the MAC address.
+        if let Some(word) = self.transport.read_config(CONFIG_MAC + 4) {
+            let status = (word >> 16) as u16;
+            self.link_up = status & VIRTIO_NET_S_LINK_UP != 0;
+        }
+    }
+
+    /// Hand a free receive buffer to the device.
+    fn post_rx(&mut self) {
+        // Every receive buffer takes exactly one descriptor, the next free one.
+        let slot = self.rx.free_head as usize;
+        let Some(buf) = self.rx_bufs.get_mut(slot) else {
+            return;
+        };
+        let addr = buf.as_mut_ptr() as u64;
+        self.rx.add(&[(addr, RX_BUF_SIZE as u32, true)]);
+    }
+
+    /// Collect transmit completions and free their buffers.
+    fn reap_tx(&mut self) {
+        while let Some((head, _)) = self.tx.pop_used() {
+            self.tx_bufs[head as usize] = None;
+        }
+    }
+
+    /// Whether a frame can be queued for transmission right now.
+    pub fn can_send(&mut self) -> bool {
+        if self.tx.num_free < 2 {
+            self.reap_tx();
+        }
+        self.tx.num_free >= 2
+    }
+
+    /// Enqueue an Ethernet frame for transmission. Fails if the transmit
+    /// queue is full.
+    #[allow(clippy::result_unit_err)]
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<(), ()> {
+        if !self.can_send() {
+            return Err(());
+        }
+
+        let mut buf = vec![0u8; self.hdr_len + frame.len()];
+        let (hdr, data) = buf.split_at_mut(self.hdr_len);
+        data.copy_from_slice(frame);
+        let mut header = VirtioNetHdr::default();
+        if self.checksum_offload() {
+            if let Some((start, offset)) = offload_checksum(data) {
+                header.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+                header.csum_start = start;
+                header.csum_offset = offset;
+            }
+        }
+        header.emit(hdr);
+
+        let addr = buf.as_ptr() as u64;
+        let chain = [
+            (addr, self.hdr_len as u32, false),
+            (addr + self.hdr_len as u64, frame.len() as u32, false),
+        ];
+        let head = self.tx.add(&chain).ok_or(())?;
+        self.tx_bufs[head as usize] = Some(buf);
+        self.transport.notify(TX_QUEUE);
+        Ok(())
+    }
+
+    /// Dequeue a received Ethernet frame into the provided buffer. Returns
+    /// the number of bytes copied into `buf`, or an error if no frame is
+    /// pending. Frames longer than `buf` are truncated.
+    #[allow(clippy::result_unit_err)]
+    pub fn receive_frame(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        let (head, len) = self.rx.pop_used().ok_or(())?;
+        let first = &self.rx_bufs[head as usize][..(len as usize).min(RX_BUF_SIZE)];
+        let header = VirtioNetHdr::parse(first);
+        let mut frame = first.get(self.hdr_len..).unwrap_or_default().to_vec();
+        self.post_rx();
+
+        // With mergeable buffers the frame continues in further buffers.
+        if self.has(VIRTIO_NET_F_MRG_RXBUF) {
+            for _ in 1..header.num_buffers {
+                let Some((head, len)) = self.rx.pop_used() else {
+                    break;
+                };
+                let len = (len as usize).min(RX_BUF_SIZE);
+                frame.extend_from_slice(&self.rx_bufs[head as usize][..len]);
+                self.post_rx();
+            }
+        }
+
+        // The buffers were posted again above.
+        self.transport.notify(RX_QUEUE);
+
+        if header.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+            complete_checksum(&mut frame, &header);
+        }
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        Ok(len)
+    }
+}
+
+/// Adapter implementing smoltcp's `Device` trait on top of the driver.
+pub struct VirtioDevice<'a, T: NetTransport> {
+    pub net: &'a mut VirtioNet<T>,
+}
+
+pub struct VirtioRxToken {
+    frame: Vec<u8>,
+}
+
+pub struct VirtioTxToken<'a, T: NetTransport> {
+    net: &'a mut VirtioNet<T>,
+}
+
+impl<'a, T: NetTransport> Device for VirtioDevice<'a, T> {
+    type RxToken<'b> = VirtioRxToken where Self: 'b;
+    type TxToken<'b> = VirtioTxToken<'b, T> where Self: 'b;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        if !self.net.link_up() || !self.net.can_send() {
+            return None;
+        }
+        let mut buf = [0u8; FRAME_SIZE];
+        let len = self.net.receive_frame(&mut buf).ok()?;
+        Some((VirtioRxToken { frame: buf[..len].to_vec() }, VirtioTxToken { net: self.net }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        if !self.net.link_up() || !self.net.can_send() {
+            return None;
+        }
+        Some(VirtioTxToken { net: self.net })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = 1514;
+        caps.medium = Medium::Ethernet;
+        // The device fills in TCP and UDP checksums of sent frames.
+        if self.net.checksum_offload() {
+            caps.checksum.tcp = Checksum::Rx;
+            caps.checksum.udp = Checksum::Rx;
+        }
+        caps
+    }
+}
+
+impl RxToken for VirtioRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.frame)
+    }
+}
+
+impl<'a, T: NetTransport> TxToken for VirtioTxToken<'a, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buf = vec![0u8; len];
+        let res = f(&mut buf[..]);
+        let _ = self.net.send_frame(&buf[..]);
+        res
+    }
+}
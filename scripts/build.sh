@@ -1061,6 +1061,7 @@ EOF
 
       enable_service fs_server
       enable_service net_server
+      enable_service dns_server
       enable_service bash
     fi
   fi
@@ -1,19 +1,48 @@
 //! POSIX AIO service proxying aio_* calls over L4 IPC.
+//!
+//! The `aio_fildes` of a client's `aiocb` (and the `fd` of a ring SQE) is a
+//! file handle obtained from fs_server. Requests are acknowledged right away
+//! and queued; the server forwards a bounded batch of queued I/O to fs_server
+//! after every client request and whenever no request is waiting, so
+//! `aio_error` reports `EINPROGRESS` until the operation has been carried out.
+//!
+//! fs_server has no data-only flush: `O_DSYNC` and `FSYNC_DATASYNC` flush
+//! everything, like `O_SYNC`.
 
 use core::mem::size_of;
+use fs_client::FsClient;
 use l4::sys::{
-    l4_ipc_error, l4_ipc_reply_and_wait, l4_ipc_wait, l4_msgtag, l4_timeout_t, l4_utcb, l4_utcb_br,
-    l4_utcb_mr,
+    l4_cap_idx_t, l4_factory_create_gate, l4_factory_create_irq, l4_ipc_error,
+    l4_ipc_reply_and_wait, l4_ipc_tcr_error_t, l4_ipc_wait, l4_irq_trigger, l4_is_invalid_cap,
+    l4_msgtag, l4_msgtag_t, l4_obj_fpage, l4_sndfpage_add, l4_task_delete_obj,
+    l4_task_release_cap, l4_timeout_t, l4_utcb, l4_utcb_br, l4_utcb_mr, l4re_util_cap_alloc,
+    l4re_util_cap_free, L4_cap_fpage_rights, l4_default_caps_t,
 };
-use l4re::sys::{l4re_env, l4re_env_get_cap};
+use l4re::sys::{l4re_env, l4re_env_get_cap, l4re_ma_alloc, l4re_rm_attach, l4re_rm_detach};
 use libc::{self, aiocb, c_int, c_void};
 use slab::Slab;
 use std::cmp::min;
-use std::os::unix::io::RawFd;
+use std::collections::VecDeque;
+
+mod ring;
+use ring::{Cqe, Ring, RingLayout, Sqe};
 
 const BR_WORDS: usize = l4_sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
 const BR_DATA_BYTES: usize = (BR_WORDS.saturating_sub(1)) * size_of::<u64>();
 
+/// Largest number of queued jobs forwarded to fs_server in one go.
+const IO_BATCH: usize = 8;
+
+/// Label of requests arriving through the `global_aio` gate.
+const GATE_LABEL: u64 = 0b1111_0000;
+/// Labels of ring gates: this bit plus the serial number of the ring in the
+/// bits above the rights, so a gate outliving its ring never matches a new
+/// one.
+const RING_LABEL: u64 = 1 << 32;
+/// The two least significant label bits carry the rights of the sender's
+/// capability.
+const LABEL_MASK: u64 = !0b11;
+
 mod opcode {
     pub const AIO_READ: u64 = 0;
     pub const AIO_WRITE: u64 = 1;
@@ -23,6 +52,9 @@ mod opcode {
     pub const AIO_SUSPEND: u64 = 5;
     pub const AIO_FSYNC: u64 = 6;
     pub const LIO_LISTIO: u64 = 7;
+    pub const RING_SETUP: u64 = 8;
+    pub const RING_ENTER: u64 = 9;
+    pub const RING_DESTROY: u64 = 10;
 }
 
 #[derive(Debug)]
@@ -32,12 +64,26 @@ enum OperationKind {
     Fsync,
 }
 
+#[derive(Debug)]
+enum OperationState {
+    InProgress,
+    Done { result: isize, error: c_int },
+}
+
 struct Operation {
     kind: OperationKind,
-    fd: RawFd,
-    result: isize,
-    error: c_int,
+    /// fs_server file handle.
+    handle: u64,
+    offset: u64,
+    /// Read destination or write payload.
     buffer: Vec<u8>,
+    state: OperationState,
+}
+
+/// Work waiting to be forwarded to fs_server, in submission order.
+enum Job {
+    Aio(usize),
+    Ring { ring: usize, sqe: Sqe },
 }
 
 fn encode_errno_raw(err: c_int) -> u64 {
@@ -48,12 +94,6 @@ unsafe fn br_clear() {
     (*l4_utcb_br()).br[0] = 0;
 }
 
-unsafe fn br_bytes_mut() -> (*mut u8, usize) {
-    let br = &mut (*l4_utcb_br()).br;
-    let len = min(br[0] as usize, BR_DATA_BYTES);
-    (br.as_mut_ptr().add(1) as *mut u8, len)
-}
-
 unsafe fn br_bytes() -> (*const u8, usize) {
     let br = &(*l4_utcb_br()).br;
     let len = min(br[0] as usize, BR_DATA_BYTES);
@@ -74,84 +114,247 @@ unsafe fn read_aiocb(expected: usize) -> Result<aiocb, ()> {
     Ok(cb)
 }
 
-fn read_buffer_from_aiocb(cb: &aiocb) -> (usize, libc::off_t) {
-    let len = cb.aio_nbytes as usize;
-    let offset = cb.aio_offset as libc::off_t;
-    (len, offset)
+fn perform_read(fs: &FsClient, handle: u64, buf: &mut [u8], offset: u64) -> Result<isize, c_int> {
+    fs.pread(handle, buf, offset).map(|n| n as isize)
 }
 
-fn perform_read(fd: RawFd, len: usize, offset: libc::off_t) -> Result<(Vec<u8>, isize), c_int> {
-    if len > BR_DATA_BYTES {
-        return Err(libc::EOVERFLOW);
+fn perform_write(fs: &FsClient, handle: u64, payload: &[u8], offset: u64) -> Result<isize, c_int> {
+    fs.pwrite(handle, payload, offset).map(|n| n as isize)
+}
+
+fn perform_fsync(fs: &FsClient, handle: u64) -> Result<isize, c_int> {
+    fs.fsync(handle).map(|_| 0)
+}
+
+/// A shared submission/completion ring and the capabilities backing it.
+struct RingState {
+    ring: Ring,
+    base: *mut c_void,
+    ds: l4_cap_idx_t,
+    irq: l4_cap_idx_t,
+    /// IPC gate handed to the client that set up the ring. Doorbell and
+    /// teardown requests are only accepted through it.
+    gate: l4_cap_idx_t,
+    /// Label the gate delivers requests with.
+    label: u64,
+}
+
+impl Drop for RingState {
+    fn drop(&mut self) {
+        unsafe {
+            l4re_rm_detach(self.base);
+            // Delete the gate rather than just dropping our capability, so
+            // the client's copy stops reaching the server.
+            let _ = l4_task_delete_obj(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, self.gate);
+            l4re_util_cap_free(self.gate);
+            for cap in [self.ds, self.irq] {
+                let _ = l4_task_release_cap(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, cap);
+                l4re_util_cap_free(cap);
+            }
+        }
     }
-    let mut buf = vec![0u8; len];
-    let res = unsafe { libc::pread(fd, buf.as_mut_ptr() as *mut c_void, len, offset) };
-    if res < 0 {
-        let err = unsafe { *libc::__errno_location() };
-        Err(err)
-    } else {
-        let res = res as isize;
-        buf.truncate(res as usize);
-        Ok((buf, res))
+}
+
+fn completion_result(res: Result<isize, c_int>) -> i32 {
+    match res {
+        Ok(n) => n as i32,
+        Err(err) => -err,
     }
 }
 
-fn perform_write(fd: RawFd, payload: &[u8], offset: libc::off_t) -> Result<isize, c_int> {
-    let res = unsafe { libc::pwrite(fd, payload.as_ptr() as *const c_void, payload.len(), offset) };
-    if res < 0 {
-        let err = unsafe { *libc::__errno_location() };
-        Err(err)
-    } else {
-        Ok(res as isize)
+fn execute_sqe(fs: &FsClient, ring: &mut Ring, sqe: &Sqe) -> i32 {
+    let handle = sqe.fd as u64;
+    match sqe.opcode {
+        ring::OP_NOP => 0,
+        ring::OP_READ => match ring.data_mut(sqe.addr, sqe.len) {
+            Some(buf) => completion_result(perform_read(fs, handle, buf, sqe.off)),
+            None => -libc::EFAULT,
+        },
+        ring::OP_WRITE => match ring.data_mut(sqe.addr, sqe.len) {
+            Some(buf) => completion_result(perform_write(fs, handle, buf, sqe.off)),
+            None => -libc::EFAULT,
+        },
+        // FSYNC_DATASYNC flushes everything, see the module docs.
+        ring::OP_FSYNC => completion_result(perform_fsync(fs, handle)),
+        _ => -libc::EINVAL,
     }
 }
 
-fn perform_fsync(fd: RawFd, op: c_int) -> Result<isize, c_int> {
-    let res = if op == libc::O_DSYNC {
-        unsafe { libc::fdatasync(fd) }
-    } else {
-        unsafe { libc::fsync(fd) }
-    };
-    if res < 0 {
-        let err = unsafe { *libc::__errno_location() };
-        Err(err)
-    } else {
-        Ok(0)
+/// Allocate and map the dataspace for a ring and create its completion IRQ
+/// and its gate, which delivers requests with `label`.
+unsafe fn alloc_ring(layout: RingLayout, label: u64) -> Result<RingState, c_int> {
+    let size = l4::sys::round_page(layout.total) as usize;
+    let ds = l4re_util_cap_alloc();
+    if l4_is_invalid_cap(ds) {
+        return Err(libc::ENOMEM);
+    }
+    if l4re_ma_alloc(size, ds, 0) < 0 {
+        l4re_util_cap_free(ds);
+        return Err(libc::ENOMEM);
+    }
+    let mut base: *mut c_void = core::ptr::null_mut();
+    let flags = l4re::sys::l4re_rm_flags_values::L4RE_RM_F_SEARCH_ADDR as u64
+        | l4re::sys::l4re_rm_flags_values::L4RE_RM_F_RW as u64;
+    if l4re_rm_attach(&mut base, size, flags, ds, 0, l4::sys::L4_PAGESHIFT as u8) < 0 {
+        let _ = l4_task_release_cap(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, ds);
+        l4re_util_cap_free(ds);
+        return Err(libc::ENOMEM);
+    }
+    let irq = l4re_util_cap_alloc();
+    if l4_is_invalid_cap(irq)
+        || l4_ipc_error(l4_factory_create_irq((*l4re_env()).factory, irq), l4_utcb()) != 0
+    {
+        l4re_rm_detach(base);
+        let _ = l4_task_release_cap(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, ds);
+        l4re_util_cap_free(ds);
+        if !l4_is_invalid_cap(irq) {
+            l4re_util_cap_free(irq);
+        }
+        return Err(libc::ENOMEM);
+    }
+    let gate = l4re_util_cap_alloc();
+    if l4_is_invalid_cap(gate)
+        || l4_ipc_error(
+            l4_factory_create_gate((*l4re_env()).factory, gate, (*l4re_env()).main_thread, label),
+            l4_utcb(),
+        ) != 0
+    {
+        l4re_rm_detach(base);
+        for cap in [ds, irq] {
+            let _ = l4_task_release_cap(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, cap);
+            l4re_util_cap_free(cap);
+        }
+        if !l4_is_invalid_cap(gate) {
+            l4re_util_cap_free(gate);
+        }
+        return Err(libc::ENOMEM);
     }
+    let ring = Ring::init(base as *mut u8, layout);
+    Ok(RingState { ring, base, ds, irq, gate, label })
 }
 
-fn handle_aio_read(ops: &mut Slab<Operation>, mr: &mut [u64]) {
-    let struct_len = mr[1] as usize;
-    let cb = unsafe { read_aiocb(struct_len) };
-    let cb = match cb {
-        Ok(cb) => cb,
-        Err(_) => {
-            mr[0] = encode_errno_raw(libc::EINVAL);
-            unsafe { br_clear() };
-            return;
+/// Server state shared by all request handlers.
+struct Server {
+    fs: FsClient,
+    ops: Slab<Operation>,
+    rings: Slab<RingState>,
+    /// Serial number of the next ring, part of its gate label.
+    next_ring: u64,
+    queue: VecDeque<Job>,
+}
+
+impl Server {
+    fn new(fs: FsClient) -> Self {
+        Self { fs, ops: Slab::new(), rings: Slab::new(), next_ring: 0, queue: VecDeque::new() }
+    }
+
+    /// Receive timeout for the next wait: poll while work is queued so the
+    /// queue makes progress between client requests, block otherwise.
+    fn wait_timeout(&self) -> l4_timeout_t {
+        if self.queue.is_empty() {
+            l4_timeout_t { raw: 0 }
+        } else {
+            l4::sys::consts::L4_IPC_RECV_TIMEOUT_0
         }
-    };
+    }
+
+    /// Record a new operation and queue it. Replies with the operation handle.
+    fn submit(&mut self, op: Operation, mr: &mut [u64]) {
+        let slot = self.ops.insert(op);
+        self.queue.push_back(Job::Aio(slot));
+        mr[0] = slot as u64;
+    }
 
-    let (len, offset) = read_buffer_from_aiocb(&cb);
-    match perform_read(cb.aio_fildes, len, offset) {
-        Ok((buffer, result)) => {
-            let slot = ops.insert(Operation {
-                kind: OperationKind::Read,
-                fd: cb.aio_fildes,
-                result,
-                error: 0,
-                buffer,
-            });
-            mr[0] = slot as u64;
+    /// Forward the oldest queued job to fs_server. Returns `false` if there
+    /// was nothing to do.
+    fn run_next(&mut self) -> bool {
+        let Some(job) = self.queue.pop_front() else {
+            return false;
+        };
+        match job {
+            Job::Aio(slot) => {
+                let Some(op) = self.ops.get_mut(slot) else {
+                    return true;
+                };
+                let res = match op.kind {
+                    OperationKind::Read => perform_read(&self.fs, op.handle, &mut op.buffer, op.offset),
+                    OperationKind::Write => perform_write(&self.fs, op.handle, &op.buffer, op.offset),
+                    OperationKind::Fsync => perform_fsync(&self.fs, op.handle),
+                };
+                op.state = match res {
+                    Ok(result) => {
+                        if let OperationKind::Read = op.kind {
+                            op.buffer.truncate(result as usize);
+                        }
+                        OperationState::Done { result, error: 0 }
+                    }
+                    Err(error) => OperationState::Done { result: -1, error },
+                };
+            }
+            Job::Ring { ring, sqe } => {
+                let Some(state) = self.rings.get_mut(ring) else {
+                    return true;
+                };
+                let res = if state.ring.chain_failed() {
+                    -libc::ECANCELED
+                } else {
+                    execute_sqe(&self.fs, &mut state.ring, &sqe)
+                };
+                state.ring.note_result(&sqe, res);
+                state.ring.complete(Cqe { user_data: sqe.user_data, res, flags: 0 });
+                // Signal once per batch rather than once per completion.
+                let more = matches!(self.queue.front(), Some(Job::Ring { ring: r, .. }) if *r == ring);
+                if !more {
+                    unsafe {
+                        let _ = l4_irq_trigger(state.irq);
+                    }
+                }
+            }
         }
-        Err(err) => {
-            mr[0] = encode_errno_raw(err);
+        true
+    }
+
+    /// Forward up to [`IO_BATCH`] queued jobs to fs_server.
+    fn run_batch(&mut self) {
+        for _ in 0..IO_BATCH {
+            if !self.run_next() {
+                break;
+            }
         }
     }
+
+    fn is_done(&self, handle: usize) -> bool {
+        matches!(self.ops.get(handle), Some(Operation { state: OperationState::Done { .. }, .. }))
+    }
+}
+
+fn handle_aio_read(srv: &mut Server, mr: &mut [u64]) {
+    let struct_len = mr[1] as usize;
+    let cb = unsafe { read_aiocb(struct_len) };
     unsafe { br_clear() };
+    let Ok(cb) = cb else {
+        mr[0] = encode_errno_raw(libc::EINVAL);
+        return;
+    };
+    // The data is handed back through the buffer registers by aio_return.
+    let len = cb.aio_nbytes as usize;
+    if len > BR_DATA_BYTES {
+        mr[0] = encode_errno_raw(libc::EOVERFLOW);
+        return;
+    }
+    srv.submit(
+        Operation {
+            kind: OperationKind::Read,
+            handle: cb.aio_fildes as u64,
+            offset: cb.aio_offset as u64,
+            buffer: vec![0u8; len],
+            state: OperationState::InProgress,
+        },
+        mr,
+    );
 }
 
-fn handle_aio_write(ops: &mut Slab<Operation>, mr: &mut [u64]) {
+fn handle_aio_write(srv: &mut Server, mr: &mut [u64]) {
     let struct_len = mr[1] as usize;
     let payload_len = mr[2] as usize;
     let (ptr, total) = unsafe { br_bytes() };
@@ -166,115 +369,125 @@ fn handle_aio_write(ops: &mut Slab<Operation>, mr: &mut [u64]) {
         core::ptr::copy_nonoverlapping(ptr, &mut cb as *mut _ as *mut u8, core::cmp::min(struct_len, size_of::<aiocb>()));
     }
     let payload_ptr = unsafe { ptr.add(struct_len) };
-    let payload = unsafe { std::slice::from_raw_parts(payload_ptr, payload_len) };
-
-    match perform_write(cb.aio_fildes, payload, cb.aio_offset as libc::off_t) {
-        Ok(result) => {
-            let slot = ops.insert(Operation {
-                kind: OperationKind::Write,
-                fd: cb.aio_fildes,
-                result,
-                error: 0,
-                buffer: Vec::new(),
-            });
-            mr[0] = slot as u64;
-        }
-        Err(err) => {
-            mr[0] = encode_errno_raw(err);
-        }
-    }
+    let payload = unsafe { std::slice::from_raw_parts(payload_ptr, payload_len) }.to_vec();
     unsafe { br_clear() };
+
+    srv.submit(
+        Operation {
+            kind: OperationKind::Write,
+            handle: cb.aio_fildes as u64,
+            offset: cb.aio_offset as u64,
+            buffer: payload,
+            state: OperationState::InProgress,
+        },
+        mr,
+    );
 }
 
-fn handle_aio_fsync(ops: &mut Slab<Operation>, mr: &mut [u64]) {
+fn handle_aio_fsync(srv: &mut Server, mr: &mut [u64]) {
     let struct_len = mr[1] as usize;
-    let op_kind = mr[3] as c_int;
     let cb = unsafe { read_aiocb(struct_len) };
-    let cb = match cb {
-        Ok(cb) => cb,
-        Err(_) => {
-            mr[0] = encode_errno_raw(libc::EINVAL);
-            unsafe { br_clear() };
-            return;
-        }
-    };
-
-    match perform_fsync(cb.aio_fildes, op_kind) {
-        Ok(result) => {
-            let slot = ops.insert(Operation {
-                kind: OperationKind::Fsync,
-                fd: cb.aio_fildes,
-                result,
-                error: 0,
-                buffer: Vec::new(),
-            });
-            mr[0] = slot as u64;
-        }
-        Err(err) => {
-            mr[0] = encode_errno_raw(err);
-        }
-    }
     unsafe { br_clear() };
+    let Ok(cb) = cb else {
+        mr[0] = encode_errno_raw(libc::EINVAL);
+        return;
+    };
+    srv.submit(
+        Operation {
+            kind: OperationKind::Fsync,
+            handle: cb.aio_fildes as u64,
+            offset: 0,
+            buffer: Vec::new(),
+            state: OperationState::InProgress,
+        },
+        mr,
+    );
 }
 
-fn handle_aio_error(ops: &Slab<Operation>, mr: &mut [u64]) {
+fn handle_aio_error(srv: &Server, mr: &mut [u64]) {
     let handle = mr[1] as usize;
-    if let Some(op) = ops.get(handle) {
-        mr[0] = op.error as u64;
-    } else {
-        mr[0] = encode_errno_raw(libc::EINVAL);
-    }
+    mr[0] = match srv.ops.get(handle) {
+        Some(Operation { state: OperationState::InProgress, .. }) => libc::EINPROGRESS as u64,
+        Some(Operation { state: OperationState::Done { error, .. }, .. }) => *error as u64,
+        None => encode_errno_raw(libc::EINVAL),
+    };
     unsafe { br_clear() };
 }
 
-fn handle_aio_return(ops: &mut Slab<Operation>, mr: &mut [u64]) {
+fn handle_aio_return(srv: &mut Server, mr: &mut [u64]) {
     let handle = mr[1] as usize;
-    if ops.contains(handle) {
-        let op = ops.remove(handle);
-        if op.error != 0 {
-            mr[0] = encode_errno_raw(op.error);
-            unsafe { br_clear() };
-            return;
-        }
-        mr[0] = op.result as u64;
-        match op.kind {
-            OperationKind::Read => {
-                mr[1] = op.buffer.len() as u64;
-                unsafe {
-                    let br = &mut (*l4_utcb_br()).br;
-                    let len = op.buffer.len().min(BR_DATA_BYTES);
-                    br[0] = len as u64;
-                    if len > 0 {
-                        let dst = br.as_mut_ptr().add(1) as *mut u8;
-                        std::ptr::copy_nonoverlapping(op.buffer.as_ptr(), dst, len);
-                    }
+    if !srv.is_done(handle) {
+        let err = if srv.ops.contains(handle) { libc::EINPROGRESS } else { libc::EINVAL };
+        mr[0] = encode_errno_raw(err);
+        unsafe { br_clear() };
+        return;
+    }
+    let op = srv.ops.remove(handle);
+    let OperationState::Done { result, error } = op.state else {
+        unreachable!();
+    };
+    if error != 0 {
+        mr[0] = encode_errno_raw(error);
+        unsafe { br_clear() };
+        return;
+    }
+    mr[0] = result as u64;
+    match op.kind {
+        OperationKind::Read => {
+            mr[1] = op.buffer.len() as u64;
+            unsafe {
+                let br = &mut (*l4_utcb_br()).br;
+                let len = op.buffer.len().min(BR_DATA_BYTES);
+                br[0] = len as u64;
+                if len > 0 {
+                    let dst = br.as_mut_ptr().add(1) as *mut u8;
+                    std::ptr::copy_nonoverlapping(op.buffer.as_ptr(), dst, len);
                 }
             }
-            _ => {
-                mr[1] = 0;
-                unsafe { br_clear() };
-            }
         }
-        return;
+        _ => {
+            mr[1] = 0;
+            unsafe { br_clear() };
+        }
     }
-    mr[0] = encode_errno_raw(libc::EINVAL);
-    unsafe { br_clear() };
 }
 
-fn handle_aio_cancel(ops: &mut Slab<Operation>, mr: &mut [u64]) {
+fn handle_aio_cancel(srv: &mut Server, mr: &mut [u64]) {
     let handle = mr[1] as usize;
-    if ops.contains(handle) {
-        ops.remove(handle);
-        mr[0] = libc::AIO_ALLDONE as u64;
-    } else {
-        mr[0] = libc::AIO_ALLDONE as u64;
-    }
+    mr[0] = match srv.ops.get(handle) {
+        Some(Operation { state: OperationState::InProgress, .. }) => {
+            srv.ops.remove(handle);
+            srv.queue.retain(|job| !matches!(job, Job::Aio(slot) if *slot == handle));
+            libc::AIO_CANCELED as u64
+        }
+        Some(_) => {
+            srv.ops.remove(handle);
+            libc::AIO_ALLDONE as u64
+        }
+        None => libc::AIO_ALLDONE as u64,
+    };
     unsafe { br_clear() };
 }
 
-fn handle_aio_suspend(mr: &mut [u64]) {
-    let _nent = mr[1] as usize;
-    // All operations are completed synchronously for now.
+/// MR1 = number of handles, handles in the buffer registers. Returns once at
+/// least one of the listed operations has completed.
+fn handle_aio_suspend(srv: &mut Server, mr: &mut [u64]) {
+    let nent = mr[1] as usize;
+    let (ptr, len) = unsafe { br_bytes() };
+    let count = min(nent, len / size_of::<u64>());
+    let handles: Vec<usize> = (0..count)
+        .map(|i| unsafe { core::ptr::read_unaligned((ptr as *const u64).add(i)) } as usize)
+        .collect();
+
+    let listed_pending = |srv: &Server| {
+        handles.iter().any(|h| srv.ops.contains(*h)) && !handles.iter().any(|h| srv.is_done(*h))
+    };
+    while listed_pending(srv) {
+        if !srv.run_next() {
+            break;
+        }
+    }
+    // Forwarding I/O reuses the UTCB, so set up the reply only now.
     mr[0] = 0;
     unsafe { br_clear() };
 }
@@ -285,16 +498,107 @@ fn handle_lio_listio(mr: &mut [u64]) {
     unsafe { br_clear() };
 }
 
+/// MR1 = SQ entries, MR2 = CQ entries (0 = twice MR1), MR3 = data area bytes.
+/// Replies with MR0 = ring handle, MR1 = dataspace size and maps the ring
+/// dataspace, the completion IRQ and the ring gate to the client. The ring
+/// gate is the client's proof of ownership: `RING_ENTER` and `RING_DESTROY`
+/// only act on a ring when sent through its own gate.
+unsafe fn handle_ring_setup(srv: &mut Server, mr: &mut [u64]) -> l4_msgtag_t {
+    br_clear();
+    let data_len = match usize::try_from(mr[3]) {
+        Ok(len) => len,
+        Err(_) => {
+            mr[0] = encode_errno_raw(libc::EINVAL);
+            return l4_msgtag(0, 1, 0, 0);
+        }
+    };
+    let Some(layout) = RingLayout::new(mr[1] as u32, mr[2] as u32, data_len) else {
+        mr[0] = encode_errno_raw(libc::EINVAL);
+        return l4_msgtag(0, 1, 0, 0);
+    };
+    let label = RING_LABEL | (srv.next_ring << 2);
+    let state = match alloc_ring(layout, label) {
+        Ok(state) => state,
+        Err(err) => {
+            mr[0] = encode_errno_raw(err);
+            return l4_msgtag(0, 1, 0, 0);
+        }
+    };
+    srv.next_ring += 1;
+    let (ds, irq, gate) = (state.ds, state.irq, state.gate);
+    mr[0] = srv.rings.insert(state) as u64;
+    mr[1] = layout.total as u64;
+
+    let rights = L4_cap_fpage_rights::L4_CAP_FPAGE_RWS as u8;
+    let mut tag = l4_msgtag(0, 2, 0, 0);
+    l4_sndfpage_add(l4_obj_fpage(ds, 0, rights), 0, &mut tag);
+    l4_sndfpage_add(l4_obj_fpage(irq, 0, rights), 0, &mut tag);
+    l4_sndfpage_add(l4_obj_fpage(gate, 0, rights), 0, &mut tag);
+    tag
+}
+
+/// Doorbell, sent through the ring gate. MR1 = ring handle, MR2 = maximum
+/// SQEs to consume (0 = all). Replies with MR0 = number of SQEs queued for
+/// execution; completions are signalled through the ring IRQ.
+unsafe fn handle_ring_enter(srv: &mut Server, mr: &mut [u64], label: u64) {
+    br_clear();
+    let ring = mr[1] as usize;
+    let Some(state) = srv.rings.get_mut(ring).filter(|state| state.label == label) else {
+        mr[0] = encode_errno_raw(libc::EBADF);
+        return;
+    };
+    let budget = if mr[2] == 0 { u64::MAX } else { mr[2] };
+    let mut consumed = 0u64;
+    while consumed < budget {
+        let Some(sqe) = state.ring.pop_sqe() else {
+            break;
+        };
+        // Jobs execute in submission order, which satisfies both link
+        // chains and drain barriers.
+        srv.queue.push_back(Job::Ring { ring, sqe });
+        consumed += 1;
+    }
+    mr[0] = consumed;
+}
+
+/// MR1 = ring handle, sent through the ring gate.
+unsafe fn handle_ring_destroy(srv: &mut Server, mr: &mut [u64], label: u64) {
+    br_clear();
+    let handle = mr[1] as usize;
+    if srv.rings.get(handle).is_some_and(|state| state.label == label) {
+        srv.rings.remove(handle);
+        srv.queue.retain(|job| !matches!(job, Job::Ring { ring, .. } if *ring == handle));
+        mr[0] = 0;
+    } else {
+        mr[0] = encode_errno_raw(libc::EBADF);
+    }
+}
+
+/// [`Server::run_batch`] while a reply is staged in the UTCB. Forwarding
+/// I/O reuses the message and buffer registers, so they are restored
+/// afterwards.
+unsafe fn run_batch_before_reply(srv: &mut Server) {
+    if srv.queue.is_empty() {
+        return;
+    }
+    let mr = (*l4_utcb_mr()).mr;
+    let (bdr, br) = ((*l4_utcb_br()).bdr, (*l4_utcb_br()).br);
+    srv.run_batch();
+    (*l4_utcb_mr()).mr = mr;
+    (*l4_utcb_br()).bdr = bdr;
+    (*l4_utcb_br()).br = br;
+}
+
 fn main() {
     unsafe { run() }
 }
 
 unsafe fn run() {
     let gate = l4re_env_get_cap("global_aio").expect("IPC gate 'global_aio' not provided");
+    let fs = FsClient::new().expect("IPC gate 'global_fs' not provided");
 
-    let gatelabel = 0b1111_0000u64;
     if l4_ipc_error(
-        l4::l4_rcv_ep_bind_thread(gate, (*l4re_env()).main_thread, gatelabel),
+        l4::l4_rcv_ep_bind_thread(gate, (*l4re_env()).main_thread, GATE_LABEL),
         l4_utcb(),
     ) != 0
     {
@@ -303,36 +607,45 @@ unsafe fn run() {
 
     println!("aio server ready");
 
-    let mut ops: Slab<Operation> = Slab::new();
+    let mut srv = Server::new(fs);
     let mut label = 0u64;
-    let mut tag = l4_ipc_wait(l4_utcb(), &mut label, l4_timeout_t { raw: 0 });
+    let mut tag = l4_ipc_wait(l4_utcb(), &mut label, srv.wait_timeout());
     loop {
-        if l4_ipc_error(tag, l4_utcb()) != 0 {
-            tag = l4_ipc_wait(l4_utcb(), &mut label, l4_timeout_t { raw: 0 });
+        let err = l4_ipc_error(tag, l4_utcb());
+        if err != 0 {
+            // Nothing arrived within the poll timeout: spend the idle time on
+            // queued I/O. Other errors, such as a client that vanished before
+            // its reply, say nothing about whether clients are waiting.
+            if err == l4_ipc_tcr_error_t::L4_IPC_RETIMEOUT as u64 {
+                srv.run_batch();
+            }
+            tag = l4_ipc_wait(l4_utcb(), &mut label, srv.wait_timeout());
             continue;
         }
 
         let mr = &mut (*l4_utcb_mr()).mr;
+        let client = label & LABEL_MASK;
+        let mut reply = l4_msgtag(0, 2, 0, 0);
         match mr[0] {
-            opcode::AIO_READ => handle_aio_read(&mut ops, mr),
-            opcode::AIO_WRITE => handle_aio_write(&mut ops, mr),
-            opcode::AIO_ERROR => handle_aio_error(&ops, mr),
-            opcode::AIO_RETURN => handle_aio_return(&mut ops, mr),
-            opcode::AIO_CANCEL => handle_aio_cancel(&mut ops, mr),
-            opcode::AIO_SUSPEND => handle_aio_suspend(mr),
-            opcode::AIO_FSYNC => handle_aio_fsync(&mut ops, mr),
+            opcode::AIO_READ => handle_aio_read(&mut srv, mr),
+            opcode::AIO_WRITE => handle_aio_write(&mut srv, mr),
+            opcode::AIO_ERROR => handle_aio_error(&srv, mr),
+            opcode::AIO_RETURN => handle_aio_return(&mut srv, mr),
+            opcode::AIO_CANCEL => handle_aio_cancel(&mut srv, mr),
+            opcode::AIO_SUSPEND => handle_aio_suspend(&mut srv, mr),
+            opcode::AIO_FSYNC => handle_aio_fsync(&mut srv, mr),
             opcode::LIO_LISTIO => handle_lio_listio(mr),
+            opcode::RING_SETUP => reply = handle_ring_setup(&mut srv, mr),
+            opcode::RING_ENTER => handle_ring_enter(&mut srv, mr, client),
+            opcode::RING_DESTROY => handle_ring_destroy(&mut srv, mr, client),
             _ => {
                 mr[0] = encode_errno_raw(libc::ENOSYS);
                 br_clear();
             }
         }
 
-        tag = l4_ipc_reply_and_wait(
-            l4_utcb(),
-            l4_msgtag(0, 2, 0, 0),
-            &mut label,
-            l4_timeout_t { raw: 0 },
-        );
+        // Keep the queue moving even if requests never stop coming in.
+        run_batch_before_reply(&mut srv);
+        tag = l4_ipc_reply_and_wait(l4_utcb(), reply, &mut label, srv.wait_timeout());
     }
 }
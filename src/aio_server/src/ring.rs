@@ -0,0 +1,478 @@
+//! Shared submission/completion rings modelled on io_uring.
+//!
+//! A ring lives in a single dataspace shared between client and server:
+//!
+//! ```text
+//! +-------------+----------------------+----------------------+-------------+
+//! | RingHeader  | Sqe[sq_entries]      | Cqe[cq_entries]      | data area   |
+//! +-------------+----------------------+----------------------+-------------+
+//! ```
+//!
+//! The client fills submission queue entries (SQEs), publishes them by
+//! advancing `sq_tail` and rings the doorbell (`RING_ENTER` on the ring's own
+//! gate). The server
+//! consumes entries from `sq_head`, executes them and appends completion queue
+//! entries (CQEs) at `cq_tail`. The client reaps completions by advancing
+//! `cq_head`. Payloads never travel through the UTCB: `Sqe::addr` is an offset
+//! into the data area at the end of the dataspace.
+//!
+//! Ordering follows io_uring: an entry flagged with [`SQE_IO_LINK`] makes the
+//! next entry depend on it, and if any member of a linked chain fails, the
+//! remaining members complete with `-ECANCELED`. [`SQE_IO_DRAIN`] delays an
+//! entry until everything submitted before it has completed.
+//!
+//! Consumed entries are executed later, so the ring reserves a CQ slot for
+//! every entry in flight; a client that does not reap completions stalls
+//! submission instead of losing CQEs.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// No operation; completes immediately with result 0.
+pub const OP_NOP: u8 = 0;
+/// Read `len` bytes at file offset `off` into the data area at `addr`.
+pub const OP_READ: u8 = 1;
+/// Write `len` bytes from the data area at `addr` to file offset `off`.
+pub const OP_WRITE: u8 = 2;
+/// Flush the file; `op_flags` may carry [`FSYNC_DATASYNC`].
+pub const OP_FSYNC: u8 = 3;
+
+/// The next SQE only starts after this one completed successfully.
+pub const SQE_IO_LINK: u8 = 1 << 0;
+/// Start this SQE only after all previously submitted SQEs completed.
+pub const SQE_IO_DRAIN: u8 = 1 << 1;
+
+/// `OP_FSYNC` flag: only flush data, like `fdatasync`. fs_server has no
+/// data-only flush, so this currently flushes everything.
+pub const FSYNC_DATASYNC: u32 = 1 << 0;
+
+/// Largest number of entries accepted for either queue.
+pub const MAX_ENTRIES: u32 = 4096;
+
+/// Control block at the start of the shared dataspace.
+#[repr(C)]
+pub struct RingHeader {
+    /// Next SQE the server consumes. Written by the server.
+    pub sq_head: AtomicU32,
+    /// One past the last SQE published by the client. Written by the client.
+    pub sq_tail: AtomicU32,
+    pub sq_entries: u32,
+    /// Byte offset of the SQE array from the start of the dataspace.
+    pub sq_off: u32,
+    /// Next CQE the client reaps. Written by the client.
+    pub cq_head: AtomicU32,
+    /// One past the last CQE posted by the server. Written by the server.
+    pub cq_tail: AtomicU32,
+    pub cq_entries: u32,
+    /// Byte offset of the CQE array from the start of the dataspace.
+    pub cq_off: u32,
+    /// Byte offset of the data area from the start of the dataspace.
+    pub data_off: u32,
+    /// Size of the data area in bytes.
+    pub data_len: u32,
+    /// Number of completions that could not be posted because the CQ was full.
+    pub cq_overflow: AtomicU32,
+    pub reserved: u32,
+}
+
+/// Submission queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,
+    /// Offset of the payload within the data area.
+    pub addr: u64,
+    pub len: u32,
+    pub op_flags: u32,
+    /// Opaque value copied into the matching CQE.
+    pub user_data: u64,
+}
+
+/// Completion queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Cqe {
+    pub user_data: u64,
+    /// Bytes transferred or a negative errno.
+    pub res: i32,
+    pub flags: u32,
+}
+
+/// Sizes and offsets of a ring laid out according to the module docs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RingLayout {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub sq_off: usize,
+    pub cq_off: usize,
+    pub data_off: usize,
+    pub data_len: usize,
+    pub total: usize,
+}
+
+impl RingLayout {
+    /// Compute the layout for the requested queue depths. `cq_entries` of 0
+    /// selects twice the submission depth, as io_uring does. Returns `None` if
+    /// a depth is zero, not a power of two or larger than [`MAX_ENTRIES`].
+    pub fn new(sq_entries: u32, cq_entries: u32, data_len: usize) -> Option<Self> {
+        let cq_entries = if cq_entries == 0 { sq_entries.checked_mul(2)? } else { cq_entries };
+        for n in [sq_entries, cq_entries] {
+            if n == 0 || !n.is_power_of_two() || n > MAX_ENTRIES {
+                return None;
+            }
+        }
+        let sq_off = align_up(size_of::<RingHeader>(), 64);
+        let cq_off = align_up(sq_off + sq_entries as usize * size_of::<Sqe>(), 64);
+        let data_off = align_up(cq_off + cq_entries as usize * size_of::<Cqe>(), 64);
+        let total = data_off.checked_add(data_len)?;
+        if total > u32::MAX as usize {
+            return None;
+        }
+        Some(Self { sq_entries, cq_entries, sq_off, cq_off, data_off, data_len, total })
+    }
+}
+
+fn align_up(v: usize, align: usize) -> usize {
+    (v + align - 1) & !(align - 1)
+}
+
+/// Server-side view of a ring mapped at `base`.
+pub struct Ring {
+    base: *mut u8,
+    layout: RingLayout,
+    /// A member of the current link chain failed; cancel the rest of it.
+    chain_failed: bool,
+    /// Entries consumed from the SQ whose completion has not been posted.
+    inflight: u32,
+}
+
+impl Ring {
+    /// Initialise the header of freshly allocated ring memory.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to at least `layout.total` writable bytes aligned to
+    /// 64 bytes that stay mapped for the lifetime of the returned `Ring`.
+    pub unsafe fn init(base: *mut u8, layout: RingLayout) -> Self {
+        core::ptr::write_bytes(base, 0, layout.data_off);
+        let hdr = &mut *(base as *mut RingHeader);
+        hdr.sq_entries = layout.sq_entries;
+        hdr.sq_off = layout.sq_off as u32;
+        hdr.cq_entries = layout.cq_entries;
+        hdr.cq_off = layout.cq_off as u32;
+        hdr.data_off = layout.data_off as u32;
+        hdr.data_len = layout.data_len as u32;
+        Self { base, layout, chain_failed: false, inflight: 0 }
+    }
+
+    pub fn layout(&self) -> &RingLayout {
+        &self.layout
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.base as *const RingHeader) }
+    }
+
+    fn sqe(&self, index: u32) -> Sqe {
+        let slot = (index & (self.layout.sq_entries - 1)) as usize;
+        unsafe {
+            let ptr = self.base.add(self.layout.sq_off) as *const Sqe;
+            core::ptr::read_volatile(ptr.add(slot))
+        }
+    }
+
+    /// Number of SQEs published by the client but not yet consumed.
+    pub fn sq_pending(&self) -> u32 {
+        let hdr = self.header();
+        hdr.sq_tail.load(Ordering::Acquire).wrapping_sub(hdr.sq_head.load(Ordering::Relaxed))
+    }
+
+    /// Free slots in the completion queue.
+    pub fn cq_space(&self) -> u32 {
+        let hdr = self.header();
+        let used = hdr.cq_tail.load(Ordering::Relaxed).wrapping_sub(hdr.cq_head.load(Ordering::Acquire));
+        self.layout.cq_entries.saturating_sub(used)
+    }
+
+    /// Borrow `len` bytes of the data area starting at `addr`.
+    pub fn data_mut(&mut self, addr: u64, len: u32) -> Option<&mut [u8]> {
+        let start = usize::try_from(addr).ok()?;
+        let end = start.checked_add(len as usize)?;
+        if end > self.layout.data_len {
+            return None;
+        }
+        unsafe {
+            let ptr = self.base.add(self.layout.data_off + start);
+            Some(core::slice::from_raw_parts_mut(ptr, len as usize))
+        }
+    }
+
+    /// Consume the next SQE if there is one and a CQ slot can be reserved for
+    /// its completion.
+    pub fn pop_sqe(&mut self) -> Option<Sqe> {
+        if self.sq_pending() == 0 || self.cq_space() <= self.inflight {
+            return None;
+        }
+        let hdr = self.header();
+        let head = hdr.sq_head.load(Ordering::Relaxed);
+        let sqe = self.sqe(head);
+        hdr.sq_head.store(head.wrapping_add(1), Ordering::Release);
+        self.inflight += 1;
+        Some(sqe)
+    }
+
+    /// Whether the entry about to be executed belongs to a link chain with a
+    /// failed member and must be completed with `-ECANCELED`.
+    pub fn chain_failed(&self) -> bool {
+        self.chain_failed
+    }
+
+    /// Record the outcome of an SQE for link-chain tracking.
+    pub fn note_result(&mut self, sqe: &Sqe, res: i32) {
+        if sqe.flags & SQE_IO_LINK != 0 {
+            self.chain_failed |= res < 0;
+        } else {
+            self.chain_failed = false;
+        }
+    }
+
+    /// Post the completion of an entry previously returned by
+    /// [`Ring::pop_sqe`]. Returns `false` and bumps the overflow counter if
+    /// the CQ is full, which only happens if the client moved `cq_head`
+    /// backwards.
+    pub fn complete(&mut self, cqe: Cqe) -> bool {
+        self.inflight = self.inflight.saturating_sub(1);
+        let hdr = self.header();
+        if self.cq_space() == 0 {
+            hdr.cq_overflow.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let tail = hdr.cq_tail.load(Ordering::Relaxed);
+        let slot = (tail & (self.layout.cq_entries - 1)) as usize;
+        unsafe {
+            let ptr = self.base.add(self.layout.cq_off) as *mut Cqe;
+            core::ptr::write_volatile(ptr.add(slot), cqe);
+        }
+        hdr.cq_tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(64))]
+    struct Line([u8; 64]);
+
+    /// Ring memory and the server's view of it.
+    struct TestRing {
+        _mem: Vec<Line>,
+        ring: Ring,
+    }
+
+    impl TestRing {
+        fn new(sq_entries: u32, cq_entries: u32) -> Self {
+            let layout = RingLayout::new(sq_entries, cq_entries, 256).unwrap();
+            let mut mem: Vec<Line> = (0..layout.total.div_ceil(64)).map(|_| Line([0; 64])).collect();
+            let ring = unsafe { Ring::init(mem.as_mut_ptr() as *mut u8, layout) };
+            Self { _mem: mem, ring }
+        }
+
+        /// Start all indices at `index`, as if the ring had been in use for a
+        /// while.
+        fn set_indices(&mut self, index: u32) {
+            let hdr = self.ring.header();
+            for idx in [&hdr.sq_head, &hdr.sq_tail, &hdr.cq_head, &hdr.cq_tail] {
+                idx.store(index, Ordering::Relaxed);
+            }
+        }
+
+        /// Publish an SQE the way a client does.
+        fn submit(&mut self, opcode: u8, flags: u8, user_data: u64) {
+            let layout = self.ring.layout;
+            let hdr = self.ring.header();
+            let tail = hdr.sq_tail.load(Ordering::Relaxed);
+            let slot = (tail & (layout.sq_entries - 1)) as usize;
+            let sqe = Sqe { opcode, flags, user_data, ..Default::default() };
+            unsafe {
+                let ptr = self.ring.base.add(layout.sq_off) as *mut Sqe;
+                core::ptr::write_volatile(ptr.add(slot), sqe);
+            }
+            hdr.sq_tail.store(tail.wrapping_add(1), Ordering::Release);
+        }
+
+        /// Consume every SQE the ring hands out.
+        fn pop_all(&mut self) -> Vec<Sqe> {
+            core::iter::from_fn(|| self.ring.pop_sqe()).collect()
+        }
+
+        /// Execute consumed SQEs in order the way the server does, with
+        /// `exec` standing in for fs_server.
+        fn run(&mut self, sqes: &[Sqe], mut exec: impl FnMut(&Ring, &Sqe) -> i32) {
+            for sqe in sqes {
+                let res = if self.ring.chain_failed() { -libc::ECANCELED } else { exec(&self.ring, sqe) };
+                self.ring.note_result(sqe, res);
+                assert!(self.ring.complete(Cqe { user_data: sqe.user_data, res, flags: 0 }));
+            }
+        }
+
+        /// Reap up to `max` posted CQEs the way a client does.
+        fn reap_some(&mut self, max: usize) -> Vec<Cqe> {
+            let layout = self.ring.layout;
+            let hdr = self.ring.header();
+            let mut head = hdr.cq_head.load(Ordering::Relaxed);
+            let tail = hdr.cq_tail.load(Ordering::Acquire);
+            let mut cqes = Vec::new();
+            while head != tail && cqes.len() < max {
+                let slot = (head & (layout.cq_entries - 1)) as usize;
+                unsafe {
+                    let ptr = self.ring.base.add(layout.cq_off) as *const Cqe;
+                    cqes.push(core::ptr::read_volatile(ptr.add(slot)));
+                }
+                head = head.wrapping_add(1);
+            }
+            hdr.cq_head.store(head, Ordering::Release);
+            cqes
+        }
+
+        fn reap(&mut self) -> Vec<Cqe> {
+            self.reap_some(usize::MAX)
+        }
+    }
+
+    fn results(cqes: &[Cqe]) -> Vec<(u64, i32)> {
+        cqes.iter().map(|cqe| (cqe.user_data, cqe.res)).collect()
+    }
+
+    #[test]
+    fn layout_rejects_bad_depths() {
+        assert_eq!(RingLayout::new(4, 0, 0).unwrap().cq_entries, 8);
+        assert!(RingLayout::new(0, 4, 0).is_none());
+        assert!(RingLayout::new(3, 4, 0).is_none());
+        assert!(RingLayout::new(MAX_ENTRIES * 2, 0, 0).is_none());
+    }
+
+    #[test]
+    fn queues_wrap_around() {
+        let mut t = TestRing::new(4, 4);
+        // Cross both the end of the arrays and the end of the index space.
+        t.set_indices(u32::MAX - 5);
+        let mut next = 0u64;
+        for _ in 0..5 {
+            for _ in 0..3 {
+                t.submit(OP_NOP, 0, next);
+                next += 1;
+            }
+            assert_eq!(t.ring.sq_pending(), 3);
+            let sqes = t.pop_all();
+            assert_eq!(sqes.iter().map(|sqe| sqe.user_data).collect::<Vec<_>>(), [next - 3, next - 2, next - 1]);
+            t.run(&sqes, |_, _| 0);
+            assert_eq!(t.ring.cq_space(), 1);
+            let cqes = t.reap();
+            assert_eq!(results(&cqes), [(next - 3, 0), (next - 2, 0), (next - 1, 0)]);
+            assert_eq!(t.ring.sq_pending(), 0);
+            assert_eq!(t.ring.cq_space(), 4);
+        }
+        assert!(t.ring.header().sq_head.load(Ordering::Relaxed) < 16);
+    }
+
+    #[test]
+    fn full_cq_stalls_submission() {
+        let mut t = TestRing::new(4, 2);
+        for user_data in 0..4 {
+            t.submit(OP_NOP, 0, user_data);
+        }
+        // Only as many entries as there are CQ slots are consumed.
+        let sqes = t.pop_all();
+        assert_eq!(sqes.len(), 2);
+        assert_eq!(t.ring.sq_pending(), 2);
+        t.run(&sqes, |_, _| 0);
+        assert_eq!(t.ring.cq_space(), 0);
+        assert!(t.ring.pop_sqe().is_none());
+
+        // Reaping one completion frees room for one more entry.
+        assert_eq!(results(&t.reap_some(1)), [(0, 0)]);
+        let sqes = t.pop_all();
+        assert_eq!(sqes.len(), 1);
+        t.run(&sqes, |_, _| 0);
+        assert_eq!(results(&t.reap()), [(1, 0), (2, 0)]);
+        let sqes = t.pop_all();
+        assert_eq!(sqes.len(), 1);
+        t.run(&sqes, |_, _| 0);
+        assert_eq!(results(&t.reap()), [(3, 0)]);
+        assert_eq!(t.ring.header().cq_overflow.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn failed_link_cancels_rest_of_chain() {
+        let mut t = TestRing::new(8, 8);
+        t.submit(OP_WRITE, SQE_IO_LINK, 0);
+        t.submit(OP_WRITE, SQE_IO_LINK, 1);
+        t.submit(OP_FSYNC, 0, 2);
+        // Not linked to the chain above.
+        t.submit(OP_NOP, 0, 3);
+        let sqes = t.pop_all();
+        let mut executed = Vec::new();
+        t.run(&sqes, |_, sqe| {
+            executed.push(sqe.user_data);
+            if sqe.user_data == 0 {
+                -libc::EIO
+            } else {
+                0
+            }
+        });
+        assert_eq!(executed, [0, 3]);
+        assert_eq!(
+            results(&t.reap()),
+            [(0, -libc::EIO), (1, -libc::ECANCELED), (2, -libc::ECANCELED), (3, 0)]
+        );
+    }
+
+    #[test]
+    fn successful_link_runs_whole_chain() {
+        let mut t = TestRing::new(4, 4);
+        t.submit(OP_WRITE, SQE_IO_LINK, 0);
+        t.submit(OP_FSYNC, 0, 1);
+        let sqes = t.pop_all();
+        t.run(&sqes, |_, _| 0);
+        assert_eq!(results(&t.reap()), [(0, 0), (1, 0)]);
+    }
+
+    #[test]
+    fn drain_waits_for_earlier_entries() {
+        let mut t = TestRing::new(8, 8);
+        t.submit(OP_WRITE, SQE_IO_LINK, 0);
+        t.submit(OP_WRITE, 0, 1);
+        t.submit(OP_WRITE, 0, 2);
+        t.submit(OP_FSYNC, SQE_IO_DRAIN, 3);
+        t.submit(OP_NOP, 0, 4);
+        let sqes = t.pop_all();
+        assert_eq!(sqes[3].flags, SQE_IO_DRAIN);
+        let mut posted_before_drain = None;
+        t.run(&sqes, |ring, sqe| {
+            if sqe.flags & SQE_IO_DRAIN != 0 {
+                let hdr = ring.header();
+                let posted = hdr.cq_tail.load(Ordering::Relaxed).wrapping_sub(hdr.cq_head.load(Ordering::Relaxed));
+                posted_before_drain = Some(posted);
+            }
+            if sqe.user_data == 0 {
+                -libc::EIO
+            } else {
+                0
+            }
+        });
+        // Everything submitted earlier, including the cancelled chain, has
+        // completed when the drain entry starts, and the drain entry itself
+        // does not inherit the failure of the chain before it.
+        assert_eq!(posted_before_drain, Some(3));
+        assert_eq!(
+            results(&t.reap()),
+            [(0, -libc::EIO), (1, -libc::ECANCELED), (2, 0), (3, 0), (4, 0)]
+        );
+    }
+}
@@ -0,0 +1,12 @@
+//! Console service on the virtio-console device, independent of L4Re.
+//!
+//! The device's ports, such as the shell console, a log channel or a debug
+//! agent, are opened by name or id and used as byte streams, see
+//! [`Console`]. The `console_server` binary (feature `l4re`) drives the
+//! device and serves [`proto`] over IPC; on the host, tests run against
+//! the simulated device of `virtio_frontend`.
+
+pub mod proto;
+pub mod service;
+
+pub use service::{Console, PortInfo};
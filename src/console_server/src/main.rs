@@ -0,0 +1,175 @@
+//! Console service on the virtio-console device.
+//!
+//! Clients list the device's ports and open them as streams through the
+//! `global_console` gate, see [`proto`] for the IPC protocol. The device
+//! registers arrive as the `virtio_console` dataspace, its interrupt as
+//! `virtio_console_irq`. Streams never block; clients retry reads and
+//! writes that fail with `EAGAIN`.
+
+use console_server::{proto, Console};
+use core::ffi::c_void;
+use core::mem::size_of;
+use l4::sys::{l4_cap_idx_t, l4_ipc_error, l4_irq_unmask, l4_msgtag, l4_timeout_t, l4_utcb};
+use l4_sys::{l4_utcb_br, l4_utcb_mr};
+use l4re::sys::{l4re_env, l4re_env_get_cap, l4re_rm_attach};
+use std::cmp::min;
+use virtio_frontend::console::ConsoleDriver;
+use virtio_frontend::mmio::{MmioRegion, MmioTransport};
+
+const BR_WORDS: usize = l4_sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
+const BR_DATA_BYTES: usize = (BR_WORDS - 1) * size_of::<u64>();
+
+/// Label of client requests arriving through the `global_console` gate.
+const GATE_LABEL: u64 = 0b1111_0000;
+/// Label of virtio-console interrupts.
+const IRQ_LABEL: u64 = 0b1_0000_0000;
+/// The two least significant label bits carry the rights of the sender's
+/// capability.
+const LABEL_MASK: u64 = !0b11;
+
+fn encode_errno(err: i32) -> u64 {
+    (-(err as i64)) as u64
+}
+
+/// Copy the payload passed in the buffer registers.
+unsafe fn br_payload() -> Vec<u8> {
+    let br = &(*l4_utcb_br()).br;
+    let len = min(br[0] as usize, BR_DATA_BYTES);
+    let mut data = vec![0u8; len];
+    core::ptr::copy_nonoverlapping(br.as_ptr().add(1) as *const u8, data.as_mut_ptr(), len);
+    data
+}
+
+/// Return `data` to the client through the buffer registers.
+unsafe fn br_reply(data: &[u8]) {
+    let br = &mut (*l4_utcb_br()).br;
+    let len = min(data.len(), BR_DATA_BYTES);
+    br[0] = len as u64;
+    core::ptr::copy_nonoverlapping(data.as_ptr(), br.as_mut_ptr().add(1) as *mut u8, len);
+}
+
+/// Handle one request. Returns the value of `MR0` and the number of words
+/// in the reply.
+unsafe fn dispatch(console: &mut Console<MmioRegion>, client: u64) -> Result<(u64, u32), i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    match mr[0] {
+        proto::OP_LIST => {
+            let index = usize::try_from(mr[1]).map_err(|_| libc::ENOENT)?;
+            let (count, port) = console.list(index)?;
+            br_reply(port.name.as_deref().unwrap_or_default().as_bytes());
+            mr[1] = port.id as u64;
+            mr[2] = port.flags;
+            Ok((count as u64, 3))
+        }
+        proto::OP_OPEN => {
+            let handle = if mr[1] == proto::OPEN_BY_NAME {
+                let name = String::from_utf8(br_payload()).map_err(|_| libc::ENOENT)?;
+                console.open_name(client, &name)?
+            } else {
+                let id = u32::try_from(mr[1]).map_err(|_| libc::ENOENT)?;
+                console.open(client, id)?
+            };
+            Ok((handle, 1))
+        }
+        proto::OP_READ => {
+            let mut buf = vec![0u8; min(mr[2] as usize, BR_DATA_BYTES)];
+            let n = console.read(client, mr[1], &mut buf)?;
+            br_reply(&buf[..n]);
+            Ok((n as u64, 1))
+        }
+        proto::OP_WRITE => {
+            let data = br_payload();
+            let n = console.write(client, mr[1], &data)?;
+            Ok((n as u64, 1))
+        }
+        proto::OP_CLOSE => {
+            console.close(client, mr[1])?;
+            Ok((0, 1))
+        }
+        proto::OP_POLL => Ok((console.poll(client, mr[1])? as u64, 1)),
+        _ => Err(libc::ENOSYS),
+    }
+}
+
+unsafe fn bind(cap: l4_cap_idx_t, label: u64) -> bool {
+    l4_ipc_error(
+        l4::l4_rcv_ep_bind_thread(cap, (*l4re_env()).main_thread, label),
+        l4_utcb(),
+    ) == 0
+}
+
+/// Map the device registers, found under `virtio_console`, and initialise
+/// the device.
+unsafe fn open_device() -> Option<Console<MmioRegion>> {
+    let regs = l4re_env_get_cap("virtio_console")?;
+    let mut base: *mut c_void = core::ptr::null_mut();
+    let flags = l4re::sys::l4re_rm_flags_values::L4RE_RM_F_SEARCH_ADDR as u64
+        | l4re::sys::l4re_rm_flags_values::L4RE_RM_F_RW as u64;
+    let size = 1 << l4::sys::L4_PAGESHIFT;
+    if l4re_rm_attach(&mut base, size, flags, regs, 0, l4::sys::L4_PAGESHIFT as u8) < 0 {
+        return None;
+    }
+    let transport = MmioTransport::new(MmioRegion::new(base as *mut u8)).ok()?;
+    match ConsoleDriver::new(transport) {
+        Ok(driver) => Some(Console::new(driver)),
+        Err(e) => {
+            println!("console_server: virtio-console: {e}");
+            None
+        }
+    }
+}
+
+fn main() {
+    unsafe { run() }
+}
+
+/// Unsafe portion of the server. Interacts directly with L4 system calls.
+unsafe fn run() {
+    let gate = l4re_env_get_cap("global_console").expect("IPC gate 'global_console' not provided");
+    if !bind(gate, GATE_LABEL) {
+        panic!("failed to bind IPC gate");
+    }
+    let mut console = open_device().expect("no virtio-console device");
+    let irq = l4re_env_get_cap("virtio_console_irq").expect("virtio-console IRQ not provided");
+    if !bind(irq, IRQ_LABEL) {
+        panic!("failed to bind virtio-console IRQ");
+    }
+    let _ = l4_irq_unmask(irq);
+
+    println!("console server ready");
+
+    // Wait for client requests and device interrupts. The device announces
+    // its ports after initialisation, so they show up with the first
+    // interrupts.
+    let mut label = 0u64;
+    let mut tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4_timeout_t { raw: 0 });
+    loop {
+        let failed = l4_ipc_error(tag, l4_utcb()) != 0;
+        if !failed && label & LABEL_MASK == IRQ_LABEL {
+            let _ = l4_irq_unmask(irq);
+            if let Err(err) = console.interrupt() {
+                println!("console_server: virtio-console: error {err}");
+            }
+        }
+        if failed || label & LABEL_MASK == IRQ_LABEL {
+            tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4_timeout_t { raw: 0 });
+            continue;
+        }
+
+        let client = label & LABEL_MASK;
+        // Pick up data that arrived since the last interrupt.
+        let _ = console.poll_device();
+        let (result, words) = match dispatch(&mut console, client) {
+            Ok(reply) => reply,
+            Err(err) => (encode_errno(err), 1),
+        };
+        (*l4_utcb_mr()).mr[0] = result;
+
+        tag = l4::l4_ipc_reply_and_wait(
+            l4_utcb(),
+            l4_msgtag(0, words, 0, 0),
+            &mut label,
+            l4_timeout_t { raw: 0 },
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Message register layout of the console protocol.
+//!
+//! ```text
+//! MR0: operation
+//!      0 = list    MR1: index
+//!                  Reply: MR0 = number of ports, MR1 = port id,
+//!                  MR2 = port flags, BRs: port name
+//!      1 = open    MR1: port id, or OPEN_BY_NAME with the name in the BRs
+//!                  Reply: MR0 = stream handle
+//!      2 = read    MR1: handle, MR2: maximum length
+//!                  Reply: MR0 = length, BRs: data
+//!      3 = write   MR1: handle, BRs: data
+//!                  Reply: MR0 = bytes written
+//!      4 = close   MR1: handle
+//!      5 = poll    MR1: handle
+//!                  Reply: MR0 = POLLIN, POLLOUT and POLLHUP bits
+//! ```
+//!
+//! Data and names travel through the buffer registers: `BR0` holds the
+//! length in bytes and the data follows from `BR1` onwards. Ports are
+//! listed in the order of their ids; listing past the last one fails with
+//! `-ENOENT`, as does opening a port the device does not have. A port is
+//! open through one stream at a time, further opens fail with `-EBUSY`.
+//!
+//! Streams never block: `read` fails with `-EAGAIN` while no data is
+//! buffered and `write` while the port's transmit queue is full. Once the
+//! device removed a port, `read` returns 0 and `write` fails with `-EPIPE`.
+//!
+//! `MR0` of a reply is 0 or a handle/count on success, `-errno` on failure.
+
+pub const OP_LIST: u64 = 0;
+pub const OP_OPEN: u64 = 1;
+pub const OP_READ: u64 = 2;
+pub const OP_WRITE: u64 = 3;
+pub const OP_CLOSE: u64 = 4;
+pub const OP_POLL: u64 = 5;
+
+/// Port id of `open` asking for the port named in the buffer registers.
+pub const OPEN_BY_NAME: u64 = u64::MAX;
+
+// Port flags.
+/// The port is the console.
+pub const PORT_CONSOLE: u64 = 1 << 0;
+/// A program on the host side has the port open.
+pub const PORT_HOST_CONNECTED: u64 = 1 << 1;
+/// A stream has the port open.
+pub const PORT_OPEN: u64 = 1 << 2;
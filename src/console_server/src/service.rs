@@ -0,0 +1,204 @@
+//! Streams on the ports of a virtio-console device.
+
+use std::collections::BTreeMap;
+
+use libc::{EAGAIN, EBADF, EBUSY, EIO, ENOENT, EPIPE};
+use virtio_frontend::console::{ConsoleDriver, ConsoleError};
+use virtio_frontend::mmio::Registers;
+use virtio_frontend::queue::QueueError;
+
+use crate::proto::{PORT_CONSOLE, PORT_HOST_CONNECTED, PORT_OPEN};
+
+/// A port as listed to clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortInfo {
+    pub id: u32,
+    pub name: Option<String>,
+    /// `PORT_*` flags of [`crate::proto`].
+    pub flags: u64,
+}
+
+/// An open port.
+struct Stream {
+    client: u64,
+    port: u32,
+    /// The device removed the port.
+    gone: bool,
+}
+
+/// The ports of a console device, opened as streams by clients.
+///
+/// Handles belong to the client that opened them; other clients get
+/// `EBADF`. Results are `errno` values as in the protocol.
+pub struct Console<R: Registers> {
+    driver: ConsoleDriver<R>,
+    streams: BTreeMap<u64, Stream>,
+    next_handle: u64,
+}
+
+fn errno(err: ConsoleError) -> i32 {
+    match err {
+        ConsoleError::Queue(QueueError::Full) => EAGAIN,
+        ConsoleError::NoPort(_) => EPIPE,
+        ConsoleError::Closed(_) => EBADF,
+        _ => EIO,
+    }
+}
+
+impl<R: Registers> Console<R> {
+    pub fn new(driver: ConsoleDriver<R>) -> Self {
+        Self {
+            driver,
+            streams: BTreeMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    pub fn driver(&self) -> &ConsoleDriver<R> {
+        &self.driver
+    }
+
+    pub fn driver_mut(&mut self) -> &mut ConsoleDriver<R> {
+        &mut self.driver
+    }
+
+    /// Handle a device interrupt.
+    pub fn interrupt(&mut self) -> Result<(), i32> {
+        let res = self.driver.interrupt().map_err(errno);
+        self.end_removed();
+        res
+    }
+
+    /// Process what the device delivered without an interrupt.
+    pub fn poll_device(&mut self) -> Result<(), i32> {
+        let res = self.driver.poll().map_err(errno);
+        self.end_removed();
+        res
+    }
+
+    /// End the streams of ports the device removed.
+    fn end_removed(&mut self) {
+        for id in self.driver.take_removed() {
+            for stream in self.streams.values_mut() {
+                if stream.port == id {
+                    stream.gone = true;
+                }
+            }
+        }
+    }
+
+    /// Whether a stream has port `id` open.
+    fn in_use(&self, id: u32) -> bool {
+        self.streams.values().any(|s| s.port == id && !s.gone)
+    }
+
+    /// Number of ports and the port at `index`.
+    pub fn list(&self, index: usize) -> Result<(usize, PortInfo), i32> {
+        let count = self.driver.ports().count();
+        let port = self.driver.ports().nth(index).ok_or(ENOENT)?;
+        let mut flags = 0;
+        if port.console {
+            flags |= PORT_CONSOLE;
+        }
+        if port.host_connected {
+            flags |= PORT_HOST_CONNECTED;
+        }
+        if self.in_use(port.id) {
+            flags |= PORT_OPEN;
+        }
+        let info = PortInfo {
+            id: port.id,
+            name: port.name.clone(),
+            flags,
+        };
+        Ok((count, info))
+    }
+
+    /// Open port `id` for `client` and return the stream handle.
+    pub fn open(&mut self, client: u64, id: u32) -> Result<u64, i32> {
+        if self.driver.port(id).is_none() {
+            return Err(ENOENT);
+        }
+        if self.in_use(id) {
+            return Err(EBUSY);
+        }
+        self.driver.open(id).map_err(errno)?;
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        let stream = Stream {
+            client,
+            port: id,
+            gone: false,
+        };
+        self.streams.insert(handle, stream);
+        Ok(handle)
+    }
+
+    /// Open the port called `name`.
+    pub fn open_name(&mut self, client: u64, name: &str) -> Result<u64, i32> {
+        let id = self.driver.find(name).ok_or(ENOENT)?;
+        self.open(client, id)
+    }
+
+    /// Port of the stream `handle` of `client`, `None` if it is gone.
+    fn stream(&self, client: u64, handle: u64) -> Result<Option<u32>, i32> {
+        match self.streams.get(&handle) {
+            Some(stream) if stream.client == client => Ok((!stream.gone).then_some(stream.port)),
+            _ => Err(EBADF),
+        }
+    }
+
+    /// Read buffered data into `buf`.
+    pub fn read(&mut self, client: u64, handle: u64, buf: &mut [u8]) -> Result<usize, i32> {
+        let Some(port) = self.stream(client, handle)? else {
+            // The device removed the port: end of stream.
+            return Ok(0);
+        };
+        match self.driver.read(port, buf).map_err(errno)? {
+            0 if !buf.is_empty() => Err(EAGAIN),
+            n => Ok(n),
+        }
+    }
+
+    /// Queue `data` for the host side. Returns how much was taken.
+    pub fn write(&mut self, client: u64, handle: u64, data: &[u8]) -> Result<usize, i32> {
+        let port = self.stream(client, handle)?.ok_or(EPIPE)?;
+        self.driver.write(port, data).map_err(errno)
+    }
+
+    pub fn close(&mut self, client: u64, handle: u64) -> Result<(), i32> {
+        let port = self.stream(client, handle)?;
+        self.streams.remove(&handle);
+        let Some(port) = port else {
+            return Ok(());
+        };
+        match self.driver.close(port) {
+            Ok(()) | Err(ConsoleError::NoPort(_)) => Ok(()),
+            Err(err) => Err(errno(err)),
+        }
+    }
+
+    /// `POLLIN`, `POLLOUT` and `POLLHUP` flags of a stream. `POLLIN` is set
+    /// whenever `read` would not fail with `EAGAIN`, `POLLOUT` while the
+    /// port exists and `POLLHUP` once it is gone or its host side
+    /// disconnected.
+    pub fn poll(&self, client: u64, handle: u64) -> Result<u32, i32> {
+        let port = self.stream(client, handle)?;
+        let (pollin, pollout, pollhup) = (
+            libc::POLLIN as u32,
+            libc::POLLOUT as u32,
+            libc::POLLHUP as u32,
+        );
+        let Some(info) = port.and_then(|id| self.driver.port(id)) else {
+            return Ok(pollin | pollhup);
+        };
+        let mut events = pollout;
+        if self.driver.available(info.id) > 0 {
+            events |= pollin;
+        }
+        if !info.host_connected {
+            events |= pollhup;
+        }
+        Ok(events)
+    }
+}
@@ -0,0 +1,130 @@
+use console_server::proto::{PORT_CONSOLE, PORT_HOST_CONNECTED, PORT_OPEN};
+use console_server::Console;
+use libc::{EAGAIN, EBADF, EBUSY, ENOENT, EPIPE};
+use virtio_frontend::console::ConsoleDriver;
+use virtio_frontend::device::{ConsoleDevice, SimDevice};
+use virtio_frontend::mmio::MmioTransport;
+
+const CLIENT: u64 = 1;
+const OTHER: u64 = 2;
+
+type SimConsole = Console<SimDevice<ConsoleDevice>>;
+
+/// A console with the shell console, a log channel and a debug agent port.
+fn console() -> SimConsole {
+    let mut model = ConsoleDevice::new(8, 80, 25);
+    model.add_port(Some("shell"), true).unwrap();
+    model.add_port(Some("log"), false).unwrap();
+    model.add_port(Some("debug"), false).unwrap();
+    // SAFETY: the driver's queues live in the driver owning the device.
+    let device = unsafe { SimDevice::new(model) };
+    let driver = ConsoleDriver::new(MmioTransport::new(device).unwrap()).unwrap();
+    let mut console = Console::new(driver);
+    settle(&mut console);
+    console
+}
+
+fn device(console: &mut SimConsole) -> &mut ConsoleDevice {
+    console.driver_mut().transport_mut().regs_mut().model_mut()
+}
+
+/// Let the device run and the server handle its interrupts until both are
+/// idle.
+fn settle(console: &mut SimConsole) {
+    for _ in 0..8 {
+        console.driver_mut().transport_mut().regs_mut().poll();
+        console.interrupt().unwrap();
+    }
+}
+
+#[test]
+fn list_ports() {
+    let console = console();
+    let names: Vec<_> = (0..3)
+        .map(|i| console.list(i).unwrap().1.name.unwrap())
+        .collect();
+    assert_eq!(names, ["shell", "log", "debug"]);
+    let (count, shell) = console.list(0).unwrap();
+    assert_eq!(count, 3);
+    // The console is opened by the driver, but not by a stream.
+    assert_eq!(shell.flags, PORT_CONSOLE | PORT_HOST_CONNECTED);
+    assert_eq!(console.list(3), Err(ENOENT));
+}
+
+#[test]
+fn streams_per_port() {
+    let mut console = console();
+    let log = console.open_name(CLIENT, "log").unwrap();
+    let debug = console.open_name(CLIENT, "debug").unwrap();
+    assert_eq!(console.open_name(OTHER, "log"), Err(EBUSY));
+    assert_eq!(console.open_name(CLIENT, "nope"), Err(ENOENT));
+    assert_eq!(console.list(1).unwrap().1.flags & PORT_OPEN, PORT_OPEN);
+
+    // Writes reach the host side of their own port only.
+    assert_eq!(console.write(CLIENT, log, b"boot ok\n"), Ok(8));
+    assert_eq!(console.write(CLIENT, debug, b"ping"), Ok(4));
+    settle(&mut console);
+    assert_eq!(device(&mut console).host_read(1), b"boot ok\n");
+    assert_eq!(device(&mut console).host_read(2), b"ping");
+
+    // Reads never block.
+    let mut buf = [0u8; 64];
+    assert_eq!(console.read(CLIENT, debug, &mut buf), Err(EAGAIN));
+    device(&mut console).host_write(2, b"pong");
+    settle(&mut console);
+    assert_eq!(
+        console.poll(CLIENT, debug),
+        Ok((libc::POLLIN | libc::POLLOUT) as u32)
+    );
+    assert_eq!(console.read(CLIENT, debug, &mut buf), Ok(4));
+    assert_eq!(&buf[..4], b"pong");
+    assert_eq!(console.read(CLIENT, log, &mut buf), Err(EAGAIN));
+
+    // Handles belong to their client.
+    assert_eq!(console.read(OTHER, debug, &mut buf), Err(EBADF));
+    assert_eq!(console.close(OTHER, debug), Err(EBADF));
+    console.close(CLIENT, debug).unwrap();
+    assert_eq!(console.read(CLIENT, debug, &mut buf), Err(EBADF));
+    settle(&mut console);
+    assert!(!device(&mut console).guest_open(2));
+    console.open_name(OTHER, "debug").unwrap();
+}
+
+#[test]
+fn large_write_in_chunks() {
+    let mut console = console();
+    let shell = console.open_name(CLIENT, "shell").unwrap();
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let mut sent = 0;
+    let mut received = Vec::new();
+    while sent < data.len() {
+        match console.write(CLIENT, shell, &data[sent..]) {
+            Ok(n) => sent += n,
+            Err(err) => assert_eq!(err, EAGAIN),
+        }
+        settle(&mut console);
+        received.extend(device(&mut console).host_read(0));
+    }
+    assert_eq!(received, data);
+}
+
+#[test]
+fn removed_port_ends_stream() {
+    let mut console = console();
+    let log = console.open_name(CLIENT, "log").unwrap();
+    device(&mut console).set_host_connected(1, false);
+    settle(&mut console);
+    let hup = libc::POLLHUP as u32;
+    assert_eq!(console.poll(CLIENT, log).unwrap() & hup, hup);
+
+    // A new port taking over the id is no continuation of the stream.
+    device(&mut console).remove_port(1);
+    device(&mut console).add_port(Some("agent"), false).unwrap();
+    settle(&mut console);
+    let mut buf = [0u8; 8];
+    assert_eq!(console.read(CLIENT, log, &mut buf), Ok(0));
+    assert_eq!(console.write(CLIENT, log, b"x"), Err(EPIPE));
+    let agent = console.open_name(OTHER, "agent").unwrap();
+    assert_eq!(console.write(OTHER, agent, b"hi"), Ok(2));
+    console.close(CLIENT, log).unwrap();
+}
@@ -0,0 +1,115 @@
+//! Answer cache honouring record TTLs.
+//!
+//! Answers are cached per name and query type for the smallest TTL of their
+//! records, bounded by [`MAX_TTL`]. Names that do not exist or lack records
+//! of the queried type are cached negatively for the SOA minimum of the
+//! answer (RFC 2308), or [`DEFAULT_NEGATIVE_TTL`] without an SOA record.
+
+use crate::wire::{RData, Record};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Upper bound for positive answers, in seconds.
+pub const MAX_TTL: u32 = 86_400;
+/// Negative answers without an SOA record, in seconds.
+pub const DEFAULT_NEGATIVE_TTL: u32 = 60;
+/// Upper bound for negative answers, in seconds.
+pub const MAX_NEGATIVE_TTL: u32 = 3_600;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Answer {
+    /// The records answering the query, CNAME records of the chain first.
+    Records(Vec<Record>),
+    /// The name does not exist or has no records of the type.
+    NotFound,
+}
+
+struct Entry {
+    answer: Answer,
+    expires: Instant,
+}
+
+pub struct Cache {
+    entries: HashMap<(String, u16), Entry>,
+    capacity: usize,
+}
+
+/// Time a positive answer may be cached.
+pub fn positive_ttl(records: &[Record]) -> u32 {
+    records
+        .iter()
+        .map(|r| r.ttl)
+        .min()
+        .unwrap_or(0)
+        .min(MAX_TTL)
+}
+
+/// Time a negative answer with `authority` records may be cached.
+pub fn negative_ttl(authority: &[Record]) -> u32 {
+    authority
+        .iter()
+        .find_map(|r| match r.data {
+            RData::Soa { minimum } => Some(r.ttl.min(minimum)),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_NEGATIVE_TTL)
+        .min(MAX_NEGATIVE_TTL)
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Cached answer for `name` and `qtype` that has not expired at `now`.
+    pub fn get(&mut self, name: &str, qtype: u16, now: Instant) -> Option<Answer> {
+        let key = (name.to_string(), qtype);
+        match self.entries.get(&key) {
+            Some(e) if e.expires > now => Some(e.answer.clone()),
+            Some(_) => {
+                self.entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache `answer` for `ttl` seconds. A full cache drops expired entries
+    /// first and then the entry closest to expiry.
+    pub fn insert(&mut self, name: &str, qtype: u16, answer: Answer, ttl: u32, now: Instant) {
+        if ttl == 0 || self.capacity == 0 {
+            return;
+        }
+        let key = (name.to_string(), qtype);
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.entries.retain(|_, e| e.expires > now);
+            if self.entries.len() >= self.capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.expires)
+                    .map(|(k, _)| k.clone());
+                if let Some(k) = oldest {
+                    self.entries.remove(&k);
+                }
+            }
+        }
+        let expires = now + Duration::from_secs(ttl as u64);
+        self.entries.insert(key, Entry { answer, expires });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,55 @@
+//! Static host table in the format of `/etc/hosts`.
+//!
+//! Each line holds an address followed by the canonical name and optional
+//! aliases; `#` starts a comment. Malformed lines are ignored.
+
+use crate::wire::normalize;
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hosts {
+    /// Address and names in file order. The first name is canonical.
+    entries: Vec<(IpAddr, Vec<String>)>,
+}
+
+impl Hosts {
+    pub fn parse(text: &str) -> Self {
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let Some(Ok(addr)) = fields.next().map(str::parse::<IpAddr>) else {
+                continue;
+            };
+            let names: Vec<String> = fields.map(normalize).collect();
+            if !names.is_empty() {
+                entries.push((addr, names));
+            }
+        }
+        Hosts { entries }
+    }
+
+    /// Canonical name and all addresses of `name`. The canonical name is the
+    /// one of the first matching line.
+    pub fn lookup(&self, name: &str) -> Option<(String, Vec<IpAddr>)> {
+        let name = normalize(name);
+        let mut found: Option<(String, Vec<IpAddr>)> = None;
+        for (addr, names) in &self.entries {
+            if names.contains(&name) {
+                let entry = found.get_or_insert_with(|| (names[0].clone(), Vec::new()));
+                if !entry.1.contains(addr) {
+                    entry.1.push(*addr);
+                }
+            }
+        }
+        found
+    }
+
+    /// Canonical name of the first line listing `addr`.
+    pub fn reverse(&self, addr: IpAddr) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(a, _)| *a == addr)
+            .map(|(_, names)| names[0].as_str())
+    }
+}
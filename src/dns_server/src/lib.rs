@@ -0,0 +1,21 @@
+//! DNS stub resolver.
+//!
+//! The library holds everything that does not depend on L4Re: the DNS
+//! message format, the answer cache, the parsers for `/etc/hosts` and
+//! `/etc/resolv.conf` and the resolver itself, which reaches name servers
+//! through a [`Transport`]. The `dns_server` binary (feature `l4re`) serves
+//! lookups over IPC as described in [`proto`]; without the feature the
+//! library builds and tests on the host.
+
+pub mod cache;
+pub mod hosts;
+pub mod proto;
+pub mod resolv;
+pub mod resolver;
+pub mod transport;
+pub mod wire;
+
+pub use hosts::Hosts;
+pub use resolv::ResolvConf;
+pub use resolver::{Family, HostInfo, LookupError, Resolver};
+pub use transport::{StdTransport, Transport};
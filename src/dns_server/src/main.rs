@@ -0,0 +1,286 @@
+//! A DNS stub resolver serving host name lookups via L4 IPC.
+//!
+//! Clients ask through the `global_dns` gate using the protocol described in
+//! [`proto`]. The configuration comes from `/etc/resolv.conf` and
+//! `/etc/hosts`, which are read through fs_server at startup and on
+//! `reload`. Queries go to the name servers through net_server sockets.
+//!
+//! Requests are served one at a time: a lookup that is neither in the host
+//! table nor in the cache keeps the server busy until a name server answers
+//! or the configured timeout expires.
+
+use core::mem::size_of;
+use dns_server::proto::{self, ADDR_WORDS};
+use dns_server::{Hosts, ResolvConf, Resolver, Transport};
+use fs_client::FsClient;
+use l4_sys::{l4_ipc_error, l4_msgtag, l4_utcb, l4_utcb_br, l4_utcb_mr};
+use l4re::sys::{l4re_env, l4re_env_get_cap};
+use net_client::{NetClient, SockAddr, EAGAIN, EINPROGRESS, SOCK_DGRAM, SOCK_STREAM};
+use std::cmp::min;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const BR_WORDS: usize = l4_sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
+const BR_DATA_BYTES: usize = (BR_WORDS - 1) * size_of::<u64>();
+
+/// Label of client requests arriving through the `global_dns` gate.
+const GATE_LABEL: u64 = 0b1111_0000;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const HOSTS: &str = "/etc/hosts";
+
+/// Sockets never block; waiting for a name server means polling.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Transport over net_server sockets.
+struct NetTransport {
+    net: NetClient,
+}
+
+fn errno(err: i32) -> io::Error {
+    io::Error::from_raw_os_error(err)
+}
+
+fn sock_addr(server: SocketAddr) -> SockAddr {
+    match server {
+        SocketAddr::V4(v4) => SockAddr::v4(v4.ip().octets(), v4.port()),
+        SocketAddr::V6(v6) => SockAddr::v6(v6.ip().octets(), v6.port()),
+    }
+}
+
+/// Retry `op` while it fails with `EAGAIN`, until `deadline`.
+fn poll<T>(deadline: Instant, mut op: impl FnMut() -> Result<T, i32>) -> io::Result<T> {
+    loop {
+        match op() {
+            Err(EAGAIN) if Instant::now() < deadline => std::thread::sleep(POLL_INTERVAL),
+            Err(EAGAIN) => return Err(io::ErrorKind::TimedOut.into()),
+            res => return res.map_err(errno),
+        }
+    }
+}
+
+impl NetTransport {
+    /// Run `f` on a fresh socket of type `ty` for talking to `addr` and
+    /// close it afterwards.
+    fn with_socket<T>(
+        &self,
+        addr: &SockAddr,
+        ty: i32,
+        f: impl FnOnce(u64) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let handle = self.net.socket(addr.family, ty, 0).map_err(errno)?;
+        let res = f(handle);
+        let _ = self.net.close(handle);
+        res
+    }
+}
+
+impl Transport for NetTransport {
+    fn udp(&mut self, server: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let addr = sock_addr(server);
+        let deadline = Instant::now() + timeout;
+        let net = &self.net;
+        self.with_socket(&addr, SOCK_DGRAM, |h| {
+            poll(deadline, || net.sendto(h, query, &addr))?;
+            let mut buf = vec![0u8; net_client::BR_DATA_MAX];
+            loop {
+                let (n, from) = poll(deadline, || net.recvfrom(h, &mut buf, 0))?;
+                if from != addr {
+                    continue;
+                }
+                if n == buf.len() {
+                    // Possibly cut off by the size of the buffer registers.
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+                buf.truncate(n);
+                return Ok(buf);
+            }
+        })
+    }
+
+    fn tcp(&mut self, server: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let addr = sock_addr(server);
+        let deadline = Instant::now() + timeout;
+        let net = &self.net;
+        self.with_socket(&addr, SOCK_STREAM, |h| {
+            match net.connect(h, &addr) {
+                Ok(()) | Err(EINPROGRESS) => {}
+                Err(err) => return Err(errno(err)),
+            }
+            // Sending fails with EAGAIN until the connection is established.
+            let frame = dns_server::transport::tcp_frame(query)?;
+            let mut sent = 0;
+            while sent < frame.len() {
+                sent += poll(deadline, || net.send(h, &frame[sent..]))?;
+            }
+            let mut msg = Vec::new();
+            let mut buf = vec![0u8; net_client::BR_DATA_MAX];
+            loop {
+                if msg.len() >= 2 {
+                    let len = u16::from_be_bytes([msg[0], msg[1]]) as usize;
+                    if msg.len() >= len + 2 {
+                        msg.truncate(len + 2);
+                        return Ok(msg.split_off(2));
+                    }
+                }
+                let n = poll(deadline, || net.recv(h, &mut buf, 0))?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                msg.extend_from_slice(&buf[..n]);
+            }
+        })
+    }
+}
+
+/// Contents of the file at `path`, or `None` if it does not exist.
+fn read_file(fs: &FsClient, path: &str) -> Option<String> {
+    // Opening creates missing files, so check first.
+    fs.stat(path).ok()?;
+    let handle = fs.open(path).ok()?;
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; fs_client::BR_DATA_MAX];
+    while let Ok(n) = fs.read(handle, &mut buf) {
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+    let _ = fs.close(handle);
+    Some(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Read the configuration files. Without fs_server the defaults apply.
+fn load_config(fs: Option<&FsClient>) -> (ResolvConf, Hosts) {
+    let read = |path| fs.and_then(|fs| read_file(fs, path)).unwrap_or_default();
+    (
+        ResolvConf::parse(&read(RESOLV_CONF)),
+        Hosts::parse(&read(HOSTS)),
+    )
+}
+
+/// Copy the payload passed in the buffer registers.
+unsafe fn br_payload() -> Vec<u8> {
+    let br = &(*l4_utcb_br()).br;
+    let len = min(br[0] as usize, BR_DATA_BYTES);
+    let mut data = vec![0u8; len];
+    core::ptr::copy_nonoverlapping(br.as_ptr().add(1) as *const u8, data.as_mut_ptr(), len);
+    data
+}
+
+/// Return `data` to the client through the buffer registers.
+unsafe fn br_reply(data: &[u8]) {
+    let br = &mut (*l4_utcb_br()).br;
+    let len = min(data.len(), BR_DATA_BYTES);
+    br[0] = len as u64;
+    core::ptr::copy_nonoverlapping(data.as_ptr(), br.as_mut_ptr().add(1) as *mut u8, len);
+}
+
+/// Execute the request in the message registers. Returns the value of `MR0`
+/// and the number of words in the reply, or an `EAI_*` code.
+unsafe fn dispatch(
+    resolver: &mut Resolver<NetTransport>,
+    fs: Option<&FsClient>,
+) -> Result<(u64, u32), i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    match mr[0] {
+        proto::OP_GETADDRINFO => {
+            let family = proto::family(mr[1] as i32)?;
+            let flags = mr[2];
+            let name = String::from_utf8(br_payload()).map_err(|_| proto::EAI_NONAME)?;
+            let info = if flags & proto::AI_NUMERICHOST != 0 {
+                let addr = name.parse::<IpAddr>().map_err(|_| proto::EAI_NONAME)?;
+                dns_server::HostInfo {
+                    canonical: name,
+                    addrs: vec![addr],
+                }
+            } else {
+                resolver
+                    .lookup_host(&name, family, Instant::now())
+                    .map_err(proto::lookup_error)?
+            };
+            let (count, canon_len, payload) =
+                proto::addrinfo_reply(&info, flags & proto::AI_CANONNAME != 0);
+            br_reply(&payload);
+            mr[1] = canon_len as u64;
+            Ok((count as u64, 2))
+        }
+        proto::OP_GETNAMEINFO => {
+            let addr = proto::decode_addr(&mr[1..1 + ADDR_WORDS])?;
+            let flags = mr[1 + ADDR_WORDS];
+            let name = if flags & proto::NI_NUMERICHOST != 0 {
+                addr.to_string()
+            } else {
+                match resolver.lookup_addr(addr, Instant::now()) {
+                    Ok(name) if flags & proto::NI_NOFQDN != 0 => {
+                        name.split('.').next().unwrap_or_default().to_string()
+                    }
+                    Ok(name) => name,
+                    Err(err) if flags & proto::NI_NAMEREQD != 0 => {
+                        return Err(proto::lookup_error(err))
+                    }
+                    Err(_) => addr.to_string(),
+                }
+            };
+            br_reply(name.as_bytes());
+            Ok((name.len() as u64, 1))
+        }
+        proto::OP_RELOAD => {
+            let (conf, hosts) = load_config(fs);
+            resolver.reconfigure(conf, hosts);
+            Ok((0, 1))
+        }
+        _ => Err(proto::EAI_FAIL),
+    }
+}
+
+fn main() {
+    unsafe { run(); }
+}
+
+/// Unsafe portion of the server. Interacts directly with L4 system calls.
+unsafe fn run() {
+    // Obtain the IPC gate capability named "global_dns" from the environment.
+    let gate = l4re_env_get_cap("global_dns").expect("IPC gate 'global_dns' not provided");
+
+    // Bind the gate to our main thread so clients can contact us.
+    if l4_ipc_error(
+        l4::l4_rcv_ep_bind_thread(gate, (*l4re_env()).main_thread, GATE_LABEL),
+        l4_utcb(),
+    ) != 0
+    {
+        panic!("failed to bind IPC gate");
+    }
+
+    let net = NetClient::new().expect("network service 'global_net' not provided");
+    let fs = FsClient::new();
+    let (conf, hosts) = load_config(fs.as_ref());
+    let mut resolver = Resolver::new(NetTransport { net }, conf, hosts);
+
+    println!("dns server ready");
+
+    // IPC loop handling lookups. Clients encode the operation in message
+    // register 0, see `proto` for the register layout.
+    let mut label = 0u64;
+    let mut tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4::l4_timeout_t { raw: 0 });
+    loop {
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4::l4_timeout_t { raw: 0 });
+            continue;
+        }
+
+        let (result, words) = match dispatch(&mut resolver, fs.as_ref()) {
+            Ok(reply) => reply,
+            Err(code) => (code as i64 as u64, 1),
+        };
+        (*l4_utcb_mr()).mr[0] = result;
+
+        tag = l4::l4_ipc_reply_and_wait(
+            l4_utcb(),
+            l4_msgtag(0, words, 0, 0),
+            &mut label,
+            l4::l4_timeout_t { raw: 0 },
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Message register layout for the resolver protocol.
+//!
+//! The resolver service communicates via L4 IPC message registers as
+//! follows:
+//!
+//! ```text
+//! MR0: operation
+//!      0 = getaddrinfo   MR1: family, MR2: flags, BRs: host name
+//!                        Reply: MR0 = number of addresses,
+//!                        MR1 = length of the canonical name,
+//!                        BRs: addresses followed by the canonical name
+//!      1 = getnameinfo   MR1..MR3: address, MR4: flags
+//!                        Reply: MR0 = length of the host name, BRs: host name
+//!      2 = reload        Re-read the configuration files and flush the cache
+//! ```
+//!
+//! `MR0` of a reply is negative on failure and holds one of the `EAI_*`
+//! codes of `getaddrinfo`. Families are `AF_UNSPEC`, `AF_INET` and
+//! `AF_INET6`. Of the `getaddrinfo` flags only `AI_CANONNAME` and
+//! `AI_NUMERICHOST` matter to the server; `getnameinfo` understands
+//! `NI_NUMERICHOST`, `NI_NOFQDN` and `NI_NAMEREQD`. Service names and ports
+//! are left to the client library.
+//!
+//! Names travel through the buffer registers: `BR0` holds the length in
+//! bytes and the data follows from `BR1` onwards. An address occupies three
+//! words, in the registers or in the buffer, encoded as by the network
+//! service with a port of 0:
+//!
+//! ```text
+//! word 0: family << 16
+//! word 1: address bytes 0..8 (network order)
+//! word 2: address bytes 8..16 (network order)
+//! ```
+//!
+//! At most [`MAX_ADDRS`] addresses are returned.
+
+use crate::resolver::{Family, HostInfo, LookupError};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const OP_GETADDRINFO: u64 = 0;
+pub const OP_GETNAMEINFO: u64 = 1;
+pub const OP_RELOAD: u64 = 2;
+
+pub const AI_CANONNAME: u64 = 0x02;
+pub const AI_NUMERICHOST: u64 = 0x04;
+pub const NI_NUMERICHOST: u64 = 0x01;
+pub const NI_NOFQDN: u64 = 0x04;
+pub const NI_NAMEREQD: u64 = 0x08;
+
+// Error codes as defined by glibc and uClibc.
+pub const EAI_NONAME: i32 = -2;
+pub const EAI_AGAIN: i32 = -3;
+pub const EAI_FAIL: i32 = -4;
+pub const EAI_FAMILY: i32 = -6;
+
+/// Number of words taken by an encoded address.
+pub const ADDR_WORDS: usize = 3;
+/// Addresses returned by a single `getaddrinfo`.
+pub const MAX_ADDRS: usize = 8;
+
+/// `getaddrinfo` failure code for `err`.
+pub fn lookup_error(err: LookupError) -> i32 {
+    match err {
+        LookupError::NotFound => EAI_NONAME,
+        LookupError::TryAgain => EAI_AGAIN,
+        LookupError::Failed => EAI_FAIL,
+    }
+}
+
+/// Address family requested by `af`.
+pub fn family(af: i32) -> Result<Family, i32> {
+    match af {
+        libc::AF_UNSPEC => Ok(Family::Any),
+        libc::AF_INET => Ok(Family::V4),
+        libc::AF_INET6 => Ok(Family::V6),
+        _ => Err(EAI_FAMILY),
+    }
+}
+
+pub fn encode_addr(addr: IpAddr) -> [u64; ADDR_WORDS] {
+    let mut bytes = [0u8; 16];
+    let family = match addr {
+        IpAddr::V4(a) => {
+            bytes[..4].copy_from_slice(&a.octets());
+            libc::AF_INET
+        }
+        IpAddr::V6(a) => {
+            bytes.copy_from_slice(&a.octets());
+            libc::AF_INET6
+        }
+    };
+    [
+        (family as u64) << 16,
+        u64::from_ne_bytes(bytes[..8].try_into().unwrap()),
+        u64::from_ne_bytes(bytes[8..].try_into().unwrap()),
+    ]
+}
+
+pub fn decode_addr(words: &[u64]) -> Result<IpAddr, i32> {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&words[1].to_ne_bytes());
+    bytes[8..].copy_from_slice(&words[2].to_ne_bytes());
+    match (words[0] >> 16) as i32 {
+        libc::AF_INET => Ok(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).into()),
+        libc::AF_INET6 => Ok(Ipv6Addr::from(bytes).into()),
+        _ => Err(EAI_FAMILY),
+    }
+}
+
+/// Buffer contents of a `getaddrinfo` reply: the number of addresses, the
+/// length of the canonical name and the payload.
+pub fn addrinfo_reply(info: &HostInfo, canonname: bool) -> (usize, usize, Vec<u8>) {
+    let addrs = &info.addrs[..info.addrs.len().min(MAX_ADDRS)];
+    let mut payload = Vec::with_capacity(addrs.len() * ADDR_WORDS * 8 + info.canonical.len());
+    for &addr in addrs {
+        for word in encode_addr(addr) {
+            payload.extend_from_slice(&word.to_ne_bytes());
+        }
+    }
+    let canon_len = if canonname { info.canonical.len() } else { 0 };
+    payload.extend_from_slice(&info.canonical.as_bytes()[..canon_len]);
+    (addrs.len(), canon_len, payload)
+}
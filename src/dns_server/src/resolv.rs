@@ -0,0 +1,104 @@
+//! Resolver configuration in the format of `/etc/resolv.conf`.
+//!
+//! Understood are `nameserver`, `domain`, `search` and the `ndots`,
+//! `timeout` and `attempts` options; everything else is ignored. As with
+//! glibc, the last of `domain` and `search` wins and a configuration without
+//! name servers queries `127.0.0.1`.
+
+use crate::wire::normalize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+pub const DNS_PORT: u16 = 53;
+/// Name servers consulted at most, as in glibc.
+pub const MAX_NAMESERVERS: usize = 3;
+const MAX_SEARCH: usize = 6;
+const MAX_NDOTS: u32 = 15;
+const MAX_TIMEOUT_SECS: u64 = 30;
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<SocketAddr>,
+    /// Domains appended to relative names.
+    pub search: Vec<String>,
+    /// Names with fewer dots are tried with the search domains first.
+    pub ndots: u32,
+    /// Time to wait for a single name server.
+    pub timeout: Duration,
+    /// Rounds through the list of name servers.
+    pub attempts: u32,
+}
+
+impl Default for ResolvConf {
+    fn default() -> Self {
+        ResolvConf {
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+        }
+    }
+}
+
+impl ResolvConf {
+    pub fn parse(text: &str) -> Self {
+        let mut conf = ResolvConf::default();
+        for line in text.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => {
+                    let addr = fields.next().and_then(|a| a.parse::<IpAddr>().ok());
+                    if let Some(addr) = addr {
+                        if conf.nameservers.len() < MAX_NAMESERVERS {
+                            conf.nameservers.push(SocketAddr::new(addr, DNS_PORT));
+                        }
+                    }
+                }
+                Some("domain") => conf.search = fields.take(1).map(normalize).collect(),
+                Some("search") => conf.search = fields.take(MAX_SEARCH).map(normalize).collect(),
+                Some("options") => fields.for_each(|opt| conf.option(opt)),
+                _ => {}
+            }
+        }
+        if conf.nameservers.is_empty() {
+            conf.nameservers
+                .push(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DNS_PORT));
+        }
+        conf
+    }
+
+    fn option(&mut self, opt: &str) {
+        let Some((key, value)) = opt.split_once(':') else {
+            return;
+        };
+        let Ok(value) = value.parse::<u32>() else {
+            return;
+        };
+        match key {
+            "ndots" => self.ndots = value.min(MAX_NDOTS),
+            "timeout" => {
+                self.timeout = Duration::from_secs((value as u64).clamp(1, MAX_TIMEOUT_SECS))
+            }
+            "attempts" => self.attempts = value.clamp(1, MAX_ATTEMPTS),
+            _ => {}
+        }
+    }
+
+    /// Names to query for `name`, in order.
+    pub fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(absolute) = name.strip_suffix('.') {
+            return vec![normalize(absolute)];
+        }
+        let name = normalize(name);
+        let searched = self.search.iter().map(|d| format!("{name}.{d}"));
+        let dots = name.matches('.').count() as u32;
+        if dots >= self.ndots {
+            std::iter::once(name.clone()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(name.clone())).collect()
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Stub resolver.
+//!
+//! Host names are looked up in the host table first, then in the cache and
+//! finally by asking the configured name servers, which are expected to
+//! resolve recursively. Relative names are qualified with the search domains
+//! of the configuration. CNAME chains are followed both within an answer and
+//! across queries.
+//!
+//! Time is passed in by the caller so that cache expiry does not depend on
+//! a particular clock.
+
+use crate::cache::{negative_ttl, positive_ttl, Answer, Cache};
+use crate::hosts::Hosts;
+use crate::resolv::ResolvConf;
+use crate::transport::Transport;
+use crate::wire::{
+    normalize, reverse_name, Message, Question, RData, Record, RCODE_NOERROR, RCODE_NXDOMAIN,
+    RCODE_SERVFAIL, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_PTR,
+};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Instant;
+
+/// Entries kept in the answer cache.
+pub const CACHE_ENTRIES: usize = 256;
+/// CNAME records followed for a single lookup.
+const MAX_CNAME_DEPTH: usize = 8;
+const MAX_HOST_NAME_LEN: usize = 253;
+
+/// Address families a host lookup asks for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Family {
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    fn accepts(self, addr: IpAddr) -> bool {
+        matches!(
+            (self, addr),
+            (Family::Any, _) | (Family::V4, IpAddr::V4(_)) | (Family::V6, IpAddr::V6(_))
+        )
+    }
+
+    fn qtypes(self) -> &'static [u16] {
+        match self {
+            Family::Any => &[TYPE_A, TYPE_AAAA],
+            Family::V4 => &[TYPE_A],
+            Family::V6 => &[TYPE_AAAA],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LookupError {
+    /// The name or address is not known.
+    NotFound,
+    /// No name server gave an answer; a later attempt may succeed.
+    TryAgain,
+    /// The name servers refused to answer.
+    Failed,
+}
+
+/// Result of a host lookup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostInfo {
+    /// Name at the end of the CNAME chain.
+    pub canonical: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+pub struct Resolver<T> {
+    transport: T,
+    conf: ResolvConf,
+    hosts: Hosts,
+    cache: Cache,
+    ids: RandomState,
+    queries: u64,
+}
+
+/// Follow the CNAME chain starting at `name` within `answers`. Returns the
+/// CNAME records followed plus the records of type `qtype` at the end of the
+/// chain, and the last name of the chain.
+fn follow_chain(answers: &[Record], name: &str, qtype: u16) -> (Vec<Record>, String) {
+    let mut chain = Vec::new();
+    let mut owner = name.to_string();
+    for _ in 0..MAX_CNAME_DEPTH {
+        let data: Vec<Record> = answers
+            .iter()
+            .filter(|r| r.name == owner && r.rtype == qtype)
+            .cloned()
+            .collect();
+        if !data.is_empty() {
+            chain.extend(data);
+            break;
+        }
+        match answers
+            .iter()
+            .find(|r| r.name == owner && r.rtype == TYPE_CNAME)
+        {
+            Some(
+                r @ Record {
+                    data: RData::Cname(target),
+                    ..
+                },
+            ) => {
+                owner = target.clone();
+                chain.push(r.clone());
+            }
+            _ => break,
+        }
+    }
+    (chain, owner)
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<T: Transport> Resolver<T> {
+    pub fn new(transport: T, conf: ResolvConf, hosts: Hosts) -> Self {
+        Resolver {
+            transport,
+            conf,
+            hosts,
+            cache: Cache::new(CACHE_ENTRIES),
+            ids: RandomState::new(),
+            queries: 0,
+        }
+    }
+
+    /// Replace the configuration and drop all cached answers.
+    pub fn reconfigure(&mut self, conf: ResolvConf, hosts: Hosts) {
+        self.conf = conf;
+        self.hosts = hosts;
+        self.cache.clear();
+    }
+
+    pub fn config(&self) -> &ResolvConf {
+        &self.conf
+    }
+
+    /// Number of cached answers.
+    pub fn cached(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Addresses of `name`. Numeric addresses are returned as they are.
+    pub fn lookup_host(
+        &mut self,
+        name: &str,
+        family: Family,
+        now: Instant,
+    ) -> Result<HostInfo, LookupError> {
+        if let Ok(addr) = name.parse::<IpAddr>() {
+            if !family.accepts(addr) {
+                return Err(LookupError::NotFound);
+            }
+            return Ok(HostInfo {
+                canonical: name.to_string(),
+                addrs: vec![addr],
+            });
+        }
+        if name.is_empty() || name.len() > MAX_HOST_NAME_LEN + 1 {
+            return Err(LookupError::NotFound);
+        }
+        if let Some(info) = self.lookup_hosts(name, family) {
+            return Ok(info);
+        }
+
+        let mut err = LookupError::NotFound;
+        for candidate in self.conf.candidates(name) {
+            let mut info = HostInfo {
+                canonical: candidate.clone(),
+                addrs: Vec::new(),
+            };
+            for &qtype in family.qtypes() {
+                match self.resolve(&candidate, qtype, now, 0) {
+                    Ok(records) => {
+                        for r in records {
+                            match r.data {
+                                RData::A(a) => info.addrs.push(a.into()),
+                                RData::Aaaa(a) => info.addrs.push(a.into()),
+                                RData::Cname(target) => info.canonical = target,
+                                _ => {}
+                            }
+                        }
+                    }
+                    Err(LookupError::NotFound) => {}
+                    Err(e) => err = e,
+                }
+            }
+            if !info.addrs.is_empty() {
+                return Ok(info);
+            }
+        }
+        Err(err)
+    }
+
+    /// Host table entry of `name`. `localhost` resolves to the loopback
+    /// addresses unless the table says otherwise (RFC 6761).
+    fn lookup_hosts(&self, name: &str, family: Family) -> Option<HostInfo> {
+        let (canonical, addrs) = match self.hosts.lookup(name) {
+            Some(entry) => entry,
+            None if normalize(name) == "localhost" => (
+                "localhost".to_string(),
+                vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+            ),
+            None => return None,
+        };
+        let addrs: Vec<IpAddr> = addrs.into_iter().filter(|a| family.accepts(*a)).collect();
+        (!addrs.is_empty()).then_some(HostInfo { canonical, addrs })
+    }
+
+    /// Host name of `addr`.
+    pub fn lookup_addr(&mut self, addr: IpAddr, now: Instant) -> Result<String, LookupError> {
+        if let Some(name) = self.hosts.reverse(addr) {
+            return Ok(name.to_string());
+        }
+        self.resolve(&reverse_name(addr), TYPE_PTR, now, 0)?
+            .into_iter()
+            .find_map(|r| match r.data {
+                RData::Ptr(name) => Some(name),
+                _ => None,
+            })
+            .ok_or(LookupError::NotFound)
+    }
+
+    /// Records of type `qtype` for `name`, preceded by the CNAME records
+    /// leading to them.
+    fn resolve(
+        &mut self,
+        name: &str,
+        qtype: u16,
+        now: Instant,
+        depth: usize,
+    ) -> Result<Vec<Record>, LookupError> {
+        match self.cache.get(name, qtype, now) {
+            Some(Answer::Records(records)) => return Ok(records),
+            Some(Answer::NotFound) => return Err(LookupError::NotFound),
+            None => {}
+        }
+        let response = self.query(name, qtype)?;
+        if response.rcode == RCODE_NOERROR {
+            let (mut chain, target) = follow_chain(&response.answers, name, qtype);
+            if chain.last().is_some_and(|r| r.rtype == qtype) {
+                self.cache.insert(
+                    name,
+                    qtype,
+                    Answer::Records(chain.clone()),
+                    positive_ttl(&chain),
+                    now,
+                );
+                return Ok(chain);
+            }
+            if target != name {
+                // The server only gave us the alias.
+                if depth >= MAX_CNAME_DEPTH {
+                    return Err(LookupError::Failed);
+                }
+                chain.extend(self.resolve(&target, qtype, now, depth + 1)?);
+                self.cache.insert(
+                    name,
+                    qtype,
+                    Answer::Records(chain.clone()),
+                    positive_ttl(&chain),
+                    now,
+                );
+                return Ok(chain);
+            }
+        }
+        // NXDOMAIN, or no records of this type.
+        let ttl = negative_ttl(&response.authority);
+        self.cache.insert(name, qtype, Answer::NotFound, ttl, now);
+        Err(LookupError::NotFound)
+    }
+
+    /// Ask the name servers in turn until one answers with success or
+    /// NXDOMAIN.
+    fn query(&mut self, name: &str, qtype: u16) -> Result<Message, LookupError> {
+        let mut err = LookupError::TryAgain;
+        for _ in 0..self.conf.attempts {
+            for i in 0..self.conf.nameservers.len() {
+                let server = self.conf.nameservers[i];
+                match self.exchange(server, name, qtype) {
+                    Ok(msg) if msg.rcode == RCODE_NOERROR || msg.rcode == RCODE_NXDOMAIN => {
+                        return Ok(msg)
+                    }
+                    Ok(msg) if msg.rcode == RCODE_SERVFAIL => err = LookupError::TryAgain,
+                    Ok(_) => err = LookupError::Failed,
+                    Err(_) => {}
+                }
+            }
+        }
+        Err(err)
+    }
+
+    /// Send a single query to `server`, over TCP if the UDP response does
+    /// not fit.
+    fn exchange(&mut self, server: SocketAddr, name: &str, qtype: u16) -> io::Result<Message> {
+        let id = self.next_id();
+        let query = Message::query(id, name, qtype)
+            .encode()
+            .map_err(|_| invalid_data("invalid name"))?;
+        let timeout = self.conf.timeout;
+        let udp = match self.transport.udp(server, &query, timeout) {
+            Ok(buf) => Message::parse(&buf).ok().filter(|m| !m.truncated()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+            Err(e) => return Err(e),
+        };
+        let msg = match udp {
+            Some(msg) => msg,
+            None => Message::parse(&self.transport.tcp(server, &query, timeout)?)
+                .map_err(|_| invalid_data("malformed response"))?,
+        };
+        let question = Question {
+            name: normalize(name),
+            qtype,
+        };
+        if msg.id != id || !msg.is_response() || msg.questions != [question] {
+            return Err(invalid_data("response does not match the query"));
+        }
+        Ok(msg)
+    }
+
+    /// Unpredictable query id, making forged responses harder to slip in.
+    fn next_id(&mut self) -> u16 {
+        self.queries += 1;
+        let mut h = self.ids.build_hasher();
+        h.write_u64(self.queries);
+        h.finish() as u16
+    }
+}
@@ -0,0 +1,63 @@
+//! Delivery of DNS messages to name servers.
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+/// Largest DNS message carried over UDP without EDNS.
+pub const MAX_UDP_LEN: usize = 512;
+
+/// Exchange of one query with one name server.
+///
+/// Implementations return the raw response. A UDP response that had to be
+/// cut short should be reported as `ErrorKind::InvalidData`; the resolver
+/// then repeats the query over TCP, as it does for responses with the TC
+/// bit set.
+pub trait Transport {
+    fn udp(&mut self, server: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>>;
+    fn tcp(&mut self, server: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>>;
+}
+
+/// Transport over the sockets of the standard library.
+#[derive(Default)]
+pub struct StdTransport;
+
+impl Transport for StdTransport {
+    fn udp(&mut self, server: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let sock = UdpSocket::bind(local)?;
+        // Only accept datagrams from the server.
+        sock.connect(server)?;
+        sock.set_read_timeout(Some(timeout))?;
+        sock.send(query)?;
+        let mut buf = vec![0u8; MAX_UDP_LEN];
+        let n = sock.recv(&mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn tcp(&mut self, server: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(&tcp_frame(query)?)?;
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Prefix `msg` with its length as required over TCP.
+pub fn tcp_frame(msg: &[u8]) -> io::Result<Vec<u8>> {
+    let len = u16::try_from(msg.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+    let mut frame = Vec::with_capacity(msg.len() + 2);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(msg);
+    Ok(frame)
+}
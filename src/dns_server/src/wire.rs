@@ -0,0 +1,401 @@
+//! DNS message format (RFC 1035).
+//!
+//! Only what a stub resolver needs is understood: one question per query and
+//! A, AAAA, CNAME, PTR and SOA records in answers. Other records are kept as
+//! [`RData::Other`]. Names are compared case-insensitively and therefore
+//! stored in lower case without the trailing dot.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+
+const HEADER_LEN: usize = 12;
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+/// Compression pointers followed while reading a single name.
+const MAX_POINTERS: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WireError {
+    /// The message ends in the middle of a field.
+    Truncated,
+    /// A name is malformed or too long.
+    BadName,
+    /// Record data does not match its type.
+    BadRecord,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ptr(String),
+    /// Only the `minimum` field is of interest: it bounds the time a
+    /// negative answer may be cached (RFC 2308).
+    Soa {
+        minimum: u32,
+    },
+    Other(Vec<u8>),
+}
+
+impl RData {
+    pub fn rtype(&self) -> Option<u16> {
+        match self {
+            RData::A(_) => Some(TYPE_A),
+            RData::Aaaa(_) => Some(TYPE_AAAA),
+            RData::Cname(_) => Some(TYPE_CNAME),
+            RData::Ptr(_) => Some(TYPE_PTR),
+            RData::Soa { .. } => Some(TYPE_SOA),
+            RData::Other(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+impl Record {
+    pub fn new(name: &str, ttl: u32, data: RData) -> Self {
+        Record {
+            name: normalize(name),
+            rtype: data.rtype().unwrap_or(0),
+            ttl,
+            data,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    /// Header flags without the response code.
+    pub flags: u16,
+    pub rcode: u8,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+}
+
+/// Lower-case `name` and strip a trailing dot.
+pub fn normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+impl Message {
+    /// A recursive query for `name`.
+    pub fn query(id: u16, name: &str, qtype: u16) -> Self {
+        Message {
+            id,
+            flags: FLAG_RD,
+            questions: vec![Question {
+                name: normalize(name),
+                qtype,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// An empty response to `query` with response code `rcode`.
+    pub fn response(query: &Message, rcode: u8) -> Self {
+        Message {
+            id: query.id,
+            flags: FLAG_QR | FLAG_RA | (query.flags & FLAG_RD),
+            rcode,
+            questions: query.questions.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_QR != 0
+    }
+
+    /// The answer did not fit into the UDP datagram.
+    pub fn truncated(&self) -> bool {
+        self.flags & FLAG_TC != 0
+    }
+
+    pub fn set_truncated(&mut self) {
+        self.flags |= FLAG_TC;
+    }
+
+    /// Serialize the message. Names are written without compression.
+    pub fn encode(&self) -> Result<Vec<u8>, WireError> {
+        let mut out = Vec::with_capacity(512);
+        put_u16(&mut out, self.id);
+        put_u16(&mut out, (self.flags & !0xf) | (self.rcode as u16 & 0xf));
+        put_u16(&mut out, self.questions.len() as u16);
+        put_u16(&mut out, self.answers.len() as u16);
+        put_u16(&mut out, self.authority.len() as u16);
+        put_u16(&mut out, 0);
+        for q in &self.questions {
+            put_name(&mut out, &q.name)?;
+            put_u16(&mut out, q.qtype);
+            put_u16(&mut out, CLASS_IN);
+        }
+        for r in self.answers.iter().chain(&self.authority) {
+            put_record(&mut out, r)?;
+        }
+        Ok(out)
+    }
+
+    /// Parse a message. Additional records are ignored.
+    pub fn parse(buf: &[u8]) -> Result<Self, WireError> {
+        if buf.len() < HEADER_LEN {
+            return Err(WireError::Truncated);
+        }
+        let mut r = Reader { buf, pos: 0 };
+        let id = r.u16()?;
+        let flags = r.u16()?;
+        let qdcount = r.u16()?;
+        let ancount = r.u16()?;
+        let nscount = r.u16()?;
+        let _arcount = r.u16()?;
+        let mut msg = Message {
+            id,
+            flags: flags & !0xf,
+            rcode: (flags & 0xf) as u8,
+            ..Default::default()
+        };
+        for _ in 0..qdcount {
+            let name = r.name()?;
+            let qtype = r.u16()?;
+            let _class = r.u16()?;
+            msg.questions.push(Question { name, qtype });
+        }
+        for _ in 0..ancount {
+            if let Some(rec) = r.record()? {
+                msg.answers.push(rec);
+            }
+        }
+        for _ in 0..nscount {
+            if let Some(rec) = r.record()? {
+                msg.authority.push(rec);
+            }
+        }
+        Ok(msg)
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) -> Result<(), WireError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() + 2 > MAX_NAME_LEN {
+        return Err(WireError::BadName);
+    }
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(WireError::BadName);
+            }
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+    }
+    out.push(0);
+    Ok(())
+}
+
+fn put_record(out: &mut Vec<u8>, r: &Record) -> Result<(), WireError> {
+    put_name(out, &r.name)?;
+    put_u16(out, r.rtype);
+    put_u16(out, CLASS_IN);
+    out.extend_from_slice(&r.ttl.to_be_bytes());
+    let len_at = out.len();
+    put_u16(out, 0);
+    match &r.data {
+        RData::A(a) => out.extend_from_slice(&a.octets()),
+        RData::Aaaa(a) => out.extend_from_slice(&a.octets()),
+        RData::Cname(n) | RData::Ptr(n) => put_name(out, n)?,
+        RData::Soa { minimum } => {
+            // Root name server and mailbox, then serial, refresh, retry and
+            // expire.
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(&[0; 16]);
+            out.extend_from_slice(&minimum.to_be_bytes());
+        }
+        RData::Other(data) => out.extend_from_slice(data),
+    }
+    let len = (out.len() - len_at - 2) as u16;
+    out[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8], WireError> {
+        let end = self.pos.checked_add(n).ok_or(WireError::Truncated)?;
+        let b = self.buf.get(self.pos..end).ok_or(WireError::Truncated)?;
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16, WireError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, WireError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Read a possibly compressed name at the current position.
+    fn name(&mut self) -> Result<String, WireError> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        // Position after the name in the original sequence, set by the
+        // first pointer.
+        let mut end = None;
+        let mut pointers = 0;
+        loop {
+            let len = *self.buf.get(pos).ok_or(WireError::Truncated)? as usize;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or(WireError::Truncated)?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    for &c in label {
+                        if c == b'.' || !c.is_ascii_graphic() {
+                            return Err(WireError::BadName);
+                        }
+                        name.push(c.to_ascii_lowercase() as char);
+                    }
+                    if name.len() >= MAX_NAME_LEN {
+                        return Err(WireError::BadName);
+                    }
+                    pos += 1 + len;
+                }
+                0xc0 => {
+                    let low = *self.buf.get(pos + 1).ok_or(WireError::Truncated)? as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(WireError::BadName);
+                    }
+                    end.get_or_insert(pos + 2);
+                    pos = (len & 0x3f) << 8 | low;
+                }
+                _ => return Err(WireError::BadName),
+            }
+        }
+        self.pos = end.unwrap_or(pos);
+        Ok(name)
+    }
+
+    /// Read a resource record. Records of other classes than IN are
+    /// skipped.
+    fn record(&mut self) -> Result<Option<Record>, WireError> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        // TTLs with the top bit set are treated as zero (RFC 2181).
+        let ttl = if ttl > i32::MAX as u32 { 0 } else { ttl };
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let data = match rtype {
+            TYPE_A => {
+                let b = self.bytes(len)?;
+                let octets: [u8; 4] = b.try_into().map_err(|_| WireError::BadRecord)?;
+                RData::A(octets.into())
+            }
+            TYPE_AAAA => {
+                let b = self.bytes(len)?;
+                let octets: [u8; 16] = b.try_into().map_err(|_| WireError::BadRecord)?;
+                RData::Aaaa(octets.into())
+            }
+            TYPE_CNAME | TYPE_PTR => {
+                let target = self.name()?;
+                if self.pos != start + len {
+                    return Err(WireError::BadRecord);
+                }
+                if rtype == TYPE_CNAME {
+                    RData::Cname(target)
+                } else {
+                    RData::Ptr(target)
+                }
+            }
+            TYPE_SOA => {
+                self.name()?;
+                self.name()?;
+                for _ in 0..4 {
+                    self.u32()?;
+                }
+                let minimum = self.u32()?;
+                if self.pos != start + len {
+                    return Err(WireError::BadRecord);
+                }
+                RData::Soa { minimum }
+            }
+            _ => RData::Other(self.bytes(len)?.to_vec()),
+        };
+        if class != CLASS_IN {
+            return Ok(None);
+        }
+        Ok(Some(Record {
+            name,
+            rtype,
+            ttl,
+            data,
+        }))
+    }
+}
+
+/// Name queried for the PTR record of `addr`.
+pub fn reverse_name(addr: std::net::IpAddr) -> String {
+    match addr {
+        std::net::IpAddr::V4(a) => {
+            let o = a.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        std::net::IpAddr::V6(a) => {
+            let mut name = String::with_capacity(72);
+            for b in a.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", b & 0xf, b >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
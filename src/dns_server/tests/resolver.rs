@@ -0,0 +1,355 @@
+use dns_server::proto;
+use dns_server::transport::tcp_frame;
+use dns_server::wire::{Message, RData, Record, RCODE_NXDOMAIN, TYPE_CNAME};
+use dns_server::{Family, Hosts, LookupError, ResolvConf, Resolver, StdTransport};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Authoritative stand-in answering from a fixed table over UDP and TCP.
+struct Zone {
+    records: HashMap<(String, u16), Vec<Record>>,
+    /// Names answered with the TC bit over UDP.
+    truncate: Vec<String>,
+}
+
+impl Zone {
+    fn new() -> Self {
+        Zone {
+            records: HashMap::new(),
+            truncate: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: &str, ttl: u32, data: RData) {
+        let rec = Record::new(name, ttl, data);
+        self.records
+            .entry((rec.name.clone(), rec.rtype))
+            .or_default()
+            .push(rec);
+    }
+
+    fn answer(&self, query: &Message, udp: bool) -> Message {
+        let q = &query.questions[0];
+        let known = self.records.keys().any(|(n, _)| *n == q.name);
+        let mut resp = Message::response(query, if known { 0 } else { RCODE_NXDOMAIN });
+        if udp && self.truncate.contains(&q.name) {
+            resp.set_truncated();
+            return resp;
+        }
+        let mut name = q.name.clone();
+        loop {
+            if let Some(data) = self.records.get(&(name.clone(), q.qtype)) {
+                resp.answers.extend(data.iter().cloned());
+                break;
+            }
+            match self.records.get(&(name.clone(), TYPE_CNAME)) {
+                Some(c) => {
+                    resp.answers.push(c[0].clone());
+                    let RData::Cname(target) = &c[0].data else {
+                        unreachable!()
+                    };
+                    name = target.clone();
+                }
+                None => break,
+            }
+        }
+        if resp.answers.is_empty() {
+            resp.authority
+                .push(Record::new("example.org", 3600, RData::Soa { minimum: 30 }));
+        }
+        resp
+    }
+}
+
+struct Server {
+    addr: SocketAddr,
+    queries: Arc<AtomicUsize>,
+}
+
+fn serve(zone: Zone) -> Server {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(addr).unwrap();
+    let zone = Arc::new(zone);
+    let queries = Arc::new(AtomicUsize::new(0));
+
+    let (z, n) = (zone.clone(), queries.clone());
+    thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((len, from)) = udp.recv_from(&mut buf) {
+            n.fetch_add(1, Ordering::SeqCst);
+            let query = Message::parse(&buf[..len]).unwrap();
+            let resp = z.answer(&query, true).encode().unwrap();
+            udp.send_to(&resp, from).unwrap();
+        }
+    });
+    let (z, n) = (zone, queries.clone());
+    thread::spawn(move || {
+        for stream in tcp.incoming() {
+            let mut stream = stream.unwrap();
+            n.fetch_add(1, Ordering::SeqCst);
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let query = Message::parse(&buf).unwrap();
+            let resp = z.answer(&query, false).encode().unwrap();
+            stream.write_all(&tcp_frame(&resp).unwrap()).unwrap();
+        }
+    });
+    Server { addr, queries }
+}
+
+fn zone() -> Zone {
+    let mut zone = Zone::new();
+    zone.add(
+        "www.example.org",
+        300,
+        RData::A(Ipv4Addr::new(192, 0, 2, 10)),
+    );
+    zone.add(
+        "www.example.org",
+        300,
+        RData::Aaaa("2001:db8::10".parse().unwrap()),
+    );
+    zone.add(
+        "alias.example.org",
+        60,
+        RData::Cname("www.example.org".into()),
+    );
+    zone.add(
+        "10.2.0.192.in-addr.arpa",
+        300,
+        RData::Ptr("www.example.org".into()),
+    );
+    zone
+}
+
+fn resolver(server: &Server) -> Resolver<StdTransport> {
+    let conf = ResolvConf {
+        nameservers: vec![server.addr],
+        timeout: Duration::from_millis(500),
+        ..Default::default()
+    };
+    Resolver::new(StdTransport, conf, Hosts::default())
+}
+
+fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+    Ipv4Addr::new(a, b, c, d).into()
+}
+
+#[test]
+fn resolves_both_families() {
+    let server = serve(zone());
+    let mut r = resolver(&server);
+    let info = r
+        .lookup_host("www.example.org", Family::Any, Instant::now())
+        .unwrap();
+    assert_eq!(info.canonical, "www.example.org");
+    assert_eq!(
+        info.addrs,
+        vec![v4(192, 0, 2, 10), "2001:db8::10".parse::<IpAddr>().unwrap()]
+    );
+    let info = r
+        .lookup_host("WWW.Example.ORG.", Family::V4, Instant::now())
+        .unwrap();
+    assert_eq!(info.addrs, vec![v4(192, 0, 2, 10)]);
+}
+
+#[test]
+fn follows_cname() {
+    let server = serve(zone());
+    let mut r = resolver(&server);
+    let info = r
+        .lookup_host("alias.example.org", Family::V4, Instant::now())
+        .unwrap();
+    assert_eq!(info.canonical, "www.example.org");
+    assert_eq!(info.addrs, vec![v4(192, 0, 2, 10)]);
+}
+
+#[test]
+fn caches_until_ttl_expires() {
+    let server = serve(zone());
+    let mut r = resolver(&server);
+    let now = Instant::now();
+    r.lookup_host("alias.example.org", Family::V4, now).unwrap();
+    assert_eq!(server.queries.load(Ordering::SeqCst), 1);
+
+    // The chain is cached for the smallest TTL in it, 60 seconds.
+    r.lookup_host(
+        "alias.example.org",
+        Family::V4,
+        now + Duration::from_secs(59),
+    )
+    .unwrap();
+    assert_eq!(server.queries.load(Ordering::SeqCst), 1);
+    r.lookup_host(
+        "alias.example.org",
+        Family::V4,
+        now + Duration::from_secs(61),
+    )
+    .unwrap();
+    assert_eq!(server.queries.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn caches_nxdomain() {
+    let server = serve(zone());
+    let mut r = resolver(&server);
+    let now = Instant::now();
+    let err = r.lookup_host("missing.example.org", Family::V4, now);
+    assert_eq!(err, Err(LookupError::NotFound));
+    assert_eq!(server.queries.load(Ordering::SeqCst), 1);
+
+    // Negative answers last for the SOA minimum of 30 seconds.
+    let later = now + Duration::from_secs(20);
+    assert!(r
+        .lookup_host("missing.example.org", Family::V4, later)
+        .is_err());
+    assert_eq!(server.queries.load(Ordering::SeqCst), 1);
+    let later = now + Duration::from_secs(31);
+    assert!(r
+        .lookup_host("missing.example.org", Family::V4, later)
+        .is_err());
+    assert_eq!(server.queries.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn reverse_lookup() {
+    let server = serve(zone());
+    let mut r = resolver(&server);
+    let name = r.lookup_addr(v4(192, 0, 2, 10), Instant::now()).unwrap();
+    assert_eq!(name, "www.example.org");
+    assert_eq!(
+        r.lookup_addr(v4(192, 0, 2, 11), Instant::now()),
+        Err(LookupError::NotFound)
+    );
+}
+
+#[test]
+fn retries_truncated_answer_over_tcp() {
+    let mut zone = zone();
+    zone.truncate.push("www.example.org".into());
+    let server = serve(zone);
+    let mut r = resolver(&server);
+    let info = r
+        .lookup_host("www.example.org", Family::V6, Instant::now())
+        .unwrap();
+    assert_eq!(info.addrs, vec!["2001:db8::10".parse::<IpAddr>().unwrap()]);
+    assert_eq!(server.queries.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn search_domains_and_hosts() {
+    let server = serve(zone());
+    let mut r = resolver(&server);
+    let mut conf = r.config().clone();
+    conf.search = vec!["example.org".into()];
+    let hosts = Hosts::parse("10.0.0.1  gateway gw.lan  # router\n::1 localhost\n");
+    r.reconfigure(conf, hosts);
+
+    let info = r.lookup_host("www", Family::V4, Instant::now()).unwrap();
+    assert_eq!(info.addrs, vec![v4(192, 0, 2, 10)]);
+
+    let info = r
+        .lookup_host("gw.lan", Family::Any, Instant::now())
+        .unwrap();
+    assert_eq!(info.canonical, "gateway");
+    assert_eq!(info.addrs, vec![v4(10, 0, 0, 1)]);
+    assert_eq!(
+        r.lookup_addr(v4(10, 0, 0, 1), Instant::now()).unwrap(),
+        "gateway"
+    );
+
+    // The host table only lists ::1 for localhost.
+    assert_eq!(
+        r.lookup_host("localhost", Family::V4, Instant::now()),
+        Err(LookupError::NotFound)
+    );
+}
+
+#[test]
+fn unanswered_queries_time_out() {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let conf = ResolvConf {
+        nameservers: vec![silent.local_addr().unwrap()],
+        timeout: Duration::from_millis(50),
+        attempts: 1,
+        ..Default::default()
+    };
+    let mut r = Resolver::new(StdTransport, conf, Hosts::default());
+    assert_eq!(
+        r.lookup_host("www.example.org", Family::V4, Instant::now()),
+        Err(LookupError::TryAgain)
+    );
+    assert_eq!(r.cached(), 0);
+}
+
+#[test]
+fn parses_resolv_conf() {
+    let conf = ResolvConf::parse(
+        "# comment\nnameserver 10.0.0.53\nnameserver 2001:db8::53\n\
+         domain lan\nsearch a.example b.example\noptions ndots:2 timeout:3 attempts:9 rotate\n",
+    );
+    assert_eq!(conf.nameservers.len(), 2);
+    assert_eq!(conf.nameservers[0], "10.0.0.53:53".parse().unwrap());
+    assert_eq!(conf.search, vec!["a.example", "b.example"]);
+    assert_eq!(conf.ndots, 2);
+    assert_eq!(conf.timeout, Duration::from_secs(3));
+    assert_eq!(conf.attempts, 5);
+    assert_eq!(
+        conf.candidates("host.sub"),
+        vec!["host.sub.a.example", "host.sub.b.example", "host.sub"]
+    );
+    assert_eq!(conf.candidates("host.sub."), vec!["host.sub"]);
+
+    let empty = ResolvConf::parse("");
+    assert_eq!(empty.nameservers, vec!["127.0.0.1:53".parse().unwrap()]);
+}
+
+#[test]
+fn parses_compressed_names() {
+    let mut packet = vec![
+        0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0, // header
+        3, b'f', b'o', b'o', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0, 0, 1, 0, 1,
+    ];
+    // foo.example CNAME bar.<example>
+    packet.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 10, 0, 6]);
+    packet.extend_from_slice(&[3, b'b', b'a', b'r', 0xc0, 16]);
+    // bar.example A 192.0.2.1
+    packet.extend_from_slice(&[0xc0, 41, 0, 1, 0, 1, 0, 0, 0, 20, 0, 4, 192, 0, 2, 1]);
+    let msg = Message::parse(&packet).unwrap();
+    assert_eq!(msg.id, 0x1234);
+    assert_eq!(msg.questions[0].name, "foo.example");
+    assert_eq!(msg.answers[0].data, RData::Cname("bar.example".into()));
+    assert_eq!(msg.answers[1].name, "bar.example");
+    assert_eq!(msg.answers[1].data, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+
+    // A pointer to itself must not loop forever.
+    let mut looped = packet[..12].to_vec();
+    looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+    assert!(Message::parse(&looped).is_err());
+}
+
+#[test]
+fn encodes_addrinfo_reply() {
+    let info = dns_server::HostInfo {
+        canonical: "www.example.org".into(),
+        addrs: vec![v4(192, 0, 2, 10); 10],
+    };
+    let (count, canon_len, payload) = proto::addrinfo_reply(&info, true);
+    assert_eq!(count, proto::MAX_ADDRS);
+    assert_eq!(canon_len, 15);
+    assert_eq!(payload.len(), proto::MAX_ADDRS * 24 + 15);
+    let words: Vec<u64> = payload[..24]
+        .chunks(8)
+        .map(|c| u64::from_ne_bytes(c.try_into().unwrap()))
+        .collect();
+    assert_eq!(proto::decode_addr(&words), Ok(v4(192, 0, 2, 10)));
+    assert_eq!(&payload[payload.len() - 15..], b"www.example.org");
+}
@@ -9,6 +9,8 @@ use driver::start_driver;
 use l4re::sys::{l4re_env, l4re_env_get_cap};
 use l4_sys::{l4_cap_idx_t, l4_ipc_error, l4_msgtag, l4_utcb};
 use std::ffi::CString;
+use virtio_frontend::config::ConfigSpace;
+use virtio_frontend::status::StatusError;
 use virtio_frontend::transport::VirtioTransport;
 
 /// FFI for registering an object with the L4Re naming service.
@@ -26,6 +28,21 @@ unsafe fn register_with_ns(name: &str, gate: l4_cap_idx_t) {
     }
 }
 
+/// Walk the device through the status sequence for a new driver and return
+/// the negotiated features. The device is marked failed if it rejects them.
+fn init_device(transport: &mut VirtioTransport, driver_supported: u64) -> Result<u64, StatusError> {
+    transport.reset();
+    transport.acknowledge()?;
+    transport.driver()?;
+    let negotiated = transport.negotiate_features(driver_supported)?;
+    if let Err(e) = transport.features_ok() {
+        transport.fail();
+        return Err(e);
+    }
+    transport.driver_ok()?;
+    Ok(negotiated)
+}
+
 fn main() {
     unsafe { run() }
 }
@@ -69,24 +86,32 @@ unsafe fn run() {
             0 => {
                 mr[0] = transport.device_features;
             }
-            // 1: negotiate features with driver. Driver-supported features in MR1.
+            // 1: reset the device and negotiate features with driver.
+            // Driver-supported features in MR1, u64::MAX in MR0 on failure.
             1 => {
-                let negotiated = transport.negotiate_features(mr[1]);
-                mr[0] = negotiated;
+                mr[0] = init_device(&mut transport, mr[1]).unwrap_or(u64::MAX);
             }
             // 2: write 32-bit value to config space. MR1=offset, MR2=value.
+            // MR0 is 0 on success and u64::MAX for an invalid offset.
             2 => {
                 let off = mr[1] as usize;
-                let val = mr[2].to_le_bytes();
-                transport.write_config(off, &val[..4]);
-                mr[0] = 0;
+                mr[0] = match transport.write_le(off, mr[2] as u32) {
+                    Ok(()) => 0,
+                    Err(_) => u64::MAX,
+                };
             }
-            // 3: read 32-bit value from config space. MR1=offset, result in MR0.
+            // 3: read 32-bit value from config space. MR1=offset, result in
+            // MR0, or u64::MAX for an invalid offset.
             3 => {
                 let off = mr[1] as usize;
-                let mut buf = [0u8; 4];
-                transport.read_config(off, &mut buf);
-                mr[0] = u32::from_le_bytes(buf) as u64;
+                mr[0] = match transport.read_le::<u32>(off) {
+                    Ok(val) => val as u64,
+                    Err(_) => u64::MAX,
+                };
+            }
+            // 4: return the device status byte.
+            4 => {
+                mr[0] = transport.status().bits() as u64;
             }
             _ => {
                 mr[0] = u64::MAX;
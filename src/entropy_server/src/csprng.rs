@@ -0,0 +1,109 @@
+//! ChaCha20-based CSPRNG with fast key erasure.
+//!
+//! Output is the ChaCha20 keystream of a 256-bit key. The first 32 bytes
+//! of the keystream of every request become the next key, so a key leaked
+//! later reveals nothing about earlier output. Entropy is folded into the
+//! key 32 bytes at a time: XOR the chunk into the key, then replace the key
+//! by the first half of its keystream block under a nonce output never
+//! uses.
+//!
+//! The generator counts as seeded once [`SEED_LEN`] bytes of entropy went
+//! in. After that it asks for a reseed every [`RESEED_INTERVAL`] and after
+//! [`RESEED_BYTES`] of output, see [`Csprng::needs_reseed`]; a reseed again
+//! takes [`SEED_LEN`] bytes.
+
+use std::time::{Duration, Instant};
+
+use crate::chacha::{self, ChaCha20, KEY_LEN, NONCE_LEN};
+
+/// Entropy that makes up a (re)seed.
+pub const SEED_LEN: usize = 32;
+pub const RESEED_INTERVAL: Duration = Duration::from_secs(300);
+pub const RESEED_BYTES: u64 = 1 << 20;
+
+const OUTPUT_NONCE: [u8; NONCE_LEN] = [0; NONCE_LEN];
+const MIX_NONCE: [u8; NONCE_LEN] = *b"entropy-mix\0";
+
+pub struct Csprng {
+    key: [u8; KEY_LEN],
+    seeded: bool,
+    /// Entropy added since the last (re)seed.
+    credit: usize,
+    last_reseed: Option<Instant>,
+    /// Output since the last (re)seed.
+    output: u64,
+    reseeds: u64,
+}
+
+impl Default for Csprng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Csprng {
+    /// An unseeded generator.
+    pub fn new() -> Self {
+        Self {
+            key: [0; KEY_LEN],
+            seeded: false,
+            credit: 0,
+            last_reseed: None,
+            output: 0,
+            reseeds: 0,
+        }
+    }
+
+    pub fn seeded(&self) -> bool {
+        self.seeded
+    }
+
+    /// Number of times the generator was (re)seeded.
+    pub fn reseeds(&self) -> u64 {
+        self.reseeds
+    }
+
+    /// Mix `entropy` into the key. Completes a (re)seed once [`SEED_LEN`]
+    /// bytes were added since the last one.
+    pub fn add_entropy(&mut self, entropy: &[u8], now: Instant) {
+        for chunk in entropy.chunks(KEY_LEN) {
+            for (k, e) in self.key.iter_mut().zip(chunk) {
+                *k ^= e;
+            }
+            let block = chacha::block(&self.key, 0, &MIX_NONCE);
+            self.key.copy_from_slice(&block[..KEY_LEN]);
+        }
+        self.credit += entropy.len();
+        if self.credit >= SEED_LEN {
+            self.seeded = true;
+            self.credit = 0;
+            self.last_reseed = Some(now);
+            self.output = 0;
+            self.reseeds += 1;
+        }
+    }
+
+    /// Whether the generator wants fresh entropy: before it is seeded, and
+    /// once the reseed interval passed or the output limit was reached.
+    pub fn needs_reseed(&self, now: Instant) -> bool {
+        match self.last_reseed {
+            Some(last) => {
+                now.saturating_duration_since(last) >= RESEED_INTERVAL
+                    || self.output >= RESEED_BYTES
+            }
+            None => true,
+        }
+    }
+
+    /// Fill `buf` with output and replace the key. Unseeded generators
+    /// produce output as well, which is not random at all.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        buf.fill(0);
+        let mut next_key = [0; KEY_LEN];
+        let mut stream = ChaCha20::new(&self.key, &OUTPUT_NONCE, 0);
+        stream.apply_keystream(&mut next_key);
+        stream.apply_keystream(buf);
+        self.key = next_key;
+        self.output = self.output.saturating_add(buf.len() as u64);
+    }
+}
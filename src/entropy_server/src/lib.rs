@@ -0,0 +1,15 @@
+//! Entropy service.
+//!
+//! The library holds everything that does not depend on L4Re: ChaCha20, the
+//! CSPRNG built on it and the service that seeds the CSPRNG from a
+//! virtio-rng device. The `entropy_server` binary (feature `l4re`) serves
+//! `getrandom` over IPC as described in [`proto`]; without the feature the
+//! library builds and tests on the host against a simulated device.
+
+pub mod chacha;
+pub mod csprng;
+pub mod proto;
+pub mod service;
+
+pub use csprng::Csprng;
+pub use service::Entropy;
@@ -0,0 +1,167 @@
+//! Entropy service on the virtio-rng device.
+//!
+//! Clients get random bytes through the `global_entropy` gate, see
+//! [`proto`] for the IPC protocol. The device registers arrive as the
+//! `virtio_rng` dataspace, its interrupt as `virtio_rng_irq`. A blocking
+//! `getrandom` before the generator is seeded waits for the device's
+//! interrupts before the reply; other clients wait meanwhile.
+
+use core::ffi::c_void;
+use core::mem::size_of;
+use entropy_server::{proto, Entropy};
+use l4::sys::{l4_cap_idx_t, l4_ipc_error, l4_irq_unmask, l4_msgtag, l4_timeout_t, l4_utcb};
+use l4_sys::{l4_utcb_br, l4_utcb_mr};
+use l4re::sys::{l4re_env, l4re_env_get_cap, l4re_rm_attach};
+use std::cmp::min;
+use std::time::Instant;
+use virtio_frontend::mmio::{MmioRegion, MmioTransport};
+use virtio_frontend::rng::RngDriver;
+
+const BR_WORDS: usize = l4_sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
+const BR_DATA_BYTES: usize = (BR_WORDS - 1) * size_of::<u64>();
+
+/// Label of client requests arriving through the `global_entropy` gate.
+const GATE_LABEL: u64 = 0b1111_0000;
+/// Label of virtio-rng interrupts.
+const IRQ_LABEL: u64 = 0b1_0000_0000;
+/// The two least significant label bits carry the rights of the sender's
+/// capability.
+const LABEL_MASK: u64 = !0b11;
+
+fn encode_errno(err: i32) -> u64 {
+    (-(err as i64)) as u64
+}
+
+/// Return `data` to the client through the buffer registers.
+unsafe fn br_reply(data: &[u8]) {
+    let br = &mut (*l4_utcb_br()).br;
+    let len = min(data.len(), BR_DATA_BYTES);
+    br[0] = len as u64;
+    core::ptr::copy_nonoverlapping(data.as_ptr(), br.as_mut_ptr().add(1) as *mut u8, len);
+}
+
+/// Wait for device interrupts until the generator is seeded.
+unsafe fn wait_seeded(entropy: &mut Entropy<MmioRegion>, irq: l4_cap_idx_t) -> Result<(), i32> {
+    while !entropy.seeded() {
+        let tag = l4::l4_ipc_receive(irq, l4_utcb(), l4_timeout_t { raw: 0 });
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            return Err(libc::EIO);
+        }
+        let _ = l4_irq_unmask(irq);
+        entropy.interrupt(Instant::now())?;
+    }
+    Ok(())
+}
+
+/// Handle one request. Returns the value of `MR0` and the number of words
+/// in the reply.
+unsafe fn dispatch(
+    entropy: &mut Entropy<MmioRegion>,
+    irq: l4_cap_idx_t,
+) -> Result<(u64, u32), i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    match mr[0] {
+        proto::OP_GETRANDOM => {
+            let (len, flags) = (mr[1], mr[2]);
+            let mut buf = vec![0u8; min(len as usize, BR_DATA_BYTES)];
+            let n = match entropy.getrandom(&mut buf, flags, Instant::now()) {
+                Err(libc::EAGAIN) if flags & proto::GRND_NONBLOCK == 0 => {
+                    wait_seeded(entropy, irq)?;
+                    entropy.getrandom(&mut buf, flags, Instant::now())?
+                }
+                res => res?,
+            };
+            br_reply(&buf[..n]);
+            buf.fill(0);
+            Ok((n as u64, 1))
+        }
+        proto::OP_STATUS => {
+            let (flags, seeds) = entropy.status();
+            mr[1] = seeds;
+            Ok((flags, 2))
+        }
+        _ => Err(libc::ENOSYS),
+    }
+}
+
+unsafe fn bind(cap: l4_cap_idx_t, label: u64) -> bool {
+    l4_ipc_error(
+        l4::l4_rcv_ep_bind_thread(cap, (*l4re_env()).main_thread, label),
+        l4_utcb(),
+    ) == 0
+}
+
+/// Map the device registers, found under `virtio_rng`, and initialise the
+/// device.
+unsafe fn open_device() -> Option<Entropy<MmioRegion>> {
+    let regs = l4re_env_get_cap("virtio_rng")?;
+    let mut base: *mut c_void = core::ptr::null_mut();
+    let flags = l4re::sys::l4re_rm_flags_values::L4RE_RM_F_SEARCH_ADDR as u64
+        | l4re::sys::l4re_rm_flags_values::L4RE_RM_F_RW as u64;
+    let size = 1 << l4::sys::L4_PAGESHIFT;
+    if l4re_rm_attach(&mut base, size, flags, regs, 0, l4::sys::L4_PAGESHIFT as u8) < 0 {
+        return None;
+    }
+    let transport = MmioTransport::new(MmioRegion::new(base as *mut u8)).ok()?;
+    let driver = match RngDriver::new(transport) {
+        Ok(driver) => driver,
+        Err(e) => {
+            println!("entropy_server: virtio-rng: {e}");
+            return None;
+        }
+    };
+    Entropy::new(driver, Instant::now()).ok()
+}
+
+fn main() {
+    unsafe { run() }
+}
+
+/// Unsafe portion of the server. Interacts directly with L4 system calls.
+unsafe fn run() {
+    let gate = l4re_env_get_cap("global_entropy").expect("IPC gate 'global_entropy' not provided");
+    if !bind(gate, GATE_LABEL) {
+        panic!("failed to bind IPC gate");
+    }
+    let mut entropy = open_device().expect("no virtio-rng device");
+    let irq = l4re_env_get_cap("virtio_rng_irq").expect("virtio-rng IRQ not provided");
+    if !bind(irq, IRQ_LABEL) {
+        panic!("failed to bind virtio-rng IRQ");
+    }
+    let _ = l4_irq_unmask(irq);
+
+    println!("entropy server ready");
+
+    // Wait for client requests and device interrupts. Reseeds are asked
+    // for as requests come in, so an idle generator is not reseeded.
+    let mut label = 0u64;
+    let mut tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4_timeout_t { raw: 0 });
+    loop {
+        let failed = l4_ipc_error(tag, l4_utcb()) != 0;
+        if !failed && label & LABEL_MASK == IRQ_LABEL {
+            let _ = l4_irq_unmask(irq);
+            if let Err(err) = entropy.interrupt(Instant::now()) {
+                println!("entropy_server: virtio-rng: error {err}");
+            }
+        }
+        if failed || label & LABEL_MASK == IRQ_LABEL {
+            tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4_timeout_t { raw: 0 });
+            continue;
+        }
+
+        // Pick up entropy that arrived since the last interrupt.
+        let _ = entropy.poll_device(Instant::now());
+        let (result, words) = match dispatch(&mut entropy, irq) {
+            Ok(reply) => reply,
+            Err(err) => (encode_errno(err), 1),
+        };
+        (*l4_utcb_mr()).mr[0] = result;
+
+        tag = l4::l4_ipc_reply_and_wait(
+            l4_utcb(),
+            l4_msgtag(0, words, 0, 0),
+            &mut label,
+            l4_timeout_t { raw: 0 },
+        );
+    }
+}
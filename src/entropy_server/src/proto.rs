@@ -0,0 +1,34 @@
+//! Message register layout of the entropy protocol.
+//!
+//! ```text
+//! MR0: operation
+//!      0 = getrandom  MR1: length, MR2: GRND_* flags
+//!                     Reply: MR0 = length, BRs: data
+//!      1 = status     Reply: MR0 = STATUS_* flags, MR1 = number of seeds
+//! ```
+//!
+//! Random bytes travel through the buffer registers: `BR0` holds the
+//! length in bytes and the data follows from `BR1` onwards. A `getrandom`
+//! returns fewer bytes than asked for if they do not fit, like the system
+//! call for large requests.
+//!
+//! Until the generator is seeded, `getrandom` blocks, or fails with
+//! `-EAGAIN` given `GRND_NONBLOCK`. `GRND_INSECURE` returns output right
+//! away, seeded or not. `GRND_RANDOM` has no effect. Unknown flags, and
+//! `GRND_RANDOM` together with `GRND_INSECURE`, fail with `-EINVAL`.
+//!
+//! `MR0` of a reply is 0 or a length on success, `-errno` on failure.
+
+pub const OP_GETRANDOM: u64 = 0;
+pub const OP_STATUS: u64 = 1;
+
+// getrandom flags, as in Linux.
+pub const GRND_NONBLOCK: u64 = 0x1;
+pub const GRND_RANDOM: u64 = 0x2;
+pub const GRND_INSECURE: u64 = 0x4;
+
+// Status flags.
+/// The generator is seeded.
+pub const STATUS_SEEDED: u64 = 1 << 0;
+/// A request for entropy is with the device.
+pub const STATUS_RESEEDING: u64 = 1 << 1;
@@ -0,0 +1,111 @@
+//! The CSPRNG fed by a virtio-rng device.
+
+use std::time::Instant;
+
+use libc::{EAGAIN, EINVAL, EIO};
+use virtio_frontend::mmio::Registers;
+use virtio_frontend::queue::QueueError;
+use virtio_frontend::rng::{RngDriver, RngError};
+
+use crate::csprng::{Csprng, SEED_LEN};
+use crate::proto::{GRND_INSECURE, GRND_NONBLOCK, GRND_RANDOM, STATUS_RESEEDING, STATUS_SEEDED};
+
+/// Bytes asked of the device for a (re)seed. More than a seed takes, as
+/// devices may deliver less than asked for.
+pub const REQUEST_LEN: usize = 2 * SEED_LEN;
+
+/// A CSPRNG that seeds and reseeds itself from an entropy device.
+///
+/// Results are `errno` values as in the protocol. Nothing blocks: a
+/// blocking `getrandom` before the generator is seeded fails with `EAGAIN`
+/// like a non-blocking one, and the caller waits for [`Entropy::seeded`].
+pub struct Entropy<R: Registers> {
+    driver: RngDriver<R>,
+    rng: Csprng,
+}
+
+impl<R: Registers> Entropy<R> {
+    /// Ask the device for the first seed.
+    pub fn new(driver: RngDriver<R>, now: Instant) -> Result<Self, i32> {
+        let mut entropy = Self {
+            driver,
+            rng: Csprng::new(),
+        };
+        entropy.refill(now)?;
+        Ok(entropy)
+    }
+
+    pub fn driver(&self) -> &RngDriver<R> {
+        &self.driver
+    }
+
+    pub fn driver_mut(&mut self) -> &mut RngDriver<R> {
+        &mut self.driver
+    }
+
+    pub fn seeded(&self) -> bool {
+        self.rng.seeded()
+    }
+
+    /// `STATUS_*` flags of [`crate::proto`] and the number of seeds.
+    pub fn status(&self) -> (u64, u64) {
+        let mut flags = 0;
+        if self.rng.seeded() {
+            flags |= STATUS_SEEDED;
+        }
+        if self.driver.pending() > 0 {
+            flags |= STATUS_RESEEDING;
+        }
+        (flags, self.rng.reseeds())
+    }
+
+    /// Handle a device interrupt.
+    pub fn interrupt(&mut self, now: Instant) -> Result<(), i32> {
+        self.driver.interrupt();
+        self.refill(now)
+    }
+
+    /// Pick up what the device delivered without an interrupt.
+    pub fn poll_device(&mut self, now: Instant) -> Result<(), i32> {
+        self.driver.poll();
+        self.refill(now)
+    }
+
+    /// Mix delivered entropy into the generator and ask the device for more
+    /// while the generator wants it.
+    fn refill(&mut self, now: Instant) -> Result<(), i32> {
+        let mut buf = [0u8; REQUEST_LEN];
+        loop {
+            let n = self.driver.take(&mut buf);
+            if n == 0 {
+                break;
+            }
+            self.rng.add_entropy(&buf[..n], now);
+        }
+        buf.fill(0);
+        if !self.rng.needs_reseed(now) || self.driver.pending() > 0 {
+            return Ok(());
+        }
+        match self.driver.request(REQUEST_LEN) {
+            Ok(()) | Err(RngError::Queue(QueueError::Full)) => Ok(()),
+            Err(_) => Err(EIO),
+        }
+    }
+
+    /// Fill `buf` with random bytes, see the protocol for `flags`.
+    pub fn getrandom(&mut self, buf: &mut [u8], flags: u64, now: Instant) -> Result<usize, i32> {
+        if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+            || flags & (GRND_RANDOM | GRND_INSECURE) == GRND_RANDOM | GRND_INSECURE
+        {
+            return Err(EINVAL);
+        }
+        if !self.rng.seeded() && flags & GRND_INSECURE == 0 {
+            return Err(EAGAIN);
+        }
+        self.rng.fill(buf);
+        // The output is good even if asking for a reseed failed, the next
+        // call asks again.
+        let _ = self.refill(now);
+        Ok(buf.len())
+    }
+}
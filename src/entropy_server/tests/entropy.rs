@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+
+use entropy_server::chacha::{self, ChaCha20};
+use entropy_server::csprng::{RESEED_BYTES, RESEED_INTERVAL, SEED_LEN};
+use entropy_server::proto::{GRND_INSECURE, GRND_NONBLOCK, GRND_RANDOM, STATUS_SEEDED};
+use entropy_server::{Csprng, Entropy};
+use libc::{EAGAIN, EINVAL};
+use virtio_frontend::device::{FakeSource, RngDevice, SimDevice};
+use virtio_frontend::mmio::MmioTransport;
+use virtio_frontend::rng::RngDriver;
+
+type SimEntropy = Entropy<SimDevice<RngDevice<FakeSource>>>;
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn rfc_key() -> [u8; 32] {
+    core::array::from_fn(|i| i as u8)
+}
+
+/// An entropy service on a device whose source has `budget` bytes.
+fn entropy(budget: Option<usize>, now: Instant) -> SimEntropy {
+    let mut source = FakeSource::new(42);
+    source.set_budget(budget);
+    // SAFETY: the driver's queue lives in the driver owning the device.
+    let device = unsafe { SimDevice::new(RngDevice::new(source)) };
+    let driver = RngDriver::new(MmioTransport::new(device).unwrap()).unwrap();
+    Entropy::new(driver, now).unwrap()
+}
+
+fn device(entropy: &mut SimEntropy) -> &mut SimDevice<RngDevice<FakeSource>> {
+    entropy.driver_mut().transport_mut().regs_mut()
+}
+
+#[test]
+fn chacha20_rfc8439() {
+    // Section 2.3.2, the block function.
+    let nonce = hex("000000090000004a00000000").try_into().unwrap();
+    let expected = hex(concat!(
+        "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e",
+        "d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e",
+    ));
+    assert_eq!(chacha::block(&rfc_key(), 1, &nonce).to_vec(), expected);
+
+    // Section 2.4.2, encryption across block boundaries.
+    let nonce = hex("000000000000004a00000000").try_into().unwrap();
+    let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+only one tip for the future, sunscreen would be it."
+        .to_vec();
+    let mut cipher = ChaCha20::new(&rfc_key(), &nonce, 1);
+    let (head, tail) = data.split_at_mut(7);
+    cipher.apply_keystream(head);
+    cipher.apply_keystream(tail);
+    let expected = hex(concat!(
+        "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b",
+        "f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8",
+        "07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736",
+        "5af90bbf74a35be6b40b8eedf2785e42874d",
+    ));
+    assert_eq!(data, expected);
+}
+
+#[test]
+fn csprng_seeding_and_reseed_policy() {
+    let start = Instant::now();
+    let mut rng = Csprng::new();
+    assert!(!rng.seeded());
+    assert!(rng.needs_reseed(start));
+
+    // A seed may arrive in parts.
+    rng.add_entropy(&[1; SEED_LEN / 2], start);
+    assert!(!rng.seeded());
+    rng.add_entropy(&[2; SEED_LEN / 2], start);
+    assert!(rng.seeded());
+    assert_eq!(rng.reseeds(), 1);
+    assert!(!rng.needs_reseed(start));
+    assert!(rng.needs_reseed(start + RESEED_INTERVAL));
+
+    // Every request rekeys, so output never repeats.
+    let mut a = [0u8; 100];
+    let mut b = [0u8; 100];
+    rng.fill(&mut a);
+    rng.fill(&mut b);
+    assert_ne!(a, b);
+
+    // Heavy use asks for a reseed early.
+    let mut big = vec![0u8; RESEED_BYTES as usize];
+    rng.fill(&mut big);
+    assert!(rng.needs_reseed(start + Duration::from_secs(1)));
+
+    // The same seed gives the same output, a different one does not.
+    let (mut x, mut y, mut z) = (Csprng::new(), Csprng::new(), Csprng::new());
+    x.add_entropy(&[7; SEED_LEN], start);
+    y.add_entropy(&[7; SEED_LEN], start);
+    z.add_entropy(&[8; SEED_LEN], start);
+    x.fill(&mut a);
+    y.fill(&mut b);
+    assert_eq!(a, b);
+    z.fill(&mut b);
+    assert_ne!(a, b);
+}
+
+#[test]
+fn unseeded_until_the_device_delivers() {
+    let now = Instant::now();
+    let mut entropy = entropy(Some(0), now);
+    device(&mut entropy).poll();
+    entropy.interrupt(now).unwrap();
+    assert!(!entropy.seeded());
+    assert_eq!(device(&mut entropy).model().pending(), 1);
+
+    let mut buf = [0u8; 16];
+    assert_eq!(entropy.getrandom(&mut buf, GRND_NONBLOCK, now), Err(EAGAIN));
+    // Blocking callers wait for `seeded`.
+    assert_eq!(entropy.getrandom(&mut buf, 0, now), Err(EAGAIN));
+    assert_eq!(entropy.getrandom(&mut buf, GRND_INSECURE, now), Ok(16));
+    assert_eq!(entropy.status().0 & STATUS_SEEDED, 0);
+
+    // Entropy trickles in: half a seed is not enough.
+    device(&mut entropy)
+        .model_mut()
+        .source_mut()
+        .set_budget(Some(SEED_LEN / 2));
+    device(&mut entropy).poll();
+    entropy.interrupt(now).unwrap();
+    assert!(!entropy.seeded());
+
+    device(&mut entropy)
+        .model_mut()
+        .source_mut()
+        .set_budget(None);
+    device(&mut entropy).poll();
+    entropy.interrupt(now).unwrap();
+    assert!(entropy.seeded());
+    assert_eq!(entropy.status(), (STATUS_SEEDED, 1));
+    assert_eq!(entropy.getrandom(&mut buf, GRND_NONBLOCK, now), Ok(16));
+    assert_eq!(entropy.getrandom(&mut buf, GRND_RANDOM, now), Ok(16));
+}
+
+#[test]
+fn reseeds_periodically() {
+    let start = Instant::now();
+    let mut entropy = entropy(None, start);
+    device(&mut entropy).poll();
+    entropy.interrupt(start).unwrap();
+    assert!(entropy.seeded());
+    let delivered = device(&mut entropy).model().delivered();
+
+    // Within the interval the device is left alone.
+    let mut buf = [0u8; 64];
+    entropy.getrandom(&mut buf, 0, start).unwrap();
+    assert_eq!(entropy.driver().pending(), 0);
+
+    // After it the next request asks for fresh entropy.
+    let later = start + RESEED_INTERVAL;
+    entropy.getrandom(&mut buf, 0, later).unwrap();
+    assert_eq!(entropy.driver().pending(), 1);
+    device(&mut entropy).poll();
+    entropy.interrupt(later).unwrap();
+    assert_eq!(entropy.status(), (STATUS_SEEDED, 2));
+    assert!(device(&mut entropy).model().delivered() > delivered);
+}
+
+#[test]
+fn bad_flags() {
+    let now = Instant::now();
+    let mut entropy = entropy(None, now);
+    let mut buf = [0u8; 8];
+    assert_eq!(entropy.getrandom(&mut buf, 0x80, now), Err(EINVAL));
+    assert_eq!(
+        entropy.getrandom(&mut buf, GRND_RANDOM | GRND_INSECURE, now),
+        Err(EINVAL)
+    );
+}
@@ -52,13 +52,13 @@ unsafe fn br_write_bytes(data: &[u8]) {
     std::ptr::copy_nonoverlapping(data.as_ptr(), dst, len);
 }
 
-/// Resolve LSB style paths to FAT paths. Supports /bin, /etc and /usr.
+/// Resolve LSB style paths to FAT paths. Supports /bin, /etc, /usr and /var.
 fn resolve_path(p: &str) -> Option<String> {
     if !p.starts_with('/') {
         return None;
     }
     let rel = &p[1..];
-    if rel.starts_with("bin/") || rel.starts_with("etc/") || rel.starts_with("usr/") {
+    if ["bin/", "etc/", "usr/", "var/"].iter().any(|d| rel.starts_with(d)) {
         Some(rel.to_string())
     } else {
         None
@@ -72,6 +72,35 @@ fn io_to_errno(e: std::io::ErrorKind) -> i32 {
     }
 }
 
+/// Run `op` with the file positioned at `offset` and restore the previous
+/// position afterwards, giving pread/pwrite semantics.
+fn positioned<F, T>(file: &mut F, offset: u64, op: impl FnOnce(&mut F) -> std::io::Result<T>) -> std::io::Result<T>
+where
+    F: Seek,
+{
+    let saved = file.stream_position()?;
+    file.seek(SeekFrom::Start(offset))?;
+    let result = op(file);
+    file.seek(SeekFrom::Start(saved))?;
+    result
+}
+
+/// Write `data` at `offset` like [`positioned`]. fatfs cannot seek past the
+/// end of a file, so a file shorter than `offset` is first extended with
+/// zeros, as a pwrite past EOF does on POSIX.
+fn write_at<F>(file: &mut F, offset: u64, data: &[u8]) -> std::io::Result<usize>
+where
+    F: Seek + Write,
+{
+    let saved = file.stream_position()?;
+    let end = file.seek(SeekFrom::End(0))?;
+    if end < offset {
+        std::io::copy(&mut std::io::repeat(0).take(offset - end), file)?;
+    }
+    file.seek(SeekFrom::Start(saved))?;
+    positioned(file, offset, |f| f.write(data))
+}
+
 mod virtio;
 use virtio::VirtioDisk;
 
@@ -99,10 +128,14 @@ unsafe fn run() {
 
     // Initialise the virtio block driver. The driver provides sector based
     // access to the backing store which is consumed by the FAT32 layer.
-    let disk = unsafe { VirtioDisk::new().expect("virtio-blk device not available") };
+    let disk = unsafe { virtio::open_disk().expect("virtio-blk device not available") };
     let fs = FileSystem::new(disk, FsOptions::new()).expect("failed to mount FAT32 volume");
     // Leak filesystem to obtain 'static references for open file handles.
     let fs: &'static FileSystem<VirtioDisk> = Box::leak(Box::new(fs));
+    // Services write files such as packet captures below /var.
+    if let Err(e) = fs.root_dir().create_dir("var") {
+        println!("fs_server: cannot create /var: {e}");
+    }
     let mut handles: Slab<fatfs::File<'static, VirtioDisk>> = Slab::new();
 
     // Ready to serve requests.
@@ -215,6 +248,59 @@ unsafe fn run() {
                     mr[0] = (-(ENOENT as i64)) as u64;
                 }
             }
+            // 6: positional read. MR1=fd, MR2=len, MR3=offset. Data returned
+            // in BRs; the file position is left untouched.
+            6 => {
+                let fd = mr[1] as usize;
+                let len = min(mr[2] as usize, BR_DATA_MAX);
+                let offset = mr[3];
+                if handles.contains(fd) {
+                    let file = &mut handles[fd];
+                    if read_buf.len() < len {
+                        read_buf.resize(len, 0);
+                    }
+                    let result = positioned(file, offset, |f| f.read(&mut read_buf[..len]));
+                    match result {
+                        Ok(n) => {
+                            unsafe { br_write_bytes(&read_buf[..n]); }
+                            mr[0] = n as u64;
+                        }
+                        Err(e) => mr[0] = (-(io_to_errno(e.kind()) as i64)) as u64,
+                    }
+                    read_buf.clear();
+                } else {
+                    mr[0] = (-(EBADF as i64)) as u64;
+                }
+            }
+            // 7: positional write. MR1=fd, MR2=offset, data in BRs.
+            7 => {
+                let fd = mr[1] as usize;
+                let offset = mr[2];
+                if handles.contains(fd) {
+                    let file = &mut handles[fd];
+                    let data_len = unsafe { br_read_bytes_into(&mut write_buf) };
+                    let result = write_at(file, offset, &write_buf[..data_len]);
+                    write_buf.clear();
+                    match result {
+                        Ok(n) => mr[0] = n as u64,
+                        Err(e) => mr[0] = (-(io_to_errno(e.kind()) as i64)) as u64,
+                    }
+                } else {
+                    mr[0] = (-(EBADF as i64)) as u64;
+                }
+            }
+            // 8: flush descriptor. MR1=fd.
+            8 => {
+                let fd = mr[1] as usize;
+                if handles.contains(fd) {
+                    match handles[fd].flush() {
+                        Ok(()) => mr[0] = 0,
+                        Err(e) => mr[0] = (-(io_to_errno(e.kind()) as i64)) as u64,
+                    }
+                } else {
+                    mr[0] = (-(EBADF as i64)) as u64;
+                }
+            }
             // unknown operation
             _ => {
                 mr[0] = (-(ENOENT as i64)) as u64;
@@ -230,3 +316,27 @@ unsafe fn run() {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn pwrite_past_end_of_file() {
+        let mut image = Cursor::new(vec![0u8; 1 << 20]);
+        fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+        let fs = FileSystem::new(image, FsOptions::new()).unwrap();
+        let mut file = fs.root_dir().create_file("sparse").unwrap();
+        file.write_all(b"head").unwrap();
+
+        assert_eq!(write_at(&mut file, 10, b"tail").unwrap(), 4);
+        // The file position stays where it was.
+        assert_eq!(file.stream_position().unwrap(), 4);
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"head\0\0\0\0\0\0tail");
+    }
+}
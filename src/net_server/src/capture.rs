@@ -0,0 +1,414 @@
+//! Packet capture.
+//!
+//! A [`Capture`] records the frames the stack receives and transmits into a
+//! ring buffer. When the buffer is full the oldest frames are dropped. An
+//! optional [`Filter`] selects the frames to keep, using a small subset of
+//! the tcpdump filter syntax:
+//!
+//! ```text
+//! tcp port 80
+//! udp and host 10.0.2.3
+//! icmp host fe80::1
+//! ```
+//!
+//! The terms `arp`, `ip`, `ip6`, `icmp`, `tcp` and `udp` select a protocol
+//! (`icmp` matches ICMPv6 as well), `port N` a TCP or UDP port and
+//! `host ADDR` a source or destination address. All terms must match;
+//! `and` may be written between them.
+//!
+//! The buffer is exported in pcap or pcapng format. Timestamps count from
+//! server start, as the server has no wall clock. pcapng exports also mark
+//! the direction of every frame.
+
+use crate::device::ETHERNET_MTU;
+use crate::packet::{Headers, Kind};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, PacketMeta, RxToken, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpProtocol};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// Buffer size used when the request leaves it open.
+pub const DEFAULT_BUFFER: usize = 256 * 1024;
+/// Largest buffer a capture may use.
+pub const MAX_BUFFER: usize = 4 * 1024 * 1024;
+/// Frames are stored in full; the snapshot length only goes into the file
+/// headers.
+const SNAPLEN: u32 = 65535;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Pcap,
+    Pcapng,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Proto {
+    Arp,
+    Ipv4,
+    Ipv6,
+    Icmp,
+    Tcp,
+    Udp,
+}
+
+/// Selects the frames kept by a capture. The default filter matches all
+/// frames.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    proto: Option<Proto>,
+    port: Option<u16>,
+    host: Option<IpAddress>,
+}
+
+impl core::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut filter = Filter::default();
+        let mut words = s.split_whitespace();
+        while let Some(word) = words.next() {
+            let proto = match word {
+                "and" => continue,
+                "arp" => Proto::Arp,
+                "ip" => Proto::Ipv4,
+                "ip6" => Proto::Ipv6,
+                "icmp" => Proto::Icmp,
+                "tcp" => Proto::Tcp,
+                "udp" => Proto::Udp,
+                "port" | "host" => {
+                    let value = words.next().ok_or(format!("{word} needs a value"))?;
+                    let invalid = format!("invalid {word}: {value}");
+                    if word == "port" {
+                        filter.port = Some(value.parse().map_err(|_| invalid)?);
+                    } else {
+                        filter.host = Some(value.parse().map_err(|_| invalid)?);
+                    }
+                    continue;
+                }
+                _ => return Err(format!("unknown filter term {word}")),
+            };
+            if filter.proto.replace(proto).is_some() {
+                return Err("only one protocol may be given".into());
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    /// Whether `frame`, as seen on a device with `medium`, passes.
+    pub fn matches(&self, medium: Medium, frame: &[u8]) -> bool {
+        if *self == Filter::default() {
+            return true;
+        }
+        let h = Headers::parse(medium, frame);
+        let proto = |p| match p {
+            Proto::Arp => h.kind == Kind::Arp,
+            Proto::Ipv4 => h.kind == Kind::Ipv4,
+            Proto::Ipv6 => h.kind == Kind::Ipv6,
+            Proto::Icmp => h.is_icmp(),
+            Proto::Tcp => h.proto == Some(IpProtocol::Tcp),
+            Proto::Udp => h.proto == Some(IpProtocol::Udp),
+        };
+        self.proto.is_none_or(proto)
+            && self
+                .port
+                .is_none_or(|p| h.ports.is_some_and(|(src, dst)| src == p || dst == p))
+            && self
+                .host
+                .is_none_or(|a| h.src == Some(a) || h.dst == Some(a))
+    }
+}
+
+struct Record {
+    time: Instant,
+    dir: Direction,
+    frame: Vec<u8>,
+}
+
+/// A capture of the frames passing a device.
+pub struct Capture {
+    medium: Medium,
+    active: bool,
+    filter: Filter,
+    limit: usize,
+    used: usize,
+    records: VecDeque<Record>,
+    lost: u64,
+    /// Export handed out by [`Capture::read`].
+    snapshot: Vec<u8>,
+}
+
+impl Capture {
+    /// An inactive capture for a device with `medium`.
+    pub fn new(medium: Medium) -> Self {
+        Self {
+            medium,
+            active: false,
+            filter: Filter::default(),
+            limit: DEFAULT_BUFFER,
+            used: 0,
+            records: VecDeque::new(),
+            lost: 0,
+            snapshot: Vec::new(),
+        }
+    }
+
+    /// Start a capture into a buffer of `limit` bytes (0 for
+    /// [`DEFAULT_BUFFER`]), discarding the frames of the previous one.
+    pub fn start(&mut self, limit: usize, filter: Filter) {
+        self.limit = match limit {
+            0 => DEFAULT_BUFFER,
+            n => n.clamp(ETHERNET_MTU, MAX_BUFFER),
+        };
+        self.filter = filter;
+        self.records.clear();
+        self.used = 0;
+        self.lost = 0;
+        self.active = true;
+    }
+
+    /// Stop capturing. The buffer stays available for export.
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Number of frames in the buffer.
+    pub fn frames(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Number of frames dropped because the buffer was full.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Record `frame` if the capture is running and the filter matches.
+    pub fn record(&mut self, time: Instant, dir: Direction, frame: &[u8]) {
+        if !self.active || !self.filter.matches(self.medium, frame) {
+            return;
+        }
+        let frame = frame[..frame.len().min(self.limit)].to_vec();
+        while self.used + frame.len() > self.limit {
+            let Some(old) = self.records.pop_front() else {
+                break;
+            };
+            self.used -= old.frame.len();
+            self.lost += 1;
+        }
+        self.used += frame.len();
+        self.records.push_back(Record { time, dir, frame });
+    }
+
+    fn link_type(&self) -> u16 {
+        match self.medium {
+            Medium::Ethernet => LINKTYPE_ETHERNET,
+            Medium::Ip => LINKTYPE_RAW,
+        }
+    }
+
+    /// The buffered frames as a capture file.
+    pub fn export(&self, format: Format) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.used + 32 * self.records.len() + 64);
+        match format {
+            Format::Pcap => self.write_pcap(&mut out),
+            Format::Pcapng => self.write_pcapng(&mut out),
+        }
+        out
+    }
+
+    /// Read the export from `offset` on. Reading at offset 0 takes a new
+    /// snapshot of the buffer, later offsets continue in the same snapshot
+    /// so a capture file can be transferred in pieces while the capture
+    /// keeps running. Returns the total size and the data from `offset`.
+    pub fn read(&mut self, format: Format, offset: usize) -> (usize, &[u8]) {
+        if offset == 0 {
+            self.snapshot = self.export(format);
+        }
+        let start = offset.min(self.snapshot.len());
+        (self.snapshot.len(), &self.snapshot[start..])
+    }
+
+    fn write_pcap(&self, out: &mut Vec<u8>) {
+        out.extend(0xa1b2_c3d4u32.to_le_bytes());
+        out.extend(2u16.to_le_bytes());
+        out.extend(4u16.to_le_bytes());
+        out.extend(0i32.to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        out.extend(SNAPLEN.to_le_bytes());
+        out.extend(u32::from(self.link_type()).to_le_bytes());
+        for rec in &self.records {
+            let micros = rec.time.total_micros();
+            out.extend(((micros / 1_000_000) as u32).to_le_bytes());
+            out.extend(((micros % 1_000_000) as u32).to_le_bytes());
+            out.extend((rec.frame.len() as u32).to_le_bytes());
+            out.extend((rec.frame.len() as u32).to_le_bytes());
+            out.extend_from_slice(&rec.frame);
+        }
+    }
+
+    fn write_pcapng(&self, out: &mut Vec<u8>) {
+        // Section header: byte order magic, version 1.0, unknown length.
+        let mut body = Vec::new();
+        body.extend(0x1a2b_3c4du32.to_le_bytes());
+        body.extend(1u16.to_le_bytes());
+        body.extend(0u16.to_le_bytes());
+        body.extend((-1i64).to_le_bytes());
+        pcapng_block(out, 0x0a0d_0d0a, &body);
+
+        // Interface description with the default microsecond resolution.
+        body.clear();
+        body.extend(self.link_type().to_le_bytes());
+        body.extend(0u16.to_le_bytes());
+        body.extend(SNAPLEN.to_le_bytes());
+        pcapng_block(out, 1, &body);
+
+        for rec in &self.records {
+            body.clear();
+            let micros = rec.time.total_micros() as u64;
+            body.extend(0u32.to_le_bytes());
+            body.extend(((micros >> 32) as u32).to_le_bytes());
+            body.extend((micros as u32).to_le_bytes());
+            body.extend((rec.frame.len() as u32).to_le_bytes());
+            body.extend((rec.frame.len() as u32).to_le_bytes());
+            body.extend_from_slice(&rec.frame);
+            body.resize(body.len().next_multiple_of(4), 0);
+            // epb_flags: inbound or outbound, then the end of options.
+            let flags: u32 = match rec.dir {
+                Direction::Rx => 1,
+                Direction::Tx => 2,
+            };
+            body.extend(2u16.to_le_bytes());
+            body.extend(4u16.to_le_bytes());
+            body.extend(flags.to_le_bytes());
+            body.extend([0u8; 4]);
+            pcapng_block(out, 6, &body);
+        }
+    }
+}
+
+/// Append a pcapng block of `ty` around `body`, which must be padded to
+/// 32 bits.
+fn pcapng_block(out: &mut Vec<u8>, ty: u32, body: &[u8]) {
+    let len = (body.len() + 12) as u32;
+    out.extend(ty.to_le_bytes());
+    out.extend(len.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend(len.to_le_bytes());
+}
+
+/// A device recording the frames of another device into a [`Capture`].
+pub struct Capturing<'a, D: Device + ?Sized> {
+    inner: &'a mut D,
+    capture: RefCell<&'a mut Capture>,
+}
+
+impl<'a, D: Device + ?Sized> Capturing<'a, D> {
+    pub fn new(inner: &'a mut D, capture: &'a mut Capture) -> Self {
+        Self {
+            inner,
+            capture: RefCell::new(capture),
+        }
+    }
+}
+
+pub struct CaptureRxToken<'b, 'a, T> {
+    inner: T,
+    capture: &'b RefCell<&'a mut Capture>,
+    time: Instant,
+}
+
+pub struct CaptureTxToken<'b, 'a, T> {
+    inner: T,
+    capture: &'b RefCell<&'a mut Capture>,
+    time: Instant,
+}
+
+impl<'a, D: Device + ?Sized> Device for Capturing<'a, D> {
+    type RxToken<'b>
+        = CaptureRxToken<'b, 'a, D::RxToken<'b>>
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = CaptureTxToken<'b, 'a, D::TxToken<'b>>
+    where
+        Self: 'b;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let (rx, tx) = self.inner.receive(timestamp)?;
+        let rx = CaptureRxToken {
+            inner: rx,
+            capture: &self.capture,
+            time: timestamp,
+        };
+        let tx = CaptureTxToken {
+            inner: tx,
+            capture: &self.capture,
+            time: timestamp,
+        };
+        Some((rx, tx))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(CaptureTxToken {
+            inner: self.inner.transmit(timestamp)?,
+            capture: &self.capture,
+            time: timestamp,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+impl<T: RxToken> RxToken for CaptureRxToken<'_, '_, T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        self.inner.consume(|frame| {
+            self.capture
+                .borrow_mut()
+                .record(self.time, Direction::Rx, frame);
+            f(frame)
+        })
+    }
+
+    fn meta(&self) -> PacketMeta {
+        self.inner.meta()
+    }
+}
+
+impl<T: TxToken> TxToken for CaptureTxToken<'_, '_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.inner.consume(len, |frame| {
+            let res = f(frame);
+            self.capture
+                .borrow_mut()
+                .record(self.time, Direction::Tx, frame);
+            res
+        })
+    }
+
+    fn set_meta(&mut self, meta: PacketMeta) {
+        self.inner.set_meta(meta)
+    }
+}
@@ -0,0 +1,186 @@
+//! Startup configuration of the network interface.
+//!
+//! Settings are read from a configuration file and then overridden by
+//! environment variables. The file is `/etc/net_server.conf` unless
+//! `NET_SERVER_CONFIG` names another one. The server reads it through
+//! fs_server; a missing file or fs_server is not an error.
+//! It holds `key = value` lines, `#` starts a comment:
+//!
+//! ```text
+//! device = virtio
+//! mac = 52:54:00:12:34:56
+//! dhcp = no
+//! address = 192.168.1.20/24
+//! gateway = 192.168.1.1
+//! ipv6 = yes
+//! slaac = no
+//! address = 2001:db8::20/64
+//! gateway6 = fe80::1
+//! rule = accept in state established
+//! rule = accept in tcp to port 22
+//! policy = drop
+//! ```
+//!
+//! `device` selects the backend, `virtio` or `loopback`, see [`crate::device`].
+//! `mac` is only used if the virtio-net device does not report its own
+//! address.
+//! `address` may be given several times and takes IPv4 and IPv6 addresses.
+//! `rule` adds a firewall rule and `policy` sets the action for packets no
+//! rule matches, see [`crate::firewall`]. The environment variables are the
+//! upper-case keys prefixed with `NET_SERVER_` (`NET_SERVER_MAC`,
+//! `NET_SERVER_DHCP`, ...); `NET_SERVER_ADDRESS` and `NET_SERVER_RULE` take
+//! comma separated lists.
+//!
+//! Without a static IPv4 address the interface is configured through DHCP,
+//! without a static global IPv6 address through SLAAC. `ipv6 = no` disables
+//! the link-local address and SLAAC. The loopback addresses 127.0.0.1/8 and
+//! ::1 are always present. On the loopback device the other addressing
+//! settings are ignored.
+
+use crate::device::DeviceKind;
+use crate::firewall::{Action, Rule};
+use smoltcp::wire::{EthernetAddress, IpCidr, Ipv4Address, Ipv6Address};
+use std::str::FromStr;
+
+const DEFAULT_PATH: &str = "/etc/net_server.conf";
+const KEYS: [&str; 10] = [
+    "device", "mac", "dhcp", "address", "gateway", "ipv6", "slaac", "gateway6", "rule", "policy",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetConfig {
+    pub device: DeviceKind,
+    pub mac: EthernetAddress,
+    /// Run the DHCP client. `None` enables it if no static IPv4 address is
+    /// set.
+    pub dhcp: Option<bool>,
+    /// Static addresses.
+    pub addresses: Vec<IpCidr>,
+    /// Static default gateway.
+    pub gateway: Option<Ipv4Address>,
+    /// Enable IPv6 on the interface.
+    pub ipv6: bool,
+    /// Run SLAAC. `None` enables it if no static IPv6 address is set.
+    pub slaac: Option<bool>,
+    /// Static IPv6 default gateway.
+    pub gateway6: Option<Ipv6Address>,
+    /// Firewall rules, in order.
+    pub rules: Vec<Rule>,
+    /// Firewall action for packets no rule matches.
+    pub policy: Action,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self {
+            device: DeviceKind::Virtio,
+            mac: EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+            dhcp: None,
+            addresses: Vec::new(),
+            gateway: None,
+            ipv6: true,
+            slaac: None,
+            gateway6: None,
+            rules: Vec::new(),
+            policy: Action::Accept,
+        }
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {key}: {value}"))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "yes" | "true" | "on" => Ok(true),
+        "0" | "no" | "false" | "off" => Ok(false),
+        _ => Err(format!("invalid value for {key}: {value}")),
+    }
+}
+
+impl NetConfig {
+    /// Read the configuration file and the environment. `read_file` returns
+    /// the contents of the file at a path, `None` if there is none. Invalid
+    /// entries are reported and skipped.
+    pub fn load(read_file: impl FnOnce(&str) -> Option<String>) -> Self {
+        let mut cfg = Self::default();
+        let path = std::env::var("NET_SERVER_CONFIG").unwrap_or_else(|_| DEFAULT_PATH.into());
+        if let Some(text) = read_file(&path) {
+            for err in cfg.apply_file(&text) {
+                println!("net_server: {path}: {err}");
+            }
+        }
+        for key in KEYS {
+            let var = format!("NET_SERVER_{}", key.to_uppercase());
+            if let Ok(value) = std::env::var(&var) {
+                match key {
+                    "address" => cfg.addresses.clear(),
+                    "rule" => cfg.rules.clear(),
+                    _ => {}
+                }
+                for value in value.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+                    if let Err(err) = cfg.set(key, value) {
+                        println!("net_server: {var}: {err}");
+                    }
+                }
+            }
+        }
+        cfg
+    }
+
+    /// Apply the lines of a configuration file. Returns the errors found.
+    pub fn apply_file(&mut self, text: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let res = match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value.trim()),
+                None => Err(format!("expected key = value: {line}")),
+            };
+            if let Err(err) = res {
+                errors.push(format!("line {}: {err}", n + 1));
+            }
+        }
+        errors
+    }
+
+    /// Set a single key. `address` adds to the list of static addresses.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "device" => self.device = parse(key, value)?,
+            "mac" => self.mac = parse(key, value)?,
+            "dhcp" => self.dhcp = Some(parse_bool(key, value)?),
+            "address" => self.addresses.push(parse(key, value)?),
+            "gateway" => self.gateway = Some(parse(key, value)?),
+            "ipv6" => self.ipv6 = parse_bool(key, value)?,
+            "slaac" => self.slaac = Some(parse_bool(key, value)?),
+            "gateway6" => self.gateway6 = Some(parse(key, value)?),
+            "rule" => self.rules.push(
+                value
+                    .parse()
+                    .map_err(|e| format!("invalid rule {value}: {e}"))?,
+            ),
+            "policy" => self.policy = parse(key, value)?,
+            _ => return Err(format!("unknown key {key}")),
+        }
+        Ok(())
+    }
+
+    /// Whether the DHCP client should run.
+    pub fn use_dhcp(&self) -> bool {
+        let static_v4 = self.addresses.iter().any(|a| matches!(a, IpCidr::Ipv4(_)));
+        self.dhcp.unwrap_or(!static_v4)
+    }
+
+    /// Whether IPv6 addresses should be autoconfigured.
+    pub fn use_slaac(&self) -> bool {
+        let static_v6 = self.addresses.iter().any(|a| matches!(a, IpCidr::Ipv6(_)));
+        self.ipv6 && self.slaac.unwrap_or(!static_v6)
+    }
+}
@@ -0,0 +1,132 @@
+//! Device backends of the interface.
+//!
+//! The stack runs on any smoltcp [`Device`]. The server binary picks one at
+//! startup through the `device` setting of [`crate::config`]:
+//!
+//! - `virtio`: the virtio-net driver, see the server binary.
+//! - `loopback`: an in-memory IP loopback device. It needs no hardware, so
+//!   the server falls back to it when no virtio-net device is available.
+//!   The interface then only has the addresses 127.0.0.1 and ::1, which it
+//!   also has on any other device, see [`crate::loopback`].
+//!
+//! For host-side tests there are [`Pipe`], two in-memory Ethernet devices
+//! connected back to back, and with the `tap` feature a Linux TAP
+//! interface.
+
+use smoltcp::phy::{Device, DeviceCapabilities, Loopback, Medium, RxToken, TxToken};
+use smoltcp::time::Instant;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Largest Ethernet frame without FCS.
+pub const ETHERNET_MTU: usize = 1514;
+/// Frames queued in one direction of a pipe. Further frames are dropped,
+/// like on a congested link.
+pub const PIPE_FRAMES: usize = 64;
+
+/// Which backend the server runs on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Virtio,
+    Loopback,
+}
+
+impl core::str::FromStr for DeviceKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "virtio" => Ok(Self::Virtio),
+            "loopback" => Ok(Self::Loopback),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A fresh loopback device.
+pub fn loopback() -> Loopback {
+    Loopback::new(Medium::Ip)
+}
+
+/// Host TAP interface `name`. The interface must exist and be accessible,
+/// e.g. created with `ip tuntap add name tap0 mode tap user $USER`.
+#[cfg(all(feature = "tap", target_os = "linux"))]
+pub fn tap(name: &str) -> std::io::Result<smoltcp::phy::TunTapInterface> {
+    smoltcp::phy::TunTapInterface::new(name, Medium::Ethernet)
+}
+
+type Queue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// One end of an in-memory Ethernet link.
+pub struct Pipe {
+    rx: Queue,
+    tx: Queue,
+}
+
+impl Pipe {
+    /// Two devices connected to each other.
+    pub fn pair() -> (Pipe, Pipe) {
+        let a = Queue::default();
+        let b = Queue::default();
+        (
+            Pipe {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            Pipe { rx: b, tx: a },
+        )
+    }
+}
+
+pub struct PipeRxToken {
+    frame: Vec<u8>,
+}
+
+pub struct PipeTxToken<'a> {
+    queue: &'a Queue,
+}
+
+impl Device for Pipe {
+    type RxToken<'a> = PipeRxToken;
+    type TxToken<'a> = PipeTxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.rx.lock().unwrap().pop_front()?;
+        Some((PipeRxToken { frame }, PipeTxToken { queue: &self.tx }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(PipeTxToken { queue: &self.tx })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = ETHERNET_MTU;
+        caps.medium = Medium::Ethernet;
+        caps
+    }
+}
+
+impl RxToken for PipeRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.frame)
+    }
+}
+
+impl TxToken for PipeTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0u8; len];
+        let res = f(&mut frame);
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() < PIPE_FRAMES {
+            queue.push_back(frame);
+        }
+        res
+    }
+}
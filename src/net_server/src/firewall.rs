@@ -0,0 +1,662 @@
+//! Stateful packet filter.
+//!
+//! The [`Firewall`] sits between the device and the stack: received frames
+//! are checked before smoltcp sees them, transmitted frames before they
+//! reach the device. Rules are checked in order and the first matching rule
+//! decides; frames no rule matches get the policy, `accept` by default.
+//! Rules are written like this:
+//!
+//! ```text
+//! accept in state established
+//! accept in tcp to port 22
+//! accept in on eth0 udp from 10.0.0.0/8 port 1024-65535 to port 53
+//! reject in tcp
+//! drop in
+//! ```
+//!
+//! A rule starts with its action, `accept`, `drop` or `reject`, followed by
+//! optional conditions in any order:
+//!
+//! - `in` or `out`: the direction.
+//! - `on NAME`: the interface, `eth0` on Ethernet devices and `lo` on the
+//!   loopback device.
+//! - `tcp`, `udp` or `icmp`: the protocol; `icmp` includes ICMPv6.
+//! - `from ADDR port PORTS`, `to ADDR port PORTS`: source and destination.
+//!   The address is a host, a prefix like `10.0.0.0/8` or `any`, either
+//!   part may be left out. Ports are a number or a range like `1000-2000`
+//!   and need `tcp` or `udp`.
+//! - `state new` or `state established`: the connection state.
+//!
+//! Accepted packets establish a connection, identified by protocol,
+//! addresses and ports, so replies and later packets in either direction
+//! are `established`. Connections expire when idle, TCP connections soon
+//! after a FIN or RST.
+//!
+//! `reject` answers received TCP segments with a reset and other packets
+//! except ICMP with an ICMP port unreachable message; outgoing packets are
+//! dropped. ARP, other non-IP frames and IPv6 neighbor discovery always
+//! pass, the stack cannot work without them.
+
+use crate::capture::Direction;
+use crate::packet::{Headers, Kind};
+use smoltcp::phy::{ChecksumCapabilities, Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetFrame, EthernetRepr, Icmpv4DstUnreachable, Icmpv4Packet, Icmpv4Repr,
+    Icmpv6DstUnreachable, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpEndpoint, IpProtocol,
+    Ipv4Packet, Ipv4Repr, Ipv6Packet, Ipv6Repr, TcpControl, TcpPacket, TcpRepr, TcpSeqNumber,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Connections tracked at most. Further connections stay `new`.
+pub const MAX_FLOWS: usize = 4096;
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+const TCP_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+const TCP_CLOSING: Duration = Duration::from_secs(10);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Accept,
+    Drop,
+    Reject,
+}
+
+impl core::str::FromStr for Action {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "accept" => Ok(Self::Accept),
+            "drop" => Ok(Self::Drop),
+            "reject" => Ok(Self::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Accept => "accept",
+            Self::Drop => "drop",
+            Self::Reject => "reject",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    New,
+    Established,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Proto {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+/// One end of a connection: an address and a port range.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Peer {
+    addr: Option<IpCidr>,
+    ports: Option<(u16, u16)>,
+}
+
+impl Peer {
+    fn matches(&self, addr: Option<IpAddress>, port: Option<u16>) -> bool {
+        self.addr
+            .is_none_or(|cidr| addr.is_some_and(|a| cidr.contains_addr(&a)))
+            && self
+                .ports
+                .is_none_or(|(lo, hi)| port.is_some_and(|p| (lo..=hi).contains(&p)))
+    }
+}
+
+/// A filter rule, see the [module documentation](self) for the syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    action: Action,
+    dir: Option<Direction>,
+    iface: Option<String>,
+    proto: Option<Proto>,
+    src: Peer,
+    dst: Peer,
+    state: Option<State>,
+}
+
+fn parse_ports(value: &str) -> Option<(u16, u16)> {
+    let (lo, hi) = value.split_once('-').unwrap_or((value, value));
+    let (lo, hi) = (lo.parse().ok()?, hi.parse().ok()?);
+    (lo <= hi).then_some((lo, hi))
+}
+
+fn parse_addr(value: &str) -> Option<Option<IpCidr>> {
+    if value == "any" {
+        return Some(None);
+    }
+    if let Ok(cidr) = value.parse() {
+        return Some(Some(cidr));
+    }
+    let addr: IpAddress = value.parse().ok()?;
+    let len = match addr {
+        IpAddress::Ipv4(_) => 32,
+        IpAddress::Ipv6(_) => 128,
+    };
+    Some(Some(IpCidr::new(addr, len)))
+}
+
+impl core::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut words = s.split_whitespace().peekable();
+        let action = words.next().ok_or("empty rule")?;
+        let mut rule = Rule {
+            action: action
+                .parse()
+                .map_err(|_| format!("unknown action {action}"))?,
+            dir: None,
+            iface: None,
+            proto: None,
+            src: Peer::default(),
+            dst: Peer::default(),
+            state: None,
+        };
+        while let Some(word) = words.next() {
+            let mut value = || words.next().ok_or(format!("{word} needs a value"));
+            match word {
+                "in" => rule.dir = Some(Direction::Rx),
+                "out" => rule.dir = Some(Direction::Tx),
+                "on" => rule.iface = Some(value()?.to_string()),
+                "tcp" => rule.proto = Some(Proto::Tcp),
+                "udp" => rule.proto = Some(Proto::Udp),
+                "icmp" => rule.proto = Some(Proto::Icmp),
+                "state" => {
+                    rule.state = Some(match value()? {
+                        "new" => State::New,
+                        "established" => State::Established,
+                        other => return Err(format!("unknown state {other}")),
+                    })
+                }
+                "from" | "to" => {
+                    let mut peer = Peer::default();
+                    let mut given = false;
+                    if let Some(addr) = words.next_if(|w| *w != "port") {
+                        peer.addr = parse_addr(addr).ok_or(format!("invalid address {addr}"))?;
+                        given = true;
+                    }
+                    if words.next_if_eq(&"port").is_some() {
+                        let ports = words.next().ok_or("port needs a value")?;
+                        peer.ports =
+                            Some(parse_ports(ports).ok_or(format!("invalid port {ports}"))?);
+                        given = true;
+                    }
+                    if !given {
+                        return Err(format!("{word} needs an address or port"));
+                    }
+                    match word {
+                        "from" => rule.src = peer,
+                        _ => rule.dst = peer,
+                    }
+                }
+                _ => return Err(format!("unknown condition {word}")),
+            }
+        }
+        let ports = rule.src.ports.is_some() || rule.dst.ports.is_some();
+        if ports && !matches!(rule.proto, Some(Proto::Tcp | Proto::Udp)) {
+            return Err("ports need tcp or udp".into());
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        match self.dir {
+            Some(Direction::Rx) => write!(f, " in")?,
+            Some(Direction::Tx) => write!(f, " out")?,
+            None => {}
+        }
+        if let Some(iface) = &self.iface {
+            write!(f, " on {iface}")?;
+        }
+        match self.proto {
+            Some(Proto::Tcp) => write!(f, " tcp")?,
+            Some(Proto::Udp) => write!(f, " udp")?,
+            Some(Proto::Icmp) => write!(f, " icmp")?,
+            None => {}
+        }
+        for (word, peer) in [("from", &self.src), ("to", &self.dst)] {
+            if *peer == Peer::default() {
+                continue;
+            }
+            write!(f, " {word}")?;
+            if let Some(addr) = peer.addr {
+                write!(f, " {addr}")?;
+            }
+            match peer.ports {
+                Some((lo, hi)) if lo == hi => write!(f, " port {lo}")?,
+                Some((lo, hi)) => write!(f, " port {lo}-{hi}")?,
+                None => {}
+            }
+        }
+        match self.state {
+            Some(State::New) => write!(f, " state new"),
+            Some(State::Established) => write!(f, " state established"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, iface: &str, dir: Direction, h: &Headers, state: State) -> bool {
+        let proto = |p| match p {
+            Proto::Tcp => h.proto == Some(IpProtocol::Tcp),
+            Proto::Udp => h.proto == Some(IpProtocol::Udp),
+            Proto::Icmp => h.is_icmp(),
+        };
+        let (sport, dport) = h.ports.unzip();
+        self.dir.is_none_or(|d| d == dir)
+            && self.iface.as_deref().is_none_or(|i| i == iface)
+            && self.proto.is_none_or(proto)
+            && self.src.matches(h.src, sport)
+            && self.dst.matches(h.dst, dport)
+            && self.state.is_none_or(|s| s == state)
+    }
+}
+
+/// Packets and bytes a rule matched.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// A tracked connection: protocol and both ends in a fixed order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct Flow {
+    proto: u8,
+    ends: [IpEndpoint; 2],
+}
+
+impl Flow {
+    fn of(h: &Headers) -> Option<Self> {
+        let (sport, dport) = h.ports.unwrap_or_default();
+        let mut ends = [
+            IpEndpoint::new(h.src?, sport),
+            IpEndpoint::new(h.dst?, dport),
+        ];
+        ends.sort();
+        Some(Self {
+            proto: h.proto.map_or(0, u8::from),
+            ends,
+        })
+    }
+}
+
+struct Conn {
+    expires: Instant,
+    closing: bool,
+}
+
+/// The rules and connection table of an interface.
+pub struct Firewall {
+    iface: &'static str,
+    medium: Medium,
+    rules: Vec<(Rule, Counters)>,
+    policy: Action,
+    policy_counters: Counters,
+    flows: HashMap<Flow, Conn>,
+}
+
+impl Firewall {
+    /// A firewall accepting everything on a device with `medium`.
+    pub fn new(medium: Medium) -> Self {
+        Self {
+            iface: match medium {
+                Medium::Ethernet => "eth0",
+                Medium::Ip => "lo",
+            },
+            medium,
+            rules: Vec::new(),
+            policy: Action::Accept,
+            policy_counters: Counters::default(),
+            flows: HashMap::new(),
+        }
+    }
+
+    /// The rules with their counters, in order.
+    pub fn rules(&self) -> &[(Rule, Counters)] {
+        &self.rules
+    }
+
+    /// Insert `rule` before position `index`, or append it if `index` is
+    /// past the end.
+    pub fn insert(&mut self, index: usize, rule: Rule) {
+        let index = index.min(self.rules.len());
+        self.rules.insert(index, (rule, Counters::default()));
+    }
+
+    /// Remove the rule at `index`.
+    pub fn remove(&mut self, index: usize) -> Option<Rule> {
+        (index < self.rules.len()).then(|| self.rules.remove(index).0)
+    }
+
+    /// The action for packets no rule matches, with its counters.
+    pub fn policy(&self) -> (Action, Counters) {
+        (self.policy, self.policy_counters)
+    }
+
+    pub fn set_policy(&mut self, action: Action) {
+        self.policy = action;
+    }
+
+    /// Number of tracked connections.
+    pub fn connections(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Decide on a frame passing in direction `dir` and update the counters
+    /// and the connection table.
+    pub fn check(&mut self, now: Instant, dir: Direction, frame: &[u8]) -> Action {
+        let h = Headers::parse(self.medium, frame);
+        if exempt(&h) {
+            return Action::Accept;
+        }
+        let Some(flow) = Flow::of(&h) else {
+            return Action::Accept;
+        };
+        let state = match self.flows.get(&flow) {
+            Some(conn) if conn.expires > now => State::Established,
+            _ => State::New,
+        };
+        let iface = self.iface;
+        let (action, counters) = match self
+            .rules
+            .iter_mut()
+            .find(|(rule, _)| rule.matches(iface, dir, &h, state))
+        {
+            Some((rule, counters)) => (rule.action, counters),
+            None => (self.policy, &mut self.policy_counters),
+        };
+        counters.packets += 1;
+        counters.bytes += frame.len() as u64;
+        if action == Action::Accept {
+            self.track(now, flow, &h);
+        }
+        action
+    }
+
+    fn track(&mut self, now: Instant, flow: Flow, h: &Headers) {
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&flow) {
+            self.flows.retain(|_, conn| conn.expires > now);
+            if self.flows.len() >= MAX_FLOWS {
+                return;
+            }
+        }
+        let tcp = h.proto == Some(IpProtocol::Tcp);
+        let conn = self.flows.entry(flow).or_insert(Conn {
+            expires: now,
+            closing: false,
+        });
+        conn.closing |= tcp && h.tcp_end;
+        conn.expires = now
+            + match (tcp, conn.closing) {
+                (true, true) => TCP_CLOSING,
+                (true, false) => TCP_TIMEOUT,
+                _ => FLOW_TIMEOUT,
+            };
+    }
+}
+
+/// Frames the stack needs regardless of the rules.
+fn exempt(h: &Headers) -> bool {
+    match h.kind {
+        Kind::Ipv4 => false,
+        // Router and neighbor solicitations and advertisements, redirects.
+        Kind::Ipv6 => {
+            h.proto == Some(IpProtocol::Icmpv6)
+                && h.icmp_type.is_some_and(|t| (133..=137).contains(&t))
+        }
+        Kind::Arp | Kind::Other => true,
+    }
+}
+
+/// The answer rejecting a received frame: a TCP reset, or an ICMP port
+/// unreachable message. `None` for ICMP, TCP resets and packets not sent
+/// to a unicast address.
+fn reject_reply(medium: Medium, frame: &[u8]) -> Option<Vec<u8>> {
+    let caps = ChecksumCapabilities::default();
+    let (eth, packet) = match medium {
+        Medium::Ethernet => {
+            let eth = EthernetFrame::new_checked(frame).ok()?;
+            (Some(EthernetRepr::parse(&eth).ok()?), eth.payload())
+        }
+        Medium::Ip => (None, frame),
+    };
+    let reply = match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(packet).ok()?;
+            let repr = Ipv4Repr::parse(&ip, &caps).ok()?;
+            if !IpAddress::from(repr.dst_addr).is_unicast() || ip.frag_offset() != 0 {
+                return None;
+            }
+            let (src, dst) = (repr.dst_addr.into(), repr.src_addr.into());
+            let (proto, payload) = match repr.next_header {
+                IpProtocol::Icmp => return None,
+                IpProtocol::Tcp => (IpProtocol::Tcp, tcp_reset(src, dst, ip.payload())?),
+                _ => {
+                    let data = &ip.payload()[..ip.payload().len().min(8)];
+                    let icmp = Icmpv4Repr::DstUnreachable {
+                        reason: Icmpv4DstUnreachable::PortUnreachable,
+                        header: repr,
+                        data,
+                    };
+                    let mut buf = vec![0; icmp.buffer_len()];
+                    icmp.emit(&mut Icmpv4Packet::new_unchecked(&mut buf[..]), &caps);
+                    (IpProtocol::Icmp, buf)
+                }
+            };
+            let reply = Ipv4Repr {
+                src_addr: repr.dst_addr,
+                dst_addr: repr.src_addr,
+                next_header: proto,
+                payload_len: payload.len(),
+                hop_limit: 64,
+            };
+            let mut buf = vec![0; reply.buffer_len() + payload.len()];
+            reply.emit(&mut Ipv4Packet::new_unchecked(&mut buf[..]), &caps);
+            buf[reply.buffer_len()..].copy_from_slice(&payload);
+            buf
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(packet).ok()?;
+            let repr = Ipv6Repr::parse(&ip).ok()?;
+            if !IpAddress::from(repr.dst_addr).is_unicast() {
+                return None;
+            }
+            let (src, dst) = (repr.dst_addr, repr.src_addr);
+            let (proto, payload) = match repr.next_header {
+                IpProtocol::Icmpv6 => return None,
+                IpProtocol::Tcp => (
+                    IpProtocol::Tcp,
+                    tcp_reset(src.into(), dst.into(), ip.payload())?,
+                ),
+                _ => {
+                    // As much of the packet as fits into the minimum MTU.
+                    let data = &ip.payload()[..ip.payload().len().min(1280 - 48 - 40)];
+                    let icmp = Icmpv6Repr::DstUnreachable {
+                        reason: Icmpv6DstUnreachable::PortUnreachable,
+                        header: repr,
+                        data,
+                    };
+                    let mut buf = vec![0; icmp.buffer_len()];
+                    let mut packet = Icmpv6Packet::new_unchecked(&mut buf[..]);
+                    icmp.emit(&src, &dst, &mut packet, &caps);
+                    (IpProtocol::Icmpv6, buf)
+                }
+            };
+            let reply = Ipv6Repr {
+                src_addr: src,
+                dst_addr: dst,
+                next_header: proto,
+                payload_len: payload.len(),
+                hop_limit: 64,
+            };
+            let mut buf = vec![0; reply.buffer_len() + payload.len()];
+            reply.emit(&mut Ipv6Packet::new_unchecked(&mut buf[..]));
+            buf[reply.buffer_len()..].copy_from_slice(&payload);
+            buf
+        }
+        _ => return None,
+    };
+    let Some(eth) = eth else {
+        return Some(reply);
+    };
+    let eth = EthernetRepr {
+        src_addr: eth.dst_addr,
+        dst_addr: eth.src_addr,
+        ethertype: eth.ethertype,
+    };
+    let mut frame = vec![0; eth.buffer_len() + reply.len()];
+    eth.emit(&mut EthernetFrame::new_unchecked(&mut frame[..]));
+    frame[eth.buffer_len()..].copy_from_slice(&reply);
+    Some(frame)
+}
+
+/// A reset answering the TCP segment `segment`, sent from `src` to `dst`.
+fn tcp_reset(src: IpAddress, dst: IpAddress, segment: &[u8]) -> Option<Vec<u8>> {
+    let tcp = TcpPacket::new_checked(segment).ok()?;
+    if tcp.rst() {
+        return None;
+    }
+    let (seq_number, ack_number) = if tcp.ack() {
+        (tcp.ack_number(), None)
+    } else {
+        (TcpSeqNumber(0), Some(tcp.seq_number() + tcp.segment_len()))
+    };
+    let repr = TcpRepr {
+        src_port: tcp.dst_port(),
+        dst_port: tcp.src_port(),
+        control: TcpControl::Rst,
+        seq_number,
+        ack_number,
+        window_len: 0,
+        window_scale: None,
+        max_seg_size: None,
+        sack_permitted: false,
+        sack_ranges: [None; 3],
+        timestamp: None,
+        payload: &[],
+    };
+    let mut buf = vec![0; repr.buffer_len()];
+    let caps = ChecksumCapabilities::default();
+    repr.emit(
+        &mut TcpPacket::new_unchecked(&mut buf[..]),
+        &src,
+        &dst,
+        &caps,
+    );
+    Some(buf)
+}
+
+/// A device passing the frames of another device through a [`Firewall`].
+pub struct Filtering<'a, D: Device + ?Sized> {
+    inner: &'a mut D,
+    firewall: &'a mut Firewall,
+}
+
+impl<'a, D: Device + ?Sized> Filtering<'a, D> {
+    pub fn new(inner: &'a mut D, firewall: &'a mut Firewall) -> Self {
+        Self { inner, firewall }
+    }
+}
+
+pub struct FilterRxToken {
+    frame: Vec<u8>,
+}
+
+pub struct FilterTxToken<'b, T> {
+    /// Missing if the device cannot transmit the reply to an accepted frame
+    /// right now; frames for it are then dropped.
+    inner: Option<T>,
+    firewall: &'b mut Firewall,
+    time: Instant,
+}
+
+impl<D: Device + ?Sized> Device for Filtering<'_, D> {
+    type RxToken<'b>
+        = FilterRxToken
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = FilterTxToken<'b, D::TxToken<'b>>
+    where
+        Self: 'b;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        // Skip over the frames the firewall does not let through.
+        let frame = loop {
+            let (rx, tx) = self.inner.receive(timestamp)?;
+            let frame = rx.consume(|frame| frame.to_vec());
+            match self.firewall.check(timestamp, Direction::Rx, &frame) {
+                Action::Accept => break frame,
+                Action::Drop => {}
+                Action::Reject => {
+                    if let Some(reply) = reject_reply(self.firewall.medium, &frame) {
+                        tx.consume(reply.len(), |buf| buf.copy_from_slice(&reply));
+                    }
+                }
+            }
+        };
+        // The token of the accepted frame cannot outlive the loop. The frame
+        // is delivered even if the device cannot transmit a reply right now.
+        let tx = FilterTxToken {
+            inner: self.inner.transmit(timestamp),
+            firewall: self.firewall,
+            time: timestamp,
+        };
+        Some((FilterRxToken { frame }, tx))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(FilterTxToken {
+            inner: Some(self.inner.transmit(timestamp)?),
+            firewall: self.firewall,
+            time: timestamp,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+impl RxToken for FilterRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.frame)
+    }
+}
+
+impl<T: TxToken> TxToken for FilterTxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0u8; len];
+        let res = f(&mut frame);
+        if self.firewall.check(self.time, Direction::Tx, &frame) == Action::Accept {
+            if let Some(inner) = self.inner {
+                inner.consume(len, |buf| buf.copy_from_slice(&frame));
+            }
+        }
+        res
+    }
+}
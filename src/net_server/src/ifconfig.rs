@@ -0,0 +1,523 @@
+//! Addresses, routes and the address autoconfiguration of the interface.
+//!
+//! Static configuration is applied once at startup; the administrative
+//! operations change it at runtime. An address acquired through DHCP is
+//! managed like any other address but replaced whenever the lease changes.
+//!
+//! With IPv6 enabled the interface always has a link-local address derived
+//! from its MAC address. Global IPv6 addresses and the IPv6 default route
+//! are either configured statically or learnt from router advertisements
+//! (SLAAC, RFC 4862). smoltcp answers neighbor solicitations itself but
+//! does not act on router advertisements, so [`Slaac`] receives them through
+//! a raw ICMPv6 socket. Duplicate address detection is not performed.
+//!
+//! Errors are positive errno values.
+
+use crate::config::NetConfig;
+use crate::loopback::is_loopback;
+use libc::{EEXIST, EINVAL, ENOENT, ENOSPC};
+use smoltcp::iface::{Interface, Route, SocketHandle, SocketSet};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::{dhcpv4, raw};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetAddress, HardwareAddress, Icmpv6Packet, Icmpv6Repr, IpAddress, IpCidr, IpProtocol,
+    IpVersion, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr,
+    NdiscPrefixInfoFlags, NdiscPrefixInformation, NdiscRepr, IPV6_HEADER_LEN,
+    IPV6_LINK_LOCAL_ALL_ROUTERS,
+};
+
+/// Add `cidr` to the interface addresses. Addresses go ahead of the
+/// loopback addresses: smoltcp sends all IPv4 traffic from the first IPv4
+/// address of the interface.
+pub fn add_address(iface: &mut Interface, cidr: IpCidr) -> Result<(), i32> {
+    if iface.ip_addrs().contains(&cidr) {
+        return Err(EEXIST);
+    }
+    let mut res = Ok(());
+    iface.update_ip_addrs(|addrs| {
+        let at = addrs
+            .iter()
+            .position(|a| is_loopback(a.address()) && !is_loopback(cidr.address()))
+            .unwrap_or(addrs.len());
+        if addrs.insert(at, cidr).is_err() {
+            res = Err(ENOSPC);
+        }
+    });
+    res
+}
+
+/// Remove `cidr` from the interface addresses.
+pub fn remove_address(iface: &mut Interface, cidr: IpCidr) -> Result<(), i32> {
+    let mut res = Err(ENOENT);
+    iface.update_ip_addrs(|addrs| {
+        if let Some(i) = addrs.iter().position(|a| *a == cidr) {
+            addrs.remove(i);
+            res = Ok(());
+        }
+    });
+    res
+}
+
+/// Current routes as destination and gateway.
+pub fn routes(iface: &mut Interface) -> Vec<(IpCidr, IpAddress)> {
+    let mut list = Vec::new();
+    iface.routes_mut().update(|routes| {
+        list.extend(routes.iter().map(|r| (r.cidr, r.via_router)));
+    });
+    list
+}
+
+/// Route `cidr` through `via`, replacing an existing route for the same
+/// destination. Both must be of the same family.
+pub fn add_route(iface: &mut Interface, cidr: IpCidr, via: IpAddress) -> Result<(), i32> {
+    if cidr.address().version() != via.version() {
+        return Err(EINVAL);
+    }
+    let route = Route {
+        cidr,
+        via_router: via,
+        preferred_until: None,
+        expires_at: None,
+    };
+    let mut res = Ok(());
+    iface.routes_mut().update(|routes| {
+        if let Some(r) = routes.iter_mut().find(|r| r.cidr == cidr) {
+            *r = route;
+        } else if routes.push(route).is_err() {
+            res = Err(ENOSPC);
+        }
+    });
+    res
+}
+
+/// Remove the route for `cidr`.
+pub fn remove_route(iface: &mut Interface, cidr: IpCidr) -> Result<(), i32> {
+    let mut res = Err(ENOENT);
+    iface.routes_mut().update(|routes| {
+        if let Some(i) = routes.iter().position(|r| r.cidr == cidr) {
+            routes.remove(i);
+            res = Ok(());
+        }
+    });
+    res
+}
+
+fn default_route() -> IpCidr {
+    IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0))
+}
+
+fn default_route_v6() -> IpCidr {
+    IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, 0))
+}
+
+/// Whether the route for `cidr` still goes through `via`.
+fn routed_via(iface: &mut Interface, cidr: IpCidr, via: IpAddress) -> bool {
+    routes(iface)
+        .into_iter()
+        .any(|(c, v)| c == cidr && v == via)
+}
+
+/// Address in the /64 `prefix` with the modified EUI-64 interface
+/// identifier of `mac` (RFC 4291, appendix A).
+pub fn with_interface_id(prefix: Ipv6Address, mac: EthernetAddress) -> Ipv6Address {
+    let mut bytes = prefix.octets();
+    let m = mac.0;
+    bytes[8..].copy_from_slice(&[m[0] ^ 0x02, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5]]);
+    Ipv6Address::from(bytes)
+}
+
+/// Link-local address of an interface with address `mac`.
+pub fn link_local(mac: EthernetAddress) -> Ipv6Cidr {
+    let prefix = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
+    Ipv6Cidr::new(with_interface_id(prefix, mac), 64)
+}
+
+fn is_link_local(addr: Ipv6Address) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Add the loopback addresses, which the interface has on every device.
+pub fn apply_loopback(iface: &mut Interface, cfg: &NetConfig) {
+    let mut addrs = vec![IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::LOCALHOST, 8))];
+    if cfg.ipv6 {
+        addrs.push(IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::LOCALHOST, 128)));
+    }
+    for cidr in addrs {
+        if let Err(err) = add_address(iface, cidr) {
+            println!("net_server: cannot add address {cidr}: errno {err}");
+        }
+    }
+}
+
+/// Apply the static part of `cfg`. Entries that do not fit are reported and
+/// skipped.
+pub fn apply_static(iface: &mut Interface, cfg: &NetConfig) {
+    let link_local = cfg.ipv6.then(|| IpCidr::Ipv6(link_local(cfg.mac)));
+    for &cidr in link_local.iter().chain(&cfg.addresses) {
+        if let Err(err) = add_address(iface, cidr) {
+            println!("net_server: cannot add address {cidr}: errno {err}");
+        }
+    }
+    if let Some(gw) = cfg.gateway {
+        if let Err(err) = add_route(iface, default_route(), IpAddress::Ipv4(gw)) {
+            println!("net_server: cannot add gateway {gw}: errno {err}");
+        }
+    }
+    if let Some(gw) = cfg.gateway6 {
+        if let Err(err) = add_route(iface, default_route_v6(), IpAddress::Ipv6(gw)) {
+            println!("net_server: cannot add gateway {gw}: errno {err}");
+        }
+    }
+}
+
+/// DHCPv4 client state.
+#[derive(Default)]
+pub struct Dhcp {
+    socket: Option<SocketHandle>,
+    /// Address added for the current lease.
+    address: Option<Ipv4Cidr>,
+    /// Default gateway installed for the current lease.
+    router: Option<Ipv4Address>,
+}
+
+impl Dhcp {
+    /// Start or stop the DHCP client. Stopping drops the leased
+    /// configuration.
+    pub fn set_enabled(&mut self, iface: &mut Interface, set: &mut SocketSet<'static>, on: bool) {
+        match (self.socket, on) {
+            (None, true) => self.socket = Some(set.add(dhcpv4::Socket::new())),
+            (Some(h), false) => {
+                set.remove(h);
+                self.socket = None;
+                self.release(iface);
+            }
+            _ => {}
+        }
+    }
+
+    /// Undo the configuration of the current lease, leaving entries alone
+    /// that have been changed in the meantime.
+    fn release(&mut self, iface: &mut Interface) {
+        if let Some(cidr) = self.address.take() {
+            let _ = remove_address(iface, IpCidr::Ipv4(cidr));
+        }
+        if let Some(router) = self.router.take() {
+            if routed_via(iface, default_route(), IpAddress::Ipv4(router)) {
+                let _ = remove_route(iface, default_route());
+            }
+        }
+    }
+
+    /// Apply configuration changes reported by the DHCP socket. Must be
+    /// called after every `Interface::poll`.
+    pub fn update(&mut self, iface: &mut Interface, set: &mut SocketSet<'static>) {
+        let Some(h) = self.socket else {
+            return;
+        };
+        let lease = match set.get_mut::<dhcpv4::Socket>(h).poll() {
+            Some(dhcpv4::Event::Configured(config)) => Some((config.address, config.router)),
+            Some(dhcpv4::Event::Deconfigured) => None,
+            None => return,
+        };
+        self.release(iface);
+        let Some((cidr, router)) = lease else {
+            println!("net_server: DHCP lease lost");
+            return;
+        };
+        println!("net_server: DHCP lease {cidr}");
+        match add_address(iface, IpCidr::Ipv4(cidr)) {
+            Ok(()) => self.address = Some(cidr),
+            // Statically configured already; not ours to remove later.
+            Err(EEXIST) => {}
+            Err(err) => println!("net_server: cannot add DHCP address {cidr}: errno {err}"),
+        }
+        if let Some(router) = router {
+            match add_route(iface, default_route(), IpAddress::Ipv4(router)) {
+                Ok(()) => self.router = Some(router),
+                Err(err) => println!("net_server: cannot add DHCP gateway {router}: errno {err}"),
+            }
+        }
+    }
+}
+
+/// Router solicitations sent when autoconfiguration starts (RFC 4861,
+/// section 10).
+const MAX_SOLICITS: u8 = 3;
+const SOLICIT_INTERVAL: Duration = Duration::from_secs(4);
+/// Lower bound for shortening the lifetime of an autoconfigured address
+/// through an advertisement (RFC 4862, section 5.5.3).
+const MIN_VALID_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+const RAW_PACKETS: usize = 4;
+const RAW_BUF: usize = 2048;
+
+/// The parts of a router advertisement used for autoconfiguration.
+struct Advert {
+    router: Ipv6Address,
+    lifetime: Duration,
+    prefix: Option<NdiscPrefixInformation>,
+}
+
+/// Parse a packet received on the raw ICMPv6 socket. Only router
+/// advertisements that come from a link-local address and were not
+/// forwarded (RFC 4861, section 6.1.2) are returned.
+fn parse_advert(packet: &[u8]) -> Option<Advert> {
+    let ip = Ipv6Packet::new_checked(packet).ok()?;
+    let ip = Ipv6Repr::parse(&ip).ok()?;
+    if ip.hop_limit != 255 || !is_link_local(ip.src_addr) {
+        return None;
+    }
+    let icmp = Icmpv6Packet::new_checked(&packet[IPV6_HEADER_LEN..]).ok()?;
+    let repr = Icmpv6Repr::parse(
+        &ip.src_addr,
+        &ip.dst_addr,
+        &icmp,
+        &ChecksumCapabilities::default(),
+    )
+    .ok()?;
+    match repr {
+        Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+            router_lifetime,
+            prefix_info,
+            ..
+        }) => Some(Advert {
+            router: ip.src_addr,
+            lifetime: router_lifetime,
+            prefix: prefix_info,
+        }),
+        _ => None,
+    }
+}
+
+/// IPv6 stateless address autoconfiguration.
+///
+/// Router solicitations are sent after starting; afterwards the client
+/// relies on periodic advertisements. Each advertised prefix of length 64
+/// with the autonomous flag yields an address, and a router with a non-zero
+/// lifetime becomes the IPv6 default router. Both are removed again when
+/// their lifetime runs out. smoltcp keeps only one prefix per
+/// advertisement, so routers announcing several prefixes are only partly
+/// honoured.
+pub struct Slaac {
+    mac: EthernetAddress,
+    socket: Option<SocketHandle>,
+    /// Autoconfigured addresses and when they expire.
+    addresses: Vec<(Ipv6Cidr, Instant)>,
+    /// Default router installed from an advertisement and when it expires.
+    router: Option<(Ipv6Address, Instant)>,
+    solicits: u8,
+    next_solicit: Instant,
+}
+
+impl Slaac {
+    pub fn new(mac: EthernetAddress) -> Self {
+        Self {
+            mac,
+            socket: None,
+            addresses: Vec::new(),
+            router: None,
+            solicits: 0,
+            next_solicit: Instant::ZERO,
+        }
+    }
+
+    /// Start or stop autoconfiguration. Stopping drops the learnt
+    /// configuration.
+    pub fn set_enabled(
+        &mut self,
+        iface: &mut Interface,
+        set: &mut SocketSet<'static>,
+        on: bool,
+        now: Instant,
+    ) {
+        match (self.socket, on) {
+            (None, true) => {
+                let buffer = || {
+                    raw::PacketBuffer::new(
+                        vec![raw::PacketMetadata::EMPTY; RAW_PACKETS],
+                        vec![0; RAW_BUF],
+                    )
+                };
+                let socket =
+                    raw::Socket::new(IpVersion::Ipv6, IpProtocol::Icmpv6, buffer(), buffer());
+                self.socket = Some(set.add(socket));
+                self.solicits = 0;
+                self.next_solicit = now;
+            }
+            (Some(h), false) => {
+                set.remove(h);
+                self.socket = None;
+                self.release(iface);
+            }
+            _ => {}
+        }
+    }
+
+    /// Remove the learnt addresses and default route, leaving entries alone
+    /// that have been changed in the meantime.
+    fn release(&mut self, iface: &mut Interface) {
+        for (cidr, _) in self.addresses.drain(..) {
+            let _ = remove_address(iface, IpCidr::Ipv6(cidr));
+        }
+        if let Some((router, _)) = self.router.take() {
+            if routed_via(iface, default_route_v6(), IpAddress::Ipv6(router)) {
+                let _ = remove_route(iface, default_route_v6());
+            }
+        }
+    }
+
+    /// Apply received advertisements, expire stale entries and send router
+    /// solicitations. Must be called after every `Interface::poll`.
+    pub fn update(&mut self, iface: &mut Interface, set: &mut SocketSet<'static>, now: Instant) {
+        let Some(h) = self.socket else {
+            return;
+        };
+        let socket = set.get_mut::<raw::Socket>(h);
+        let mut adverts = Vec::new();
+        while let Ok(packet) = socket.recv() {
+            adverts.extend(parse_advert(packet));
+        }
+        for advert in adverts {
+            self.solicits = MAX_SOLICITS;
+            if let Some(prefix) = advert.prefix {
+                self.prefix(iface, &prefix, now);
+            }
+            self.default_router(iface, advert.router, advert.lifetime, now);
+        }
+
+        self.addresses.retain(|&(cidr, expires)| {
+            if expires > now {
+                return true;
+            }
+            println!("net_server: SLAAC address {cidr} expired");
+            let _ = remove_address(iface, IpCidr::Ipv6(cidr));
+            false
+        });
+        if let Some((router, expires)) = self.router {
+            if expires <= now {
+                self.default_router(iface, router, Duration::ZERO, now);
+            }
+        }
+
+        if self.solicits < MAX_SOLICITS && now >= self.next_solicit {
+            if self.solicit(iface, set.get_mut::<raw::Socket>(h)) {
+                self.solicits += 1;
+            }
+            self.next_solicit = now + SOLICIT_INTERVAL;
+        }
+    }
+
+    /// Handle the prefix information of an advertisement.
+    fn prefix(&mut self, iface: &mut Interface, info: &NdiscPrefixInformation, now: Instant) {
+        if !info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+            || info.prefix_len != 64
+            || is_link_local(info.prefix)
+            || info.preferred_lifetime > info.valid_lifetime
+        {
+            return;
+        }
+        let cidr = Ipv6Cidr::new(with_interface_id(info.prefix, self.mac), 64);
+        if let Some((_, expires)) = self.addresses.iter_mut().find(|(c, _)| *c == cidr) {
+            // Only shorten the lifetime down to two hours, so forged
+            // advertisements cannot take the address away (RFC 4862,
+            // section 5.5.3 e).
+            let remaining = *expires - now;
+            if info.valid_lifetime > MIN_VALID_LIFETIME || info.valid_lifetime > remaining {
+                *expires = now + info.valid_lifetime;
+            } else if remaining > MIN_VALID_LIFETIME {
+                *expires = now + MIN_VALID_LIFETIME;
+            }
+            return;
+        }
+        if info.valid_lifetime == Duration::ZERO {
+            return;
+        }
+        match add_address(iface, IpCidr::Ipv6(cidr)) {
+            Ok(()) => {
+                println!("net_server: SLAAC address {cidr}");
+                self.addresses.push((cidr, now + info.valid_lifetime));
+            }
+            // Statically configured already; not ours to remove later.
+            Err(EEXIST) => {}
+            Err(err) => println!("net_server: cannot add SLAAC address {cidr}: errno {err}"),
+        }
+    }
+
+    /// Install `router` as default router for `lifetime`, or remove it if
+    /// the lifetime is zero.
+    fn default_router(
+        &mut self,
+        iface: &mut Interface,
+        router: Ipv6Address,
+        lifetime: Duration,
+        now: Instant,
+    ) {
+        let ours = self.router.is_some_and(|(r, _)| r == router);
+        if lifetime == Duration::ZERO {
+            if ours {
+                self.router = None;
+                if routed_via(iface, default_route_v6(), IpAddress::Ipv6(router)) {
+                    let _ = remove_route(iface, default_route_v6());
+                }
+            }
+            return;
+        }
+        if !ours && routes(iface).iter().any(|(c, _)| *c == default_route_v6()) {
+            // Keep a static default route or the router learnt first.
+            return;
+        }
+        match add_route(iface, default_route_v6(), IpAddress::Ipv6(router)) {
+            Ok(()) => {
+                if !ours {
+                    println!("net_server: IPv6 default router {router}");
+                }
+                self.router = Some((router, now + lifetime));
+            }
+            Err(err) => println!("net_server: cannot add IPv6 router {router}: errno {err}"),
+        }
+    }
+
+    /// Queue a router solicitation. Returns whether it could be sent.
+    fn solicit(&self, iface: &Interface, socket: &mut raw::Socket) -> bool {
+        // The link-layer address option must not be sent from the
+        // unspecified address.
+        let src = iface.ip_addrs().iter().find_map(|cidr| match cidr {
+            IpCidr::Ipv6(c) if is_link_local(c.address()) => Some(c.address()),
+            _ => None,
+        });
+        let lladdr = src.map(|_| HardwareAddress::Ethernet(self.mac).into());
+        let src = src.unwrap_or(Ipv6Address::UNSPECIFIED);
+        let dst = IPV6_LINK_LOCAL_ALL_ROUTERS;
+        let icmp = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr });
+        let ip = Ipv6Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 255,
+        };
+        let Ok(buf) = socket.send(IPV6_HEADER_LEN + icmp.buffer_len()) else {
+            return false;
+        };
+        let mut packet = Ipv6Packet::new_unchecked(&mut buf[..]);
+        ip.emit(&mut packet);
+        icmp.emit(
+            &src,
+            &dst,
+            &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+            &ChecksumCapabilities::default(),
+        );
+        true
+    }
+
+    /// When `update` has to run next regardless of network traffic.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.socket?;
+        let solicit = (self.solicits < MAX_SOLICITS).then_some(self.next_solicit);
+        solicit
+            .into_iter()
+            .chain(self.addresses.iter().map(|&(_, expires)| expires))
+            .chain(self.router.map(|(_, expires)| expires))
+            .min()
+    }
+}
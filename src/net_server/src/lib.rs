@@ -0,0 +1,20 @@
+//! The network stack of net_server, independent of L4Re.
+//!
+//! The server binary serves [`proto`] over L4 IPC on top of a [`Stack`].
+//! Everything here also builds on the host, where tests run stacks back to
+//! back over the in-memory devices of [`device`].
+
+pub mod capture;
+pub mod config;
+pub mod device;
+pub mod firewall;
+pub mod ifconfig;
+pub mod loopback;
+mod packet;
+pub mod proto;
+pub mod socket;
+pub mod stack;
+
+pub use config::NetConfig;
+pub use socket::Sockets;
+pub use stack::Stack;
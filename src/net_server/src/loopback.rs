@@ -0,0 +1,137 @@
+//! Loopback traffic on a network device.
+//!
+//! The interface always owns 127.0.0.1/8 and, with IPv6, ::1. On the
+//! loopback device that is all there is; on an Ethernet device smoltcp
+//! would hand packets from and to these addresses to the wire. [`Looping`]
+//! catches such frames, including address resolution for the loopback
+//! addresses, on their way out and feeds them back to the interface, which
+//! then answers its own ARP requests and neighbor solicitations. Looped
+//! frames never reach the device, the firewall or captures.
+
+use crate::packet::Headers;
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::IpAddress;
+use std::collections::VecDeque;
+
+/// Frames waiting to be looped back. Further frames are dropped, like on a
+/// full device queue.
+pub const LOOP_FRAMES: usize = 64;
+
+/// Frames sent to the loopback addresses, waiting to be received.
+pub type LoopQueue = VecDeque<Vec<u8>>;
+
+/// Whether `addr` is one of the loopback addresses.
+pub fn is_loopback(addr: IpAddress) -> bool {
+    match addr {
+        IpAddress::Ipv4(addr) => addr.is_loopback(),
+        IpAddress::Ipv6(addr) => addr.is_loopback(),
+    }
+}
+
+/// Whether a frame sent on `medium` belongs to the loopback addresses.
+fn loops_back(medium: Medium, frame: &[u8]) -> bool {
+    if medium != Medium::Ethernet {
+        return false;
+    }
+    let headers = Headers::parse(medium, frame);
+    headers.src.is_some_and(is_loopback) || headers.dst.is_some_and(is_loopback)
+}
+
+/// A device passing the frames of another device, except those of the
+/// loopback addresses, which go through `queue` instead.
+pub struct Looping<'a, D: Device + ?Sized> {
+    inner: &'a mut D,
+    queue: &'a mut LoopQueue,
+    medium: Medium,
+}
+
+impl<'a, D: Device + ?Sized> Looping<'a, D> {
+    pub fn new(inner: &'a mut D, queue: &'a mut LoopQueue) -> Self {
+        let medium = inner.capabilities().medium;
+        Self {
+            inner,
+            queue,
+            medium,
+        }
+    }
+}
+
+pub struct LoopRxToken {
+    frame: Vec<u8>,
+}
+
+pub struct LoopTxToken<'b, T> {
+    /// Missing if the device cannot transmit the reply to a looped frame
+    /// right now; frames for it are then dropped.
+    inner: Option<T>,
+    queue: &'b mut LoopQueue,
+    medium: Medium,
+}
+
+impl<D: Device + ?Sized> Device for Looping<'_, D> {
+    type RxToken<'b>
+        = LoopRxToken
+    where
+        Self: 'b;
+    type TxToken<'b>
+        = LoopTxToken<'b, D::TxToken<'b>>
+    where
+        Self: 'b;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        // Looped frames go first, they are already in memory.
+        let (frame, inner) = match self.queue.pop_front() {
+            Some(frame) => (frame, self.inner.transmit(timestamp)),
+            None => {
+                let (rx, tx) = self.inner.receive(timestamp)?;
+                (rx.consume(|frame| frame.to_vec()), Some(tx))
+            }
+        };
+        let tx = LoopTxToken {
+            inner,
+            queue: self.queue,
+            medium: self.medium,
+        };
+        Some((LoopRxToken { frame }, tx))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(LoopTxToken {
+            inner: Some(self.inner.transmit(timestamp)?),
+            queue: self.queue,
+            medium: self.medium,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+impl RxToken for LoopRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.frame)
+    }
+}
+
+impl<T: TxToken> TxToken for LoopTxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0u8; len];
+        let res = f(&mut frame);
+        if loops_back(self.medium, &frame) {
+            if self.queue.len() < LOOP_FRAMES {
+                self.queue.push_back(frame);
+            }
+        } else if let Some(inner) = self.inner {
+            inner.consume(len, |buf| buf.copy_from_slice(&frame));
+        }
+        res
+    }
+}
@@ -1,77 +1,488 @@
-//! A basic network server exposing packet operations via L4 IPC.
+//! A network server exposing BSD-style sockets via L4 IPC.
 //!
-//! This server demonstrates how a network service could be structured in
-//! Rust using the L4Re libraries. The implementation is intentionally
-//! minimal and mainly focuses on the IPC setup and loop.
+//! The server runs the [`Stack`] of the `net_server` library on top of a
+//! virtio-net device or, if there is none or `device = loopback` is
+//! configured, on the in-memory loopback device. 127.0.0.1 and ::1 are
+//! reachable on either, see `net_server::loopback`. It serves the socket
+//! protocol described in [`proto`]. Every request is answered immediately;
+//! operations that would block fail with `EAGAIN`.
+//!
+//! The stack runs independently of client traffic: the server waits for
+//! client requests and virtio-net interrupts with a receive timeout derived
+//! from [`Stack::poll_delay`], so timers such as TCP retransmissions, ARP
+//! expiry and keepalives fire on time.
+//!
+//! The interface is dual-stack and configured from [`net_server::config`]
+//! at startup, either statically or through DHCP and IPv6 stateless
+//! autoconfiguration. Clients holding the `net_admin` gate may change
+//! addresses, routes, the DHCP client, autoconfiguration and the firewall
+//! at runtime, capture the frames passing the interface into pcap files and
+//! open raw sockets. The stack answers ICMP echo requests by itself.
+//!
+//! Every client talks to the server through a session gate of its own,
+//! created on request, so the server can tell clients apart and close their
+//! sockets once they are gone.
 
+use core::mem::size_of;
+use fs_client::FsClient;
 use l4re::sys::{l4re_env, l4re_env_get_cap};
-use l4_sys::{l4_ipc_error, l4_msgtag, l4_utcb};
+use l4_sys::{
+    l4_cap_idx_t, l4_default_caps_t, l4_factory_create_gate, l4_ipc_error, l4_irq_unmask,
+    l4_is_invalid_cap, l4_msgtag, l4_msgtag_label, l4_msgtag_t, l4_obj_fpage, l4_sndfpage_add,
+    l4_task_cap_has_child, l4_task_delete_obj, l4_timeout, l4_timeout_from_us, l4_timeout_t,
+    l4_utcb, l4_utcb_br, l4_utcb_mr, l4re_util_cap_alloc, l4re_util_cap_free,
+    L4_cap_fpage_rights,
+};
+use std::cmp::min;
+use std::collections::HashMap;
 
 // smoltcp imports for network stack handling
-use smoltcp::iface::{Config, Interface, SocketSet, SocketStorage};
-use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
-use smoltcp::socket::{udp, TcpSocket, TcpSocketBuffer, UdpSocket, UdpSocketBuffer};
-use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr};
+use smoltcp::phy::{Device, Loopback};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::IpEndpoint;
+
+use net_server::device::{self, DeviceKind};
+use net_server::proto::{self, ADDR_WORDS};
+use net_server::{ifconfig, NetConfig, Sockets, Stack};
+use virtio_net::{GateTransport, VirtioDevice, VirtioNet};
 
-mod virtio;
-use virtio::VirtioNet;
+const BR_WORDS: usize = l4_sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
+const BR_DATA_BYTES: usize = (BR_WORDS - 1) * size_of::<u64>();
 
-// Adapter implementing smoltcp's `Device` trait on top of the simple
-// virtio-net driver.
-struct VirtioDevice<'a> {
-    net: &'a mut VirtioNet,
+/// Label of client requests arriving through the `global_net` gate.
+const GATE_LABEL: u64 = 0b1111_0000;
+/// Label of requests arriving through the `net_admin` gate.
+const ADMIN_LABEL: u64 = 0b1111_0100;
+/// Label of virtio-net interrupts.
+const IRQ_LABEL: u64 = 0b1_0000_0000;
+/// Labels of session gates: this bit plus the serial number of the session
+/// in the bits above the rights, so the label of a closed session is never
+/// reused.
+const SESSION_LABEL: u64 = 1 << 32;
+/// The two least significant label bits carry the rights of the sender's
+/// capability.
+const LABEL_MASK: u64 = !0b11;
+
+/// Monotonic time since server start. std's clock reads the KIP clock on
+/// L4Re.
+struct Clock {
+    start: std::time::Instant,
 }
 
-struct VirtioRxToken<'a> {
-    net: &'a mut VirtioNet,
+impl Clock {
+    fn new() -> Self {
+        Self { start: std::time::Instant::now() }
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_micros(self.start.elapsed().as_micros() as i64)
+    }
 }
 
-struct VirtioTxToken<'a> {
-    net: &'a mut VirtioNet,
+/// How often sessions are checked for clients that went away.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A client's own gate to the server, see [`proto`].
+struct Session {
+    gate: l4_cap_idx_t,
+    /// Opened through the `net_admin` gate.
+    admin: bool,
 }
 
-impl<'a> Device for VirtioDevice<'a> {
-    type RxToken<'b> = VirtioRxToken<'b> where Self: 'b;
-    type TxToken<'b> = VirtioTxToken<'b> where Self: 'b;
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            // Delete the gate rather than just dropping our capability, so
+            // the client's copy stops reaching the server.
+            let task = l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t;
+            let _ = l4_task_delete_obj(task, self.gate);
+            l4re_util_cap_free(self.gate);
+        }
+    }
+}
 
-    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        Some((VirtioRxToken { net: self.net }, VirtioTxToken { net: self.net }))
+/// Open sessions by the label of their gate, which is also the key of their
+/// socket table.
+struct Sessions {
+    open: HashMap<u64, Session>,
+    /// Serial number of the next session, part of its gate label.
+    next: u64,
+    /// When to look for sessions whose client went away.
+    sweep_at: Instant,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        Self { open: HashMap::new(), next: 0, sweep_at: Instant::ZERO }
     }
 
-    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        Some(VirtioTxToken { net: self.net })
+    fn is_admin(&self, client: u64) -> bool {
+        client == ADMIN_LABEL || self.open.get(&client).is_some_and(|s| s.admin)
     }
 
-    fn capabilities(&self) -> DeviceCapabilities {
-        let mut caps = DeviceCapabilities::default();
-        caps.max_transmission_unit = 1514;
-        caps.max_burst_size = Some(1);
-        caps.medium = Medium::Ethernet;
-        caps
+    /// Create a session gate. Returns the capability to map to the client.
+    unsafe fn create(&mut self, admin: bool, now: Instant) -> Result<l4_cap_idx_t, i32> {
+        let label = SESSION_LABEL | (self.next << 2);
+        let gate = l4re_util_cap_alloc();
+        if l4_is_invalid_cap(gate) {
+            return Err(libc::ENOMEM);
+        }
+        let env = &*l4re_env();
+        let tag = l4_factory_create_gate(env.factory, gate, env.main_thread, label);
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            l4re_util_cap_free(gate);
+            return Err(libc::ENOMEM);
+        }
+        if self.open.is_empty() {
+            self.sweep_at = now + SWEEP_INTERVAL;
+        }
+        self.next += 1;
+        self.open.insert(label, Session { gate, admin });
+        Ok(gate)
+    }
+
+    /// Close the session `client` together with its sockets.
+    fn close(&mut self, sockets: &mut Sockets, client: u64) -> Result<(), i32> {
+        self.open.remove(&client).ok_or(libc::EPERM)?;
+        sockets.close_client(client);
+        Ok(())
+    }
+
+    /// Close the sessions whose gate is no longer mapped to any task, once
+    /// the sweep interval passed.
+    unsafe fn sweep(&mut self, sockets: &mut Sockets, now: Instant) {
+        if self.open.is_empty() || now < self.sweep_at {
+            return;
+        }
+        self.sweep_at = now + SWEEP_INTERVAL;
+        let task = l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t;
+        // Checking and deleting gates uses the message registers, which may
+        // hold a request.
+        let saved = (*l4_utcb_mr()).mr;
+        let gone: Vec<u64> = self
+            .open
+            .iter()
+            .filter(|(_, session)| {
+                let tag = l4_task_cap_has_child(task, session.gate);
+                l4_ipc_error(tag, l4_utcb()) == 0 && l4_msgtag_label(tag) == 0
+            })
+            .map(|(&label, _)| label)
+            .collect();
+        for client in gone {
+            let _ = self.close(sockets, client);
+        }
+        (*l4_utcb_mr()).mr = saved;
     }
+
+    /// Time until the next sweep, if there is anything to sweep.
+    fn sweep_delay(&self, now: Instant) -> Option<Duration> {
+        if self.open.is_empty() {
+            return None;
+        }
+        Some(if now < self.sweep_at { self.sweep_at - now } else { Duration::ZERO })
+    }
+}
+
+/// Receive timeout for the next wait: wake up when the stack needs to be
+/// polled again or sessions are due to be checked, or never if there is
+/// nothing pending.
+fn wait_timeout(stack: &mut Stack, sessions: &Sessions, clock: &Clock) -> l4_timeout_t {
+    let now = clock.now();
+    let delay = [stack.poll_delay(now), sessions.sweep_delay(now)].into_iter().flatten().min();
+    let rcv = match delay {
+        Some(delay) => l4_timeout_from_us(min(delay.total_micros(), u32::MAX as u64 - 1) as u32),
+        None => l4_timeout_from_us(u32::MAX),
+    };
+    // Never time out sending the reply.
+    l4_timeout(0, rcv)
 }
 
-impl<'a> RxToken for VirtioRxToken<'a> {
-    fn consume<R, F>(self, f: F) -> R
-    where
-        F: FnOnce(&[u8]) -> R,
-    {
-        let mut buf = [0u8; 1536];
-        let len = self.net.receive_frame(&mut buf).unwrap_or(0);
-        f(&buf[..len])
+/// Devices that have work to do when their interrupt fires, before the
+/// stack is polled.
+trait Interrupt {
+    fn interrupt(&mut self);
+}
+
+impl Interrupt for VirtioDevice<'_, GateTransport> {
+    fn interrupt(&mut self) {
+        self.net.interrupt();
+    }
+}
+
+impl Interrupt for Loopback {
+    fn interrupt(&mut self) {}
+}
+
+fn encode_errno(err: i32) -> u64 {
+    (-(err as i64)) as u64
+}
+
+/// Copy the payload passed in the buffer registers.
+unsafe fn br_payload() -> Vec<u8> {
+    let br = &(*l4_utcb_br()).br;
+    let len = min(br[0] as usize, BR_DATA_BYTES);
+    let mut data = vec![0u8; len];
+    core::ptr::copy_nonoverlapping(br.as_ptr().add(1) as *const u8, data.as_mut_ptr(), len);
+    data
+}
+
+/// Return `data` to the client through the buffer registers.
+unsafe fn br_reply(data: &[u8]) {
+    let br = &mut (*l4_utcb_br()).br;
+    let len = min(data.len(), BR_DATA_BYTES);
+    br[0] = len as u64;
+    core::ptr::copy_nonoverlapping(data.as_ptr(), br.as_mut_ptr().add(1) as *mut u8, len);
+}
+
+fn put_endpoint(mr: &mut [u64], ep: IpEndpoint) {
+    mr[..ADDR_WORDS].copy_from_slice(&proto::encode_endpoint(ep));
+}
+
+/// Contents of the file at `path` read through fs_server, or `None` if it
+/// does not exist.
+fn read_file(fs: &FsClient, path: &str) -> Option<String> {
+    // Opening creates missing files, so check first.
+    fs.stat(path).ok()?;
+    let handle = fs.open(path).ok()?;
+    let mut data = Vec::new();
+    let mut buf = vec![0u8; fs_client::BR_DATA_MAX];
+    while let Ok(n) = fs.read(handle, &mut buf) {
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
     }
+    let _ = fs.close(handle);
+    Some(String::from_utf8_lossy(&data).into_owned())
 }
 
-impl<'a> TxToken for VirtioTxToken<'a> {
-    fn consume<R, F>(self, len: usize, f: F) -> R
-    where
-        F: FnOnce(&mut [u8]) -> R,
-    {
-        let mut buf = vec![0u8; len];
-        let res = f(&mut buf[..]);
-        let _ = self.net.send_frame(&buf[..]);
-        res
+/// Write `data` to the new file `path` through fs_server.
+fn save_file(fs: &FsClient, path: &str, data: &[u8]) -> Result<usize, i32> {
+    // Opening creates the file but cannot truncate an existing one.
+    if fs.stat(path).is_ok() {
+        return Err(libc::EEXIST);
+    }
+    let handle = fs.open(path)?;
+    let res = fs.pwrite(handle, data, 0).and_then(|n| fs.fsync(handle).map(|_| n));
+    let _ = fs.close(handle);
+    res
+}
+
+/// Execute an administrative request, see [`proto`].
+unsafe fn dispatch_admin(
+    stack: &mut Stack,
+    fs: Option<&FsClient>,
+    now: Instant,
+) -> Result<(u64, u32), i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    let iface = &mut stack.iface;
+    match mr[0] {
+        proto::OP_ADDR_LIST => {
+            let addrs = iface.ip_addrs();
+            let cidr = *addrs.get(mr[1] as usize).ok_or(libc::ENOENT)?;
+            let count = addrs.len() as u64;
+            mr[1..1 + ADDR_WORDS].copy_from_slice(&proto::encode_cidr(cidr));
+            Ok((count, 1 + ADDR_WORDS as u32))
+        }
+        proto::OP_ADDR_ADD => {
+            let cidr = proto::decode_cidr(&mr[1..1 + ADDR_WORDS])?;
+            ifconfig::add_address(iface, cidr).map(|_| (0, 1))
+        }
+        proto::OP_ADDR_DEL => {
+            let cidr = proto::decode_cidr(&mr[1..1 + ADDR_WORDS])?;
+            ifconfig::remove_address(iface, cidr).map(|_| (0, 1))
+        }
+        proto::OP_ROUTE_LIST => {
+            let routes = ifconfig::routes(iface);
+            let (cidr, via) = *routes.get(mr[1] as usize).ok_or(libc::ENOENT)?;
+            mr[1..1 + ADDR_WORDS].copy_from_slice(&proto::encode_cidr(cidr));
+            put_endpoint(&mut mr[1 + ADDR_WORDS..], IpEndpoint::new(via, 0));
+            Ok((routes.len() as u64, 1 + 2 * ADDR_WORDS as u32))
+        }
+        proto::OP_ROUTE_ADD => {
+            let cidr = proto::decode_cidr(&mr[1..1 + ADDR_WORDS])?;
+            let via = proto::decode_listen_endpoint(&mr[1 + ADDR_WORDS..1 + 2 * ADDR_WORDS])?;
+            let via = via.addr.ok_or(libc::EINVAL)?;
+            ifconfig::add_route(iface, cidr, via).map(|_| (0, 1))
+        }
+        proto::OP_ROUTE_DEL => {
+            let cidr = proto::decode_cidr(&mr[1..1 + ADDR_WORDS])?;
+            ifconfig::remove_route(iface, cidr).map(|_| (0, 1))
+        }
+        proto::OP_DHCP => stack.set_dhcp(mr[1] != 0).map(|_| (0, 1)),
+        proto::OP_SLAAC => stack.set_slaac(mr[1] != 0, now).map(|_| (0, 1)),
+        proto::OP_CAPTURE_START => {
+            let filter = String::from_utf8(br_payload()).map_err(|_| libc::EINVAL)?;
+            let filter = filter.parse().map_err(|_| libc::EINVAL)?;
+            stack.capture.start(mr[1] as usize, filter);
+            Ok((0, 1))
+        }
+        proto::OP_CAPTURE_STOP => {
+            stack.capture.stop();
+            mr[1] = stack.capture.frames() as u64;
+            mr[2] = stack.capture.lost();
+            Ok((0, 3))
+        }
+        proto::OP_CAPTURE_READ => {
+            let format = proto::decode_capture_format(mr[1])?;
+            let (size, data) = stack.capture.read(format, mr[2] as usize);
+            br_reply(data);
+            Ok((size as u64, 1))
+        }
+        proto::OP_CAPTURE_SAVE => {
+            let format = proto::decode_capture_format(mr[1])?;
+            let path = String::from_utf8(br_payload()).map_err(|_| libc::EINVAL)?;
+            let fs = fs.ok_or(libc::ENOSYS)?;
+            // The server stands still while the file is written.
+            save_file(fs, &path, &stack.capture.export(format)).map(|n| (n as u64, 1))
+        }
+        proto::OP_FW_LIST => {
+            let rules = stack.firewall.rules();
+            let (rule, counters) = rules.get(mr[1] as usize).ok_or(libc::ENOENT)?;
+            mr[1] = counters.packets;
+            mr[2] = counters.bytes;
+            br_reply(rule.to_string().as_bytes());
+            Ok((rules.len() as u64, 3))
+        }
+        proto::OP_FW_INSERT => {
+            let rule = String::from_utf8(br_payload()).map_err(|_| libc::EINVAL)?;
+            let rule = rule.parse().map_err(|_| libc::EINVAL)?;
+            stack.firewall.insert(mr[1] as usize, rule);
+            Ok((0, 1))
+        }
+        proto::OP_FW_DELETE => {
+            stack.firewall.remove(mr[1] as usize).ok_or(libc::ENOENT)?;
+            Ok((0, 1))
+        }
+        proto::OP_FW_POLICY => {
+            if mr[1] != u64::MAX {
+                stack.firewall.set_policy(proto::decode_action(mr[1])?);
+            }
+            let (action, counters) = stack.firewall.policy();
+            mr[1] = proto::encode_action(action);
+            mr[2] = counters.packets;
+            mr[3] = counters.bytes;
+            Ok((0, 4))
+        }
+        _ => Err(libc::ENOSYS),
+    }
+}
+
+/// Open a session for a request through `global_net` or `net_admin`, or
+/// close the session whose gate the request came through. Returns the tag
+/// of the reply; opening maps the session gate.
+unsafe fn dispatch_session(
+    sessions: &mut Sessions,
+    sockets: &mut Sockets,
+    client: u64,
+    now: Instant,
+) -> Result<l4_msgtag_t, i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    if mr[0] == proto::OP_SESSION_CLOSE {
+        sessions.close(sockets, client)?;
+        mr[0] = 0;
+        return Ok(l4_msgtag(0, 1, 0, 0));
+    }
+    let admin = match client {
+        GATE_LABEL => false,
+        ADMIN_LABEL => true,
+        _ => return Err(libc::EINVAL),
+    };
+    let gate = sessions.create(admin, now)?;
+    mr[0] = 0;
+    let mut tag = l4_msgtag(0, 1, 0, 0);
+    let rights = L4_cap_fpage_rights::L4_CAP_FPAGE_RWS as u8;
+    l4_sndfpage_add(l4_obj_fpage(gate, 0, rights), 0, &mut tag);
+    Ok(tag)
+}
+
+/// Decode and execute the request in the UTCB. Returns the value for `MR0`
+/// of the reply and the number of message registers to send back.
+unsafe fn dispatch(
+    stack: &mut Stack,
+    sessions: &Sessions,
+    fs: Option<&FsClient>,
+    client: u64,
+    now: Instant,
+) -> Result<(u64, u32), i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    if mr[0] >= proto::OP_ADMIN_FIRST {
+        if !sessions.is_admin(client) {
+            return Err(libc::EPERM);
+        }
+        return dispatch_admin(stack, fs, now);
+    }
+    // Sockets belong to sessions only, see `proto`.
+    if !sessions.open.contains_key(&client) {
+        return Err(libc::EPERM);
+    }
+    let sockets = &mut stack.sockets;
+    let fd = mr[1];
+    match mr[0] {
+        proto::OP_SOCKET => {
+            // Raw sockets see all traffic of their protocol.
+            if mr[2] as i32 == libc::SOCK_RAW && !sessions.is_admin(client) {
+                return Err(libc::EPERM);
+            }
+            let fd = sockets.socket(client, mr[1] as i32, mr[2] as i32, mr[3] as i32)?;
+            Ok((fd, 1))
+        }
+        proto::OP_SEND => {
+            let n = sockets.send(client, fd, &br_payload())?;
+            Ok((n as u64, 1))
+        }
+        proto::OP_RECV | proto::OP_RECVFROM => {
+            let mut buf = vec![0u8; min(mr[2] as usize, BR_DATA_BYTES)];
+            let (n, src) = sockets.recvfrom(client, fd, &mut buf, mr[3] as i32)?;
+            br_reply(&buf[..n]);
+            if mr[0] == proto::OP_RECV {
+                return Ok((n as u64, 1));
+            }
+            put_endpoint(&mut mr[1..], src);
+            Ok((n as u64, 1 + ADDR_WORDS as u32))
+        }
+        proto::OP_CLOSE => sockets.close(client, fd).map(|_| (0, 1)),
+        proto::OP_BIND => {
+            let local = proto::decode_listen_endpoint(&mr[2..2 + ADDR_WORDS])?;
+            sockets.bind(client, fd, local).map(|_| (0, 1))
+        }
+        proto::OP_LISTEN => sockets.listen(client, fd, mr[2] as usize).map(|_| (0, 1)),
+        proto::OP_ACCEPT => {
+            let (new, peer) = sockets.accept(client, fd)?;
+            put_endpoint(&mut mr[1..], peer);
+            Ok((new, 1 + ADDR_WORDS as u32))
+        }
+        proto::OP_CONNECT => {
+            let remote = proto::decode_endpoint(&mr[2..2 + ADDR_WORDS])?;
+            sockets.connect(&mut stack.iface, client, fd, remote).map(|_| (0, 1))
+        }
+        proto::OP_SHUTDOWN => sockets.shutdown(client, fd, mr[2] as i32).map(|_| (0, 1)),
+        proto::OP_SENDTO => {
+            let dest = proto::decode_endpoint(&mr[3..3 + ADDR_WORDS])?;
+            let n = sockets.sendto(client, fd, &br_payload(), dest)?;
+            Ok((n as u64, 1))
+        }
+        proto::OP_GETSOCKNAME | proto::OP_GETPEERNAME => {
+            let ep = if mr[0] == proto::OP_GETSOCKNAME {
+                sockets.getsockname(client, fd)?
+            } else {
+                sockets.getpeername(client, fd)?
+            };
+            put_endpoint(&mut mr[1..], ep);
+            Ok((0, 1 + ADDR_WORDS as u32))
+        }
+        proto::OP_SETSOCKOPT => sockets
+            .setsockopt(client, fd, mr[2] as i32, mr[3] as i32, mr[4])
+            .map(|_| (0, 1)),
+        proto::OP_GETSOCKOPT => {
+            mr[1] = sockets.getsockopt(client, fd, mr[2] as i32, mr[3] as i32)?;
+            Ok((0, 2))
+        }
+        proto::OP_POLL => {
+            let events = sockets.poll(client, fd)?;
+            Ok((events as u64, 1))
+        }
+        _ => Err(libc::ENOSYS),
     }
 }
 
@@ -79,119 +490,133 @@ fn main() {
     unsafe { run(); }
 }
 
+/// Bind `cap` to the main thread, receiving its messages with `label`.
+unsafe fn bind(cap: l4_cap_idx_t, label: u64) -> bool {
+    l4_ipc_error(
+        l4::l4_rcv_ep_bind_thread(cap, (*l4re_env()).main_thread, label),
+        l4_utcb(),
+    ) == 0
+}
+
 /// Unsafe portion of the server. Interacts directly with L4 system calls.
 unsafe fn run() {
     // Obtain the IPC gate capability named "global_net" from the environment.
     let gate = l4re_env_get_cap("global_net").expect("IPC gate 'global_net' not provided");
 
     // Bind the gate to our main thread so clients can contact us.
-    let gatelabel = 0b1111_0000u64;
-    if l4_ipc_error(
-        l4::l4_rcv_ep_bind_thread(gate, (*l4re_env()).main_thread, gatelabel),
-        l4_utcb(),
-    ) != 0
-    {
+    if !bind(gate, GATE_LABEL) {
         panic!("failed to bind IPC gate");
     }
 
-    // Initialise the virtio network driver and wrap it for smoltcp.
-    let mut net = unsafe { VirtioNet::new().expect("virtio-net device not available") };
-    let mut device = VirtioDevice { net: &mut net };
-
-    // Configure interface parameters: MAC address, IP and gateway.
-    let mac = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
-    let mut config = Config::new(mac.into());
-    let mut iface = Interface::new(config, &mut device, Instant::from_millis(0));
-    let ip = Ipv4Address::new(10, 0, 2, 15);
-    iface
-        .update_ip_addrs(|addrs| addrs.push(Ipv4Cidr::new(ip, 24)).unwrap());
-    iface
-        .routes_mut()
-        .add_default_ipv4_route(Ipv4Address::new(10, 0, 2, 2))
-        .unwrap();
-
-    // Prepare a small socket set containing one UDP and one TCP socket.
-    let mut sockets_storage: [SocketStorage; 2] = [SocketStorage::EMPTY; 2];
-    let mut sockets = SocketSet::new(&mut sockets_storage[..]);
-
-    let udp_rx_meta = [udp::PacketMetadata::EMPTY; 4];
-    let udp_rx_buf = [0u8; 512];
-    let udp_tx_meta = [udp::PacketMetadata::EMPTY; 4];
-    let udp_tx_buf = [0u8; 512];
-    let udp_socket = UdpSocket::new(
-        UdpSocketBuffer::new(udp_rx_meta, udp_rx_buf),
-        UdpSocketBuffer::new(udp_tx_meta, udp_tx_buf),
-    );
-    let udp_handle = sockets.add(udp_socket);
-
-    let tcp_rx = TcpSocketBuffer::new(vec![0; 1024]);
-    let tcp_tx = TcpSocketBuffer::new(vec![0; 1024]);
-    let tcp_socket = TcpSocket::new(tcp_rx, tcp_tx);
-    let tcp_handle = sockets.add(tcp_socket);
+    // The administrative gate is optional.
+    if let Some(admin) = l4re_env_get_cap("net_admin") {
+        if !bind(admin, ADMIN_LABEL) {
+            panic!("failed to bind admin gate");
+        }
+    }
+
+    // The configuration is read and capture files are saved through
+    // fs_server if it is available.
+    let fs = FsClient::new();
+    let mut cfg = NetConfig::load(|path| fs.as_ref().and_then(|fs| read_file(fs, path)));
+
+    // Initialise the virtio network driver and wrap it for smoltcp. Without
+    // one the server still provides loopback networking.
+    let net = match cfg.device {
+        DeviceKind::Virtio => VirtioNet::new(),
+        DeviceKind::Loopback => None,
+    };
+    let Some(mut net) = net else {
+        if cfg.device == DeviceKind::Virtio {
+            println!("net_server: no virtio-net device, using loopback");
+        }
+        serve(&cfg, &mut device::loopback(), None, fs.as_ref());
+    };
+
+    // The device's own MAC address takes precedence over the configured one.
+    if let Some(mac) = net.mac() {
+        cfg.mac = mac;
+    }
+
+    // Device interrupts arrive as IPC on the main thread as well.
+    let irq = net.irq();
+    if !bind(irq, IRQ_LABEL) {
+        panic!("failed to bind virtio-net IRQ");
+    }
+    let _ = l4_irq_unmask(irq);
+
+    serve(&cfg, &mut VirtioDevice { net: &mut net }, Some(irq), fs.as_ref());
+}
+
+/// Run the stack on `device` and serve requests. `irq` signals received
+/// frames, devices without one only make progress when polled. Captures are
+/// saved through `fs`.
+unsafe fn serve<D: Device + Interrupt>(
+    cfg: &NetConfig,
+    device: &mut D,
+    irq: Option<l4_cap_idx_t>,
+    fs: Option<&FsClient>,
+) -> ! {
+    let clock = Clock::new();
+
+    // Configure interface parameters: MAC address, static addresses and
+    // gateways, or DHCP and SLAAC.
+    let mut stack = Stack::new(cfg, device, clock.now());
 
     println!("network server ready");
 
-    // IPC loop handling basic socket requests. Clients encode the operation in
-    // message register 0. Additional arguments would normally be placed in
-    // further registers or buffers.
+    let mut sessions = Sessions::new();
+
+    // IPC loop handling socket requests and device interrupts. Clients
+    // encode the operation in message register 0, see `proto` for the
+    // register layout. The label of the incoming message selects the client's
+    // session and socket table. A timed out wait only drives the stack and
+    // checks the sessions.
     let mut label = 0u64;
-    let mut tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4::l4_timeout_t { raw: 0 });
+    let timeout = wait_timeout(&mut stack, &sessions, &clock);
+    let mut tag = l4::l4_ipc_wait(l4_utcb(), &mut label, timeout);
     loop {
-        if l4_ipc_error(tag, l4_utcb()) != 0 {
-            tag = l4::l4_ipc_wait(l4_utcb(), &mut label, l4::l4_timeout_t { raw: 0 });
-            continue;
+        let failed = l4_ipc_error(tag, l4_utcb()) != 0;
+        let interrupt = !failed && label & LABEL_MASK == IRQ_LABEL;
+        if let Some(irq) = irq.filter(|_| interrupt) {
+            let _ = l4_irq_unmask(irq);
+            device.interrupt();
         }
 
-        // Drive the network stack.
-        let _ = iface.poll(Instant::from_millis(0), &mut device, &mut sockets);
+        // Drive the network stack so the request sees the latest state.
+        stack.poll(device, clock.now());
+        sessions.sweep(&mut stack.sockets, clock.now());
 
-        match (*l4::l4_utcb_mr()).mr[0] {
-            // Operation 0: send an empty UDP packet to a fixed endpoint.
-            0 => {
-                let mut sock = sockets.get::<UdpSocket>(udp_handle);
-                let endpoint = IpEndpoint::new(IpAddress::v4(10, 0, 2, 2), 80);
-                let _ = sock.send_slice(&[], endpoint);
-                (*l4::l4_utcb_mr()).mr[0] = 0;
-            }
-            // Operation 1: try to receive a UDP packet, returning its length.
-            1 => {
-                let mut sock = sockets.get::<UdpSocket>(udp_handle);
-                let res = sock.recv().map(|(d, _)| d.len()).unwrap_or(0);
-                (*l4::l4_utcb_mr()).mr[0] = res as u64;
-            }
-            // Operation 2: initiate a TCP connection to a fixed endpoint.
-            2 => {
-                let mut sock = sockets.get::<TcpSocket>(tcp_handle);
-                if !sock.is_open() {
-                    let _ = sock.connect(IpEndpoint::new(IpAddress::v4(10, 0, 2, 2), 80), 49500);
-                }
-                (*l4::l4_utcb_mr()).mr[0] = 0;
-            }
-            // Operation 3: send an empty TCP packet if the connection is open.
-            3 => {
-                let mut sock = sockets.get::<TcpSocket>(tcp_handle);
-                let _ = sock.send_slice(b"");
-                (*l4::l4_utcb_mr()).mr[0] = 0;
-            }
-            // Operation 4: receive data from the TCP socket, returning the length.
-            4 => {
-                let mut sock = sockets.get::<TcpSocket>(tcp_handle);
-                let mut buf = [0u8; 512];
-                let res = sock.recv_slice(&mut buf).unwrap_or(0);
-                (*l4::l4_utcb_mr()).mr[0] = res as u64;
-            }
-            // Unsupported operations are indicated with all bits set.
-            _ => {
-                (*l4::l4_utcb_mr()).mr[0] = u64::MAX;
-            }
+        if failed || interrupt {
+            let timeout = wait_timeout(&mut stack, &sessions, &clock);
+            tag = l4::l4_ipc_wait(l4_utcb(), &mut label, timeout);
+            continue;
         }
 
+        let client = label & LABEL_MASK;
+        let op = (*l4_utcb_mr()).mr[0];
+        let reply = if op == proto::OP_SESSION || op == proto::OP_SESSION_CLOSE {
+            dispatch_session(&mut sessions, &mut stack.sockets, client, clock.now())
+        } else {
+            dispatch(&mut stack, &sessions, fs, client, clock.now()).map(|(res, words)| {
+                (*l4_utcb_mr()).mr[0] = res;
+                l4_msgtag(0, words, 0, 0)
+            })
+        };
+        let reply = reply.unwrap_or_else(|err| {
+            (*l4_utcb_mr()).mr[0] = encode_errno(err);
+            l4_msgtag(0, 1, 0, 0)
+        });
+
+        // Flush whatever the request queued for transmission.
+        stack.poll(device, clock.now());
+
         // Reply to the client and wait for the next request.
         tag = l4::l4_ipc_reply_and_wait(
             l4_utcb(),
-            l4_msgtag(0, 1, 0, 0),
+            reply,
             &mut label,
-            l4::l4_timeout_t { raw: 0 },
+            wait_timeout(&mut stack, &sessions, &clock),
         );
     }
 }
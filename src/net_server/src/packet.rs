@@ -0,0 +1,130 @@
+//! Header fields of a frame, as examined by the capture filter and the
+//! firewall.
+
+use smoltcp::phy::Medium;
+use smoltcp::wire::{
+    ArpPacket, EthernetFrame, EthernetProtocol, IpAddress, IpProtocol, Ipv4Address, Ipv4Packet,
+    Ipv6Packet, TcpPacket, UdpPacket,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Arp,
+    Ipv4,
+    Ipv6,
+    Other,
+}
+
+/// The headers found in a frame. Malformed headers end the parse, leaving
+/// the fields of the inner layers empty.
+#[derive(Debug)]
+pub(crate) struct Headers {
+    pub kind: Kind,
+    /// IP addresses, or the protocol addresses of an ARP packet.
+    pub src: Option<IpAddress>,
+    pub dst: Option<IpAddress>,
+    /// The transport protocol. Missing for IPv4 fragments other than the
+    /// first, IPv6 extension headers are not followed.
+    pub proto: Option<IpProtocol>,
+    /// TCP or UDP source and destination port.
+    pub ports: Option<(u16, u16)>,
+    /// ICMP or ICMPv6 message type.
+    pub icmp_type: Option<u8>,
+    /// TCP FIN or RST flag.
+    pub tcp_end: bool,
+}
+
+impl Headers {
+    pub fn parse(medium: Medium, frame: &[u8]) -> Self {
+        let mut headers = Headers {
+            kind: Kind::Other,
+            src: None,
+            dst: None,
+            proto: None,
+            ports: None,
+            icmp_type: None,
+            tcp_end: false,
+        };
+        let packet = match medium {
+            Medium::Ethernet => {
+                let Ok(eth) = EthernetFrame::new_checked(frame) else {
+                    return headers;
+                };
+                match eth.ethertype() {
+                    EthernetProtocol::Arp => {
+                        headers.arp(eth.payload());
+                        return headers;
+                    }
+                    EthernetProtocol::Ipv4 | EthernetProtocol::Ipv6 => eth.payload(),
+                    _ => return headers,
+                }
+            }
+            Medium::Ip => frame,
+        };
+        headers.ip(packet);
+        headers
+    }
+
+    fn arp(&mut self, payload: &[u8]) {
+        self.kind = Kind::Arp;
+        let Ok(arp) = ArpPacket::new_checked(payload) else {
+            return;
+        };
+        let ipv4 = |addr: &[u8]| {
+            <[u8; 4]>::try_from(addr)
+                .ok()
+                .map(|a| IpAddress::Ipv4(Ipv4Address::from(a)))
+        };
+        self.src = ipv4(arp.source_protocol_addr());
+        self.dst = ipv4(arp.target_protocol_addr());
+    }
+
+    fn ip(&mut self, packet: &[u8]) {
+        let (next, payload) = match packet.first().map(|b| b >> 4) {
+            Some(4) => {
+                let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+                    return;
+                };
+                self.kind = Kind::Ipv4;
+                self.src = Some(IpAddress::Ipv4(ip.src_addr()));
+                self.dst = Some(IpAddress::Ipv4(ip.dst_addr()));
+                // Only the first fragment carries the transport header.
+                if ip.frag_offset() != 0 {
+                    return;
+                }
+                (ip.next_header(), ip.payload())
+            }
+            Some(6) => {
+                let Ok(ip) = Ipv6Packet::new_checked(packet) else {
+                    return;
+                };
+                self.kind = Kind::Ipv6;
+                self.src = Some(IpAddress::Ipv6(ip.src_addr()));
+                self.dst = Some(IpAddress::Ipv6(ip.dst_addr()));
+                (ip.next_header(), ip.payload())
+            }
+            _ => return,
+        };
+        self.proto = Some(next);
+        match next {
+            IpProtocol::Icmp | IpProtocol::Icmpv6 => self.icmp_type = payload.first().copied(),
+            IpProtocol::Tcp => {
+                if let Ok(tcp) = TcpPacket::new_checked(payload) {
+                    self.ports = Some((tcp.src_port(), tcp.dst_port()));
+                    self.tcp_end = tcp.fin() || tcp.rst();
+                }
+            }
+            IpProtocol::Udp => {
+                if let Ok(udp) = UdpPacket::new_checked(payload) {
+                    self.ports = Some((udp.src_port(), udp.dst_port()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the frame carries ICMP or ICMPv6.
+    pub fn is_icmp(&self) -> bool {
+        matches!(self.proto, Some(IpProtocol::Icmp | IpProtocol::Icmpv6))
+    }
+}
@@ -1,23 +1,286 @@
-//! Message register layout for the simple network protocol.
+//! Message register layout for the network protocol.
 //!
 //! The network service communicates via L4 IPC message registers as
 //! follows:
 //!
 //! ```text
 //! MR0: operation
-//!     0 = open
-//!     1 = send
-//!     2 = recv
-//!     3 = close
-//! MR1: socket handle (except for open)
-//! MR2: length / buffer size (send/recv)
-//! MR3..: payload data
+//!      0 = socket        MR1: domain, MR2: type, MR3: protocol
+//!                        Reply: MR0 = socket handle
+//!      1 = send          MR1: handle, MR2: flags, BRs: payload
+//!                        Reply: MR0 = bytes sent
+//!      2 = recv          MR1: handle, MR2: capacity, MR3: flags
+//!                        Reply: MR0 = bytes received, BRs: payload
+//!      3 = close         MR1: handle
+//!      4 = bind          MR1: handle, MR2..MR4: address
+//!      5 = listen        MR1: handle, MR2: backlog
+//!      6 = accept        MR1: handle
+//!                        Reply: MR0 = new handle, MR1..MR3: peer address
+//!      7 = connect       MR1: handle, MR2..MR4: address
+//!      8 = shutdown      MR1: handle, MR2: how
+//!      9 = sendto        MR1: handle, MR2: flags, MR3..MR5: address,
+//!                        BRs: payload
+//!                        Reply: MR0 = bytes sent
+//!     10 = recvfrom      MR1: handle, MR2: capacity, MR3: flags
+//!                        Reply: MR0 = bytes received, MR1..MR3: source
+//!                        address, BRs: payload
+//!     11 = getsockname   MR1: handle     Reply: MR1..MR3: local address
+//!     12 = getpeername   MR1: handle     Reply: MR1..MR3: peer address
+//!     13 = setsockopt    MR1: handle, MR2: level, MR3: option, MR4: value
+//!     14 = getsockopt    MR1: handle, MR2: level, MR3: option
+//!                        Reply: MR1 = value
+//!     15 = poll          MR1: handle
+//!                        Reply: MR0 = ready events (POLLIN, POLLOUT, ...)
+//!     16 = session       Reply: maps the session gate
+//!     17 = session_close
 //! ```
 //!
-//! Replies reuse the same layout with `MR0` carrying either a new socket
-//! handle (for open) or a status/length field for other operations.
+//! The protocol of a socket is 0 for the default of its type,
+//! `IPPROTO_ICMP` or `IPPROTO_ICMPV6` for ping sockets of type `SOCK_DGRAM`,
+//! or the IP protocol of a `SOCK_RAW` socket; see [`crate::socket`]. Only
+//! sessions opened through the `net_admin` gate may create raw sockets,
+//! others get `-EPERM`.
+//!
+//! Administrative operations change the interface configuration. They are
+//! only accepted through the `net_admin` gate and its sessions; other
+//! clients get `-EPERM`.
+//!
+//! ```text
+//!     32 = addr_list     MR1: index
+//!                        Reply: MR0 = number of addresses, MR1..MR3: address
+//!     33 = addr_add      MR1..MR3: address
+//!     34 = addr_del      MR1..MR3: address
+//!     35 = route_list    MR1: index
+//!                        Reply: MR0 = number of routes, MR1..MR3: destination,
+//!                        MR4..MR6: gateway
+//!     36 = route_add     MR1..MR3: destination, MR4..MR6: gateway
+//!     37 = route_del     MR1..MR3: destination
+//!     38 = dhcp          MR1: 1 = start, 0 = stop the DHCP client
+//!     39 = slaac         MR1: 1 = start, 0 = stop IPv6 autoconfiguration
+//!     40 = capture_start MR1: buffer size in bytes, 0 = default,
+//!                        BRs: filter expression, empty = all frames
+//!     41 = capture_stop  Reply: MR1 = frames buffered, MR2 = frames lost
+//!     42 = capture_read  MR1: format, MR2: offset
+//!                        Reply: MR0 = size of the capture file,
+//!                        BRs: data from offset
+//!     43 = capture_save  MR1: format, BRs: path
+//!                        Reply: MR0 = bytes written
+//!     44 = fw_list       MR1: index
+//!                        Reply: MR0 = number of rules, MR1 = packets,
+//!                        MR2 = bytes, BRs: rule
+//!     45 = fw_insert     MR1: position, BRs: rule
+//!     46 = fw_delete     MR1: index
+//!     47 = fw_policy     MR1: action, -1 = unchanged
+//!                        Reply: MR1 = action, MR2 = packets, MR3 = bytes
+//! ```
+//!
+//! Listing past the last entry fails with `-ENOENT`. Starting DHCP or SLAAC
+//! on the loopback device fails with `-EOPNOTSUPP`. Interface addresses and
+//! route destinations are encoded like socket addresses with the prefix
+//! length in place of the port.
+//!
+//! Packet captures are described in [`crate::capture`]. The format is
+//! [`CAPTURE_PCAP`] or [`CAPTURE_PCAPNG`]. `capture_read` at offset 0 takes
+//! a snapshot of the buffer which later offsets continue to read, so a
+//! client fetches a capture file in pieces. `capture_save` writes the file
+//! through fs_server; it fails with `-EEXIST` if the file exists and with
+//! `-ENOSYS` without fs_server.
+//!
+//! Firewall rules travel as text, see [`crate::firewall`]; invalid rules
+//! fail with `-EINVAL`. `fw_insert` appends when the position is past the
+//! last rule. The policy is [`FW_ACCEPT`], [`FW_DROP`] or [`FW_REJECT`], its
+//! counters count the packets no rule matched.
+//!
+//! `MR0` of a reply is the result of the operation: 0 or a length/handle on
+//! success, `-errno` on failure. Domains, socket types, flags, levels and
+//! option names use the libc constants (`AF_INET`, `SOCK_STREAM`,
+//! `MSG_PEEK`, `SOL_SOCKET`, ...).
+//!
+//! Payloads travel through the buffer registers: `BR0` holds the length in
+//! bytes and the data follows from `BR1` onwards.
+//!
+//! An address occupies three consecutive registers:
+//!
+//! ```text
+//! word 0: family << 16 | port
+//! word 1: address bytes 0..8 (network order)
+//! word 2: address bytes 8..16 (network order)
+//! ```
+//!
+//! IPv4 addresses use the first four address bytes. Sockets created with
+//! `AF_INET6` are dual-stack: they accept IPv4 addresses as well and report
+//! IPv4 peers with family `AF_INET`.
+//!
+//! Sockets never block. Operations that would have to wait return
+//! `-EAGAIN` (and `connect` on a stream socket `-EINPROGRESS`), the client
+//! retries later. A `recv` with capacity 0 on a connecting stream socket
+//! tells how the handshake went: it returns 0 once the connection is
+//! established, `-EAGAIN` while it is in progress and `-ENOTCONN` if it
+//! failed.
+//!
+//! Socket operations are only accepted through a session gate, requests
+//! through `global_net` itself fail with `-EPERM`. A client first sends
+//! `session` to `global_net` (or `net_admin`) and receives a gate of its
+//! own, which the server tells apart from all other clients' gates. Socket
+//! handles are private to a session: every session owns its own table of
+//! sockets. `session_close`, sent through the session gate, closes all of
+//! its sockets and deletes the gate. Sessions whose gate no longer reaches
+//! any client, because the client exited, are closed the same way.
+
+use crate::capture::Format;
+use crate::firewall::Action;
+use smoltcp::wire::{
+    IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr,
+};
 
-pub const OP_OPEN: u64 = 0;
+pub const OP_SOCKET: u64 = 0;
 pub const OP_SEND: u64 = 1;
 pub const OP_RECV: u64 = 2;
 pub const OP_CLOSE: u64 = 3;
+pub const OP_BIND: u64 = 4;
+pub const OP_LISTEN: u64 = 5;
+pub const OP_ACCEPT: u64 = 6;
+pub const OP_CONNECT: u64 = 7;
+pub const OP_SHUTDOWN: u64 = 8;
+pub const OP_SENDTO: u64 = 9;
+pub const OP_RECVFROM: u64 = 10;
+pub const OP_GETSOCKNAME: u64 = 11;
+pub const OP_GETPEERNAME: u64 = 12;
+pub const OP_SETSOCKOPT: u64 = 13;
+pub const OP_GETSOCKOPT: u64 = 14;
+pub const OP_POLL: u64 = 15;
+pub const OP_SESSION: u64 = 16;
+pub const OP_SESSION_CLOSE: u64 = 17;
+
+pub const OP_ADDR_LIST: u64 = 32;
+pub const OP_ADDR_ADD: u64 = 33;
+pub const OP_ADDR_DEL: u64 = 34;
+pub const OP_ROUTE_LIST: u64 = 35;
+pub const OP_ROUTE_ADD: u64 = 36;
+pub const OP_ROUTE_DEL: u64 = 37;
+pub const OP_DHCP: u64 = 38;
+pub const OP_SLAAC: u64 = 39;
+pub const OP_CAPTURE_START: u64 = 40;
+pub const OP_CAPTURE_STOP: u64 = 41;
+pub const OP_CAPTURE_READ: u64 = 42;
+pub const OP_CAPTURE_SAVE: u64 = 43;
+pub const OP_FW_LIST: u64 = 44;
+pub const OP_FW_INSERT: u64 = 45;
+pub const OP_FW_DELETE: u64 = 46;
+pub const OP_FW_POLICY: u64 = 47;
+
+/// First administrative operation code.
+pub const OP_ADMIN_FIRST: u64 = OP_ADDR_LIST;
+
+/// Capture file formats.
+pub const CAPTURE_PCAP: u64 = 0;
+pub const CAPTURE_PCAPNG: u64 = 1;
+
+/// Firewall actions.
+pub const FW_ACCEPT: u64 = 0;
+pub const FW_DROP: u64 = 1;
+pub const FW_REJECT: u64 = 2;
+
+/// Number of message registers taken by an encoded address.
+pub const ADDR_WORDS: usize = 3;
+
+/// Encode `ep` into its three-register representation.
+pub fn encode_endpoint(ep: IpEndpoint) -> [u64; ADDR_WORDS] {
+    let mut bytes = [0u8; 16];
+    let family = match ep.addr {
+        IpAddress::Ipv4(a) => {
+            bytes[..4].copy_from_slice(&a.octets());
+            libc::AF_INET
+        }
+        IpAddress::Ipv6(a) => {
+            bytes.copy_from_slice(&a.octets());
+            libc::AF_INET6
+        }
+    };
+    [
+        (family as u64) << 16 | ep.port as u64,
+        u64::from_ne_bytes(bytes[..8].try_into().unwrap()),
+        u64::from_ne_bytes(bytes[8..].try_into().unwrap()),
+    ]
+}
+
+/// Decode an address as passed to `bind`. The unspecified address binds to
+/// all local addresses and port 0 asks for an ephemeral port.
+pub fn decode_listen_endpoint(words: &[u64]) -> Result<IpListenEndpoint, i32> {
+    let family = (words[0] >> 16) as i32;
+    let port = words[0] as u16;
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&words[1].to_ne_bytes());
+    bytes[8..].copy_from_slice(&words[2].to_ne_bytes());
+    let addr = match family {
+        libc::AF_INET => IpAddress::Ipv4(Ipv4Address::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        libc::AF_INET6 => IpAddress::Ipv6(Ipv6Address::from(bytes)),
+        _ => return Err(libc::EAFNOSUPPORT),
+    };
+    let addr = if addr.is_unspecified() {
+        None
+    } else {
+        Some(addr)
+    };
+    Ok(IpListenEndpoint { addr, port })
+}
+
+/// Decode a destination address. The address and port must be specified.
+pub fn decode_endpoint(words: &[u64]) -> Result<IpEndpoint, i32> {
+    match decode_listen_endpoint(words)? {
+        IpListenEndpoint {
+            addr: Some(addr),
+            port,
+        } if port != 0 => Ok(IpEndpoint::new(addr, port)),
+        _ => Err(libc::EINVAL),
+    }
+}
+
+/// Encode an interface address or route destination.
+pub fn encode_cidr(cidr: IpCidr) -> [u64; ADDR_WORDS] {
+    encode_endpoint(IpEndpoint::new(cidr.address(), cidr.prefix_len() as u16))
+}
+
+/// Decode an interface address or route destination.
+pub fn decode_cidr(words: &[u64]) -> Result<IpCidr, i32> {
+    let ep = decode_listen_endpoint(words)?;
+    let v6 = (words[0] >> 16) as i32 == libc::AF_INET6;
+    let max = if v6 { 128 } else { 32 };
+    if ep.port > max {
+        return Err(libc::EINVAL);
+    }
+    let prefix = ep.port as u8;
+    Ok(match ep.addr {
+        Some(IpAddress::Ipv4(a)) => IpCidr::Ipv4(Ipv4Cidr::new(a, prefix)),
+        Some(IpAddress::Ipv6(a)) => IpCidr::Ipv6(Ipv6Cidr::new(a, prefix)),
+        None if v6 => IpCidr::Ipv6(Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, prefix)),
+        None => IpCidr::Ipv4(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, prefix)),
+    })
+}
+
+/// Decode a capture file format.
+pub fn decode_capture_format(word: u64) -> Result<Format, i32> {
+    match word {
+        CAPTURE_PCAP => Ok(Format::Pcap),
+        CAPTURE_PCAPNG => Ok(Format::Pcapng),
+        _ => Err(libc::EINVAL),
+    }
+}
+
+pub fn encode_action(action: Action) -> u64 {
+    match action {
+        Action::Accept => FW_ACCEPT,
+        Action::Drop => FW_DROP,
+        Action::Reject => FW_REJECT,
+    }
+}
+
+/// Decode a firewall action.
+pub fn decode_action(word: u64) -> Result<Action, i32> {
+    match word {
+        FW_ACCEPT => Ok(Action::Accept),
+        FW_DROP => Ok(Action::Drop),
+        FW_REJECT => Ok(Action::Reject),
+        _ => Err(libc::EINVAL),
+    }
+}
@@ -0,0 +1,987 @@
+//! BSD-style sockets on top of a smoltcp socket set.
+//!
+//! Every client owns a slab of [`Socket`] entries; the slab index is the
+//! handle the client sees. An entry only gets a smoltcp socket once it is
+//! bound, connected or listening, so options affecting buffer sizes can be
+//! set right after `socket()`.
+//!
+//! smoltcp accepts at most one connection per listening TCP socket. A
+//! listening entry therefore owns `backlog` smoltcp sockets listening on the
+//! same endpoint; `accept` hands out one that completed the handshake and
+//! puts a fresh listener in its place.
+//!
+//! Closed TCP sockets linger in the socket set until smoltcp finished the
+//! connection teardown.
+//!
+//! `AF_INET` sockets only take IPv4 addresses. `AF_INET6` sockets take both
+//! families, much like sockets without `IPV6_V6ONLY` elsewhere, and report
+//! IPv4 peers as plain IPv4 addresses rather than IPv4-mapped ones.
+//!
+//! Ping sockets (`SOCK_DGRAM` with `IPPROTO_ICMP` or `IPPROTO_ICMPV6`) send
+//! and receive ICMP echo messages, header included. The identifier of an
+//! outgoing request is replaced by the socket's, which `bind` takes in place
+//! of the port, and only replies carrying it are received. Checksums are
+//! filled in by the stack.
+//!
+//! Raw sockets (`SOCK_RAW`) exchange whole IP packets of one protocol, the
+//! IP header included in both directions, like `IP_HDRINCL` elsewhere. The
+//! destination is taken from the header. They cannot be bound and only see
+//! the packets of their own IP version. Echo requests are answered by the
+//! stack whether or not a ping or raw socket receives them as well.
+//!
+//! Ping and raw sockets are single-stack: an `AF_INET6` one only takes IPv6
+//! addresses.
+//!
+//! Errors are positive errno values.
+
+use libc::{
+    EADDRINUSE, EAFNOSUPPORT, EAGAIN, EALREADY, EBADF, EDESTADDRREQ, EINPROGRESS, EINVAL, EISCONN,
+    EMFILE, EMSGSIZE, ENETUNREACH, ENOPROTOOPT, ENOTCONN, EOPNOTSUPP, EPIPE, EPROTONOSUPPORT,
+};
+use slab::Slab;
+use smoltcp::iface::{Interface, SocketHandle, SocketSet};
+use smoltcp::socket::{icmp, raw, tcp, udp};
+use smoltcp::wire::{
+    Icmpv4Message, Icmpv6Message, IpAddress, IpEndpoint, IpListenEndpoint, IpProtocol, IpVersion,
+    Ipv4Address, Ipv4Packet, Ipv6Address, Ipv6Packet,
+};
+use std::collections::HashMap;
+
+/// Sockets a single client may have open at the same time.
+pub const MAX_SOCKETS: usize = 64;
+/// Largest backlog accepted by `listen`.
+pub const SOMAXCONN: usize = 16;
+
+const DEFAULT_BUF: usize = 8192;
+const MIN_BUF: usize = 256;
+const MAX_BUF: usize = 256 * 1024;
+/// Average datagram size assumed when sizing packet metadata.
+const META_DIV: usize = 256;
+/// Length of an ICMP echo header.
+const ECHO_HEADER: usize = 8;
+
+const EPHEMERAL_FIRST: u16 = 49152;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SockType {
+    Stream,
+    Dgram,
+    /// ICMP echo ("ping") socket.
+    Ping,
+    Raw,
+}
+
+/// smoltcp state backing an entry.
+enum Inner {
+    /// Created by `socket` but neither bound nor connected.
+    Idle,
+    Udp(SocketHandle),
+    /// Ping socket bound to its identifier.
+    Icmp(SocketHandle),
+    /// Raw sockets get their smoltcp socket right away.
+    Raw(SocketHandle),
+    Tcp(SocketHandle),
+    /// Listening TCP socket with its pool of smoltcp listeners.
+    Listen(Vec<SocketHandle>),
+}
+
+struct Socket {
+    /// `AF_INET` or `AF_INET6`.
+    domain: i32,
+    ty: SockType,
+    inner: Inner,
+    /// Local endpoint assigned by `bind` or chosen automatically.
+    local: Option<IpListenEndpoint>,
+    /// Default destination of a connected datagram socket.
+    peer: Option<IpEndpoint>,
+    shut_rd: bool,
+    shut_wr: bool,
+    reuse_addr: bool,
+    nodelay: bool,
+    rcvbuf: usize,
+    sndbuf: usize,
+}
+
+impl Socket {
+    fn new(domain: i32, ty: SockType) -> Self {
+        Self {
+            domain,
+            ty,
+            inner: Inner::Idle,
+            local: None,
+            peer: None,
+            shut_rd: false,
+            shut_wr: false,
+            reuse_addr: false,
+            nodelay: false,
+            rcvbuf: DEFAULT_BUF,
+            sndbuf: DEFAULT_BUF,
+        }
+    }
+
+    fn is_listening(&self) -> bool {
+        matches!(self.inner, Inner::Listen(_))
+    }
+
+    /// Whether `addr` can be used with this socket.
+    fn check_family(&self, addr: Option<IpAddress>) -> Result<(), i32> {
+        match addr {
+            Some(IpAddress::Ipv6(_)) if self.domain == libc::AF_INET => Err(EAFNOSUPPORT),
+            Some(IpAddress::Ipv4(_))
+                if self.domain == libc::AF_INET6
+                    && matches!(self.ty, SockType::Ping | SockType::Raw) =>
+            {
+                Err(EAFNOSUPPORT)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether two local endpoints would receive the same traffic.
+fn overlaps(a: &IpListenEndpoint, b: &IpListenEndpoint) -> bool {
+    a.port == b.port && (a.addr.is_none() || b.addr.is_none() || a.addr == b.addr)
+}
+
+fn lookup(
+    clients: &mut HashMap<u64, Slab<Socket>>,
+    client: u64,
+    fd: u64,
+) -> Result<&mut Socket, i32> {
+    clients
+        .get_mut(&client)
+        .and_then(|t| t.get_mut(fd as usize))
+        .ok_or(EBADF)
+}
+
+/// The unspecified address of `domain`.
+fn unspecified_addr(domain: i32) -> IpAddress {
+    if domain == libc::AF_INET6 {
+        IpAddress::Ipv6(Ipv6Address::UNSPECIFIED)
+    } else {
+        IpAddress::Ipv4(Ipv4Address::UNSPECIFIED)
+    }
+}
+
+fn unspecified(domain: i32) -> IpEndpoint {
+    IpEndpoint::new(unspecified_addr(domain), 0)
+}
+
+/// Number of packet metadata entries for a buffer of `len` bytes.
+fn meta_count(len: usize) -> usize {
+    (len / META_DIV).max(4)
+}
+
+/// `POLLIN` and `POLLOUT` as requested.
+fn ready(readable: bool, writable: bool) -> u32 {
+    let mut events = 0;
+    if readable {
+        events |= libc::POLLIN as u32;
+    }
+    if writable {
+        events |= libc::POLLOUT as u32;
+    }
+    events
+}
+
+/// Source address in the header of an IP packet.
+fn packet_source(packet: &[u8]) -> Option<IpAddress> {
+    match IpVersion::of_packet(packet).ok()? {
+        IpVersion::Ipv4 => Ipv4Packet::new_checked(packet)
+            .ok()
+            .map(|p| p.src_addr().into()),
+        IpVersion::Ipv6 => Ipv6Packet::new_checked(packet)
+            .ok()
+            .map(|p| p.src_addr().into()),
+    }
+}
+
+/// All sockets of all clients.
+pub struct Sockets {
+    set: SocketSet<'static>,
+    clients: HashMap<u64, Slab<Socket>>,
+    /// Closed TCP sockets still finishing their connection teardown.
+    lingering: Vec<SocketHandle>,
+    next_port: u16,
+}
+
+impl Default for Sockets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sockets {
+    pub fn new() -> Self {
+        Self {
+            set: SocketSet::new(Vec::new()),
+            clients: HashMap::new(),
+            lingering: Vec::new(),
+            next_port: EPHEMERAL_FIRST,
+        }
+    }
+
+    /// The smoltcp socket set, for computing the poll delay.
+    pub fn set(&self) -> &SocketSet<'static> {
+        &self.set
+    }
+
+    /// The smoltcp socket set to be polled by the interface.
+    pub fn set_mut(&mut self) -> &mut SocketSet<'static> {
+        &mut self.set
+    }
+
+    /// Drop lingering sockets whose connection has been torn down.
+    pub fn reap(&mut self) {
+        let set = &mut self.set;
+        self.lingering.retain(|&h| {
+            if set.get::<tcp::Socket>(h).state() == tcp::State::Closed {
+                set.remove(h);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn entry(&mut self, client: u64, fd: u64) -> Result<&mut Socket, i32> {
+        lookup(&mut self.clients, client, fd)
+    }
+
+    fn port_in_use(&self, ty: SockType, local: &IpListenEndpoint, reuse: bool) -> bool {
+        self.clients.values().flat_map(|t| t.iter()).any(|(_, s)| {
+            s.ty == ty
+                && s.local.as_ref().is_some_and(|l| overlaps(l, local))
+                && !(reuse && s.reuse_addr && !s.is_listening())
+        })
+    }
+
+    fn ephemeral_port(&mut self, ty: SockType) -> Result<u16, i32> {
+        for _ in EPHEMERAL_FIRST..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX {
+                EPHEMERAL_FIRST
+            } else {
+                port + 1
+            };
+            if !self.port_in_use(ty, &IpListenEndpoint { addr: None, port }, false) {
+                return Ok(port);
+            }
+        }
+        Err(EADDRINUSE)
+    }
+
+    fn new_tcp(&mut self, rcvbuf: usize, sndbuf: usize, nodelay: bool) -> SocketHandle {
+        let mut sock = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; rcvbuf]),
+            tcp::SocketBuffer::new(vec![0; sndbuf]),
+        );
+        sock.set_nagle_enabled(!nodelay);
+        self.set.add(sock)
+    }
+
+    fn new_udp(&mut self, rcvbuf: usize, sndbuf: usize) -> SocketHandle {
+        let meta = |len| vec![udp::PacketMetadata::EMPTY; meta_count(len)];
+        let sock = udp::Socket::new(
+            udp::PacketBuffer::new(meta(rcvbuf), vec![0; rcvbuf]),
+            udp::PacketBuffer::new(meta(sndbuf), vec![0; sndbuf]),
+        );
+        self.set.add(sock)
+    }
+
+    fn new_icmp(&mut self, rcvbuf: usize, sndbuf: usize) -> SocketHandle {
+        let meta = |len| vec![icmp::PacketMetadata::EMPTY; meta_count(len)];
+        let sock = icmp::Socket::new(
+            icmp::PacketBuffer::new(meta(rcvbuf), vec![0; rcvbuf]),
+            icmp::PacketBuffer::new(meta(sndbuf), vec![0; sndbuf]),
+        );
+        self.set.add(sock)
+    }
+
+    fn new_raw(&mut self, version: IpVersion, protocol: IpProtocol) -> SocketHandle {
+        let buffer = || {
+            raw::PacketBuffer::new(
+                vec![raw::PacketMetadata::EMPTY; meta_count(DEFAULT_BUF)],
+                vec![0; DEFAULT_BUF],
+            )
+        };
+        self.set
+            .add(raw::Socket::new(version, protocol, buffer(), buffer()))
+    }
+
+    /// Make sure an entry has a local endpoint, picking an ephemeral port if
+    /// it was never bound, and return it.
+    fn local_or_ephemeral(&mut self, client: u64, fd: u64) -> Result<IpListenEndpoint, i32> {
+        let sock = self.entry(client, fd)?;
+        if let Some(local) = sock.local {
+            return Ok(local);
+        }
+        let ty = sock.ty;
+        let local = IpListenEndpoint {
+            addr: None,
+            port: self.ephemeral_port(ty)?,
+        };
+        self.entry(client, fd)?.local = Some(local);
+        Ok(local)
+    }
+
+    /// Give an idle datagram entry its smoltcp socket.
+    fn udp_handle(&mut self, client: u64, fd: u64) -> Result<SocketHandle, i32> {
+        let sock = self.entry(client, fd)?;
+        if let Inner::Udp(h) = sock.inner {
+            return Ok(h);
+        }
+        let (rcvbuf, sndbuf) = (sock.rcvbuf, sock.sndbuf);
+        let local = self.local_or_ephemeral(client, fd)?;
+        let h = self.new_udp(rcvbuf, sndbuf);
+        self.set
+            .get_mut::<udp::Socket>(h)
+            .bind(local)
+            .map_err(|_| EINVAL)?;
+        self.entry(client, fd)?.inner = Inner::Udp(h);
+        Ok(h)
+    }
+
+    /// Give an idle ping entry its smoltcp socket, bound to the local port
+    /// as identifier.
+    fn icmp_handle(&mut self, client: u64, fd: u64) -> Result<SocketHandle, i32> {
+        let sock = self.entry(client, fd)?;
+        if let Inner::Icmp(h) = sock.inner {
+            return Ok(h);
+        }
+        let (rcvbuf, sndbuf) = (sock.rcvbuf, sock.sndbuf);
+        let local = self.local_or_ephemeral(client, fd)?;
+        let h = self.new_icmp(rcvbuf, sndbuf);
+        self.set
+            .get_mut::<icmp::Socket>(h)
+            .bind(icmp::Endpoint::Ident(local.port))
+            .map_err(|_| EINVAL)?;
+        self.entry(client, fd)?.inner = Inner::Icmp(h);
+        Ok(h)
+    }
+
+    /// Give a datagram, ping or raw entry its smoltcp socket.
+    fn datagram_handle(&mut self, client: u64, fd: u64) -> Result<SocketHandle, i32> {
+        let sock = self.entry(client, fd)?;
+        match (sock.ty, &sock.inner) {
+            (SockType::Dgram, _) => self.udp_handle(client, fd),
+            (SockType::Ping, _) => self.icmp_handle(client, fd),
+            (SockType::Raw, Inner::Raw(h)) => Ok(*h),
+            _ => Err(EOPNOTSUPP),
+        }
+    }
+
+    /// Create a socket and return its handle. `protocol` 0 picks TCP for
+    /// stream and UDP for datagram sockets; raw sockets need an explicit
+    /// protocol.
+    pub fn socket(&mut self, client: u64, domain: i32, ty: i32, protocol: i32) -> Result<u64, i32> {
+        let v6 = match domain {
+            libc::AF_INET => false,
+            libc::AF_INET6 => true,
+            _ => return Err(EAFNOSUPPORT),
+        };
+        let ty = match (ty, protocol) {
+            (libc::SOCK_STREAM, 0 | libc::IPPROTO_TCP) => SockType::Stream,
+            (libc::SOCK_DGRAM, 0 | libc::IPPROTO_UDP) => SockType::Dgram,
+            (libc::SOCK_DGRAM, libc::IPPROTO_ICMP) if !v6 => SockType::Ping,
+            (libc::SOCK_DGRAM, libc::IPPROTO_ICMPV6) if v6 => SockType::Ping,
+            (libc::SOCK_RAW, 1..=254) => SockType::Raw,
+            _ => return Err(EPROTONOSUPPORT),
+        };
+        let table = self.clients.entry(client).or_default();
+        if table.len() >= MAX_SOCKETS {
+            return Err(EMFILE);
+        }
+        let mut sock = Socket::new(domain, ty);
+        if ty == SockType::Raw {
+            let version = if v6 { IpVersion::Ipv6 } else { IpVersion::Ipv4 };
+            sock.inner = Inner::Raw(self.new_raw(version, IpProtocol::from(protocol as u8)));
+        }
+        let table = self.clients.entry(client).or_default();
+        Ok(table.insert(sock) as u64)
+    }
+
+    pub fn bind(&mut self, client: u64, fd: u64, mut local: IpListenEndpoint) -> Result<(), i32> {
+        let sock = self.entry(client, fd)?;
+        if sock.ty == SockType::Raw {
+            return Err(EOPNOTSUPP);
+        }
+        if sock.local.is_some() || !matches!(sock.inner, Inner::Idle) {
+            return Err(EINVAL);
+        }
+        sock.check_family(local.addr)?;
+        let (ty, reuse) = (sock.ty, sock.reuse_addr);
+        if local.port == 0 {
+            local.port = self.ephemeral_port(ty)?;
+        } else if self.port_in_use(ty, &local, reuse) {
+            return Err(EADDRINUSE);
+        }
+        self.entry(client, fd)?.local = Some(local);
+        if ty != SockType::Stream {
+            self.datagram_handle(client, fd)?;
+        }
+        Ok(())
+    }
+
+    pub fn listen(&mut self, client: u64, fd: u64, backlog: usize) -> Result<(), i32> {
+        let sock = self.entry(client, fd)?;
+        if sock.ty != SockType::Stream {
+            return Err(EOPNOTSUPP);
+        }
+        let backlog = backlog.clamp(1, SOMAXCONN);
+        let (rcvbuf, sndbuf, nodelay) = (sock.rcvbuf, sock.sndbuf, sock.nodelay);
+        let mut pool = match &mut sock.inner {
+            Inner::Idle => Vec::new(),
+            Inner::Listen(pool) => core::mem::take(pool),
+            _ => return Err(EINVAL),
+        };
+        let local = self.local_or_ephemeral(client, fd)?;
+        while pool.len() < backlog {
+            let h = self.new_tcp(rcvbuf, sndbuf, nodelay);
+            self.set
+                .get_mut::<tcp::Socket>(h)
+                .listen(local)
+                .map_err(|_| EINVAL)?;
+            pool.push(h);
+        }
+        self.entry(client, fd)?.inner = Inner::Listen(pool);
+        Ok(())
+    }
+
+    /// Take an established connection from a listening socket. Returns the
+    /// new handle and the address of the peer.
+    pub fn accept(&mut self, client: u64, fd: u64) -> Result<(u64, IpEndpoint), i32> {
+        let sock = self.entry(client, fd)?;
+        let (domain, local) = (sock.domain, sock.local);
+        let (rcvbuf, sndbuf, nodelay) = (sock.rcvbuf, sock.sndbuf, sock.nodelay);
+        let Inner::Listen(pool) = &sock.inner else {
+            return Err(EINVAL);
+        };
+        let pool = pool.clone();
+        let local = local.ok_or(EINVAL)?;
+
+        let mut ready = None;
+        for (i, &h) in pool.iter().enumerate() {
+            let tcp = self.set.get_mut::<tcp::Socket>(h);
+            match tcp.state() {
+                tcp::State::Listen | tcp::State::SynReceived => {}
+                // Handshake aborted by the peer; listen again.
+                tcp::State::Closed => tcp.listen(local).map_err(|_| EINVAL)?,
+                _ => {
+                    ready = Some(i);
+                    break;
+                }
+            }
+        }
+        let i = ready.ok_or(EAGAIN)?;
+        if self.clients[&client].len() >= MAX_SOCKETS {
+            return Err(EMFILE);
+        }
+
+        let conn = pool[i];
+        let fresh = self.new_tcp(rcvbuf, sndbuf, nodelay);
+        self.set
+            .get_mut::<tcp::Socket>(fresh)
+            .listen(local)
+            .map_err(|_| EINVAL)?;
+        if let Inner::Listen(pool) = &mut self.entry(client, fd)?.inner {
+            pool[i] = fresh;
+        }
+
+        let tcp = self.set.get::<tcp::Socket>(conn);
+        let peer = tcp.remote_endpoint().ok_or(ENOTCONN)?;
+        let mut accepted = Socket::new(domain, SockType::Stream);
+        accepted.inner = Inner::Tcp(conn);
+        accepted.local = tcp.local_endpoint().map(IpListenEndpoint::from);
+        accepted.rcvbuf = rcvbuf;
+        accepted.sndbuf = sndbuf;
+        accepted.nodelay = nodelay;
+        let table = self.clients.get_mut(&client).ok_or(EBADF)?;
+        Ok((table.insert(accepted) as u64, peer))
+    }
+
+    /// Connect a socket. Stream sockets start the handshake and report
+    /// `EINPROGRESS`; datagram, ping and raw sockets only record the default
+    /// destination.
+    pub fn connect(
+        &mut self,
+        iface: &mut Interface,
+        client: u64,
+        fd: u64,
+        remote: IpEndpoint,
+    ) -> Result<(), i32> {
+        let sock = self.entry(client, fd)?;
+        sock.check_family(Some(remote.addr))?;
+        if sock.ty != SockType::Stream {
+            sock.peer = Some(remote);
+            self.datagram_handle(client, fd)?;
+            return Ok(());
+        }
+
+        let (rcvbuf, sndbuf, nodelay) = (sock.rcvbuf, sock.sndbuf, sock.nodelay);
+        let h = match sock.inner {
+            Inner::Idle => None,
+            Inner::Tcp(h) => Some(h),
+            _ => return Err(EINVAL),
+        };
+        if let Some(h) = h {
+            match self.set.get::<tcp::Socket>(h).state() {
+                tcp::State::SynSent | tcp::State::SynReceived => return Err(EALREADY),
+                tcp::State::Closed => {}
+                _ => return Err(EISCONN),
+            }
+        }
+        let local = self.local_or_ephemeral(client, fd)?;
+        let h = match h {
+            Some(h) => h,
+            None => {
+                let h = self.new_tcp(rcvbuf, sndbuf, nodelay);
+                self.entry(client, fd)?.inner = Inner::Tcp(h);
+                h
+            }
+        };
+        self.set
+            .get_mut::<tcp::Socket>(h)
+            .connect(iface.context(), remote, local)
+            .map_err(|e| match e {
+                tcp::ConnectError::InvalidState => EISCONN,
+                tcp::ConnectError::Unaddressable => ENETUNREACH,
+            })?;
+        Err(EINPROGRESS)
+    }
+
+    pub fn shutdown(&mut self, client: u64, fd: u64, how: i32) -> Result<(), i32> {
+        let (rd, wr) = match how {
+            libc::SHUT_RD => (true, false),
+            libc::SHUT_WR => (false, true),
+            libc::SHUT_RDWR => (true, true),
+            _ => return Err(EINVAL),
+        };
+        let sock = self.entry(client, fd)?;
+        match sock.inner {
+            Inner::Tcp(h) => {
+                if wr {
+                    self.set.get_mut::<tcp::Socket>(h).close();
+                }
+            }
+            Inner::Udp(_) | Inner::Icmp(_) | Inner::Raw(_) if sock.peer.is_some() => {}
+            _ => return Err(ENOTCONN),
+        }
+        let sock = self.entry(client, fd)?;
+        sock.shut_rd |= rd;
+        sock.shut_wr |= wr;
+        Ok(())
+    }
+
+    pub fn close(&mut self, client: u64, fd: u64) -> Result<(), i32> {
+        let table = self.clients.get_mut(&client).ok_or(EBADF)?;
+        let sock = table.try_remove(fd as usize).ok_or(EBADF)?;
+        match sock.inner {
+            Inner::Idle => {}
+            Inner::Udp(h) | Inner::Icmp(h) | Inner::Raw(h) => {
+                self.set.remove(h);
+            }
+            Inner::Tcp(h) => {
+                self.set.get_mut::<tcp::Socket>(h).close();
+                self.lingering.push(h);
+            }
+            Inner::Listen(pool) => {
+                for h in pool {
+                    self.set.get_mut::<tcp::Socket>(h).abort();
+                    self.lingering.push(h);
+                }
+            }
+        }
+        if table.is_empty() {
+            self.clients.remove(&client);
+        }
+        Ok(())
+    }
+
+    /// Close all sockets of `client`, which went away.
+    pub fn close_client(&mut self, client: u64) {
+        let fds: Vec<usize> = match self.clients.get(&client) {
+            Some(table) => table.iter().map(|(fd, _)| fd).collect(),
+            None => return,
+        };
+        for fd in fds {
+            let _ = self.close(client, fd as u64);
+        }
+    }
+
+    /// Send on a connected socket.
+    pub fn send(&mut self, client: u64, fd: u64, data: &[u8]) -> Result<usize, i32> {
+        let sock = lookup(&mut self.clients, client, fd)?;
+        if sock.shut_wr {
+            return Err(EPIPE);
+        }
+        match (sock.ty, &sock.inner) {
+            (SockType::Stream, Inner::Tcp(h)) => {
+                let tcp = self.set.get_mut::<tcp::Socket>(*h);
+                match tcp.state() {
+                    tcp::State::SynSent | tcp::State::SynReceived => return Err(EAGAIN),
+                    tcp::State::Closed => return Err(ENOTCONN),
+                    _ => {}
+                }
+                match tcp.send_slice(data) {
+                    Ok(0) if !data.is_empty() => Err(EAGAIN),
+                    Ok(n) => Ok(n),
+                    Err(tcp::SendError::InvalidState) => Err(EPIPE),
+                }
+            }
+            (SockType::Stream, _) => Err(ENOTCONN),
+            _ => {
+                let peer = sock.peer.ok_or(EDESTADDRREQ)?;
+                self.sendto(client, fd, data, peer)
+            }
+        }
+    }
+
+    /// Send a datagram to `dest`. Stream sockets ignore the address, raw
+    /// sockets only check its family.
+    pub fn sendto(
+        &mut self,
+        client: u64,
+        fd: u64,
+        data: &[u8],
+        dest: IpEndpoint,
+    ) -> Result<usize, i32> {
+        let sock = self.entry(client, fd)?;
+        if sock.ty == SockType::Stream {
+            return self.send(client, fd, data);
+        }
+        if sock.shut_wr {
+            return Err(EPIPE);
+        }
+        sock.check_family(Some(dest.addr))?;
+        let ty = sock.ty;
+        let h = self.datagram_handle(client, fd)?;
+        match ty {
+            SockType::Ping => {
+                let ident = self.entry(client, fd)?.local.map_or(0, |l| l.port);
+                self.send_echo(h, ident, data, dest.addr)
+            }
+            SockType::Raw => {
+                let socket = self.set.get_mut::<raw::Socket>(h);
+                let valid = match socket.ip_version() {
+                    IpVersion::Ipv4 => Ipv4Packet::new_checked(data)
+                        .is_ok_and(|p| p.next_header() == socket.ip_protocol()),
+                    IpVersion::Ipv6 => Ipv6Packet::new_checked(data)
+                        .is_ok_and(|p| p.next_header() == socket.ip_protocol()),
+                };
+                if !valid {
+                    return Err(EINVAL);
+                }
+                if data.len() > socket.payload_send_capacity() {
+                    return Err(EMSGSIZE);
+                }
+                socket.send_slice(data).map_err(|_| EAGAIN)?;
+                Ok(data.len())
+            }
+            _ => {
+                let udp = self.set.get_mut::<udp::Socket>(h);
+                if data.len() > udp.payload_send_capacity() {
+                    return Err(EMSGSIZE);
+                }
+                udp.send_slice(data, dest).map_err(|e| match e {
+                    udp::SendError::BufferFull => EAGAIN,
+                    udp::SendError::Unaddressable => ENETUNREACH,
+                })?;
+                Ok(data.len())
+            }
+        }
+    }
+
+    /// Queue the echo request in `data` on the ping socket `h`, stamped with
+    /// its identifier.
+    fn send_echo(
+        &mut self,
+        h: SocketHandle,
+        ident: u16,
+        data: &[u8],
+        dest: IpAddress,
+    ) -> Result<usize, i32> {
+        let request = match dest {
+            IpAddress::Ipv4(_) => u8::from(Icmpv4Message::EchoRequest),
+            IpAddress::Ipv6(_) => u8::from(Icmpv6Message::EchoRequest),
+        };
+        if data.len() < ECHO_HEADER || data[0] != request || data[1] != 0 {
+            return Err(EINVAL);
+        }
+        let icmp = self.set.get_mut::<icmp::Socket>(h);
+        if data.len() > icmp.payload_send_capacity() {
+            return Err(EMSGSIZE);
+        }
+        let buf = icmp.send(data.len(), dest).map_err(|e| match e {
+            icmp::SendError::BufferFull => EAGAIN,
+            icmp::SendError::Unaddressable => ENETUNREACH,
+        })?;
+        buf.copy_from_slice(data);
+        buf[4..6].copy_from_slice(&ident.to_be_bytes());
+        Ok(data.len())
+    }
+
+    /// Receive into `buf`. Returns the number of bytes and the source
+    /// address. With `MSG_PEEK` the data stays queued. Datagrams longer than
+    /// `buf` are truncated.
+    pub fn recvfrom(
+        &mut self,
+        client: u64,
+        fd: u64,
+        buf: &mut [u8],
+        flags: i32,
+    ) -> Result<(usize, IpEndpoint), i32> {
+        let peek = flags & libc::MSG_PEEK != 0;
+        let sock = self.entry(client, fd)?;
+        let (domain, shut_rd, peer) = (sock.domain, sock.shut_rd, sock.peer);
+        match sock.inner {
+            Inner::Tcp(h) => {
+                let tcp = self.set.get_mut::<tcp::Socket>(h);
+                let remote = tcp.remote_endpoint().unwrap_or(unspecified(domain));
+                if shut_rd {
+                    return Ok((0, remote));
+                }
+                let res = if peek {
+                    tcp.peek(buf.len()).map(|data| {
+                        buf[..data.len()].copy_from_slice(data);
+                        data.len()
+                    })
+                } else {
+                    tcp.recv_slice(buf)
+                };
+                match res {
+                    Ok(0) if !buf.is_empty() => Err(EAGAIN),
+                    Ok(n) => Ok((n, remote)),
+                    Err(tcp::RecvError::Finished) => Ok((0, remote)),
+                    Err(tcp::RecvError::InvalidState) => match tcp.state() {
+                        tcp::State::SynSent | tcp::State::SynReceived => Err(EAGAIN),
+                        _ => Err(ENOTCONN),
+                    },
+                }
+            }
+            Inner::Udp(h) => {
+                if shut_rd {
+                    return Ok((0, peer.unwrap_or(unspecified(domain))));
+                }
+                let udp = self.set.get_mut::<udp::Socket>(h);
+                loop {
+                    let (data, src) = if peek {
+                        udp.peek().map(|(d, m)| (d, m.endpoint))
+                    } else {
+                        udp.recv().map(|(d, m)| (d, m.endpoint))
+                    }
+                    .map_err(|_| EAGAIN)?;
+                    // A connected socket only accepts datagrams from its peer.
+                    if peer.is_some_and(|p| p != src) {
+                        if peek {
+                            let _ = udp.recv();
+                        }
+                        continue;
+                    }
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    return Ok((n, src));
+                }
+            }
+            Inner::Icmp(h) => {
+                if shut_rd {
+                    return Ok((0, peer.unwrap_or(unspecified(domain))));
+                }
+                // smoltcp cannot peek into an ICMP socket.
+                if peek {
+                    return Err(EOPNOTSUPP);
+                }
+                let icmp = self.set.get_mut::<icmp::Socket>(h);
+                loop {
+                    let (data, src) = icmp.recv().map_err(|_| EAGAIN)?;
+                    if peer.is_some_and(|p| p.addr != src) {
+                        continue;
+                    }
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    return Ok((n, IpEndpoint::new(src, 0)));
+                }
+            }
+            Inner::Raw(h) => {
+                if shut_rd {
+                    return Ok((0, peer.unwrap_or(unspecified(domain))));
+                }
+                let socket = self.set.get_mut::<raw::Socket>(h);
+                loop {
+                    let data =
+                        if peek { socket.peek() } else { socket.recv() }.map_err(|_| EAGAIN)?;
+                    let src = packet_source(data).unwrap_or(unspecified_addr(domain));
+                    if peer.is_some_and(|p| p.addr != src) {
+                        if peek {
+                            let _ = socket.recv();
+                        }
+                        continue;
+                    }
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    return Ok((n, IpEndpoint::new(src, 0)));
+                }
+            }
+            Inner::Idle if sock.ty != SockType::Stream => Err(EAGAIN),
+            _ => Err(ENOTCONN),
+        }
+    }
+
+    /// Events a client waiting on the socket would see, as a mask of the
+    /// `POLLIN`, `POLLOUT`, `POLLERR`, `POLLHUP` and `POLLRDHUP` flags.
+    /// `POLLIN` is set whenever `recvfrom` would not fail with `EAGAIN`, and
+    /// on a listening socket when `accept` would not.
+    pub fn poll(&mut self, client: u64, fd: u64) -> Result<u32, i32> {
+        let sock = lookup(&mut self.clients, client, fd)?;
+        let (pollin, pollout) = (libc::POLLIN as u32, libc::POLLOUT as u32);
+        let mut events = match &sock.inner {
+            Inner::Idle if sock.ty == SockType::Stream => pollout | libc::POLLHUP as u32,
+            Inner::Idle => pollout,
+            Inner::Tcp(h) => {
+                let tcp = self.set.get::<tcp::Socket>(*h);
+                let state = tcp.state();
+                match state {
+                    tcp::State::SynSent | tcp::State::SynReceived => 0,
+                    tcp::State::Closed => pollin | (libc::POLLERR | libc::POLLHUP) as u32,
+                    _ => {
+                        let mut events = ready(tcp.can_recv(), tcp.can_send());
+                        // The peer sent its FIN.
+                        if matches!(
+                            state,
+                            tcp::State::CloseWait
+                                | tcp::State::LastAck
+                                | tcp::State::Closing
+                                | tcp::State::TimeWait
+                        ) {
+                            events |= pollin | libc::POLLRDHUP as u32;
+                        }
+                        // Both directions are closed.
+                        if matches!(
+                            state,
+                            tcp::State::LastAck | tcp::State::Closing | tcp::State::TimeWait
+                        ) {
+                            events |= libc::POLLHUP as u32;
+                        }
+                        events
+                    }
+                }
+            }
+            Inner::Listen(pool) => {
+                let ready = pool.iter().any(|&h| {
+                    !matches!(
+                        self.set.get::<tcp::Socket>(h).state(),
+                        tcp::State::Listen | tcp::State::SynReceived | tcp::State::Closed
+                    )
+                });
+                if ready {
+                    pollin
+                } else {
+                    0
+                }
+            }
+            Inner::Udp(h) => {
+                let udp = self.set.get::<udp::Socket>(*h);
+                ready(udp.can_recv(), udp.can_send())
+            }
+            Inner::Icmp(h) => {
+                let icmp = self.set.get::<icmp::Socket>(*h);
+                ready(icmp.can_recv(), icmp.can_send())
+            }
+            Inner::Raw(h) => {
+                let raw = self.set.get::<raw::Socket>(*h);
+                ready(raw.can_recv(), raw.can_send())
+            }
+        };
+        if sock.shut_rd {
+            events |= pollin;
+        }
+        Ok(events)
+    }
+
+    pub fn getsockname(&mut self, client: u64, fd: u64) -> Result<IpEndpoint, i32> {
+        let sock = lookup(&mut self.clients, client, fd)?;
+        if let Inner::Tcp(h) = sock.inner {
+            if let Some(local) = self.set.get::<tcp::Socket>(h).local_endpoint() {
+                return Ok(local);
+            }
+        }
+        Ok(match sock.local {
+            Some(local) => IpEndpoint::new(
+                local.addr.unwrap_or(unspecified_addr(sock.domain)),
+                local.port,
+            ),
+            None => unspecified(sock.domain),
+        })
+    }
+
+    pub fn getpeername(&mut self, client: u64, fd: u64) -> Result<IpEndpoint, i32> {
+        let sock = self.entry(client, fd)?;
+        match sock.inner {
+            Inner::Tcp(h) => {
+                let tcp = self.set.get::<tcp::Socket>(h);
+                match tcp.state() {
+                    tcp::State::Closed | tcp::State::SynSent => Err(ENOTCONN),
+                    _ => tcp.remote_endpoint().ok_or(ENOTCONN),
+                }
+            }
+            _ => sock.peer.ok_or(ENOTCONN),
+        }
+    }
+
+    /// Supported options: `SO_REUSEADDR`, `SO_RCVBUF` and `SO_SNDBUF` on
+    /// level `SOL_SOCKET`, `TCP_NODELAY` on level `IPPROTO_TCP`. Buffer sizes
+    /// can only be changed before the socket is bound or connected.
+    pub fn setsockopt(
+        &mut self,
+        client: u64,
+        fd: u64,
+        level: i32,
+        name: i32,
+        value: u64,
+    ) -> Result<(), i32> {
+        let sock = lookup(&mut self.clients, client, fd)?;
+        match (level, name) {
+            (libc::SOL_SOCKET, libc::SO_REUSEADDR) => sock.reuse_addr = value != 0,
+            (libc::SOL_SOCKET, libc::SO_RCVBUF | libc::SO_SNDBUF) => {
+                if !matches!(sock.inner, Inner::Idle) {
+                    return Err(EINVAL);
+                }
+                let size = (value.min(MAX_BUF as u64) as usize).max(MIN_BUF);
+                if name == libc::SO_RCVBUF {
+                    sock.rcvbuf = size;
+                } else {
+                    sock.sndbuf = size;
+                }
+            }
+            (libc::IPPROTO_TCP, libc::TCP_NODELAY) if sock.ty == SockType::Stream => {
+                sock.nodelay = value != 0;
+                if let Inner::Tcp(h) = sock.inner {
+                    self.set
+                        .get_mut::<tcp::Socket>(h)
+                        .set_nagle_enabled(value == 0);
+                }
+            }
+            _ => return Err(ENOPROTOOPT),
+        }
+        Ok(())
+    }
+
+    /// Supports the options of [`Sockets::setsockopt`] plus `SO_TYPE`.
+    pub fn getsockopt(&mut self, client: u64, fd: u64, level: i32, name: i32) -> Result<u64, i32> {
+        let sock = self.entry(client, fd)?;
+        Ok(match (level, name) {
+            (libc::SOL_SOCKET, libc::SO_REUSEADDR) => sock.reuse_addr as u64,
+            (libc::SOL_SOCKET, libc::SO_RCVBUF) => sock.rcvbuf as u64,
+            (libc::SOL_SOCKET, libc::SO_SNDBUF) => sock.sndbuf as u64,
+            (libc::SOL_SOCKET, libc::SO_TYPE) => match sock.ty {
+                SockType::Stream => libc::SOCK_STREAM as u64,
+                SockType::Dgram | SockType::Ping => libc::SOCK_DGRAM as u64,
+                SockType::Raw => libc::SOCK_RAW as u64,
+            },
+            (libc::IPPROTO_TCP, libc::TCP_NODELAY) if sock.ty == SockType::Stream => {
+                sock.nodelay as u64
+            }
+            _ => return Err(ENOPROTOOPT),
+        })
+    }
+}
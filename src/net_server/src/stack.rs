@@ -0,0 +1,135 @@
+//! The network stack of a server instance.
+//!
+//! A [`Stack`] bundles the smoltcp interface with the client sockets, the
+//! address autoconfiguration, the firewall and the packet capture. Captures
+//! see the frames on the device, before the firewall drops received frames
+//! and after it let transmitted frames pass. Traffic of the loopback
+//! addresses, which the interface has on every device, bypasses both, see
+//! [`crate::loopback`]. It does not own its device: the server binary
+//! drives it with the backend chosen at startup, tests drive it with any
+//! device from [`crate::device`] and a clock of their own.
+
+use crate::capture::{Capture, Capturing};
+use crate::config::NetConfig;
+use crate::firewall::{Filtering, Firewall};
+use crate::ifconfig::{self, Dhcp, Slaac};
+use crate::loopback::{LoopQueue, Looping};
+use crate::socket::Sockets;
+use smoltcp::iface::{Config, Interface, PollResult};
+use smoltcp::phy::{Device, Medium};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::HardwareAddress;
+use std::cmp::min;
+
+/// Interface polls per call of [`Stack::poll`]. Each poll handles the
+/// frames received so far and transmits what the sockets have queued; more
+/// rounds let replies to the loopback addresses go out within one call.
+const POLL_ROUNDS: usize = 16;
+
+pub struct Stack {
+    pub iface: Interface,
+    pub sockets: Sockets,
+    pub dhcp: Dhcp,
+    pub slaac: Slaac,
+    pub firewall: Firewall,
+    pub capture: Capture,
+    looped: LoopQueue,
+    medium: Medium,
+    /// The last poll stopped with work left.
+    busy: bool,
+}
+
+impl Stack {
+    /// Set up the interface on `device` as configured by `cfg`.
+    pub fn new<D: Device + ?Sized>(cfg: &NetConfig, device: &mut D, now: Instant) -> Self {
+        let medium = device.capabilities().medium;
+        let hw = match medium {
+            Medium::Ethernet => HardwareAddress::Ethernet(cfg.mac),
+            Medium::Ip => HardwareAddress::Ip,
+        };
+        let mut stack = Self {
+            iface: Interface::new(Config::new(hw), device, now),
+            sockets: Sockets::new(),
+            dhcp: Dhcp::default(),
+            slaac: Slaac::new(cfg.mac),
+            firewall: Firewall::new(medium),
+            capture: Capture::new(medium),
+            looped: LoopQueue::new(),
+            medium,
+            busy: false,
+        };
+        for rule in &cfg.rules {
+            stack.firewall.insert(usize::MAX, rule.clone());
+        }
+        stack.firewall.set_policy(cfg.policy);
+        ifconfig::apply_loopback(&mut stack.iface, cfg);
+        if medium == Medium::Ip {
+            return stack;
+        }
+        ifconfig::apply_static(&mut stack.iface, cfg);
+        let _ = stack.set_dhcp(cfg.use_dhcp());
+        let _ = stack.set_slaac(cfg.use_slaac(), now);
+        stack
+    }
+
+    /// Autoconfiguration needs link-layer addresses.
+    fn check_ethernet(&self, on: bool) -> Result<(), i32> {
+        match self.medium {
+            Medium::Ip if on => Err(libc::EOPNOTSUPP),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start or stop the DHCP client, see [`Dhcp::set_enabled`]. Fails
+    /// with `EOPNOTSUPP` on the loopback device.
+    pub fn set_dhcp(&mut self, on: bool) -> Result<(), i32> {
+        self.check_ethernet(on)?;
+        self.dhcp
+            .set_enabled(&mut self.iface, self.sockets.set_mut(), on);
+        Ok(())
+    }
+
+    /// Start or stop SLAAC, see [`Slaac::set_enabled`]. Fails with
+    /// `EOPNOTSUPP` on the loopback device.
+    pub fn set_slaac(&mut self, on: bool, now: Instant) -> Result<(), i32> {
+        self.check_ethernet(on)?;
+        self.slaac
+            .set_enabled(&mut self.iface, self.sockets.set_mut(), on, now);
+        Ok(())
+    }
+
+    /// Advance the stack, apply DHCP and SLAAC configuration changes and
+    /// drop sockets that finished closing.
+    pub fn poll<D: Device + ?Sized>(&mut self, device: &mut D, now: Instant) {
+        let mut device = Capturing::new(device, &mut self.capture);
+        let mut device = Filtering::new(&mut device, &mut self.firewall);
+        let mut device = Looping::new(&mut device, &mut self.looped);
+        self.busy = true;
+        for _ in 0..POLL_ROUNDS {
+            if self.iface.poll(now, &mut device, self.sockets.set_mut()) == PollResult::None {
+                self.busy = false;
+                break;
+            }
+        }
+        self.dhcp.update(&mut self.iface, self.sockets.set_mut());
+        self.slaac
+            .update(&mut self.iface, self.sockets.set_mut(), now);
+        self.sockets.reap();
+    }
+
+    /// Time until the stack needs to be polled again, `None` if it has
+    /// nothing pending.
+    pub fn poll_delay(&mut self, now: Instant) -> Option<Duration> {
+        if self.busy {
+            return Some(Duration::ZERO);
+        }
+        let slaac = self
+            .slaac
+            .poll_at()
+            .map(|at| if at > now { at - now } else { Duration::ZERO });
+        match (self.iface.poll_delay(now, self.sockets.set()), slaac) {
+            (Some(a), Some(b)) => Some(min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use libc::{
+    AF_INET, AF_INET6, EADDRINUSE, EAGAIN, EBADF, EINPROGRESS, ENOTCONN, SOCK_DGRAM, SOCK_STREAM,
+};
+use net_server::device::{self, Pipe};
+use net_server::{NetConfig, Sockets, Stack};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::IpEndpoint;
+
+mod common;
+use common::{listen_on, static_config, Link, CLIENT, STEPS};
+
+/// Receive until `len` bytes arrived or the peer closed.
+fn recv_all(link: &mut Link, on_b: bool, fd: u64, len: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1000];
+    while data.len() < len {
+        let n = link.wait(|l| {
+            let stack = if on_b { &mut l.b } else { &mut l.a };
+            stack
+                .sockets
+                .recvfrom(CLIENT, fd, &mut buf, 0)
+                .map(|(n, _)| n)
+        });
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+    data
+}
+
+/// Connect from `a` to `server` on `b`, exchange data both ways and close.
+fn tcp_exchange(link: &mut Link, domain: i32, server: IpEndpoint) {
+    let listener = link
+        .b
+        .sockets
+        .socket(CLIENT, domain, SOCK_STREAM, 0)
+        .unwrap();
+    link.b
+        .sockets
+        .bind(CLIENT, listener, listen_on(server.port))
+        .unwrap();
+    link.b.sockets.listen(CLIENT, listener, 4).unwrap();
+
+    let client = link
+        .a
+        .sockets
+        .socket(CLIENT, domain, SOCK_STREAM, 0)
+        .unwrap();
+    assert_eq!(
+        link.a
+            .sockets
+            .connect(&mut link.a.iface, CLIENT, client, server),
+        Err(EINPROGRESS)
+    );
+    let (conn, peer) = link.wait(|l| l.b.sockets.accept(CLIENT, listener));
+    let local = link.a.sockets.getsockname(CLIENT, client).unwrap();
+    assert_eq!(peer, local);
+    assert_eq!(link.a.sockets.getpeername(CLIENT, client), Ok(server));
+
+    // More than the default socket buffers, so flow control kicks in.
+    let request: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+    let mut received = Vec::new();
+    let mut sent = 0;
+    let mut buf = [0u8; 1000];
+    for _ in 0..STEPS {
+        if received.len() == request.len() {
+            break;
+        }
+        match link.a.sockets.send(CLIENT, client, &request[sent..]) {
+            Ok(n) => sent += n,
+            Err(err) => assert_eq!(err, EAGAIN),
+        }
+        match link.b.sockets.recvfrom(CLIENT, conn, &mut buf, 0) {
+            Ok((n, _)) => received.extend_from_slice(&buf[..n]),
+            Err(err) => assert_eq!(err, EAGAIN),
+        }
+        link.step();
+    }
+    assert_eq!(received, request);
+
+    link.wait(|l| l.b.sockets.send(CLIENT, conn, b"pong"));
+    assert_eq!(recv_all(link, false, client, 4), b"pong");
+
+    // Closing one side shows up as end of stream on the other.
+    link.a.sockets.close(CLIENT, client).unwrap();
+    assert_eq!(recv_all(link, true, conn, 1), b"");
+    link.b.sockets.close(CLIENT, conn).unwrap();
+    link.b.sockets.close(CLIENT, listener).unwrap();
+}
+
+#[test]
+fn tcp_over_ipv4() {
+    let mut link = Link::new(
+        &static_config(1, &["10.0.0.1/24"]),
+        &static_config(2, &["10.0.0.2/24"]),
+    );
+    tcp_exchange(&mut link, AF_INET, "10.0.0.2:7".parse().unwrap());
+}
+
+#[test]
+fn tcp_over_ipv6() {
+    let mut link = Link::new(
+        &static_config(1, &["2001:db8::1/64"]),
+        &static_config(2, &["2001:db8::2/64"]),
+    );
+    tcp_exchange(&mut link, AF_INET6, "[2001:db8::2]:7".parse().unwrap());
+}
+
+#[test]
+fn connect_progress() {
+    let mut link = Link::new(
+        &static_config(1, &["10.0.0.1/24"]),
+        &static_config(2, &["10.0.0.2/24"]),
+    );
+    let listener = link
+        .b
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+        .unwrap();
+    link.b.sockets.bind(CLIENT, listener, listen_on(7)).unwrap();
+    link.b.sockets.listen(CLIENT, listener, 1).unwrap();
+
+    // An empty receive reports the outcome of the handshake.
+    for (port, outcome) in [(7, Ok(0)), (8, Err(ENOTCONN))] {
+        let fd = link
+            .a
+            .sockets
+            .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+            .unwrap();
+        let server = IpEndpoint::new("10.0.0.2".parse().unwrap(), port);
+        let res = link
+            .a
+            .sockets
+            .connect(&mut link.a.iface, CLIENT, fd, server);
+        assert_eq!(res, Err(EINPROGRESS));
+        let res = link.a.sockets.recvfrom(CLIENT, fd, &mut [], 0);
+        assert_eq!(res, Err(EAGAIN));
+        let mut res = Err(EAGAIN);
+        for _ in 0..STEPS {
+            res = link.a.sockets.recvfrom(CLIENT, fd, &mut [], 0);
+            if res != Err(EAGAIN) {
+                break;
+            }
+            link.step();
+        }
+        assert_eq!(res.map(|(n, _)| n), outcome);
+    }
+}
+
+#[test]
+fn poll_events() {
+    const IN: u32 = libc::POLLIN as u32;
+    const OUT: u32 = libc::POLLOUT as u32;
+    const RDHUP: u32 = libc::POLLRDHUP as u32;
+    let mut link = Link::new(
+        &static_config(1, &["10.0.0.1/24"]),
+        &static_config(2, &["10.0.0.2/24"]),
+    );
+    let listener = link
+        .b
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+        .unwrap();
+    link.b.sockets.bind(CLIENT, listener, listen_on(7)).unwrap();
+    link.b.sockets.listen(CLIENT, listener, 1).unwrap();
+    assert_eq!(link.b.sockets.poll(CLIENT, listener), Ok(0));
+
+    let client = link
+        .a
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+        .unwrap();
+    let server = "10.0.0.2:7".parse().unwrap();
+    let res = link
+        .a
+        .sockets
+        .connect(&mut link.a.iface, CLIENT, client, server);
+    assert_eq!(res, Err(EINPROGRESS));
+    assert_eq!(link.a.sockets.poll(CLIENT, client), Ok(0));
+    let (conn, _) = link.wait(|l| l.b.sockets.accept(CLIENT, listener));
+    assert_eq!(link.a.sockets.poll(CLIENT, client), Ok(OUT));
+    assert_eq!(link.b.sockets.poll(CLIENT, conn), Ok(OUT));
+
+    link.a.sockets.send(CLIENT, client, b"ping").unwrap();
+    link.a.sockets.close(CLIENT, client).unwrap();
+    for _ in 0..100 {
+        link.step();
+    }
+    assert_eq!(link.b.sockets.poll(CLIENT, conn), Ok(IN | OUT | RDHUP));
+
+    let udp = link
+        .a
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    assert_eq!(link.a.sockets.poll(CLIENT, udp), Ok(OUT));
+}
+
+#[test]
+fn udp_datagrams() {
+    let mut link = Link::new(
+        &static_config(1, &["10.0.0.1/24"]),
+        &static_config(2, &["10.0.0.2/24"]),
+    );
+    let server = link
+        .b
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    link.b.sockets.bind(CLIENT, server, listen_on(53)).unwrap();
+    let client = link
+        .a
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    let dest: IpEndpoint = "10.0.0.2:53".parse().unwrap();
+
+    // The first datagram waits for ARP resolution.
+    link.a
+        .sockets
+        .sendto(CLIENT, client, b"query", dest)
+        .unwrap();
+    let mut buf = [0u8; 64];
+    let (n, from) = link.wait(|l| l.b.sockets.recvfrom(CLIENT, server, &mut buf, 0));
+    assert_eq!(&buf[..n], b"query");
+    assert_eq!(from.addr.to_string(), "10.0.0.1");
+
+    link.b
+        .sockets
+        .sendto(CLIENT, server, b"answer", from)
+        .unwrap();
+    let (n, src) = link.wait(|l| l.a.sockets.recvfrom(CLIENT, client, &mut buf, 0));
+    assert_eq!(&buf[..n], b"answer");
+    assert_eq!(src, dest);
+}
+
+#[test]
+fn clients_are_isolated() {
+    const OTHER: u64 = 2;
+    let mut sockets = Sockets::new();
+    let fd = sockets.socket(CLIENT, AF_INET, SOCK_DGRAM, 0).unwrap();
+    sockets.bind(CLIENT, fd, listen_on(53)).unwrap();
+
+    // Another client neither sees the handle nor gets the port.
+    assert_eq!(sockets.getsockname(OTHER, fd), Err(EBADF));
+    assert_eq!(sockets.close(OTHER, fd), Err(EBADF));
+    let other = sockets.socket(OTHER, AF_INET, SOCK_DGRAM, 0).unwrap();
+    assert_eq!(sockets.bind(OTHER, other, listen_on(53)), Err(EADDRINUSE));
+
+    // Once the first client is gone, its sockets are closed.
+    sockets.close_client(CLIENT);
+    assert_eq!(sockets.getsockname(CLIENT, fd), Err(EBADF));
+    sockets.bind(OTHER, other, listen_on(53)).unwrap();
+}
+
+#[test]
+fn loopback_device() {
+    let cfg = NetConfig::default();
+    let mut dev = device::loopback();
+    let mut now = Instant::ZERO;
+    let mut stack = Stack::new(&cfg, &mut dev, now);
+    let addrs: Vec<String> = stack
+        .iface
+        .ip_addrs()
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    assert_eq!(addrs, ["127.0.0.1/8", "::1/128"]);
+    // Autoconfiguration needs a link layer.
+    assert_eq!(stack.set_dhcp(true), Err(libc::EOPNOTSUPP));
+    assert_eq!(stack.set_slaac(true, now), Err(libc::EOPNOTSUPP));
+
+    for (domain, server) in [(AF_INET, "127.0.0.1:80"), (AF_INET6, "[::1]:80")] {
+        let server: IpEndpoint = server.parse().unwrap();
+        let sockets = &mut stack.sockets;
+        let listener = sockets.socket(CLIENT, domain, SOCK_STREAM, 0).unwrap();
+        sockets.bind(CLIENT, listener, listen_on(80)).unwrap();
+        sockets.listen(CLIENT, listener, 1).unwrap();
+        let client = sockets.socket(CLIENT, domain, SOCK_STREAM, 0).unwrap();
+        assert_eq!(
+            sockets.connect(&mut stack.iface, CLIENT, client, server),
+            Err(EINPROGRESS)
+        );
+
+        // A single poll carries the handshake through.
+        now += Duration::from_millis(1);
+        stack.poll(&mut dev, now);
+        let (conn, _) = stack.sockets.accept(CLIENT, listener).unwrap();
+        assert_eq!(stack.sockets.send(CLIENT, client, b"hello"), Ok(5));
+        stack.poll(&mut dev, now);
+        let mut buf = [0u8; 16];
+        let (n, _) = stack.sockets.recvfrom(CLIENT, conn, &mut buf, 0).unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        for fd in [client, conn, listener] {
+            stack.sockets.close(CLIENT, fd).unwrap();
+        }
+    }
+}
+
+/// Poll a single stack until `op` stops failing with `EAGAIN`.
+fn poll_until<T>(
+    stack: &mut Stack,
+    dev: &mut Pipe,
+    now: &mut Instant,
+    mut op: impl FnMut(&mut Stack) -> Result<T, i32>,
+) -> T {
+    for _ in 0..STEPS {
+        match op(stack) {
+            Err(EAGAIN) => {
+                *now += Duration::from_millis(1);
+                stack.poll(dev, *now);
+            }
+            res => return res.expect("socket operation failed"),
+        }
+    }
+    panic!("timed out");
+}
+
+#[test]
+fn loopback_beside_device() {
+    let mut cfg = static_config(1, &["10.0.0.1/24"]);
+    cfg.gateway = Some("10.0.0.254".parse().unwrap());
+    let (mut dev, mut peer) = Pipe::pair();
+    let mut now = Instant::ZERO;
+    let mut stack = Stack::new(&cfg, &mut dev, now);
+    let addrs: Vec<String> = stack
+        .iface
+        .ip_addrs()
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    assert!(addrs.iter().any(|a| a == "127.0.0.1/8"));
+    assert!(addrs.iter().any(|a| a == "::1/128"));
+
+    for (domain, server) in [(AF_INET, "127.0.0.1:80"), (AF_INET6, "[::1]:80")] {
+        let server: IpEndpoint = server.parse().unwrap();
+        let sockets = &mut stack.sockets;
+        let listener = sockets.socket(CLIENT, domain, SOCK_STREAM, 0).unwrap();
+        sockets.bind(CLIENT, listener, listen_on(80)).unwrap();
+        sockets.listen(CLIENT, listener, 1).unwrap();
+        let client = sockets.socket(CLIENT, domain, SOCK_STREAM, 0).unwrap();
+        assert_eq!(
+            sockets.connect(&mut stack.iface, CLIENT, client, server),
+            Err(EINPROGRESS)
+        );
+
+        let (conn, _) = poll_until(&mut stack, &mut dev, &mut now, |s| {
+            s.sockets.accept(CLIENT, listener)
+        });
+        assert_eq!(stack.sockets.send(CLIENT, client, b"hello"), Ok(5));
+        let mut buf = [0u8; 16];
+        let (n, _) = poll_until(&mut stack, &mut dev, &mut now, |s| {
+            s.sockets.recvfrom(CLIENT, conn, &mut buf, 0)
+        });
+        assert_eq!(&buf[..n], b"hello");
+
+        for fd in [client, conn, listener] {
+            stack.sockets.close(CLIENT, fd).unwrap();
+        }
+    }
+    // None of it went out on the link, not even address resolution.
+    assert!(smoltcp::phy::Device::receive(&mut peer, now).is_none());
+}
@@ -0,0 +1,139 @@
+use libc::{AF_INET, EAGAIN, SOCK_DGRAM};
+use net_server::capture::{Capture, Direction, Filter, Format};
+use net_server::device::ETHERNET_MTU;
+use net_server::NetConfig;
+use smoltcp::phy::Medium;
+use smoltcp::time::Instant;
+use smoltcp::wire::IpEndpoint;
+
+mod common;
+use common::{listen_on, static_config, Link, CLIENT};
+
+fn ipv4_config(mac: u8, addr: &str) -> NetConfig {
+    NetConfig {
+        ipv6: false,
+        ..static_config(mac, &[addr])
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Frame lengths of a pcap file, checking the headers on the way.
+fn pcap_frames(data: &[u8]) -> Vec<usize> {
+    assert_eq!(u32_at(data, 0), 0xa1b2_c3d4);
+    assert_eq!(u32_at(data, 20), 1, "Ethernet link type");
+    let mut frames = Vec::new();
+    let mut pos = 24;
+    while pos < data.len() {
+        let len = u32_at(data, pos + 8) as usize;
+        assert_eq!(u32_at(data, pos + 12) as usize, len);
+        frames.push(len);
+        pos += 16 + len;
+    }
+    assert_eq!(pos, data.len());
+    frames
+}
+
+/// Block types of a pcapng file, checking the block lengths on the way.
+fn pcapng_blocks(data: &[u8]) -> Vec<u32> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = u32_at(data, pos + 4) as usize;
+        assert_eq!(len % 4, 0);
+        assert_eq!(u32_at(data, pos + len - 4) as usize, len);
+        blocks.push(u32_at(data, pos));
+        pos += len;
+    }
+    assert_eq!(pos, data.len());
+    blocks
+}
+
+#[test]
+fn filter_syntax() {
+    assert_eq!("".parse::<Filter>(), Ok(Filter::default()));
+    assert!("tcp port 80".parse::<Filter>().is_ok());
+    assert!("udp and host 10.0.2.3".parse::<Filter>().is_ok());
+    assert!("icmp host fe80::1".parse::<Filter>().is_ok());
+    assert!("port".parse::<Filter>().is_err());
+    assert!("port http".parse::<Filter>().is_err());
+    assert!("host 10.0.2".parse::<Filter>().is_err());
+    assert!("tcp udp".parse::<Filter>().is_err());
+    assert!("or".parse::<Filter>().is_err());
+}
+
+#[test]
+fn capture_udp_exchange() {
+    let mut link = Link::new(
+        &ipv4_config(1, "10.0.0.1/24"),
+        &ipv4_config(2, "10.0.0.2/24"),
+    );
+    link.a.capture.start(0, "udp port 53".parse().unwrap());
+    link.b.capture.start(0, "arp".parse().unwrap());
+
+    let (a, b) = (&mut link.a.sockets, &mut link.b.sockets);
+    let server = b.socket(CLIENT, AF_INET, SOCK_DGRAM, 0).unwrap();
+    b.bind(CLIENT, server, listen_on(53)).unwrap();
+    let client = a.socket(CLIENT, AF_INET, SOCK_DGRAM, 0).unwrap();
+    let dest: IpEndpoint = "10.0.0.2:53".parse().unwrap();
+    a.sendto(CLIENT, client, b"query", dest).unwrap();
+
+    let mut buf = [0u8; 64];
+    let mut answered = false;
+    for _ in 0..100 {
+        link.step();
+        let (a, b) = (&mut link.a.sockets, &mut link.b.sockets);
+        match b.recvfrom(CLIENT, server, &mut buf, 0) {
+            Ok((_, from)) => {
+                b.sendto(CLIENT, server, b"answer", from).unwrap();
+                answered = true;
+            }
+            Err(err) => assert_eq!(err, EAGAIN),
+        }
+        if answered && a.recvfrom(CLIENT, client, &mut buf, 0).is_ok() {
+            break;
+        }
+    }
+    let (a, b) = (&mut link.a, &mut link.b);
+    a.capture.stop();
+    b.capture.stop();
+
+    // a kept the query and the answer, b only the ARP request and reply.
+    assert_eq!(a.capture.frames(), 2);
+    assert_eq!(b.capture.frames(), 2);
+    assert_eq!(a.capture.lost(), 0);
+    // Ethernet, IPv4 and UDP headers around the payload.
+    let frames = pcap_frames(&a.capture.export(Format::Pcap));
+    assert_eq!(frames, [14 + 20 + 8 + 5, 14 + 20 + 8 + 6]);
+    let blocks = pcapng_blocks(&a.capture.export(Format::Pcapng));
+    assert_eq!(blocks, [0x0a0d_0d0a, 1, 6, 6]);
+}
+
+#[test]
+fn ring_drops_oldest_frames() {
+    let mut capture = Capture::new(Medium::Ethernet);
+    let frame = [0u8; 1000];
+    capture.record(Instant::ZERO, Direction::Rx, &frame);
+    assert_eq!(capture.frames(), 0, "not started");
+
+    capture.start(ETHERNET_MTU, Filter::default());
+    for n in 0..3 {
+        capture.record(Instant::from_millis(n), Direction::Tx, &frame);
+    }
+    assert_eq!(capture.frames(), 1);
+    assert_eq!(capture.lost(), 2);
+
+    // Reads continue in the snapshot taken at offset 0.
+    let (size, data) = capture.read(Format::Pcap, 0);
+    assert_eq!((size, data.len()), (24 + 16 + 1000, size));
+    capture.record(Instant::from_millis(3), Direction::Tx, &frame);
+    let (size, data) = capture.read(Format::Pcap, 1000);
+    assert_eq!((size, data.len()), (24 + 16 + 1000, 40));
+    assert_eq!(capture.read(Format::Pcap, 0).0, size);
+
+    // Starting again discards the buffer.
+    capture.start(0, Filter::default());
+    assert_eq!((capture.frames(), capture.lost()), (0, 0));
+}
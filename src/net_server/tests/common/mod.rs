@@ -0,0 +1,75 @@
+//! Fixtures shared by the integration tests.
+
+// Every test crate uses a different part of the fixtures.
+#![allow(dead_code)]
+
+use libc::{EAGAIN, EINPROGRESS};
+use net_server::device::Pipe;
+use net_server::{NetConfig, Stack};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, IpListenEndpoint};
+
+pub const CLIENT: u64 = 1;
+/// Simulated milliseconds before a test gives up waiting.
+pub const STEPS: usize = 5000;
+
+/// A static configuration without DHCP and autoconfiguration.
+pub fn static_config(mac: u8, addrs: &[&str]) -> NetConfig {
+    NetConfig {
+        mac: EthernetAddress([0x02, 0, 0, 0, 0, mac]),
+        dhcp: Some(false),
+        slaac: Some(false),
+        addresses: addrs.iter().map(|a| a.parse().unwrap()).collect(),
+        ..NetConfig::default()
+    }
+}
+
+pub fn listen_on(port: u16) -> IpListenEndpoint {
+    IpListenEndpoint { addr: None, port }
+}
+
+/// Two stacks connected back to back through a pipe, with a simulated clock.
+pub struct Link {
+    pub a: Stack,
+    pub b: Stack,
+    pub dev_a: Pipe,
+    pub dev_b: Pipe,
+    pub now: Instant,
+}
+
+impl Link {
+    pub fn new(a: &NetConfig, b: &NetConfig) -> Self {
+        let (mut dev_a, mut dev_b) = Pipe::pair();
+        let now = Instant::ZERO;
+        Link {
+            a: Stack::new(a, &mut dev_a, now),
+            b: Stack::new(b, &mut dev_b, now),
+            dev_a,
+            dev_b,
+            now,
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.now += Duration::from_millis(1);
+        self.a.poll(&mut self.dev_a, self.now);
+        self.b.poll(&mut self.dev_b, self.now);
+    }
+
+    pub fn run(&mut self, millis: u64) {
+        for _ in 0..millis {
+            self.step();
+        }
+    }
+
+    /// Retry `op` while it would block, advancing the clock in between.
+    pub fn wait<T>(&mut self, mut op: impl FnMut(&mut Self) -> Result<T, i32>) -> T {
+        for _ in 0..STEPS {
+            match op(self) {
+                Err(EAGAIN) | Err(EINPROGRESS) => self.step(),
+                res => return res.expect("socket operation failed"),
+            }
+        }
+        panic!("timed out");
+    }
+}
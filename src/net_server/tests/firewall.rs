@@ -0,0 +1,238 @@
+use libc::{AF_INET, EAGAIN, ENOTCONN, SOCK_DGRAM, SOCK_STREAM};
+use net_server::capture::Format;
+use net_server::device::{Pipe, PipeRxToken, PipeTxToken};
+use net_server::firewall::{Action, Filtering, Firewall, Rule};
+use net_server::NetConfig;
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::IpEndpoint;
+
+mod common;
+use common::{listen_on, static_config, Link, CLIENT};
+
+fn config(mac: u8, addr: &str, rules: &[&str], policy: Action) -> NetConfig {
+    NetConfig {
+        ipv6: false,
+        rules: rules.iter().map(|r| r.parse().unwrap()).collect(),
+        policy,
+        ..static_config(mac, &[addr])
+    }
+}
+
+#[test]
+fn rule_syntax() {
+    for rule in [
+        "accept in state established",
+        "accept in on eth0 tcp to port 22",
+        "reject tcp from 10.0.0.0/8 port 1000-2000 to 10.0.0.2/32",
+        "drop out udp to 2001:db8::/32 port 53 state new",
+        "accept icmp",
+    ] {
+        assert_eq!(rule.parse::<Rule>().unwrap().to_string(), rule);
+    }
+    // Conditions may come in any order and hosts get a full prefix.
+    let rule: Rule = "accept to 10.0.0.2 tcp in".parse().unwrap();
+    assert_eq!(rule.to_string(), "accept in tcp to 10.0.0.2/32");
+
+    for bad in [
+        "",
+        "allow in",
+        "accept sideways",
+        "accept on",
+        "accept from",
+        "accept to port",
+        "accept tcp to port 80-22",
+        "accept to 10.0.0.300",
+        "accept to port 22",
+        "accept icmp to port 22",
+        "accept state closed",
+    ] {
+        assert!(bad.parse::<Rule>().is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn stateful_filter() {
+    let rules = [
+        "accept state established",
+        "accept in tcp to port 22",
+        "reject in tcp to port 23",
+    ];
+    let mut link = Link::new(
+        &config(1, "10.0.0.1/24", &[], Action::Accept),
+        &config(2, "10.0.0.2/24", &rules, Action::Drop),
+    );
+    let mut listeners = Vec::new();
+    for port in [22, 23, 80] {
+        let fd = link
+            .b
+            .sockets
+            .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+            .unwrap();
+        link.b.sockets.bind(CLIENT, fd, listen_on(port)).unwrap();
+        link.b.sockets.listen(CLIENT, fd, 1).unwrap();
+        listeners.push(fd);
+    }
+    let mut clients = Vec::new();
+    for port in [22, 23, 80] {
+        let fd = link
+            .a
+            .sockets
+            .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+            .unwrap();
+        let server = IpEndpoint::new("10.0.0.2".parse().unwrap(), port);
+        let _ = link
+            .a
+            .sockets
+            .connect(&mut link.a.iface, CLIENT, fd, server);
+        clients.push(fd);
+    }
+    link.run(100);
+
+    // Port 22 is open and the connection carries data both ways.
+    let (conn, _) = link.b.sockets.accept(CLIENT, listeners[0]).unwrap();
+    assert_eq!(link.a.sockets.send(CLIENT, clients[0], b"ping"), Ok(4));
+    assert_eq!(link.b.sockets.send(CLIENT, conn, b"pong"), Ok(4));
+    link.run(10);
+    let mut buf = [0u8; 8];
+    let res = link.b.sockets.recvfrom(CLIENT, conn, &mut buf, 0);
+    assert_eq!(res.map(|(n, _)| n), Ok(4));
+    let res = link.a.sockets.recvfrom(CLIENT, clients[0], &mut buf, 0);
+    assert_eq!(res.map(|(n, _)| n), Ok(4));
+
+    // Port 23 answers with a reset, port 80 does not answer at all.
+    let res = link.a.sockets.recvfrom(CLIENT, clients[1], &mut buf, 0);
+    assert_eq!(res, Err(ENOTCONN));
+    let res = link.a.sockets.recvfrom(CLIENT, clients[2], &mut buf, 0);
+    assert_eq!(res, Err(EAGAIN));
+    for fd in &listeners[1..] {
+        assert_eq!(link.b.sockets.accept(CLIENT, *fd), Err(EAGAIN));
+    }
+
+    let counters: Vec<u64> = link
+        .b
+        .firewall
+        .rules()
+        .iter()
+        .map(|(_, c)| c.packets)
+        .collect();
+    // The ACK of the handshake and the data segment arrive established.
+    assert!(counters[0] >= 2, "{counters:?}");
+    assert_eq!(counters[1], 1);
+    assert_eq!(counters[2], 1);
+    let (policy, dropped) = link.b.firewall.policy();
+    assert_eq!(policy, Action::Drop);
+    assert!(dropped.packets >= 1);
+    assert!(link.b.firewall.connections() >= 1);
+}
+
+#[test]
+fn outgoing_rules() {
+    let rules = ["drop out udp to port 53"];
+    let mut link = Link::new(
+        &config(1, "10.0.0.1/24", &rules, Action::Accept),
+        &config(2, "10.0.0.2/24", &[], Action::Accept),
+    );
+    let mut servers = Vec::new();
+    for port in [53, 54] {
+        let fd = link
+            .b
+            .sockets
+            .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+            .unwrap();
+        link.b.sockets.bind(CLIENT, fd, listen_on(port)).unwrap();
+        servers.push(fd);
+    }
+    let client = link
+        .a
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    for port in [53, 54] {
+        let dest = IpEndpoint::new("10.0.0.2".parse().unwrap(), port);
+        link.a
+            .sockets
+            .sendto(CLIENT, client, b"query", dest)
+            .unwrap();
+    }
+    link.run(10);
+
+    let mut buf = [0u8; 8];
+    let res = link.b.sockets.recvfrom(CLIENT, servers[0], &mut buf, 0);
+    assert_eq!(res, Err(EAGAIN));
+    let res = link.b.sockets.recvfrom(CLIENT, servers[1], &mut buf, 0);
+    assert_eq!(res.map(|(n, _)| n), Ok(5));
+    assert_eq!(link.a.firewall.rules()[0].1.packets, 1);
+
+    // Removing the rule lets the next datagram through.
+    assert!(link.a.firewall.remove(0).is_some());
+    let dest = IpEndpoint::new("10.0.0.2".parse().unwrap(), 53);
+    link.a
+        .sockets
+        .sendto(CLIENT, client, b"query", dest)
+        .unwrap();
+    link.run(10);
+    let res = link.b.sockets.recvfrom(CLIENT, servers[0], &mut buf, 0);
+    assert_eq!(res.map(|(n, _)| n), Ok(5));
+}
+
+#[test]
+fn reject_udp() {
+    let mut link = Link::new(
+        &config(1, "10.0.0.1/24", &[], Action::Accept),
+        &config(2, "10.0.0.2/24", &["reject in udp"], Action::Accept),
+    );
+    link.a.capture.start(0, "icmp".parse().unwrap());
+    let client = link
+        .a
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    let dest = IpEndpoint::new("10.0.0.2".parse().unwrap(), 9);
+    link.a
+        .sockets
+        .sendto(CLIENT, client, b"query", dest)
+        .unwrap();
+    link.run(10);
+
+    // A port unreachable message came back, quoting the datagram.
+    assert_eq!(link.a.capture.frames(), 1);
+    let (_, data) = link.a.capture.read(Format::Pcap, 0);
+    let frame = &data[24 + 16..];
+    assert_eq!(frame.len(), 14 + 20 + 8 + 20 + 8);
+    assert_eq!(&frame[14 + 20..14 + 20 + 2], [3, 3]);
+}
+
+/// A pipe end that cannot transmit, like a device with a full queue.
+struct Busy(Pipe);
+
+impl Device for Busy {
+    type RxToken<'a> = PipeRxToken;
+    type TxToken<'a> = PipeTxToken<'a>;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.0.receive(timestamp)
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        None
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.0.capabilities()
+    }
+}
+
+#[test]
+fn accepted_frame_survives_full_transmit_queue() {
+    let (mut peer, dev) = Pipe::pair();
+    let sent: Vec<u8> = (0..60).collect();
+    let tx = peer.transmit(Instant::ZERO).unwrap();
+    tx.consume(sent.len(), |buf| buf.copy_from_slice(&sent));
+
+    let mut busy = Busy(dev);
+    let mut firewall = Firewall::new(Medium::Ethernet);
+    let mut filtering = Filtering::new(&mut busy, &mut firewall);
+    let (rx, _) = filtering.receive(Instant::ZERO).expect("frame dropped");
+    assert_eq!(rx.consume(|frame| frame.to_vec()), sent);
+}
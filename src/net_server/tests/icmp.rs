@@ -0,0 +1,201 @@
+use libc::{
+    AF_INET, AF_INET6, EAFNOSUPPORT, EAGAIN, EINVAL, EOPNOTSUPP, EPROTONOSUPPORT, IPPROTO_ICMP,
+    IPPROTO_ICMPV6, IPPROTO_UDP, MSG_PEEK, SOCK_DGRAM, SOCK_RAW, SOCK_STREAM,
+};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{
+    IpEndpoint, IpListenEndpoint, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr,
+};
+
+mod common;
+use common::{static_config, Link, CLIENT};
+
+/// Two dual-stack hosts on one link.
+fn dual_stack() -> Link {
+    Link::new(
+        &static_config(1, &["10.0.0.1/24", "fd00::1/64"]),
+        &static_config(2, &["10.0.0.2/24", "fd00::2/64"]),
+    )
+}
+
+/// An echo request with the given type byte, identifier 0xdead, sequence
+/// number 7 and a short payload. The checksum is left for the stack.
+fn echo_request(ty: u8) -> Vec<u8> {
+    let mut msg = vec![ty, 0, 0, 0, 0xde, 0xad, 0, 7];
+    msg.extend_from_slice(b"hello");
+    msg
+}
+
+#[test]
+fn ping_sockets() {
+    for (domain, protocol, request, reply, peer) in [
+        (AF_INET, IPPROTO_ICMP, 8, 0, "10.0.0.2"),
+        (AF_INET6, IPPROTO_ICMPV6, 128, 129, "fd00::2"),
+    ] {
+        let mut link = dual_stack();
+        let fd = link
+            .a
+            .sockets
+            .socket(CLIENT, domain, SOCK_DGRAM, protocol)
+            .unwrap();
+        let dest = IpEndpoint::new(peer.parse().unwrap(), 0);
+        let msg = echo_request(request);
+        assert_eq!(link.a.sockets.sendto(CLIENT, fd, &msg, dest), Ok(msg.len()));
+        let ident = link.a.sockets.getsockname(CLIENT, fd).unwrap().port;
+        link.run(20);
+
+        // b answered by itself; the reply carries the socket's identifier.
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            link.a.sockets.recvfrom(CLIENT, fd, &mut buf, MSG_PEEK),
+            Err(EOPNOTSUPP)
+        );
+        let (n, src) = link.a.sockets.recvfrom(CLIENT, fd, &mut buf, 0).unwrap();
+        assert_eq!(src, dest);
+        assert_eq!(n, msg.len());
+        assert_eq!(buf[0], reply);
+        assert_eq!(buf[4..6], ident.to_be_bytes());
+        assert_eq!(buf[6..n], msg[6..]);
+        assert_eq!(
+            link.a.sockets.recvfrom(CLIENT, fd, &mut buf, 0),
+            Err(EAGAIN)
+        );
+    }
+}
+
+#[test]
+fn ping_socket_errors() {
+    let mut link = dual_stack();
+    let sockets = &mut link.a.sockets;
+    for (domain, ty, protocol) in [
+        (AF_INET, SOCK_DGRAM, IPPROTO_ICMPV6),
+        (AF_INET6, SOCK_DGRAM, IPPROTO_ICMP),
+        (AF_INET, SOCK_STREAM, IPPROTO_ICMP),
+        (AF_INET, SOCK_RAW, 0),
+    ] {
+        let res = sockets.socket(CLIENT, domain, ty, protocol);
+        assert_eq!(res, Err(EPROTONOSUPPORT));
+    }
+
+    let fd = sockets
+        .socket(CLIENT, AF_INET6, SOCK_DGRAM, IPPROTO_ICMPV6)
+        .unwrap();
+    let v4 = IpEndpoint::new("10.0.0.2".parse().unwrap(), 0);
+    let v6 = IpEndpoint::new("fd00::2".parse().unwrap(), 0);
+    let res = sockets.sendto(CLIENT, fd, &echo_request(128), v4);
+    assert_eq!(res, Err(EAFNOSUPPORT));
+    // Only echo requests of the right family can be sent.
+    assert_eq!(
+        sockets.sendto(CLIENT, fd, &echo_request(8), v6),
+        Err(EINVAL)
+    );
+    assert_eq!(sockets.sendto(CLIENT, fd, &[128, 0, 0], v6), Err(EINVAL));
+
+    // The identifier is bound like a port.
+    let ident = IpListenEndpoint {
+        addr: None,
+        port: 4242,
+    };
+    let other = sockets
+        .socket(CLIENT, AF_INET6, SOCK_DGRAM, IPPROTO_ICMPV6)
+        .unwrap();
+    sockets.bind(CLIENT, other, ident).unwrap();
+    assert_eq!(sockets.getsockname(CLIENT, other).unwrap().port, 4242);
+}
+
+#[test]
+fn raw_sockets() {
+    let mut link = dual_stack();
+    let raw_icmp = link
+        .b
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_RAW, IPPROTO_ICMP)
+        .unwrap();
+    let any = IpListenEndpoint {
+        addr: None,
+        port: 0,
+    };
+    assert_eq!(link.b.sockets.bind(CLIENT, raw_icmp, any), Err(EOPNOTSUPP));
+
+    // A raw ICMP socket sees the echo request the stack answers.
+    let ping = link
+        .a
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, IPPROTO_ICMP)
+        .unwrap();
+    let dest = IpEndpoint::new("10.0.0.2".parse().unwrap(), 0);
+    link.a
+        .sockets
+        .sendto(CLIENT, ping, &echo_request(8), dest)
+        .unwrap();
+    link.run(20);
+    let mut buf = [0u8; 64];
+    let (n, src) = link
+        .b
+        .sockets
+        .recvfrom(CLIENT, raw_icmp, &mut buf, 0)
+        .unwrap();
+    assert_eq!(src, IpEndpoint::new("10.0.0.1".parse().unwrap(), 0));
+    assert_eq!(n, 20 + 8 + 5);
+    assert_eq!(buf[0], 0x45);
+    assert_eq!(buf[9], 1);
+    assert_eq!(buf[20], 8);
+    assert!(link.a.sockets.recvfrom(CLIENT, ping, &mut buf, 0).is_ok());
+
+    // Raw sockets send packets complete with their IP header.
+    let server = link
+        .b
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    let port = IpListenEndpoint {
+        addr: None,
+        port: 9,
+    };
+    link.b.sockets.bind(CLIENT, server, port).unwrap();
+    let raw_udp = link
+        .a
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_RAW, IPPROTO_UDP)
+        .unwrap();
+    let (src_addr, dst_addr) = (Ipv4Address::new(10, 0, 0, 1), Ipv4Address::new(10, 0, 0, 2));
+    let udp = UdpRepr {
+        src_port: 1234,
+        dst_port: 9,
+    };
+    let ip = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        next_header: IpProtocol::Udp,
+        payload_len: udp.header_len() + 3,
+        hop_limit: 64,
+    };
+    let mut packet = vec![0u8; ip.buffer_len() + ip.payload_len];
+    let caps = ChecksumCapabilities::default();
+    ip.emit(&mut Ipv4Packet::new_unchecked(&mut packet[..]), &caps);
+    udp.emit(
+        &mut UdpPacket::new_unchecked(&mut packet[ip.buffer_len()..]),
+        &src_addr.into(),
+        &dst_addr.into(),
+        3,
+        |buf| buf.copy_from_slice(b"raw"),
+        &caps,
+    );
+    assert_eq!(
+        link.a.sockets.sendto(CLIENT, raw_udp, &packet[..20], dest),
+        Err(EINVAL),
+        "truncated packet"
+    );
+    assert_eq!(
+        link.a.sockets.sendto(CLIENT, raw_udp, &packet, dest),
+        Ok(packet.len())
+    );
+    link.run(20);
+    let (n, from) = link
+        .b
+        .sockets
+        .recvfrom(CLIENT, server, &mut buf, 0)
+        .unwrap();
+    assert_eq!(&buf[..n], b"raw");
+    assert_eq!(from, IpEndpoint::new(src_addr.into(), 1234));
+}
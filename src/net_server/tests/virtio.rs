@@ -0,0 +1,251 @@
+use libc::{AF_INET, EAGAIN, SOCK_DGRAM, SOCK_STREAM};
+use net_server::{NetConfig, Stack};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, IpEndpoint};
+use virtio_frontend::config::ConfigSpace;
+use virtio_frontend::device::net::CONFIG_LEN;
+use virtio_frontend::device::{FramePipe, NetDevice, SimDevice};
+use virtio_frontend::mmio::MmioTransport;
+use virtio_frontend::status::Status;
+use virtio_net::{NetTransport, VirtioDevice, VirtioNet};
+
+mod common;
+use common::{listen_on, static_config, CLIENT, STEPS};
+
+/// The driver's view of a simulated virtio-net device.
+struct Sim {
+    mmio: MmioTransport<SimDevice<NetDevice>>,
+    status: Status,
+}
+
+impl Sim {
+    fn device(&mut self) -> &mut SimDevice<NetDevice> {
+        self.mmio.regs_mut()
+    }
+}
+
+impl NetTransport for Sim {
+    fn device_features(&mut self) -> u64 {
+        self.mmio.reset();
+        self.status = Status::ACKNOWLEDGE | Status::DRIVER;
+        self.mmio.set_status(self.status.bits() as u32);
+        self.mmio.device_features()
+    }
+
+    fn negotiate(&mut self, features: u64) -> u64 {
+        self.mmio.set_driver_features(features);
+        self.status |= Status::FEATURES_OK;
+        self.mmio.set_status(self.status.bits() as u32);
+        features
+    }
+
+    fn read_config(&mut self, offset: u64) -> Option<u32> {
+        self.mmio.read_le(offset as usize).ok()
+    }
+
+    fn setup_queue(&mut self, index: u16, size: u16, desc: u64, avail: u64, used: u64) {
+        self.mmio
+            .setup_queue_at(index, size, desc, avail, used)
+            .unwrap();
+    }
+
+    fn driver_ok(&mut self) {
+        self.status |= Status::DRIVER_OK;
+        self.mmio.set_status(self.status.bits() as u32);
+    }
+
+    fn notify(&mut self, index: u16) {
+        self.mmio.notify(index);
+    }
+
+    fn ack_interrupt(&mut self) {
+        self.mmio.ack_interrupt();
+    }
+}
+
+fn driver(mac: u8, pipe: FramePipe) -> VirtioNet<Sim> {
+    let model = NetDevice::new([0x02, 0, 0, 0, 0, mac], pipe);
+    // SAFETY: the driver's queues live in the `VirtioNet` owning the
+    // device and outlive it.
+    let device = unsafe { SimDevice::new(model) };
+    let mmio = MmioTransport::new(device)
+        .unwrap()
+        .with_config_len(CONFIG_LEN);
+    VirtioNet::with_transport(Sim {
+        mmio,
+        status: Status::empty(),
+    })
+}
+
+/// One end of the link: a stack on a driver on a simulated device.
+struct Host {
+    net: VirtioNet<Sim>,
+    stack: Stack,
+}
+
+impl Host {
+    fn new(mac: u8, addr: &str, pipe: FramePipe, now: Instant) -> Self {
+        let mut net = driver(mac, pipe);
+        let cfg = NetConfig {
+            mac: net.mac().unwrap(),
+            ..static_config(mac, &[addr])
+        };
+        let stack = Stack::new(&cfg, &mut VirtioDevice { net: &mut net }, now);
+        Host { net, stack }
+    }
+
+    fn step(&mut self, now: Instant) {
+        // The device takes frames off the wire, then raises its interrupt.
+        let device = self.net.transport_mut().device();
+        device.poll();
+        if device.regs().interrupt_status() != 0 {
+            self.net.interrupt();
+        }
+        self.stack
+            .poll(&mut VirtioDevice { net: &mut self.net }, now);
+    }
+}
+
+/// Two hosts whose virtio-net devices are connected through a frame pipe.
+struct Link {
+    a: Host,
+    b: Host,
+    now: Instant,
+}
+
+impl Link {
+    fn new() -> Self {
+        let (pipe_a, pipe_b) = FramePipe::pair();
+        let now = Instant::ZERO;
+        Link {
+            a: Host::new(1, "10.0.0.1/24", pipe_a, now),
+            b: Host::new(2, "10.0.0.2/24", pipe_b, now),
+            now,
+        }
+    }
+
+    fn step(&mut self) {
+        self.now += Duration::from_millis(1);
+        self.a.step(self.now);
+        self.b.step(self.now);
+    }
+
+    /// Retry `op` while it would block, advancing the clock in between.
+    fn wait<T>(&mut self, mut op: impl FnMut(&mut Self) -> Result<T, i32>) -> T {
+        for _ in 0..STEPS {
+            match op(self) {
+                Err(EAGAIN) => self.step(),
+                res => return res.expect("socket operation failed"),
+            }
+        }
+        panic!("timed out");
+    }
+}
+
+#[test]
+fn mac_from_device() {
+    let (pipe, _) = FramePipe::pair();
+    let net = driver(7, pipe);
+    assert_eq!(net.mac(), Some(EthernetAddress([0x02, 0, 0, 0, 0, 7])));
+    assert!(net.link_up());
+    assert!(net.checksum_offload());
+}
+
+#[test]
+fn tcp_over_virtio() {
+    let mut link = Link::new();
+    let listener = link
+        .b
+        .stack
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+        .unwrap();
+    let sockets = &mut link.b.stack.sockets;
+    sockets.bind(CLIENT, listener, listen_on(7)).unwrap();
+    sockets.listen(CLIENT, listener, 1).unwrap();
+
+    let client = link
+        .a
+        .stack
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_STREAM, 0)
+        .unwrap();
+    let server: IpEndpoint = "10.0.0.2:7".parse().unwrap();
+    let a = &mut link.a.stack;
+    let _ = a.sockets.connect(&mut a.iface, CLIENT, client, server);
+    let (conn, _) = link.wait(|l| l.b.stack.sockets.accept(CLIENT, listener));
+
+    // Many more frames than the driver has receive buffers, so they have to
+    // be posted again as frames are taken out.
+    let request: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    let mut received = Vec::new();
+    let mut sent = 0;
+    let mut buf = [0u8; 1000];
+    for _ in 0..STEPS {
+        if received.len() == request.len() {
+            break;
+        }
+        match link.a.stack.sockets.send(CLIENT, client, &request[sent..]) {
+            Ok(n) => sent += n,
+            Err(err) => assert_eq!(err, EAGAIN),
+        }
+        match link.b.stack.sockets.recvfrom(CLIENT, conn, &mut buf, 0) {
+            Ok((n, _)) => received.extend_from_slice(&buf[..n]),
+            Err(err) => assert_eq!(err, EAGAIN),
+        }
+        link.step();
+    }
+    assert_eq!(received, request);
+    assert_eq!(link.b.net.transport_mut().device().model().rx_dropped(), 0);
+}
+
+#[test]
+fn link_status_follows_device() {
+    let mut link = Link::new();
+    let server = link
+        .b
+        .stack
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    link.b
+        .stack
+        .sockets
+        .bind(CLIENT, server, listen_on(53))
+        .unwrap();
+    let client = link
+        .a
+        .stack
+        .sockets
+        .socket(CLIENT, AF_INET, SOCK_DGRAM, 0)
+        .unwrap();
+    let dest: IpEndpoint = "10.0.0.2:53".parse().unwrap();
+
+    // The driver picks up the new status with the configuration interrupt.
+    let device = link.b.net.transport_mut().device();
+    device.model_mut().set_link_up(false);
+    device.refresh_config();
+    link.step();
+    assert!(!link.b.net.link_up());
+
+    link.a
+        .stack
+        .sockets
+        .sendto(CLIENT, client, b"query", dest)
+        .unwrap();
+    for _ in 0..100 {
+        link.step();
+    }
+    let mut buf = [0u8; 64];
+    let res = link.b.stack.sockets.recvfrom(CLIENT, server, &mut buf, 0);
+    assert_eq!(res.map(|(n, _)| n), Err(EAGAIN));
+    assert!(link.b.net.transport_mut().device().model().rx_dropped() > 0);
+
+    // Once the link is back the datagram gets through.
+    let device = link.b.net.transport_mut().device();
+    device.model_mut().set_link_up(true);
+    device.refresh_config();
+    let (n, _) = link.wait(|l| l.b.stack.sockets.recvfrom(CLIENT, server, &mut buf, 0));
+    assert!(link.b.net.link_up());
+    assert_eq!(&buf[..n], b"query");
+}
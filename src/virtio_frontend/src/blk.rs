@@ -0,0 +1,379 @@
+//! virtio-blk driver on top of a virtio-mmio transport, and [`BlkDisk`],
+//! byte-granular `Read`/`Write`/`Seek` access to it as filesystems expect.
+
+use core::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::config::{ConfigError, ConfigSpace};
+use crate::features::{BlkFeatures, DeviceType, Features};
+use crate::mmio::{MmioError, MmioTransport, Registers};
+use crate::queue::{self, Queue, QueueError, VirtqDesc, VIRTQ_DESC_F_WRITE};
+use crate::status::Status;
+
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Requests address the device in units of 512 bytes, whatever its block
+/// size.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Offset of the capacity, in sectors, in the configuration space.
+pub const CONFIG_CAPACITY: usize = 0;
+pub const CONFIG_SEG_MAX: usize = 12;
+pub const CONFIG_BLK_SIZE: usize = 20;
+/// Configuration space up to and including `blk_size`.
+pub const CONFIG_LEN: usize = 24;
+
+/// Largest queue the driver sets up.
+const MAX_QUEUE_SIZE: u16 = 64;
+
+/// Request header as defined by the virtio block specification.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlkReqHeader {
+    pub req_type: u32,
+    pub reserved: u32,
+    pub sector: u64,
+}
+
+impl BlkReqHeader {
+    /// Parse a little-endian header.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            req_type: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            reserved: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            sector: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlkError {
+    Mmio(MmioError),
+    Queue(QueueError),
+    Config(ConfigError),
+    /// The device is no block device.
+    WrongDevice(u32),
+    /// The device did not accept the negotiated features.
+    FeaturesRejected,
+    /// The request lies beyond the end of the device or is no whole number
+    /// of sectors.
+    OutOfRange,
+    ReadOnly,
+    /// The device completed the request with this status.
+    Device(u8),
+}
+
+impl fmt::Display for BlkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mmio(e) => write!(f, "transport: {:?}", e),
+            Self::Queue(e) => write!(f, "queue: {}", e),
+            Self::Config(e) => write!(f, "config: {}", e),
+            Self::WrongDevice(id) => write!(f, "device {} is no block device", id),
+            Self::FeaturesRejected => write!(f, "features rejected"),
+            Self::OutOfRange => write!(f, "request out of range"),
+            Self::ReadOnly => write!(f, "device is read-only"),
+            Self::Device(status) => write!(f, "device status {}", status),
+        }
+    }
+}
+
+impl std::error::Error for BlkError {}
+
+impl From<MmioError> for BlkError {
+    fn from(e: MmioError) -> Self {
+        Self::Mmio(e)
+    }
+}
+
+impl From<QueueError> for BlkError {
+    fn from(e: QueueError) -> Self {
+        Self::Queue(e)
+    }
+}
+
+impl From<ConfigError> for BlkError {
+    fn from(e: ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+
+/// Driver for a virtio-blk device with one request in flight at a time.
+pub struct BlkDriver<R: Registers> {
+    transport: MmioTransport<R>,
+    queue: Box<dyn Queue + Send>,
+    features: u64,
+    capacity: u64,
+    wait: Option<Box<dyn FnMut() + Send>>,
+}
+
+impl<R: Registers> BlkDriver<R> {
+    /// Initialise the device behind `transport` and set up its request
+    /// queue.
+    pub fn new(transport: MmioTransport<R>) -> Result<Self, BlkError> {
+        let mut transport = transport.with_config_len(CONFIG_LEN);
+        if transport.device_id() != DeviceType::Block as u32 {
+            return Err(BlkError::WrongDevice(transport.device_id()));
+        }
+        transport.reset();
+        let mut status = Status::ACKNOWLEDGE;
+        transport.set_status(status.bits() as u32);
+        status |= Status::DRIVER;
+        transport.set_status(status.bits() as u32);
+
+        let supported = (BlkFeatures::RO | BlkFeatures::BLK_SIZE | BlkFeatures::FLUSH).bits()
+            | (Features::VERSION_1
+                | Features::INDIRECT_DESC
+                | Features::EVENT_IDX
+                | Features::RING_PACKED)
+                .bits();
+        let features = transport.device_features() & supported;
+        transport.set_driver_features(features);
+        status |= Status::FEATURES_OK;
+        transport.set_status(status.bits() as u32);
+        if transport.status() & Status::FEATURES_OK.bits() as u32 == 0 {
+            transport.set_status((status | Status::FAILED).bits() as u32);
+            return Err(BlkError::FeaturesRejected);
+        }
+
+        let max = transport.queue_max(0).min(MAX_QUEUE_SIZE);
+        if max == 0 {
+            return Err(MmioError::QueueUnavailable.into());
+        }
+        // Split rings need a power of two.
+        let size = 1 << (15 - max.leading_zeros());
+        let queue = queue::for_features(size, features)?;
+        transport.setup_queue(0, &*queue)?;
+        let capacity = transport.read_le::<u64>(CONFIG_CAPACITY)?;
+        status |= Status::DRIVER_OK;
+        transport.set_status(status.bits() as u32);
+        Ok(Self {
+            transport,
+            queue,
+            features,
+            capacity,
+            wait: None,
+        })
+    }
+
+    /// Call `wait` while a request is outstanding, such as to block on the
+    /// device interrupt. Without it the driver polls.
+    pub fn with_wait(mut self, wait: impl FnMut() + Send + 'static) -> Self {
+        self.wait = Some(Box::new(wait));
+        self
+    }
+
+    pub fn transport(&self) -> &MmioTransport<R> {
+        &self.transport
+    }
+
+    /// Capacity in sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.features & BlkFeatures::RO.bits() != 0
+    }
+
+    /// Read whole sectors starting at `sector` into `buf`.
+    pub fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlkError> {
+        self.check_range(sector, buf.len())?;
+        let data = VirtqDesc {
+            addr: buf.as_mut_ptr() as u64,
+            len: buf.len() as u32,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        self.request(VIRTIO_BLK_T_IN, sector, Some(data))
+    }
+
+    /// Write whole sectors starting at `sector` from `buf`.
+    pub fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlkError> {
+        if self.read_only() {
+            return Err(BlkError::ReadOnly);
+        }
+        self.check_range(sector, buf.len())?;
+        let data = VirtqDesc {
+            addr: buf.as_ptr() as u64,
+            len: buf.len() as u32,
+            flags: 0,
+            next: 0,
+        };
+        self.request(VIRTIO_BLK_T_OUT, sector, Some(data))
+    }
+
+    /// Make completed writes durable. A no-op for devices without a cache
+    /// to flush.
+    pub fn flush(&mut self) -> Result<(), BlkError> {
+        if self.features & BlkFeatures::FLUSH.bits() == 0 {
+            return Ok(());
+        }
+        self.request(VIRTIO_BLK_T_FLUSH, 0, None)
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), BlkError> {
+        let sectors = (len / SECTOR_SIZE) as u64;
+        let in_range = sector
+            .checked_add(sectors)
+            .is_some_and(|end| end <= self.capacity);
+        if len == 0 || !len.is_multiple_of(SECTOR_SIZE) || u32::try_from(len).is_err() || !in_range
+        {
+            return Err(BlkError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Submit a request and wait for its completion.
+    fn request(
+        &mut self,
+        req_type: u32,
+        sector: u64,
+        data: Option<VirtqDesc>,
+    ) -> Result<(), BlkError> {
+        let header = BlkReqHeader {
+            req_type: req_type.to_le(),
+            reserved: 0,
+            sector: sector.to_le(),
+        };
+        let mut status = VIRTIO_BLK_S_IOERR;
+        let mut chain = vec![VirtqDesc {
+            addr: &header as *const _ as u64,
+            len: core::mem::size_of::<BlkReqHeader>() as u32,
+            flags: 0,
+            next: 0,
+        }];
+        chain.extend(data);
+        chain.push(VirtqDesc {
+            addr: &mut status as *mut _ as u64,
+            len: 1,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        });
+        let token = self.queue.add(&chain)?;
+        if self.queue.needs_notify() {
+            self.transport.notify(0);
+        }
+        loop {
+            if let Some((used, _)) = self.queue.pop_used() {
+                debug_assert_eq!(used, token);
+                break;
+            }
+            match self.wait.as_mut() {
+                Some(wait) => wait(),
+                None => core::hint::spin_loop(),
+            }
+        }
+        self.transport.ack_interrupt();
+        // The device wrote the status behind the compiler's back.
+        match unsafe { core::ptr::read_volatile(&status) } {
+            VIRTIO_BLK_S_OK => Ok(()),
+            status => Err(BlkError::Device(status)),
+        }
+    }
+}
+
+fn io_error(e: BlkError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Byte-granular access to a [`BlkDriver`]; partial sectors are read,
+/// modified and written back.
+pub struct BlkDisk<R: Registers> {
+    driver: BlkDriver<R>,
+    pos: u64,
+}
+
+impl<R: Registers> BlkDisk<R> {
+    pub fn new(driver: BlkDriver<R>) -> Self {
+        Self { driver, pos: 0 }
+    }
+
+    pub fn driver(&self) -> &BlkDriver<R> {
+        &self.driver
+    }
+
+    pub fn into_driver(self) -> BlkDriver<R> {
+        self.driver
+    }
+
+    /// Size in bytes.
+    pub fn len(&self) -> u64 {
+        self.driver.capacity() * SECTOR_SIZE as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<R: Registers> Read for BlkDisk<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() && self.pos < self.len() {
+            let sector = self.pos / SECTOR_SIZE as u64;
+            let offset = (self.pos % SECTOR_SIZE as u64) as usize;
+            let mut blk = [0u8; SECTOR_SIZE];
+            self.driver
+                .read_sectors(sector, &mut blk)
+                .map_err(io_error)?;
+            let count = (SECTOR_SIZE - offset).min(buf.len() - done);
+            buf[done..done + count].copy_from_slice(&blk[offset..offset + count]);
+            self.pos += count as u64;
+            done += count;
+        }
+        Ok(done)
+    }
+}
+
+impl<R: Registers> Write for BlkDisk<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            if self.pos >= self.len() {
+                if done == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                break;
+            }
+            let sector = self.pos / SECTOR_SIZE as u64;
+            let offset = (self.pos % SECTOR_SIZE as u64) as usize;
+            let count = (SECTOR_SIZE - offset).min(buf.len() - done);
+            let mut blk = [0u8; SECTOR_SIZE];
+            if count < SECTOR_SIZE {
+                // Read-modify-write when only part of the sector changes.
+                self.driver
+                    .read_sectors(sector, &mut blk)
+                    .map_err(io_error)?;
+            }
+            blk[offset..offset + count].copy_from_slice(&buf[done..done + count]);
+            self.driver.write_sectors(sector, &blk).map_err(io_error)?;
+            self.pos += count as u64;
+            done += count;
+        }
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.driver.flush().map_err(io_error)
+    }
+}
+
+impl<R: Registers> Seek for BlkDisk<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+            SeekFrom::End(off) => self.len().checked_add_signed(off),
+        };
+        self.pos = new.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,126 @@
+//! Device configuration space access.
+//!
+//! Transports provide raw byte access through [`ConfigSpace`], which adds the
+//! bounds checks, the typed little-endian fields and the ConfigGeneration
+//! retry loop on top.
+
+use core::fmt;
+
+/// Give up a read after the generation changed this many times in a row.
+pub const MAX_GENERATION_RETRIES: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The access does not fit into the configuration space.
+    OutOfRange {
+        offset: usize,
+        len: usize,
+        size: usize,
+    },
+    /// The device kept changing the configuration during the read.
+    Unstable,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfRange { offset, len, size } => write!(
+                f,
+                "config access of {} bytes at {} exceeds {} bytes",
+                len, offset, size
+            ),
+            Self::Unstable => write!(f, "config generation kept changing"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Integer fields of the configuration space, stored little-endian.
+pub trait ConfigField: Copy {
+    const SIZE: usize;
+
+    fn from_le(bytes: &[u8]) -> Self;
+    fn to_le(self, out: &mut [u8]);
+}
+
+macro_rules! config_field {
+    ($($t:ty),*) => {$(
+        impl ConfigField for $t {
+            const SIZE: usize = core::mem::size_of::<$t>();
+
+            fn from_le(bytes: &[u8]) -> Self {
+                let mut raw = [0u8; core::mem::size_of::<$t>()];
+                raw.copy_from_slice(bytes);
+                <$t>::from_le_bytes(raw)
+            }
+
+            fn to_le(self, out: &mut [u8]) {
+                out.copy_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*};
+}
+
+config_field!(u8, u16, u32, u64);
+
+pub trait ConfigSpace {
+    /// Size of the configuration space in bytes.
+    fn config_len(&self) -> usize;
+
+    fn config_generation(&self) -> u32;
+
+    /// Copy bytes out of the configuration space, already bounds checked.
+    fn read_raw(&self, offset: usize, data: &mut [u8]);
+
+    /// Copy bytes into the configuration space, already bounds checked.
+    fn write_raw(&mut self, offset: usize, data: &[u8]);
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), ConfigError> {
+        let size = self.config_len();
+        match offset.checked_add(len) {
+            Some(end) if end <= size => Ok(()),
+            _ => Err(ConfigError::OutOfRange { offset, len, size }),
+        }
+    }
+
+    /// Read a consistent snapshot of `data.len()` bytes at `offset`,
+    /// repeating the read while the device changes the generation under it.
+    fn read_config(&self, offset: usize, data: &mut [u8]) -> Result<(), ConfigError> {
+        self.check_range(offset, data.len())?;
+        for _ in 0..MAX_GENERATION_RETRIES {
+            let generation = self.config_generation();
+            self.read_raw(offset, data);
+            if self.config_generation() == generation {
+                return Ok(());
+            }
+        }
+        Err(ConfigError::Unstable)
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) -> Result<(), ConfigError> {
+        self.check_range(offset, data.len())?;
+        self.write_raw(offset, data);
+        Ok(())
+    }
+
+    /// Read a little-endian field.
+    fn read_le<T: ConfigField>(&self, offset: usize) -> Result<T, ConfigError>
+    where
+        Self: Sized,
+    {
+        let mut raw = [0u8; 8];
+        self.read_config(offset, &mut raw[..T::SIZE])?;
+        Ok(T::from_le(&raw[..T::SIZE]))
+    }
+
+    /// Write a little-endian field.
+    fn write_le<T: ConfigField>(&mut self, offset: usize, value: T) -> Result<(), ConfigError>
+    where
+        Self: Sized,
+    {
+        let mut raw = [0u8; 8];
+        value.to_le(&mut raw[..T::SIZE]);
+        self.write_config(offset, &raw[..T::SIZE])
+    }
+}
@@ -0,0 +1,514 @@
+//! virtio-console driver on top of a virtio-mmio transport.
+//!
+//! Without `VIRTIO_CONSOLE_F_MULTIPORT` the device has a single port, 0,
+//! which is the console. With it the device announces ports, their names
+//! and whether the host side is connected over the control queues; the
+//! driver answers and opens ports as its users ask. Port data is buffered
+//! by the driver, [`ConsoleDriver::read`] and [`ConsoleDriver::write`]
+//! never block.
+
+use core::fmt;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::config::{ConfigError, ConfigSpace};
+use crate::features::{ConsoleFeatures, DeviceType, Features};
+use crate::mmio::{MmioError, MmioTransport, Registers, INTERRUPT_CONFIG_CHANGE};
+use crate::queue::{QueueError, RxQueue, TxQueue};
+use crate::status::Status;
+
+// Control events.
+pub const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+pub const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+pub const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+pub const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+pub const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+pub const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+pub const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+pub const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// Configuration space.
+pub const CONFIG_COLS: usize = 0;
+pub const CONFIG_ROWS: usize = 2;
+pub const CONFIG_MAX_NR_PORTS: usize = 4;
+pub const CONFIG_EMERG_WR: usize = 8;
+pub const CONFIG_LEN: usize = 12;
+
+/// Queues of the control channel, between those of port 0 and port 1.
+pub const CONTROL_RX_QUEUE: u16 = 2;
+pub const CONTROL_TX_QUEUE: u16 = 3;
+
+/// Ports the driver sets up queues for.
+pub const MAX_PORTS: u32 = 16;
+/// Largest queue the driver sets up.
+const MAX_QUEUE_SIZE: u16 = 16;
+/// Size of a receive buffer. Control messages carrying a port name are the
+/// largest messages that have to fit into one.
+pub const RX_BUF_SIZE: usize = 256;
+/// Largest chunk a single write puts on a transmit queue.
+pub const TX_CHUNK: usize = 4096;
+
+/// Receive and transmit queue of port `id`.
+pub fn port_queues(id: u32) -> (u16, u16) {
+    let rx = if id == 0 { 0 } else { 2 * id as u16 + 2 };
+    (rx, rx + 1)
+}
+
+/// Message on the control queues as defined by the virtio specification.
+/// `PORT_NAME` messages are followed by the name, `RESIZE` messages by the
+/// number of rows and columns.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConsoleControl {
+    pub id: u32,
+    pub event: u16,
+    pub value: u16,
+}
+
+impl ConsoleControl {
+    pub const SIZE: usize = 8;
+
+    pub fn new(id: u32, event: u16, value: u16) -> Self {
+        Self { id, event, value }
+    }
+
+    /// Parse a little-endian message.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE)?;
+        Some(Self {
+            id: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            event: u16::from_le_bytes([bytes[4], bytes[5]]),
+            value: u16::from_le_bytes([bytes[6], bytes[7]]),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.id.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.event.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleError {
+    Mmio(MmioError),
+    Queue(QueueError),
+    Config(ConfigError),
+    /// The device is no console.
+    WrongDevice(u32),
+    /// The device did not accept the negotiated features.
+    FeaturesRejected,
+    /// The device announced no such port, or removed it.
+    NoPort(u32),
+    /// The port has not been opened.
+    Closed(u32),
+}
+
+impl fmt::Display for ConsoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mmio(e) => write!(f, "transport: {:?}", e),
+            Self::Queue(e) => write!(f, "queue: {}", e),
+            Self::Config(e) => write!(f, "config: {}", e),
+            Self::WrongDevice(id) => write!(f, "device {} is no console", id),
+            Self::FeaturesRejected => write!(f, "features rejected"),
+            Self::NoPort(id) => write!(f, "no port {}", id),
+            Self::Closed(id) => write!(f, "port {} is closed", id),
+        }
+    }
+}
+
+impl std::error::Error for ConsoleError {}
+
+impl From<MmioError> for ConsoleError {
+    fn from(e: MmioError) -> Self {
+        Self::Mmio(e)
+    }
+}
+
+impl From<QueueError> for ConsoleError {
+    fn from(e: QueueError) -> Self {
+        Self::Queue(e)
+    }
+}
+
+impl From<ConfigError> for ConsoleError {
+    fn from(e: ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+
+/// A port the device announced.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConsolePort {
+    pub id: u32,
+    pub name: Option<String>,
+    /// The port is the console.
+    pub console: bool,
+    /// A program on the host side has the port open.
+    pub host_connected: bool,
+    /// The driver opened the port.
+    pub open: bool,
+}
+
+/// Driver for a virtio-console device, with multiport support.
+pub struct ConsoleDriver<R: Registers> {
+    transport: MmioTransport<R>,
+    features: u64,
+    /// Queues of every port the device may have, by port id.
+    rx: Vec<RxQueue>,
+    tx: Vec<TxQueue>,
+    control: Option<(RxQueue, TxQueue)>,
+    ports: BTreeMap<u32, ConsolePort>,
+    /// Data received on each port and not read yet.
+    input: HashMap<u32, VecDeque<u8>>,
+    /// Columns and rows of the console.
+    size: Option<(u16, u16)>,
+    /// Ports removed since the last `take_removed`.
+    removed: Vec<u32>,
+}
+
+impl<R: Registers> ConsoleDriver<R> {
+    /// Initialise the device behind `transport`, set up the queues of all
+    /// its ports and, with multiport support, tell the device the driver is
+    /// ready for port announcements.
+    pub fn new(transport: MmioTransport<R>) -> Result<Self, ConsoleError> {
+        let mut transport = transport.with_config_len(CONFIG_LEN);
+        if transport.device_id() != DeviceType::Console as u32 {
+            return Err(ConsoleError::WrongDevice(transport.device_id()));
+        }
+        transport.reset();
+        let mut status = Status::ACKNOWLEDGE;
+        transport.set_status(status.bits() as u32);
+        status |= Status::DRIVER;
+        transport.set_status(status.bits() as u32);
+
+        let supported = (ConsoleFeatures::SIZE | ConsoleFeatures::MULTIPORT).bits()
+            | (Features::VERSION_1
+                | Features::INDIRECT_DESC
+                | Features::EVENT_IDX
+                | Features::RING_PACKED)
+                .bits();
+        let features = transport.device_features() & supported;
+        transport.set_driver_features(features);
+        status |= Status::FEATURES_OK;
+        transport.set_status(status.bits() as u32);
+        if transport.status() & Status::FEATURES_OK.bits() as u32 == 0 {
+            transport.set_status((status | Status::FAILED).bits() as u32);
+            return Err(ConsoleError::FeaturesRejected);
+        }
+
+        let multiport = features & ConsoleFeatures::MULTIPORT.bits() != 0;
+        let nr_ports = if multiport {
+            transport
+                .read_le::<u32>(CONFIG_MAX_NR_PORTS)?
+                .clamp(1, MAX_PORTS)
+        } else {
+            1
+        };
+        let mut rx = Vec::new();
+        let mut tx = Vec::new();
+        for id in 0..nr_ports {
+            let (rx_index, tx_index) = port_queues(id);
+            rx.push(RxQueue::new(
+                queue_size(&mut transport, rx_index)?,
+                features,
+                RX_BUF_SIZE,
+            )?);
+            tx.push(TxQueue::new(
+                queue_size(&mut transport, tx_index)?,
+                features,
+            )?);
+            transport.setup_queue(rx_index, &*rx[id as usize].queue)?;
+            transport.setup_queue(tx_index, &*tx[id as usize].queue)?;
+        }
+        let control = if multiport {
+            let control_rx = RxQueue::new(
+                queue_size(&mut transport, CONTROL_RX_QUEUE)?,
+                features,
+                RX_BUF_SIZE,
+            )?;
+            let control_tx = TxQueue::new(queue_size(&mut transport, CONTROL_TX_QUEUE)?, features)?;
+            transport.setup_queue(CONTROL_RX_QUEUE, &*control_rx.queue)?;
+            transport.setup_queue(CONTROL_TX_QUEUE, &*control_tx.queue)?;
+            Some((control_rx, control_tx))
+        } else {
+            None
+        };
+        status |= Status::DRIVER_OK;
+        transport.set_status(status.bits() as u32);
+
+        let mut driver = Self {
+            transport,
+            features,
+            rx,
+            tx,
+            control,
+            ports: BTreeMap::new(),
+            input: HashMap::new(),
+            size: None,
+            removed: Vec::new(),
+        };
+        for id in 0..nr_ports {
+            let (rx_index, _) = port_queues(id);
+            driver.transport.notify(rx_index);
+        }
+        driver.read_size()?;
+        if multiport {
+            driver.transport.notify(CONTROL_RX_QUEUE);
+            driver.send_control(ConsoleControl::new(0, VIRTIO_CONSOLE_DEVICE_READY, 1))?;
+        } else {
+            // The single port is the console and always connected.
+            driver.ports.insert(
+                0,
+                ConsolePort {
+                    id: 0,
+                    name: None,
+                    console: true,
+                    host_connected: true,
+                    open: true,
+                },
+            );
+        }
+        Ok(driver)
+    }
+
+    pub fn transport(&self) -> &MmioTransport<R> {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut MmioTransport<R> {
+        &mut self.transport
+    }
+
+    pub fn multiport(&self) -> bool {
+        self.features & ConsoleFeatures::MULTIPORT.bits() != 0
+    }
+
+    /// Columns and rows of the console, if the device reports them.
+    pub fn size(&self) -> Option<(u16, u16)> {
+        self.size
+    }
+
+    /// The ports the device announced, by id.
+    pub fn ports(&self) -> impl Iterator<Item = &ConsolePort> {
+        self.ports.values()
+    }
+
+    pub fn port(&self, id: u32) -> Option<&ConsolePort> {
+        self.ports.get(&id)
+    }
+
+    /// Id of the port called `name`.
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.ports()
+            .find(|p| p.name.as_deref() == Some(name))
+            .map(|p| p.id)
+    }
+
+    /// Ports the device removed since the last call, in order. A later port
+    /// may reuse the id of a removed one.
+    pub fn take_removed(&mut self) -> Vec<u32> {
+        core::mem::take(&mut self.removed)
+    }
+
+    /// Handle an interrupt: pick up a changed console size and process what
+    /// the device delivered.
+    pub fn interrupt(&mut self) -> Result<(), ConsoleError> {
+        if self.transport.ack_interrupt() & INTERRUPT_CONFIG_CHANGE != 0 {
+            self.read_size()?;
+        }
+        self.poll()
+    }
+
+    /// Process control messages and received data, and free the buffers of
+    /// completed writes.
+    pub fn poll(&mut self) -> Result<(), ConsoleError> {
+        while let Some(msg) = self.control.as_mut().and_then(|(rx, _)| rx.pop()) {
+            self.handle_control(&msg)?;
+        }
+        for id in 0..self.rx.len() as u32 {
+            let mut notify = false;
+            while let Some(data) = self.rx[id as usize].pop() {
+                notify = true;
+                // Data for ports the device did not announce is dropped.
+                if self.ports.contains_key(&id) {
+                    self.input.entry(id).or_default().extend(data);
+                }
+            }
+            if notify && self.rx[id as usize].queue.needs_notify() {
+                self.transport.notify(port_queues(id).0);
+            }
+        }
+        for tx in &mut self.tx {
+            tx.reap();
+        }
+        if let Some((rx, tx)) = &mut self.control {
+            tx.reap();
+            if rx.queue.needs_notify() {
+                self.transport.notify(CONTROL_RX_QUEUE);
+            }
+        }
+        Ok(())
+    }
+
+    /// Open port `id` and tell the host side about it.
+    pub fn open(&mut self, id: u32) -> Result<(), ConsoleError> {
+        let port = self.ports.get_mut(&id).ok_or(ConsoleError::NoPort(id))?;
+        if port.open {
+            return Ok(());
+        }
+        port.open = true;
+        self.send_control(ConsoleControl::new(id, VIRTIO_CONSOLE_PORT_OPEN, 1))
+    }
+
+    /// Close port `id`. Data received but not read is discarded.
+    pub fn close(&mut self, id: u32) -> Result<(), ConsoleError> {
+        let multiport = self.multiport();
+        let port = self.ports.get_mut(&id).ok_or(ConsoleError::NoPort(id))?;
+        if !port.open || !multiport {
+            return Ok(());
+        }
+        port.open = false;
+        self.input.remove(&id);
+        self.send_control(ConsoleControl::new(id, VIRTIO_CONSOLE_PORT_OPEN, 0))
+    }
+
+    /// Bytes received on port `id` and not read yet.
+    pub fn available(&self, id: u32) -> usize {
+        self.input.get(&id).map_or(0, VecDeque::len)
+    }
+
+    /// Copy received data of port `id` into `buf`. Returns 0 if there is
+    /// none.
+    pub fn read(&mut self, id: u32, buf: &mut [u8]) -> Result<usize, ConsoleError> {
+        self.check_open(id)?;
+        let Some(input) = self.input.get_mut(&id) else {
+            return Ok(0);
+        };
+        let len = buf.len().min(input.len());
+        for (dst, src) in buf.iter_mut().zip(input.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    /// Queue up to [`TX_CHUNK`] bytes of `data` for port `id`. Fails with
+    /// [`QueueError::Full`] while the transmit queue is full.
+    pub fn write(&mut self, id: u32, data: &[u8]) -> Result<usize, ConsoleError> {
+        self.check_open(id)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let len = data.len().min(TX_CHUNK);
+        let tx = &mut self.tx[id as usize];
+        tx.push(data[..len].to_vec())?;
+        if tx.queue.needs_notify() {
+            self.transport.notify(port_queues(id).1);
+        }
+        Ok(len)
+    }
+
+    fn check_open(&self, id: u32) -> Result<(), ConsoleError> {
+        match self.ports.get(&id) {
+            Some(port) if port.open => Ok(()),
+            Some(_) => Err(ConsoleError::Closed(id)),
+            None => Err(ConsoleError::NoPort(id)),
+        }
+    }
+
+    fn read_size(&mut self) -> Result<(), ConsoleError> {
+        if self.features & ConsoleFeatures::SIZE.bits() != 0 {
+            let cols = self.transport.read_le::<u16>(CONFIG_COLS)?;
+            let rows = self.transport.read_le::<u16>(CONFIG_ROWS)?;
+            self.size = Some((cols, rows));
+        }
+        Ok(())
+    }
+
+    fn handle_control(&mut self, msg: &[u8]) -> Result<(), ConsoleError> {
+        let Some(ctrl) = ConsoleControl::from_bytes(msg) else {
+            return Ok(());
+        };
+        let id = ctrl.id;
+        match ctrl.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                // Ports beyond the queues set up are refused.
+                let ok = (id as usize) < self.rx.len();
+                if ok {
+                    self.ports.insert(
+                        id,
+                        ConsolePort {
+                            id,
+                            ..ConsolePort::default()
+                        },
+                    );
+                }
+                self.send_control(ConsoleControl::new(
+                    id,
+                    VIRTIO_CONSOLE_PORT_READY,
+                    ok as u16,
+                ))?;
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => {
+                if self.ports.remove(&id).is_some() {
+                    self.removed.push(id);
+                }
+                self.input.remove(&id);
+            }
+            VIRTIO_CONSOLE_CONSOLE_PORT => {
+                if let Some(port) = self.ports.get_mut(&id) {
+                    port.console = true;
+                }
+                // The console is opened right away.
+                self.open(id)?;
+            }
+            VIRTIO_CONSOLE_RESIZE => {
+                let rows = msg.get(8..10).map(|b| u16::from_le_bytes([b[0], b[1]]));
+                let cols = msg.get(10..12).map(|b| u16::from_le_bytes([b[0], b[1]]));
+                if let (Some(rows), Some(cols)) = (rows, cols) {
+                    self.size = Some((cols, rows));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(&id) {
+                    port.host_connected = ctrl.value != 0;
+                }
+            }
+            VIRTIO_CONSOLE_PORT_NAME => {
+                if let Some(port) = self.ports.get_mut(&id) {
+                    let name = &msg[ConsoleControl::SIZE..];
+                    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                    port.name = Some(String::from_utf8_lossy(&name[..end]).into_owned());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn send_control(&mut self, ctrl: ConsoleControl) -> Result<(), ConsoleError> {
+        let Some((_, tx)) = &mut self.control else {
+            return Ok(());
+        };
+        tx.push(ctrl.to_bytes().to_vec())?;
+        if tx.queue.needs_notify() {
+            self.transport.notify(CONTROL_TX_QUEUE);
+        }
+        Ok(())
+    }
+}
+
+/// Size of queue `index`: the largest power of two the device and the
+/// driver support.
+fn queue_size<R: Registers>(
+    transport: &mut MmioTransport<R>,
+    index: u16,
+) -> Result<u16, ConsoleError> {
+    let max = transport.queue_max(index).min(MAX_QUEUE_SIZE);
+    if max == 0 {
+        return Err(MmioError::QueueUnavailable.into());
+    }
+    Ok(1 << (15 - max.leading_zeros()))
+}
@@ -0,0 +1,222 @@
+//! virtio-blk device model backed by memory or an image file.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::{gather, scatter, writable_len, DeviceModel};
+use crate::blk::{
+    BlkReqHeader, CONFIG_LEN, SECTOR_SIZE, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK,
+    VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT,
+};
+use crate::features::{BlkFeatures, DeviceType, Features};
+use crate::queue::{DescChain, DeviceRing};
+
+/// Largest queue the model offers.
+pub const BLK_QUEUE_MAX: u16 = 64;
+/// Length of the id string returned for GET_ID requests.
+pub const BLK_ID_LEN: usize = 20;
+
+/// Storage behind a [`BlkDevice`].
+pub trait BlockBackend {
+    /// Size in bytes.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BlockBackend for Vec<u8> {
+    fn len(&self) -> u64 {
+        self.as_slice().len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let start = offset as usize;
+        buf.copy_from_slice(&self[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let start = offset as usize;
+        self[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// A host image file. Its size is fixed when the device is created.
+impl BlockBackend for File {
+    fn len(&self) -> u64 {
+        self.metadata().map_or(0, |m| m.len())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// virtio-blk device with a single request queue.
+pub struct BlkDevice<B: BlockBackend> {
+    backend: B,
+    capacity: u64,
+    read_only: bool,
+    id: [u8; BLK_ID_LEN],
+}
+
+impl<B: BlockBackend> BlkDevice<B> {
+    /// A writable device of `backend`, whose size is rounded down to whole
+    /// sectors.
+    pub fn new(backend: B) -> Self {
+        let capacity = backend.len() / SECTOR_SIZE as u64;
+        let mut id = [0; BLK_ID_LEN];
+        id[..8].copy_from_slice(b"simblk0\0");
+        Self {
+            backend,
+            capacity,
+            read_only: false,
+            id,
+        }
+    }
+
+    /// A device rejecting writes.
+    pub fn read_only(backend: B) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(backend)
+        }
+    }
+
+    /// Capacity in sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+
+    /// Execute the request of `chain` and return the bytes to write back,
+    /// ending with the status byte.
+    fn handle(&mut self, chain: &DescChain) -> Vec<u8> {
+        // SAFETY: the chain came from a ring attached by `SimDevice`.
+        let request = unsafe { gather(chain) };
+        let header_len = core::mem::size_of::<BlkReqHeader>();
+        let writable = writable_len(chain);
+        if request.len() < header_len || writable == 0 {
+            return vec![VIRTIO_BLK_S_IOERR];
+        }
+        let header = BlkReqHeader::from_bytes(&request[..header_len]);
+        let data = &request[header_len..];
+        // The status byte is the last byte the device writes, whatever
+        // precedes it is data for reads.
+        let mut reply = vec![0; writable - 1];
+        let status = match header.req_type {
+            VIRTIO_BLK_T_IN => self.transfer(header.sector, &mut reply, true),
+            VIRTIO_BLK_T_OUT if self.read_only => VIRTIO_BLK_S_IOERR,
+            VIRTIO_BLK_T_OUT => self.transfer(header.sector, &mut data.to_vec(), false),
+            VIRTIO_BLK_T_FLUSH => match self.backend.flush() {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let len = reply.len().min(BLK_ID_LEN);
+                reply[..len].copy_from_slice(&self.id[..len]);
+                VIRTIO_BLK_S_OK
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        reply.push(status);
+        reply
+    }
+
+    fn transfer(&mut self, sector: u64, data: &mut [u8], read: bool) -> u8 {
+        let sectors = (data.len() / SECTOR_SIZE) as u64;
+        let in_range = sector
+            .checked_add(sectors)
+            .is_some_and(|end| end <= self.capacity);
+        if !data.len().is_multiple_of(SECTOR_SIZE) || !in_range {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        let offset = sector * SECTOR_SIZE as u64;
+        let result = if read {
+            self.backend.read_at(offset, data)
+        } else {
+            self.backend.write_at(offset, data)
+        };
+        match result {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(_) => VIRTIO_BLK_S_IOERR,
+        }
+    }
+}
+
+impl<B: BlockBackend> DeviceModel for BlkDevice<B> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+
+    fn features(&self) -> u64 {
+        let mut blk = BlkFeatures::SEG_MAX | BlkFeatures::BLK_SIZE | BlkFeatures::FLUSH;
+        blk.set(BlkFeatures::RO, self.read_only);
+        let common = Features::VERSION_1
+            | Features::INDIRECT_DESC
+            | Features::EVENT_IDX
+            | Features::RING_PACKED;
+        blk.bits() | common.bits()
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; CONFIG_LEN];
+        config[0..8].copy_from_slice(&self.capacity.to_le_bytes());
+        // seg_max: the header and the status take two descriptors.
+        config[12..16].copy_from_slice(&(BLK_QUEUE_MAX as u32 - 2).to_le_bytes());
+        config[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        config
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn queue_max(&self) -> u16 {
+        BLK_QUEUE_MAX
+    }
+
+    fn process(&mut self, _index: u16, ring: &mut dyn DeviceRing) {
+        loop {
+            let chain = match ring.pop_avail() {
+                Ok(Some(chain)) => chain,
+                Ok(None) => break,
+                // The malformed chain is gone, there is nothing to answer.
+                Err(_) => continue,
+            };
+            let reply = self.handle(&chain);
+            // SAFETY: as in `handle`.
+            let written = unsafe { scatter(&chain, &reply) };
+            ring.push_used(chain.head, written as u32);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! virtio-console device model with multiport support.
+//!
+//! Ports are added and removed on the host side at any time; the model
+//! announces them to the driver over the control queues once it reported
+//! ready. What the driver writes to a port is collected for the host to
+//! take, what the host writes fills the port's receive buffers.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use super::{gather, scatter, writable_len, DeviceModel};
+use crate::console::{
+    port_queues, ConsoleControl, CONFIG_LEN, CONFIG_MAX_NR_PORTS, CONFIG_ROWS, CONTROL_RX_QUEUE,
+    CONTROL_TX_QUEUE, VIRTIO_CONSOLE_CONSOLE_PORT, VIRTIO_CONSOLE_DEVICE_ADD,
+    VIRTIO_CONSOLE_DEVICE_READY, VIRTIO_CONSOLE_DEVICE_REMOVE, VIRTIO_CONSOLE_PORT_NAME,
+    VIRTIO_CONSOLE_PORT_OPEN, VIRTIO_CONSOLE_PORT_READY, VIRTIO_CONSOLE_RESIZE,
+};
+use crate::features::{ConsoleFeatures, DeviceType, Features};
+use crate::queue::{DescChain, DeviceRing};
+
+/// Largest queue the model offers.
+pub const CONSOLE_QUEUE_MAX: u16 = 16;
+
+/// Host side of a port.
+#[derive(Default)]
+struct HostPort {
+    name: Option<String>,
+    console: bool,
+    host_connected: bool,
+    /// The driver accepted the port.
+    ready: bool,
+    /// The driver opened the port.
+    guest_open: bool,
+    /// Data for the driver.
+    input: VecDeque<u8>,
+    /// Data the driver wrote.
+    output: Vec<u8>,
+}
+
+/// virtio-console device with up to `max_ports` ports.
+pub struct ConsoleDevice {
+    max_ports: u32,
+    size: (u16, u16),
+    features: u64,
+    ports: BTreeMap<u32, HostPort>,
+    /// The driver sent `DEVICE_READY`.
+    driver_ready: bool,
+    /// Control messages for the driver.
+    control: VecDeque<Vec<u8>>,
+    /// Receive buffers taken from the rings but not filled yet, per queue.
+    rx_bufs: BTreeMap<u16, VecDeque<DescChain>>,
+}
+
+impl ConsoleDevice {
+    /// A device without ports of a console of `cols` by `rows`.
+    pub fn new(max_ports: u32, cols: u16, rows: u16) -> Self {
+        Self {
+            max_ports: max_ports.max(1),
+            size: (cols, rows),
+            features: 0,
+            ports: BTreeMap::new(),
+            driver_ready: false,
+            control: VecDeque::new(),
+            rx_bufs: BTreeMap::new(),
+        }
+    }
+
+    fn multiport(&self) -> bool {
+        self.features & ConsoleFeatures::MULTIPORT.bits() != 0
+    }
+
+    /// Add a port and return its id, or `None` if all ports are in use.
+    /// Without multiport support only port 0 is used by the driver.
+    pub fn add_port(&mut self, name: Option<&str>, console: bool) -> Option<u32> {
+        let id = (0..self.max_ports).find(|id| !self.ports.contains_key(id))?;
+        self.ports.insert(
+            id,
+            HostPort {
+                name: name.map(str::to_string),
+                console,
+                host_connected: true,
+                ..HostPort::default()
+            },
+        );
+        if self.driver_ready {
+            self.send(ConsoleControl::new(id, VIRTIO_CONSOLE_DEVICE_ADD, 0), &[]);
+        }
+        Some(id)
+    }
+
+    pub fn remove_port(&mut self, id: u32) {
+        if self.ports.remove(&id).is_some() && self.driver_ready {
+            self.send(
+                ConsoleControl::new(id, VIRTIO_CONSOLE_DEVICE_REMOVE, 0),
+                &[],
+            );
+        }
+    }
+
+    /// Connect or disconnect the host side of port `id`.
+    pub fn set_host_connected(&mut self, id: u32, connected: bool) {
+        let Some(port) = self.ports.get_mut(&id) else {
+            return;
+        };
+        port.host_connected = connected;
+        if port.ready {
+            let msg = ConsoleControl::new(id, VIRTIO_CONSOLE_PORT_OPEN, connected as u16);
+            self.send(msg, &[]);
+        }
+    }
+
+    /// Change the console size and tell the driver.
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.size = (cols, rows);
+        let console = self.ports.iter().find(|(_, p)| p.console && p.ready);
+        if let Some((&id, _)) = console {
+            let mut size = rows.to_le_bytes().to_vec();
+            size.extend_from_slice(&cols.to_le_bytes());
+            self.send(ConsoleControl::new(id, VIRTIO_CONSOLE_RESIZE, 0), &size);
+        }
+    }
+
+    /// Whether the driver opened port `id`.
+    pub fn guest_open(&self, id: u32) -> bool {
+        self.ports.get(&id).is_some_and(|p| p.guest_open)
+    }
+
+    /// Queue `data` for the driver on port `id`.
+    pub fn host_write(&mut self, id: u32, data: &[u8]) {
+        if let Some(port) = self.ports.get_mut(&id) {
+            port.input.extend(data);
+        }
+    }
+
+    /// Take what the driver wrote to port `id`.
+    pub fn host_read(&mut self, id: u32) -> Vec<u8> {
+        self.ports
+            .get_mut(&id)
+            .map(|p| core::mem::take(&mut p.output))
+            .unwrap_or_default()
+    }
+
+    fn send(&mut self, ctrl: ConsoleControl, extra: &[u8]) {
+        let mut msg = ctrl.to_bytes().to_vec();
+        msg.extend_from_slice(extra);
+        self.control.push_back(msg);
+    }
+
+    /// Port whose queue `index` is, and whether it is the receive queue.
+    fn port_of(&self, index: u16) -> Option<(u32, bool)> {
+        (0..self.max_ports).find_map(|id| {
+            let (rx, tx) = port_queues(id);
+            (index == rx || index == tx).then_some((id, index == rx))
+        })
+    }
+
+    fn handle_control(&mut self, ctrl: ConsoleControl) {
+        let id = ctrl.id;
+        match ctrl.event {
+            VIRTIO_CONSOLE_DEVICE_READY if ctrl.value != 0 => {
+                self.driver_ready = true;
+                let ids: Vec<u32> = self.ports.keys().copied().collect();
+                for id in ids {
+                    self.send(ConsoleControl::new(id, VIRTIO_CONSOLE_DEVICE_ADD, 0), &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if ctrl.value != 0 => {
+                let Some(port) = self.ports.get_mut(&id) else {
+                    return;
+                };
+                port.ready = true;
+                let (console, connected) = (port.console, port.host_connected);
+                let name = port.name.clone();
+                if console {
+                    self.send(ConsoleControl::new(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1), &[]);
+                }
+                if let Some(name) = name {
+                    let ctrl = ConsoleControl::new(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    self.send(ctrl, name.as_bytes());
+                }
+                if connected {
+                    self.send(ConsoleControl::new(id, VIRTIO_CONSOLE_PORT_OPEN, 1), &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(&id) {
+                    port.guest_open = ctrl.value != 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Take the chains of `ring` into the receive buffers of queue `index`.
+    fn stash(&mut self, index: u16, ring: &mut dyn DeviceRing) {
+        let bufs = self.rx_bufs.entry(index).or_default();
+        loop {
+            match ring.pop_avail() {
+                Ok(Some(chain)) => bufs.push_back(chain),
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// Deliver control messages, one per buffer.
+    fn deliver_control(&mut self, ring: &mut dyn DeviceRing) {
+        let bufs = self.rx_bufs.entry(CONTROL_RX_QUEUE).or_default();
+        while !self.control.is_empty() {
+            let Some(chain) = bufs.pop_front() else {
+                break;
+            };
+            let msg = self.control.pop_front().unwrap();
+            // SAFETY: the chain came from a ring attached by `SimDevice`.
+            let written = unsafe { scatter(&chain, &msg) };
+            ring.push_used(chain.head, written as u32);
+        }
+    }
+
+    /// Fill the receive buffers of port `id` with its input.
+    fn deliver_input(&mut self, id: u32, index: u16, ring: &mut dyn DeviceRing) {
+        let multiport = self.multiport();
+        let Some(port) = self.ports.get_mut(&id) else {
+            return;
+        };
+        // The driver only takes data on ports it accepted.
+        if multiport && !port.ready {
+            return;
+        }
+        let bufs = self.rx_bufs.entry(index).or_default();
+        while !port.input.is_empty() {
+            let Some(chain) = bufs.pop_front() else {
+                break;
+            };
+            let len = writable_len(&chain).min(port.input.len());
+            let data: Vec<u8> = port.input.drain(..len).collect();
+            // SAFETY: as in `deliver_control`.
+            let written = unsafe { scatter(&chain, &data) };
+            ring.push_used(chain.head, written as u32);
+        }
+    }
+
+    /// Take the chains of a transmit queue, passing their data to `sink`.
+    fn drain(ring: &mut dyn DeviceRing, mut sink: impl FnMut(Vec<u8>)) {
+        loop {
+            let chain = match ring.pop_avail() {
+                Ok(Some(chain)) => chain,
+                Ok(None) => break,
+                Err(_) => continue,
+            };
+            // SAFETY: as in `deliver_control`.
+            sink(unsafe { gather(&chain) });
+            ring.push_used(chain.head, 0);
+        }
+    }
+}
+
+impl DeviceModel for ConsoleDevice {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Console
+    }
+
+    fn features(&self) -> u64 {
+        let console = ConsoleFeatures::SIZE | ConsoleFeatures::MULTIPORT;
+        let common = Features::VERSION_1 | Features::INDIRECT_DESC | Features::EVENT_IDX;
+        console.bits() | common.bits()
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; CONFIG_LEN];
+        config[0..2].copy_from_slice(&self.size.0.to_le_bytes());
+        config[CONFIG_ROWS..CONFIG_ROWS + 2].copy_from_slice(&self.size.1.to_le_bytes());
+        config[CONFIG_MAX_NR_PORTS..CONFIG_MAX_NR_PORTS + 4]
+            .copy_from_slice(&self.max_ports.to_le_bytes());
+        config
+    }
+
+    fn num_queues(&self) -> usize {
+        2 * (self.max_ports as usize + 1)
+    }
+
+    fn queue_max(&self) -> u16 {
+        CONSOLE_QUEUE_MAX
+    }
+
+    fn set_features(&mut self, features: u64) {
+        self.features = features;
+    }
+
+    fn process(&mut self, index: u16, ring: &mut dyn DeviceRing) {
+        match index {
+            CONTROL_RX_QUEUE => {
+                self.stash(index, ring);
+                self.deliver_control(ring);
+            }
+            CONTROL_TX_QUEUE => {
+                let mut msgs = Vec::new();
+                Self::drain(ring, |data| msgs.extend(ConsoleControl::from_bytes(&data)));
+                for ctrl in msgs {
+                    self.handle_control(ctrl);
+                }
+            }
+            _ => match self.port_of(index) {
+                Some((id, true)) => {
+                    self.stash(index, ring);
+                    self.deliver_input(id, index, ring);
+                }
+                Some((id, false)) => {
+                    let mut output = Vec::new();
+                    Self::drain(ring, |data| output.extend(data));
+                    // Data for ports the host removed is lost.
+                    if let Some(port) = self.ports.get_mut(&id) {
+                        port.output.extend(output);
+                    }
+                }
+                None => {}
+            },
+        }
+    }
+
+    fn reset(&mut self) {
+        self.features = 0;
+        self.driver_ready = false;
+        self.control.clear();
+        self.rx_bufs.clear();
+        for port in self.ports.values_mut() {
+            port.ready = false;
+            port.guest_open = false;
+        }
+    }
+}
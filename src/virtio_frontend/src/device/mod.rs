@@ -0,0 +1,237 @@
+//! Device-side models of virtio devices.
+//!
+//! A [`DeviceModel`] serves the chains of its queues. [`SimDevice`] puts a
+//! model behind the virtio-mmio registers of a [`SimRegisters`], so a driver
+//! talking to it through an [`MmioTransport`](crate::mmio::MmioTransport)
+//! runs exactly as against hardware: the model works on the very queue
+//! memory the driver set up and raises the used buffer interrupt.
+
+pub mod blk;
+pub mod console;
+pub mod net;
+pub mod rng;
+pub mod vsock;
+
+use crate::features::DeviceType;
+use crate::mmio::{
+    Registers, SimRegisters, INTERRUPT_USED_BUFFER, QUEUE_NOTIFY, QUEUE_READY, STATUS,
+};
+use crate::queue::{self, DescChain, DeviceRing};
+
+pub use blk::{BlkDevice, BlockBackend};
+pub use console::ConsoleDevice;
+pub use net::{FramePipe, NetDevice};
+pub use rng::{EntropySource, FakeSource, RngDevice};
+pub use vsock::VsockDevice;
+
+pub trait DeviceModel {
+    fn device_type(&self) -> DeviceType;
+
+    /// Features the device offers.
+    fn features(&self) -> u64;
+
+    /// Initial contents of the configuration space.
+    fn config(&self) -> Vec<u8>;
+
+    fn num_queues(&self) -> usize;
+
+    /// Largest size of each queue.
+    fn queue_max(&self) -> u16;
+
+    /// The features the driver accepted, known once it sets up queues.
+    fn set_features(&mut self, _features: u64) {}
+
+    /// Serve the chains the driver made available on queue `index`.
+    fn process(&mut self, index: u16, ring: &mut dyn DeviceRing);
+
+    /// Forget all state of the current driver.
+    fn reset(&mut self) {}
+}
+
+/// A device model behind simulated virtio-mmio registers.
+///
+/// Once the driver marks a queue ready the device side of it is attached,
+/// and every notification for the queue lets the model serve it right away.
+pub struct SimDevice<M: DeviceModel> {
+    regs: SimRegisters,
+    model: M,
+    rings: Vec<Option<Box<dyn DeviceRing>>>,
+    interrupts: usize,
+}
+
+impl<M: DeviceModel> SimDevice<M> {
+    /// Present `model` as a virtio-mmio device.
+    ///
+    /// # Safety
+    ///
+    /// The queue and buffer addresses the driver hands to the device must
+    /// point to memory of this address space that stays valid while the
+    /// device uses it, as is the case for queues set up through
+    /// [`MmioTransport::setup_queue`](crate::mmio::MmioTransport::setup_queue)
+    /// that outlive the device or are disabled before.
+    pub unsafe fn new(model: M) -> Self {
+        let config = model.config();
+        let mut regs = SimRegisters::new(
+            model.device_type() as u32,
+            model.features(),
+            model.num_queues(),
+            model.queue_max() as u32,
+            config.len(),
+        );
+        for (i, b) in config.iter().enumerate() {
+            regs.write8(crate::mmio::CONFIG + i, *b);
+        }
+        let rings = (0..model.num_queues()).map(|_| None).collect();
+        Self {
+            regs,
+            model,
+            rings,
+            interrupts: 0,
+        }
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    pub fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    pub fn regs(&self) -> &SimRegisters {
+        &self.regs
+    }
+
+    /// Publish the model's current configuration space, which starts a new
+    /// generation and raises a configuration change interrupt.
+    pub fn refresh_config(&mut self) {
+        let config = self.model.config();
+        self.regs.set_config(0, &config);
+    }
+
+    /// Number of used buffer interrupts raised so far.
+    pub fn interrupts(&self) -> usize {
+        self.interrupts
+    }
+
+    /// Let the model serve all attached queues, as if it ran on its own.
+    /// Models use this to complete chains without a notification, such as
+    /// receive buffers filled by incoming data.
+    pub fn poll(&mut self) {
+        for index in 0..self.rings.len() {
+            self.serve(index as u16);
+        }
+    }
+
+    fn serve(&mut self, index: u16) {
+        let Some(Some(ring)) = self.rings.get_mut(index as usize) else {
+            return;
+        };
+        self.model.process(index, &mut **ring);
+        if ring.needs_interrupt() {
+            self.interrupts += 1;
+            self.regs.interrupt(INTERRUPT_USED_BUFFER);
+        }
+    }
+
+    /// Attach the device side of queue `index`, which the driver just
+    /// marked ready.
+    fn attach(&mut self, index: u32) {
+        let (Some(q), Some(slot)) = (
+            self.regs.queue(index as usize),
+            self.rings.get_mut(index as usize),
+        ) else {
+            return;
+        };
+        self.model.set_features(self.regs.driver_features());
+        // SAFETY: the driver vouches for the queue addresses, see `new`.
+        *slot = unsafe {
+            queue::device_ring_for(
+                q.num as u16,
+                self.regs.driver_features(),
+                q.desc,
+                q.driver,
+                q.device,
+            )
+        }
+        .ok();
+    }
+}
+
+impl<M: DeviceModel> Registers for SimDevice<M> {
+    fn read32(&self, offset: usize) -> u32 {
+        self.regs.read32(offset)
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        self.regs.write32(offset, value);
+        match offset {
+            QUEUE_READY => {
+                let index = self.regs.queue_sel();
+                if value & 1 != 0 {
+                    self.attach(index);
+                } else if let Some(slot) = self.rings.get_mut(index as usize) {
+                    *slot = None;
+                }
+            }
+            QUEUE_NOTIFY => {
+                for index in self.regs.take_notifications() {
+                    self.serve(index as u16);
+                }
+            }
+            STATUS if value == 0 => {
+                self.rings.iter_mut().for_each(|r| *r = None);
+                self.model.reset();
+            }
+            _ => {}
+        }
+    }
+
+    fn read8(&self, offset: usize) -> u8 {
+        self.regs.read8(offset)
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        self.regs.write8(offset, value);
+    }
+}
+
+/// Copy the buffers of `chain` the device reads into one vector.
+///
+/// # Safety
+///
+/// The buffer addresses must be valid, see [`SimDevice::new`].
+pub unsafe fn gather(chain: &DescChain) -> Vec<u8> {
+    let mut data = Vec::new();
+    for d in chain.readable() {
+        data.extend_from_slice(core::slice::from_raw_parts(
+            d.addr as *const u8,
+            d.len as usize,
+        ));
+    }
+    data
+}
+
+/// Total length of the buffers of `chain` the device writes.
+pub fn writable_len(chain: &DescChain) -> usize {
+    chain.writable().map(|d| d.len as usize).sum()
+}
+
+/// Copy `data` into the buffers of `chain` the device writes, as far as
+/// they reach. Returns the number of bytes written.
+///
+/// # Safety
+///
+/// The buffer addresses must be valid, see [`SimDevice::new`].
+pub unsafe fn scatter(chain: &DescChain, data: &[u8]) -> usize {
+    let mut done = 0;
+    for d in chain.writable() {
+        let count = (d.len as usize).min(data.len() - done);
+        core::ptr::copy_nonoverlapping(data[done..].as_ptr(), d.addr as *mut u8, count);
+        done += count;
+        if done == data.len() {
+            break;
+        }
+    }
+    done
+}
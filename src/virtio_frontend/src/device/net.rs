@@ -0,0 +1,282 @@
+//! virtio-net device model whose wire is an in-memory [`FramePipe`].
+//!
+//! Frames the driver transmits come out of the pipe, frames pushed into the
+//! pipe's other end fill the driver's receive buffers. Two models connected
+//! by [`FramePipe::pair`] form a link without any network.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::{gather, scatter, writable_len, DeviceModel};
+use crate::features::{DeviceType, Features, NetFeatures};
+use crate::queue::{DescChain, DeviceRing};
+
+pub const RX_QUEUE: u16 = 0;
+pub const TX_QUEUE: u16 = 1;
+/// Largest queue the model offers.
+pub const NET_QUEUE_MAX: u16 = 256;
+
+/// Configuration space: the MAC address followed by the link status.
+pub const CONFIG_MAC: usize = 0;
+pub const CONFIG_STATUS: usize = 6;
+pub const CONFIG_LEN: usize = 8;
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+/// Header length without, and with, the `num_buffers` field.
+pub const NET_HDR_LEN: usize = 10;
+pub const NET_HDR_LEN_MRG: usize = 12;
+
+/// Frames queued in one direction of a pipe. Further frames are dropped,
+/// like on a congested link.
+pub const PIPE_FRAMES: usize = 64;
+
+type Frames = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// One end of an in-memory Ethernet link.
+pub struct FramePipe {
+    rx: Frames,
+    tx: Frames,
+}
+
+impl FramePipe {
+    /// Two ends connected to each other.
+    pub fn pair() -> (FramePipe, FramePipe) {
+        let a = Frames::default();
+        let b = Frames::default();
+        (
+            FramePipe {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            FramePipe { rx: b, tx: a },
+        )
+    }
+
+    /// Put a frame on the wire. Returns false if it was dropped.
+    pub fn send(&self, frame: Vec<u8>) -> bool {
+        let mut queue = self.tx.lock().unwrap();
+        if queue.len() >= PIPE_FRAMES {
+            return false;
+        }
+        queue.push_back(frame);
+        true
+    }
+
+    /// Take the next frame off the wire.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.rx.lock().unwrap().pop_front()
+    }
+
+    /// Frames waiting to be received.
+    pub fn pending(&self) -> usize {
+        self.rx.lock().unwrap().len()
+    }
+
+    fn front_len(&self) -> Option<usize> {
+        self.rx.lock().unwrap().front().map(Vec::len)
+    }
+}
+
+/// virtio-net device with one receive and one transmit queue.
+pub struct NetDevice {
+    mac: [u8; 6],
+    pipe: FramePipe,
+    link_up: bool,
+    features: u64,
+    /// Receive buffers taken from the ring but not filled yet.
+    rx_bufs: VecDeque<DescChain>,
+    rx_dropped: usize,
+}
+
+impl NetDevice {
+    pub fn new(mac: [u8; 6], pipe: FramePipe) -> Self {
+        Self {
+            mac,
+            pipe,
+            link_up: true,
+            features: 0,
+            rx_bufs: VecDeque::new(),
+            rx_dropped: 0,
+        }
+    }
+
+    pub fn pipe(&self) -> &FramePipe {
+        &self.pipe
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    /// Change the link status. The driver learns about it once the new
+    /// configuration is published, see
+    /// [`SimDevice::refresh_config`](super::SimDevice::refresh_config).
+    pub fn set_link_up(&mut self, up: bool) {
+        self.link_up = up;
+    }
+
+    /// Received frames dropped for lack of buffers or a down link.
+    pub fn rx_dropped(&self) -> usize {
+        self.rx_dropped
+    }
+
+    fn has(&self, feature: NetFeatures) -> bool {
+        self.features & feature.bits() != 0
+    }
+
+    fn hdr_len(&self) -> usize {
+        let mrg = Features::VERSION_1.bits() | NetFeatures::MRG_RXBUF.bits();
+        if self.features & mrg != 0 {
+            NET_HDR_LEN_MRG
+        } else {
+            NET_HDR_LEN
+        }
+    }
+
+    fn transmit(&mut self, ring: &mut dyn DeviceRing) {
+        loop {
+            let chain = match ring.pop_avail() {
+                Ok(Some(chain)) => chain,
+                Ok(None) => break,
+                Err(_) => continue,
+            };
+            // SAFETY: the chain came from a ring attached by `SimDevice`.
+            let data = unsafe { gather(&chain) };
+            if let Some(mut frame) = data.get(self.hdr_len()..).map(<[u8]>::to_vec) {
+                let csum_start = u16::from_le_bytes([data[6], data[7]]) as usize;
+                let csum_offset = u16::from_le_bytes([data[8], data[9]]) as usize;
+                if data[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 && self.has(NetFeatures::CSUM) {
+                    fill_checksum(&mut frame, csum_start, csum_offset);
+                }
+                if self.link_up {
+                    self.pipe.send(frame);
+                }
+            }
+            ring.push_used(chain.head, 0);
+        }
+    }
+
+    fn receive(&mut self, ring: &mut dyn DeviceRing) {
+        loop {
+            match ring.pop_avail() {
+                Ok(Some(chain)) => self.rx_bufs.push_back(chain),
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+        let hdr_len = self.hdr_len();
+        while let Some(len) = self.pipe.front_len() {
+            if !self.link_up {
+                self.pipe.recv();
+                self.rx_dropped += 1;
+                continue;
+            }
+            // Without mergeable buffers a frame has to fit into one buffer.
+            let need = hdr_len + len;
+            let mut count = 0;
+            let mut room = 0;
+            for chain in &self.rx_bufs {
+                if room >= need || (count == 1 && !self.has(NetFeatures::MRG_RXBUF)) {
+                    break;
+                }
+                room += writable_len(chain);
+                count += 1;
+            }
+            if room < need {
+                if count == 1 && !self.has(NetFeatures::MRG_RXBUF) {
+                    self.pipe.recv();
+                    self.rx_dropped += 1;
+                    continue;
+                }
+                // Wait for the driver to post more buffers.
+                break;
+            }
+
+            let frame = self.pipe.recv().unwrap();
+            let mut data = vec![0; hdr_len];
+            if hdr_len == NET_HDR_LEN_MRG {
+                data[10..12].copy_from_slice(&(count as u16).to_le_bytes());
+            }
+            data.extend_from_slice(&frame);
+            let mut done = 0;
+            for chain in self.rx_bufs.drain(..count) {
+                // SAFETY: as in `transmit`.
+                let written = unsafe { scatter(&chain, &data[done..]) };
+                done += written;
+                ring.push_used(chain.head, written as u32);
+            }
+        }
+    }
+}
+
+/// Store the Internet checksum of `frame[start..]` at `start + offset`,
+/// where the driver left the pseudo-header sum.
+fn fill_checksum(frame: &mut [u8], start: usize, offset: usize) {
+    let at = start + offset;
+    if at + 2 > frame.len() {
+        return;
+    }
+    let mut sum = 0u32;
+    let mut chunks = frame[start..].chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    frame[at..at + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+impl DeviceModel for NetDevice {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Net
+    }
+
+    fn features(&self) -> u64 {
+        let net =
+            NetFeatures::CSUM | NetFeatures::MAC | NetFeatures::MRG_RXBUF | NetFeatures::STATUS;
+        let common = Features::VERSION_1 | Features::INDIRECT_DESC | Features::EVENT_IDX;
+        net.bits() | common.bits()
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; CONFIG_LEN];
+        config[CONFIG_MAC..CONFIG_MAC + 6].copy_from_slice(&self.mac);
+        let status = if self.link_up {
+            VIRTIO_NET_S_LINK_UP
+        } else {
+            0
+        };
+        config[CONFIG_STATUS..CONFIG_STATUS + 2].copy_from_slice(&status.to_le_bytes());
+        config
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn queue_max(&self) -> u16 {
+        NET_QUEUE_MAX
+    }
+
+    fn set_features(&mut self, features: u64) {
+        self.features = features;
+    }
+
+    fn process(&mut self, index: u16, ring: &mut dyn DeviceRing) {
+        match index {
+            RX_QUEUE => self.receive(ring),
+            TX_QUEUE => self.transmit(ring),
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.features = 0;
+        self.rx_bufs.clear();
+    }
+}
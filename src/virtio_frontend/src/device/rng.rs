@@ -0,0 +1,156 @@
+//! virtio-rng device model drawing from an [`EntropySource`].
+//!
+//! Requests the source cannot serve right away stay with the device until
+//! it can, as a real device holds them until it gathered enough entropy.
+//! [`FakeSource`] produces a deterministic stream for tests and can be
+//! limited to simulate a source that runs dry.
+
+use std::collections::VecDeque;
+
+use super::{scatter, writable_len, DeviceModel};
+use crate::features::{DeviceType, Features};
+use crate::queue::{DescChain, DeviceRing};
+use crate::rng::REQUEST_QUEUE;
+
+/// Largest queue the model offers.
+pub const RNG_QUEUE_MAX: u16 = 8;
+
+/// Where a device gets its entropy from.
+pub trait EntropySource {
+    /// Fill the start of `buf` and return how many bytes were filled, 0 if
+    /// the source has nothing right now.
+    fn fill(&mut self, buf: &mut [u8]) -> usize;
+}
+
+/// Deterministic source: a splitmix64 stream from a seed. Not random at
+/// all, only meant for tests.
+pub struct FakeSource {
+    state: u64,
+    /// Bytes the source still produces, unlimited if `None`.
+    budget: Option<usize>,
+}
+
+impl FakeSource {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            budget: None,
+        }
+    }
+
+    /// Produce only `bytes` more bytes, or without limit.
+    pub fn set_budget(&mut self, bytes: Option<usize>) {
+        self.budget = bytes;
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl EntropySource for FakeSource {
+    fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let len = self.budget.map_or(buf.len(), |b| b.min(buf.len()));
+        for chunk in buf[..len].chunks_mut(8) {
+            let word = self.next().to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+        if let Some(budget) = &mut self.budget {
+            *budget -= len;
+        }
+        len
+    }
+}
+
+/// virtio-rng device.
+pub struct RngDevice<S: EntropySource> {
+    source: S,
+    /// Requests waiting for entropy.
+    pending: VecDeque<DescChain>,
+    /// Bytes delivered so far.
+    delivered: usize,
+}
+
+impl<S: EntropySource> RngDevice<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            pending: VecDeque::new(),
+            delivered: 0,
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Requests waiting for entropy.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bytes delivered to the driver so far.
+    pub fn delivered(&self) -> usize {
+        self.delivered
+    }
+}
+
+impl<S: EntropySource> DeviceModel for RngDevice<S> {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Entropy
+    }
+
+    fn features(&self) -> u64 {
+        (Features::VERSION_1 | Features::INDIRECT_DESC | Features::EVENT_IDX).bits()
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn queue_max(&self) -> u16 {
+        RNG_QUEUE_MAX
+    }
+
+    fn process(&mut self, index: u16, ring: &mut dyn DeviceRing) {
+        if index != REQUEST_QUEUE {
+            return;
+        }
+        loop {
+            match ring.pop_avail() {
+                Ok(Some(chain)) => self.pending.push_back(chain),
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+        // Requests are served in order, each with as much as the source has.
+        while let Some(chain) = self.pending.front() {
+            let mut data = vec![0; writable_len(chain)];
+            let len = self.source.fill(&mut data);
+            if len == 0 && !data.is_empty() {
+                break;
+            }
+            let chain = self.pending.pop_front().unwrap();
+            // SAFETY: the chain came from a ring attached by `SimDevice`.
+            let written = unsafe { scatter(&chain, &data[..len]) };
+            self.delivered += written;
+            ring.push_used(chain.head, written as u32);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
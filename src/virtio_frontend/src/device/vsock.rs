@@ -0,0 +1,161 @@
+//! virtio-vsock device model whose host side is a packet queue.
+//!
+//! Packets the driver sends are collected for the host to take, packets the
+//! host sends fill the driver's receive buffers. Whatever plays the host,
+//! such as a second socket implementation in a test, exchanges packets
+//! through [`VsockDevice::host_recv`] and [`VsockDevice::host_send`].
+
+use std::collections::{BTreeMap, VecDeque};
+
+use super::{gather, scatter, writable_len, DeviceModel};
+use crate::features::{DeviceType, Features, VsockFeatures};
+use crate::queue::{DescChain, DeviceRing};
+use crate::vsock::{
+    VsockPacket, CONFIG_LEN, EVENT_QUEUE, RX_QUEUE, TX_QUEUE, VIRTIO_VSOCK_EVENT_TRANSPORT_RESET,
+};
+
+/// Largest queue the model offers.
+pub const VSOCK_QUEUE_MAX: u16 = 64;
+
+/// virtio-vsock device of a guest with CID `guest_cid`.
+pub struct VsockDevice {
+    guest_cid: u64,
+    /// Packets for the driver.
+    to_guest: VecDeque<VsockPacket>,
+    /// Packets the driver sent.
+    from_guest: VecDeque<VsockPacket>,
+    /// Events for the driver.
+    events: VecDeque<u32>,
+    /// Buffers taken from the rings but not filled yet, per queue.
+    bufs: BTreeMap<u16, VecDeque<DescChain>>,
+}
+
+impl VsockDevice {
+    pub fn new(guest_cid: u64) -> Self {
+        Self {
+            guest_cid,
+            to_guest: VecDeque::new(),
+            from_guest: VecDeque::new(),
+            events: VecDeque::new(),
+            bufs: BTreeMap::new(),
+        }
+    }
+
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Queue `pkt` for the driver.
+    pub fn host_send(&mut self, pkt: VsockPacket) {
+        self.to_guest.push_back(pkt);
+    }
+
+    /// Take the next packet the driver sent.
+    pub fn host_recv(&mut self) -> Option<VsockPacket> {
+        self.from_guest.pop_front()
+    }
+
+    /// Drop all packets in flight and tell the driver its connections are
+    /// gone, giving the guest a new CID. The new CID is visible once the
+    /// configuration space is refreshed.
+    pub fn transport_reset(&mut self, guest_cid: u64) {
+        self.guest_cid = guest_cid;
+        self.to_guest.clear();
+        self.from_guest.clear();
+        self.events.push_back(VIRTIO_VSOCK_EVENT_TRANSPORT_RESET);
+    }
+
+    /// Take the chains of `ring` into the buffers of queue `index`.
+    fn stash(&mut self, index: u16, ring: &mut dyn DeviceRing) {
+        let bufs = self.bufs.entry(index).or_default();
+        loop {
+            match ring.pop_avail() {
+                Ok(Some(chain)) => bufs.push_back(chain),
+                Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// Fill `bufs` with `msgs`, one message per buffer. Messages that do
+    /// not fit a buffer are dropped.
+    fn deliver<T>(
+        bufs: &mut VecDeque<DescChain>,
+        msgs: &mut VecDeque<T>,
+        encode: impl Fn(T) -> Vec<u8>,
+        ring: &mut dyn DeviceRing,
+    ) {
+        while !msgs.is_empty() {
+            let Some(chain) = bufs.pop_front() else {
+                break;
+            };
+            let msg = encode(msgs.pop_front().unwrap());
+            if msg.len() > writable_len(&chain) {
+                bufs.push_front(chain);
+                continue;
+            }
+            // SAFETY: the chain came from a ring attached by `SimDevice`.
+            let written = unsafe { scatter(&chain, &msg) };
+            ring.push_used(chain.head, written as u32);
+        }
+    }
+}
+
+impl DeviceModel for VsockDevice {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Vsock
+    }
+
+    fn features(&self) -> u64 {
+        let common = Features::VERSION_1 | Features::INDIRECT_DESC | Features::EVENT_IDX;
+        VsockFeatures::STREAM.bits() | common.bits()
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; CONFIG_LEN];
+        config.copy_from_slice(&self.guest_cid.to_le_bytes());
+        config
+    }
+
+    fn num_queues(&self) -> usize {
+        3
+    }
+
+    fn queue_max(&self) -> u16 {
+        VSOCK_QUEUE_MAX
+    }
+
+    fn process(&mut self, index: u16, ring: &mut dyn DeviceRing) {
+        match index {
+            RX_QUEUE => {
+                self.stash(index, ring);
+                let bufs = self.bufs.entry(index).or_default();
+                Self::deliver(bufs, &mut self.to_guest, |p| p.to_bytes(), ring);
+            }
+            TX_QUEUE => loop {
+                let chain = match ring.pop_avail() {
+                    Ok(Some(chain)) => chain,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                };
+                // SAFETY: as in `deliver`.
+                let data = unsafe { gather(&chain) };
+                ring.push_used(chain.head, 0);
+                if let Some(pkt) = VsockPacket::from_bytes(&data) {
+                    self.from_guest.push_back(pkt);
+                }
+            },
+            EVENT_QUEUE => {
+                self.stash(index, ring);
+                let bufs = self.bufs.entry(index).or_default();
+                Self::deliver(bufs, &mut self.events, |e| e.to_le_bytes().to_vec(), ring);
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.events.clear();
+        self.bufs.clear();
+    }
+}
@@ -0,0 +1,105 @@
+//! Typed feature bits. [`Features`] covers the reserved bits shared by all
+//! device types, the per-device sets cover the device specific bits 0 to 23.
+
+use bitflags::bitflags;
+
+use crate::queue;
+
+bitflags! {
+    /// Feature bits independent of the device type.
+    pub struct Features: u64 {
+        const NOTIFY_ON_EMPTY = 1 << 24;
+        const ANY_LAYOUT = 1 << 27;
+        const INDIRECT_DESC = queue::VIRTIO_F_INDIRECT_DESC;
+        const EVENT_IDX = queue::VIRTIO_F_EVENT_IDX;
+        const VERSION_1 = 1 << 32;
+        const ACCESS_PLATFORM = 1 << 33;
+        const RING_PACKED = queue::VIRTIO_F_RING_PACKED;
+        const IN_ORDER = queue::VIRTIO_F_IN_ORDER;
+        const ORDER_PLATFORM = 1 << 36;
+        const SR_IOV = 1 << 37;
+        const NOTIFICATION_DATA = 1 << 38;
+        const RING_RESET = 1 << 40;
+    }
+}
+
+bitflags! {
+    /// Network device feature bits.
+    pub struct NetFeatures: u64 {
+        const CSUM = 1 << 0;
+        const GUEST_CSUM = 1 << 1;
+        const MAC = 1 << 5;
+        const MRG_RXBUF = 1 << 15;
+        const STATUS = 1 << 16;
+        const CTRL_VQ = 1 << 17;
+        const MQ = 1 << 22;
+    }
+}
+
+bitflags! {
+    /// Block device feature bits.
+    pub struct BlkFeatures: u64 {
+        const SIZE_MAX = 1 << 1;
+        const SEG_MAX = 1 << 2;
+        const GEOMETRY = 1 << 4;
+        const RO = 1 << 5;
+        const BLK_SIZE = 1 << 6;
+        const FLUSH = 1 << 9;
+        const TOPOLOGY = 1 << 10;
+        const CONFIG_WCE = 1 << 11;
+        const DISCARD = 1 << 13;
+        const WRITE_ZEROES = 1 << 14;
+    }
+}
+
+bitflags! {
+    /// Console device feature bits.
+    pub struct ConsoleFeatures: u64 {
+        const SIZE = 1 << 0;
+        const MULTIPORT = 1 << 1;
+        const EMERG_WRITE = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// Socket device feature bits.
+    pub struct VsockFeatures: u64 {
+        const STREAM = 1 << 0;
+        const SEQPACKET = 1 << 1;
+    }
+}
+
+/// Device types as reported in the device id register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Net = 1,
+    Block = 2,
+    Console = 3,
+    Entropy = 4,
+    Vsock = 19,
+}
+
+impl DeviceType {
+    /// Look up the device type for a device id, `None` for unknown ids.
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(Self::Net),
+            2 => Some(Self::Block),
+            3 => Some(Self::Console),
+            4 => Some(Self::Entropy),
+            19 => Some(Self::Vsock),
+            _ => None,
+        }
+    }
+
+    /// All device specific feature bits known for this device type.
+    pub fn known_features(self) -> u64 {
+        match self {
+            Self::Net => NetFeatures::all().bits(),
+            Self::Block => BlkFeatures::all().bits(),
+            Self::Console => ConsoleFeatures::all().bits(),
+            Self::Entropy => 0,
+            Self::Vsock => VsockFeatures::all().bits(),
+        }
+    }
+}
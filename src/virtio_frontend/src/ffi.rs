@@ -1,6 +1,37 @@
+use crate::config::{ConfigError, ConfigSpace};
 use crate::queue::VirtqDesc;
+use crate::status::StatusError;
 use crate::transport::VirtioTransport;
 
+/// The status step does not follow the current device state.
+pub const VIRTIO_ERR_ORDER: i32 = -1;
+/// The device rejected the negotiated features.
+pub const VIRTIO_ERR_FEATURES: i32 = -2;
+/// The device needs a reset.
+pub const VIRTIO_ERR_NEEDS_RESET: i32 = -3;
+/// The device was marked failed.
+pub const VIRTIO_ERR_FAILED: i32 = -4;
+/// The config space access is out of range.
+pub const VIRTIO_ERR_RANGE: i32 = -5;
+/// The device kept changing its config space during the read.
+pub const VIRTIO_ERR_UNSTABLE: i32 = -6;
+
+fn status_error(err: StatusError) -> i32 {
+    match err {
+        StatusError::OutOfOrder(_) => VIRTIO_ERR_ORDER,
+        StatusError::FeaturesRejected => VIRTIO_ERR_FEATURES,
+        StatusError::NeedsReset => VIRTIO_ERR_NEEDS_RESET,
+        StatusError::Failed => VIRTIO_ERR_FAILED,
+    }
+}
+
+fn config_error(err: ConfigError) -> i32 {
+    match err {
+        ConfigError::OutOfRange { .. } => VIRTIO_ERR_RANGE,
+        ConfigError::Unstable => VIRTIO_ERR_UNSTABLE,
+    }
+}
+
 /// Create a new transport instance for use from C.
 #[no_mangle]
 pub extern "C" fn virtio_transport_create(
@@ -11,6 +42,11 @@ pub extern "C" fn virtio_transport_create(
 }
 
 /// Destroy a transport previously created via [`virtio_transport_create`].
+///
+/// # Safety
+///
+/// `transport` must be null or come from [`virtio_transport_create`] and
+/// not be used afterwards.
 #[no_mangle]
 pub unsafe extern "C" fn virtio_transport_destroy(transport: *mut VirtioTransport) {
     if !transport.is_null() {
@@ -18,40 +54,121 @@ pub unsafe extern "C" fn virtio_transport_destroy(transport: *mut VirtioTranspor
     }
 }
 
-/// Negotiate features using a C ABI.
+/// Read the device status byte.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`].
+#[no_mangle]
+pub unsafe extern "C" fn virtio_get_status(transport: *const VirtioTransport) -> u8 {
+    (*transport).status().bits()
+}
+
+/// Write the device status byte. Returns 0 on success or a `VIRTIO_ERR_*`
+/// code if the write does not follow the initialisation sequence.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`].
+#[no_mangle]
+pub unsafe extern "C" fn virtio_set_status(transport: *mut VirtioTransport, status: u8) -> i32 {
+    match (*transport).write_status(status) {
+        Ok(()) => 0,
+        Err(e) => status_error(e),
+    }
+}
+
+/// Reset the device.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`].
+#[no_mangle]
+pub unsafe extern "C" fn virtio_reset(transport: *mut VirtioTransport) {
+    (*transport).reset();
+}
+
+/// Negotiate features using a C ABI. Stores the agreed upon features in
+/// `negotiated` and returns 0, or a `VIRTIO_ERR_*` code if the device is
+/// not in the DRIVER state.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`] and `negotiated`
+/// must be valid for writes.
 #[no_mangle]
 pub unsafe extern "C" fn virtio_negotiate_features(
     transport: *mut VirtioTransport,
     driver_supported: u64,
-) -> u64 {
-    (*transport).negotiate_features(driver_supported)
+    negotiated: *mut u64,
+) -> i32 {
+    match (*transport).negotiate_features(driver_supported) {
+        Ok(features) => {
+            *negotiated = features;
+            0
+        }
+        Err(e) => status_error(e),
+    }
+}
+
+/// Current configuration generation.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`].
+#[no_mangle]
+pub unsafe extern "C" fn virtio_config_generation(transport: *const VirtioTransport) -> u32 {
+    (*transport).config_generation()
 }
 
-/// Read from the configuration space using raw pointers.
+/// Read from the configuration space using raw pointers. Returns 0 on
+/// success or a `VIRTIO_ERR_*` code.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`] and `buf` must
+/// be valid for writes of `len` bytes.
 #[no_mangle]
 pub unsafe extern "C" fn virtio_config_read(
     transport: *const VirtioTransport,
     offset: usize,
     buf: *mut u8,
     len: usize,
-) {
+) -> i32 {
     let slice = core::slice::from_raw_parts_mut(buf, len);
-    (*transport).read_config(offset, slice);
+    match (*transport).read_config(offset, slice) {
+        Ok(()) => 0,
+        Err(e) => config_error(e),
+    }
 }
 
-/// Write to the configuration space using raw pointers.
+/// Write to the configuration space using raw pointers. Returns 0 on
+/// success or a `VIRTIO_ERR_*` code.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`] and `buf` must
+/// be valid for reads of `len` bytes.
 #[no_mangle]
 pub unsafe extern "C" fn virtio_config_write(
     transport: *mut VirtioTransport,
     offset: usize,
     buf: *const u8,
     len: usize,
-) {
+) -> i32 {
     let slice = core::slice::from_raw_parts(buf, len);
-    (*transport).write_config(offset, slice);
+    match (*transport).write_config(offset, slice) {
+        Ok(()) => 0,
+        Err(e) => config_error(e),
+    }
 }
 
 /// Add a descriptor chain to the transport's queue. Returns 0 on success.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`] and `descs`
+/// must point to `count` descriptors.
 #[no_mangle]
 pub unsafe extern "C" fn virtio_queue_add(
     transport: *mut VirtioTransport,
@@ -64,3 +181,26 @@ pub unsafe extern "C" fn virtio_queue_add(
         Err(_) => -1,
     }
 }
+
+/// Take the next completed chain from the transport's queue. Returns 1 and
+/// fills `token` and `len` if there was one, 0 otherwise.
+///
+/// # Safety
+///
+/// `transport` must come from [`virtio_transport_create`] and `token` and
+/// `len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn virtio_queue_pop_used(
+    transport: *mut VirtioTransport,
+    token: *mut u16,
+    len: *mut u32,
+) -> i32 {
+    match (*transport).queue.pop_used() {
+        Some((t, l)) => {
+            *token = t;
+            *len = l;
+            1
+        }
+        None => 0,
+    }
+}
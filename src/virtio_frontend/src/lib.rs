@@ -1,25 +1,234 @@
+pub mod blk;
+pub mod config;
+pub mod console;
+pub mod device;
+pub mod features;
 pub mod ffi;
+pub mod mmio;
+pub mod packed;
 pub mod queue;
+pub mod rng;
+pub mod status;
 pub mod transport;
+pub mod vsock;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use config::{ConfigError, ConfigSpace};
+    use mmio::Registers;
+    use queue::{DeviceRing, Queue};
+    use status::{DeviceState, Status, StatusError};
 
     #[test]
     fn feature_negotiation() {
         let mut dev = transport::VirtioTransport::new(0b1010, 0);
-        let features = dev.negotiate_features(0b1110);
+        assert_eq!(
+            dev.negotiate_features(0b1110),
+            Err(StatusError::OutOfOrder(DeviceState::Reset))
+        );
+        dev.acknowledge().unwrap();
+        dev.driver().unwrap();
+        let features = dev.negotiate_features(0b1110).unwrap();
         assert_eq!(features, 0b1010 & 0b1110);
     }
 
+    #[test]
+    fn status_sequence() {
+        let mut dev = transport::VirtioTransport::new(0, 0);
+        assert_eq!(dev.status(), Status::empty());
+        assert_eq!(
+            dev.driver(),
+            Err(StatusError::OutOfOrder(DeviceState::Reset))
+        );
+        dev.acknowledge().unwrap();
+        dev.driver().unwrap();
+        assert_eq!(
+            dev.driver_ok(),
+            Err(StatusError::OutOfOrder(DeviceState::Driver))
+        );
+        dev.negotiate_features(0).unwrap();
+        dev.features_ok().unwrap();
+        assert_eq!(
+            dev.negotiate_features(0),
+            Err(StatusError::OutOfOrder(DeviceState::FeaturesOk))
+        );
+        dev.driver_ok().unwrap();
+        assert_eq!(
+            dev.status(),
+            Status::ACKNOWLEDGE | Status::DRIVER | Status::FEATURES_OK | Status::DRIVER_OK
+        );
+
+        // NEEDS_RESET and FAILED only leave through a reset.
+        dev.set_needs_reset();
+        assert!(dev.status().contains(Status::NEEDS_RESET));
+        dev.reset();
+        dev.acknowledge().unwrap();
+        dev.fail();
+        assert_eq!(dev.status(), Status::FAILED);
+        assert_eq!(dev.driver(), Err(StatusError::Failed));
+        dev.reset();
+        assert_eq!(dev.state(), DeviceState::Reset);
+        dev.acknowledge().unwrap();
+    }
+
+    #[test]
+    fn status_raw_writes() {
+        let mut dev = transport::VirtioTransport::new(0, 0);
+        let ack = Status::ACKNOWLEDGE.bits();
+        let driver = ack | Status::DRIVER.bits();
+        assert!(dev.write_status(driver).is_err());
+        dev.write_status(ack).unwrap();
+        dev.write_status(ack).unwrap();
+        dev.write_status(driver).unwrap();
+        assert_eq!(dev.state(), DeviceState::Driver);
+        dev.write_status(driver | Status::FAILED.bits()).unwrap();
+        assert_eq!(dev.state(), DeviceState::Failed);
+        dev.write_status(0).unwrap();
+        assert_eq!(dev.state(), DeviceState::Reset);
+    }
+
+    #[test]
+    fn features_rejected_without_version_1() {
+        let version_1 = features::Features::VERSION_1.bits();
+        let mut dev = transport::VirtioTransport::new(version_1 | 1, 0);
+        dev.acknowledge().unwrap();
+        dev.driver().unwrap();
+        dev.negotiate_features(1).unwrap();
+        assert_eq!(dev.features_ok(), Err(StatusError::FeaturesRejected));
+        assert_eq!(dev.state(), DeviceState::Driver);
+
+        dev.negotiate_features(version_1 | 1).unwrap();
+        dev.features_ok().unwrap();
+        assert!(dev.features().contains(features::Features::VERSION_1));
+        assert!(
+            features::NetFeatures::from_bits_truncate(dev.driver_features)
+                .contains(features::NetFeatures::CSUM)
+        );
+    }
+
     #[test]
     fn config_space_rw() {
         let mut dev = transport::VirtioTransport::new(0, 8);
-        dev.write_config(2, &[1, 2, 3, 4]);
+        dev.write_config(2, &[1, 2, 3, 4]).unwrap();
         let mut buf = [0u8; 4];
-        dev.read_config(2, &mut buf);
+        dev.read_config(2, &mut buf).unwrap();
         assert_eq!(&buf, &[1, 2, 3, 4]);
+        assert_eq!(dev.read_le::<u16>(3), Ok(0x0302));
+        dev.write_le(0, 0x0807_0605_0403_0201u64).unwrap();
+        assert_eq!(dev.read_le::<u32>(4), Ok(0x0807_0605));
+        assert_eq!(dev.read_le::<u8>(7), Ok(8));
+    }
+
+    #[test]
+    fn config_space_bounds() {
+        let mut dev = transport::VirtioTransport::new(0, 8);
+        let out_of_range = ConfigError::OutOfRange {
+            offset: 6,
+            len: 4,
+            size: 8,
+        };
+        assert_eq!(dev.read_le::<u32>(6), Err(out_of_range));
+        assert_eq!(dev.write_le(6, 0u32), Err(out_of_range));
+        assert!(dev.read_config(usize::MAX, &mut [0; 2]).is_err());
+        assert!(dev.set_config(8, &[1]).is_err());
+        assert_eq!(dev.read_le::<u64>(0), Ok(0));
+    }
+
+    #[test]
+    fn config_change_callback() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+
+        let seen = Arc::new(AtomicU32::new(0));
+        let mut dev = transport::VirtioTransport::new(0, 4);
+        let generation = dev.config_generation();
+        let s = seen.clone();
+        dev.on_config_change(move |generation| s.store(generation, Ordering::SeqCst));
+        dev.set_config(0, &[0x34, 0x12]).unwrap();
+        assert_ne!(dev.config_generation(), generation);
+        assert_eq!(seen.load(Ordering::SeqCst), dev.config_generation());
+        assert_eq!(dev.read_le::<u16>(0), Ok(0x1234));
+    }
+
+    /// Config space whose generation moves on for the first `changes`
+    /// generation reads.
+    struct Flapping {
+        changes: std::cell::Cell<u32>,
+        reads: std::cell::Cell<u32>,
+    }
+
+    impl ConfigSpace for Flapping {
+        fn config_len(&self) -> usize {
+            4
+        }
+
+        fn config_generation(&self) -> u32 {
+            let changes = self.changes.get();
+            self.changes.set(changes.saturating_sub(1));
+            changes
+        }
+
+        fn read_raw(&self, _offset: usize, data: &mut [u8]) {
+            self.reads.set(self.reads.get() + 1);
+            data.fill(self.changes.get() as u8);
+        }
+
+        fn write_raw(&mut self, _offset: usize, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn config_read_retries_on_generation_change() {
+        let space = Flapping {
+            changes: 3.into(),
+            reads: 0.into(),
+        };
+        assert_eq!(space.read_le::<u32>(0), Ok(0));
+        assert_eq!(space.reads.get(), 3);
+
+        let space = Flapping {
+            changes: 100.into(),
+            reads: 0.into(),
+        };
+        assert_eq!(space.read_le::<u32>(0), Err(ConfigError::Unstable));
+        assert_eq!(space.reads.get() as usize, config::MAX_GENERATION_RETRIES);
+    }
+
+    /// Attach the device side to a driver queue.
+    fn device_of(queue: &dyn Queue) -> queue::DeviceQueue {
+        unsafe {
+            queue::DeviceQueue::new(
+                queue.size(),
+                queue.desc_addr(),
+                queue.driver_addr(),
+                queue.device_addr(),
+            )
+            .unwrap()
+        }
+    }
+
+    /// Attach the device side to a driver queue in the format `features`
+    /// call for.
+    fn device_ring_of(queue: &dyn Queue, features: u64) -> Box<dyn DeviceRing> {
+        unsafe {
+            queue::device_ring_for(
+                queue.size(),
+                features,
+                queue.desc_addr(),
+                queue.driver_addr(),
+                queue.device_addr(),
+            )
+            .unwrap()
+        }
+    }
+
+    fn buf(addr: u64, len: u32, flags: u16) -> queue::VirtqDesc {
+        queue::VirtqDesc {
+            addr,
+            len,
+            flags,
+            next: 0,
+        }
     }
 
     #[test]
@@ -39,23 +248,797 @@ mod tests {
                 next: 0,
             },
         ];
-        dev.queue.add(&descs).unwrap();
-        assert_eq!(dev.queue.avail.idx, 1);
-        assert_eq!(dev.queue.desc[0].addr, 0x1000);
+        let token = dev.queue.add(&descs).unwrap();
+        assert_eq!(dev.queue.num_free(), queue::DEFAULT_QUEUE_SIZE - 2);
+
+        let mut device = device_of(&*dev.queue);
+        let chain = device.pop_avail().unwrap().unwrap();
+        assert_eq!(chain.head, token);
+        assert_eq!(chain.descs.len(), 2);
+        assert_eq!(chain.descs[0].addr, 0x1000);
+        assert_eq!(
+            chain.descs[0].flags,
+            queue::VIRTQ_DESC_F_WRITE | queue::VIRTQ_DESC_F_NEXT
+        );
+        assert_eq!(chain.descs[1].addr, 0x2000);
+        assert_eq!(chain.descs[1].flags, 0);
+        assert_eq!(device.pop_avail().unwrap(), None);
+    }
+
+    #[test]
+    fn queue_sizes() {
+        assert_eq!(
+            queue::VirtQueue::new(0).err(),
+            Some(queue::QueueError::InvalidSize)
+        );
+        assert_eq!(
+            queue::VirtQueue::new(6).err(),
+            Some(queue::QueueError::InvalidSize)
+        );
+        let layout = queue::SplitLayout::new(256).unwrap();
+        assert_eq!(layout.avail, 4096);
+        assert_eq!(layout.used, 4096 + 6 + 512 + 2);
+        assert_eq!(layout.total, layout.used + 6 + 8 * 256);
+
+        let q = queue::VirtQueue::new(256).unwrap();
+        assert_eq!(q.desc_addr() % 4096, 0);
+        assert_eq!(q.avail_addr() - q.desc_addr(), layout.avail as u64);
+        assert_eq!(q.used_addr() - q.desc_addr(), layout.used as u64);
+    }
+
+    #[test]
+    fn queue_free_list_and_used() {
+        let mut q = queue::VirtQueue::new(4).unwrap();
+        let mut device = device_of(&q);
+        assert_eq!(q.add(&[]), Err(queue::QueueError::EmptyChain));
+        assert_eq!(
+            q.add(&[buf(0, 1, 0); 5]),
+            Err(queue::QueueError::ChainTooLong)
+        );
+
+        let a = q.add(&[buf(0x100, 8, 0)]).unwrap();
+        let b = q.add(&[buf(0x200, 8, 0), buf(0x300, 64, 2)]).unwrap();
+        let c = q.add(&[buf(0x400, 8, 0)]).unwrap();
+        assert_eq!(q.num_free(), 0);
+        assert_eq!(q.add(&[buf(0x500, 8, 0)]), Err(queue::QueueError::Full));
+        assert_eq!(q.pop_used(), None);
+
+        let chains: Vec<_> = (0..3)
+            .map(|_| device.pop_avail().unwrap().unwrap())
+            .collect();
+        assert_eq!(chains[1].head, b);
+        assert_eq!(chains[1].readable().count(), 1);
+        assert_eq!(chains[1].writable().next().unwrap().addr, 0x300);
+
+        // The device may complete chains out of order.
+        device.push_used(b, 64);
+        device.push_used(a, 0);
+        assert_eq!(q.pop_used(), Some((b, 64)));
+        assert_eq!(q.num_free(), 2);
+        let d = q.add(&[buf(0x600, 8, 0), buf(0x700, 8, 0)]).unwrap();
+        assert_eq!(q.pop_used(), Some((a, 0)));
+        assert_eq!(q.pop_used(), None);
+
+        let chain = device.pop_avail().unwrap().unwrap();
+        assert_eq!(chain.head, d);
+        assert_eq!(chain.descs[1].addr, 0x700);
+        device.push_used(c, 1);
+        device.push_used(d, 2);
+        assert_eq!(q.pop_used(), Some((c, 1)));
+        assert_eq!(q.pop_used(), Some((d, 2)));
+        assert_eq!(q.num_free(), 4);
+    }
+
+    #[test]
+    fn queue_wraps_around() {
+        let mut q = queue::VirtQueue::new(2).unwrap();
+        let mut device = device_of(&q);
+        for i in 0..70_000u32 {
+            let token = q.add(&[buf(i as u64, 4, 2), buf(0, 4, 2)]).unwrap();
+            let chain = device.pop_avail().unwrap().unwrap();
+            assert_eq!(chain.descs[0].addr, i as u64);
+            device.push_used(chain.head, i);
+            assert_eq!(q.pop_used(), Some((token, i)));
+        }
+        assert_eq!(q.num_free(), 2);
+    }
+
+    #[test]
+    fn queue_indirect() {
+        let mut q = queue::VirtQueue::new(2).unwrap();
+        q.set_features(queue::VIRTIO_F_INDIRECT_DESC);
+        let mut device = device_of(&q);
+        let bufs: Vec<_> = (0..4).map(|i| buf(0x1000 * i, 16, 0)).collect();
+        // Longer than the free descriptors, but one table entry is enough.
+        assert_eq!(q.add(&bufs), Err(queue::QueueError::ChainTooLong));
+        let token = q.add(&bufs[..2]).unwrap();
+        assert_eq!(q.num_free(), 1);
+        q.add(&bufs[2..3]).unwrap();
+
+        let chain = device.pop_avail().unwrap().unwrap();
+        assert_eq!(chain.head, token);
+        assert_eq!(chain.descs.len(), 2);
+        assert_eq!(chain.descs[1].addr, 0x1000);
+        assert_eq!(device.pop_avail().unwrap().unwrap().descs.len(), 1);
+        device.push_used(token, 0);
+        assert_eq!(q.pop_used(), Some((token, 0)));
+        assert_eq!(q.num_free(), 1);
+    }
+
+    #[test]
+    fn queue_malformed_chains() {
+        let mut q = queue::VirtQueue::new(4).unwrap();
+        let mut device = device_of(&q);
+        let token = q.add(&[buf(0, 1, 0), buf(0, 1, 0)]).unwrap();
+        // Let the chain loop back to its head.
+        unsafe {
+            let desc = q.desc_addr() as *mut queue::VirtqDesc;
+            let second = (*desc.add(token as usize)).next;
+            (*desc.add(second as usize)).flags = queue::VIRTQ_DESC_F_NEXT;
+            (*desc.add(second as usize)).next = token;
+        }
+        assert_eq!(device.pop_avail(), Err(queue::QueueError::Malformed));
+
+        let token = q.add(&[buf(0, 1, 0)]).unwrap();
+        unsafe {
+            let desc = q.desc_addr() as *mut queue::VirtqDesc;
+            (*desc.add(token as usize)).flags = queue::VIRTQ_DESC_F_INDIRECT;
+            (*desc.add(token as usize)).len = 15;
+        }
+        assert_eq!(device.pop_avail(), Err(queue::QueueError::Malformed));
+        assert_eq!(device.pop_avail(), Ok(None));
+    }
+
+    #[test]
+    fn queue_notification_suppression() {
+        // Without EVENT_IDX the ring flags decide.
+        let mut q = queue::VirtQueue::new(8).unwrap();
+        let mut device = device_of(&q);
+        assert!(!q.needs_notify());
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        assert!(q.needs_notify());
+        device.set_notify(false);
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        assert!(!q.needs_notify());
+        let head = device.pop_avail().unwrap().unwrap().head;
+        device.push_used(head, 0);
+        q.disable_interrupts();
+        assert!(!device.needs_interrupt());
+        assert!(!q.enable_interrupts());
+        assert_eq!(q.pop_used(), Some((head, 0)));
+        assert!(q.enable_interrupts());
+
+        // With EVENT_IDX each side names the index it wants to hear about.
+        let mut q = queue::VirtQueue::new(8).unwrap();
+        q.set_features(queue::VIRTIO_F_EVENT_IDX);
+        let mut device = device_of(&q);
+        device.set_features(queue::VIRTIO_F_EVENT_IDX);
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        assert!(q.needs_notify());
+        // The device has not caught up yet: avail_event is still 0.
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        assert!(!q.needs_notify());
+        let first = device.pop_avail().unwrap().unwrap().head;
+        let second = device.pop_avail().unwrap().unwrap().head;
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        assert!(q.needs_notify());
+
+        device.push_used(first, 0);
+        assert!(device.needs_interrupt());
+        device.push_used(second, 0);
+        // The driver has not popped the first completion yet.
+        assert!(!device.needs_interrupt());
+        assert_eq!(q.pop_used(), Some((first, 0)));
+        assert_eq!(q.pop_used(), Some((second, 0)));
+        let third = device.pop_avail().unwrap().unwrap().head;
+        device.push_used(third, 0);
+        assert!(device.needs_interrupt());
+
+        q.disable_interrupts();
+        assert_eq!(q.pop_used(), Some((third, 0)));
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        let head = device.pop_avail().unwrap().unwrap().head;
+        device.push_used(head, 0);
+        assert!(!device.needs_interrupt());
+        assert!(!q.enable_interrupts());
+    }
+
+    #[test]
+    fn packed_queue_out_of_order() {
+        let mut q = packed::PackedQueue::new(5).unwrap();
+        let mut device = device_ring_of(&q, queue::VIRTIO_F_RING_PACKED);
+        assert_eq!(
+            packed::PackedQueue::new(0).err(),
+            Some(queue::QueueError::InvalidSize)
+        );
+        // Chains of varying length wrap around the ring at every position.
+        for round in 0..200u32 {
+            let len = (round % 3 + 1) as usize;
+            let chain: Vec<_> = (0..len).map(|i| buf(i as u64, 8, 2)).collect();
+            let a = q.add(&chain).unwrap();
+            let b = q.add(&[buf(0x100, 4, 0)]).unwrap();
+            let first = device.pop_avail().unwrap().unwrap();
+            assert_eq!(first.head, a);
+            assert_eq!(first.writable().count(), len);
+            assert_eq!(device.pop_avail().unwrap().unwrap().head, b);
+            assert_eq!(device.pop_avail().unwrap(), None);
+            device.push_used(b, 0);
+            device.push_used(a, round);
+            assert_eq!(q.pop_used(), Some((b, 0)));
+            assert_eq!(q.pop_used(), Some((a, round)));
+            assert_eq!(q.pop_used(), None);
+            assert_eq!(q.num_free(), 5);
+        }
+        let chain = [buf(0, 1, 0); 4];
+        q.add(&chain).unwrap();
+        assert_eq!(q.add(&chain[..2]), Err(queue::QueueError::Full));
+    }
+
+    #[test]
+    fn packed_queue_indirect_in_order() {
+        let features =
+            queue::VIRTIO_F_RING_PACKED | queue::VIRTIO_F_INDIRECT_DESC | queue::VIRTIO_F_IN_ORDER;
+        let mut q = queue::for_features(4, features).unwrap();
+        let mut device = unsafe {
+            packed::PackedDeviceQueue::new(4, q.desc_addr(), q.driver_addr(), q.device_addr())
+                .unwrap()
+        };
+        device.set_features(features);
+        let a = q.add(&[buf(0x1000, 16, 0), buf(0x2000, 32, 2)]).unwrap();
+        let b = q.add(&[buf(0x3000, 16, 0)]).unwrap();
+        let c = q.add(&[buf(0x4000, 16, 0), buf(0x5000, 64, 2)]).unwrap();
+        assert_eq!(q.num_free(), 1);
+
+        let chain = device.pop_avail().unwrap().unwrap();
+        assert_eq!(chain.head, a);
+        assert_eq!(chain.readable().next().unwrap().addr, 0x1000);
+        assert_eq!(chain.writable().next().unwrap().len, 32);
+        assert_eq!(device.pop_avail().unwrap().unwrap().head, b);
+        assert_eq!(device.pop_avail().unwrap().unwrap().head, c);
+
+        // One used descriptor completes the whole batch.
+        device.push_used_batch(&[a, b, c], 64);
+        assert!(q.has_used());
+        assert_eq!(q.pop_used(), Some((a, 0)));
+        assert_eq!(q.pop_used(), Some((b, 0)));
+        assert_eq!(q.pop_used(), Some((c, 64)));
+        assert_eq!(q.pop_used(), None);
+        assert_eq!(q.num_free(), 4);
+
+        let d = q.add(&[buf(0x6000, 16, 0)]).unwrap();
+        assert_eq!(device.pop_avail().unwrap().unwrap().head, d);
+        device.push_used(d, 1);
+        assert_eq!(q.pop_used(), Some((d, 1)));
+    }
+
+    #[test]
+    fn packed_queue_notification_suppression() {
+        let mut q = packed::PackedQueue::new(4).unwrap();
+        let mut device = device_ring_of(&q, queue::VIRTIO_F_RING_PACKED);
+        assert!(!q.needs_notify());
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        assert!(q.needs_notify());
+        device.set_notify(false);
+        q.add(&[buf(0, 1, 0)]).unwrap();
+        assert!(!q.needs_notify());
+        let first = device.pop_avail().unwrap().unwrap().head;
+        let second = device.pop_avail().unwrap().unwrap().head;
+        device.push_used(first, 0);
+        assert!(device.needs_interrupt());
+        q.disable_interrupts();
+        device.push_used(second, 0);
+        assert!(!device.needs_interrupt());
+        assert!(!q.enable_interrupts());
+        assert_eq!(q.pop_used(), Some((first, 0)));
+        assert_eq!(q.pop_used(), Some((second, 0)));
+        assert!(q.enable_interrupts());
+
+        let features = queue::VIRTIO_F_RING_PACKED | queue::VIRTIO_F_EVENT_IDX;
+        let mut q = queue::for_features(4, features).unwrap();
+        let mut device = device_ring_of(&*q, features);
+        device.set_notify(true);
+        q.enable_interrupts();
+        for round in 0..10 {
+            q.add(&[buf(0, 1, 0), buf(0, 1, 0), buf(0, 1, 0)]).unwrap();
+            assert!(q.needs_notify(), "round {round}");
+            // The device has not caught up, so it needs no second kick.
+            q.add(&[buf(0, 1, 0)]).unwrap();
+            assert!(!q.needs_notify(), "round {round}");
+            let a = device.pop_avail().unwrap().unwrap().head;
+            let b = device.pop_avail().unwrap().unwrap().head;
+            device.push_used(a, 0);
+            assert!(device.needs_interrupt(), "round {round}");
+            // The driver has not popped the first completion yet.
+            device.push_used(b, 0);
+            assert!(!device.needs_interrupt(), "round {round}");
+            assert_eq!(q.pop_used(), Some((a, 0)));
+            assert_eq!(q.pop_used(), Some((b, 0)));
+        }
+    }
+
+    #[test]
+    fn transport_picks_ring_format() {
+        let features = queue::VIRTIO_F_RING_PACKED | queue::VIRTIO_F_EVENT_IDX;
+        let mut dev = transport::VirtioTransport::new(features, 0);
+        assert_eq!(dev.queue.device_addr() - dev.queue.desc_addr(), 152);
+        dev.acknowledge().unwrap();
+        dev.driver().unwrap();
+        let negotiated = dev.negotiate_features(queue::VIRTIO_F_RING_PACKED).unwrap();
+        assert_eq!(negotiated, queue::VIRTIO_F_RING_PACKED);
+        assert_eq!(dev.queue.size(), queue::DEFAULT_QUEUE_SIZE);
+        assert_eq!(dev.queue.device_addr() - dev.queue.desc_addr(), 132);
+
+        let mut device = device_ring_of(&*dev.queue, negotiated);
+        let token = dev.queue.add(&[buf(0x1000, 8, 0)]).unwrap();
+        let chain = device.pop_avail().unwrap().unwrap();
+        assert_eq!(chain.head, token);
+        device.push_used(token, 8);
+        assert_eq!(dev.queue.pop_used(), Some((token, 8)));
+    }
+
+    #[test]
+    fn mmio_probe() {
+        let sim = mmio::SimRegisters::new(1, 0, 1, 8, 0);
+        let dev = mmio::MmioTransport::new(sim).unwrap();
+        assert_eq!(dev.device_id(), 1);
+        assert_eq!(dev.vendor_id(), dev.regs().vendor_id);
+
+        let mut sim = mmio::SimRegisters::new(1, 0, 1, 8, 0);
+        sim.magic = 0x1234;
+        assert_eq!(
+            mmio::MmioTransport::new(sim).err(),
+            Some(mmio::MmioError::BadMagic(0x1234))
+        );
+        let mut sim = mmio::SimRegisters::new(1, 0, 1, 8, 0);
+        sim.version = 1;
+        assert_eq!(
+            mmio::MmioTransport::new(sim).err(),
+            Some(mmio::MmioError::UnsupportedVersion(1))
+        );
+        let sim = mmio::SimRegisters::new(0, 0, 1, 8, 0);
+        assert_eq!(
+            mmio::MmioTransport::new(sim).err(),
+            Some(mmio::MmioError::NoDevice)
+        );
+    }
+
+    #[test]
+    fn mmio_features_and_status() {
+        let features = 1 << 32 | queue::VIRTIO_F_EVENT_IDX | 0b101;
+        let sim = mmio::SimRegisters::new(2, features, 1, 8, 0);
+        let mut dev = mmio::MmioTransport::new(sim).unwrap();
+        assert_eq!(dev.device_features(), features);
+        dev.set_status(1);
+        dev.set_status(1 | 2);
+        dev.set_driver_features(1 << 32 | 0b100);
+        assert_eq!(dev.regs().driver_features(), 1 << 32 | 0b100);
+        assert_eq!(dev.status(), 3);
+        dev.reset();
+        assert_eq!(dev.status(), 0);
+        assert_eq!(dev.regs().driver_features(), 0);
+    }
+
+    #[test]
+    fn mmio_queue_setup() {
+        let sim = mmio::SimRegisters::new(2, 0, 2, 16, 0);
+        let mut dev = mmio::MmioTransport::new(sim).unwrap();
+        assert_eq!(dev.queue_max(1), 16);
+        assert_eq!(dev.queue_max(2), 0);
+
+        let mut q = queue::VirtQueue::new(8).unwrap();
+        dev.setup_queue(1, &q).unwrap();
+        let regs = dev.regs().queue(1).unwrap();
+        assert!(regs.ready);
+        assert_eq!(regs.num, 8);
+        assert_eq!(regs.desc, q.desc_addr());
+        assert_eq!(regs.driver, q.driver_addr());
+        assert_eq!(regs.device, q.device_addr());
+        assert_eq!(dev.setup_queue(1, &q), Err(mmio::MmioError::QueueInUse));
+        assert_eq!(
+            dev.setup_queue(2, &q),
+            Err(mmio::MmioError::QueueUnavailable)
+        );
+        let big = queue::VirtQueue::new(32).unwrap();
+        assert_eq!(
+            dev.setup_queue(0, &big),
+            Err(mmio::MmioError::QueueTooLarge)
+        );
+
+        // Play the device on the queue the registers describe.
+        let mut device = unsafe {
+            queue::DeviceQueue::new(regs.num as u16, regs.desc, regs.driver, regs.device).unwrap()
+        };
+        let token = q.add(&[buf(0x1000, 8, 0)]).unwrap();
+        if q.needs_notify() {
+            dev.notify(1);
+        }
+        assert_eq!(dev.regs_mut().take_notifications(), vec![1]);
+        let chain = device.pop_avail().unwrap().unwrap();
+        device.push_used(chain.head, 0);
+        if device.needs_interrupt() {
+            dev.regs_mut().interrupt(mmio::INTERRUPT_USED_BUFFER);
+        }
+        assert_eq!(dev.ack_interrupt(), mmio::INTERRUPT_USED_BUFFER);
+        assert_eq!(dev.ack_interrupt(), 0);
+        assert_eq!(q.pop_used(), Some((token, 0)));
+
+        dev.disable_queue(1);
+        assert!(!dev.regs().queue(1).unwrap().ready);
+        dev.setup_queue(1, &q).unwrap();
+        dev.reset();
+        assert_eq!(dev.regs().queue(1).unwrap().desc, 0);
+    }
+
+    #[test]
+    fn mmio_config_space() {
+        let sim = mmio::SimRegisters::new(1, 0, 1, 8, 12);
+        let mut dev = mmio::MmioTransport::new(sim).unwrap();
+        let generation = dev.config_generation();
+        dev.regs_mut().set_config(4, &[1, 2, 3, 4]);
+        assert_ne!(dev.config_generation(), generation);
+        assert_eq!(dev.ack_interrupt(), mmio::INTERRUPT_CONFIG_CHANGE);
+        let mut buf = [0u8; 6];
+        dev.read_config(3, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3, 4, 0]);
+        dev.write_config(10, &[0xaa, 0xbb]).unwrap();
+        assert_eq!(&dev.regs().config()[8..], &[0, 0, 0xaa, 0xbb]);
+        assert_eq!(dev.regs().read32(mmio::CONFIG + 4), 0x0403_0201);
+        assert_eq!(dev.read_le::<u32>(4), Ok(0x0403_0201));
+        let mut dev = dev.with_config_len(12);
+        assert!(dev.read_le::<u32>(10).is_err());
+        assert!(dev.write_le(12, 0u8).is_err());
+    }
+
+    /// A block driver talking to a simulated device of `backend`.
+    fn blk_driver<B: device::BlockBackend>(
+        device: device::BlkDevice<B>,
+    ) -> blk::BlkDriver<device::SimDevice<device::BlkDevice<B>>> {
+        let sim = unsafe { device::SimDevice::new(device) };
+        blk::BlkDriver::new(mmio::MmioTransport::new(sim).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn blk_device_read_write() {
+        let image: Vec<u8> = (0..64 * blk::SECTOR_SIZE)
+            .map(|i| (i / 512) as u8)
+            .collect();
+        let mut driver = blk_driver(device::BlkDevice::new(image));
+        assert_eq!(driver.capacity(), 64);
+        assert!(!driver.read_only());
+
+        let mut buf = [0u8; 2 * blk::SECTOR_SIZE];
+        driver.read_sectors(3, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 3));
+        assert!(buf[512..].iter().all(|&b| b == 4));
+
+        driver.write_sectors(62, &[0xee; 1024]).unwrap();
+        driver.flush().unwrap();
+        let sim = driver.transport().regs();
+        assert!(sim.model().backend()[62 * 512..].iter().all(|&b| b == 0xee));
+        assert!(sim.interrupts() >= 3);
+
+        assert_eq!(
+            driver.read_sectors(63, &mut buf),
+            Err(blk::BlkError::OutOfRange)
+        );
+        assert_eq!(
+            driver.read_sectors(0, &mut buf[..100]),
+            Err(blk::BlkError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn blk_device_read_only() {
+        let mut driver = blk_driver(device::BlkDevice::read_only(vec![7u8; 4096]));
+        assert!(driver.read_only());
+        assert_eq!(
+            driver.write_sectors(0, &[0; 512]),
+            Err(blk::BlkError::ReadOnly)
+        );
+        let mut buf = [0u8; 512];
+        driver.read_sectors(7, &mut buf).unwrap();
+        assert_eq!(buf, [7; 512]);
+    }
+
+    #[test]
+    fn blk_disk_partial_sectors() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let mut disk = blk::BlkDisk::new(blk_driver(device::BlkDevice::new(vec![0u8; 2048])));
+        assert_eq!(disk.len(), 2048);
+        disk.seek(SeekFrom::Start(500)).unwrap();
+        disk.write_all(&[1; 600]).unwrap();
+        assert_eq!(disk.seek(SeekFrom::End(-2048)).unwrap(), 0);
+        let mut all = vec![0; 4096];
+        assert_eq!(disk.read(&mut all).unwrap(), 2048);
+        assert!(all[..500].iter().all(|&b| b == 0));
+        assert!(all[500..1100].iter().all(|&b| b == 1));
+        assert!(all[1100..2048].iter().all(|&b| b == 0));
+        assert_eq!(
+            disk.write(&[1]).unwrap_err().kind(),
+            std::io::ErrorKind::WriteZero
+        );
+    }
+
+    #[test]
+    fn blk_fat_filesystem_on_image_file() {
+        use std::io::{Read, Write};
+
+        let path = std::env::temp_dir().join(format!("virtio-blk-{}.img", std::process::id()));
+        let mut image = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        image.set_len(1 << 20).unwrap();
+        fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+
+        // Mount the image the way fs_server does, through the driver.
+        let disk = blk::BlkDisk::new(blk_driver(device::BlkDevice::new(image)));
+        let fs = fatfs::FileSystem::new(disk, fatfs::FsOptions::new()).unwrap();
+        let var = fs.root_dir().create_dir("var").unwrap();
+        let mut file = var.create_file("log.txt").unwrap();
+        file.write_all(&b"hello from virtio-blk\n".repeat(100))
+            .unwrap();
+        drop(file);
+        drop(var);
+        fs.unmount().unwrap();
+
+        let image = std::fs::File::open(&path).unwrap();
+        let fs = fatfs::FileSystem::new(image, fatfs::FsOptions::new()).unwrap();
+        let mut text = String::new();
+        fs.root_dir()
+            .open_file("var/log.txt")
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "hello from virtio-blk\n".repeat(100));
+        drop(fs);
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
     fn ffi_roundtrip() {
         unsafe {
             let dev = ffi::virtio_transport_create(0b1, 4);
-            let neg = ffi::virtio_negotiate_features(dev, 0b11);
+            let mut neg = 0;
+            assert_eq!(
+                ffi::virtio_negotiate_features(dev, 0b11, &mut neg),
+                ffi::VIRTIO_ERR_ORDER
+            );
+            let driver = (Status::ACKNOWLEDGE | Status::DRIVER).bits();
+            assert_eq!(ffi::virtio_set_status(dev, driver), ffi::VIRTIO_ERR_ORDER);
+            assert_eq!(ffi::virtio_set_status(dev, Status::ACKNOWLEDGE.bits()), 0);
+            assert_eq!(ffi::virtio_set_status(dev, driver), 0);
+            assert_eq!(ffi::virtio_negotiate_features(dev, 0b11, &mut neg), 0);
             assert_eq!(neg, 0b1);
+            assert_eq!(ffi::virtio_get_status(dev), driver);
+            ffi::virtio_reset(dev);
+            assert_eq!(ffi::virtio_get_status(dev), 0);
             let value = [0xAAu8];
-            ffi::virtio_config_write(dev, 0, value.as_ptr(), value.len());
-            let mut out = [0u8];
-            ffi::virtio_config_read(dev, 0, out.as_mut_ptr(), out.len());
+            assert_eq!(
+                ffi::virtio_config_write(dev, 0, value.as_ptr(), value.len()),
+                0
+            );
+            let mut out = [0u8; 2];
+            assert_eq!(ffi::virtio_config_read(dev, 0, out.as_mut_ptr(), 1), 0);
             assert_eq!(out[0], 0xAA);
+            assert_eq!(
+                ffi::virtio_config_read(dev, 3, out.as_mut_ptr(), 2),
+                ffi::VIRTIO_ERR_RANGE
+            );
+            assert_eq!(
+                ffi::virtio_config_write(dev, 4, value.as_ptr(), 1),
+                ffi::VIRTIO_ERR_RANGE
+            );
             ffi::virtio_transport_destroy(dev);
         }
     }
+
+    #[test]
+    fn net_device_both_directions() {
+        let (pipe, peer) = device::FramePipe::pair();
+        let model = device::NetDevice::new([2, 0, 0, 0, 0, 1], pipe);
+        let sim = unsafe { device::SimDevice::new(model) };
+        let mut t = mmio::MmioTransport::new(sim).unwrap();
+        t.reset();
+        t.set_status((Status::ACKNOWLEDGE | Status::DRIVER).bits() as u32);
+        t.set_driver_features(features::Features::VERSION_1.bits());
+        t.set_status((Status::ACKNOWLEDGE | Status::DRIVER | Status::FEATURES_OK).bits() as u32);
+
+        // Both queues have to be attached, not only the first one.
+        let mut rx = queue::VirtQueue::new(8).unwrap();
+        let mut tx = queue::VirtQueue::new(8).unwrap();
+        t.setup_queue(device::net::RX_QUEUE, &rx).unwrap();
+        t.setup_queue(device::net::TX_QUEUE, &tx).unwrap();
+
+        let header = [0u8; device::net::NET_HDR_LEN_MRG];
+        let frame = [0x5au8; 60];
+        tx.add(&[
+            buf(header.as_ptr() as u64, header.len() as u32, 0),
+            buf(frame.as_ptr() as u64, frame.len() as u32, 0),
+        ])
+        .unwrap();
+        t.notify(device::net::TX_QUEUE);
+        assert_eq!(peer.recv().as_deref(), Some(&frame[..]));
+        assert!(tx.pop_used().is_some());
+
+        let mut rx_buf = [0u8; 2048];
+        rx.add(&[buf(
+            rx_buf.as_mut_ptr() as u64,
+            rx_buf.len() as u32,
+            queue::VIRTQ_DESC_F_WRITE,
+        )])
+        .unwrap();
+        t.notify(device::net::RX_QUEUE);
+        assert!(peer.send(vec![0xa5; 100]));
+        t.regs_mut().poll();
+        let (_, len) = rx.pop_used().unwrap();
+        assert_eq!(len as usize, device::net::NET_HDR_LEN_MRG + 100);
+        // num_buffers
+        assert_eq!(&rx_buf[10..12], &[1, 0]);
+        assert!(rx_buf[12..112].iter().all(|&b| b == 0xa5));
+        assert_eq!(t.regs().model().rx_dropped(), 0);
+    }
+
+    /// Let a console driver and its simulated device exchange messages until
+    /// both are idle.
+    fn console_settle(
+        driver: &mut console::ConsoleDriver<device::SimDevice<device::ConsoleDevice>>,
+    ) {
+        for _ in 0..8 {
+            driver.transport_mut().regs_mut().poll();
+            driver.interrupt().unwrap();
+        }
+    }
+
+    #[test]
+    fn console_multiport() {
+        let mut model = device::ConsoleDevice::new(4, 80, 25);
+        let console = model.add_port(None, true).unwrap();
+        let log = model.add_port(Some("log"), false).unwrap();
+        let sim = unsafe { device::SimDevice::new(model) };
+        let mut driver =
+            console::ConsoleDriver::new(mmio::MmioTransport::new(sim).unwrap()).unwrap();
+        assert!(driver.multiport());
+        console_settle(&mut driver);
+
+        assert_eq!(driver.size(), Some((80, 25)));
+        assert_eq!(driver.ports().count(), 2);
+        assert!(driver.port(console).unwrap().console);
+        assert!(driver.port(console).unwrap().open);
+        assert_eq!(driver.find("log"), Some(log));
+        assert!(driver.port(log).unwrap().host_connected);
+        assert_eq!(
+            driver.write(log, b"x"),
+            Err(console::ConsoleError::Closed(log))
+        );
+
+        driver.open(log).unwrap();
+        assert_eq!(driver.write(log, b"hello").unwrap(), 5);
+        let sim = driver.transport_mut().regs_mut();
+        sim.model_mut().host_write(log, b"world");
+        console_settle(&mut driver);
+        let model = driver.transport_mut().regs_mut().model_mut();
+        assert!(model.guest_open(log));
+        assert_eq!(model.host_read(log), b"hello");
+        let mut buf = [0u8; 16];
+        assert_eq!(driver.read(log, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(driver.read(log, &mut buf).unwrap(), 0);
+
+        // Ports come and go at runtime.
+        let sim = driver.transport_mut().regs_mut();
+        sim.model_mut().remove_port(log);
+        let shell = sim.model_mut().add_port(Some("shell"), false).unwrap();
+        sim.model_mut().set_host_connected(console, false);
+        sim.model_mut().resize(132, 43);
+        console_settle(&mut driver);
+        assert_eq!(driver.find("log"), None);
+        assert_eq!(driver.find("shell"), Some(shell));
+        assert!(!driver.port(console).unwrap().host_connected);
+        assert_eq!(driver.size(), Some((132, 43)));
+        assert_eq!(
+            driver.read(99, &mut buf),
+            Err(console::ConsoleError::NoPort(99))
+        );
+    }
+
+    #[test]
+    fn rng_holds_requests_until_entropy() {
+        use device::EntropySource;
+
+        let mut source = device::FakeSource::new(7);
+        source.set_budget(Some(40));
+        let sim = unsafe { device::SimDevice::new(device::RngDevice::new(source)) };
+        let mut driver = rng::RngDriver::new(mmio::MmioTransport::new(sim).unwrap()).unwrap();
+        driver.request(32).unwrap();
+        driver.request(32).unwrap();
+        driver.interrupt();
+
+        // The first request is served in full, the second with what is left.
+        let mut expected = [0u8; 40];
+        device::FakeSource::new(7).fill(&mut expected);
+        let mut buf = [0u8; 64];
+        assert_eq!(driver.take(&mut buf), 40);
+        assert_eq!(buf[..40], expected);
+        assert_eq!(driver.pending(), 0);
+
+        // A dry source keeps the request with the device.
+        driver.request(16).unwrap();
+        driver.interrupt();
+        assert_eq!(driver.available(), 0);
+        assert_eq!(driver.pending(), 1);
+        let sim = driver.transport_mut().regs_mut();
+        sim.model_mut().source_mut().set_budget(None);
+        sim.poll();
+        driver.interrupt();
+        assert_eq!(driver.take(&mut buf), 16);
+        assert_eq!(driver.transport().regs().model().delivered(), 56);
+    }
+
+    #[test]
+    fn vsock_packets_and_reset() {
+        use vsock::{VsockHeader, VsockPacket, VIRTIO_VSOCK_OP_RW, VMADDR_CID_HOST};
+
+        let sim = unsafe { device::SimDevice::new(device::VsockDevice::new(3)) };
+        let mut driver = vsock::VsockDriver::new(mmio::MmioTransport::new(sim).unwrap()).unwrap();
+        assert_eq!(driver.guest_cid(), 3);
+
+        let hdr = VsockHeader {
+            src_cid: 3,
+            dst_cid: VMADDR_CID_HOST,
+            src_port: 1024,
+            dst_port: 80,
+            op: VIRTIO_VSOCK_OP_RW,
+            buf_alloc: 4096,
+            ..VsockHeader::default()
+        };
+        let pkt = VsockPacket {
+            hdr,
+            data: b"ping".to_vec(),
+        };
+        driver.send(&pkt).unwrap();
+        let sim = driver.transport_mut().regs_mut();
+        let sent = sim.model_mut().host_recv().unwrap();
+        assert_eq!(sent.hdr.len, 4);
+        assert_eq!(sent.data, b"ping");
+
+        let reply = VsockPacket {
+            hdr: VsockHeader {
+                src_cid: VMADDR_CID_HOST,
+                dst_cid: 3,
+                src_port: 80,
+                dst_port: 1024,
+                len: 4,
+                ..hdr
+            },
+            data: b"pong".to_vec(),
+        };
+        sim.model_mut().host_send(reply.clone());
+        sim.poll();
+        driver.interrupt().unwrap();
+        assert_eq!(driver.recv(), Some(reply));
+        assert_eq!(driver.recv(), None);
+        assert!(!driver.take_reset());
+
+        // A reset comes with a new CID.
+        let sim = driver.transport_mut().regs_mut();
+        sim.model_mut().transport_reset(7);
+        sim.refresh_config();
+        sim.poll();
+        driver.interrupt().unwrap();
+        assert!(driver.take_reset());
+        assert_eq!(driver.guest_cid(), 7);
+        let big = VsockPacket {
+            hdr,
+            data: vec![0; vsock::MAX_PAYLOAD + 1],
+        };
+        assert_eq!(
+            driver.send(&big),
+            Err(vsock::VsockError::TooLarge(vsock::MAX_PAYLOAD + 1))
+        );
+    }
 }
@@ -0,0 +1,519 @@
+//! virtio-mmio transport, version 2 register layout (section 4.2 of the
+//! virtio 1.2 specification).
+//!
+//! Registers are reached through [`Registers`], implemented by
+//! [`MmioRegion`] for a mapped device and by [`SimRegisters`], a device
+//! simulated in plain memory that tests and device models drive.
+
+use core::ptr;
+
+use crate::config::ConfigSpace;
+use crate::queue::Queue;
+
+pub const MAGIC_VALUE: usize = 0x000;
+pub const VERSION: usize = 0x004;
+pub const DEVICE_ID: usize = 0x008;
+pub const VENDOR_ID: usize = 0x00c;
+pub const DEVICE_FEATURES: usize = 0x010;
+pub const DEVICE_FEATURES_SEL: usize = 0x014;
+pub const DRIVER_FEATURES: usize = 0x020;
+pub const DRIVER_FEATURES_SEL: usize = 0x024;
+pub const QUEUE_SEL: usize = 0x030;
+pub const QUEUE_NUM_MAX: usize = 0x034;
+pub const QUEUE_NUM: usize = 0x038;
+pub const QUEUE_READY: usize = 0x044;
+pub const QUEUE_NOTIFY: usize = 0x050;
+pub const INTERRUPT_STATUS: usize = 0x060;
+pub const INTERRUPT_ACK: usize = 0x064;
+pub const STATUS: usize = 0x070;
+pub const QUEUE_DESC_LOW: usize = 0x080;
+pub const QUEUE_DESC_HIGH: usize = 0x084;
+pub const QUEUE_DRIVER_LOW: usize = 0x090;
+pub const QUEUE_DRIVER_HIGH: usize = 0x094;
+pub const QUEUE_DEVICE_LOW: usize = 0x0a0;
+pub const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+pub const CONFIG_GENERATION: usize = 0x0fc;
+/// Start of the device-specific configuration space.
+pub const CONFIG: usize = 0x100;
+/// Configuration space that fits into the usual 0x200 byte device window.
+pub const MAX_CONFIG_LEN: usize = 0x100;
+
+/// "virt" in little endian.
+pub const MMIO_MAGIC: u32 = 0x7472_6976;
+/// The only register layout supported; version 1 is the legacy layout.
+pub const MMIO_VERSION: u32 = 2;
+
+/// Interrupt status: a queue has used buffers.
+pub const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+/// Interrupt status: the configuration space changed.
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+/// Access to the registers of a virtio-mmio device.
+pub trait Registers {
+    fn read32(&self, offset: usize) -> u32;
+    fn write32(&mut self, offset: usize, value: u32);
+    /// Byte-wide access, used for the configuration space.
+    fn read8(&self, offset: usize) -> u8;
+    fn write8(&mut self, offset: usize, value: u8);
+}
+
+/// Registers of a device mapped into the address space.
+pub struct MmioRegion {
+    base: *mut u8,
+}
+
+impl MmioRegion {
+    /// # Safety
+    ///
+    /// `base` must point to the mapped register window of a virtio-mmio
+    /// device, at least 0x100 bytes plus its configuration space.
+    pub unsafe fn new(base: *mut u8) -> Self {
+        Self { base }
+    }
+}
+
+impl Registers for MmioRegion {
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.base.add(offset) as *const u32) }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.base.add(offset) as *mut u32, value) }
+    }
+
+    fn read8(&self, offset: usize) -> u8 {
+        unsafe { ptr::read_volatile(self.base.add(offset)) }
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        unsafe { ptr::write_volatile(self.base.add(offset), value) }
+    }
+}
+
+/// Errors of the mmio transport.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MmioError {
+    /// MagicValue is not "virt".
+    BadMagic(u32),
+    /// The device uses a register layout other than version 2.
+    UnsupportedVersion(u32),
+    /// DeviceID 0: a placeholder without a device behind it.
+    NoDevice,
+    /// The device has no queue with that index.
+    QueueUnavailable,
+    /// The queue is already set up.
+    QueueInUse,
+    /// The queue has more entries than the device supports.
+    QueueTooLarge,
+}
+
+impl core::fmt::Display for MmioError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MmioError::BadMagic(v) => write!(f, "bad magic value {v:#x}"),
+            MmioError::UnsupportedVersion(v) => write!(f, "unsupported mmio version {v}"),
+            MmioError::NoDevice => f.write_str("no device"),
+            MmioError::QueueUnavailable => f.write_str("queue not available"),
+            MmioError::QueueInUse => f.write_str("queue already in use"),
+            MmioError::QueueTooLarge => f.write_str("queue larger than the device supports"),
+        }
+    }
+}
+
+impl std::error::Error for MmioError {}
+
+/// Driver side of a virtio-mmio device.
+pub struct MmioTransport<R: Registers> {
+    regs: R,
+    device_id: u32,
+    config_len: usize,
+}
+
+impl<R: Registers> MmioTransport<R> {
+    /// Check the identification registers and take over the device.
+    pub fn new(regs: R) -> Result<Self, MmioError> {
+        let magic = regs.read32(MAGIC_VALUE);
+        if magic != MMIO_MAGIC {
+            return Err(MmioError::BadMagic(magic));
+        }
+        let version = regs.read32(VERSION);
+        if version != MMIO_VERSION {
+            return Err(MmioError::UnsupportedVersion(version));
+        }
+        let device_id = regs.read32(DEVICE_ID);
+        if device_id == 0 {
+            return Err(MmioError::NoDevice);
+        }
+        Ok(Self {
+            regs,
+            device_id,
+            config_len: MAX_CONFIG_LEN,
+        })
+    }
+
+    /// Limit configuration space accesses to the `len` bytes the device
+    /// type defines.
+    pub fn with_config_len(mut self, len: usize) -> Self {
+        self.config_len = len.min(MAX_CONFIG_LEN);
+        self
+    }
+
+    pub fn regs(&self) -> &R {
+        &self.regs
+    }
+
+    pub fn regs_mut(&mut self) -> &mut R {
+        &mut self.regs
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        self.regs.read32(VENDOR_ID)
+    }
+
+    /// Read all 64 feature bits through the 32-bit selection window.
+    pub fn device_features(&mut self) -> u64 {
+        self.regs.write32(DEVICE_FEATURES_SEL, 0);
+        let low = self.regs.read32(DEVICE_FEATURES) as u64;
+        self.regs.write32(DEVICE_FEATURES_SEL, 1);
+        let high = self.regs.read32(DEVICE_FEATURES) as u64;
+        high << 32 | low
+    }
+
+    pub fn set_driver_features(&mut self, features: u64) {
+        self.regs.write32(DRIVER_FEATURES_SEL, 0);
+        self.regs.write32(DRIVER_FEATURES, features as u32);
+        self.regs.write32(DRIVER_FEATURES_SEL, 1);
+        self.regs.write32(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    pub fn status(&self) -> u32 {
+        self.regs.read32(STATUS)
+    }
+
+    pub fn set_status(&mut self, status: u32) {
+        self.regs.write32(STATUS, status);
+    }
+
+    /// Reset the device and wait until it reports the reset as done.
+    pub fn reset(&mut self) {
+        self.regs.write32(STATUS, 0);
+        while self.regs.read32(STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Largest size queue `index` supports, 0 if there is no such queue.
+    pub fn queue_max(&mut self, index: u16) -> u16 {
+        self.regs.write32(QUEUE_SEL, index as u32);
+        self.regs.read32(QUEUE_NUM_MAX).min(u16::MAX as u32) as u16
+    }
+
+    /// Program the size and areas of `queue` into queue `index` and mark it
+    /// ready.
+    pub fn setup_queue(&mut self, index: u16, queue: &dyn Queue) -> Result<(), MmioError> {
+        self.setup_queue_at(
+            index,
+            queue.size(),
+            queue.desc_addr(),
+            queue.driver_addr(),
+            queue.device_addr(),
+        )
+    }
+
+    /// Like [`Self::setup_queue`] for a queue of `size` entries the driver
+    /// manages itself, given by the addresses of its areas.
+    pub fn setup_queue_at(
+        &mut self,
+        index: u16,
+        size: u16,
+        desc: u64,
+        driver: u64,
+        device: u64,
+    ) -> Result<(), MmioError> {
+        self.regs.write32(QUEUE_SEL, index as u32);
+        if self.regs.read32(QUEUE_READY) != 0 {
+            return Err(MmioError::QueueInUse);
+        }
+        let max = self.regs.read32(QUEUE_NUM_MAX);
+        if max == 0 {
+            return Err(MmioError::QueueUnavailable);
+        }
+        if size as u32 > max {
+            return Err(MmioError::QueueTooLarge);
+        }
+        self.regs.write32(QUEUE_NUM, size as u32);
+        self.write64(QUEUE_DESC_LOW, desc);
+        self.write64(QUEUE_DRIVER_LOW, driver);
+        self.write64(QUEUE_DEVICE_LOW, device);
+        self.regs.write32(QUEUE_READY, 1);
+        Ok(())
+    }
+
+    /// Take queue `index` away from the device.
+    pub fn disable_queue(&mut self, index: u16) {
+        self.regs.write32(QUEUE_SEL, index as u32);
+        self.regs.write32(QUEUE_READY, 0);
+    }
+
+    fn write64(&mut self, low: usize, value: u64) {
+        self.regs.write32(low, value as u32);
+        self.regs.write32(low + 4, (value >> 32) as u32);
+    }
+
+    /// Tell the device queue `index` has new buffers.
+    pub fn notify(&mut self, index: u16) {
+        self.regs.write32(QUEUE_NOTIFY, index as u32);
+    }
+
+    /// Read and acknowledge the pending interrupt causes.
+    pub fn ack_interrupt(&mut self) -> u32 {
+        let status = self.regs.read32(INTERRUPT_STATUS);
+        if status != 0 {
+            self.regs.write32(INTERRUPT_ACK, status);
+        }
+        status
+    }
+}
+
+impl<R: Registers> ConfigSpace for MmioTransport<R> {
+    fn config_len(&self) -> usize {
+        self.config_len
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.regs.read32(CONFIG_GENERATION)
+    }
+
+    fn read_raw(&self, offset: usize, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = self.regs.read8(CONFIG + offset + i);
+        }
+    }
+
+    fn write_raw(&mut self, offset: usize, data: &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            self.regs.write8(CONFIG + offset + i, *b);
+        }
+    }
+}
+
+/// Queue registers of a [`SimRegisters`] device.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimQueue {
+    pub num_max: u32,
+    pub num: u32,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+}
+
+/// A virtio-mmio device simulated in plain memory. The identification
+/// fields are public so tests can present any device; the rest changes
+/// through register writes like on real hardware.
+pub struct SimRegisters {
+    pub magic: u32,
+    pub version: u32,
+    pub device_id: u32,
+    pub vendor_id: u32,
+    pub device_features: u64,
+    driver_features: u64,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<SimQueue>,
+    notifications: Vec<u32>,
+    interrupt_status: u32,
+    status: u32,
+    config: Vec<u8>,
+    generation: u32,
+}
+
+impl SimRegisters {
+    /// A device with `queues` queues of up to `queue_num_max` entries and a
+    /// configuration space of `config_len` bytes.
+    pub fn new(
+        device_id: u32,
+        device_features: u64,
+        queues: usize,
+        queue_num_max: u32,
+        config_len: usize,
+    ) -> Self {
+        Self {
+            magic: MMIO_MAGIC,
+            version: MMIO_VERSION,
+            device_id,
+            vendor_id: 0x554d_4551,
+            device_features,
+            driver_features: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: vec![
+                SimQueue {
+                    num_max: queue_num_max,
+                    ..Default::default()
+                };
+                queues
+            ],
+            notifications: Vec::new(),
+            interrupt_status: 0,
+            status: 0,
+            config: vec![0; config_len],
+            generation: 0,
+        }
+    }
+
+    /// Features the driver accepted.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    pub fn queue(&self, index: usize) -> Option<SimQueue> {
+        self.queues.get(index).copied()
+    }
+
+    /// Queue the driver selected last. The register itself is write-only.
+    pub fn queue_sel(&self) -> u32 {
+        self.queue_sel
+    }
+
+    /// Queue notifications written by the driver since the last call.
+    pub fn take_notifications(&mut self) -> Vec<u32> {
+        core::mem::take(&mut self.notifications)
+    }
+
+    /// Raise the interrupt causes `status`.
+    pub fn interrupt(&mut self, status: u32) {
+        self.interrupt_status |= status;
+    }
+
+    /// Interrupt causes not yet acknowledged.
+    pub fn interrupt_status(&self) -> u32 {
+        self.interrupt_status
+    }
+
+    pub fn config(&self) -> &[u8] {
+        &self.config
+    }
+
+    /// Change the configuration space as the device, which starts a new
+    /// generation and raises a configuration change interrupt.
+    pub fn set_config(&mut self, offset: usize, data: &[u8]) {
+        self.config[offset..offset + data.len()].copy_from_slice(data);
+        self.generation = self.generation.wrapping_add(1);
+        self.interrupt(INTERRUPT_CONFIG_CHANGE);
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.driver_features = 0;
+        self.interrupt_status = 0;
+        for q in &mut self.queues {
+            *q = SimQueue {
+                num_max: q.num_max,
+                ..Default::default()
+            };
+        }
+    }
+
+    fn selected(&mut self) -> Option<&mut SimQueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+}
+
+fn set_low(value: &mut u64, low: u32) {
+    *value = (*value & !0xffff_ffff) | low as u64;
+}
+
+fn set_high(value: &mut u64, high: u32) {
+    *value = (*value & 0xffff_ffff) | (high as u64) << 32;
+}
+
+impl Registers for SimRegisters {
+    fn read32(&self, offset: usize) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            MAGIC_VALUE => self.magic,
+            VERSION => self.version,
+            DEVICE_ID => self.device_id,
+            VENDOR_ID => self.vendor_id,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features as u32,
+                1 => (self.device_features >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |q| q.num_max),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => self.generation,
+            _ if offset >= CONFIG => {
+                u32::from_le_bytes(core::array::from_fn(|i| self.read8(offset + i)))
+            }
+            _ => 0,
+        }
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, value),
+                1 => set_high(&mut self.driver_features, value),
+                _ => {}
+            },
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NOTIFY => self.notifications.push(value),
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS if value == 0 => self.reset(),
+            STATUS => self.status = value,
+            _ if offset >= CONFIG => {
+                for (i, b) in value.to_le_bytes().into_iter().enumerate() {
+                    self.write8(offset + i, b);
+                }
+            }
+            _ => {
+                let Some(q) = self.selected() else {
+                    return;
+                };
+                match offset {
+                    QUEUE_NUM => q.num = value,
+                    QUEUE_READY => q.ready = value & 1 != 0,
+                    QUEUE_DESC_LOW => set_low(&mut q.desc, value),
+                    QUEUE_DESC_HIGH => set_high(&mut q.desc, value),
+                    QUEUE_DRIVER_LOW => set_low(&mut q.driver, value),
+                    QUEUE_DRIVER_HIGH => set_high(&mut q.driver, value),
+                    QUEUE_DEVICE_LOW => set_low(&mut q.device, value),
+                    QUEUE_DEVICE_HIGH => set_high(&mut q.device, value),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn read8(&self, offset: usize) -> u8 {
+        if offset < CONFIG {
+            return (self.read32(offset & !3) >> (8 * (offset & 3))) as u8;
+        }
+        self.config.get(offset - CONFIG).copied().unwrap_or(0)
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        if let Some(b) = offset
+            .checked_sub(CONFIG)
+            .and_then(|i| self.config.get_mut(i))
+        {
+            *b = value;
+        }
+    }
+}
@@ -0,0 +1,696 @@
+//! Packed virtqueue as laid out in section 2.8 of the virtio 1.2
+//! specification.
+//!
+//! Descriptors live in a single ring that driver and device walk in the same
+//! direction. Whether a slot holds an available or a used descriptor is
+//! told by its AVAIL and USED flags compared with a wrap counter each side
+//! flips whenever it passes the end of the ring. [`PackedQueue`] is the
+//! driver side, [`PackedDeviceQueue`] the device side of the same memory.
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use std::collections::{HashMap, VecDeque};
+
+use crate::queue::{
+    need_event, DescChain, DeviceRing, Queue, QueueError, QueueMemory, VirtqDesc,
+    VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_IN_ORDER, VIRTQ_DESC_F_INDIRECT,
+    VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+};
+
+/// Set to the driver's wrap counter when a descriptor is made available.
+pub const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+/// Set to the device's wrap counter when a descriptor is used.
+pub const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+/// Event suppression: notifications are wanted.
+pub const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+/// Event suppression: notifications are not wanted.
+pub const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+/// Event suppression: a notification is wanted for the descriptor named by
+/// `desc`. Requires VIRTIO_F_EVENT_IDX.
+pub const RING_EVENT_FLAGS_DESC: u16 = 2;
+
+/// Largest queue size the packed format allows.
+pub const MAX_PACKED_SIZE: u16 = 1 << 15;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PvirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub id: u16,
+    pub flags: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PvirtqEventSuppress {
+    /// Ring offset in bits 0..15, wrap counter in bit 15.
+    pub desc: u16,
+    pub flags: u16,
+}
+
+/// Byte offsets of the parts of a packed queue within its memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PackedLayout {
+    /// Number of entries.
+    pub size: u16,
+    /// Offset of the driver event suppression structure; the descriptor
+    /// ring starts at 0.
+    pub driver: usize,
+    /// Offset of the device event suppression structure.
+    pub device: usize,
+    /// Bytes needed for the whole queue.
+    pub total: usize,
+}
+
+impl PackedLayout {
+    pub fn new(size: u16) -> Result<Self, QueueError> {
+        if size == 0 || size > MAX_PACKED_SIZE {
+            return Err(QueueError::InvalidSize);
+        }
+        let driver = 16 * size as usize;
+        Ok(Self {
+            size,
+            driver,
+            device: driver + 4,
+            total: driver + 8,
+        })
+    }
+}
+
+fn avail_flags(wrap: bool) -> u16 {
+    if wrap {
+        VIRTQ_DESC_F_AVAIL
+    } else {
+        VIRTQ_DESC_F_USED
+    }
+}
+
+fn used_flags(wrap: bool) -> u16 {
+    if wrap {
+        VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+    } else {
+        0
+    }
+}
+
+fn is_avail(flags: u16, wrap: bool) -> bool {
+    (flags & VIRTQ_DESC_F_AVAIL != 0) == wrap && (flags & VIRTQ_DESC_F_USED != 0) != wrap
+}
+
+fn is_used(flags: u16, wrap: bool) -> bool {
+    let used = flags & VIRTQ_DESC_F_USED != 0;
+    (flags & VIRTQ_DESC_F_AVAIL != 0) == used && used == wrap
+}
+
+fn off_wrap(pos: u16, wrap: bool) -> u16 {
+    pos | (wrap as u16) << 15
+}
+
+/// Move a ring position forward by `by` slots, flipping the wrap counter
+/// when it passes the end.
+fn advance(pos: &mut u16, wrap: &mut bool, by: u16, size: u16) {
+    let next = *pos as u32 + by as u32;
+    if next >= size as u32 {
+        *pos = (next - size as u32) as u16;
+        *wrap = !*wrap;
+    } else {
+        *pos = next as u16;
+    }
+}
+
+/// Whether the last `added` slots up to position `new` (in the lap of wrap
+/// counter `wrap`) cover the slot an event suppression structure names.
+fn event_passed(event: u16, wrap: bool, size: u16, new: u16, added: u16) -> bool {
+    if added >= size {
+        return true;
+    }
+    let mut pos = event & !(1 << 15);
+    if (event >> 15 != 0) != wrap {
+        pos = pos.wrapping_sub(size);
+    }
+    need_event(pos, new, new.wrapping_sub(added))
+}
+
+/// Raw view of the descriptor ring and event suppression structures.
+#[derive(Copy, Clone)]
+struct Ring {
+    desc: *mut PvirtqDesc,
+    driver: *mut PvirtqEventSuppress,
+    device: *mut PvirtqEventSuppress,
+    size: u16,
+}
+
+impl Ring {
+    unsafe fn desc(&self, pos: u16) -> PvirtqDesc {
+        ptr::read_volatile(self.desc.add(pos as usize))
+    }
+
+    unsafe fn set_desc(&self, pos: u16, desc: PvirtqDesc) {
+        ptr::write_volatile(self.desc.add(pos as usize), desc)
+    }
+
+    unsafe fn flags(&self, pos: u16) -> u16 {
+        ptr::read_volatile(ptr::addr_of!((*self.desc.add(pos as usize)).flags))
+    }
+
+    /// Write everything but the flags, which are published separately.
+    unsafe fn set_body(&self, pos: u16, desc: PvirtqDesc) {
+        let d = self.desc.add(pos as usize);
+        ptr::write_volatile(ptr::addr_of_mut!((*d).addr), desc.addr);
+        ptr::write_volatile(ptr::addr_of_mut!((*d).len), desc.len);
+        ptr::write_volatile(ptr::addr_of_mut!((*d).id), desc.id);
+    }
+
+    unsafe fn set_flags(&self, pos: u16, flags: u16) {
+        ptr::write_volatile(
+            ptr::addr_of_mut!((*self.desc.add(pos as usize)).flags),
+            flags,
+        )
+    }
+}
+
+/// A buffer the device owns, indexed by its id.
+#[derive(Default)]
+struct Buffer {
+    /// Ring slots the buffer took; 0 while the id is free.
+    slots: u16,
+    /// Keeps an indirect table alive while the device may read it.
+    _table: Option<Box<[PvirtqDesc]>>,
+}
+
+/// Driver side of a packed virtqueue.
+pub struct PackedQueue {
+    mem: QueueMemory,
+    layout: PackedLayout,
+    ring: Ring,
+    num_free: u16,
+    next_avail: u16,
+    avail_wrap: bool,
+    last_used: u16,
+    used_wrap: bool,
+    /// Slots made available since the device was last considered for a
+    /// notification.
+    added: u16,
+    free_ids: Vec<u16>,
+    buffers: Vec<Buffer>,
+    /// Ids in the order they were made available, for in-order completion.
+    in_flight: VecDeque<u16>,
+    /// Buffers completed by one used descriptor but not yet popped.
+    completed: VecDeque<(u16, u32)>,
+    event_idx: bool,
+    indirect: bool,
+    in_order: bool,
+    interrupts: bool,
+}
+
+// The ring points into `mem`, which the queue alone owns.
+unsafe impl Send for PackedQueue {}
+
+impl PackedQueue {
+    /// Allocate a queue of `size` entries. Unlike split queues the size need
+    /// not be a power of two.
+    pub fn new(size: u16) -> Result<Self, QueueError> {
+        let layout = PackedLayout::new(size)?;
+        let mem = QueueMemory::new(layout.total);
+        let base = mem.as_ptr();
+        let ring = Ring {
+            desc: base as *mut PvirtqDesc,
+            driver: unsafe { base.add(layout.driver) } as *mut PvirtqEventSuppress,
+            device: unsafe { base.add(layout.device) } as *mut PvirtqEventSuppress,
+            size,
+        };
+        let mut queue = Self {
+            mem,
+            layout,
+            ring,
+            num_free: 0,
+            next_avail: 0,
+            avail_wrap: true,
+            last_used: 0,
+            used_wrap: true,
+            added: 0,
+            free_ids: Vec::with_capacity(size as usize),
+            buffers: (0..size).map(|_| Buffer::default()).collect(),
+            in_flight: VecDeque::new(),
+            completed: VecDeque::new(),
+            event_idx: false,
+            indirect: false,
+            in_order: false,
+            interrupts: true,
+        };
+        queue.reset();
+        Ok(queue)
+    }
+
+    pub fn layout(&self) -> PackedLayout {
+        self.layout
+    }
+
+    /// Give the slots of buffer `id` back and queue its completion.
+    fn release(&mut self, id: u16, len: u32) {
+        let buffer = core::mem::take(&mut self.buffers[id as usize]);
+        advance(
+            &mut self.last_used,
+            &mut self.used_wrap,
+            buffer.slots,
+            self.layout.size,
+        );
+        self.num_free += buffer.slots;
+        self.free_ids.push(id);
+        self.completed.push_back((id, len));
+    }
+
+    fn set_driver_event(&mut self, event: PvirtqEventSuppress) {
+        unsafe { ptr::write_volatile(self.ring.driver, event) };
+    }
+}
+
+impl Queue for PackedQueue {
+    fn size(&self) -> u16 {
+        self.layout.size
+    }
+
+    fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    fn set_features(&mut self, features: u64) {
+        self.event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+        self.indirect = features & VIRTIO_F_INDIRECT_DESC != 0;
+        self.in_order = features & VIRTIO_F_IN_ORDER != 0;
+    }
+
+    fn reset(&mut self) {
+        self.mem.clear();
+        let size = self.layout.size;
+        self.num_free = size;
+        self.next_avail = 0;
+        self.avail_wrap = true;
+        self.last_used = 0;
+        self.used_wrap = true;
+        self.added = 0;
+        self.free_ids.clear();
+        self.free_ids.extend((0..size).rev());
+        self.buffers.iter_mut().for_each(|b| *b = Buffer::default());
+        self.in_flight.clear();
+        self.completed.clear();
+        self.interrupts = true;
+    }
+
+    fn desc_addr(&self) -> u64 {
+        self.ring.desc as u64
+    }
+
+    fn driver_addr(&self) -> u64 {
+        self.ring.driver as u64
+    }
+
+    fn device_addr(&self) -> u64 {
+        self.ring.device as u64
+    }
+
+    fn add(&mut self, chain: &[VirtqDesc]) -> Result<u16, QueueError> {
+        if chain.is_empty() {
+            return Err(QueueError::EmptyChain);
+        }
+        if chain.len() > self.layout.size as usize {
+            return Err(QueueError::ChainTooLong);
+        }
+        let indirect = self.indirect && chain.len() > 1;
+        let slots = if indirect { 1 } else { chain.len() as u16 };
+        if slots > self.num_free {
+            return Err(QueueError::Full);
+        }
+        let id = self.free_ids.pop().ok_or(QueueError::Full)?;
+        let head = self.next_avail;
+        let mut table = None;
+        let head_flags;
+        if indirect {
+            let entries: Box<[PvirtqDesc]> = chain
+                .iter()
+                .map(|d| PvirtqDesc {
+                    addr: d.addr,
+                    len: d.len,
+                    id: 0,
+                    flags: d.flags & VIRTQ_DESC_F_WRITE,
+                })
+                .collect();
+            let desc = PvirtqDesc {
+                addr: entries.as_ptr() as u64,
+                len: core::mem::size_of_val(&*entries) as u32,
+                id,
+                flags: 0,
+            };
+            unsafe { self.ring.set_body(head, desc) };
+            head_flags = VIRTQ_DESC_F_INDIRECT | avail_flags(self.avail_wrap);
+            table = Some(entries);
+        } else {
+            let mut pos = head;
+            let mut wrap = self.avail_wrap;
+            let mut first = 0;
+            for (i, d) in chain.iter().enumerate() {
+                let more = if i + 1 < chain.len() {
+                    VIRTQ_DESC_F_NEXT
+                } else {
+                    0
+                };
+                let desc = PvirtqDesc {
+                    addr: d.addr,
+                    len: d.len,
+                    id,
+                    flags: (d.flags & VIRTQ_DESC_F_WRITE) | more | avail_flags(wrap),
+                };
+                if i == 0 {
+                    first = desc.flags;
+                    unsafe { self.ring.set_body(pos, desc) };
+                } else {
+                    unsafe { self.ring.set_desc(pos, desc) };
+                }
+                advance(&mut pos, &mut wrap, 1, self.layout.size);
+            }
+            head_flags = first;
+        }
+        // The head's flags hand the whole chain over, so they go last.
+        fence(Ordering::Release);
+        unsafe { self.ring.set_flags(head, head_flags) };
+        advance(
+            &mut self.next_avail,
+            &mut self.avail_wrap,
+            slots,
+            self.layout.size,
+        );
+        self.num_free -= slots;
+        self.added = self.added.saturating_add(slots);
+        self.buffers[id as usize] = Buffer {
+            slots,
+            _table: table,
+        };
+        if self.in_order {
+            self.in_flight.push_back(id);
+        }
+        Ok(id)
+    }
+
+    fn needs_notify(&mut self) -> bool {
+        fence(Ordering::SeqCst);
+        let added = core::mem::take(&mut self.added);
+        if added == 0 {
+            return false;
+        }
+        let event = unsafe { ptr::read_volatile(self.ring.device) };
+        match event.flags {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.event_idx => event_passed(
+                event.desc,
+                self.avail_wrap,
+                self.layout.size,
+                self.next_avail,
+                added,
+            ),
+            _ => true,
+        }
+    }
+
+    fn has_used(&self) -> bool {
+        !self.completed.is_empty()
+            || is_used(unsafe { self.ring.flags(self.last_used) }, self.used_wrap)
+    }
+
+    fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if let Some(done) = self.completed.pop_front() {
+            return Some(done);
+        }
+        if !is_used(unsafe { self.ring.flags(self.last_used) }, self.used_wrap) {
+            return None;
+        }
+        // Read the descriptor only after seeing the flags that hand it over.
+        fence(Ordering::Acquire);
+        let desc = unsafe { self.ring.desc(self.last_used) };
+        let id = desc.id;
+        // Ids the driver never handed out are a device bug; there is no
+        // telling how far to skip, so the queue stalls.
+        if id as usize >= self.buffers.len() || self.buffers[id as usize].slots == 0 {
+            return None;
+        }
+        if self.in_order {
+            // One used descriptor may complete a whole batch; only the
+            // length of its last buffer is known.
+            while let Some(front) = self.in_flight.pop_front() {
+                self.release(front, if front == id { desc.len } else { 0 });
+                if front == id {
+                    break;
+                }
+            }
+        } else {
+            self.release(id, desc.len);
+        }
+        if self.event_idx && self.interrupts {
+            self.set_driver_event(PvirtqEventSuppress {
+                desc: off_wrap(self.last_used, self.used_wrap),
+                flags: RING_EVENT_FLAGS_DESC,
+            });
+        }
+        self.completed.pop_front()
+    }
+
+    fn disable_interrupts(&mut self) {
+        self.interrupts = false;
+        self.set_driver_event(PvirtqEventSuppress {
+            desc: 0,
+            flags: RING_EVENT_FLAGS_DISABLE,
+        });
+    }
+
+    fn enable_interrupts(&mut self) -> bool {
+        self.interrupts = true;
+        let event = if self.event_idx {
+            PvirtqEventSuppress {
+                desc: off_wrap(self.last_used, self.used_wrap),
+                flags: RING_EVENT_FLAGS_DESC,
+            }
+        } else {
+            PvirtqEventSuppress {
+                desc: 0,
+                flags: RING_EVENT_FLAGS_ENABLE,
+            }
+        };
+        self.set_driver_event(event);
+        fence(Ordering::SeqCst);
+        !self.has_used()
+    }
+}
+
+/// Device side of a packed virtqueue.
+pub struct PackedDeviceQueue {
+    ring: Ring,
+    next_avail: u16,
+    avail_wrap: bool,
+    next_used: u16,
+    used_wrap: bool,
+    /// Slots used since the driver was last considered for an interrupt.
+    used_added: u16,
+    /// Ring slots of each buffer the device holds, by id.
+    slots: HashMap<u16, u16>,
+    event_idx: bool,
+    notify: bool,
+}
+
+impl PackedDeviceQueue {
+    /// Attach to the queue of `size` entries at the given addresses.
+    ///
+    /// # Safety
+    ///
+    /// The addresses must point to a packed queue of `size` entries that
+    /// stays mapped while the device queue is used, such as one reported by
+    /// a [`PackedQueue`].
+    pub unsafe fn new(size: u16, desc: u64, driver: u64, device: u64) -> Result<Self, QueueError> {
+        if size == 0 || size > MAX_PACKED_SIZE {
+            return Err(QueueError::InvalidSize);
+        }
+        Ok(Self {
+            ring: Ring {
+                desc: desc as *mut PvirtqDesc,
+                driver: driver as *mut PvirtqEventSuppress,
+                device: device as *mut PvirtqEventSuppress,
+                size,
+            },
+            next_avail: 0,
+            avail_wrap: true,
+            next_used: 0,
+            used_wrap: true,
+            used_added: 0,
+            slots: HashMap::new(),
+            event_idx: false,
+            notify: true,
+        })
+    }
+
+    /// Complete the in-order batch `ids` with a single used descriptor, as a
+    /// device that negotiated VIRTIO_F_IN_ORDER may. `len` is the length of
+    /// the last buffer.
+    pub fn push_used_batch(&mut self, ids: &[u16], len: u32) {
+        let Some(&last) = ids.last() else {
+            return;
+        };
+        let slots = ids
+            .iter()
+            .map(|id| self.slots.remove(id).unwrap_or(1))
+            .sum();
+        self.write_used(last, len, slots);
+    }
+
+    fn write_used(&mut self, id: u16, len: u32, slots: u16) {
+        let desc = PvirtqDesc {
+            addr: 0,
+            len,
+            id,
+            flags: 0,
+        };
+        unsafe {
+            self.ring.set_body(self.next_used, desc);
+            fence(Ordering::Release);
+            self.ring
+                .set_flags(self.next_used, used_flags(self.used_wrap));
+        }
+        advance(
+            &mut self.next_used,
+            &mut self.used_wrap,
+            slots,
+            self.ring.size,
+        );
+        self.used_added = self.used_added.saturating_add(slots);
+    }
+
+    fn set_device_event(&mut self, event: PvirtqEventSuppress) {
+        unsafe { ptr::write_volatile(self.ring.device, event) };
+    }
+
+    /// Read the chain starting at `next_avail`, advancing past it.
+    fn walk(&mut self) -> Result<(u16, Vec<VirtqDesc>), QueueError> {
+        let mut descs = Vec::new();
+        let mut count = 0;
+        loop {
+            if count >= self.ring.size {
+                return Err(QueueError::Malformed);
+            }
+            let d = unsafe { self.ring.desc(self.next_avail) };
+            advance(
+                &mut self.next_avail,
+                &mut self.avail_wrap,
+                1,
+                self.ring.size,
+            );
+            count += 1;
+            if d.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if d.flags & VIRTQ_DESC_F_NEXT != 0 || !descs.is_empty() {
+                    return Err(QueueError::Malformed);
+                }
+                descs = Self::walk_indirect(&d)?;
+            } else {
+                descs.push(VirtqDesc {
+                    addr: d.addr,
+                    len: d.len,
+                    flags: d.flags & (VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT),
+                    next: 0,
+                });
+                if d.flags & VIRTQ_DESC_F_NEXT != 0 {
+                    continue;
+                }
+            }
+            self.slots.insert(d.id, count);
+            return Ok((d.id, descs));
+        }
+    }
+
+    fn walk_indirect(desc: &PvirtqDesc) -> Result<Vec<VirtqDesc>, QueueError> {
+        let entry = core::mem::size_of::<PvirtqDesc>();
+        let count = desc.len as usize / entry;
+        if count == 0 || !(desc.len as usize).is_multiple_of(entry) {
+            return Err(QueueError::Malformed);
+        }
+        let table = desc.addr as *const PvirtqDesc;
+        // The entries follow each other; only their WRITE flag counts.
+        Ok((0..count)
+            .map(|i| {
+                let d = unsafe { ptr::read_unaligned(table.add(i)) };
+                VirtqDesc {
+                    addr: d.addr,
+                    len: d.len,
+                    flags: (d.flags & VIRTQ_DESC_F_WRITE)
+                        | if i + 1 < count { VIRTQ_DESC_F_NEXT } else { 0 },
+                    next: 0,
+                }
+            })
+            .collect())
+    }
+}
+
+impl DeviceRing for PackedDeviceQueue {
+    fn set_features(&mut self, features: u64) {
+        self.event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+    }
+
+    fn set_notify(&mut self, enabled: bool) {
+        self.notify = enabled;
+        let event = if !enabled {
+            PvirtqEventSuppress {
+                desc: 0,
+                flags: RING_EVENT_FLAGS_DISABLE,
+            }
+        } else if self.event_idx {
+            PvirtqEventSuppress {
+                desc: off_wrap(self.next_avail, self.avail_wrap),
+                flags: RING_EVENT_FLAGS_DESC,
+            }
+        } else {
+            PvirtqEventSuppress {
+                desc: 0,
+                flags: RING_EVENT_FLAGS_ENABLE,
+            }
+        };
+        self.set_device_event(event);
+    }
+
+    fn pop_avail(&mut self) -> Result<Option<DescChain>, QueueError> {
+        if !is_avail(unsafe { self.ring.flags(self.next_avail) }, self.avail_wrap) {
+            return Ok(None);
+        }
+        fence(Ordering::Acquire);
+        let chain = self.walk();
+        if self.event_idx && self.notify {
+            self.set_device_event(PvirtqEventSuppress {
+                desc: off_wrap(self.next_avail, self.avail_wrap),
+                flags: RING_EVENT_FLAGS_DESC,
+            });
+        }
+        let (head, descs) = chain?;
+        Ok(Some(DescChain { head, descs }))
+    }
+
+    fn push_used(&mut self, head: u16, len: u32) {
+        let slots = self.slots.remove(&head).unwrap_or(1);
+        self.write_used(head, len, slots);
+    }
+
+    fn needs_interrupt(&mut self) -> bool {
+        fence(Ordering::SeqCst);
+        let added = core::mem::take(&mut self.used_added);
+        if added == 0 {
+            return false;
+        }
+        let event = unsafe { ptr::read_volatile(self.ring.driver) };
+        match event.flags {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.event_idx => event_passed(
+                event.desc,
+                self.used_wrap,
+                self.ring.size,
+                self.next_used,
+                added,
+            ),
+            _ => true,
+        }
+    }
+}
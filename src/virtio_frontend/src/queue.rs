@@ -1,10 +1,52 @@
-/// Structures implementing a very small virtqueue similar to the ones used in
-/// existing server implementations.
+//! Virtqueues. [`Queue`] and [`DeviceRing`] are the driver and device sides
+//! common to both ring formats; this module implements the split ring laid
+//! out in section 2.7 of the virtio 1.2 specification, [`crate::packed`] the
+//! packed ring.
+//!
+//! [`VirtQueue`] is the split driver side. It owns the descriptor table and
+//! both rings in one page-aligned allocation whose addresses are programmed
+//! into the device. [`DeviceQueue`] is the device side of the same memory;
+//! device models and tests use it to consume chains and complete them.
+//!
+//! Buffer addresses are used as given, so driver and device must share an
+//! address space (or the caller translates them before adding a chain).
 
-const QUEUE_SIZE: usize = 8;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{fence, Ordering};
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+
+use crate::packed::{PackedDeviceQueue, PackedQueue};
+
+/// The buffer continues in the descriptor named by `next`.
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device.
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The buffer holds a table of indirect descriptors.
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Set by the driver: no interrupt is needed after buffers are used.
+pub const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Set by the device: no notification is needed after buffers are added.
+pub const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/// Feature bit: descriptors may point to tables of further descriptors.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+/// Feature bit: `used_event`/`avail_event` replace the ring flags.
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+/// Feature bit: queues use the packed ring format.
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+/// Feature bit: the device uses buffers in the order they were made available.
+pub const VIRTIO_F_IN_ORDER: u64 = 1 << 35;
+
+/// Queue size used when the transport does not pick one.
+pub const DEFAULT_QUEUE_SIZE: u16 = 8;
+
+/// Alignment of the queue memory.
+const QUEUE_ALIGN: usize = 4096;
 
 #[repr(C)]
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct VirtqDesc {
     pub addr: u64,
     pub len: u32,
@@ -13,73 +55,858 @@ pub struct VirtqDesc {
 }
 
 #[repr(C)]
-pub struct VirtqAvail {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [u16; QUEUE_SIZE],
-    pub used_event: u16,
-}
-
-#[repr(C)]
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct VirtqUsedElem {
     pub id: u32,
     pub len: u32,
 }
 
-#[repr(C)]
-pub struct VirtqUsed {
-    pub flags: u16,
-    pub idx: u16,
-    pub ring: [VirtqUsedElem; QUEUE_SIZE],
-    pub avail_event: u16,
+/// Errors of queue operations.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueueError {
+    /// The queue size is zero or not valid for the ring format.
+    InvalidSize,
+    /// The chain holds no buffers.
+    EmptyChain,
+    /// The chain has more buffers than the queue has entries.
+    ChainTooLong,
+    /// Not enough free descriptors for the chain.
+    Full,
+    /// A chain loops, leaves the descriptor table or nests indirect tables.
+    Malformed,
 }
 
-/// Simple descriptor ring for demonstration purposes.
-pub struct VirtQueue {
-    pub desc: [VirtqDesc; QUEUE_SIZE],
-    pub avail: VirtqAvail,
-    pub used: VirtqUsed,
+impl core::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            QueueError::InvalidSize => "invalid queue size",
+            QueueError::EmptyChain => "descriptor chain is empty",
+            QueueError::ChainTooLong => "descriptor chain is longer than the queue",
+            QueueError::Full => "not enough free descriptors",
+            QueueError::Malformed => "malformed descriptor chain",
+        };
+        f.write_str(msg)
+    }
 }
 
-impl VirtQueue {
-    pub const fn new() -> Self {
-        const DESC: VirtqDesc = VirtqDesc {
-            addr: 0,
-            len: 0,
+impl std::error::Error for QueueError {}
+
+/// Driver side of a virtqueue, whatever its ring format.
+pub trait Queue {
+    /// Number of entries.
+    fn size(&self) -> u16;
+
+    /// Number of descriptors not owned by the device.
+    fn num_free(&self) -> u16;
+
+    /// Use the ring features among the negotiated `features`.
+    fn set_features(&mut self, features: u64);
+
+    /// Forget all chains and return the queue to its initial state. Only
+    /// valid while the device is not using the queue, e.g. after a reset.
+    fn reset(&mut self);
+
+    /// Address of the descriptor area.
+    fn desc_addr(&self) -> u64;
+
+    /// Address of the driver area: the available ring or the driver event
+    /// suppression structure.
+    fn driver_addr(&self) -> u64;
+
+    /// Address of the device area: the used ring or the device event
+    /// suppression structure.
+    fn device_addr(&self) -> u64;
+
+    /// Make a chain of buffers available to the device and return the token
+    /// [`pop_used`](Self::pop_used) reports it with. Only `addr`, `len` and
+    /// `VIRTQ_DESC_F_WRITE` of the given descriptors are used; the queue
+    /// links them itself, through an indirect table if that was negotiated.
+    fn add(&mut self, chain: &[VirtqDesc]) -> Result<u16, QueueError>;
+
+    /// Whether the device has to be notified of the chains added since the
+    /// last call.
+    fn needs_notify(&mut self) -> bool;
+
+    /// Whether the device has completed chains not yet popped.
+    fn has_used(&self) -> bool;
+
+    /// Take the next completed chain, returning its token and the number of
+    /// bytes the device wrote.
+    fn pop_used(&mut self) -> Option<(u16, u32)>;
+
+    /// Ask the device not to interrupt when it uses buffers. This is only a
+    /// hint; interrupts may still arrive.
+    fn disable_interrupts(&mut self);
+
+    /// Ask for interrupts again. Returns false if chains were completed in
+    /// the meantime, which the caller has to pop as no interrupt announces
+    /// them.
+    fn enable_interrupts(&mut self) -> bool;
+}
+
+/// Device side of a virtqueue, whatever its ring format.
+pub trait DeviceRing {
+    /// Use the ring features among the negotiated `features`.
+    fn set_features(&mut self, features: u64);
+
+    /// Ask the driver to (not) notify when it adds chains.
+    fn set_notify(&mut self, enabled: bool);
+
+    /// Take the next available chain. A malformed chain is consumed and
+    /// reported as an error.
+    fn pop_avail(&mut self) -> Result<Option<DescChain>, QueueError>;
+
+    /// Complete the chain `head`, of which `len` bytes were written.
+    fn push_used(&mut self, head: u16, len: u32);
+
+    /// Whether the driver wants an interrupt for the chains completed since
+    /// the last call.
+    fn needs_interrupt(&mut self) -> bool;
+}
+
+/// Allocate a driver queue of `size` entries in the ring format the
+/// negotiated `features` call for.
+pub fn for_features(size: u16, features: u64) -> Result<Box<dyn Queue + Send>, QueueError> {
+    let mut queue: Box<dyn Queue + Send> = if features & VIRTIO_F_RING_PACKED != 0 {
+        Box::new(PackedQueue::new(size)?)
+    } else {
+        Box::new(VirtQueue::new(size)?)
+    };
+    queue.set_features(features);
+    Ok(queue)
+}
+
+/// Attach the device side to a queue in the ring format the negotiated
+/// `features` call for.
+///
+/// # Safety
+///
+/// The addresses must point to a queue of `size` entries in that format
+/// which stays mapped while the device side is used, such as one reported
+/// by a [`Queue`].
+pub unsafe fn device_ring_for(
+    size: u16,
+    features: u64,
+    desc: u64,
+    driver: u64,
+    device: u64,
+) -> Result<Box<dyn DeviceRing>, QueueError> {
+    let mut ring: Box<dyn DeviceRing> = if features & VIRTIO_F_RING_PACKED != 0 {
+        Box::new(PackedDeviceQueue::new(size, desc, driver, device)?)
+    } else {
+        Box::new(DeviceQueue::new(size, desc, driver, device)?)
+    };
+    ring.set_features(features);
+    Ok(ring)
+}
+
+/// A driver queue the device fills, kept stocked with buffers of the same
+/// size.
+pub(crate) struct RxQueue {
+    pub(crate) queue: Box<dyn Queue + Send>,
+    /// Buffers handed to the device, by token.
+    bufs: HashMap<u16, Vec<u8>>,
+}
+
+impl RxQueue {
+    pub(crate) fn new(size: u16, features: u64, buf_size: usize) -> Result<Self, QueueError> {
+        let mut rx = Self {
+            queue: for_features(size, features)?,
+            bufs: HashMap::new(),
+        };
+        for _ in 0..size {
+            rx.post(vec![0; buf_size])?;
+        }
+        Ok(rx)
+    }
+
+    pub(crate) fn post(&mut self, mut buf: Vec<u8>) -> Result<(), QueueError> {
+        let desc = VirtqDesc {
+            addr: buf.as_mut_ptr() as u64,
+            len: buf.len() as u32,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        let token = self.queue.add(&[desc])?;
+        self.bufs.insert(token, buf);
+        Ok(())
+    }
+
+    /// Take the data of the next filled buffer and post the buffer again.
+    pub(crate) fn pop(&mut self) -> Option<Vec<u8>> {
+        let (token, len) = self.queue.pop_used()?;
+        let buf = self.bufs.remove(&token)?;
+        let data = buf[..(len as usize).min(buf.len())].to_vec();
+        // The buffer was just taken off the queue, so there is room for it.
+        let _ = self.post(buf);
+        Some(data)
+    }
+}
+
+/// A driver queue the device reads from.
+pub(crate) struct TxQueue {
+    pub(crate) queue: Box<dyn Queue + Send>,
+    /// Data in flight, by token.
+    bufs: HashMap<u16, Vec<u8>>,
+}
+
+impl TxQueue {
+    pub(crate) fn new(size: u16, features: u64) -> Result<Self, QueueError> {
+        Ok(Self {
+            queue: for_features(size, features)?,
+            bufs: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn push(&mut self, data: Vec<u8>) -> Result<(), QueueError> {
+        self.reap();
+        let desc = VirtqDesc {
+            addr: data.as_ptr() as u64,
+            len: data.len() as u32,
             flags: 0,
             next: 0,
         };
-        const USED_ELEM: VirtqUsedElem = VirtqUsedElem { id: 0, len: 0 };
-        Self {
-            desc: [DESC; QUEUE_SIZE],
-            avail: VirtqAvail {
-                flags: 0,
-                idx: 0,
-                ring: [0; QUEUE_SIZE],
-                used_event: 0,
-            },
-            used: VirtqUsed {
-                flags: 0,
-                idx: 0,
-                ring: [USED_ELEM; QUEUE_SIZE],
-                avail_event: 0,
-            },
+        let token = self.queue.add(&[desc])?;
+        self.bufs.insert(token, data);
+        Ok(())
+    }
+
+    /// Free the data of completed transmissions.
+    pub(crate) fn reap(&mut self) {
+        while let Some((token, _)) = self.queue.pop_used() {
+            self.bufs.remove(&token);
         }
     }
+}
+
+/// `vring_need_event()`: whether moving an index from `old` to `new` passed
+/// the `event` index the other side asked to be told about.
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
 
-    /// Place a descriptor chain into the available ring. The descriptors are
-    /// copied into the descriptor table starting at index 0.
-    pub fn add(&mut self, chain: &[VirtqDesc]) -> Result<u16, ()> {
-        if chain.len() > QUEUE_SIZE {
-            return Err(());
+/// Zeroed, page-aligned memory holding the rings of a queue.
+pub(crate) struct QueueMemory {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl QueueMemory {
+    pub(crate) fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, QUEUE_ALIGN).unwrap();
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, layout }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        unsafe { ptr::write_bytes(self.ptr.as_ptr(), 0, self.layout.size()) };
+    }
+}
+
+impl Drop for QueueMemory {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+/// Byte offsets of the parts of a split queue within its memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SplitLayout {
+    /// Number of entries.
+    pub size: u16,
+    /// Offset of the available ring; the descriptor table starts at 0.
+    pub avail: usize,
+    /// Offset of the used ring.
+    pub used: usize,
+    /// Bytes needed for the whole queue.
+    pub total: usize,
+}
+
+impl SplitLayout {
+    pub fn new(size: u16) -> Result<Self, QueueError> {
+        if !size.is_power_of_two() {
+            return Err(QueueError::InvalidSize);
         }
+        let n = size as usize;
+        let avail = 16 * n;
+        let used = (avail + 6 + 2 * n + 3) & !3;
+        Ok(Self {
+            size,
+            avail,
+            used,
+            total: used + 6 + 8 * n,
+        })
+    }
+}
+
+/// Raw view of the descriptor table and rings shared by both sides.
+#[derive(Copy, Clone)]
+struct Rings {
+    desc: *mut VirtqDesc,
+    avail: *mut u16,
+    used: *mut u16,
+    size: u16,
+}
+
+impl Rings {
+    fn slot(&self, idx: u16) -> usize {
+        (idx & (self.size - 1)) as usize
+    }
+
+    unsafe fn desc(&self, i: u16) -> VirtqDesc {
+        ptr::read_volatile(self.desc.add(i as usize))
+    }
+
+    unsafe fn set_desc(&self, i: u16, desc: VirtqDesc) {
+        ptr::write_volatile(self.desc.add(i as usize), desc)
+    }
+
+    unsafe fn avail_flags(&self) -> u16 {
+        ptr::read_volatile(self.avail)
+    }
+
+    unsafe fn set_avail_flags(&self, flags: u16) {
+        ptr::write_volatile(self.avail, flags)
+    }
+
+    unsafe fn avail_idx(&self) -> u16 {
+        ptr::read_volatile(self.avail.add(1))
+    }
+
+    unsafe fn set_avail_idx(&self, idx: u16) {
+        ptr::write_volatile(self.avail.add(1), idx)
+    }
+
+    unsafe fn avail_ring(&self, idx: u16) -> u16 {
+        ptr::read_volatile(self.avail.add(2 + self.slot(idx)))
+    }
+
+    unsafe fn set_avail_ring(&self, idx: u16, head: u16) {
+        ptr::write_volatile(self.avail.add(2 + self.slot(idx)), head)
+    }
+
+    unsafe fn used_event(&self) -> u16 {
+        ptr::read_volatile(self.avail.add(2 + self.size as usize))
+    }
+
+    unsafe fn set_used_event(&self, idx: u16) {
+        ptr::write_volatile(self.avail.add(2 + self.size as usize), idx)
+    }
+
+    unsafe fn used_flags(&self) -> u16 {
+        ptr::read_volatile(self.used)
+    }
+
+    unsafe fn set_used_flags(&self, flags: u16) {
+        ptr::write_volatile(self.used, flags)
+    }
+
+    unsafe fn used_idx(&self) -> u16 {
+        ptr::read_volatile(self.used.add(1))
+    }
+
+    unsafe fn set_used_idx(&self, idx: u16) {
+        ptr::write_volatile(self.used.add(1), idx)
+    }
+
+    unsafe fn used_elem(&self, idx: u16) -> VirtqUsedElem {
+        let ring = self.used.add(2) as *mut VirtqUsedElem;
+        ptr::read_volatile(ring.add(self.slot(idx)))
+    }
+
+    unsafe fn set_used_elem(&self, idx: u16, elem: VirtqUsedElem) {
+        let ring = self.used.add(2) as *mut VirtqUsedElem;
+        ptr::write_volatile(ring.add(self.slot(idx)), elem)
+    }
+
+    unsafe fn avail_event(&self) -> u16 {
+        ptr::read_volatile(self.used.add(2 + 4 * self.size as usize))
+    }
+
+    unsafe fn set_avail_event(&self, idx: u16) {
+        ptr::write_volatile(self.used.add(2 + 4 * self.size as usize), idx)
+    }
+}
+
+/// Bookkeeping for a chain the device owns, indexed by its head.
+#[derive(Default)]
+struct Chain {
+    /// Descriptors taken from the table; 0 while the head is free.
+    len: u16,
+    tail: u16,
+    /// Keeps an indirect table alive while the device may read it.
+    _table: Option<Box<[VirtqDesc]>>,
+}
+
+/// Driver side of a split virtqueue.
+pub struct VirtQueue {
+    mem: QueueMemory,
+    layout: SplitLayout,
+    rings: Rings,
+    free_head: u16,
+    num_free: u16,
+    /// Shadow of `avail.idx`.
+    avail_idx: u16,
+    /// `avail.idx` when the device was last considered for a notification.
+    kicked_idx: u16,
+    last_used: u16,
+    chains: Vec<Chain>,
+    event_idx: bool,
+    indirect: bool,
+    interrupts: bool,
+}
+
+// The queue memory is owned by the queue alone; the device only sees it
+// through the addresses handed out.
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Allocate a queue of `size` entries, which must be a power of two.
+    pub fn new(size: u16) -> Result<Self, QueueError> {
+        let layout = SplitLayout::new(size)?;
+        let mem = QueueMemory::new(layout.total);
+        let base = mem.as_ptr();
+        let rings = Rings {
+            desc: base as *mut VirtqDesc,
+            avail: unsafe { base.add(layout.avail) } as *mut u16,
+            used: unsafe { base.add(layout.used) } as *mut u16,
+            size,
+        };
+        let mut queue = Self {
+            mem,
+            layout,
+            rings,
+            free_head: 0,
+            num_free: 0,
+            avail_idx: 0,
+            kicked_idx: 0,
+            last_used: 0,
+            chains: (0..size).map(|_| Chain::default()).collect(),
+            event_idx: false,
+            indirect: false,
+            interrupts: true,
+        };
+        queue.reset();
+        Ok(queue)
+    }
+
+    pub fn layout(&self) -> SplitLayout {
+        self.layout
+    }
+
+    /// Address of the available ring.
+    pub fn avail_addr(&self) -> u64 {
+        self.rings.avail as u64
+    }
+
+    /// Address of the used ring.
+    pub fn used_addr(&self) -> u64 {
+        self.rings.used as u64
+    }
+
+    fn add_direct(&mut self, chain: &[VirtqDesc]) -> Result<u16, QueueError> {
+        if chain.len() > self.num_free as usize {
+            return Err(QueueError::Full);
+        }
+        let head = self.free_head;
+        let mut idx = head;
+        let mut tail = head;
         for (i, d) in chain.iter().enumerate() {
-            self.desc[i] = *d;
+            // Free descriptors are linked through `next`, so the chain simply
+            // follows the free list.
+            let next = unsafe { self.rings.desc(idx) }.next;
+            let more = if i + 1 < chain.len() {
+                VIRTQ_DESC_F_NEXT
+            } else {
+                0
+            };
+            let desc = VirtqDesc {
+                addr: d.addr,
+                len: d.len,
+                flags: (d.flags & VIRTQ_DESC_F_WRITE) | more,
+                next,
+            };
+            unsafe { self.rings.set_desc(idx, desc) };
+            tail = idx;
+            idx = next;
+        }
+        self.free_head = idx;
+        self.num_free -= chain.len() as u16;
+        self.chains[head as usize] = Chain {
+            len: chain.len() as u16,
+            tail,
+            _table: None,
+        };
+        Ok(head)
+    }
+
+    fn add_indirect(&mut self, chain: &[VirtqDesc]) -> Result<u16, QueueError> {
+        if self.num_free == 0 {
+            return Err(QueueError::Full);
+        }
+        let table: Box<[VirtqDesc]> = chain
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let last = i + 1 == chain.len();
+                VirtqDesc {
+                    addr: d.addr,
+                    len: d.len,
+                    flags: (d.flags & VIRTQ_DESC_F_WRITE)
+                        | if last { 0 } else { VIRTQ_DESC_F_NEXT },
+                    next: if last { 0 } else { i as u16 + 1 },
+                }
+            })
+            .collect();
+        let head = self.free_head;
+        let next = unsafe { self.rings.desc(head) }.next;
+        let desc = VirtqDesc {
+            addr: table.as_ptr() as u64,
+            len: core::mem::size_of_val(&*table) as u32,
+            flags: VIRTQ_DESC_F_INDIRECT,
+            next,
+        };
+        unsafe { self.rings.set_desc(head, desc) };
+        self.free_head = next;
+        self.num_free -= 1;
+        self.chains[head as usize] = Chain {
+            len: 1,
+            tail: head,
+            _table: Some(table),
+        };
+        Ok(head)
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let chain = core::mem::take(&mut self.chains[head as usize]);
+        let mut tail = unsafe { self.rings.desc(chain.tail) };
+        tail.next = self.free_head;
+        unsafe { self.rings.set_desc(chain.tail, tail) };
+        self.free_head = head;
+        self.num_free += chain.len;
+    }
+}
+
+impl Queue for VirtQueue {
+    fn size(&self) -> u16 {
+        self.layout.size
+    }
+
+    fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    fn set_features(&mut self, features: u64) {
+        self.event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+        self.indirect = features & VIRTIO_F_INDIRECT_DESC != 0;
+    }
+
+    fn reset(&mut self) {
+        self.mem.clear();
+        let size = self.layout.size;
+        for i in 0..size {
+            let next = if i + 1 < size { i + 1 } else { 0 };
+            unsafe {
+                self.rings.set_desc(
+                    i,
+                    VirtqDesc {
+                        next,
+                        ..Default::default()
+                    },
+                )
+            };
+        }
+        self.chains.iter_mut().for_each(|c| *c = Chain::default());
+        self.free_head = 0;
+        self.num_free = size;
+        self.avail_idx = 0;
+        self.kicked_idx = 0;
+        self.last_used = 0;
+        self.interrupts = true;
+    }
+
+    fn desc_addr(&self) -> u64 {
+        self.rings.desc as u64
+    }
+
+    fn driver_addr(&self) -> u64 {
+        self.avail_addr()
+    }
+
+    fn device_addr(&self) -> u64 {
+        self.used_addr()
+    }
+
+    fn add(&mut self, chain: &[VirtqDesc]) -> Result<u16, QueueError> {
+        if chain.is_empty() {
+            return Err(QueueError::EmptyChain);
+        }
+        if chain.len() > self.layout.size as usize {
+            return Err(QueueError::ChainTooLong);
+        }
+        let head = if self.indirect && chain.len() > 1 {
+            self.add_indirect(chain)?
+        } else {
+            self.add_direct(chain)?
+        };
+        unsafe {
+            self.rings.set_avail_ring(self.avail_idx, head);
+            // The descriptors and the ring entry must be visible first.
+            fence(Ordering::Release);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.rings.set_avail_idx(self.avail_idx);
+        }
+        Ok(head)
+    }
+
+    fn needs_notify(&mut self) -> bool {
+        // Publish avail.idx before looking at the device's suppression state.
+        fence(Ordering::SeqCst);
+        let old = self.kicked_idx;
+        let new = self.avail_idx;
+        self.kicked_idx = new;
+        if new == old {
+            return false;
+        }
+        unsafe {
+            if self.event_idx {
+                need_event(self.rings.avail_event(), new, old)
+            } else {
+                self.rings.used_flags() & VIRTQ_USED_F_NO_NOTIFY == 0
+            }
+        }
+    }
+
+    fn has_used(&self) -> bool {
+        self.last_used != unsafe { self.rings.used_idx() }
+    }
+
+    fn pop_used(&mut self) -> Option<(u16, u32)> {
+        while self.has_used() {
+            // Read the element only after seeing the index that covers it.
+            fence(Ordering::Acquire);
+            let elem = unsafe { self.rings.used_elem(self.last_used) };
+            self.last_used = self.last_used.wrapping_add(1);
+            if self.event_idx && self.interrupts {
+                unsafe { self.rings.set_used_event(self.last_used) };
+            }
+            let head = elem.id as usize;
+            // Ids the driver never handed out are a device bug; skip them.
+            if head < self.chains.len() && self.chains[head].len != 0 {
+                self.free_chain(head as u16);
+                return Some((head as u16, elem.len));
+            }
+        }
+        None
+    }
+
+    fn disable_interrupts(&mut self) {
+        self.interrupts = false;
+        if !self.event_idx {
+            unsafe { self.rings.set_avail_flags(VIRTQ_AVAIL_F_NO_INTERRUPT) };
+        }
+    }
+
+    fn enable_interrupts(&mut self) -> bool {
+        self.interrupts = true;
+        unsafe {
+            if self.event_idx {
+                self.rings.set_used_event(self.last_used);
+            } else {
+                self.rings.set_avail_flags(0);
+            }
+        }
+        fence(Ordering::SeqCst);
+        !self.has_used()
+    }
+}
+
+impl Default for VirtQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUEUE_SIZE).unwrap()
+    }
+}
+
+/// A chain taken from the available ring by the device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DescChain {
+    /// Id to complete the chain with.
+    pub head: u16,
+    /// The buffers in order, with indirect tables resolved.
+    pub descs: Vec<VirtqDesc>,
+}
+
+impl DescChain {
+    /// Buffers the device reads.
+    pub fn readable(&self) -> impl Iterator<Item = &VirtqDesc> {
+        self.descs
+            .iter()
+            .filter(|d| d.flags & VIRTQ_DESC_F_WRITE == 0)
+    }
+
+    /// Buffers the device writes.
+    pub fn writable(&self) -> impl Iterator<Item = &VirtqDesc> {
+        self.descs
+            .iter()
+            .filter(|d| d.flags & VIRTQ_DESC_F_WRITE != 0)
+    }
+}
+
+/// Device side of a split virtqueue.
+pub struct DeviceQueue {
+    rings: Rings,
+    last_avail: u16,
+    used_idx: u16,
+    /// `used.idx` when the driver was last considered for an interrupt.
+    signalled_idx: u16,
+    event_idx: bool,
+    notify: bool,
+}
+
+impl DeviceQueue {
+    /// Attach to the queue of `size` entries at the given addresses.
+    ///
+    /// # Safety
+    ///
+    /// The addresses must point to a split queue of `size` entries that
+    /// stays mapped while the device queue is used, such as one reported by
+    /// a [`VirtQueue`].
+    pub unsafe fn new(size: u16, desc: u64, avail: u64, used: u64) -> Result<Self, QueueError> {
+        if !size.is_power_of_two() {
+            return Err(QueueError::InvalidSize);
+        }
+        Ok(Self {
+            rings: Rings {
+                desc: desc as *mut VirtqDesc,
+                avail: avail as *mut u16,
+                used: used as *mut u16,
+                size,
+            },
+            last_avail: 0,
+            used_idx: 0,
+            signalled_idx: 0,
+            event_idx: false,
+            notify: true,
+        })
+    }
+
+    fn walk(&self, head: u16) -> Result<Vec<VirtqDesc>, QueueError> {
+        let size = self.rings.size;
+        let mut descs = Vec::new();
+        let mut idx = head;
+        loop {
+            if idx >= size || descs.len() >= size as usize {
+                return Err(QueueError::Malformed);
+            }
+            let desc = unsafe { self.rings.desc(idx) };
+            if desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if desc.flags & VIRTQ_DESC_F_NEXT != 0 {
+                    return Err(QueueError::Malformed);
+                }
+                descs.extend(Self::walk_indirect(&desc)?);
+                return Ok(descs);
+            }
+            descs.push(desc);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(descs);
+            }
+            idx = desc.next;
+        }
+    }
+
+    fn walk_indirect(desc: &VirtqDesc) -> Result<Vec<VirtqDesc>, QueueError> {
+        let entry = core::mem::size_of::<VirtqDesc>();
+        let count = desc.len as usize / entry;
+        if count == 0 || !(desc.len as usize).is_multiple_of(entry) {
+            return Err(QueueError::Malformed);
+        }
+        let table = desc.addr as *const VirtqDesc;
+        let mut descs = Vec::new();
+        let mut idx = 0usize;
+        loop {
+            if idx >= count || descs.len() >= count {
+                return Err(QueueError::Malformed);
+            }
+            let d = unsafe { ptr::read_unaligned(table.add(idx)) };
+            if d.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return Err(QueueError::Malformed);
+            }
+            descs.push(d);
+            if d.flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(descs);
+            }
+            idx = d.next as usize;
+        }
+    }
+}
+
+impl DeviceRing for DeviceQueue {
+    fn set_features(&mut self, features: u64) {
+        self.event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+    }
+
+    fn set_notify(&mut self, enabled: bool) {
+        self.notify = enabled;
+        unsafe {
+            if self.event_idx {
+                if enabled {
+                    self.rings.set_avail_event(self.last_avail);
+                }
+            } else {
+                self.rings
+                    .set_used_flags(if enabled { 0 } else { VIRTQ_USED_F_NO_NOTIFY });
+            }
+        }
+    }
+
+    fn pop_avail(&mut self) -> Result<Option<DescChain>, QueueError> {
+        if self.last_avail == unsafe { self.rings.avail_idx() } {
+            return Ok(None);
+        }
+        fence(Ordering::Acquire);
+        let head = unsafe { self.rings.avail_ring(self.last_avail) };
+        self.last_avail = self.last_avail.wrapping_add(1);
+        if self.event_idx && self.notify {
+            unsafe { self.rings.set_avail_event(self.last_avail) };
+        }
+        let descs = self.walk(head)?;
+        Ok(Some(DescChain { head, descs }))
+    }
+
+    fn push_used(&mut self, head: u16, len: u32) {
+        unsafe {
+            self.rings.set_used_elem(
+                self.used_idx,
+                VirtqUsedElem {
+                    id: head as u32,
+                    len,
+                },
+            );
+            fence(Ordering::Release);
+            self.used_idx = self.used_idx.wrapping_add(1);
+            self.rings.set_used_idx(self.used_idx);
+        }
+    }
+
+    fn needs_interrupt(&mut self) -> bool {
+        fence(Ordering::SeqCst);
+        let old = self.signalled_idx;
+        let new = self.used_idx;
+        self.signalled_idx = new;
+        if new == old {
+            return false;
+        }
+        unsafe {
+            if self.event_idx {
+                need_event(self.rings.used_event(), new, old)
+            } else {
+                self.rings.avail_flags() & VIRTQ_AVAIL_F_NO_INTERRUPT == 0
+            }
         }
-        let idx = self.avail.idx as usize % QUEUE_SIZE;
-        self.avail.ring[idx] = 0;
-        self.avail.idx = self.avail.idx.wrapping_add(1);
-        Ok(idx as u16)
     }
 }
@@ -0,0 +1,172 @@
+//! virtio-rng (entropy device) driver on top of a virtio-mmio transport.
+//!
+//! The device has a single request queue. The driver posts writable buffers
+//! on it and the device fills them with entropy, possibly only in part and
+//! possibly only once it gathered enough. [`RngDriver::request`] asks for
+//! bytes, [`RngDriver::take`] hands out what arrived; neither blocks.
+
+use core::fmt;
+use std::collections::{HashMap, VecDeque};
+
+use crate::features::{DeviceType, Features};
+use crate::mmio::{MmioError, MmioTransport, Registers};
+use crate::queue::{self, Queue, QueueError, VirtqDesc, VIRTQ_DESC_F_WRITE};
+use crate::status::Status;
+
+pub const REQUEST_QUEUE: u16 = 0;
+/// Largest queue the driver sets up.
+const MAX_QUEUE_SIZE: u16 = 8;
+/// Largest buffer a single request posts.
+pub const MAX_REQUEST: usize = 4096;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RngError {
+    Mmio(MmioError),
+    Queue(QueueError),
+    /// The device is no entropy source.
+    WrongDevice(u32),
+    /// The device did not accept the negotiated features.
+    FeaturesRejected,
+}
+
+impl fmt::Display for RngError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mmio(e) => write!(f, "transport: {:?}", e),
+            Self::Queue(e) => write!(f, "queue: {}", e),
+            Self::WrongDevice(id) => write!(f, "device {} is no entropy source", id),
+            Self::FeaturesRejected => write!(f, "features rejected"),
+        }
+    }
+}
+
+impl std::error::Error for RngError {}
+
+impl From<MmioError> for RngError {
+    fn from(e: MmioError) -> Self {
+        Self::Mmio(e)
+    }
+}
+
+impl From<QueueError> for RngError {
+    fn from(e: QueueError) -> Self {
+        Self::Queue(e)
+    }
+}
+
+/// Driver for a virtio-rng device.
+pub struct RngDriver<R: Registers> {
+    transport: MmioTransport<R>,
+    queue: Box<dyn Queue + Send>,
+    /// Buffers handed to the device, by token.
+    bufs: HashMap<u16, Vec<u8>>,
+    /// Entropy received and not taken yet.
+    entropy: VecDeque<u8>,
+}
+
+impl<R: Registers> RngDriver<R> {
+    /// Initialise the device behind `transport` and set up its request
+    /// queue. No entropy is requested yet.
+    pub fn new(mut transport: MmioTransport<R>) -> Result<Self, RngError> {
+        if transport.device_id() != DeviceType::Entropy as u32 {
+            return Err(RngError::WrongDevice(transport.device_id()));
+        }
+        transport.reset();
+        let mut status = Status::ACKNOWLEDGE;
+        transport.set_status(status.bits() as u32);
+        status |= Status::DRIVER;
+        transport.set_status(status.bits() as u32);
+
+        // The device has no features of its own.
+        let supported = (Features::VERSION_1
+            | Features::INDIRECT_DESC
+            | Features::EVENT_IDX
+            | Features::RING_PACKED)
+            .bits();
+        let features = transport.device_features() & supported;
+        transport.set_driver_features(features);
+        status |= Status::FEATURES_OK;
+        transport.set_status(status.bits() as u32);
+        if transport.status() & Status::FEATURES_OK.bits() as u32 == 0 {
+            transport.set_status((status | Status::FAILED).bits() as u32);
+            return Err(RngError::FeaturesRejected);
+        }
+
+        let max = transport.queue_max(REQUEST_QUEUE).min(MAX_QUEUE_SIZE);
+        if max == 0 {
+            return Err(MmioError::QueueUnavailable.into());
+        }
+        let queue = queue::for_features(1 << (15 - max.leading_zeros()), features)?;
+        transport.setup_queue(REQUEST_QUEUE, &*queue)?;
+        status |= Status::DRIVER_OK;
+        transport.set_status(status.bits() as u32);
+
+        Ok(Self {
+            transport,
+            queue,
+            bufs: HashMap::new(),
+            entropy: VecDeque::new(),
+        })
+    }
+
+    pub fn transport(&self) -> &MmioTransport<R> {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut MmioTransport<R> {
+        &mut self.transport
+    }
+
+    /// Ask the device for up to `len` bytes, at most [`MAX_REQUEST`]. Fails
+    /// with [`QueueError::Full`] while all buffers are with the device.
+    pub fn request(&mut self, len: usize) -> Result<(), RngError> {
+        let mut buf = vec![0; len.clamp(1, MAX_REQUEST)];
+        let desc = VirtqDesc {
+            addr: buf.as_mut_ptr() as u64,
+            len: buf.len() as u32,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        let token = self.queue.add(&[desc])?;
+        self.bufs.insert(token, buf);
+        if self.queue.needs_notify() {
+            self.transport.notify(REQUEST_QUEUE);
+        }
+        Ok(())
+    }
+
+    /// Number of requests the device has not completed yet.
+    pub fn pending(&self) -> usize {
+        self.bufs.len()
+    }
+
+    /// Handle an interrupt and collect the entropy the device delivered.
+    pub fn interrupt(&mut self) {
+        self.transport.ack_interrupt();
+        self.poll();
+    }
+
+    /// Collect the entropy of completed requests.
+    pub fn poll(&mut self) {
+        while let Some((token, len)) = self.queue.pop_used() {
+            if let Some(buf) = self.bufs.remove(&token) {
+                self.entropy.extend(&buf[..(len as usize).min(buf.len())]);
+            }
+        }
+    }
+
+    /// Bytes received and not taken yet.
+    pub fn available(&self) -> usize {
+        self.entropy.len()
+    }
+
+    /// Move received entropy into `buf`. Returns how much was copied, 0 if
+    /// there is none. Every byte is handed out once.
+    pub fn take(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.entropy.len());
+        for (dst, src) in buf.iter_mut().zip(self.entropy.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+}
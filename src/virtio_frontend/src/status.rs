@@ -0,0 +1,156 @@
+//! Device status field and the initialisation sequence a driver has to walk
+//! through: acknowledge, driver, features ok, driver ok.
+
+use core::fmt;
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// Bits of the device status field.
+    pub struct Status: u8 {
+        const ACKNOWLEDGE = 1;
+        const DRIVER = 2;
+        const DRIVER_OK = 4;
+        const FEATURES_OK = 8;
+        const NEEDS_RESET = 64;
+        const FAILED = 128;
+    }
+}
+
+/// Steps of the initialisation sequence, in order.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceState {
+    #[default]
+    Reset,
+    Acknowledged,
+    Driver,
+    FeaturesOk,
+    DriverOk,
+    Failed,
+}
+
+impl DeviceState {
+    /// Status bits reported by a device in this state.
+    pub fn status(self) -> Status {
+        match self {
+            Self::Reset => Status::empty(),
+            Self::Acknowledged => Status::ACKNOWLEDGE,
+            Self::Driver => Status::ACKNOWLEDGE | Status::DRIVER,
+            Self::FeaturesOk => Status::ACKNOWLEDGE | Status::DRIVER | Status::FEATURES_OK,
+            Self::DriverOk => {
+                Status::ACKNOWLEDGE | Status::DRIVER | Status::FEATURES_OK | Status::DRIVER_OK
+            }
+            Self::Failed => Status::FAILED,
+        }
+    }
+
+    fn next(self) -> Option<Self> {
+        match self {
+            Self::Reset => Some(Self::Acknowledged),
+            Self::Acknowledged => Some(Self::Driver),
+            Self::Driver => Some(Self::FeaturesOk),
+            Self::FeaturesOk => Some(Self::DriverOk),
+            Self::DriverOk | Self::Failed => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StatusError {
+    /// The requested step does not follow the current state.
+    OutOfOrder(DeviceState),
+    /// The device does not accept the negotiated features.
+    FeaturesRejected,
+    /// The device signalled NEEDS_RESET; only a reset is accepted.
+    NeedsReset,
+    /// The device was marked FAILED; only a reset is accepted.
+    Failed,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfOrder(state) => write!(f, "status step out of order in state {:?}", state),
+            Self::FeaturesRejected => write!(f, "negotiated features rejected"),
+            Self::NeedsReset => write!(f, "device needs reset"),
+            Self::Failed => write!(f, "device failed"),
+        }
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Status state machine rejecting out-of-order transitions. FAILED may be
+/// set at any time, after that (or after the device raised NEEDS_RESET) only
+/// a reset brings the device back.
+#[derive(Clone, Debug, Default)]
+pub struct StatusMachine {
+    state: DeviceState,
+    needs_reset: bool,
+}
+
+impl StatusMachine {
+    pub fn state(&self) -> DeviceState {
+        self.state
+    }
+
+    /// Current status field including NEEDS_RESET.
+    pub fn status(&self) -> Status {
+        let mut status = self.state.status();
+        status.set(Status::NEEDS_RESET, self.needs_reset);
+        status
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn fail(&mut self) {
+        self.state = DeviceState::Failed;
+    }
+
+    /// Device side: the device hit an error it cannot recover from without
+    /// a reset.
+    pub fn set_needs_reset(&mut self) {
+        self.needs_reset = true;
+    }
+
+    /// Move forward to `next`, which has to be the step after the current
+    /// state.
+    pub fn advance(&mut self, next: DeviceState) -> Result<(), StatusError> {
+        if self.state == DeviceState::Failed {
+            return Err(StatusError::Failed);
+        }
+        if self.needs_reset {
+            return Err(StatusError::NeedsReset);
+        }
+        if self.state.next() != Some(next) {
+            return Err(StatusError::OutOfOrder(self.state));
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    /// Apply a raw status write: 0 resets, FAILED fails, anything else has
+    /// to be the current bits plus the next step. Rewriting the current bits
+    /// is a no-op; NEEDS_RESET is owned by the device and ignored.
+    pub fn write(&mut self, status: Status) -> Result<(), StatusError> {
+        if status.is_empty() {
+            self.reset();
+            return Ok(());
+        }
+        if status.contains(Status::FAILED) {
+            self.fail();
+            return Ok(());
+        }
+        let status = status - Status::NEEDS_RESET;
+        if status == self.state.status() {
+            return Ok(());
+        }
+        match self.state.next() {
+            Some(next) if next.status() == status => self.advance(next),
+            _ if self.state == DeviceState::Failed => Err(StatusError::Failed),
+            _ => Err(StatusError::OutOfOrder(self.state)),
+        }
+    }
+}
@@ -1,12 +1,19 @@
-use crate::queue::VirtQueue;
+use crate::config::{ConfigError, ConfigSpace};
+use crate::features::Features;
+use crate::queue::{self, Queue, DEFAULT_QUEUE_SIZE};
+use crate::status::{DeviceState, Status, StatusError, StatusMachine};
 
-/// Simple virtio transport exposing feature negotiation, config space access
-/// and a single virtqueue.
+/// Simple virtio transport exposing the device status sequence, feature
+/// negotiation, config space access and a single virtqueue, whose ring format
+/// follows the negotiated features.
 pub struct VirtioTransport {
     pub device_features: u64,
     pub driver_features: u64,
+    status: StatusMachine,
     config: Vec<u8>,
-    pub queue: VirtQueue,
+    generation: u32,
+    config_changed: Option<Box<dyn FnMut(u32) + Send>>,
+    pub queue: Box<dyn Queue + Send>,
 }
 
 impl VirtioTransport {
@@ -16,27 +23,134 @@ impl VirtioTransport {
         Self {
             device_features,
             driver_features: 0,
+            status: StatusMachine::default(),
             config: vec![0; config_len],
-            queue: VirtQueue::new(),
+            generation: 0,
+            config_changed: None,
+            queue: queue::for_features(DEFAULT_QUEUE_SIZE, 0).unwrap(),
         }
     }
 
-    /// Negotiate features with the driver. Returns the agreed upon feature set.
-    pub fn negotiate_features(&mut self, driver_supported: u64) -> u64 {
+    pub fn state(&self) -> DeviceState {
+        self.status.state()
+    }
+
+    /// Current device status field.
+    pub fn status(&self) -> Status {
+        self.status.status()
+    }
+
+    /// Reset the device: clears the status, the negotiated features and the
+    /// queue.
+    pub fn reset(&mut self) {
+        self.status.reset();
+        self.driver_features = 0;
+        self.queue = queue::for_features(self.queue.size(), 0).unwrap();
+    }
+
+    pub fn acknowledge(&mut self) -> Result<(), StatusError> {
+        self.status.advance(DeviceState::Acknowledged)
+    }
+
+    pub fn driver(&mut self) -> Result<(), StatusError> {
+        self.status.advance(DeviceState::Driver)
+    }
+
+    /// Negotiate features with the driver. Only valid after [`Self::driver`]
+    /// and before [`Self::features_ok`]. Returns the agreed upon feature set.
+    pub fn negotiate_features(&mut self, driver_supported: u64) -> Result<u64, StatusError> {
+        if self.state() != DeviceState::Driver {
+            return Err(StatusError::OutOfOrder(self.state()));
+        }
         let negotiated = self.device_features & driver_supported;
         self.driver_features = negotiated;
-        negotiated
+        self.queue = queue::for_features(self.queue.size(), negotiated).unwrap();
+        Ok(negotiated)
+    }
+
+    /// Device independent part of the negotiated features.
+    pub fn features(&self) -> Features {
+        Features::from_bits_truncate(self.driver_features)
+    }
+
+    /// Freeze the negotiated features. A device offering VERSION_1 rejects
+    /// drivers that did not accept it; the driver should [`Self::fail`] then.
+    pub fn features_ok(&mut self) -> Result<(), StatusError> {
+        if self.status.state() == DeviceState::Driver && !self.accepts_features() {
+            return Err(StatusError::FeaturesRejected);
+        }
+        self.status.advance(DeviceState::FeaturesOk)
+    }
+
+    pub fn driver_ok(&mut self) -> Result<(), StatusError> {
+        self.status.advance(DeviceState::DriverOk)
+    }
+
+    /// Give up on the device. Only a reset is accepted afterwards.
+    pub fn fail(&mut self) {
+        self.status.fail();
+    }
+
+    /// Device side: signal that the device needs a reset.
+    pub fn set_needs_reset(&mut self) {
+        self.status.set_needs_reset();
+    }
+
+    /// Apply a raw status register write, as done through the FFI.
+    pub fn write_status(&mut self, status: u8) -> Result<(), StatusError> {
+        let status = Status::from_bits_truncate(status);
+        if status.is_empty() {
+            self.reset();
+            return Ok(());
+        }
+        if status.contains(Status::FEATURES_OK)
+            && !status.contains(Status::FAILED)
+            && self.status.state() == DeviceState::Driver
+            && !self.accepts_features()
+        {
+            return Err(StatusError::FeaturesRejected);
+        }
+        self.status.write(status)
+    }
+
+    fn accepts_features(&self) -> bool {
+        let version_1 = Features::VERSION_1.bits();
+        self.device_features & version_1 == 0 || self.driver_features & version_1 != 0
+    }
+
+    /// Call `callback` with the new generation whenever the device changes
+    /// its configuration space.
+    pub fn on_config_change(&mut self, callback: impl FnMut(u32) + Send + 'static) {
+        self.config_changed = Some(Box::new(callback));
+    }
+
+    /// Device side: change the configuration space, which starts a new
+    /// generation and notifies the driver.
+    pub fn set_config(&mut self, offset: usize, data: &[u8]) -> Result<(), ConfigError> {
+        self.check_range(offset, data.len())?;
+        self.config[offset..offset + data.len()].copy_from_slice(data);
+        self.generation = self.generation.wrapping_add(1);
+        if let Some(callback) = self.config_changed.as_mut() {
+            callback(self.generation);
+        }
+        Ok(())
+    }
+}
+
+impl ConfigSpace for VirtioTransport {
+    fn config_len(&self) -> usize {
+        self.config.len()
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.generation
     }
 
-    /// Read from the virtual device configuration space.
-    pub fn read_config(&self, offset: usize, data: &mut [u8]) {
-        assert!(offset + data.len() <= self.config.len());
+    fn read_raw(&self, offset: usize, data: &mut [u8]) {
         data.copy_from_slice(&self.config[offset..offset + data.len()]);
     }
 
-    /// Write to the virtual device configuration space.
-    pub fn write_config(&mut self, offset: usize, data: &[u8]) {
-        assert!(offset + data.len() <= self.config.len());
+    fn write_raw(&mut self, offset: usize, data: &[u8]) {
         self.config[offset..offset + data.len()].copy_from_slice(data);
     }
 }
@@ -0,0 +1,353 @@
+//! virtio-vsock driver on top of a virtio-mmio transport.
+//!
+//! The device carries packets between the guest, known to the host by the
+//! CID in the configuration space, and the host, CID [`VMADDR_CID_HOST`].
+//! Every packet starts with a [`VsockHeader`] naming both ends and the
+//! operation, stream data follows the header. The driver only moves packets;
+//! connections and their flow control are up to its user.
+//!
+//! The device tells the driver about a transport reset, such as after a
+//! migration, on the event queue. All connections are gone then and the
+//! guest CID may have changed, see [`VsockDriver::take_reset`].
+
+use core::fmt;
+use std::collections::VecDeque;
+
+use crate::config::{ConfigError, ConfigSpace};
+use crate::features::{DeviceType, Features, VsockFeatures};
+use crate::mmio::{MmioError, MmioTransport, Registers};
+use crate::queue::{QueueError, RxQueue, TxQueue};
+use crate::status::Status;
+
+pub const RX_QUEUE: u16 = 0;
+pub const TX_QUEUE: u16 = 1;
+pub const EVENT_QUEUE: u16 = 2;
+
+// Configuration space.
+pub const CONFIG_GUEST_CID: usize = 0;
+pub const CONFIG_LEN: usize = 8;
+
+// Well-known CIDs.
+pub const VMADDR_CID_HYPERVISOR: u64 = 0;
+pub const VMADDR_CID_LOCAL: u64 = 1;
+pub const VMADDR_CID_HOST: u64 = 2;
+
+pub const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+// Operations.
+pub const VIRTIO_VSOCK_OP_INVALID: u16 = 0;
+pub const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+pub const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+pub const VIRTIO_VSOCK_OP_RST: u16 = 3;
+pub const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VIRTIO_VSOCK_OP_RW: u16 = 5;
+pub const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Flags of `SHUTDOWN`.
+pub const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+pub const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+/// Event on the event queue: all connections were reset.
+pub const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+const EVENT_LEN: usize = 4;
+
+/// Largest payload of a packet. Receive buffers take a header and this
+/// much data.
+pub const MAX_PAYLOAD: usize = 4096;
+/// Largest queue the driver sets up.
+const MAX_QUEUE_SIZE: u16 = 64;
+
+/// Header of every packet, as defined by the virtio specification.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VsockHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    /// Length of the data following the header.
+    pub len: u32,
+    pub ty: u16,
+    pub op: u16,
+    pub flags: u32,
+    /// Receive buffer space of the sender.
+    pub buf_alloc: u32,
+    /// Bytes the sender's user consumed from its receive buffer.
+    pub fwd_cnt: u32,
+}
+
+impl VsockHeader {
+    pub const SIZE: usize = 44;
+
+    /// Parse a little-endian header.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let b = bytes.get(..Self::SIZE)?;
+        let u16_at = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        Some(Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            ty: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        b[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        b[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        b[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        b[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        b[24..28].copy_from_slice(&self.len.to_le_bytes());
+        b[28..30].copy_from_slice(&self.ty.to_le_bytes());
+        b[30..32].copy_from_slice(&self.op.to_le_bytes());
+        b[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        b[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        b[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        b
+    }
+}
+
+/// A header and its data.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VsockPacket {
+    pub hdr: VsockHeader,
+    pub data: Vec<u8>,
+}
+
+impl VsockPacket {
+    /// Parse a packet. Fails if the data is shorter than the header says.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let hdr = VsockHeader::from_bytes(bytes)?;
+        let data = bytes
+            .get(VsockHeader::SIZE..VsockHeader::SIZE + hdr.len as usize)?
+            .to_vec();
+        Some(Self { hdr, data })
+    }
+
+    /// The packet on the wire, with the length taken from the data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let hdr = VsockHeader {
+            len: self.data.len() as u32,
+            ..self.hdr
+        };
+        let mut bytes = hdr.to_bytes().to_vec();
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VsockError {
+    Mmio(MmioError),
+    Queue(QueueError),
+    Config(ConfigError),
+    /// The device is no socket device.
+    WrongDevice(u32),
+    /// The device did not accept the negotiated features.
+    FeaturesRejected,
+    /// The packet carries more than [`MAX_PAYLOAD`] bytes.
+    TooLarge(usize),
+}
+
+impl fmt::Display for VsockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mmio(e) => write!(f, "transport: {:?}", e),
+            Self::Queue(e) => write!(f, "queue: {}", e),
+            Self::Config(e) => write!(f, "config: {}", e),
+            Self::WrongDevice(id) => write!(f, "device {} is no socket device", id),
+            Self::FeaturesRejected => write!(f, "features rejected"),
+            Self::TooLarge(len) => write!(f, "{} bytes exceed a packet", len),
+        }
+    }
+}
+
+impl std::error::Error for VsockError {}
+
+impl From<MmioError> for VsockError {
+    fn from(e: MmioError) -> Self {
+        Self::Mmio(e)
+    }
+}
+
+impl From<QueueError> for VsockError {
+    fn from(e: QueueError) -> Self {
+        Self::Queue(e)
+    }
+}
+
+impl From<ConfigError> for VsockError {
+    fn from(e: ConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+
+/// Driver for a virtio-vsock device.
+pub struct VsockDriver<R: Registers> {
+    transport: MmioTransport<R>,
+    guest_cid: u64,
+    rx: RxQueue,
+    tx: TxQueue,
+    event: RxQueue,
+    /// Packets received and not taken yet.
+    received: VecDeque<VsockPacket>,
+    /// The device reset the transport since the last `take_reset`.
+    reset: bool,
+}
+
+impl<R: Registers> VsockDriver<R> {
+    /// Initialise the device behind `transport`, read the guest CID and set
+    /// up the queues with receive and event buffers.
+    pub fn new(transport: MmioTransport<R>) -> Result<Self, VsockError> {
+        let mut transport = transport.with_config_len(CONFIG_LEN);
+        if transport.device_id() != DeviceType::Vsock as u32 {
+            return Err(VsockError::WrongDevice(transport.device_id()));
+        }
+        transport.reset();
+        let mut status = Status::ACKNOWLEDGE;
+        transport.set_status(status.bits() as u32);
+        status |= Status::DRIVER;
+        transport.set_status(status.bits() as u32);
+
+        // Only stream sockets are supported, which devices without any
+        // socket type feature provide as well.
+        let supported = VsockFeatures::STREAM.bits()
+            | (Features::VERSION_1
+                | Features::INDIRECT_DESC
+                | Features::EVENT_IDX
+                | Features::RING_PACKED)
+                .bits();
+        let features = transport.device_features() & supported;
+        transport.set_driver_features(features);
+        status |= Status::FEATURES_OK;
+        transport.set_status(status.bits() as u32);
+        if transport.status() & Status::FEATURES_OK.bits() as u32 == 0 {
+            transport.set_status((status | Status::FAILED).bits() as u32);
+            return Err(VsockError::FeaturesRejected);
+        }
+
+        let guest_cid = transport.read_le::<u64>(CONFIG_GUEST_CID)?;
+        let rx = RxQueue::new(
+            queue_size(&mut transport, RX_QUEUE)?,
+            features,
+            VsockHeader::SIZE + MAX_PAYLOAD,
+        )?;
+        let tx = TxQueue::new(queue_size(&mut transport, TX_QUEUE)?, features)?;
+        let event = RxQueue::new(
+            queue_size(&mut transport, EVENT_QUEUE)?,
+            features,
+            EVENT_LEN,
+        )?;
+        transport.setup_queue(RX_QUEUE, &*rx.queue)?;
+        transport.setup_queue(TX_QUEUE, &*tx.queue)?;
+        transport.setup_queue(EVENT_QUEUE, &*event.queue)?;
+        status |= Status::DRIVER_OK;
+        transport.set_status(status.bits() as u32);
+        transport.notify(RX_QUEUE);
+        transport.notify(EVENT_QUEUE);
+
+        Ok(Self {
+            transport,
+            guest_cid,
+            rx,
+            tx,
+            event,
+            received: VecDeque::new(),
+            reset: false,
+        })
+    }
+
+    pub fn transport(&self) -> &MmioTransport<R> {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut MmioTransport<R> {
+        &mut self.transport
+    }
+
+    /// The CID of this guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Whether the device reset the transport since the last call. The
+    /// guest CID is read again before this reports the reset.
+    pub fn take_reset(&mut self) -> bool {
+        core::mem::take(&mut self.reset)
+    }
+
+    /// Handle an interrupt and collect what the device delivered.
+    pub fn interrupt(&mut self) -> Result<(), VsockError> {
+        self.transport.ack_interrupt();
+        self.poll()
+    }
+
+    /// Collect received packets and events, and free the buffers of sent
+    /// packets.
+    pub fn poll(&mut self) -> Result<(), VsockError> {
+        let mut notify = false;
+        while let Some(data) = self.rx.pop() {
+            notify = true;
+            // Malformed packets are dropped.
+            if let Some(pkt) = VsockPacket::from_bytes(&data) {
+                self.received.push_back(pkt);
+            }
+        }
+        if notify && self.rx.queue.needs_notify() {
+            self.transport.notify(RX_QUEUE);
+        }
+        let mut notify = false;
+        while let Some(event) = self.event.pop() {
+            notify = true;
+            if event.get(..EVENT_LEN) == Some(&VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes()) {
+                self.guest_cid = self.transport.read_le::<u64>(CONFIG_GUEST_CID)?;
+                self.reset = true;
+            }
+        }
+        if notify && self.event.queue.needs_notify() {
+            self.transport.notify(EVENT_QUEUE);
+        }
+        self.tx.reap();
+        Ok(())
+    }
+
+    /// Take the next received packet.
+    pub fn recv(&mut self) -> Option<VsockPacket> {
+        self.received.pop_front()
+    }
+
+    /// Queue `pkt` for the device. Fails with [`QueueError::Full`] while the
+    /// transmit queue is full.
+    pub fn send(&mut self, pkt: &VsockPacket) -> Result<(), VsockError> {
+        if pkt.data.len() > MAX_PAYLOAD {
+            return Err(VsockError::TooLarge(pkt.data.len()));
+        }
+        self.tx.push(pkt.to_bytes())?;
+        if self.tx.queue.needs_notify() {
+            self.transport.notify(TX_QUEUE);
+        }
+        Ok(())
+    }
+}
+
+/// Size of queue `index`: the largest power of two the device and the
+/// driver support.
+fn queue_size<R: Registers>(
+    transport: &mut MmioTransport<R>,
+    index: u16,
+) -> Result<u16, VsockError> {
+    let max = transport.queue_max(index).min(MAX_QUEUE_SIZE);
+    if max == 0 {
+        return Err(MmioError::QueueUnavailable.into());
+    }
+    Ok(1 << (15 - max.leading_zeros()))
+}
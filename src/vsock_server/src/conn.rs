@@ -0,0 +1,544 @@
+//! The virtio-vsock stream protocol, without a device.
+//!
+//! [`Vsock`] is one end of the transport: it keeps the connections of a
+//! CID, turns socket operations into packets for the other end and packets
+//! from the other end into connection state. Packets leave through
+//! [`Vsock::take_packet`] and arrive through [`Vsock::handle`]; nothing
+//! here touches a device, so two endpoints handing each other their packets
+//! form a working link.
+//!
+//! A connection starts with `REQUEST`, answered by `RESPONSE` from a
+//! listener or `RST` if there is none. Flow control is credit based: every
+//! packet carries the sender's receive buffer size (`buf_alloc`) and how
+//! much of it its user consumed so far (`fwd_cnt`). A sender keeps the data
+//! in flight, `tx_cnt - peer_fwd_cnt`, within `peer_buf_alloc`, so a
+//! receiver never has to drop data. Receivers report consumed data with
+//! `CREDIT_UPDATE` once half their buffer freed up, or when the peer runs
+//! out of credit and asks with `CREDIT_REQUEST`.
+//!
+//! `SHUTDOWN` ends one or both directions. A connection closed by its user
+//! sends `SHUTDOWN` for both and waits for the peer's `RST`; a peer that
+//! does not answer within [`CLOSE_TIMEOUT`] is reset, see
+//! [`Vsock::expire`]. A peer that shut down both directions is answered
+//! with `RST` right away.
+//!
+//! Errors are positive errno values.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use libc::{EADDRINUSE, EAGAIN, ECONNREFUSED, ECONNRESET, EINVAL, ENOTCONN, EPIPE};
+use virtio_frontend::vsock::{
+    VsockHeader, VsockPacket, MAX_PAYLOAD, VIRTIO_VSOCK_OP_CREDIT_REQUEST,
+    VIRTIO_VSOCK_OP_CREDIT_UPDATE, VIRTIO_VSOCK_OP_REQUEST, VIRTIO_VSOCK_OP_RESPONSE,
+    VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_OP_RW, VIRTIO_VSOCK_OP_SHUTDOWN, VIRTIO_VSOCK_SHUTDOWN_RCV,
+    VIRTIO_VSOCK_SHUTDOWN_SEND, VIRTIO_VSOCK_TYPE_STREAM,
+};
+
+/// Receive buffer of a connection unless asked otherwise.
+pub const DEFAULT_BUF_ALLOC: u32 = 64 * 1024;
+/// How long a closed connection waits for the peer's `RST`.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(8);
+/// Both `SHUTDOWN` flags.
+pub const SHUTDOWN_BOTH: u32 = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+
+const EPHEMERAL_FIRST: u32 = 49152;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VsockAddr {
+    pub cid: u64,
+    pub port: u32,
+}
+
+impl VsockAddr {
+    pub fn new(cid: u64, port: u32) -> Self {
+        Self { cid, port }
+    }
+}
+
+/// A connection: the local port and the peer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnId {
+    pub local_port: u32,
+    pub peer: VsockAddr,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// `REQUEST` sent, waiting for `RESPONSE`.
+    Connecting,
+    Connected,
+    /// Closed by the user, waiting for the peer's `RST`.
+    Closing,
+    /// Ended, normally or by [`Vsock::error`].
+    Closed,
+}
+
+struct Conn {
+    state: State,
+    /// `ECONNREFUSED` or `ECONNRESET` if the connection ended abnormally.
+    error: Option<i32>,
+    /// Received data not read yet.
+    rx: VecDeque<u8>,
+    buf_alloc: u32,
+    /// Bytes the user read so far, wrapping.
+    fwd_cnt: u32,
+    /// `fwd_cnt` as last told to the peer.
+    last_fwd_cnt: u32,
+    /// Bytes sent so far, wrapping.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// `SHUTDOWN` flags received and sent.
+    peer_shutdown: u32,
+    local_shutdown: u32,
+    /// A `CREDIT_REQUEST` went out since the peer last sent anything.
+    credit_requested: bool,
+    /// When the user closed the connection, which is then removed once it
+    /// ended.
+    closed_at: Option<Instant>,
+}
+
+impl Conn {
+    fn new(state: State, buf_alloc: u32) -> Self {
+        Self {
+            state,
+            error: None,
+            rx: VecDeque::new(),
+            buf_alloc,
+            fwd_cnt: 0,
+            last_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            peer_shutdown: 0,
+            local_shutdown: 0,
+            credit_requested: false,
+            closed_at: None,
+        }
+    }
+
+    /// Bytes the peer can take right now.
+    fn credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    /// The peer sends no more data.
+    fn eof(&self) -> bool {
+        self.state == State::Closed || self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+    }
+}
+
+struct Listener {
+    backlog: usize,
+    buf_alloc: u32,
+    /// Connections established but not accepted yet.
+    pending: VecDeque<ConnId>,
+}
+
+/// One end of the transport, with CID `cid`.
+pub struct Vsock {
+    cid: u64,
+    listeners: BTreeMap<u32, Listener>,
+    conns: BTreeMap<ConnId, Conn>,
+    /// Packets for the other end.
+    out: VecDeque<VsockPacket>,
+    next_port: u32,
+}
+
+impl Vsock {
+    pub fn new(cid: u64) -> Self {
+        Self {
+            cid,
+            listeners: BTreeMap::new(),
+            conns: BTreeMap::new(),
+            out: VecDeque::new(),
+            next_port: EPHEMERAL_FIRST,
+        }
+    }
+
+    pub fn cid(&self) -> u64 {
+        self.cid
+    }
+
+    /// The transport was reset: every connection ends with `ECONNRESET`
+    /// and the endpoint continues as `cid`. Listeners stay.
+    pub fn reset(&mut self, cid: u64) {
+        self.cid = cid;
+        self.out.clear();
+        self.conns.retain(|_, conn| conn.closed_at.is_none());
+        for conn in self.conns.values_mut() {
+            conn.state = State::Closed;
+            conn.error = Some(ECONNRESET);
+        }
+    }
+
+    /// The next packet for the other end.
+    pub fn peek_packet(&self) -> Option<&VsockPacket> {
+        self.out.front()
+    }
+
+    pub fn take_packet(&mut self) -> Option<VsockPacket> {
+        self.out.pop_front()
+    }
+
+    /// Whether `port` is taken by a listener or a connection.
+    pub fn port_in_use(&self, port: u32) -> bool {
+        self.listeners.contains_key(&port) || self.conns.keys().any(|id| id.local_port == port)
+    }
+
+    /// A port neither listening nor connected.
+    pub fn ephemeral_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_port;
+            self.next_port = match self.next_port.checked_add(1) {
+                Some(next) if next != u32::MAX => next,
+                _ => EPHEMERAL_FIRST,
+            };
+            if !self.port_in_use(port) {
+                return port;
+            }
+        }
+    }
+
+    /// Accept up to `backlog` connections on `port`, each with a receive
+    /// buffer of `buf_alloc` bytes.
+    pub fn listen(&mut self, port: u32, backlog: usize, buf_alloc: u32) -> Result<(), i32> {
+        if self.listeners.contains_key(&port) {
+            return Err(EADDRINUSE);
+        }
+        let listener = Listener {
+            backlog: backlog.max(1),
+            buf_alloc,
+            pending: VecDeque::new(),
+        };
+        self.listeners.insert(port, listener);
+        Ok(())
+    }
+
+    /// Stop listening on `port`, resetting connections not accepted yet.
+    pub fn unlisten(&mut self, port: u32) {
+        let Some(listener) = self.listeners.remove(&port) else {
+            return;
+        };
+        for id in listener.pending {
+            self.conns.remove(&id);
+            self.send_rst(id);
+        }
+    }
+
+    /// Take an established connection of the listener on `port`.
+    pub fn accept(&mut self, port: u32) -> Result<ConnId, i32> {
+        let listener = self.listeners.get_mut(&port).ok_or(EINVAL)?;
+        listener.pending.pop_front().ok_or(EAGAIN)
+    }
+
+    /// Whether `accept` on `port` would succeed.
+    pub fn can_accept(&self, port: u32) -> bool {
+        self.listeners
+            .get(&port)
+            .is_some_and(|l| !l.pending.is_empty())
+    }
+
+    /// Connect `local_port` to `peer` with a receive buffer of `buf_alloc`
+    /// bytes. The connection is established once [`Vsock::state`] says so.
+    pub fn connect(
+        &mut self,
+        local_port: u32,
+        peer: VsockAddr,
+        buf_alloc: u32,
+    ) -> Result<ConnId, i32> {
+        let id = ConnId { local_port, peer };
+        if self.conns.contains_key(&id) {
+            return Err(EADDRINUSE);
+        }
+        self.conns
+            .insert(id, Conn::new(State::Connecting, buf_alloc));
+        self.send(id, VIRTIO_VSOCK_OP_REQUEST, 0, Vec::new());
+        Ok(id)
+    }
+
+    pub fn state(&self, id: ConnId) -> Option<State> {
+        self.conns.get(&id).map(|c| c.state)
+    }
+
+    /// Why the connection ended, if not normally.
+    pub fn error(&self, id: ConnId) -> Option<i32> {
+        self.conns.get(&id).and_then(|c| c.error)
+    }
+
+    /// Bytes received and not read yet.
+    pub fn available(&self, id: ConnId) -> usize {
+        self.conns.get(&id).map_or(0, |c| c.rx.len())
+    }
+
+    /// `SHUTDOWN` flags the peer sent.
+    pub fn peer_shutdown(&self, id: ConnId) -> u32 {
+        self.conns.get(&id).map_or(0, |c| c.peer_shutdown)
+    }
+
+    /// Bytes `send` would take right now.
+    pub fn send_capacity(&self, id: ConnId) -> usize {
+        match self.conns.get(&id) {
+            Some(c)
+                if c.state == State::Connected
+                    && c.local_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND == 0 =>
+            {
+                c.credit() as usize
+            }
+            _ => 0,
+        }
+    }
+
+    /// Send as much of `data` as the peer has credit for. Fails with
+    /// `EAGAIN` while there is none, after asking the peer for more.
+    pub fn send_data(&mut self, id: ConnId, data: &[u8]) -> Result<usize, i32> {
+        let conn = self.conns.get_mut(&id).ok_or(ENOTCONN)?;
+        match conn.state {
+            State::Connecting => return Err(EAGAIN),
+            State::Connected => {}
+            State::Closing => return Err(EPIPE),
+            State::Closed => return Err(conn.error.unwrap_or(EPIPE)),
+        }
+        if conn.local_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0
+            || conn.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0
+        {
+            return Err(EPIPE);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let credit = conn.credit() as usize;
+        if credit == 0 {
+            if !conn.credit_requested {
+                conn.credit_requested = true;
+                self.send(id, VIRTIO_VSOCK_OP_CREDIT_REQUEST, 0, Vec::new());
+            }
+            return Err(EAGAIN);
+        }
+        let len = data.len().min(credit);
+        conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+        for chunk in data[..len].chunks(MAX_PAYLOAD) {
+            self.send(id, VIRTIO_VSOCK_OP_RW, 0, chunk.to_vec());
+        }
+        Ok(len)
+    }
+
+    /// Read received data into `buf`, leaving it in place with `peek`.
+    /// Returns 0 at the end of the stream and fails with `EAGAIN` while
+    /// there is nothing to read.
+    pub fn recv(&mut self, id: ConnId, buf: &mut [u8], peek: bool) -> Result<usize, i32> {
+        let conn = self.conns.get_mut(&id).ok_or(ENOTCONN)?;
+        if conn.rx.is_empty() || buf.is_empty() {
+            return match conn.state {
+                _ if buf.is_empty() => Ok(0),
+                State::Closed => conn.error.map_or(Ok(0), Err),
+                _ if conn.eof() || conn.local_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 => Ok(0),
+                _ => Err(EAGAIN),
+            };
+        }
+        let len = buf.len().min(conn.rx.len());
+        for (dst, src) in buf.iter_mut().zip(conn.rx.iter()) {
+            *dst = *src;
+        }
+        if peek {
+            return Ok(len);
+        }
+        conn.rx.drain(..len);
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+        // Tell the peer about the space once half the buffer freed up.
+        let freed = conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt);
+        if conn.state == State::Connected && freed >= conn.buf_alloc / 2 {
+            self.send(id, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+        }
+        Ok(len)
+    }
+
+    /// Shut down the directions in `flags`, `VIRTIO_VSOCK_SHUTDOWN_*`.
+    pub fn shutdown(&mut self, id: ConnId, flags: u32) -> Result<(), i32> {
+        let conn = self.conns.get_mut(&id).ok_or(ENOTCONN)?;
+        if conn.state != State::Connected {
+            return Err(ENOTCONN);
+        }
+        let flags = flags & SHUTDOWN_BOTH & !conn.local_shutdown;
+        if flags == 0 {
+            return Ok(());
+        }
+        conn.local_shutdown |= flags;
+        if flags & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 {
+            conn.rx.clear();
+        }
+        self.send(id, VIRTIO_VSOCK_OP_SHUTDOWN, flags, Vec::new());
+        Ok(())
+    }
+
+    /// Close the connection for the user. It goes away once the peer
+    /// acknowledged, or right away if it already ended.
+    pub fn close(&mut self, id: ConnId, now: Instant) {
+        let Some(conn) = self.conns.get_mut(&id) else {
+            return;
+        };
+        conn.closed_at = Some(now);
+        match conn.state {
+            State::Connecting => {
+                self.conns.remove(&id);
+                self.send_rst(id);
+            }
+            State::Connected => {
+                conn.state = State::Closing;
+                conn.rx.clear();
+                let flags = SHUTDOWN_BOTH & !conn.local_shutdown;
+                conn.local_shutdown = SHUTDOWN_BOTH;
+                self.send(id, VIRTIO_VSOCK_OP_SHUTDOWN, flags, Vec::new());
+            }
+            State::Closing => {}
+            State::Closed => {
+                self.conns.remove(&id);
+            }
+        }
+    }
+
+    /// Reset closed connections whose peer did not answer in time.
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<ConnId> = self
+            .conns
+            .iter()
+            .filter(|(_, c)| {
+                c.state == State::Closing
+                    && c.closed_at
+                        .is_some_and(|t| now.saturating_duration_since(t) >= CLOSE_TIMEOUT)
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.conns.remove(&id);
+            self.send_rst(id);
+        }
+    }
+
+    /// Process a packet from the other end.
+    pub fn handle(&mut self, pkt: VsockPacket) {
+        let hdr = pkt.hdr;
+        if hdr.dst_cid != self.cid {
+            return;
+        }
+        let id = ConnId {
+            local_port: hdr.dst_port,
+            peer: VsockAddr::new(hdr.src_cid, hdr.src_port),
+        };
+        if hdr.ty != VIRTIO_VSOCK_TYPE_STREAM {
+            if hdr.op != VIRTIO_VSOCK_OP_RST {
+                self.send_rst(id);
+            }
+            return;
+        }
+        let Some(conn) = self.conns.get_mut(&id) else {
+            self.handle_unknown(id, &hdr);
+            return;
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+        conn.credit_requested = false;
+
+        match (conn.state, hdr.op) {
+            (_, VIRTIO_VSOCK_OP_RST) => {
+                if conn.closed_at.is_some() {
+                    self.conns.remove(&id);
+                    return;
+                }
+                if conn.state == State::Connecting {
+                    conn.error = Some(ECONNREFUSED);
+                } else if conn.peer_shutdown != SHUTDOWN_BOTH {
+                    conn.error = Some(ECONNRESET);
+                }
+                conn.state = State::Closed;
+            }
+            (State::Connecting, VIRTIO_VSOCK_OP_RESPONSE) => conn.state = State::Connected,
+            (State::Connecting, _) => {
+                conn.state = State::Closed;
+                conn.error = Some(ECONNRESET);
+                self.send_rst(id);
+            }
+            (State::Closed, _) => self.send_rst(id),
+            (_, VIRTIO_VSOCK_OP_RW) => {
+                let fits = conn.rx.len() + pkt.data.len() <= conn.buf_alloc as usize;
+                // Data the user does not want, or beyond the credit the peer
+                // got, is dropped.
+                if conn.local_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0 && fits {
+                    conn.rx.extend(pkt.data);
+                }
+            }
+            (_, VIRTIO_VSOCK_OP_CREDIT_REQUEST) => {
+                self.send(id, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+            }
+            (_, VIRTIO_VSOCK_OP_SHUTDOWN) => {
+                conn.peer_shutdown |= hdr.flags & SHUTDOWN_BOTH;
+                if conn.peer_shutdown == SHUTDOWN_BOTH {
+                    if conn.closed_at.is_some() {
+                        self.conns.remove(&id);
+                    } else {
+                        conn.state = State::Closed;
+                    }
+                    self.send_rst(id);
+                }
+            }
+            // Credit updates only carry the header, duplicate requests and
+            // responses are ignored.
+            _ => {}
+        }
+    }
+
+    /// Packet for a connection this end does not have: a request for a
+    /// listener, or something to answer with `RST`.
+    fn handle_unknown(&mut self, id: ConnId, hdr: &VsockHeader) {
+        match hdr.op {
+            VIRTIO_VSOCK_OP_RST => {}
+            VIRTIO_VSOCK_OP_REQUEST => {
+                let Some(listener) = self.listeners.get_mut(&id.local_port) else {
+                    self.send_rst(id);
+                    return;
+                };
+                if listener.pending.len() >= listener.backlog {
+                    self.send_rst(id);
+                    return;
+                }
+                listener.pending.push_back(id);
+                let mut conn = Conn::new(State::Connected, listener.buf_alloc);
+                conn.peer_buf_alloc = hdr.buf_alloc;
+                conn.peer_fwd_cnt = hdr.fwd_cnt;
+                self.conns.insert(id, conn);
+                self.send(id, VIRTIO_VSOCK_OP_RESPONSE, 0, Vec::new());
+            }
+            _ => self.send_rst(id),
+        }
+    }
+
+    /// Queue a packet of connection `id`, carrying its credit.
+    fn send(&mut self, id: ConnId, op: u16, flags: u32, data: Vec<u8>) {
+        let (buf_alloc, fwd_cnt) = match self.conns.get_mut(&id) {
+            Some(conn) => {
+                conn.last_fwd_cnt = conn.fwd_cnt;
+                (conn.buf_alloc, conn.fwd_cnt)
+            }
+            None => (0, 0),
+        };
+        let hdr = VsockHeader {
+            src_cid: self.cid,
+            dst_cid: id.peer.cid,
+            src_port: id.local_port,
+            dst_port: id.peer.port,
+            len: data.len() as u32,
+            ty: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc,
+            fwd_cnt,
+        };
+        self.out.push_back(VsockPacket { hdr, data });
+    }
+
+    fn send_rst(&mut self, id: ConnId) {
+        self.send(id, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+    }
+}
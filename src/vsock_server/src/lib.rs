@@ -0,0 +1,16 @@
+//! Vsock service.
+//!
+//! The library holds everything that does not depend on L4Re: the stream
+//! protocol of virtio-vsock with its credit-based flow control, and the
+//! sockets clients use on top of a virtio-vsock device. The `vsock_server`
+//! binary (feature `l4re`) serves `AF_VSOCK` stream sockets over IPC as
+//! described in [`proto`]; without the feature the library builds and
+//! tests on the host against a simulated device or a second [`Vsock`]
+//! playing the host.
+
+pub mod conn;
+pub mod proto;
+pub mod service;
+
+pub use conn::{ConnId, State, Vsock, VsockAddr};
+pub use service::Sockets;
@@ -0,0 +1,372 @@
+//! Vsock service on the virtio-vsock device.
+//!
+//! Clients open `AF_VSOCK` stream sockets to the host through the
+//! `global_vsock` gate, see [`proto`] for the IPC protocol. The device
+//! registers arrive as the `virtio_vsock` dataspace, its interrupt as
+//! `virtio_vsock_irq`. Sockets never block; clients retry operations that
+//! fail with `EAGAIN` and poll for connections to complete.
+//!
+//! Every client talks to the server through a session gate of its own,
+//! created on request, so the server can tell clients apart and close their
+//! sockets once they are gone.
+
+use core::ffi::c_void;
+use core::mem::size_of;
+use l4::sys::{l4_cap_idx_t, l4_ipc_error, l4_irq_unmask, l4_msgtag, l4_timeout_t, l4_utcb};
+use l4_sys::{
+    l4_default_caps_t, l4_factory_create_gate, l4_is_invalid_cap, l4_msgtag_label, l4_msgtag_t,
+    l4_obj_fpage, l4_sndfpage_add, l4_task_cap_has_child, l4_task_delete_obj, l4_timeout,
+    l4_timeout_from_us, l4_utcb_br, l4_utcb_mr, l4re_util_cap_alloc, l4re_util_cap_free,
+    L4_cap_fpage_rights,
+};
+use l4re::sys::{l4re_env, l4re_env_get_cap, l4re_rm_attach};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use virtio_frontend::mmio::{MmioRegion, MmioTransport};
+use virtio_frontend::vsock::VsockDriver;
+use vsock_server::{proto, Sockets};
+
+const BR_WORDS: usize = l4_sys::consts::UtcbConsts::L4_UTCB_GENERIC_BUFFERS_SIZE as usize;
+const BR_DATA_BYTES: usize = (BR_WORDS - 1) * size_of::<u64>();
+
+/// Label of client requests arriving through the `global_vsock` gate.
+const GATE_LABEL: u64 = 0b1111_0000;
+/// Label of virtio-vsock interrupts.
+const IRQ_LABEL: u64 = 0b1_0000_0000;
+/// Labels of session gates: this bit plus the serial number of the session
+/// in the bits above the rights, so the label of a closed session is never
+/// reused.
+const SESSION_LABEL: u64 = 1 << 32;
+/// The two least significant label bits carry the rights of the sender's
+/// capability.
+const LABEL_MASK: u64 = !0b11;
+
+/// How often sessions are checked for clients that went away.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A client's own gate to the server, see [`proto`].
+struct Session {
+    gate: l4_cap_idx_t,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            // Delete the gate rather than just dropping our capability, so
+            // the client's copy stops reaching the server.
+            let task = l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t;
+            let _ = l4_task_delete_obj(task, self.gate);
+            l4re_util_cap_free(self.gate);
+        }
+    }
+}
+
+/// Open sessions by the label of their gate, which also marks their
+/// sockets.
+struct Sessions {
+    open: HashMap<u64, Session>,
+    /// Serial number of the next session, part of its gate label.
+    next: u64,
+    /// When to look for sessions whose client went away.
+    sweep_at: Instant,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        Self { open: HashMap::new(), next: 0, sweep_at: Instant::now() }
+    }
+
+    /// Create a session gate. Returns the capability to map to the client.
+    unsafe fn create(&mut self, now: Instant) -> Result<l4_cap_idx_t, i32> {
+        let label = SESSION_LABEL | (self.next << 2);
+        let gate = l4re_util_cap_alloc();
+        if l4_is_invalid_cap(gate) {
+            return Err(libc::ENOMEM);
+        }
+        let env = &*l4re_env();
+        let tag = l4_factory_create_gate(env.factory, gate, env.main_thread, label);
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            l4re_util_cap_free(gate);
+            return Err(libc::ENOMEM);
+        }
+        if self.open.is_empty() {
+            self.sweep_at = now + SWEEP_INTERVAL;
+        }
+        self.next += 1;
+        self.open.insert(label, Session { gate });
+        Ok(gate)
+    }
+
+    /// Close the session `client` together with its sockets.
+    fn close(
+        &mut self,
+        sockets: &mut Sockets<MmioRegion>,
+        client: u64,
+        now: Instant,
+    ) -> Result<(), i32> {
+        self.open.remove(&client).ok_or(libc::EPERM)?;
+        sockets.close_client(client, now);
+        Ok(())
+    }
+
+    /// Close the sessions whose gate is no longer mapped to any task, once
+    /// the sweep interval passed.
+    unsafe fn sweep(&mut self, sockets: &mut Sockets<MmioRegion>, now: Instant) {
+        if self.open.is_empty() || now < self.sweep_at {
+            return;
+        }
+        self.sweep_at = now + SWEEP_INTERVAL;
+        let task = l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t;
+        // Checking and deleting gates uses the message registers, which may
+        // hold a request.
+        let saved = (*l4_utcb_mr()).mr;
+        let gone: Vec<u64> = self
+            .open
+            .iter()
+            .filter(|(_, session)| {
+                let tag = l4_task_cap_has_child(task, session.gate);
+                l4_ipc_error(tag, l4_utcb()) == 0 && l4_msgtag_label(tag) == 0
+            })
+            .map(|(&label, _)| label)
+            .collect();
+        for client in gone {
+            let _ = self.close(sockets, client, now);
+        }
+        (*l4_utcb_mr()).mr = saved;
+    }
+
+    /// Receive timeout for the next wait: wake up for the next sweep, or
+    /// never without sessions.
+    fn wait_timeout(&self, now: Instant) -> l4_timeout_t {
+        if self.open.is_empty() {
+            return l4_timeout_t { raw: 0 };
+        }
+        let delay = self.sweep_at.saturating_duration_since(now).as_micros();
+        // Never time out sending the reply.
+        l4_timeout(0, l4_timeout_from_us(min(delay, u32::MAX as u128 - 1) as u32))
+    }
+}
+
+fn encode_errno(err: i32) -> u64 {
+    (-(err as i64)) as u64
+}
+
+/// Copy the payload passed in the buffer registers.
+unsafe fn br_payload() -> Vec<u8> {
+    let br = &(*l4_utcb_br()).br;
+    let len = min(br[0] as usize, BR_DATA_BYTES);
+    let mut data = vec![0u8; len];
+    core::ptr::copy_nonoverlapping(br.as_ptr().add(1) as *const u8, data.as_mut_ptr(), len);
+    data
+}
+
+/// Return `data` to the client through the buffer registers.
+unsafe fn br_reply(data: &[u8]) {
+    let br = &mut (*l4_utcb_br()).br;
+    let len = min(data.len(), BR_DATA_BYTES);
+    br[0] = len as u64;
+    core::ptr::copy_nonoverlapping(data.as_ptr(), br.as_mut_ptr().add(1) as *mut u8, len);
+}
+
+/// Open a session for a request through `global_vsock`, or close the
+/// session whose gate the request came through. Returns the tag of the
+/// reply; opening maps the session gate.
+unsafe fn dispatch_session(
+    sessions: &mut Sessions,
+    sockets: &mut Sockets<MmioRegion>,
+    client: u64,
+    now: Instant,
+) -> Result<l4_msgtag_t, i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    if mr[0] == proto::OP_SESSION_CLOSE {
+        sessions.close(sockets, client, now)?;
+        mr[0] = 0;
+        return Ok(l4_msgtag(0, 1, 0, 0));
+    }
+    if client != GATE_LABEL {
+        return Err(libc::EINVAL);
+    }
+    let gate = sessions.create(now)?;
+    mr[0] = 0;
+    let mut tag = l4_msgtag(0, 1, 0, 0);
+    let rights = L4_cap_fpage_rights::L4_CAP_FPAGE_RWS as u8;
+    l4_sndfpage_add(l4_obj_fpage(gate, 0, rights), 0, &mut tag);
+    Ok(tag)
+}
+
+/// Handle one request. Returns the value of `MR0` and the number of words
+/// in the reply.
+unsafe fn dispatch(
+    sockets: &mut Sockets<MmioRegion>,
+    sessions: &Sessions,
+    client: u64,
+) -> Result<(u64, u32), i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    // Sockets belong to sessions only, see `proto`.
+    if !sessions.open.contains_key(&client) {
+        return Err(libc::EPERM);
+    }
+    let handle = mr[1];
+    match mr[0] {
+        proto::OP_SOCKET => {
+            let handle = sockets.socket_open(client, mr[1] as i32, mr[2] as i32, mr[3] as i32)?;
+            Ok((handle, 1))
+        }
+        proto::OP_SEND => {
+            let data = br_payload();
+            let n = sockets.send(client, handle, &data)?;
+            Ok((n as u64, 1))
+        }
+        proto::OP_RECV => {
+            let mut buf = vec![0u8; min(mr[2] as usize, BR_DATA_BYTES)];
+            let n = sockets.recv(client, handle, &mut buf, mr[3] as i32)?;
+            br_reply(&buf[..n]);
+            Ok((n as u64, 1))
+        }
+        proto::OP_CLOSE => {
+            sockets.close(client, handle, Instant::now())?;
+            Ok((0, 1))
+        }
+        proto::OP_BIND => {
+            let (cid, port) = proto::decode_addr(&mr[2..5])?;
+            sockets.bind(client, handle, cid, port)?;
+            Ok((0, 1))
+        }
+        proto::OP_LISTEN => {
+            sockets.listen(client, handle, mr[2] as usize)?;
+            Ok((0, 1))
+        }
+        proto::OP_ACCEPT => {
+            let (new, (cid, port)) = sockets.accept(client, handle)?;
+            proto::encode_addr(cid, port, &mut mr[1..4]);
+            Ok((new, 4))
+        }
+        proto::OP_CONNECT => {
+            let (cid, port) = proto::decode_addr(&mr[2..5])?;
+            sockets.connect(client, handle, cid, port)?;
+            Ok((0, 1))
+        }
+        proto::OP_SHUTDOWN => {
+            sockets.shutdown(client, handle, mr[2] as i32)?;
+            Ok((0, 1))
+        }
+        proto::OP_SENDTO | proto::OP_RECVFROM => Err(libc::EOPNOTSUPP),
+        proto::OP_GETSOCKNAME => {
+            let (cid, port) = sockets.getsockname(client, handle)?;
+            proto::encode_addr(cid, port, &mut mr[1..4]);
+            Ok((0, 4))
+        }
+        proto::OP_GETPEERNAME => {
+            let (cid, port) = sockets.getpeername(client, handle)?;
+            proto::encode_addr(cid, port, &mut mr[1..4]);
+            Ok((0, 4))
+        }
+        proto::OP_SETSOCKOPT => {
+            sockets.setsockopt(client, handle, mr[2] as i32, mr[3] as i32, mr[4])?;
+            Ok((0, 1))
+        }
+        proto::OP_GETSOCKOPT => {
+            mr[1] = sockets.getsockopt(client, handle, mr[2] as i32, mr[3] as i32)?;
+            Ok((0, 2))
+        }
+        proto::OP_POLL => Ok((sockets.poll(client, handle)? as u64, 1)),
+        _ => Err(libc::ENOSYS),
+    }
+}
+
+unsafe fn bind(cap: l4_cap_idx_t, label: u64) -> bool {
+    l4_ipc_error(
+        l4::l4_rcv_ep_bind_thread(cap, (*l4re_env()).main_thread, label),
+        l4_utcb(),
+    ) == 0
+}
+
+/// Map the device registers, found under `virtio_vsock`, and initialise
+/// the device.
+unsafe fn open_device() -> Option<Sockets<MmioRegion>> {
+    let regs = l4re_env_get_cap("virtio_vsock")?;
+    let mut base: *mut c_void = core::ptr::null_mut();
+    let flags = l4re::sys::l4re_rm_flags_values::L4RE_RM_F_SEARCH_ADDR as u64
+        | l4re::sys::l4re_rm_flags_values::L4RE_RM_F_RW as u64;
+    let size = 1 << l4::sys::L4_PAGESHIFT;
+    if l4re_rm_attach(&mut base, size, flags, regs, 0, l4::sys::L4_PAGESHIFT as u8) < 0 {
+        return None;
+    }
+    let transport = MmioTransport::new(MmioRegion::new(base as *mut u8)).ok()?;
+    match VsockDriver::new(transport) {
+        Ok(driver) => Some(Sockets::new(driver)),
+        Err(e) => {
+            println!("vsock_server: virtio-vsock: {e}");
+            None
+        }
+    }
+}
+
+fn main() {
+    unsafe { run() }
+}
+
+/// Unsafe portion of the server. Interacts directly with L4 system calls.
+unsafe fn run() {
+    let gate = l4re_env_get_cap("global_vsock").expect("IPC gate 'global_vsock' not provided");
+    if !bind(gate, GATE_LABEL) {
+        panic!("failed to bind IPC gate");
+    }
+    let mut sockets = open_device().expect("no virtio-vsock device");
+    let irq = l4re_env_get_cap("virtio_vsock_irq").expect("virtio-vsock IRQ not provided");
+    if !bind(irq, IRQ_LABEL) {
+        panic!("failed to bind virtio-vsock IRQ");
+    }
+    let _ = l4_irq_unmask(irq);
+
+    println!("vsock server ready, CID {}", sockets.guest_cid());
+
+    let mut sessions = Sessions::new();
+
+    // Wait for client requests and device interrupts. Closed connections
+    // whose peer stays silent are reset as events come in. While sessions
+    // are open, the wait times out to check them now and then.
+    let mut label = 0u64;
+    let timeout = sessions.wait_timeout(Instant::now());
+    let mut tag = l4::l4_ipc_wait(l4_utcb(), &mut label, timeout);
+    loop {
+        let failed = l4_ipc_error(tag, l4_utcb()) != 0;
+        if !failed && label & LABEL_MASK == IRQ_LABEL {
+            let _ = l4_irq_unmask(irq);
+            if let Err(err) = sockets.interrupt(Instant::now()) {
+                println!("vsock_server: virtio-vsock: error {err}");
+            }
+        }
+        sessions.sweep(&mut sockets, Instant::now());
+        if failed || label & LABEL_MASK == IRQ_LABEL {
+            let timeout = sessions.wait_timeout(Instant::now());
+            tag = l4::l4_ipc_wait(l4_utcb(), &mut label, timeout);
+            continue;
+        }
+
+        // Pick up packets that arrived since the last interrupt.
+        let _ = sockets.poll_device(Instant::now());
+        let client = label & LABEL_MASK;
+        let op = (*l4_utcb_mr()).mr[0];
+        let reply = if op == proto::OP_SESSION || op == proto::OP_SESSION_CLOSE {
+            dispatch_session(&mut sessions, &mut sockets, client, Instant::now())
+        } else {
+            dispatch(&mut sockets, &sessions, client).map(|(result, words)| {
+                (*l4_utcb_mr()).mr[0] = result;
+                l4_msgtag(0, words, 0, 0)
+            })
+        };
+        let reply = reply.unwrap_or_else(|err| {
+            (*l4_utcb_mr()).mr[0] = encode_errno(err);
+            l4_msgtag(0, 1, 0, 0)
+        });
+
+        tag = l4::l4_ipc_reply_and_wait(
+            l4_utcb(),
+            reply,
+            &mut label,
+            sessions.wait_timeout(Instant::now()),
+        );
+    }
+}
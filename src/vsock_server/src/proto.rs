@@ -0,0 +1,110 @@
+//! Message register layout of the vsock protocol.
+//!
+//! The service speaks the socket protocol of the network service, so
+//! `net_client` talks to either; this is the part that applies to
+//! `AF_VSOCK` stream sockets:
+//!
+//! ```text
+//! MR0: operation
+//!      0 = socket        MR1: domain, MR2: type, MR3: protocol
+//!                        Reply: MR0 = socket handle
+//!      1 = send          MR1: handle, MR2: flags, BRs: payload
+//!                        Reply: MR0 = bytes sent
+//!      2 = recv          MR1: handle, MR2: capacity, MR3: flags
+//!                        Reply: MR0 = bytes received, BRs: payload
+//!      3 = close         MR1: handle
+//!      4 = bind          MR1: handle, MR2..MR4: address
+//!      5 = listen        MR1: handle, MR2: backlog
+//!      6 = accept        MR1: handle
+//!                        Reply: MR0 = new handle, MR1..MR3: peer address
+//!      7 = connect       MR1: handle, MR2..MR4: address
+//!      8 = shutdown      MR1: handle, MR2: how
+//!     11 = getsockname   MR1: handle     Reply: MR1..MR3: local address
+//!     12 = getpeername   MR1: handle     Reply: MR1..MR3: peer address
+//!     13 = setsockopt    MR1: handle, MR2: level, MR3: option, MR4: value
+//!     14 = getsockopt    MR1: handle, MR2: level, MR3: option
+//!                        Reply: MR1 = value
+//!     15 = poll          MR1: handle
+//!                        Reply: MR0 = ready events (POLLIN, POLLOUT, ...)
+//!     16 = session       Reply: maps the session gate
+//!     17 = session_close
+//! ```
+//!
+//! As with the network service, socket operations only go through a
+//! session gate: a client sends `session` to `global_vsock` and receives a
+//! gate of its own, whose sockets no other client can reach. Requests
+//! through `global_vsock` itself fail with `-EPERM`. `session_close`, sent
+//! through the session gate, closes all of the session's sockets, as does
+//! the client going away.
+//!
+//! An address takes three registers: `AF_VSOCK << 16`, the CID and the
+//! port. Binding to [`VMADDR_CID_ANY`] or the guest's own CID and
+//! [`VMADDR_PORT_ANY`] picks a free port. Only `SOCK_STREAM` sockets with
+//! protocol 0 exist; `sendto` and `recvfrom` fail with `-EOPNOTSUPP`.
+//!
+//! Sockets never block: operations that would have to wait fail with
+//! `-EAGAIN` and `connect` with `-EINPROGRESS` while the handshake runs. A
+//! `recv` with capacity 0 returns 0 once a connection is established and
+//! fails with `-EAGAIN` while it is in progress. `SO_RCVBUF` sets the
+//! receive buffer, and so the credit offered to the peer, of connections
+//! made afterwards.
+//!
+//! Payloads travel through the buffer registers: `BR0` holds the length in
+//! bytes and the data follows from `BR1` onwards. `MR0` of a reply is 0 or
+//! a handle/length on success, `-errno` on failure.
+
+pub const OP_SOCKET: u64 = 0;
+pub const OP_SEND: u64 = 1;
+pub const OP_RECV: u64 = 2;
+pub const OP_CLOSE: u64 = 3;
+pub const OP_BIND: u64 = 4;
+pub const OP_LISTEN: u64 = 5;
+pub const OP_ACCEPT: u64 = 6;
+pub const OP_CONNECT: u64 = 7;
+pub const OP_SHUTDOWN: u64 = 8;
+pub const OP_SENDTO: u64 = 9;
+pub const OP_RECVFROM: u64 = 10;
+pub const OP_GETSOCKNAME: u64 = 11;
+pub const OP_GETPEERNAME: u64 = 12;
+pub const OP_SETSOCKOPT: u64 = 13;
+pub const OP_GETSOCKOPT: u64 = 14;
+pub const OP_POLL: u64 = 15;
+pub const OP_SESSION: u64 = 16;
+pub const OP_SESSION_CLOSE: u64 = 17;
+
+pub const AF_VSOCK: i32 = 40;
+pub const SOCK_STREAM: i32 = 1;
+
+/// Any CID, in `bind`.
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+/// Any port, in `bind`.
+pub const VMADDR_PORT_ANY: u32 = u32::MAX;
+
+pub const SHUT_RD: i32 = 0;
+pub const SHUT_WR: i32 = 1;
+pub const SHUT_RDWR: i32 = 2;
+
+pub const MSG_PEEK: i32 = 2;
+
+pub const SOL_SOCKET: i32 = 1;
+pub const SO_TYPE: i32 = 3;
+pub const SO_ERROR: i32 = 4;
+pub const SO_RCVBUF: i32 = 8;
+
+/// Address of `cid` and `port` as carried in three message registers.
+pub fn encode_addr(cid: u32, port: u32, words: &mut [u64]) {
+    words[0] = (AF_VSOCK as u64) << 16;
+    words[1] = cid as u64;
+    words[2] = port as u64;
+}
+
+/// CID and port of an address in three message registers. Fails with
+/// `EAFNOSUPPORT` for other families and `EINVAL` for values out of range.
+pub fn decode_addr(words: &[u64]) -> Result<(u32, u32), i32> {
+    if (words[0] >> 16) as i32 != AF_VSOCK {
+        return Err(libc::EAFNOSUPPORT);
+    }
+    let cid = u32::try_from(words[1]).map_err(|_| libc::EINVAL)?;
+    let port = u32::try_from(words[2]).map_err(|_| libc::EINVAL)?;
+    Ok((cid, port))
+}
@@ -0,0 +1,458 @@
+//! `AF_VSOCK` stream sockets on a virtio-vsock device.
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use libc::{
+    EADDRINUSE, EADDRNOTAVAIL, EAFNOSUPPORT, EAGAIN, EALREADY, EBADF, EINPROGRESS, EINVAL, EIO,
+    EISCONN, EMFILE, ENETUNREACH, ENOPROTOOPT, ENOTCONN, EPROTONOSUPPORT, ESOCKTNOSUPPORT,
+};
+use virtio_frontend::mmio::Registers;
+use virtio_frontend::queue::QueueError;
+use virtio_frontend::vsock::{
+    VsockDriver, VsockError, VIRTIO_VSOCK_SHUTDOWN_RCV, VIRTIO_VSOCK_SHUTDOWN_SEND,
+    VMADDR_CID_LOCAL,
+};
+
+use crate::conn::{ConnId, State, Vsock, VsockAddr, DEFAULT_BUF_ALLOC, SHUTDOWN_BOTH};
+use crate::proto::{
+    AF_VSOCK, MSG_PEEK, SHUT_RD, SHUT_RDWR, SHUT_WR, SOCK_STREAM, SOL_SOCKET, SO_ERROR, SO_RCVBUF,
+    SO_TYPE, VMADDR_CID_ANY, VMADDR_PORT_ANY,
+};
+
+/// Sockets open at a time, over all clients.
+pub const MAX_SOCKETS: usize = 256;
+/// Largest listen backlog.
+pub const SOMAXCONN: usize = 128;
+/// Range of `SO_RCVBUF`.
+pub const MIN_BUF_ALLOC: u32 = 4096;
+pub const MAX_BUF_ALLOC: u32 = 1024 * 1024;
+
+enum Kind {
+    /// Created, maybe bound.
+    Idle,
+    Listening,
+    /// Connecting or connected.
+    Stream(ConnId),
+}
+
+struct Socket {
+    client: u64,
+    /// Local port, once bound.
+    port: Option<u32>,
+    kind: Kind,
+    /// Receive buffer of connections made from now on.
+    buf_alloc: u32,
+}
+
+/// The stream sockets of all clients on one vsock device.
+///
+/// Handles belong to the client that created them; other clients get
+/// `EBADF`. Results are `errno` values as in the protocol. Packets move
+/// between the device and the connections whenever the device interrupts
+/// and after every operation that produces some.
+pub struct Sockets<R: Registers> {
+    driver: VsockDriver<R>,
+    vsock: Vsock,
+    sockets: BTreeMap<u64, Socket>,
+    next_handle: u64,
+}
+
+fn errno(err: VsockError) -> i32 {
+    match err {
+        VsockError::Queue(QueueError::Full) => EAGAIN,
+        _ => EIO,
+    }
+}
+
+impl<R: Registers> Sockets<R> {
+    pub fn new(driver: VsockDriver<R>) -> Self {
+        let vsock = Vsock::new(driver.guest_cid());
+        Self {
+            driver,
+            vsock,
+            sockets: BTreeMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    pub fn driver(&self) -> &VsockDriver<R> {
+        &self.driver
+    }
+
+    pub fn driver_mut(&mut self) -> &mut VsockDriver<R> {
+        &mut self.driver
+    }
+
+    /// The CID of this guest.
+    pub fn guest_cid(&self) -> u32 {
+        self.vsock.cid() as u32
+    }
+
+    /// Handle a device interrupt.
+    pub fn interrupt(&mut self, now: Instant) -> Result<(), i32> {
+        self.driver.interrupt().map_err(errno)?;
+        self.exchange(now)
+    }
+
+    /// Process what the device delivered without an interrupt.
+    pub fn poll_device(&mut self, now: Instant) -> Result<(), i32> {
+        self.driver.poll().map_err(errno)?;
+        self.exchange(now)
+    }
+
+    /// Hand received packets to the connections and send theirs.
+    fn exchange(&mut self, now: Instant) -> Result<(), i32> {
+        if self.driver.take_reset() {
+            self.vsock.reset(self.driver.guest_cid());
+        }
+        while let Some(pkt) = self.driver.recv() {
+            self.vsock.handle(pkt);
+        }
+        self.vsock.expire(now);
+        self.flush()
+    }
+
+    /// Send the connections' packets while the transmit queue has room;
+    /// the rest waits for the next interrupt.
+    fn flush(&mut self) -> Result<(), i32> {
+        while let Some(pkt) = self.vsock.peek_packet() {
+            match self.driver.send(pkt) {
+                Ok(()) => {}
+                Err(VsockError::Queue(QueueError::Full)) => break,
+                Err(err) => {
+                    self.vsock.take_packet();
+                    return Err(errno(err));
+                }
+            }
+            self.vsock.take_packet();
+        }
+        Ok(())
+    }
+
+    fn socket(&self, client: u64, handle: u64) -> Result<&Socket, i32> {
+        match self.sockets.get(&handle) {
+            Some(socket) if socket.client == client => Ok(socket),
+            _ => Err(EBADF),
+        }
+    }
+
+    fn socket_mut(&mut self, client: u64, handle: u64) -> Result<&mut Socket, i32> {
+        match self.sockets.get_mut(&handle) {
+            Some(socket) if socket.client == client => Ok(socket),
+            _ => Err(EBADF),
+        }
+    }
+
+    /// The connection of a stream socket.
+    fn conn(&self, client: u64, handle: u64) -> Result<ConnId, i32> {
+        match self.socket(client, handle)?.kind {
+            Kind::Stream(id) => Ok(id),
+            _ => Err(ENOTCONN),
+        }
+    }
+
+    fn port_taken(&self, port: u32) -> bool {
+        self.vsock.port_in_use(port) || self.sockets.values().any(|s| s.port == Some(port))
+    }
+
+    /// The port of the socket, binding it to a free one first if needed.
+    fn autobind(&mut self, handle: u64) -> u32 {
+        if let Some(port) = self.sockets[&handle].port {
+            return port;
+        }
+        let port = loop {
+            let port = self.vsock.ephemeral_port();
+            if !self.port_taken(port) {
+                break port;
+            }
+        };
+        self.sockets.get_mut(&handle).unwrap().port = Some(port);
+        port
+    }
+
+    /// Create a socket for `client` and return its handle.
+    pub fn socket_open(
+        &mut self,
+        client: u64,
+        domain: i32,
+        ty: i32,
+        protocol: i32,
+    ) -> Result<u64, i32> {
+        if domain != AF_VSOCK {
+            return Err(EAFNOSUPPORT);
+        }
+        if ty != SOCK_STREAM {
+            return Err(ESOCKTNOSUPPORT);
+        }
+        if protocol != 0 {
+            return Err(EPROTONOSUPPORT);
+        }
+        if self.sockets.len() >= MAX_SOCKETS {
+            return Err(EMFILE);
+        }
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        let socket = Socket {
+            client,
+            port: None,
+            kind: Kind::Idle,
+            buf_alloc: DEFAULT_BUF_ALLOC,
+        };
+        self.sockets.insert(handle, socket);
+        Ok(handle)
+    }
+
+    pub fn bind(&mut self, client: u64, handle: u64, cid: u32, port: u32) -> Result<(), i32> {
+        let socket = self.socket(client, handle)?;
+        if socket.port.is_some() || !matches!(socket.kind, Kind::Idle) {
+            return Err(EINVAL);
+        }
+        if cid != VMADDR_CID_ANY && cid != self.guest_cid() {
+            return Err(EADDRNOTAVAIL);
+        }
+        if port == VMADDR_PORT_ANY {
+            self.autobind(handle);
+            return Ok(());
+        }
+        if self.port_taken(port) {
+            return Err(EADDRINUSE);
+        }
+        self.socket_mut(client, handle)?.port = Some(port);
+        Ok(())
+    }
+
+    pub fn listen(&mut self, client: u64, handle: u64, backlog: usize) -> Result<(), i32> {
+        let socket = self.socket(client, handle)?;
+        let buf_alloc = socket.buf_alloc;
+        match socket.kind {
+            Kind::Idle => {}
+            Kind::Listening => return Ok(()),
+            Kind::Stream(_) => return Err(EINVAL),
+        }
+        let port = self.autobind(handle);
+        self.vsock
+            .listen(port, backlog.clamp(1, SOMAXCONN), buf_alloc)?;
+        self.socket_mut(client, handle)?.kind = Kind::Listening;
+        Ok(())
+    }
+
+    /// Take a connection of a listening socket. Returns the new handle and
+    /// the peer's CID and port.
+    pub fn accept(&mut self, client: u64, handle: u64) -> Result<(u64, (u32, u32)), i32> {
+        let socket = self.socket(client, handle)?;
+        let (Kind::Listening, Some(port)) = (&socket.kind, socket.port) else {
+            return Err(EINVAL);
+        };
+        let buf_alloc = socket.buf_alloc;
+        if self.sockets.len() >= MAX_SOCKETS {
+            return Err(EMFILE);
+        }
+        let id = self.vsock.accept(port)?;
+        let new = self.next_handle;
+        self.next_handle += 1;
+        let socket = Socket {
+            client,
+            port: Some(port),
+            kind: Kind::Stream(id),
+            buf_alloc,
+        };
+        self.sockets.insert(new, socket);
+        Ok((new, (id.peer.cid as u32, id.peer.port)))
+    }
+
+    /// Start connecting to `cid:port`, which fails with `EINPROGRESS`.
+    /// Calling again reports how the attempt went.
+    pub fn connect(&mut self, client: u64, handle: u64, cid: u32, port: u32) -> Result<(), i32> {
+        let socket = self.socket(client, handle)?;
+        let buf_alloc = socket.buf_alloc;
+        match socket.kind {
+            Kind::Idle => {}
+            Kind::Listening => return Err(EINVAL),
+            Kind::Stream(id) => {
+                return match self.vsock.state(id) {
+                    Some(State::Connecting) => Err(EALREADY),
+                    Some(State::Closed) if self.vsock.error(id).is_some() => {
+                        // A failed attempt is reported once, after which the
+                        // socket may try again.
+                        let err = self.vsock.error(id).unwrap();
+                        self.vsock.close(id, Instant::now());
+                        self.socket_mut(client, handle)?.kind = Kind::Idle;
+                        Err(err)
+                    }
+                    _ => Err(EISCONN),
+                };
+            }
+        }
+        // There is no loopback transport.
+        if cid == self.guest_cid() || cid as u64 == VMADDR_CID_LOCAL || cid == VMADDR_CID_ANY {
+            return Err(ENETUNREACH);
+        }
+        let local = self.autobind(handle);
+        let id = self
+            .vsock
+            .connect(local, VsockAddr::new(cid as u64, port), buf_alloc)?;
+        self.socket_mut(client, handle)?.kind = Kind::Stream(id);
+        self.flush()?;
+        Err(EINPROGRESS)
+    }
+
+    /// Send as much of `data` as the peer can take.
+    pub fn send(&mut self, client: u64, handle: u64, data: &[u8]) -> Result<usize, i32> {
+        let id = self.conn(client, handle)?;
+        let res = self.vsock.send_data(id, data);
+        self.flush()?;
+        res
+    }
+
+    /// Read received data into `buf`. Returns 0 at the end of the stream.
+    pub fn recv(
+        &mut self,
+        client: u64,
+        handle: u64,
+        buf: &mut [u8],
+        flags: i32,
+    ) -> Result<usize, i32> {
+        let id = self.conn(client, handle)?;
+        if buf.is_empty() {
+            return match self.vsock.state(id) {
+                Some(State::Connecting) => Err(EAGAIN),
+                Some(State::Closed) => self.vsock.error(id).map_or(Ok(0), |_| Err(ENOTCONN)),
+                _ => Ok(0),
+            };
+        }
+        let res = self.vsock.recv(id, buf, flags & MSG_PEEK != 0);
+        // Reading may free credit to announce.
+        self.flush()?;
+        res
+    }
+
+    pub fn shutdown(&mut self, client: u64, handle: u64, how: i32) -> Result<(), i32> {
+        let id = self.conn(client, handle)?;
+        let flags = match how {
+            SHUT_RD => VIRTIO_VSOCK_SHUTDOWN_RCV,
+            SHUT_WR => VIRTIO_VSOCK_SHUTDOWN_SEND,
+            SHUT_RDWR => SHUTDOWN_BOTH,
+            _ => return Err(EINVAL),
+        };
+        self.vsock.shutdown(id, flags)?;
+        self.flush()
+    }
+
+    pub fn close(&mut self, client: u64, handle: u64, now: Instant) -> Result<(), i32> {
+        self.socket(client, handle)?;
+        let socket = self.sockets.remove(&handle).unwrap();
+        match (socket.kind, socket.port) {
+            (Kind::Listening, Some(port)) => self.vsock.unlisten(port),
+            (Kind::Stream(id), _) => self.vsock.close(id, now),
+            _ => {}
+        }
+        self.flush()
+    }
+
+    /// Close all sockets of `client`, which went away.
+    pub fn close_client(&mut self, client: u64, now: Instant) {
+        let handles: Vec<u64> = self
+            .sockets
+            .iter()
+            .filter(|(_, socket)| socket.client == client)
+            .map(|(&handle, _)| handle)
+            .collect();
+        for handle in handles {
+            let _ = self.close(client, handle, now);
+        }
+    }
+
+    /// Local CID and port; the port is `VMADDR_PORT_ANY` while unbound.
+    pub fn getsockname(&self, client: u64, handle: u64) -> Result<(u32, u32), i32> {
+        let socket = self.socket(client, handle)?;
+        Ok((self.guest_cid(), socket.port.unwrap_or(VMADDR_PORT_ANY)))
+    }
+
+    pub fn getpeername(&self, client: u64, handle: u64) -> Result<(u32, u32), i32> {
+        let id = self.conn(client, handle)?;
+        match self.vsock.state(id) {
+            Some(State::Connected | State::Closing) => Ok((id.peer.cid as u32, id.peer.port)),
+            _ => Err(ENOTCONN),
+        }
+    }
+
+    pub fn setsockopt(
+        &mut self,
+        client: u64,
+        handle: u64,
+        level: i32,
+        name: i32,
+        value: u64,
+    ) -> Result<(), i32> {
+        let socket = self.socket_mut(client, handle)?;
+        match (level, name) {
+            (SOL_SOCKET, SO_RCVBUF) => {
+                let value = value.clamp(MIN_BUF_ALLOC as u64, MAX_BUF_ALLOC as u64);
+                socket.buf_alloc = value as u32;
+                Ok(())
+            }
+            (SOL_SOCKET, SO_TYPE | SO_ERROR) => Err(EINVAL),
+            _ => Err(ENOPROTOOPT),
+        }
+    }
+
+    pub fn getsockopt(&self, client: u64, handle: u64, level: i32, name: i32) -> Result<u64, i32> {
+        let socket = self.socket(client, handle)?;
+        match (level, name) {
+            (SOL_SOCKET, SO_TYPE) => Ok(SOCK_STREAM as u64),
+            (SOL_SOCKET, SO_RCVBUF) => Ok(socket.buf_alloc as u64),
+            (SOL_SOCKET, SO_ERROR) => match socket.kind {
+                Kind::Stream(id) => Ok(self.vsock.error(id).unwrap_or(0) as u64),
+                _ => Ok(0),
+            },
+            _ => Err(ENOPROTOOPT),
+        }
+    }
+
+    /// `POLLIN`, `POLLOUT`, `POLLRDHUP`, `POLLHUP` and `POLLERR` flags of a
+    /// socket. `POLLIN` is set whenever `recv` or `accept` would not fail
+    /// with `EAGAIN`, `POLLOUT` while the peer has credit left.
+    pub fn poll(&self, client: u64, handle: u64) -> Result<u32, i32> {
+        let socket = self.socket(client, handle)?;
+        let (pollin, pollout, pollhup, pollerr, pollrdhup) = (
+            libc::POLLIN as u32,
+            libc::POLLOUT as u32,
+            libc::POLLHUP as u32,
+            libc::POLLERR as u32,
+            libc::POLLRDHUP as u32,
+        );
+        let id = match (&socket.kind, socket.port) {
+            (Kind::Stream(id), _) => *id,
+            (Kind::Listening, Some(port)) if self.vsock.can_accept(port) => return Ok(pollin),
+            _ => return Ok(0),
+        };
+        let mut events = 0;
+        match self.vsock.state(id) {
+            Some(State::Connecting) => {}
+            Some(State::Connected) => {
+                let peer_shutdown = self.vsock.peer_shutdown(id);
+                if self.vsock.available(id) > 0 || peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+                    events |= pollin;
+                }
+                if peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+                    events |= pollrdhup;
+                }
+                if peer_shutdown == SHUTDOWN_BOTH {
+                    events |= pollhup;
+                }
+                if self.vsock.send_capacity(id) > 0 {
+                    events |= pollout;
+                }
+            }
+            Some(State::Closed) => {
+                events |= pollin | pollrdhup | pollhup;
+                if self.vsock.error(id).is_some() {
+                    events |= pollerr;
+                }
+            }
+            Some(State::Closing) | None => events |= pollhup,
+        }
+        Ok(events)
+    }
+}
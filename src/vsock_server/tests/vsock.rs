@@ -0,0 +1,320 @@
+use std::time::Instant;
+
+use libc::{
+    EADDRINUSE, EAFNOSUPPORT, EAGAIN, EALREADY, EBADF, ECONNREFUSED, ECONNRESET, EINPROGRESS,
+    EISCONN, ENOTCONN, EPIPE,
+};
+use virtio_frontend::device::{SimDevice, VsockDevice};
+use virtio_frontend::mmio::MmioTransport;
+use virtio_frontend::vsock::{
+    VsockDriver, VIRTIO_VSOCK_OP_CREDIT_REQUEST, VIRTIO_VSOCK_OP_RST, VIRTIO_VSOCK_OP_RW,
+    VIRTIO_VSOCK_SHUTDOWN_SEND, VMADDR_CID_HOST,
+};
+use vsock_server::conn::{CLOSE_TIMEOUT, DEFAULT_BUF_ALLOC, SHUTDOWN_BOTH};
+use vsock_server::proto::{AF_VSOCK, SHUT_WR, SOCK_STREAM, VMADDR_CID_ANY, VMADDR_PORT_ANY};
+use vsock_server::{Sockets, State, Vsock, VsockAddr};
+
+const GUEST: u64 = 3;
+const CLIENT: u64 = 1;
+const OTHER: u64 = 2;
+
+type SimSockets = Sockets<SimDevice<VsockDevice>>;
+
+/// Hand packets between two endpoints until both are quiet.
+fn link(a: &mut Vsock, b: &mut Vsock) {
+    loop {
+        let mut moved = false;
+        while let Some(pkt) = a.take_packet() {
+            b.handle(pkt);
+            moved = true;
+        }
+        while let Some(pkt) = b.take_packet() {
+            a.handle(pkt);
+            moved = true;
+        }
+        if !moved {
+            break;
+        }
+    }
+}
+
+/// A guest connected to a host listening on port 1234 with a receive
+/// buffer of `host_buf` bytes. Returns the guest's and the host's side.
+fn connected(host_buf: u32) -> (Vsock, Vsock, vsock_server::ConnId, vsock_server::ConnId) {
+    let mut guest = Vsock::new(GUEST);
+    let mut host = Vsock::new(VMADDR_CID_HOST);
+    host.listen(1234, 4, host_buf).unwrap();
+    let g = guest
+        .connect(
+            50000,
+            VsockAddr::new(VMADDR_CID_HOST, 1234),
+            DEFAULT_BUF_ALLOC,
+        )
+        .unwrap();
+    assert_eq!(guest.state(g), Some(State::Connecting));
+    link(&mut guest, &mut host);
+    assert_eq!(guest.state(g), Some(State::Connected));
+    let h = host.accept(1234).unwrap();
+    assert_eq!(h.peer, VsockAddr::new(GUEST, 50000));
+    (guest, host, g, h)
+}
+
+#[test]
+fn handshake_transfer_and_eof() {
+    let (mut guest, mut host, g, h) = connected(DEFAULT_BUF_ALLOC);
+    assert_eq!(host.accept(1234), Err(EAGAIN));
+
+    assert_eq!(guest.send_data(g, b"hello host"), Ok(10));
+    link(&mut guest, &mut host);
+    let mut buf = [0u8; 32];
+    assert_eq!(host.recv(h, &mut buf[..5], true), Ok(5));
+    assert_eq!(host.recv(h, &mut buf, false), Ok(10));
+    assert_eq!(&buf[..10], b"hello host");
+    assert_eq!(host.recv(h, &mut buf, false), Err(EAGAIN));
+
+    // Half a connection stays open after a shutdown.
+    guest.shutdown(g, VIRTIO_VSOCK_SHUTDOWN_SEND).unwrap();
+    assert_eq!(guest.send_data(g, b"late"), Err(EPIPE));
+    host.send_data(h, b"reply").unwrap();
+    link(&mut guest, &mut host);
+    assert_eq!(host.recv(h, &mut buf, false), Ok(0));
+    assert_eq!(guest.recv(g, &mut buf, false), Ok(5));
+
+    // Closing ends with the peer's reset and removes both sides.
+    guest.close(g, Instant::now());
+    assert_eq!(guest.state(g), Some(State::Closing));
+    link(&mut guest, &mut host);
+    assert_eq!(guest.state(g), None);
+    assert_eq!(host.state(h), Some(State::Closed));
+    assert_eq!(host.error(h), None);
+    assert_eq!(host.peer_shutdown(h), SHUTDOWN_BOTH);
+    host.close(h, Instant::now());
+    assert_eq!(host.state(h), None);
+}
+
+#[test]
+fn credit_limits_the_sender() {
+    let (mut guest, mut host, g, h) = connected(8192);
+    assert_eq!(guest.send_capacity(g), 8192);
+
+    // Only what the peer's buffer holds goes out, in packets of at most
+    // MAX_PAYLOAD bytes.
+    let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+    assert_eq!(guest.send_data(g, &data), Ok(8192));
+    let mut rw = 0;
+    while let Some(pkt) = guest.take_packet() {
+        assert_eq!(pkt.hdr.op, VIRTIO_VSOCK_OP_RW);
+        rw += 1;
+        host.handle(pkt);
+    }
+    assert_eq!(rw, 2);
+    assert_eq!(host.available(h), 8192);
+
+    // Out of credit the sender asks for more, once.
+    assert_eq!(guest.send_data(g, &data[8192..]), Err(EAGAIN));
+    assert_eq!(guest.send_data(g, &data[8192..]), Err(EAGAIN));
+    assert_eq!(
+        guest.peek_packet().map(|p| p.hdr.op),
+        Some(VIRTIO_VSOCK_OP_CREDIT_REQUEST)
+    );
+    link(&mut guest, &mut host);
+    assert_eq!(guest.send_capacity(g), 0);
+
+    // Reading less than half the buffer does not announce anything,
+    // reading half of it does.
+    let mut buf = vec![0u8; 8192];
+    assert_eq!(host.recv(h, &mut buf[..1000], false), Ok(1000));
+    assert!(host.peek_packet().is_none());
+    assert_eq!(host.recv(h, &mut buf[1000..4096], false), Ok(3096));
+    link(&mut guest, &mut host);
+    assert_eq!(guest.send_capacity(g), 4096);
+    assert_eq!(guest.send_data(g, &data[8192..]), Ok(4096));
+    link(&mut guest, &mut host);
+    assert_eq!(host.recv(h, &mut buf, false), Ok(8192));
+    assert_eq!(buf[..4096], data[4096..8192]);
+    assert_eq!(buf[4096..], data[8192..12288]);
+}
+
+#[test]
+fn refused_reset_and_expired() {
+    let mut guest = Vsock::new(GUEST);
+    let mut host = Vsock::new(VMADDR_CID_HOST);
+
+    // Nobody listens.
+    let g = guest
+        .connect(50000, VsockAddr::new(VMADDR_CID_HOST, 9), DEFAULT_BUF_ALLOC)
+        .unwrap();
+    link(&mut guest, &mut host);
+    assert_eq!(guest.state(g), Some(State::Closed));
+    assert_eq!(guest.error(g), Some(ECONNREFUSED));
+    let mut buf = [0u8; 4];
+    assert_eq!(guest.recv(g, &mut buf, false), Err(ECONNREFUSED));
+
+    // A connection the peer forgot is reset.
+    let (mut guest, mut host, g, h) = connected(DEFAULT_BUF_ALLOC);
+    host.reset(VMADDR_CID_HOST);
+    assert_eq!(host.error(h), Some(ECONNRESET));
+    guest.send_data(g, b"anyone?").unwrap();
+    link(&mut guest, &mut host);
+    assert_eq!(guest.state(g), Some(State::Closed));
+    assert_eq!(guest.error(g), Some(ECONNRESET));
+
+    // A peer that never answers a close is reset after the timeout.
+    let (mut guest, _host, g, _) = connected(DEFAULT_BUF_ALLOC);
+    let start = Instant::now();
+    guest.close(g, start);
+    guest.take_packet().unwrap();
+    guest.expire(start + CLOSE_TIMEOUT / 2);
+    assert_eq!(guest.state(g), Some(State::Closing));
+    guest.expire(start + CLOSE_TIMEOUT);
+    assert_eq!(guest.state(g), None);
+    assert_eq!(
+        guest.take_packet().map(|p| p.hdr.op),
+        Some(VIRTIO_VSOCK_OP_RST)
+    );
+}
+
+/// Sockets of a guest with CID 3 on a simulated device.
+fn sockets() -> SimSockets {
+    // SAFETY: the driver's queues live in the driver owning the device.
+    let device = unsafe { SimDevice::new(VsockDevice::new(GUEST)) };
+    let driver = VsockDriver::new(MmioTransport::new(device).unwrap()).unwrap();
+    Sockets::new(driver)
+}
+
+/// Let the device, the host and the server exchange packets until all
+/// are idle.
+fn settle(sockets: &mut SimSockets, host: &mut Vsock) {
+    for _ in 0..8 {
+        let sim = sockets.driver_mut().transport_mut().regs_mut();
+        sim.poll();
+        while let Some(pkt) = sim.model_mut().host_recv() {
+            host.handle(pkt);
+        }
+        while let Some(pkt) = host.take_packet() {
+            sim.model_mut().host_send(pkt);
+        }
+        sim.poll();
+        sockets.interrupt(Instant::now()).unwrap();
+    }
+}
+
+#[test]
+fn sockets_through_the_device() {
+    let mut sockets = sockets();
+    let mut host = Vsock::new(VMADDR_CID_HOST);
+    host.listen(1234, 4, DEFAULT_BUF_ALLOC).unwrap();
+    assert_eq!(sockets.guest_cid(), GUEST as u32);
+    assert_eq!(
+        sockets.socket_open(CLIENT, 2, SOCK_STREAM, 0),
+        Err(EAFNOSUPPORT)
+    );
+
+    // Guest to host.
+    let s = sockets
+        .socket_open(CLIENT, AF_VSOCK, SOCK_STREAM, 0)
+        .unwrap();
+    assert_eq!(
+        sockets.getsockname(CLIENT, s),
+        Ok((GUEST as u32, VMADDR_PORT_ANY))
+    );
+    assert_eq!(sockets.connect(CLIENT, s, 2, 1234), Err(EINPROGRESS));
+    assert_eq!(sockets.connect(CLIENT, s, 2, 1234), Err(EALREADY));
+    assert_eq!(sockets.recv(CLIENT, s, &mut [], 0), Err(EAGAIN));
+    assert_eq!(sockets.getpeername(CLIENT, s), Err(ENOTCONN));
+    settle(&mut sockets, &mut host);
+    assert_eq!(sockets.recv(CLIENT, s, &mut [], 0), Ok(0));
+    assert_eq!(sockets.connect(CLIENT, s, 2, 1234), Err(EISCONN));
+    assert_eq!(sockets.getpeername(CLIENT, s), Ok((2, 1234)));
+    assert_eq!(sockets.poll(CLIENT, s), Ok(libc::POLLOUT as u32));
+    assert_eq!(sockets.send(OTHER, s, b"x"), Err(EBADF));
+
+    let h = host.accept(1234).unwrap();
+    assert_eq!(sockets.send(CLIENT, s, b"ping"), Ok(4));
+    settle(&mut sockets, &mut host);
+    let mut buf = [0u8; 16];
+    assert_eq!(host.recv(h, &mut buf, false), Ok(4));
+    assert_eq!(&buf[..4], b"ping");
+    host.send_data(h, b"pong").unwrap();
+    settle(&mut sockets, &mut host);
+    assert_eq!(
+        sockets.poll(CLIENT, s),
+        Ok((libc::POLLIN | libc::POLLOUT) as u32)
+    );
+    assert_eq!(sockets.recv(CLIENT, s, &mut buf, 0), Ok(4));
+    assert_eq!(&buf[..4], b"pong");
+    assert_eq!(sockets.recv(CLIENT, s, &mut buf, 0), Err(EAGAIN));
+    sockets.shutdown(CLIENT, s, SHUT_WR).unwrap();
+    settle(&mut sockets, &mut host);
+    assert_eq!(host.recv(h, &mut buf, false), Ok(0));
+
+    // Host to guest.
+    let l = sockets
+        .socket_open(CLIENT, AF_VSOCK, SOCK_STREAM, 0)
+        .unwrap();
+    sockets.bind(CLIENT, l, VMADDR_CID_ANY, 80).unwrap();
+    let other = sockets
+        .socket_open(OTHER, AF_VSOCK, SOCK_STREAM, 0)
+        .unwrap();
+    assert_eq!(
+        sockets.bind(OTHER, other, VMADDR_CID_ANY, 80),
+        Err(EADDRINUSE)
+    );
+    sockets.listen(CLIENT, l, 8).unwrap();
+    assert_eq!(sockets.accept(CLIENT, l), Err(EAGAIN));
+    let hc = host
+        .connect(60000, VsockAddr::new(GUEST, 80), DEFAULT_BUF_ALLOC)
+        .unwrap();
+    settle(&mut sockets, &mut host);
+    assert_eq!(host.state(hc), Some(State::Connected));
+    assert_eq!(sockets.poll(CLIENT, l), Ok(libc::POLLIN as u32));
+    let (a, peer) = sockets.accept(CLIENT, l).unwrap();
+    assert_eq!(peer, (2, 60000));
+    host.send_data(hc, b"hi guest").unwrap();
+    settle(&mut sockets, &mut host);
+    assert_eq!(sockets.recv(CLIENT, a, &mut buf, 0), Ok(8));
+
+    // Closing the guest side ends the host's connection.
+    sockets.close(CLIENT, a, Instant::now()).unwrap();
+    settle(&mut sockets, &mut host);
+    assert_eq!(host.state(hc), Some(State::Closed));
+    assert_eq!(host.recv(hc, &mut buf, false), Ok(0));
+
+    // A transport reset ends all connections.
+    let sim = sockets.driver_mut().transport_mut().regs_mut();
+    sim.model_mut().transport_reset(7);
+    sim.refresh_config();
+    settle(&mut sockets, &mut host);
+    assert_eq!(sockets.guest_cid(), 7);
+    assert_eq!(sockets.recv(CLIENT, s, &mut buf, 0), Err(ECONNRESET));
+    let events = sockets.poll(CLIENT, s).unwrap();
+    assert_ne!(events & libc::POLLERR as u32, 0);
+}
+
+#[test]
+fn closing_a_client_ends_its_sockets() {
+    let mut sockets = sockets();
+    let mut host = Vsock::new(VMADDR_CID_HOST);
+    host.listen(1234, 4, DEFAULT_BUF_ALLOC).unwrap();
+    let s = sockets
+        .socket_open(CLIENT, AF_VSOCK, SOCK_STREAM, 0)
+        .unwrap();
+    assert_eq!(sockets.connect(CLIENT, s, 2, 1234), Err(EINPROGRESS));
+    let l = sockets
+        .socket_open(CLIENT, AF_VSOCK, SOCK_STREAM, 0)
+        .unwrap();
+    sockets.bind(CLIENT, l, VMADDR_CID_ANY, 80).unwrap();
+    sockets.listen(CLIENT, l, 8).unwrap();
+    settle(&mut sockets, &mut host);
+    let h = host.accept(1234).unwrap();
+
+    sockets.close_client(CLIENT, Instant::now());
+    settle(&mut sockets, &mut host);
+    let mut buf = [0u8; 16];
+    assert_eq!(host.recv(h, &mut buf, false), Ok(0));
+    assert_eq!(sockets.poll(CLIENT, s), Err(EBADF));
+    let other = sockets
+        .socket_open(OTHER, AF_VSOCK, SOCK_STREAM, 0)
+        .unwrap();
+    sockets.bind(OTHER, other, VMADDR_CID_ANY, 80).unwrap();
+}
@@ -0,0 +1,19 @@
+//! Virtual Ethernet switch, independent of L4Re.
+//!
+//! A [`Switch`] forwards frames between [`Port`]s: it learns which port a
+//! MAC address lives behind, floods broadcast, multicast and unknown unicast
+//! frames, separates VLANs and counts traffic per port. The `vswitch` binary
+//! (feature `l4re`) connects clients through shared-memory virtqueues, see
+//! [`vring`], with the virtio-net device as uplink and serves [`proto`]
+//! over IPC. On the host, tests switch frames between the in-memory ports
+//! of [`port`].
+
+pub mod port;
+pub mod proto;
+pub mod switch;
+pub mod vlan;
+pub mod vring;
+
+pub use port::{MemPort, Port};
+pub use switch::{FdbEntry, PortId, PortStats, Switch};
+pub use vlan::VlanMode;
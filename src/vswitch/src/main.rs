@@ -0,0 +1,477 @@
+//! Virtual Ethernet switch service.
+//!
+//! Clients attach ports through the `vswitch` gate and exchange frames with
+//! the switch over shared-memory virtqueues, see [`vswitch::vring`] and
+//! [`proto`] for the IPC protocol. The virtio-net device is the uplink,
+//! port [`proto::UPLINK`]; its VLAN mode comes from `VSWITCH_UPLINK_VLAN`
+//! (`access:<vid>`, `trunk` or `trunk:<vid>,...`, default `access:1`).
+//! Without a device the switch only connects its clients and the uplink
+//! drops everything sent to it.
+//!
+//! Every client talks to the switch through a session gate of its own,
+//! created on request, so the switch can tell clients apart and detach
+//! their ports once they are gone.
+//!
+//! The switch owns the virtio-net device, so net_server cannot run on it
+//! at the same time.
+
+use core::ffi::c_void;
+use l4::sys::{
+    l4_cap_idx_t, l4_default_caps_t, l4_factory_create_gate, l4_factory_create_irq, l4_ipc_error,
+    l4_irq_trigger, l4_irq_unmask, l4_is_invalid_cap, l4_msgtag, l4_msgtag_label, l4_msgtag_t,
+    l4_obj_fpage, l4_sndfpage_add, l4_task_cap_has_child, l4_task_delete_obj,
+    l4_task_release_cap, l4_timeout, l4_timeout_from_us, l4_timeout_t, l4_utcb, l4_utcb_mr,
+    l4re_util_cap_alloc, l4re_util_cap_free, L4_cap_fpage_rights,
+};
+use l4re::sys::{l4re_env, l4re_env_get_cap, l4re_ma_alloc, l4re_rm_attach, l4re_rm_detach};
+use smoltcp::time::Instant;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use vswitch::proto;
+use vswitch::vring::{VringLayout, VringPort};
+use vswitch::{Port, Switch, VlanMode};
+use virtio_net::{GateTransport, VirtioNet};
+
+/// Label of client requests arriving through the `vswitch` gate.
+const GATE_LABEL: u64 = 0b1111_0000;
+/// Label of virtio-net interrupts.
+const IRQ_LABEL: u64 = 0b1_0000_0000;
+/// Labels of session gates: this bit plus the serial number of the session
+/// in the bits above the rights, so the label of a closed session is never
+/// reused.
+const SESSION_LABEL: u64 = 1 << 32;
+/// The two least significant label bits carry the rights of the sender's
+/// capability.
+const LABEL_MASK: u64 = !0b11;
+
+/// How often sessions are checked for clients that went away.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Monotonic time since server start.
+struct Clock {
+    start: std::time::Instant,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Self { start: std::time::Instant::now() }
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_micros(self.start.elapsed().as_micros() as i64)
+    }
+}
+
+/// A client port and the capabilities backing it.
+struct ClientPort {
+    vring: VringPort,
+    /// Label of the session that attached the port.
+    owner: u64,
+    base: *mut c_void,
+    ds: l4_cap_idx_t,
+    irq: l4_cap_idx_t,
+}
+
+impl Drop for ClientPort {
+    fn drop(&mut self) {
+        unsafe {
+            l4re_rm_detach(self.base);
+            for cap in [self.ds, self.irq] {
+                let _ = l4_task_release_cap(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, cap);
+                l4re_util_cap_free(cap);
+            }
+        }
+    }
+}
+
+/// The ports of the switch.
+enum SwitchPort {
+    Uplink(VirtioNet<GateTransport>),
+    /// Stands in for a missing uplink.
+    Disconnected,
+    Client(ClientPort),
+}
+
+impl Port for SwitchPort {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        match self {
+            Self::Uplink(net) => {
+                if !net.link_up() {
+                    return None;
+                }
+                let mut buf = [0u8; virtio_net::FRAME_SIZE];
+                let len = net.receive_frame(&mut buf).ok()?;
+                Some(buf[..len].to_vec())
+            }
+            Self::Disconnected => None,
+            Self::Client(port) => port.vring.receive(),
+        }
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> bool {
+        match self {
+            Self::Uplink(net) => net.link_up() && net.send_frame(frame).is_ok(),
+            Self::Disconnected => false,
+            Self::Client(port) => port.vring.transmit(frame),
+        }
+    }
+}
+
+/// A client's own gate to the switch, see [`proto`].
+struct Session {
+    gate: l4_cap_idx_t,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            // Delete the gate rather than just dropping our capability, so
+            // the client's copy stops reaching the switch.
+            let task = l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t;
+            let _ = l4_task_delete_obj(task, self.gate);
+            l4re_util_cap_free(self.gate);
+        }
+    }
+}
+
+/// Open sessions by the label of their gate, which also marks their ports.
+struct Sessions {
+    open: HashMap<u64, Session>,
+    /// Serial number of the next session, part of its gate label.
+    next: u64,
+    /// When to look for sessions whose client went away.
+    sweep_at: std::time::Instant,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        Self { open: HashMap::new(), next: 0, sweep_at: std::time::Instant::now() }
+    }
+
+    /// Create a session gate. Returns the capability to map to the client.
+    unsafe fn create(&mut self, now: std::time::Instant) -> Result<l4_cap_idx_t, i32> {
+        let label = SESSION_LABEL | (self.next << 2);
+        let gate = l4re_util_cap_alloc();
+        if l4_is_invalid_cap(gate) {
+            return Err(libc::ENOMEM);
+        }
+        let env = &*l4re_env();
+        let tag = l4_factory_create_gate(env.factory, gate, env.main_thread, label);
+        if l4_ipc_error(tag, l4_utcb()) != 0 {
+            l4re_util_cap_free(gate);
+            return Err(libc::ENOMEM);
+        }
+        if self.open.is_empty() {
+            self.sweep_at = now + SWEEP_INTERVAL;
+        }
+        self.next += 1;
+        self.open.insert(label, Session { gate });
+        Ok(gate)
+    }
+
+    /// Close the session `client` and detach its ports.
+    fn close(&mut self, switch: &mut Switch<SwitchPort>, client: u64) -> Result<(), i32> {
+        self.open.remove(&client).ok_or(libc::EPERM)?;
+        for id in switch.port_ids() {
+            if matches!(switch.port(id), Some(SwitchPort::Client(port)) if port.owner == client) {
+                switch.remove_port(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the sessions whose gate is no longer mapped to any task, once
+    /// the sweep interval passed.
+    unsafe fn sweep(&mut self, switch: &mut Switch<SwitchPort>, now: std::time::Instant) {
+        if self.open.is_empty() || now < self.sweep_at {
+            return;
+        }
+        self.sweep_at = now + SWEEP_INTERVAL;
+        let task = l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t;
+        // Checking and deleting gates uses the message registers, which may
+        // hold a request.
+        let saved = (*l4_utcb_mr()).mr;
+        let gone: Vec<u64> = self
+            .open
+            .iter()
+            .filter(|(_, session)| {
+                let tag = l4_task_cap_has_child(task, session.gate);
+                l4_ipc_error(tag, l4_utcb()) == 0 && l4_msgtag_label(tag) == 0
+            })
+            .map(|(&label, _)| label)
+            .collect();
+        for client in gone {
+            let _ = self.close(switch, client);
+        }
+        (*l4_utcb_mr()).mr = saved;
+    }
+
+    /// Receive timeout for the next wait: wake up for the next sweep, or
+    /// never without sessions.
+    fn wait_timeout(&self, now: std::time::Instant) -> l4_timeout_t {
+        if self.open.is_empty() {
+            return l4_timeout_t { raw: 0 };
+        }
+        let delay = self.sweep_at.saturating_duration_since(now).as_micros();
+        // Never time out sending the reply.
+        l4_timeout(0, l4_timeout_from_us(delay.min(u32::MAX as u128 - 1) as u32))
+    }
+}
+
+fn encode_errno(err: i32) -> u64 {
+    (-(err as i64)) as u64
+}
+
+/// Allocate and map the dataspace of a client port and create its
+/// notification IRQ.
+unsafe fn alloc_port(layout: VringLayout, owner: u64) -> Result<ClientPort, i32> {
+    let size = l4::sys::round_page(layout.total) as usize;
+    let ds = l4re_util_cap_alloc();
+    if l4_is_invalid_cap(ds) {
+        return Err(libc::ENOMEM);
+    }
+    if l4re_ma_alloc(size, ds, 0) < 0 {
+        l4re_util_cap_free(ds);
+        return Err(libc::ENOMEM);
+    }
+    let mut base: *mut c_void = core::ptr::null_mut();
+    let flags = l4re::sys::l4re_rm_flags_values::L4RE_RM_F_SEARCH_ADDR as u64
+        | l4re::sys::l4re_rm_flags_values::L4RE_RM_F_RW as u64;
+    if l4re_rm_attach(&mut base, size, flags, ds, 0, l4::sys::L4_PAGESHIFT as u8) < 0 {
+        let _ = l4_task_release_cap(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, ds);
+        l4re_util_cap_free(ds);
+        return Err(libc::ENOMEM);
+    }
+    let irq = l4re_util_cap_alloc();
+    if l4_is_invalid_cap(irq)
+        || l4_ipc_error(l4_factory_create_irq((*l4re_env()).factory, irq), l4_utcb()) != 0
+    {
+        l4re_rm_detach(base);
+        let _ = l4_task_release_cap(l4_default_caps_t::L4_BASE_TASK_CAP as l4_cap_idx_t, ds);
+        l4re_util_cap_free(ds);
+        if !l4_is_invalid_cap(irq) {
+            l4re_util_cap_free(irq);
+        }
+        return Err(libc::ENOMEM);
+    }
+    // Fresh memory from the allocator is zeroed.
+    let vring = VringPort::new(base as *mut u8, layout);
+    Ok(ClientPort { vring, owner, base, ds, irq })
+}
+
+/// The client port `handle` if it belongs to `client`.
+fn own_port(switch: &mut Switch<SwitchPort>, client: u64, handle: u64) -> Result<usize, i32> {
+    let id = usize::try_from(handle).map_err(|_| libc::EBADF)?;
+    match switch.port(id) {
+        Some(SwitchPort::Client(port)) if port.owner == client => Ok(id),
+        _ => Err(libc::EBADF),
+    }
+}
+
+/// Open a session for a request through `vswitch`, or close the session
+/// whose gate the request came through. Returns the tag of the reply;
+/// opening maps the session gate.
+unsafe fn dispatch_session(
+    sessions: &mut Sessions,
+    switch: &mut Switch<SwitchPort>,
+    client: u64,
+) -> Result<l4_msgtag_t, i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    if mr[0] == proto::OP_SESSION_CLOSE {
+        sessions.close(switch, client)?;
+        mr[0] = 0;
+        return Ok(l4_msgtag(0, 1, 0, 0));
+    }
+    if client != GATE_LABEL {
+        return Err(libc::EINVAL);
+    }
+    let gate = sessions.create(std::time::Instant::now())?;
+    mr[0] = 0;
+    let mut tag = l4_msgtag(0, 1, 0, 0);
+    let rights = L4_cap_fpage_rights::L4_CAP_FPAGE_RWS as u8;
+    l4_sndfpage_add(l4_obj_fpage(gate, 0, rights), 0, &mut tag);
+    Ok(tag)
+}
+
+/// Handle one request. Returns the reply tag; MR0 holds the result.
+unsafe fn dispatch(
+    switch: &mut Switch<SwitchPort>,
+    sessions: &Sessions,
+    client: u64,
+) -> Result<l4_msgtag_t, i32> {
+    let mr = &mut (*l4_utcb_mr()).mr;
+    // Ports belong to sessions only, see `proto`.
+    let port_op = matches!(
+        mr[0],
+        proto::OP_ATTACH | proto::OP_KICK | proto::OP_DETACH | proto::OP_SET_VLAN
+    );
+    if port_op && !sessions.open.contains_key(&client) {
+        return Err(libc::EPERM);
+    }
+    match mr[0] {
+        proto::OP_ATTACH => {
+            let size = u16::try_from(mr[1]).map_err(|_| libc::EINVAL)?;
+            let layout = VringLayout::new(size).ok_or(libc::EINVAL)?;
+            let vlan = proto::vlan_mode(mr[2]).ok_or(libc::EINVAL)?;
+            let port = alloc_port(layout, client)?;
+            let (ds, irq) = (port.ds, port.irq);
+            mr[0] = switch.add_port(SwitchPort::Client(port), vlan) as u64;
+            mr[1] = layout.total as u64;
+
+            let rights = L4_cap_fpage_rights::L4_CAP_FPAGE_RWS as u8;
+            let mut tag = l4_msgtag(0, 2, 0, 0);
+            l4_sndfpage_add(l4_obj_fpage(ds, 0, rights), 0, &mut tag);
+            l4_sndfpage_add(l4_obj_fpage(irq, 0, rights), 0, &mut tag);
+            Ok(tag)
+        }
+        proto::OP_KICK => {
+            own_port(switch, client, mr[1])?;
+            // The frames are forwarded when the switch is polled after the
+            // request.
+            mr[0] = 0;
+            Ok(l4_msgtag(0, 1, 0, 0))
+        }
+        proto::OP_DETACH => {
+            let id = own_port(switch, client, mr[1])?;
+            switch.remove_port(id);
+            mr[0] = 0;
+            Ok(l4_msgtag(0, 1, 0, 0))
+        }
+        proto::OP_STATS => {
+            let id = usize::try_from(mr[1]).map_err(|_| libc::EBADF)?;
+            let stats = switch.stats(id).ok_or(libc::EBADF)?;
+            mr[0] = 0;
+            mr[1] = stats.rx_frames;
+            mr[2] = stats.rx_bytes;
+            mr[3] = stats.tx_frames;
+            mr[4] = stats.tx_bytes;
+            mr[5] = stats.rx_dropped;
+            mr[6] = stats.tx_dropped;
+            Ok(l4_msgtag(0, 7, 0, 0))
+        }
+        proto::OP_FDB => {
+            let fdb = switch.fdb();
+            let entry = usize::try_from(mr[1]).ok().and_then(|i| fdb.get(i));
+            let entry = entry.ok_or(libc::ENOENT)?;
+            mr[0] = fdb.len() as u64;
+            mr[1] = proto::mac_word(entry.mac);
+            mr[2] = entry.vlan as u64;
+            mr[3] = entry.port as u64;
+            Ok(l4_msgtag(0, 4, 0, 0))
+        }
+        proto::OP_SET_VLAN => {
+            let id = own_port(switch, client, mr[1])?;
+            let vlan = proto::vlan_mode(mr[2]).ok_or(libc::EINVAL)?;
+            switch.set_vlan(id, vlan);
+            mr[0] = 0;
+            Ok(l4_msgtag(0, 1, 0, 0))
+        }
+        _ => Err(libc::ENOSYS),
+    }
+}
+
+/// Forward pending frames and notify the clients that received some.
+unsafe fn forward(switch: &mut Switch<SwitchPort>, clock: &Clock) {
+    switch.poll(clock.now());
+    for id in switch.port_ids() {
+        if let Some(SwitchPort::Client(port)) = switch.port_mut(id) {
+            if port.vring.take_notify() {
+                let _ = l4_irq_trigger(port.irq);
+            }
+        }
+    }
+}
+
+unsafe fn bind(cap: l4_cap_idx_t, label: u64) -> bool {
+    l4_ipc_error(
+        l4::l4_rcv_ep_bind_thread(cap, (*l4re_env()).main_thread, label),
+        l4_utcb(),
+    ) == 0
+}
+
+fn main() {
+    unsafe { run() }
+}
+
+/// Unsafe portion of the server. Interacts directly with L4 system calls.
+unsafe fn run() {
+    let gate = l4re_env_get_cap("vswitch").expect("IPC gate 'vswitch' not provided");
+    if !bind(gate, GATE_LABEL) {
+        panic!("failed to bind IPC gate");
+    }
+
+    let uplink_vlan = match std::env::var("VSWITCH_UPLINK_VLAN") {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            println!("vswitch: invalid VSWITCH_UPLINK_VLAN {value:?}, using access:1");
+            VlanMode::default()
+        }),
+        Err(_) => VlanMode::default(),
+    };
+
+    // The uplink is the first port, so it gets handle `proto::UPLINK`.
+    let mut switch = Switch::new();
+    let irq = match VirtioNet::new() {
+        Some(net) => {
+            let irq = net.irq();
+            if !bind(irq, IRQ_LABEL) {
+                panic!("failed to bind virtio-net IRQ");
+            }
+            let _ = l4_irq_unmask(irq);
+            switch.add_port(SwitchPort::Uplink(net), uplink_vlan);
+            Some(irq)
+        }
+        None => {
+            println!("vswitch: no virtio-net device, running without uplink");
+            switch.add_port(SwitchPort::Disconnected, uplink_vlan);
+            None
+        }
+    };
+
+    let clock = Clock::new();
+    let mut sessions = Sessions::new();
+    println!("virtual switch ready");
+
+    // Wait for client requests and uplink interrupts. Every message is
+    // followed by a round of forwarding. While sessions are open, the wait
+    // times out to check them now and then.
+    let mut label = 0u64;
+    let timeout = sessions.wait_timeout(std::time::Instant::now());
+    let mut tag = l4::l4_ipc_wait(l4_utcb(), &mut label, timeout);
+    loop {
+        let failed = l4_ipc_error(tag, l4_utcb()) != 0;
+        let interrupt = !failed && label & LABEL_MASK == IRQ_LABEL;
+        if let Some(irq) = irq.filter(|_| interrupt) {
+            let _ = l4_irq_unmask(irq);
+            if let Some(SwitchPort::Uplink(net)) = switch.port_mut(proto::UPLINK as usize) {
+                net.interrupt();
+            }
+        }
+        sessions.sweep(&mut switch, std::time::Instant::now());
+        if failed || interrupt {
+            forward(&mut switch, &clock);
+            let timeout = sessions.wait_timeout(std::time::Instant::now());
+            tag = l4::l4_ipc_wait(l4_utcb(), &mut label, timeout);
+            continue;
+        }
+
+        let client = label & LABEL_MASK;
+        let op = (*l4_utcb_mr()).mr[0];
+        let reply = if op == proto::OP_SESSION || op == proto::OP_SESSION_CLOSE {
+            dispatch_session(&mut sessions, &mut switch, client)
+        } else {
+            dispatch(&mut switch, &sessions, client)
+        };
+        let reply = reply.unwrap_or_else(|err| {
+            (*l4_utcb_mr()).mr[0] = encode_errno(err);
+            l4_msgtag(0, 1, 0, 0)
+        });
+        // Triggering IRQs uses the message registers, keep the reply aside.
+        let saved = (*l4_utcb_mr()).mr;
+        forward(&mut switch, &clock);
+        (*l4_utcb_mr()).mr = saved;
+
+        let timeout = sessions.wait_timeout(std::time::Instant::now());
+        tag = l4::l4_ipc_reply_and_wait(l4_utcb(), reply, &mut label, timeout);
+    }
+}
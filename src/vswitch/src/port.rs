@@ -0,0 +1,66 @@
+//! The ports a switch forwards between.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Frames queued in one direction of a [`MemPort`]. Further frames are
+/// dropped, like on a congested link.
+pub const MEM_PORT_FRAMES: usize = 64;
+
+/// One port of a [`crate::Switch`], seen from the switch.
+pub trait Port {
+    /// The next frame the attached station sent, if any.
+    fn receive(&mut self) -> Option<Vec<u8>>;
+
+    /// Hand `frame` to the attached station. Returns `false` if the port
+    /// had no room for it; the switch counts the frame as dropped.
+    fn transmit(&mut self, frame: &[u8]) -> bool;
+}
+
+type Queue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// An in-memory port. The switch owns one end, the station the other.
+pub struct MemPort {
+    rx: Queue,
+    tx: Queue,
+}
+
+impl MemPort {
+    /// The switch end and the station end of a new port.
+    pub fn pair() -> (MemPort, MemPort) {
+        let a = Queue::default();
+        let b = Queue::default();
+        (
+            MemPort {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            MemPort { rx: b, tx: a },
+        )
+    }
+
+    /// Send `frame` to the other end.
+    pub fn send(&self, frame: &[u8]) -> bool {
+        let mut queue = self.tx.lock().unwrap();
+        if queue.len() >= MEM_PORT_FRAMES {
+            return false;
+        }
+        queue.push_back(frame.to_vec());
+        true
+    }
+
+    /// The next frame from the other end.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.rx.lock().unwrap().pop_front()
+    }
+}
+
+impl Port for MemPort {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.recv()
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> bool {
+        self.send(frame)
+    }
+}
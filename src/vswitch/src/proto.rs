@@ -0,0 +1,78 @@
+//! Message register layout of the switch protocol.
+//!
+//! ```text
+//! MR0: operation
+//!      0 = attach    MR1: queue size, 0 = default, MR2: VLAN mode
+//!                    Reply: MR0 = port handle, MR1 = size of the port
+//!                    region; the region's dataspace and the notification
+//!                    IRQ are mapped to the client
+//!      1 = kick      MR1: handle
+//!      2 = detach    MR1: handle
+//!      3 = stats     MR1: handle
+//!                    Reply: MR1 = frames received, MR2 = bytes received,
+//!                    MR3 = frames sent, MR4 = bytes sent, MR5 = received
+//!                    frames dropped, MR6 = frames dropped for lack of room
+//!      4 = fdb       MR1: index
+//!                    Reply: MR0 = number of entries, MR1 = MAC address,
+//!                    MR2 = VLAN, MR3 = port handle
+//!      5 = set_vlan  MR1: handle, MR2: VLAN mode
+//!      6 = session   Reply: maps the session gate
+//!      7 = session_close
+//! ```
+//!
+//! Ports belong to sessions: a client sends `session` to the `vswitch`
+//! gate and receives a gate of its own. Attaching, kicking, detaching and
+//! reconfiguring ports only go through a session gate, and only for the
+//! session's own ports; through `vswitch` itself they fail with `-EPERM`.
+//! `session_close`, sent through the session gate, detaches all of the
+//! session's ports, as does the client going away.
+//!
+//! A port region is laid out as described in [`crate::vring`]; the queue
+//! size must be a power of two up to [`crate::vring::MAX_QUEUE_SIZE`].
+//! After posting frames on its TX queue the client kicks the port. The
+//! switch triggers the IRQ whenever it put frames on the RX queue.
+//!
+//! The VLAN mode is 0 for an access port of [`NATIVE_VLAN`], a VLAN id for
+//! an access port of that VLAN, or [`VLAN_TRUNK`] for a trunk of all VLANs;
+//! see [`crate::vlan`]. Statistics are counted by the switch: frames
+//! received are those the port's station sent.
+//!
+//! Handles are switch port ids. The uplink is port [`UPLINK`]; its
+//! statistics can be read like those of any port. Statistics and the FDB
+//! can be read through any gate. Listing the FDB past the last entry fails
+//! with `-ENOENT`, MAC addresses occupy the low 48 bits of MR1 in network
+//! byte order.
+//!
+//! `MR0` of a reply is 0 or a handle/count on success, `-errno` on failure.
+
+use crate::vlan::{VlanMode, MAX_VID, NATIVE_VLAN};
+use smoltcp::wire::EthernetAddress;
+
+pub const OP_ATTACH: u64 = 0;
+pub const OP_KICK: u64 = 1;
+pub const OP_DETACH: u64 = 2;
+pub const OP_STATS: u64 = 3;
+pub const OP_FDB: u64 = 4;
+pub const OP_SET_VLAN: u64 = 5;
+pub const OP_SESSION: u64 = 6;
+pub const OP_SESSION_CLOSE: u64 = 7;
+
+/// Port handle of the uplink.
+pub const UPLINK: u64 = 0;
+/// VLAN mode of a trunk carrying all VLANs.
+pub const VLAN_TRUNK: u64 = 1 << 16;
+
+/// Decode a VLAN mode word.
+pub fn vlan_mode(word: u64) -> Option<VlanMode> {
+    match word {
+        0 => Some(VlanMode::Access(NATIVE_VLAN)),
+        VLAN_TRUNK => Some(VlanMode::Trunk(None)),
+        vid if vid <= MAX_VID as u64 => Some(VlanMode::Access(vid as u16)),
+        _ => None,
+    }
+}
+
+/// Encode a MAC address for MR1.
+pub fn mac_word(mac: EthernetAddress) -> u64 {
+    mac.0.iter().fold(0, |word, &b| word << 8 | b as u64)
+}
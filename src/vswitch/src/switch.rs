@@ -0,0 +1,246 @@
+//! Frame forwarding.
+//!
+//! The switch learns the source address of every frame it receives, per
+//! VLAN, into its forwarding database (FDB). Frames to a known unicast
+//! address go to the port the address was last seen on; broadcast,
+//! multicast and unknown unicast frames are flooded to all other ports of
+//! the VLAN. Frames to an address behind their own ingress port are
+//! filtered. Entries expire after the ageing time, by default
+//! [`DEFAULT_AGEING`], and the FDB holds at most [`FDB_CAPACITY`] of them;
+//! when it is full new addresses are flooded instead of learned.
+
+use crate::port::Port;
+use crate::vlan::{self, VlanMode};
+use slab::Slab;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::EthernetAddress;
+use std::collections::HashMap;
+
+/// Time after which an address that sent nothing is forgotten.
+pub const DEFAULT_AGEING: Duration = Duration::from_secs(300);
+/// Largest number of learned addresses.
+pub const FDB_CAPACITY: usize = 4096;
+/// Frames taken from one port before the next port's turn, so that a busy
+/// port does not starve the others.
+pub const PORT_BUDGET: usize = 32;
+
+/// Identifies a port of a switch.
+pub type PortId = usize;
+
+/// Traffic counters of a port. "rx" is traffic from the station into the
+/// switch, "tx" traffic the switch handed to the station.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortStats {
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    /// Received frames that were malformed or not allowed on the port's
+    /// VLANs.
+    pub rx_dropped: u64,
+    /// Frames the port had no room for.
+    pub tx_dropped: u64,
+}
+
+/// A learned address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FdbEntry {
+    pub mac: EthernetAddress,
+    pub vlan: u16,
+    pub port: PortId,
+    pub last_seen: Instant,
+}
+
+struct Member<P> {
+    port: P,
+    vlan: VlanMode,
+    stats: PortStats,
+}
+
+/// A learning Ethernet switch over ports of type `P`.
+pub struct Switch<P> {
+    ports: Slab<Member<P>>,
+    fdb: HashMap<(u16, EthernetAddress), (PortId, Instant)>,
+    ageing: Duration,
+}
+
+impl<P: Port> Default for Switch<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Port> Switch<P> {
+    /// A switch without ports.
+    pub fn new() -> Self {
+        Self {
+            ports: Slab::new(),
+            fdb: HashMap::new(),
+            ageing: DEFAULT_AGEING,
+        }
+    }
+
+    /// Change the ageing time of learned addresses.
+    pub fn set_ageing(&mut self, ageing: Duration) {
+        self.ageing = ageing;
+    }
+
+    /// Connect `port` with the given VLAN configuration.
+    pub fn add_port(&mut self, port: P, vlan: VlanMode) -> PortId {
+        self.ports.insert(Member {
+            port,
+            vlan,
+            stats: PortStats::default(),
+        })
+    }
+
+    /// Disconnect port `id` and forget the addresses learned on it.
+    pub fn remove_port(&mut self, id: PortId) -> Option<P> {
+        let member = self.ports.try_remove(id)?;
+        self.fdb.retain(|_, (port, _)| *port != id);
+        Some(member.port)
+    }
+
+    pub fn port(&self, id: PortId) -> Option<&P> {
+        self.ports.get(id).map(|m| &m.port)
+    }
+
+    pub fn port_mut(&mut self, id: PortId) -> Option<&mut P> {
+        self.ports.get_mut(id).map(|m| &mut m.port)
+    }
+
+    /// Ids of all ports.
+    pub fn port_ids(&self) -> Vec<PortId> {
+        self.ports.iter().map(|(id, _)| id).collect()
+    }
+
+    pub fn stats(&self, id: PortId) -> Option<PortStats> {
+        self.ports.get(id).map(|m| m.stats)
+    }
+
+    pub fn vlan(&self, id: PortId) -> Option<&VlanMode> {
+        self.ports.get(id).map(|m| &m.vlan)
+    }
+
+    /// Change the VLAN configuration of port `id`. Addresses learned on the
+    /// port are forgotten.
+    pub fn set_vlan(&mut self, id: PortId, vlan: VlanMode) -> bool {
+        let Some(member) = self.ports.get_mut(id) else {
+            return false;
+        };
+        member.vlan = vlan;
+        self.fdb.retain(|_, (port, _)| *port != id);
+        true
+    }
+
+    /// The learned addresses, ordered by VLAN and address.
+    pub fn fdb(&self) -> Vec<FdbEntry> {
+        let mut entries: Vec<_> = self
+            .fdb
+            .iter()
+            .map(|(&(vlan, mac), &(port, last_seen))| FdbEntry {
+                mac,
+                vlan,
+                port,
+                last_seen,
+            })
+            .collect();
+        entries.sort_by_key(|e| (e.vlan, e.mac.0));
+        entries
+    }
+
+    /// Forget all learned addresses.
+    pub fn flush(&mut self) {
+        self.fdb.clear();
+    }
+
+    /// The port `mac` was last seen on in `vlan`, unless the entry expired.
+    pub fn lookup(&self, mac: EthernetAddress, vlan: u16, now: Instant) -> Option<PortId> {
+        let &(port, last_seen) = self.fdb.get(&(vlan, mac))?;
+        (now - last_seen < self.ageing).then_some(port)
+    }
+
+    /// Forward frames until no port has any left. Returns the number of
+    /// frames received.
+    pub fn poll(&mut self, now: Instant) -> usize {
+        let ageing = self.ageing;
+        self.fdb
+            .retain(|_, (_, last_seen)| now - *last_seen < ageing);
+
+        let mut total = 0;
+        loop {
+            let mut received = 0;
+            for id in self.port_ids() {
+                for _ in 0..PORT_BUDGET {
+                    let Some(frame) = self.ports[id].port.receive() else {
+                        break;
+                    };
+                    self.input(id, &frame, now);
+                    received += 1;
+                }
+            }
+            if received == 0 {
+                return total;
+            }
+            total += received;
+        }
+    }
+
+    /// Forward `frame`, which arrived on port `ingress`.
+    pub fn input(&mut self, ingress: PortId, frame: &[u8], now: Instant) {
+        let Some(member) = self.ports.get_mut(ingress) else {
+            return;
+        };
+        member.stats.rx_frames += 1;
+        member.stats.rx_bytes += frame.len() as u64;
+        let classified =
+            vlan::untag(frame).and_then(|(tag, frame)| Some((member.vlan.classify(tag)?, frame)));
+        let Some((vid, frame)) = classified else {
+            member.stats.rx_dropped += 1;
+            return;
+        };
+
+        let dst = EthernetAddress::from_bytes(&frame[0..6]);
+        let src = EthernetAddress::from_bytes(&frame[6..12]);
+        if src.is_unicast() {
+            self.learn(src, vid, ingress, now);
+        }
+
+        let egress = match self.lookup(dst, vid, now).filter(|_| dst.is_unicast()) {
+            Some(port) if port == ingress => return,
+            Some(port) => vec![port],
+            None => self
+                .port_ids()
+                .into_iter()
+                .filter(|&id| id != ingress)
+                .collect(),
+        };
+        for id in egress {
+            let member = &mut self.ports[id];
+            if !member.vlan.carries(vid) {
+                continue;
+            }
+            let sent = if member.vlan.tags(vid) {
+                let tagged = vlan::tag(&frame, vid);
+                member.port.transmit(&tagged).then_some(tagged.len())
+            } else {
+                member.port.transmit(&frame).then_some(frame.len())
+            };
+            match sent {
+                Some(len) => {
+                    member.stats.tx_frames += 1;
+                    member.stats.tx_bytes += len as u64;
+                }
+                None => member.stats.tx_dropped += 1,
+            }
+        }
+    }
+
+    fn learn(&mut self, mac: EthernetAddress, vlan: u16, port: PortId, now: Instant) {
+        let key = (vlan, mac);
+        if self.fdb.len() >= FDB_CAPACITY && !self.fdb.contains_key(&key) {
+            return;
+        }
+        self.fdb.insert(key, (port, now));
+    }
+}
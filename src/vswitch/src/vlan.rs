@@ -0,0 +1,128 @@
+//! IEEE 802.1Q VLANs.
+//!
+//! Every port is either an access port of one VLAN or a trunk. Access ports
+//! carry untagged frames only. Trunks carry tagged frames of the VLANs they
+//! allow, and untagged frames, which belong to [`NATIVE_VLAN`]. Inside the
+//! switch frames travel untagged along with their VLAN; tags are added
+//! again on trunk egress with priority 0.
+//!
+//! As text, a mode reads `access:<vid>`, `trunk` or
+//! `trunk:<vid>,<vid>,...`.
+
+use core::str::FromStr;
+
+/// VLAN of untagged frames on trunks, and of access ports by default.
+pub const NATIVE_VLAN: u16 = 1;
+/// Largest valid VLAN id.
+pub const MAX_VID: u16 = 4094;
+/// EtherType of a VLAN tag.
+pub const TPID: u16 = 0x8100;
+
+const HEADER: usize = 14;
+const TAG: usize = 4;
+
+/// How a port handles VLANs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VlanMode {
+    /// Untagged frames of the given VLAN.
+    Access(u16),
+    /// Tagged frames of the listed VLANs, or of all if `None`, plus
+    /// untagged frames of [`NATIVE_VLAN`].
+    Trunk(Option<Vec<u16>>),
+}
+
+impl Default for VlanMode {
+    fn default() -> Self {
+        Self::Access(NATIVE_VLAN)
+    }
+}
+
+impl VlanMode {
+    /// Whether the port is a member of `vid`.
+    pub fn carries(&self, vid: u16) -> bool {
+        match self {
+            Self::Access(v) => *v == vid,
+            Self::Trunk(None) => true,
+            Self::Trunk(Some(allowed)) => vid == NATIVE_VLAN || allowed.contains(&vid),
+        }
+    }
+
+    /// The VLAN a frame arriving with `tag` belongs to, or `None` if the
+    /// port does not accept it.
+    pub fn classify(&self, tag: Option<u16>) -> Option<u16> {
+        match (self, tag) {
+            (Self::Access(v), None) => Some(*v),
+            (Self::Access(_), Some(_)) => None,
+            (Self::Trunk(_), None) => Some(NATIVE_VLAN),
+            (Self::Trunk(_), Some(vid)) => Some(vid).filter(|&vid| self.carries(vid)),
+        }
+    }
+
+    /// Whether frames of `vid` leave the port tagged.
+    pub fn tags(&self, vid: u16) -> bool {
+        matches!(self, Self::Trunk(_)) && vid != NATIVE_VLAN
+    }
+
+    /// Whether the configuration is valid: all VLAN ids lie in
+    /// 1..=[`MAX_VID`].
+    pub fn is_valid(&self) -> bool {
+        let valid = |vid: &u16| (1..=MAX_VID).contains(vid);
+        match self {
+            Self::Access(vid) => valid(vid),
+            Self::Trunk(None) => true,
+            Self::Trunk(Some(allowed)) => allowed.iter().all(valid),
+        }
+    }
+}
+
+impl FromStr for VlanMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mode = match s.trim().split_once(':') {
+            Some(("access", vid)) => Self::Access(vid.trim().parse().map_err(|_| ())?),
+            Some(("trunk", list)) => {
+                let allowed = list
+                    .split(',')
+                    .map(|vid| vid.trim().parse().map_err(|_| ()));
+                Self::Trunk(Some(allowed.collect::<Result<_, _>>()?))
+            }
+            None if s.trim() == "trunk" => Self::Trunk(None),
+            _ => return Err(()),
+        };
+        if mode.is_valid() {
+            Ok(mode)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Split the VLAN tag off `frame`. Returns the VLAN id of the tag, if there
+/// is one, and the untagged frame, or `None` if the frame is too short.
+pub fn untag(frame: &[u8]) -> Option<(Option<u16>, Vec<u8>)> {
+    if frame.len() < HEADER {
+        return None;
+    }
+    if u16::from_be_bytes([frame[12], frame[13]]) != TPID {
+        return Some((None, frame.to_vec()));
+    }
+    if frame.len() < HEADER + TAG {
+        return None;
+    }
+    let vid = u16::from_be_bytes([frame[14], frame[15]]) & 0x0fff;
+    let mut untagged = Vec::with_capacity(frame.len() - TAG);
+    untagged.extend_from_slice(&frame[..12]);
+    untagged.extend_from_slice(&frame[12 + TAG..]);
+    Some((Some(vid), untagged))
+}
+
+/// `frame` with a tag for `vid` inserted after the addresses.
+pub fn tag(frame: &[u8], vid: u16) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(frame.len() + TAG);
+    tagged.extend_from_slice(&frame[..12]);
+    tagged.extend_from_slice(&TPID.to_be_bytes());
+    tagged.extend_from_slice(&(vid & 0x0fff).to_be_bytes());
+    tagged.extend_from_slice(&frame[12..]);
+    tagged
+}
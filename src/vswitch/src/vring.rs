@@ -0,0 +1,479 @@
+//! Ports on shared-memory virtqueues.
+//!
+//! A client port lives in a memory region shared between the client and
+//! the switch. It holds a header and two split virtqueues laid out as in
+//! the virtio specification, followed by the frame buffers:
+//!
+//! ```text
+//! +--------+----------------------+----------------------+---------------+
+//! | header | RX queue             | TX queue             | buffers       |
+//! |        | desc | avail | used  | desc | avail | used  | 2 * size * 2K |
+//! +--------+----------------------+----------------------+---------------+
+//! ```
+//!
+//! The client plays the virtio driver and the switch the device. On the RX
+//! queue the client posts empty buffers which the switch fills with frames
+//! for the client, on the TX queue it posts frames for the switch.
+//! Descriptor addresses are byte offsets from the start of the region and
+//! must point into the buffer area. Frames carry no virtio-net header.
+//!
+//! The header contains, as little-endian values:
+//!
+//! ```text
+//! offset  0: u32 magic "vswp"
+//! offset  4: u16 queue size (descriptors per queue)
+//! offset  8: u32 buffer size
+//! offset 12: u32 offset of the RX queue
+//! offset 16: u32 offset of the TX queue
+//! offset 20: u32 offset of the buffer area
+//! offset 24: u32 size of the region
+//! ```
+//!
+//! [`VringPort`] is the switch's side of a port, [`VringClient`] a minimal
+//! client that gives buffer `i` to RX descriptor `i` and buffer
+//! `size + i` to TX descriptor `i`. Both access the region through raw
+//! pointers, as they would if it was mapped into two address spaces.
+
+use crate::port::Port;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+/// First word of the header.
+pub const MAGIC: u32 = u32::from_le_bytes(*b"vswp");
+/// Queue size used when the client asks for none.
+pub const DEFAULT_QUEUE_SIZE: u16 = 64;
+/// Largest queue size.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+/// Size of every frame buffer.
+pub const BUF_SIZE: u32 = 2048;
+/// Largest frame a port passes on: an Ethernet frame with VLAN tag, without
+/// FCS.
+pub const MAX_FRAME: usize = 1518;
+
+const HEADER_SIZE: usize = 64;
+const ALIGN: usize = 64;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Where the parts of a port region lie.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VringLayout {
+    pub queue_size: u16,
+    pub rx_off: usize,
+    pub tx_off: usize,
+    pub data_off: usize,
+    pub total: usize,
+}
+
+fn align_up(v: usize) -> usize {
+    (v + ALIGN - 1) & !(ALIGN - 1)
+}
+
+/// Bytes taken by a split virtqueue of `size` descriptors.
+fn queue_bytes(size: usize) -> usize {
+    used_off(size) + 6 + 8 * size
+}
+
+fn avail_off(size: usize) -> usize {
+    16 * size
+}
+
+fn used_off(size: usize) -> usize {
+    (avail_off(size) + 6 + 2 * size + 3) & !3
+}
+
+impl VringLayout {
+    /// The layout for queues of `queue_size` descriptors, 0 for the
+    /// default. Returns `None` unless the size is a power of two up to
+    /// [`MAX_QUEUE_SIZE`].
+    pub fn new(queue_size: u16) -> Option<Self> {
+        let size = if queue_size == 0 {
+            DEFAULT_QUEUE_SIZE
+        } else {
+            queue_size
+        };
+        if !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+            return None;
+        }
+        let n = size as usize;
+        let rx_off = HEADER_SIZE;
+        let tx_off = align_up(rx_off + queue_bytes(n));
+        let data_off = align_up(tx_off + queue_bytes(n));
+        let total = data_off + 2 * n * BUF_SIZE as usize;
+        Some(Self {
+            queue_size: size,
+            rx_off,
+            tx_off,
+            data_off,
+            total,
+        })
+    }
+}
+
+/// A shared memory region.
+#[derive(Copy, Clone)]
+struct Shared {
+    base: *mut u8,
+    len: usize,
+}
+
+impl Shared {
+    fn read_u16(&self, off: usize) -> u16 {
+        debug_assert!(off + 2 <= self.len);
+        u16::from_le(unsafe { ptr::read_volatile(self.base.add(off) as *const u16) })
+    }
+
+    fn read_u32(&self, off: usize) -> u32 {
+        debug_assert!(off + 4 <= self.len);
+        u32::from_le(unsafe { ptr::read_volatile(self.base.add(off) as *const u32) })
+    }
+
+    fn read_u64(&self, off: usize) -> u64 {
+        debug_assert!(off + 8 <= self.len);
+        u64::from_le(unsafe { ptr::read_volatile(self.base.add(off) as *const u64) })
+    }
+
+    fn write_u16(&self, off: usize, v: u16) {
+        debug_assert!(off + 2 <= self.len);
+        unsafe { ptr::write_volatile(self.base.add(off) as *mut u16, v.to_le()) }
+    }
+
+    fn write_u32(&self, off: usize, v: u32) {
+        debug_assert!(off + 4 <= self.len);
+        unsafe { ptr::write_volatile(self.base.add(off) as *mut u32, v.to_le()) }
+    }
+
+    fn write_u64(&self, off: usize, v: u64) {
+        debug_assert!(off + 8 <= self.len);
+        unsafe { ptr::write_volatile(self.base.add(off) as *mut u64, v.to_le()) }
+    }
+
+    fn read(&self, off: usize, buf: &mut [u8]) {
+        debug_assert!(off + buf.len() <= self.len);
+        unsafe { ptr::copy_nonoverlapping(self.base.add(off), buf.as_mut_ptr(), buf.len()) }
+    }
+
+    fn write(&self, off: usize, data: &[u8]) {
+        debug_assert!(off + data.len() <= self.len);
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.base.add(off), data.len()) }
+    }
+}
+
+/// A descriptor of a chain.
+struct Desc {
+    addr: usize,
+    len: usize,
+    write: bool,
+}
+
+/// Device side of a split virtqueue.
+struct DeviceQueue {
+    off: usize,
+    size: usize,
+    last_avail: u16,
+    used_idx: u16,
+}
+
+impl DeviceQueue {
+    fn new(off: usize, size: u16) -> Self {
+        Self {
+            off,
+            size: size as usize,
+            last_avail: 0,
+            used_idx: 0,
+        }
+    }
+
+    /// The head of the next chain the driver made available.
+    fn pop(&mut self, mem: &Shared) -> Option<u16> {
+        let avail = self.off + avail_off(self.size);
+        if mem.read_u16(avail + 2) == self.last_avail {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let slot = self.last_avail as usize % self.size;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        Some(mem.read_u16(avail + 4 + 2 * slot))
+    }
+
+    /// The descriptors of the chain at `head`, or `None` if the chain is
+    /// malformed: it loops or points outside `data`.
+    fn chain(&self, mem: &Shared, head: u16, data: core::ops::Range<usize>) -> Option<Vec<Desc>> {
+        let mut descs = Vec::new();
+        let mut idx = head as usize;
+        loop {
+            if idx >= self.size || descs.len() == self.size {
+                return None;
+            }
+            let off = self.off + 16 * idx;
+            let addr = usize::try_from(mem.read_u64(off)).ok()?;
+            let len = mem.read_u32(off + 8) as usize;
+            let flags = mem.read_u16(off + 12);
+            if addr < data.start || addr.checked_add(len)? > data.end {
+                return None;
+            }
+            descs.push(Desc {
+                addr,
+                len,
+                write: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Some(descs);
+            }
+            idx = mem.read_u16(off + 14) as usize;
+        }
+    }
+
+    /// Return the chain at `head` to the driver with `len` bytes written.
+    fn push_used(&mut self, mem: &Shared, head: u16, len: u32) {
+        let used = self.off + used_off(self.size);
+        let elem = used + 4 + 8 * (self.used_idx as usize % self.size);
+        mem.write_u32(elem, head as u32);
+        mem.write_u32(elem + 4, len);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        fence(Ordering::Release);
+        mem.write_u16(used + 2, self.used_idx);
+    }
+}
+
+/// The switch's side of a client port.
+pub struct VringPort {
+    mem: Shared,
+    layout: VringLayout,
+    rx: DeviceQueue,
+    tx: DeviceQueue,
+    notify: bool,
+}
+
+impl VringPort {
+    /// Set up a fresh port in the zeroed region at `base`, laid out by
+    /// `layout`.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be aligned to 8 bytes and valid for reads and writes of
+    /// `layout.total` bytes for the lifetime of the port.
+    pub unsafe fn new(base: *mut u8, layout: VringLayout) -> Self {
+        let mem = Shared {
+            base,
+            len: layout.total,
+        };
+        mem.write_u32(0, MAGIC);
+        mem.write_u16(4, layout.queue_size);
+        mem.write_u32(8, BUF_SIZE);
+        mem.write_u32(12, layout.rx_off as u32);
+        mem.write_u32(16, layout.tx_off as u32);
+        mem.write_u32(20, layout.data_off as u32);
+        mem.write_u32(24, layout.total as u32);
+        Self {
+            mem,
+            layout,
+            rx: DeviceQueue::new(layout.rx_off, layout.queue_size),
+            tx: DeviceQueue::new(layout.tx_off, layout.queue_size),
+            notify: false,
+        }
+    }
+
+    /// Whether frames were delivered since the last call, so the client
+    /// should be notified.
+    pub fn take_notify(&mut self) -> bool {
+        core::mem::take(&mut self.notify)
+    }
+
+    fn data(&self) -> core::ops::Range<usize> {
+        self.layout.data_off..self.layout.total
+    }
+
+    /// The frame in the TX chain at `head`, or `None` for malformed chains
+    /// and oversized frames.
+    fn read_chain(&self, head: u16) -> Option<Vec<u8>> {
+        let chain = self.tx.chain(&self.mem, head, self.data())?;
+        if chain.iter().any(|d| d.write) {
+            return None;
+        }
+        let len: usize = chain.iter().map(|d| d.len).sum();
+        if len > MAX_FRAME {
+            return None;
+        }
+        let mut frame = vec![0; len];
+        let mut at = 0;
+        for desc in chain {
+            self.mem.read(desc.addr, &mut frame[at..at + desc.len]);
+            at += desc.len;
+        }
+        Some(frame)
+    }
+}
+
+impl Port for VringPort {
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let head = self.tx.pop(&self.mem)?;
+            let frame = self.read_chain(head);
+            // The buffer goes back once the frame has been copied out.
+            self.tx.push_used(&self.mem, head, 0);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> bool {
+        let Some(head) = self.rx.pop(&self.mem) else {
+            return false;
+        };
+        let chain = self.rx.chain(&self.mem, head, self.data());
+        let chain = chain.filter(|c| c.iter().all(|d| d.write));
+        let room: usize = chain.iter().flatten().map(|d| d.len).sum();
+        if room < frame.len() {
+            // The buffer goes back unused.
+            self.rx.push_used(&self.mem, head, 0);
+            return false;
+        }
+        let mut rest = frame;
+        for desc in chain.into_iter().flatten() {
+            let n = desc.len.min(rest.len());
+            self.mem.write(desc.addr, &rest[..n]);
+            rest = &rest[n..];
+        }
+        self.rx.push_used(&self.mem, head, frame.len() as u32);
+        self.notify = true;
+        true
+    }
+}
+
+/// Driver side of a split virtqueue whose chains are single descriptors.
+struct DriverQueue {
+    off: usize,
+    size: usize,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+impl DriverQueue {
+    fn new(off: usize, size: u16) -> Self {
+        Self {
+            off,
+            size: size as usize,
+            avail_idx: 0,
+            last_used: 0,
+        }
+    }
+
+    fn add(&mut self, mem: &Shared, id: u16, addr: usize, len: usize, write: bool) {
+        let desc = self.off + 16 * id as usize;
+        mem.write_u64(desc, addr as u64);
+        mem.write_u32(desc + 8, len as u32);
+        mem.write_u16(desc + 12, if write { VIRTQ_DESC_F_WRITE } else { 0 });
+        mem.write_u16(desc + 14, 0);
+        let avail = self.off + avail_off(self.size);
+        mem.write_u16(avail + 4 + 2 * (self.avail_idx as usize % self.size), id);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        fence(Ordering::Release);
+        mem.write_u16(avail + 2, self.avail_idx);
+    }
+
+    fn pop_used(&mut self, mem: &Shared) -> Option<(u16, usize)> {
+        let used = self.off + used_off(self.size);
+        if mem.read_u16(used + 2) == self.last_used {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let elem = used + 4 + 8 * (self.last_used as usize % self.size);
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((mem.read_u32(elem) as u16, mem.read_u32(elem + 4) as usize))
+    }
+}
+
+/// A client of a port.
+pub struct VringClient {
+    mem: Shared,
+    layout: VringLayout,
+    rx: DriverQueue,
+    tx: DriverQueue,
+    /// TX descriptors not in use.
+    tx_free: Vec<u16>,
+}
+
+impl VringClient {
+    /// Attach to the port in the region at `base`, which holds `len`
+    /// bytes, and post all receive buffers. Returns `None` if the header
+    /// is invalid.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be aligned to 8 bytes and valid for reads and writes of
+    /// `len` bytes for the lifetime of the client.
+    pub unsafe fn new(base: *mut u8, len: usize) -> Option<Self> {
+        let mem = Shared { base, len };
+        if len < HEADER_SIZE || mem.read_u32(0) != MAGIC || mem.read_u32(8) != BUF_SIZE {
+            return None;
+        }
+        let layout = VringLayout::new(mem.read_u16(4))?;
+        let header = [
+            mem.read_u32(12),
+            mem.read_u32(16),
+            mem.read_u32(20),
+            mem.read_u32(24),
+        ];
+        let expected = [layout.rx_off, layout.tx_off, layout.data_off, layout.total];
+        if header.iter().zip(expected).any(|(&h, e)| h as usize != e) || layout.total > len {
+            return None;
+        }
+
+        let size = layout.queue_size;
+        let mut client = Self {
+            mem,
+            layout,
+            rx: DriverQueue::new(layout.rx_off, size),
+            tx: DriverQueue::new(layout.tx_off, size),
+            tx_free: (0..size).rev().collect(),
+        };
+        for id in 0..size {
+            client.post_rx(id);
+        }
+        Some(client)
+    }
+
+    fn buffer(&self, index: usize) -> usize {
+        self.layout.data_off + index * BUF_SIZE as usize
+    }
+
+    fn post_rx(&mut self, id: u16) {
+        let addr = self.buffer(id as usize);
+        self.rx.add(&self.mem, id, addr, BUF_SIZE as usize, true);
+    }
+
+    /// Queue `frame` for the switch. Returns `false` if it is too long or
+    /// the TX queue is full.
+    pub fn send(&mut self, frame: &[u8]) -> bool {
+        while let Some((id, _)) = self.tx.pop_used(&self.mem) {
+            self.tx_free.push(id);
+        }
+        if frame.len() > MAX_FRAME {
+            return false;
+        }
+        let Some(id) = self.tx_free.pop() else {
+            return false;
+        };
+        let addr = self.buffer(self.layout.queue_size as usize + id as usize);
+        self.mem.write(addr, frame);
+        self.tx.add(&self.mem, id, addr, frame.len(), false);
+        true
+    }
+
+    /// The next frame from the switch.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (id, len) = self.rx.pop_used(&self.mem)?;
+            let mut frame = vec![0; len.min(BUF_SIZE as usize)];
+            self.mem.read(self.buffer(id as usize), &mut frame);
+            self.post_rx(id);
+            // Buffers the switch could not use come back empty.
+            if !frame.is_empty() {
+                return Some(frame);
+            }
+        }
+    }
+}
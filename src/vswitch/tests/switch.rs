@@ -0,0 +1,218 @@
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::EthernetAddress;
+use vswitch::port::MEM_PORT_FRAMES;
+use vswitch::proto::{self, VLAN_TRUNK};
+use vswitch::vlan::{self, NATIVE_VLAN};
+use vswitch::{MemPort, Switch, VlanMode};
+
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+fn mac(n: u8) -> [u8; 6] {
+    [0x02, 0, 0, 0, 0, n]
+}
+
+fn frame(dst: [u8; 6], src: [u8; 6], payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&0x0800u16.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A switch with a station end for every mode in `vlans`.
+fn switch(vlans: &[VlanMode]) -> (Switch<MemPort>, Vec<MemPort>) {
+    let mut switch = Switch::new();
+    let mut stations = Vec::new();
+    for vlan in vlans {
+        let (port, station) = MemPort::pair();
+        switch.add_port(port, vlan.clone());
+        stations.push(station);
+    }
+    (switch, stations)
+}
+
+fn drain(station: &MemPort) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| station.recv()).collect()
+}
+
+#[test]
+fn learning() {
+    let (mut switch, stations) = switch(&vec![VlanMode::default(); 3]);
+    let now = Instant::ZERO;
+
+    // Unknown destination: flooded.
+    let hello = frame(mac(2), mac(1), b"hello");
+    stations[0].send(&hello);
+    assert_eq!(switch.poll(now), 1);
+    assert!(drain(&stations[0]).is_empty());
+    assert_eq!(drain(&stations[1]), vec![hello.clone()]);
+    assert_eq!(drain(&stations[2]), vec![hello.clone()]);
+
+    // The reply goes to the learned port only, after which both are known.
+    let reply = frame(mac(1), mac(2), b"reply");
+    stations[1].send(&reply);
+    switch.poll(now);
+    assert_eq!(drain(&stations[0]), [reply]);
+    assert!(drain(&stations[2]).is_empty());
+    stations[0].send(&hello);
+    switch.poll(now);
+    assert_eq!(drain(&stations[1]), vec![hello.clone()]);
+    assert!(drain(&stations[2]).is_empty());
+
+    let fdb = switch.fdb();
+    assert_eq!(fdb.len(), 2);
+    assert_eq!(fdb[0].mac, EthernetAddress(mac(1)));
+    assert_eq!((fdb[0].vlan, fdb[0].port), (NATIVE_VLAN, 0));
+    assert_eq!(
+        switch.lookup(EthernetAddress(mac(2)), NATIVE_VLAN, now),
+        Some(1)
+    );
+
+    // Frames to a station behind the ingress port are filtered.
+    stations[0].send(&frame(mac(1), mac(3), b"local"));
+    switch.poll(now);
+    assert!(stations.iter().all(|s| drain(s).is_empty()));
+
+    let stats = switch.stats(0).unwrap();
+    assert_eq!((stats.rx_frames, stats.tx_frames), (3, 1));
+    assert_eq!(stats.rx_bytes, 2 * hello.len() as u64 + 19);
+    assert_eq!(switch.stats(2).unwrap().tx_frames, 1);
+}
+
+#[test]
+fn flooding() {
+    let (mut switch, stations) = switch(&vec![VlanMode::default(); 3]);
+    let now = Instant::ZERO;
+
+    let broadcast = frame(BROADCAST, mac(1), b"who has");
+    stations[0].send(&broadcast);
+    let multicast = frame([0x01, 0, 0x5e, 0, 0, 1], mac(1), b"group");
+    stations[0].send(&multicast);
+    switch.poll(now);
+    for station in &stations[1..] {
+        assert_eq!(drain(station), [broadcast.clone(), multicast.clone()]);
+    }
+
+    // Multicast sources are not learned.
+    stations[1].send(&frame(mac(5), [0x01, 0, 0, 0, 0, 9], b"bogus"));
+    switch.poll(now);
+    assert_eq!(switch.fdb().len(), 1);
+    assert_eq!(switch.stats(1).unwrap().rx_dropped, 0);
+}
+
+#[test]
+fn vlans() {
+    let trunk_10 = VlanMode::Trunk(Some(vec![10]));
+    let (mut switch, stations) = switch(&[
+        VlanMode::Access(10),
+        VlanMode::Access(10),
+        VlanMode::Access(20),
+        VlanMode::Trunk(None),
+        trunk_10,
+    ]);
+    let now = Instant::ZERO;
+
+    // Access ports only reach their VLAN; trunks get the frame tagged.
+    let broadcast = frame(BROADCAST, mac(1), b"vlan 10");
+    stations[0].send(&broadcast);
+    switch.poll(now);
+    assert_eq!(drain(&stations[1]), vec![broadcast.clone()]);
+    assert!(drain(&stations[2]).is_empty());
+    let tagged = vlan::tag(&broadcast, 10);
+    assert_eq!(drain(&stations[3]), vec![tagged.clone()]);
+    assert_eq!(drain(&stations[4]), vec![tagged.clone()]);
+    assert_eq!(vlan::untag(&tagged), Some((Some(10), broadcast)));
+
+    // Tagged frames from a trunk arrive untagged on access ports.
+    let to_20 = frame(BROADCAST, mac(4), b"vlan 20");
+    stations[3].send(&vlan::tag(&to_20, 20));
+    switch.poll(now);
+    assert_eq!(drain(&stations[2]), vec![to_20.clone()]);
+    assert!(drain(&stations[0]).is_empty());
+    assert!(drain(&stations[4]).is_empty());
+
+    // Untagged frames on a trunk belong to the native VLAN, which no access
+    // port is in.
+    stations[3].send(&frame(BROADCAST, mac(4), b"native"));
+    switch.poll(now);
+    assert_eq!(drain(&stations[4]).len(), 1);
+    assert!(stations[..3].iter().all(|s| drain(s).is_empty()));
+
+    // The same address is learned per VLAN.
+    let fdb = switch.fdb();
+    assert_eq!(
+        fdb.iter()
+            .filter(|e| e.mac == EthernetAddress(mac(4)))
+            .count(),
+        2
+    );
+
+    // Tagged frames on access ports and VLANs a trunk does not allow are
+    // dropped.
+    stations[0].send(&vlan::tag(&frame(BROADCAST, mac(1), b"x"), 10));
+    stations[4].send(&vlan::tag(&to_20, 20));
+    stations[1].send(&[0u8; 10]);
+    switch.poll(now);
+    assert!(stations.iter().all(|s| drain(s).is_empty()));
+    for port in [0, 1, 4] {
+        assert_eq!(switch.stats(port).unwrap().rx_dropped, 1);
+    }
+}
+
+#[test]
+fn ageing_and_ports() {
+    let (mut switch, stations) = switch(&vec![VlanMode::default(); 3]);
+    switch.set_ageing(Duration::from_secs(10));
+    let mut now = Instant::ZERO;
+
+    stations[1].send(&frame(BROADCAST, mac(2), b"here"));
+    switch.poll(now);
+    drain(&stations[0]);
+    drain(&stations[2]);
+    now += Duration::from_secs(11);
+    assert_eq!(
+        switch.lookup(EthernetAddress(mac(2)), NATIVE_VLAN, now),
+        None
+    );
+    switch.poll(now);
+    assert!(switch.fdb().is_empty());
+
+    // Removing a port forgets its addresses.
+    stations[1].send(&frame(BROADCAST, mac(2), b"here"));
+    switch.poll(now);
+    assert!(switch.remove_port(1).is_some());
+    assert!(switch.fdb().is_empty());
+    assert_eq!(switch.port_ids(), [0, 2]);
+    assert_eq!(switch.stats(1), None);
+
+    // A port without room drops frames.
+    drain(&stations[2]);
+    let before = switch.stats(2).unwrap();
+    for _ in 0..MEM_PORT_FRAMES + 1 {
+        stations[0].send(&frame(mac(3), mac(1), b"flood"));
+        switch.poll(now);
+    }
+    let stats = switch.stats(2).unwrap();
+    assert_eq!(stats.tx_frames - before.tx_frames, MEM_PORT_FRAMES as u64);
+    assert_eq!(stats.tx_dropped, 1);
+}
+
+#[test]
+fn vlan_modes() {
+    assert_eq!("access:10".parse(), Ok(VlanMode::Access(10)));
+    assert_eq!("trunk".parse(), Ok(VlanMode::Trunk(None)));
+    assert_eq!(
+        "trunk:10, 20".parse(),
+        Ok(VlanMode::Trunk(Some(vec![10, 20])))
+    );
+    for bad in ["access:0", "access:4095", "trunk:", "access", "hybrid:1"] {
+        assert_eq!(bad.parse::<VlanMode>(), Err(()), "{bad}");
+    }
+
+    assert_eq!(proto::vlan_mode(0), Some(VlanMode::Access(NATIVE_VLAN)));
+    assert_eq!(proto::vlan_mode(42), Some(VlanMode::Access(42)));
+    assert_eq!(proto::vlan_mode(VLAN_TRUNK), Some(VlanMode::Trunk(None)));
+    assert_eq!(proto::vlan_mode(4095), None);
+    assert_eq!(proto::mac_word(EthernetAddress(mac(7))), 0x0200_0000_0007);
+}
@@ -0,0 +1,101 @@
+use smoltcp::time::Instant;
+use vswitch::vring::{VringClient, VringLayout, VringPort, MAX_FRAME, MAX_QUEUE_SIZE};
+use vswitch::{Port, Switch, VlanMode};
+
+/// Memory for a port region, aligned like a dataspace.
+fn region(layout: &VringLayout) -> Vec<u64> {
+    vec![0; layout.total.div_ceil(8)]
+}
+
+fn frame(dst: u8, src: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x02, 0, 0, 0, 0, dst, 0x02, 0, 0, 0, 0, src, 0x08, 0x00];
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn layout() {
+    assert_eq!(VringLayout::new(0).unwrap().queue_size, 64);
+    assert!(VringLayout::new(3).is_none());
+    assert!(VringLayout::new(MAX_QUEUE_SIZE * 2).is_none());
+    let layout = VringLayout::new(8).unwrap();
+    assert!(layout.rx_off < layout.tx_off && layout.tx_off < layout.data_off);
+    assert_eq!(layout.total, layout.data_off + 16 * 2048);
+
+    // A client refuses regions without a valid header.
+    let mut mem = region(&layout);
+    let client = unsafe { VringClient::new(mem.as_mut_ptr() as *mut u8, layout.total) };
+    assert!(client.is_none());
+    let _port = unsafe { VringPort::new(mem.as_mut_ptr() as *mut u8, layout) };
+    let client = unsafe { VringClient::new(mem.as_mut_ptr() as *mut u8, layout.total - 1) };
+    assert!(client.is_none());
+}
+
+#[test]
+fn clients_through_switch() {
+    let layout = VringLayout::new(4).unwrap();
+    let (mut mem_a, mut mem_b) = (region(&layout), region(&layout));
+    let (base_a, base_b) = (mem_a.as_mut_ptr() as *mut u8, mem_b.as_mut_ptr() as *mut u8);
+    let mut switch = Switch::new();
+    let a = switch.add_port(
+        unsafe { VringPort::new(base_a, layout) },
+        VlanMode::default(),
+    );
+    let b = switch.add_port(
+        unsafe { VringPort::new(base_b, layout) },
+        VlanMode::default(),
+    );
+    let mut client_a = unsafe { VringClient::new(base_a, layout.total) }.unwrap();
+    let mut client_b = unsafe { VringClient::new(base_b, layout.total) }.unwrap();
+
+    let ping = frame(2, 1, b"ping");
+    assert!(client_a.send(&ping));
+    assert_eq!(switch.poll(Instant::ZERO), 1);
+    assert!(switch.port_mut(b).unwrap().take_notify());
+    assert!(!switch.port_mut(a).unwrap().take_notify());
+    assert_eq!(client_b.recv(), Some(ping));
+    assert_eq!(client_b.recv(), None);
+
+    // More frames than the queues hold: the client reclaims TX buffers and
+    // the RX buffers come back after every receive.
+    for round in 0..3u8 {
+        for i in 0..4 {
+            assert!(client_b.send(&frame(1, 2, &[round, i])));
+        }
+        assert!(!client_b.send(&frame(1, 2, b"full")));
+        assert_eq!(switch.poll(Instant::ZERO), 4);
+        for i in 0..4 {
+            assert_eq!(client_a.recv(), Some(frame(1, 2, &[round, i])));
+        }
+    }
+
+    // Without posted RX buffers the switch drops frames for the client.
+    for i in 0..5 {
+        assert!(client_a.send(&frame(2, 1, &[i])));
+        switch.poll(Instant::ZERO);
+    }
+    let stats = switch.stats(b).unwrap();
+    assert_eq!(stats.tx_frames, 1 + 4);
+    assert_eq!(stats.tx_dropped, 1);
+    assert_eq!(std::iter::from_fn(|| client_b.recv()).count(), 4);
+
+    assert!(!client_a.send(&vec![0; MAX_FRAME + 1]));
+}
+
+#[test]
+fn malformed_chains() {
+    let layout = VringLayout::new(4).unwrap();
+    let mut mem = region(&layout);
+    let base = mem.as_mut_ptr() as *mut u8;
+    let mut port = unsafe { VringPort::new(base, layout) };
+    let mut client = unsafe { VringClient::new(base, layout.total) }.unwrap();
+
+    // Point the client's next TX descriptor outside the buffer area; the
+    // switch skips the chain and takes the following one.
+    assert!(client.send(&frame(2, 1, b"bad")));
+    let desc = layout.tx_off;
+    unsafe { (base.add(desc) as *mut u64).write(0) };
+    assert!(client.send(&frame(2, 1, b"good")));
+    assert_eq!(port.receive(), Some(frame(2, 1, b"good")));
+    assert_eq!(port.receive(), None);
+}